@@ -0,0 +1,3 @@
+DROP INDEX transactions_status_hold_until_idx;
+ALTER TABLE transactions
+  DROP COLUMN IF EXISTS hold_until;
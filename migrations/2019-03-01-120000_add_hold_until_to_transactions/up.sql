@@ -0,0 +1,3 @@
+ALTER TABLE transactions
+  ADD COLUMN hold_until TIMESTAMP;
+CREATE INDEX transactions_status_hold_until_idx ON transactions (status, hold_until);
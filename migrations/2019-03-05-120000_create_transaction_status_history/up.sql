@@ -0,0 +1,11 @@
+CREATE TABLE transaction_status_history (
+    id BIGSERIAL PRIMARY KEY,
+    transaction_id UUID NOT NULL REFERENCES transactions(id),
+    status VARCHAR NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT current_timestamp
+);
+
+CREATE INDEX transaction_status_history_transaction_id_idx ON transaction_status_history (transaction_id);
+
+INSERT INTO transaction_status_history (transaction_id, status, created_at)
+  SELECT id, status, updated_at FROM transactions;
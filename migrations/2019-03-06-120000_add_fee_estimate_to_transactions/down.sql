@@ -0,0 +1,3 @@
+ALTER TABLE transactions
+  DROP COLUMN IF EXISTS gross_fee,
+  DROP COLUMN IF EXISTS fee_price;
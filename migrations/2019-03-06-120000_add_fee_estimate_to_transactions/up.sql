@@ -0,0 +1,3 @@
+ALTER TABLE transactions
+  ADD COLUMN fee_price DOUBLE PRECISION,
+  ADD COLUMN gross_fee NUMERIC;
@@ -0,0 +1,7 @@
+DELETE FROM seen_hashes WHERE output_index <> (
+  SELECT MIN(output_index) FROM seen_hashes AS s WHERE s.hash = seen_hashes.hash AND s.currency = seen_hashes.currency
+);
+ALTER TABLE seen_hashes DROP CONSTRAINT seen_hashes_pkey;
+ALTER TABLE seen_hashes ADD PRIMARY KEY (hash, currency);
+ALTER TABLE seen_hashes
+  DROP COLUMN IF EXISTS output_index;
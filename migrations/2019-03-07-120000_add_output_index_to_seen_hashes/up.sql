@@ -0,0 +1,4 @@
+ALTER TABLE seen_hashes
+  ADD COLUMN output_index INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE seen_hashes DROP CONSTRAINT seen_hashes_pkey;
+ALTER TABLE seen_hashes ADD PRIMARY KEY (hash, currency, output_index);
@@ -0,0 +1,2 @@
+ALTER TABLE transactions
+  ADD COLUMN failure_reason VARCHAR;
@@ -0,0 +1,2 @@
+ALTER TABLE accounts
+  ADD COLUMN reserve NUMERIC NOT NULL DEFAULT 0;
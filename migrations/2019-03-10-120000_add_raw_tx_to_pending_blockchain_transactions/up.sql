@@ -0,0 +1,2 @@
+ALTER TABLE pending_blockchain_transactions
+  ADD COLUMN raw_tx VARCHAR;
@@ -0,0 +1,2 @@
+ALTER TABLE transactions
+  ADD COLUMN user_note VARCHAR;
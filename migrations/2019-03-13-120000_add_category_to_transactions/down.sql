@@ -0,0 +1,3 @@
+DROP INDEX transactions_category_idx;
+ALTER TABLE transactions
+  DROP COLUMN IF EXISTS category;
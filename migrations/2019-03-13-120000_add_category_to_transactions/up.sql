@@ -0,0 +1,3 @@
+ALTER TABLE transactions
+  ADD COLUMN category VARCHAR;
+CREATE INDEX transactions_category_idx ON transactions (category);
@@ -0,0 +1 @@
+-- original form of addresses is not kept, normalized ones are valid as well
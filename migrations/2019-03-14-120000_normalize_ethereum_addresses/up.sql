@@ -0,0 +1,3 @@
+-- the same as `BlockchainAddress::normalized`, ethereum addresses are case insensitive
+UPDATE accounts SET address = LOWER(TRIM(address)) WHERE currency IN ('eth', 'stq') AND address <> LOWER(TRIM(address));
+UPDATE accounts SET address = TRIM(address) WHERE currency = 'btc' AND address <> TRIM(address);
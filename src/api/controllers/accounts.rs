@@ -1,7 +1,7 @@
 use failure::Fail;
 use futures::prelude::*;
 
-use super::super::utils::{parse_body, response_with_model};
+use super::super::utils::{clamp_pagination, parse_body, response_with_model};
 use super::Context;
 use super::ControllerFuture;
 use api::error::*;
@@ -32,8 +32,9 @@ pub fn post_accounts(ctx: &Context) -> ControllerFuture {
 }
 
 pub fn get_users_accounts(ctx: &Context, user_id: UserId) -> ControllerFuture {
-    let accounts_service = ctx.accounts_service.clone();
+    let transactions_service = ctx.transactions_service.clone();
     let maybe_token = ctx.get_auth_token();
+    let max_list_limit = ctx.max_list_limit;
     let path_and_query = ctx.uri.path_and_query();
     let path_and_query_clone = ctx.uri.path_and_query();
     Box::new(
@@ -46,6 +47,9 @@ pub fn get_users_accounts(ctx: &Context, user_id: UserId) -> ControllerFuture {
                     ectx!(err e, ErrorContext::RequestQueryParams, ErrorKind::BadRequest => path_and_query_clone)
                 })
             })
+            .and_then(move |input| {
+                clamp_pagination(input.offset, input.limit, max_list_limit).map(|(offset, limit)| GetUsersAccountsParams { offset, limit })
+            })
             .into_future()
             .and_then(move |input| {
                 maybe_token
@@ -53,13 +57,13 @@ pub fn get_users_accounts(ctx: &Context, user_id: UserId) -> ControllerFuture {
                     .into_future()
                     .and_then(move |token| {
                         let input_clone = input.clone();
-                        accounts_service
-                            .get_accounts_for_user(token, user_id, input.offset, input.limit)
+                        transactions_service
+                            .list_accounts_with_balances(token, user_id, input.offset, input.limit)
                             .map_err(ectx!(convert => input_clone))
                     })
             })
             .and_then(|accounts| {
-                let accounts: Vec<AccountsResponse> = accounts.into_iter().map(From::from).collect();
+                let accounts: Vec<AccountWithBalanceResponse> = accounts.into_iter().map(From::from).collect();
                 response_with_model(&accounts)
             }),
     )
@@ -117,3 +121,20 @@ pub fn delete_accounts(ctx: &Context, account_id: AccountId) -> ControllerFuture
             }),
     )
 }
+
+pub fn get_addresses_is_internal(ctx: &Context, address: String) -> ControllerFuture {
+    let accounts_service = ctx.accounts_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    let address = BlockchainAddress::new(address);
+    Box::new(
+        maybe_token
+            .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+            .into_future()
+            .and_then(move |token| {
+                accounts_service
+                    .is_internal_address(token, address.clone())
+                    .map_err(ectx!(convert => address))
+                    .and_then(|is_internal| response_with_model(&IsInternalAddressResponse { is_internal }))
+            }),
+    )
+}
@@ -1,7 +1,16 @@
+use chrono::Duration;
+use failure::Fail;
+use futures::prelude::*;
+
 use super::super::utils::response_with_model;
 use super::Context;
 use super::ControllerFuture;
+use api::error::*;
+use api::requests::*;
+use api::responses::*;
+use models::*;
 use prelude::*;
+use serde_qs;
 
 pub fn get_metrics(ctx: &Context) -> ControllerFuture {
     let metrics_service = ctx.metrics_service.clone();
@@ -12,3 +21,152 @@ pub fn get_metrics(ctx: &Context) -> ControllerFuture {
             .and_then(|metrics| response_with_model(&metrics)),
     )
 }
+
+pub fn get_system_reconcile(ctx: &Context) -> ControllerFuture {
+    let metrics_service = ctx.metrics_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    let path_and_query = ctx.uri.path_and_query();
+    let path_and_query_clone = ctx.uri.path_and_query();
+    Box::new(
+        ctx.uri
+            .query()
+            .ok_or(ectx!(err ErrorContext::RequestMissingQuery, ErrorKind::BadRequest => path_and_query))
+            .and_then(|query| {
+                serde_qs::from_str::<GetSystemReconcileParams>(query).map_err(|e| {
+                    let e = format_err!("{}", e);
+                    ectx!(err e, ErrorContext::RequestQueryParams, ErrorKind::BadRequest => path_and_query_clone)
+                })
+            })
+            .into_future()
+            .and_then(move |input| {
+                maybe_token
+                    .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+                    .into_future()
+                    .and_then(move |token| {
+                        metrics_service
+                            .get_reconciliation_report(token, input.currency)
+                            .map_err(ectx!(convert))
+                    })
+            })
+            .and_then(|report| response_with_model(&report)),
+    )
+}
+
+pub fn get_system_fees(ctx: &Context) -> ControllerFuture {
+    let metrics_service = ctx.metrics_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    let path_and_query = ctx.uri.path_and_query();
+    let path_and_query_clone = ctx.uri.path_and_query();
+    Box::new(
+        ctx.uri
+            .query()
+            .ok_or(ectx!(err ErrorContext::RequestMissingQuery, ErrorKind::BadRequest => path_and_query))
+            .and_then(|query| {
+                serde_qs::from_str::<GetSystemFeesParams>(query).map_err(|e| {
+                    let e = format_err!("{}", e);
+                    ectx!(err e, ErrorContext::RequestQueryParams, ErrorKind::BadRequest => path_and_query_clone)
+                })
+            })
+            .into_future()
+            .and_then(move |input| {
+                maybe_token
+                    .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+                    .into_future()
+                    .and_then(move |token| {
+                        let input_clone = input.clone();
+                        metrics_service
+                            .get_fees_collected(token, input.currency, input.from, input.to)
+                            .map_err(ectx!(convert => input_clone))
+                    })
+            })
+            .and_then(|fees| response_with_model(&fees)),
+    )
+}
+
+pub fn get_system_accounts_by_address(ctx: &Context, address: String) -> ControllerFuture {
+    let metrics_service = ctx.metrics_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    let address = BlockchainAddress::new(address);
+    Box::new(
+        maybe_token
+            .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+            .into_future()
+            .and_then(move |token| {
+                metrics_service
+                    .get_accounts_by_address(token, address.clone())
+                    .map_err(ectx!(convert => address))
+            })
+            .and_then(|accounts| {
+                let accounts: Vec<AccountsResponse> = accounts.into_iter().map(From::from).collect();
+                response_with_model(&accounts)
+            }),
+    )
+}
+
+pub fn get_system_strange_transactions(ctx: &Context) -> ControllerFuture {
+    let metrics_service = ctx.metrics_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    let path_and_query = ctx.uri.path_and_query();
+    let path_and_query_clone = ctx.uri.path_and_query();
+    Box::new(
+        ctx.uri
+            .query()
+            .ok_or(ectx!(err ErrorContext::RequestMissingQuery, ErrorKind::BadRequest => path_and_query))
+            .and_then(|query| {
+                serde_qs::from_str::<GetSystemStrangeTransactionsParams>(query).map_err(|e| {
+                    let e = format_err!("{}", e);
+                    ectx!(err e, ErrorContext::RequestQueryParams, ErrorKind::BadRequest => path_and_query_clone)
+                })
+            })
+            .into_future()
+            .and_then(move |input| {
+                maybe_token
+                    .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+                    .into_future()
+                    .and_then(move |token| {
+                        let input_clone = input.clone();
+                        metrics_service
+                            .list_strange_transactions(token, input.offset, input.limit, input.currency)
+                            .map_err(ectx!(convert => input_clone))
+                    })
+            })
+            .and_then(|transactions| {
+                let transactions: Vec<StrangeTransactionResponse> = transactions.into_iter().map(From::from).collect();
+                response_with_model(&transactions)
+            }),
+    )
+}
+
+pub fn get_system_stuck_transactions(ctx: &Context) -> ControllerFuture {
+    let metrics_service = ctx.metrics_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    let path_and_query = ctx.uri.path_and_query();
+    let path_and_query_clone = ctx.uri.path_and_query();
+    Box::new(
+        ctx.uri
+            .query()
+            .ok_or(ectx!(err ErrorContext::RequestMissingQuery, ErrorKind::BadRequest => path_and_query))
+            .and_then(|query| {
+                serde_qs::from_str::<GetSystemStuckTransactionsParams>(query).map_err(|e| {
+                    let e = format_err!("{}", e);
+                    ectx!(err e, ErrorContext::RequestQueryParams, ErrorKind::BadRequest => path_and_query_clone)
+                })
+            })
+            .into_future()
+            .and_then(move |input| {
+                maybe_token
+                    .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+                    .into_future()
+                    .and_then(move |token| {
+                        let input_clone = input.clone();
+                        metrics_service
+                            .list_stuck_transactions(token, Duration::minutes(input.older_than_minutes as i64), input.limit)
+                            .map_err(ectx!(convert => input_clone))
+                    })
+            })
+            .and_then(|transactions| {
+                let transactions: Vec<TransactionLegResponse> = transactions.into_iter().map(From::from).collect();
+                response_with_model(&transactions)
+            }),
+    )
+}
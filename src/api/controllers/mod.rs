@@ -5,6 +5,7 @@ use futures::prelude::*;
 use hyper::{header::HeaderValue, header::AUTHORIZATION, Body, HeaderMap, Method, Response, Uri};
 
 use super::error::*;
+use super::responses::ApiVersion;
 use models::*;
 use services::{AccountsService, ExchangeService, FeesService, MetricsService, TransactionsService, UsersService};
 
@@ -38,6 +39,7 @@ pub struct Context {
     pub exchange_service: Arc<dyn ExchangeService>,
     pub metrics_service: Arc<dyn MetricsService>,
     pub fees_service: Arc<dyn FeesService>,
+    pub max_list_limit: i64,
 }
 
 impl Context {
@@ -55,6 +57,15 @@ impl Context {
             })
             .map(AuthenticationToken::new)
     }
+
+    /// Version of responses requested in `Accept-Version` header, latest one if absent or unknown
+    pub fn get_api_version(&self) -> ApiVersion {
+        self.headers
+            .get("Accept-Version")
+            .and_then(|header| header.to_str().ok())
+            .and_then(ApiVersion::from_header)
+            .unwrap_or_default()
+    }
 }
 
 impl Display for Context {
@@ -1,7 +1,9 @@
 use failure::Fail;
 use futures::prelude::*;
+use futures::stream;
+use hyper::{Body, Response};
 
-use super::super::utils::{parse_body, response_with_model};
+use super::super::utils::{clamp_pagination, parse_body, response_with_model};
 use super::Context;
 use super::ControllerFuture;
 use api::error::*;
@@ -9,10 +11,12 @@ use api::requests::*;
 use api::responses::*;
 use models::*;
 use serde_qs;
+use utils::log_error;
 
 pub fn post_transactions(ctx: &Context) -> ControllerFuture {
     let transactions_service = ctx.transactions_service.clone();
     let maybe_token = ctx.get_auth_token();
+    let api_version = ctx.get_api_version();
     let body = ctx.body.clone();
     Box::new(
         maybe_token
@@ -24,8 +28,8 @@ pub fn post_transactions(ctx: &Context) -> ControllerFuture {
                     transactions_service
                         .create_transaction(token, input.into())
                         .map_err(ectx!(convert => input_clone))
-                        .and_then(|transaction| {
-                            let resp: TransactionsResponse = transaction.into();
+                        .and_then(move |transaction| {
+                            let resp = VersionedTransactionsResponse::new(transaction, api_version);
                             response_with_model(&resp)
                         })
                 })
@@ -33,9 +37,127 @@ pub fn post_transactions(ctx: &Context) -> ControllerFuture {
     )
 }
 
+pub fn post_transactions_transfer_and_withdraw(ctx: &Context) -> ControllerFuture {
+    let transactions_service = ctx.transactions_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    let api_version = ctx.get_api_version();
+    let body = ctx.body.clone();
+    Box::new(
+        maybe_token
+            .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+            .into_future()
+            .and_then(move |token| {
+                parse_body::<PostTransactionsTransferAndWithdrawRequest>(body).and_then(move |input| {
+                    let input_clone = input.clone();
+                    transactions_service
+                        .create_transfer_and_withdrawal(token, input.transaction.into(), input.funding_account_id)
+                        .map_err(ectx!(convert => input_clone))
+                        .and_then(move |transaction| {
+                            let resp = VersionedTransactionsResponse::new(transaction, api_version);
+                            response_with_model(&resp)
+                        })
+                })
+            }),
+    )
+}
+
+pub fn post_transactions_batch(ctx: &Context) -> ControllerFuture {
+    let transactions_service = ctx.transactions_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    let api_version = ctx.get_api_version();
+    let body = ctx.body.clone();
+    Box::new(
+        maybe_token
+            .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+            .into_future()
+            .and_then(move |token| {
+                parse_body::<PostTransactionsBatchRequest>(body).and_then(move |input| {
+                    let input_clone = input.clone();
+                    transactions_service
+                        .create_batch_transaction(token, input.into())
+                        .map_err(ectx!(convert => input_clone))
+                        .and_then(move |transaction| {
+                            let resp = VersionedTransactionsResponse::new(transaction, api_version);
+                            response_with_model(&resp)
+                        })
+                })
+            }),
+    )
+}
+
+pub fn post_transactions_split(ctx: &Context) -> ControllerFuture {
+    let transactions_service = ctx.transactions_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    let api_version = ctx.get_api_version();
+    let body = ctx.body.clone();
+    Box::new(
+        maybe_token
+            .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+            .into_future()
+            .and_then(move |token| {
+                parse_body::<PostTransactionsBatchRequest>(body).and_then(move |input| {
+                    let input_clone = input.clone();
+                    transactions_service
+                        .create_split_transaction(token, input.into())
+                        .map_err(ectx!(convert => input_clone))
+                        .and_then(move |transaction| {
+                            let resp = VersionedTransactionsResponse::new(transaction, api_version);
+                            response_with_model(&resp)
+                        })
+                })
+            }),
+    )
+}
+
+/// Whole history of the user as csv, pages of the listing are fetched one by one while the response is sent.
+/// Every page starts right after the previous one, so transactions created meanwhile don't shift the pages
+pub fn get_users_transactions_export(ctx: &Context, user_id: UserId) -> ControllerFuture {
+    let transactions_service = ctx.transactions_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    let page_size = ctx.max_list_limit.max(1);
+    Box::new(
+        maybe_token
+            .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+            .into_future()
+            .and_then(move |token| {
+                // the first page is fetched before responding, so that e.g. a wrong token gets a proper status
+                transactions_service
+                    .get_transactions_for_user_after(token.clone(), user_id, None, page_size)
+                    .map_err(ectx!(convert => user_id))
+                    .map(move |(first_page, next)| {
+                        let next_pages = stream::unfold(next, move |next| {
+                            next.map(|after| {
+                                transactions_service
+                                    .get_transactions_for_user_after(token.clone(), user_id, Some(after), page_size)
+                                    .map_err(ectx!(convert => user_id, after))
+                            })
+                        });
+                        let rows = stream::once(Ok(first_page))
+                            .chain(next_pages)
+                            .map(|page| page.iter().map(transaction_csv_row).collect::<String>());
+                        let body = stream::once::<_, Error>(Ok(TRANSACTIONS_CSV_HEADER.to_string()))
+                            .chain(rows)
+                            .map_err(|e| {
+                                // the status is already sent, so the client only sees the connection closed
+                                log_error(&e);
+                                e.compat()
+                            });
+                        Response::builder()
+                            .status(200)
+                            .header("Content-Type", "text/csv")
+                            .header("Content-Disposition", "attachment; filename=\"transactions.csv\"")
+                            .body(Body::wrap_stream(body))
+                            .unwrap()
+                    })
+            }),
+    )
+}
+
 pub fn get_users_transactions(ctx: &Context, user_id: UserId) -> ControllerFuture {
     let transactions_service = ctx.transactions_service.clone();
     let maybe_token = ctx.get_auth_token();
+    let max_list_limit = ctx.max_list_limit;
+    let api_version = ctx.get_api_version();
     let path_and_query = ctx.uri.path_and_query();
     let path_and_query_clone = ctx.uri.path_and_query();
     Box::new(
@@ -48,6 +170,13 @@ pub fn get_users_transactions(ctx: &Context, user_id: UserId) -> ControllerFutur
                     ectx!(err e, ErrorContext::RequestQueryParams, ErrorKind::BadRequest => path_and_query_clone)
                 })
             })
+            .and_then(move |input| {
+                clamp_pagination(input.offset, input.limit, max_list_limit).map(|(offset, limit)| GetUsersTransactionsParams {
+                    offset,
+                    limit,
+                    ..input
+                })
+            })
             .into_future()
             .and_then(move |input| {
                 maybe_token
@@ -56,12 +185,15 @@ pub fn get_users_transactions(ctx: &Context, user_id: UserId) -> ControllerFutur
                     .and_then(move |token| {
                         let input_clone = input.clone();
                         transactions_service
-                            .get_transactions_for_user(token, user_id, input.offset, input.limit)
+                            .get_transactions_for_user(token, user_id, input.category.clone(), input.offset, input.limit)
                             .map_err(ectx!(convert => input_clone))
                     })
             })
-            .and_then(|transactions| {
-                let transactions: Vec<TransactionsResponse> = transactions.into_iter().map(From::from).collect();
+            .and_then(move |transactions| {
+                let transactions: Vec<VersionedTransactionsResponse> = transactions
+                    .into_iter()
+                    .map(|transaction| VersionedTransactionsResponse::new(transaction, api_version))
+                    .collect();
                 response_with_model(&transactions)
             }),
     )
@@ -70,6 +202,7 @@ pub fn get_users_transactions(ctx: &Context, user_id: UserId) -> ControllerFutur
 pub fn get_transactions(ctx: &Context, transaction_id: TransactionId) -> ControllerFuture {
     let transactions_service = ctx.transactions_service.clone();
     let maybe_token = ctx.get_auth_token();
+    let api_version = ctx.get_api_version();
     Box::new(
         maybe_token
             .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
@@ -78,7 +211,102 @@ pub fn get_transactions(ctx: &Context, transaction_id: TransactionId) -> Control
                 transactions_service
                     .get_transaction(token, transaction_id)
                     .map_err(ectx!(convert))
-                    .and_then(|transaction| response_with_model(&transaction.map(TransactionsResponse::from)))
+                    .and_then(move |transaction| {
+                        response_with_model(&transaction.map(|transaction| VersionedTransactionsResponse::new(transaction, api_version)))
+                    })
+            }),
+    )
+}
+
+pub fn post_transactions_cancel(ctx: &Context, transaction_id: TransactionId) -> ControllerFuture {
+    let transactions_service = ctx.transactions_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    let api_version = ctx.get_api_version();
+    Box::new(
+        maybe_token
+            .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+            .into_future()
+            .and_then(move |token| {
+                transactions_service
+                    .cancel_scheduled_transaction(token, transaction_id)
+                    .map_err(ectx!(convert))
+                    .and_then(move |transaction| response_with_model(&VersionedTransactionsResponse::new(transaction, api_version)))
+            }),
+    )
+}
+
+pub fn get_transactions_group_legs(ctx: &Context, gid: TransactionId) -> ControllerFuture {
+    let transactions_service = ctx.transactions_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    Box::new(
+        maybe_token
+            .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+            .into_future()
+            .and_then(move |token| {
+                transactions_service
+                    .get_transaction_legs(token, gid)
+                    .map_err(ectx!(convert => gid))
+                    .and_then(|legs| {
+                        let legs: Vec<TransactionLegResponse> = legs.into_iter().map(From::from).collect();
+                        response_with_model(&legs)
+                    })
+            }),
+    )
+}
+
+pub fn get_transactions_history(ctx: &Context, transaction_id: TransactionId) -> ControllerFuture {
+    let transactions_service = ctx.transactions_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    Box::new(
+        maybe_token
+            .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+            .into_future()
+            .and_then(move |token| {
+                transactions_service
+                    .get_transaction_status_history(token, transaction_id)
+                    .map_err(ectx!(convert => transaction_id))
+                    .and_then(|history| {
+                        let history: Vec<TransactionStatusChangeResponse> = history.into_iter().map(From::from).collect();
+                        response_with_model(&history)
+                    })
+            }),
+    )
+}
+
+pub fn get_transactions_related(ctx: &Context, transaction_id: TransactionId) -> ControllerFuture {
+    let transactions_service = ctx.transactions_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    Box::new(
+        maybe_token
+            .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+            .into_future()
+            .and_then(move |token| {
+                transactions_service
+                    .get_related_transactions(token, transaction_id)
+                    .map_err(ectx!(convert => transaction_id))
+                    .and_then(|legs| {
+                        let legs: Vec<TransactionLegResponse> = legs.into_iter().map(From::from).collect();
+                        response_with_model(&legs)
+                    })
+            }),
+    )
+}
+
+pub fn get_system_transactions_raw(ctx: &Context, transaction_id: TransactionId) -> ControllerFuture {
+    let transactions_service = ctx.transactions_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    Box::new(
+        maybe_token
+            .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+            .into_future()
+            .and_then(move |token| {
+                transactions_service
+                    .get_raw_transactions(token, transaction_id)
+                    .map_err(ectx!(convert => transaction_id))
+                    .and_then(|transactions| {
+                        let transactions: Vec<SignedBlockchainTransactionResponse> = transactions.into_iter().map(From::from).collect();
+                        response_with_model(&transactions)
+                    })
             }),
     )
 }
@@ -86,6 +314,8 @@ pub fn get_transactions(ctx: &Context, transaction_id: TransactionId) -> Control
 pub fn get_accounts_transactions(ctx: &Context, account_id: AccountId) -> ControllerFuture {
     let transactions_service = ctx.transactions_service.clone();
     let maybe_token = ctx.get_auth_token();
+    let max_list_limit = ctx.max_list_limit;
+    let api_version = ctx.get_api_version();
     let path_and_query = ctx.uri.path_and_query();
     let path_and_query_clone = ctx.uri.path_and_query();
     Box::new(
@@ -93,11 +323,18 @@ pub fn get_accounts_transactions(ctx: &Context, account_id: AccountId) -> Contro
             .query()
             .ok_or(ectx!(err ErrorContext::RequestMissingQuery, ErrorKind::BadRequest => path_and_query))
             .and_then(|query| {
-                serde_qs::from_str::<GetUsersTransactionsParams>(query).map_err(|e| {
+                serde_qs::from_str::<GetAccountTransactionsParams>(query).map_err(|e| {
                     let e = format_err!("{}", e);
                     ectx!(err e, ErrorContext::RequestQueryParams, ErrorKind::BadRequest => path_and_query_clone)
                 })
             })
+            .and_then(move |input| {
+                clamp_pagination(input.offset, input.limit, max_list_limit).map(|(offset, limit)| GetAccountTransactionsParams {
+                    offset,
+                    limit,
+                    ..input
+                })
+            })
             .into_future()
             .and_then(move |input| {
                 maybe_token
@@ -105,18 +342,97 @@ pub fn get_accounts_transactions(ctx: &Context, account_id: AccountId) -> Contro
                     .into_future()
                     .and_then(move |token| {
                         transactions_service
-                            .get_account_transactions(token, account_id, input.offset, input.limit)
+                            .get_account_transactions(token, account_id, input.kind, input.category, input.offset, input.limit)
                             .map_err(ectx!(convert))
                     })
             })
-            .and_then(|transactions| {
-                let transactions: Vec<TransactionsResponse> = transactions.into_iter().map(From::from).collect();
+            .and_then(move |transactions| {
+                let transactions: Vec<VersionedTransactionsResponse> = transactions
+                    .into_iter()
+                    .map(|transaction| VersionedTransactionsResponse::new(transaction, api_version))
+                    .collect();
                 response_with_model(&transactions)
             }),
     )
 }
 
+pub fn get_users_exchanges(ctx: &Context, user_id: UserId) -> ControllerFuture {
+    let transactions_service = ctx.transactions_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    let path_and_query = ctx.uri.path_and_query();
+    let path_and_query_clone = ctx.uri.path_and_query();
+    Box::new(
+        ctx.uri
+            .query()
+            .ok_or(ectx!(err ErrorContext::RequestMissingQuery, ErrorKind::BadRequest => path_and_query))
+            .and_then(|query| {
+                serde_qs::from_str::<GetUsersExchangesParams>(query).map_err(|e| {
+                    let e = format_err!("{}", e);
+                    ectx!(err e, ErrorContext::RequestQueryParams, ErrorKind::BadRequest => path_and_query_clone)
+                })
+            })
+            .into_future()
+            .and_then(move |input| {
+                maybe_token
+                    .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+                    .into_future()
+                    .and_then(move |token| {
+                        let input_clone = input.clone();
+                        transactions_service
+                            .get_exchange_history(token, user_id, input.from, input.to)
+                            .map_err(ectx!(convert => input_clone))
+                    })
+            })
+            .and_then(|exchanges| response_with_model(&exchanges)),
+    )
+}
+
+pub fn get_users_summary(ctx: &Context, user_id: UserId) -> ControllerFuture {
+    let transactions_service = ctx.transactions_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    Box::new(
+        maybe_token
+            .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+            .into_future()
+            .and_then(move |token| {
+                transactions_service
+                    .get_user_summary(token, user_id)
+                    .map_err(ectx!(convert => user_id))
+                    .and_then(|summary| response_with_model(&summary))
+            }),
+    )
+}
+
 pub fn get_accounts_balances(ctx: &Context, account_id: AccountId) -> ControllerFuture {
+    let transactions_service = ctx.transactions_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    let path_and_query = ctx.uri.path_and_query();
+    // query is optional here
+    let query = ctx.uri.query().unwrap_or_default().to_string();
+    Box::new(
+        serde_qs::from_str::<GetAccountsBalancesParams>(&query)
+            .map_err(|e| {
+                let e = format_err!("{}", e);
+                ectx!(err e, ErrorContext::RequestQueryParams, ErrorKind::BadRequest => path_and_query)
+            })
+            .into_future()
+            .and_then(move |input| {
+                maybe_token
+                    .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+                    .into_future()
+                    .and_then(move |token| {
+                        let balance = match input.as_of {
+                            Some(as_of) => transactions_service.get_account_balance_at(token, account_id, as_of),
+                            None => transactions_service.get_account_balance(token, account_id, input.strict),
+                        };
+                        balance.map_err(ectx!(convert))
+                    })
+            })
+            .and_then(|account_with_balance| response_with_model(&account_with_balance)),
+    )
+}
+
+pub fn post_system_accounts_recompute_balance(ctx: &Context, account_id: AccountId) -> ControllerFuture {
     let transactions_service = ctx.transactions_service.clone();
     let maybe_token = ctx.get_auth_token();
     Box::new(
@@ -125,9 +441,363 @@ pub fn get_accounts_balances(ctx: &Context, account_id: AccountId) -> Controller
             .into_future()
             .and_then(move |token| {
                 transactions_service
-                    .get_account_balance(token, account_id)
-                    .map_err(ectx!(convert))
+                    .recompute_account_balance(token, account_id)
+                    .map_err(ectx!(convert => account_id))
                     .and_then(|account_with_balance| response_with_model(&account_with_balance))
             }),
     )
 }
+
+pub fn post_system_deposits(ctx: &Context) -> ControllerFuture {
+    let transactions_service = ctx.transactions_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    let api_version = ctx.get_api_version();
+    let body = ctx.body.clone();
+    Box::new(
+        maybe_token
+            .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+            .into_future()
+            .and_then(move |token| {
+                parse_body::<PostSystemDepositsRequest>(body).and_then(move |input| {
+                    let input_clone = input.clone();
+                    transactions_service
+                        .manual_deposit(token, input.account_id, input.currency, input.value, input.blockchain_tx_id)
+                        .map_err(ectx!(convert => input_clone))
+                        .and_then(move |transaction| {
+                            let resp = VersionedTransactionsResponse::new(transaction, api_version);
+                            response_with_model(&resp)
+                        })
+                })
+            }),
+    )
+}
+
+pub fn get_system_resolver(ctx: &Context) -> ControllerFuture {
+    let transactions_service = ctx.transactions_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    Box::new(
+        maybe_token
+            .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+            .into_future()
+            .and_then(move |token| {
+                transactions_service
+                    .get_resolver_enabled(token)
+                    .map_err(ectx!(convert))
+                    .and_then(|enabled| response_with_model(&ResolverStatusResponse { enabled }))
+            }),
+    )
+}
+
+pub fn put_system_resolver(ctx: &Context) -> ControllerFuture {
+    let transactions_service = ctx.transactions_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    let body = ctx.body.clone();
+    Box::new(
+        maybe_token
+            .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+            .into_future()
+            .and_then(move |token| {
+                parse_body::<PutSystemResolverRequest>(body).and_then(move |input| {
+                    transactions_service
+                        .set_resolver_enabled(token, input.enabled)
+                        .map_err(ectx!(convert => input))
+                        .and_then(|enabled| response_with_model(&ResolverStatusResponse { enabled }))
+                })
+            }),
+    )
+}
+
+pub fn get_accounts_max_withdrawable(ctx: &Context, account_id: AccountId) -> ControllerFuture {
+    let transactions_service = ctx.transactions_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    let path_and_query = ctx.uri.path_and_query();
+    let path_and_query_clone = ctx.uri.path_and_query();
+    Box::new(
+        ctx.uri
+            .query()
+            .ok_or(ectx!(err ErrorContext::RequestMissingQuery, ErrorKind::BadRequest => path_and_query))
+            .and_then(|query| {
+                serde_qs::from_str::<GetAccountMaxWithdrawableParams>(query).map_err(|e| {
+                    let e = format_err!("{}", e);
+                    ectx!(err e, ErrorContext::RequestQueryParams, ErrorKind::BadRequest => path_and_query_clone)
+                })
+            })
+            .into_future()
+            .and_then(move |input| {
+                maybe_token
+                    .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+                    .into_future()
+                    .and_then(move |token| {
+                        let to_currency = input.to_currency;
+                        transactions_service
+                            .max_withdrawable(token, account_id, to_currency)
+                            .map_err(ectx!(convert => account_id, to_currency))
+                            .and_then(move |value| {
+                                response_with_model(&MaxWithdrawableResponse {
+                                    account_id,
+                                    currency: to_currency,
+                                    value,
+                                })
+                            })
+                    })
+            }),
+    )
+}
+
+pub fn get_system_transactions(ctx: &Context) -> ControllerFuture {
+    let transactions_service = ctx.transactions_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    let max_list_limit = ctx.max_list_limit;
+    let api_version = ctx.get_api_version();
+    let path_and_query = ctx.uri.path_and_query();
+    let path_and_query_clone = ctx.uri.path_and_query();
+    Box::new(
+        ctx.uri
+            .query()
+            .ok_or(ectx!(err ErrorContext::RequestMissingQuery, ErrorKind::BadRequest => path_and_query))
+            .and_then(|query| {
+                serde_qs::from_str::<GetSystemTransactionsParams>(query).map_err(|e| {
+                    let e = format_err!("{}", e);
+                    ectx!(err e, ErrorContext::RequestQueryParams, ErrorKind::BadRequest => path_and_query_clone)
+                })
+            })
+            .and_then(move |input| {
+                clamp_pagination(input.offset, input.limit, max_list_limit).map(|(offset, limit)| GetSystemTransactionsParams {
+                    offset,
+                    limit,
+                    ..input
+                })
+            })
+            .into_future()
+            .and_then(move |input| {
+                maybe_token
+                    .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+                    .into_future()
+                    .and_then(move |token| {
+                        let input_clone = input.clone();
+                        transactions_service
+                            .get_transactions_by_group_kind(token, input.group_kind, input.offset, input.limit)
+                            .map_err(ectx!(convert => input_clone))
+                    })
+            })
+            .and_then(move |transactions| {
+                let transactions: Vec<VersionedTransactionsResponse> = transactions
+                    .into_iter()
+                    .map(|transaction| VersionedTransactionsResponse::new(transaction, api_version))
+                    .collect();
+                response_with_model(&transactions)
+            }),
+    )
+}
+
+pub fn get_system_transactions_by_destination(ctx: &Context) -> ControllerFuture {
+    let transactions_service = ctx.transactions_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    let path_and_query = ctx.uri.path_and_query();
+    let path_and_query_clone = ctx.uri.path_and_query();
+    Box::new(
+        ctx.uri
+            .query()
+            .ok_or(ectx!(err ErrorContext::RequestMissingQuery, ErrorKind::BadRequest => path_and_query))
+            .and_then(|query| {
+                serde_qs::from_str::<GetSystemTransactionsByDestinationParams>(query).map_err(|e| {
+                    let e = format_err!("{}", e);
+                    ectx!(err e, ErrorContext::RequestQueryParams, ErrorKind::BadRequest => path_and_query_clone)
+                })
+            })
+            .into_future()
+            .and_then(move |input| {
+                maybe_token
+                    .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+                    .into_future()
+                    .and_then(move |token| {
+                        let input_clone = input.clone();
+                        transactions_service
+                            .find_transactions_to_address(token, input.address, input.currency, input.offset, input.limit)
+                            .map_err(ectx!(convert => input_clone))
+                    })
+            })
+            .and_then(|legs| {
+                let legs: Vec<TransactionLegResponse> = legs.into_iter().map(From::from).collect();
+                response_with_model(&legs)
+            }),
+    )
+}
+
+pub fn get_system_withdrawals_coin_selection(ctx: &Context) -> ControllerFuture {
+    let transactions_service = ctx.transactions_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    let path_and_query = ctx.uri.path_and_query();
+    let path_and_query_clone = ctx.uri.path_and_query();
+    Box::new(
+        ctx.uri
+            .query()
+            .ok_or(ectx!(err ErrorContext::RequestMissingQuery, ErrorKind::BadRequest => path_and_query))
+            .and_then(|query| {
+                serde_qs::from_str::<GetSystemCoinSelectionParams>(query).map_err(|e| {
+                    let e = format_err!("{}", e);
+                    ectx!(err e, ErrorContext::RequestQueryParams, ErrorKind::BadRequest => path_and_query_clone)
+                })
+            })
+            .into_future()
+            .and_then(move |input| {
+                maybe_token
+                    .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+                    .into_future()
+                    .and_then(move |token| {
+                        let input_clone = input.clone();
+                        transactions_service
+                            .preview_coin_selection(token, input.currency, input.value)
+                            .map_err(ectx!(convert => input_clone))
+                    })
+            })
+            .and_then(|sources| {
+                let sources: Vec<AccountWithBalanceResponse> = sources.into_iter().map(From::from).collect();
+                response_with_model(&sources)
+            }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use hyper::header::{HeaderValue, AUTHORIZATION};
+    use hyper::{HeaderMap, Method};
+    use tokio_core::reactor::Core;
+
+    use super::*;
+    use client::*;
+    use config::Config;
+    use rabbit::TransactionPublisherMock;
+    use repos::*;
+    use services::*;
+
+    fn create_context(
+        token: AuthenticationToken,
+        user_id: UserId,
+        transactions_repo: Arc<TransactionsRepoMock>,
+        accounts_repo: Arc<AccountsRepoMock>,
+    ) -> Context {
+        let config = Config::new().unwrap();
+        let auth_service = Arc::new(AuthServiceMock::new(vec![(token.clone(), user_id)]));
+        let db_executor = DbExecutorMock::default();
+        let keys_client = Arc::new(KeysClientMock::default());
+        let exchange_client = Arc::new(ExchangeClientMock::default());
+        let blockchain_client = Arc::new(BlockchainClientMock::default());
+        let pending_blockchain_transactions_repo = Arc::new(PendingBlockchainTransactionsRepoMock::default());
+        let strange_blockchain_transactions_repo = Arc::new(StrangeBlockchainTransactionsRepoMock::default());
+        let transactions_service = Arc::new(TransactionsServiceImpl::new(
+            config.clone(),
+            auth_service.clone(),
+            transactions_repo.clone(),
+            pending_blockchain_transactions_repo.clone(),
+            Arc::new(BlockchainTransactionsRepoMock::default()),
+            Arc::new(SeenHashesRepoMock::default()),
+            strange_blockchain_transactions_repo.clone(),
+            accounts_repo.clone(),
+            Arc::new(KeyValuesRepoMock::default()),
+            db_executor.clone(),
+            keys_client.clone(),
+            blockchain_client.clone(),
+            exchange_client.clone(),
+            Arc::new(TransactionPublisherMock::default()),
+            Arc::new(MockClock::default()),
+        ));
+        let system_service = Arc::new(SystemServiceImpl::new(
+            accounts_repo.clone(),
+            transactions_repo.clone(),
+            pending_blockchain_transactions_repo.clone(),
+            Arc::new(config.clone()),
+            Arc::new(MockClock::default()),
+        ));
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, HeaderValue::from_str(&format!("Bearer {}", token.raw())).unwrap());
+        Context {
+            body: vec![],
+            method: Method::GET,
+            uri: format!("/v1/users/{}/transactions/export", user_id).parse().unwrap(),
+            headers,
+            users_service: Arc::new(UsersServiceImpl::new(Arc::new(UsersRepoMock::default()), db_executor.clone())),
+            accounts_service: Arc::new(AccountsServiceImpl::new(
+                auth_service.clone(),
+                accounts_repo.clone(),
+                db_executor.clone(),
+                keys_client,
+                config.system.enabled_currencies.clone(),
+            )),
+            transactions_service,
+            exchange_service: Arc::new(ExchangeServiceImpl::new(exchange_client.clone())),
+            metrics_service: Arc::new(MetricsServiceImpl::new(
+                Arc::new(config.clone()),
+                auth_service,
+                accounts_repo.clone(),
+                transactions_repo,
+                pending_blockchain_transactions_repo,
+                strange_blockchain_transactions_repo,
+                db_executor.clone(),
+                blockchain_client,
+                system_service,
+                Arc::new(MockClock::default()),
+            )),
+            fees_service: Arc::new(FeesServiceImpl::new(
+                &config,
+                accounts_repo,
+                db_executor,
+                exchange_client,
+                Arc::new(FeesClientMock::default()),
+            )),
+            max_list_limit: 2,
+        }
+    }
+
+    fn create_transfer(transactions_repo: &TransactionsRepoMock, user_id: UserId, from: &Account, to: &Account) -> TransactionId {
+        let mut transfer = NewTransaction::default();
+        transfer.user_id = user_id;
+        transfer.dr_account_id = from.id;
+        transfer.cr_account_id = to.id;
+        transfer.value = Amount::new(100);
+        transfer.status = TransactionStatus::Done;
+        transactions_repo.create(transfer).unwrap().gid
+    }
+
+    #[test]
+    fn test_get_users_transactions_export() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::new("user_token".to_string());
+        let user_id = UserId::generate();
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let from = accounts_repo
+            .create(NewAccount {
+                user_id,
+                ..Default::default()
+            })
+            .unwrap();
+        let to = accounts_repo.create(NewAccount::default()).unwrap();
+        let gids: HashSet<TransactionId> = (0..5).map(|_| create_transfer(&transactions_repo, user_id, &from, &to)).collect();
+        let ctx = create_context(token, user_id, transactions_repo.clone(), accounts_repo);
+
+        // the first page is read before responding, the rest of 3 pages while the body is sent
+        let response = core.run(get_users_transactions_export(&ctx, user_id)).unwrap();
+        assert_eq!(response.status(), 200);
+        // transactions created during the export don't shift the pages
+        thread::sleep(Duration::from_millis(10));
+        for _ in 0..3 {
+            create_transfer(&transactions_repo, user_id, &from, &to);
+        }
+        let body = core.run(response.into_body().concat2()).unwrap();
+        let csv = String::from_utf8(body.to_vec()).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], TRANSACTIONS_CSV_HEADER.trim_end());
+        let exported: Vec<TransactionId> = lines[1..]
+            .iter()
+            .map(|line| line.split(',').next().unwrap().parse().unwrap())
+            .collect();
+        assert_eq!(exported.len(), gids.len());
+        assert_eq!(exported.into_iter().collect::<HashSet<_>>(), gids);
+    }
+}
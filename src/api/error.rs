@@ -1,4 +1,5 @@
 use failure::{Backtrace, Context, Fail};
+use serde_json::{self, Value};
 use services::ErrorKind as ServiceErrorKind;
 use std::fmt;
 use std::fmt::Display;
@@ -21,6 +22,10 @@ pub enum ErrorKind {
     Internal,
     #[fail(display = "controller error - not found")]
     NotFound,
+    #[fail(display = "controller error - service unavailable")]
+    ServiceUnavailable,
+    #[fail(display = "controller error - too many requests")]
+    TooManyRequests,
 }
 
 #[allow(dead_code)]
@@ -47,6 +52,8 @@ pub enum ErrorContext {
     RequestMissingQuery,
     #[fail(display = "controller context - failed to extract query params")]
     RequestQueryParams,
+    #[fail(display = "controller context - requested url not found")]
+    UnknownRoute,
 }
 
 derive_error_impls!();
@@ -59,6 +66,68 @@ impl From<ServiceErrorKind> for ErrorKind {
             ServiceErrorKind::MalformedInput => ErrorKind::BadRequest,
             ServiceErrorKind::NotFound => ErrorKind::NotFound,
             ServiceErrorKind::InvalidInput(s) => ErrorKind::UnprocessableEntity(s),
+            ServiceErrorKind::Balance(s) => ErrorKind::UnprocessableEntity(s),
+            ServiceErrorKind::ExchangeUnavailable => ErrorKind::ServiceUnavailable,
+            ServiceErrorKind::SignerUnavailable => ErrorKind::ServiceUnavailable,
+            ServiceErrorKind::RateRejected(s) => ErrorKind::UnprocessableEntity(s),
+            ServiceErrorKind::TooManyRequests => ErrorKind::TooManyRequests,
+            ServiceErrorKind::ShuttingDown => ErrorKind::ServiceUnavailable,
         }
     }
 }
+
+impl ErrorKind {
+    pub fn status(&self) -> u16 {
+        match self {
+            ErrorKind::BadRequest => 400,
+            ErrorKind::Unauthorized => 401,
+            ErrorKind::NotFound => 404,
+            ErrorKind::UnprocessableEntity(_) => 422,
+            ErrorKind::TooManyRequests => 429,
+            ErrorKind::Internal => 500,
+            ErrorKind::ServiceUnavailable => 503,
+        }
+    }
+
+    /// Stable code for clients to match on. Validation errors are reported with the code of the
+    /// first failed validation, e.g. `not_enough_balance`
+    pub fn code(&self) -> String {
+        match self {
+            ErrorKind::BadRequest => "bad_request".to_string(),
+            ErrorKind::Unauthorized => "unauthorized".to_string(),
+            ErrorKind::NotFound => "not_found".to_string(),
+            ErrorKind::UnprocessableEntity(errors) => first_validation_error(errors)
+                .and_then(|error| error.get("code").and_then(Value::as_str).map(String::from))
+                .unwrap_or_else(|| "unprocessable_entity".to_string()),
+            ErrorKind::TooManyRequests => "too_many_requests".to_string(),
+            ErrorKind::Internal => "internal_error".to_string(),
+            ErrorKind::ServiceUnavailable => "service_unavailable".to_string(),
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            ErrorKind::BadRequest => "Bad request".to_string(),
+            ErrorKind::Unauthorized => "Unauthorized".to_string(),
+            ErrorKind::NotFound => "Not found".to_string(),
+            ErrorKind::UnprocessableEntity(errors) => first_validation_error(errors)
+                .and_then(|error| error.get("message").and_then(Value::as_str).map(String::from))
+                .unwrap_or_else(|| "Unprocessable entity".to_string()),
+            ErrorKind::TooManyRequests => "Too many requests".to_string(),
+            ErrorKind::Internal => "Internal server error".to_string(),
+            ErrorKind::ServiceUnavailable => "Service unavailable".to_string(),
+        }
+    }
+}
+
+// validation errors are serialized `ValidationErrors`, i.e. `{"field": [{"code": .., "message": .., "params": ..}]}`
+fn first_validation_error(errors: &str) -> Option<Value> {
+    let errors: Value = serde_json::from_str(errors).ok()?;
+    errors
+        .as_object()?
+        .values()
+        .filter_map(Value::as_array)
+        .flat_map(|field_errors| field_errors.iter())
+        .find(|error| error.get("code").is_some())
+        .cloned()
+}
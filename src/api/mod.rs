@@ -4,12 +4,16 @@ use std::sync::Arc;
 use diesel::pg::PgConnection;
 use diesel::r2d2::ConnectionManager;
 use failure::{Compat, Fail};
+use futures::future::{self, Either};
 use futures::prelude::*;
 use futures_cpupool::CpuPool;
 use hyper;
+use hyper::header::{HeaderMap, HeaderValue, CONTENT_TYPE};
 use hyper::Server;
 use hyper::{service::Service, Body, Request, Response};
 use r2d2;
+use serde_json;
+use uuid::Uuid;
 
 use super::config::Config;
 use super::utils::{log_and_capture_error, log_error, log_warn};
@@ -23,37 +27,46 @@ pub mod utils;
 
 use self::controllers::*;
 use self::error::*;
+use self::responses::ApiErrorResponse;
 use client::{
-    BlockchainClient, BlockchainClientImpl, ExchangeClient, ExchangeClientImpl, FeesClient, FeesClientImpl, HttpClientImpl, KeysClient,
-    KeysClientImpl,
+    create_keys_client, BlockchainClient, BlockchainClientImpl, ConcurrencyLimitedBlockchainClient, ExchangeClient, ExchangeClientImpl,
+    FeesClient, FeesClientImpl, HttpClientImpl, KeysClient,
 };
 use models::*;
 use prelude::*;
 use rabbit::TransactionPublisher;
 use repos::{
     AccountsRepoImpl, BlockchainTransactionsRepoImpl, DbExecutorImpl, KeyValuesRepoImpl, PendingBlockchainTransactionsRepoImpl,
-    StrangeBlockchainTransactionsRepoImpl, TransactionsRepoImpl, UsersRepoImpl,
+    SeenHashesRepoImpl, StrangeBlockchainTransactionsRepoImpl, TransactionsRepoImpl, UsersRepoImpl,
 };
 use services::{
-    AccountsServiceImpl, AuthServiceImpl, ExchangeServiceImpl, FeesServiceImpl, MetricsServiceImpl, TransactionsServiceImpl,
-    UsersServiceImpl,
+    AccountsServiceImpl, AuthServiceImpl, ExchangeServiceImpl, FeesServiceImpl, InMemoryRateLimiter, MetricsServiceImpl, RateLimiter,
+    ShutdownCoordinator, SystemClock, SystemServiceImpl, TransactionsServiceImpl, UsersServiceImpl,
 };
 
+const REQUEST_ID_HEADER: &str = "X-Request-Id";
+const MAX_REQUEST_ID_LEN: usize = 128;
+
 #[derive(Clone)]
 pub struct ApiService {
     server_address: SocketAddr,
     config: Config,
     db_pool: PgPool,
+    read_db_pool: PgPool,
     cpu_pool: CpuPool,
     keys_client: Arc<dyn KeysClient>,
     blockchain_client: Arc<dyn BlockchainClient>,
     exchange_client: Arc<dyn ExchangeClient>,
     fees_client: Arc<dyn FeesClient>,
     publisher: Arc<dyn TransactionPublisher>,
+    // shared by all requests, so that it sees every transaction created by a user
+    create_transaction_rate_limiter: Arc<dyn RateLimiter>,
+    // shared by all requests, so that transactions of every request are drained on shutdown
+    shutdown: ShutdownCoordinator,
 }
 
 impl ApiService {
-    fn from_config(config: &Config, publisher: Arc<dyn TransactionPublisher>) -> Result<Self, Error> {
+    fn from_config(config: &Config, publisher: Arc<dyn TransactionPublisher>, shutdown: ShutdownCoordinator) -> Result<Self, Error> {
         let server_address = format!("{}:{}", config.server.host, config.server.port)
             .parse::<SocketAddr>()
             .map_err(ectx!(try
@@ -69,10 +82,24 @@ impl ApiService {
             ErrorKind::Internal =>
             database_url
         ))?;
+        let read_db_pool = match config.database.read_replica_url {
+            Some(ref read_replica_url) => {
+                let manager = ConnectionManager::<PgConnection>::new(read_replica_url.clone());
+                r2d2::Pool::builder().build(manager).map_err(ectx!(try
+                    ErrorContext::Config,
+                    ErrorKind::Internal =>
+                    read_replica_url
+                ))?
+            }
+            None => db_pool.clone(),
+        };
         let cpu_pool = CpuPool::new(config.cpu_pool.size);
         let client = HttpClientImpl::new(config);
-        let keys_client = KeysClientImpl::new(&config, client.clone());
-        let blockchain_client = BlockchainClientImpl::new(&config, client.clone());
+        let keys_client = create_keys_client(&config, client.clone());
+        let blockchain_client = ConcurrencyLimitedBlockchainClient::new(
+            Arc::new(BlockchainClientImpl::new(&config, client.clone())),
+            config.client.blockchain_max_in_flight,
+        );
         let exchange_client = ExchangeClientImpl::new(&config, client.clone());
         let fees_client = FeesClientImpl::new(&config, client);
 
@@ -80,12 +107,18 @@ impl ApiService {
             config: config.clone(),
             server_address,
             db_pool,
+            read_db_pool,
             cpu_pool,
-            keys_client: Arc::new(keys_client),
+            keys_client,
             blockchain_client: Arc::new(blockchain_client),
             exchange_client: Arc::new(exchange_client),
             fees_client: Arc::new(fees_client),
             publisher,
+            create_transaction_rate_limiter: Arc::new(InMemoryRateLimiter::new(
+                &config.create_transaction_rate_limit,
+                Arc::new(SystemClock),
+            )),
+            shutdown,
         })
     }
 }
@@ -98,14 +131,21 @@ impl Service for ApiService {
 
     fn call(&mut self, req: Request<Body>) -> Self::Future {
         let (parts, http_body) = req.into_parts();
+        let request_id = get_request_id(&parts.headers);
+        let response_request_id = request_id.clone();
+        let error_request_id = request_id.clone();
         let db_pool = self.db_pool.clone();
+        let read_db_pool = self.read_db_pool.clone();
         let cpu_pool = self.cpu_pool.clone();
         let keys_client = self.keys_client.clone();
         let blockchain_client = self.blockchain_client.clone();
         let exchange_client = self.exchange_client.clone();
         let publisher = self.publisher.clone();
         let fees_client = self.fees_client.clone();
+        let create_transaction_rate_limiter = self.create_transaction_rate_limiter.clone();
+        let shutdown = self.shutdown.clone();
         let db_executor = DbExecutorImpl::new(db_pool.clone(), cpu_pool.clone());
+        let read_db_executor = DbExecutorImpl::new(read_db_pool, cpu_pool.clone());
         let config = self.config.clone();
         Box::new(
             read_body(http_body)
@@ -120,20 +160,46 @@ impl Service for ApiService {
                         PUT /v1/accounts/{account_id: AccountId} => put_accounts,
                         DELETE /v1/accounts/{account_id: AccountId} => delete_accounts,
                         GET /v1/accounts/{account_id: AccountId}/balances => get_accounts_balances,
+                        GET /v1/accounts/{account_id: AccountId}/max_withdrawable => get_accounts_max_withdrawable,
                         GET /v1/accounts/{account_id: AccountId}/transactions => get_accounts_transactions,
+                        GET /v1/addresses/{address: String}/is_internal => get_addresses_is_internal,
                         GET /v1/users/{user_id: UserId}/transactions => get_users_transactions,
+                        GET /v1/users/{user_id: UserId}/transactions/export.csv => get_users_transactions_export,
+                        GET /v1/users/{user_id: UserId}/summary => get_users_summary,
+                        GET /v1/users/{user_id: UserId}/exchanges => get_users_exchanges,
                         POST /v1/transactions => post_transactions,
+                        POST /v1/transactions/batch => post_transactions_batch,
+                        POST /v1/transactions/split => post_transactions_split,
+                        POST /v1/transactions/transfer_and_withdraw => post_transactions_transfer_and_withdraw,
                         GET /v1/transactions/{transaction_id: TransactionId} => get_transactions,
+                        POST /v1/transactions/{transaction_id: TransactionId}/cancel => post_transactions_cancel,
+                        GET /v1/transactions/{transaction_id: TransactionId}/history => get_transactions_history,
+                        GET /v1/transactions/{transaction_id: TransactionId}/related => get_transactions_related,
+                        GET /v1/transactions/group/{gid: TransactionId}/legs => get_transactions_group_legs,
                         POST /v1/rate => post_rate,
                         POST /v1/rate/refresh => post_rate_refresh,
                         POST /v1/fees => post_fees,
                         GET /v1/metrics => get_metrics,
+                        GET /v1/system/reconcile => get_system_reconcile,
+                        GET /v1/system/fees => get_system_fees,
+                        GET /v1/system/transactions => get_system_transactions,
+                        GET /v1/system/transactions/by_destination => get_system_transactions_by_destination,
+                        GET /v1/system/withdrawals/coin_selection => get_system_withdrawals_coin_selection,
+                        GET /v1/system/transactions/{transaction_id: TransactionId}/raw => get_system_transactions_raw,
+                        GET /v1/system/strange_transactions => get_system_strange_transactions,
+                        GET /v1/system/stuck_transactions => get_system_stuck_transactions,
+                        GET /v1/system/accounts/by_address/{address: String} => get_system_accounts_by_address,
+                        POST /v1/system/accounts/{account_id: AccountId}/recompute_balance => post_system_accounts_recompute_balance,
+                        POST /v1/system/deposits => post_system_deposits,
+                        GET /v1/system/resolver => get_system_resolver,
+                        PUT /v1/system/resolver => put_system_resolver,
                         _ => not_found,
                     };
 
                     let auth_service = Arc::new(AuthServiceImpl::new(
                         Arc::new(UsersRepoImpl::new(config.system.system_user_id)),
                         db_executor.clone(),
+                        config.system.system_user_id,
                     ));
                     let users_service = Arc::new(UsersServiceImpl::new(
                         Arc::new(UsersRepoImpl::new(config.system.system_user_id)),
@@ -145,6 +211,7 @@ impl Service for ApiService {
                         Arc::new(AccountsRepoImpl),
                         db_executor.clone(),
                         keys_client.clone(),
+                        config.system.enabled_currencies.clone(),
                     ));
                     let fees_service = Arc::new(FeesServiceImpl::new(
                         &config,
@@ -158,29 +225,47 @@ impl Service for ApiService {
                         config.system.eth_fees_account_id,
                         config.system.stq_fees_account_id,
                     ];
-                    let transactions_service = Arc::new(TransactionsServiceImpl::new(
-                        config.clone(),
-                        auth_service.clone(),
+                    let transactions_service = Arc::new(
+                        TransactionsServiceImpl::new(
+                            config.clone(),
+                            auth_service.clone(),
+                            Arc::new(TransactionsRepoImpl::new(config.system.system_user_id, fees_accounts_ids.clone())),
+                            Arc::new(PendingBlockchainTransactionsRepoImpl),
+                            Arc::new(BlockchainTransactionsRepoImpl),
+                            Arc::new(SeenHashesRepoImpl),
+                            Arc::new(StrangeBlockchainTransactionsRepoImpl),
+                            Arc::new(AccountsRepoImpl),
+                            Arc::new(KeyValuesRepoImpl),
+                            db_executor.clone(),
+                            keys_client,
+                            blockchain_client.clone(),
+                            exchange_client.clone(),
+                            publisher.clone(),
+                            Arc::new(SystemClock),
+                        )
+                        .with_read_db_executor(read_db_executor)
+                        .with_rate_limiter(create_transaction_rate_limiter)
+                        .with_shutdown(shutdown),
+                    );
+                    let exchange_service = Arc::new(ExchangeServiceImpl::new(exchange_client));
+                    let system_service = Arc::new(SystemServiceImpl::new(
+                        Arc::new(AccountsRepoImpl),
                         Arc::new(TransactionsRepoImpl::new(config.system.system_user_id, fees_accounts_ids.clone())),
                         Arc::new(PendingBlockchainTransactionsRepoImpl),
-                        Arc::new(BlockchainTransactionsRepoImpl),
-                        Arc::new(AccountsRepoImpl),
-                        Arc::new(KeyValuesRepoImpl),
-                        db_executor.clone(),
-                        keys_client,
-                        blockchain_client.clone(),
-                        exchange_client.clone(),
-                        publisher.clone(),
+                        Arc::new(config.clone()),
+                        Arc::new(SystemClock),
                     ));
-                    let exchange_service = Arc::new(ExchangeServiceImpl::new(exchange_client));
                     let metrics_service = Arc::new(MetricsServiceImpl::new(
                         Arc::new(config.clone()),
+                        auth_service,
                         Arc::new(AccountsRepoImpl),
                         Arc::new(TransactionsRepoImpl::new(config.system.system_user_id, fees_accounts_ids)),
                         Arc::new(PendingBlockchainTransactionsRepoImpl),
                         Arc::new(StrangeBlockchainTransactionsRepoImpl),
                         db_executor.clone(),
                         blockchain_client.clone(),
+                        system_service,
+                        Arc::new(SystemClock),
                     ));
 
                     let ctx = Context {
@@ -194,75 +279,70 @@ impl Service for ApiService {
                         exchange_service,
                         metrics_service,
                         fees_service,
+                        max_list_limit: config.server.max_list_limit,
                     };
 
-                    debug!("Received request {}", ctx);
+                    debug!("Received request {}, request id: {}", ctx, request_id);
 
                     router(ctx, parts.method.into(), parts.uri.path())
                 })
                 .and_then(|resp| {
+                    // exports are streamed while they are read from db, so they are neither buffered nor logged
+                    if resp.headers().get(CONTENT_TYPE).map(|value| *value == "text/csv").unwrap_or(false) {
+                        return Either::A(future::ok(resp));
+                    }
                     let (parts, body) = resp.into_parts();
-                    read_body(body)
-                        .map_err(ectx!(ErrorSource::Hyper, ErrorKind::Internal))
-                        .map(|body| (parts, body))
+                    Either::B(read_body(body).map_err(ectx!(ErrorSource::Hyper, ErrorKind::Internal)).map(|body| {
+                        debug!(
+                            "Sent response with status {}, headers: {:#?}, body: {:?}",
+                            parts.status.as_u16(),
+                            parts.headers,
+                            String::from_utf8(body.clone()).ok()
+                        );
+                        Response::from_parts(parts, body.into())
+                    }))
                 })
-                .map(|(parts, body)| {
-                    debug!(
-                        "Sent response with status {}, headers: {:#?}, body: {:?}",
-                        parts.status.as_u16(),
-                        parts.headers,
-                        String::from_utf8(body.clone()).ok()
-                    );
-                    Response::from_parts(parts, body.into())
-                })
-                .or_else(|e| match e.kind() {
-                    ErrorKind::BadRequest => {
-                        log_error(&e);
-                        Ok(Response::builder()
-                            .status(400)
-                            .header("Content-Type", "application/json")
-                            .body(Body::from(r#"{"description": "Bad request"}"#))
-                            .unwrap())
-                    }
-                    ErrorKind::Unauthorized => {
-                        log_warn(&e);
-                        Ok(Response::builder()
-                            .status(401)
-                            .header("Content-Type", "application/json")
-                            .body(Body::from(r#"{"description": "Unauthorized"}"#))
-                            .unwrap())
-                    }
-                    ErrorKind::NotFound => {
-                        log_warn(&e);
-                        Ok(Response::builder()
-                            .status(404)
-                            .header("Content-Type", "application/json")
-                            .body(Body::from(r#"{"description": "Not found"}"#))
-                            .unwrap())
-                    }
-                    ErrorKind::UnprocessableEntity(errors) => {
-                        log_warn(&e);
-                        Ok(Response::builder()
-                            .status(422)
-                            .header("Content-Type", "application/json")
-                            .body(Body::from(errors))
-                            .unwrap())
-                    }
-                    ErrorKind::Internal => {
-                        log_and_capture_error(e);
-                        Ok(Response::builder()
-                            .status(500)
-                            .header("Content-Type", "application/json")
-                            .body(Body::from(r#"{"description": "Internal server error"}"#))
-                            .unwrap())
+                .map(move |mut resp| {
+                    if let Ok(header) = HeaderValue::from_str(&response_request_id) {
+                        resp.headers_mut().insert(REQUEST_ID_HEADER, header);
                     }
+                    resp
+                })
+                .or_else(move |e| {
+                    let kind = e.kind();
+                    match kind {
+                        ErrorKind::Internal => log_and_capture_error(e),
+                        ErrorKind::BadRequest => log_error(&e),
+                        _ => log_warn(&e),
+                    };
+                    let body = serde_json::to_string(&ApiErrorResponse::new(&kind, error_request_id.clone())).unwrap_or_default();
+                    Ok(Response::builder()
+                        .status(kind.status())
+                        .header("Content-Type", "application/json")
+                        .header(REQUEST_ID_HEADER, error_request_id.as_str())
+                        .body(Body::from(body))
+                        .unwrap())
                 }),
         )
     }
 }
 
-pub fn server(config: Config, publisher: Arc<dyn TransactionPublisher>) -> Box<Future<Item = (), Error = ()> + Send> {
-    let fut = ApiService::from_config(&config, publisher)
+// id of the request set by a proxy is kept, so that logs of all services can be matched
+fn get_request_id(headers: &HeaderMap<HeaderValue>) -> String {
+    headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|header| header.to_str().ok())
+        .filter(|request_id| !request_id.is_empty() && request_id.len() <= MAX_REQUEST_ID_LEN)
+        .map(String::from)
+        .unwrap_or_else(|| Uuid::new_v4().to_string())
+}
+
+pub fn server(
+    config: Config,
+    publisher: Arc<dyn TransactionPublisher>,
+    shutdown: ShutdownCoordinator,
+) -> Box<Future<Item = (), Error = ()> + Send> {
+    let fut = ApiService::from_config(&config, publisher, shutdown)
         .into_future()
         .and_then(move |api| {
             let api_clone = api.clone();
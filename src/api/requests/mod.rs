@@ -1,3 +1,5 @@
+use chrono::NaiveDateTime;
+
 use models::*;
 
 #[derive(Debug, Deserialize, Clone)]
@@ -60,6 +62,7 @@ impl From<PostAccountsRequest> for CreateAccount {
 #[serde(rename_all = "camelCase")]
 pub struct PutAccountsRequest {
     pub name: Option<String>,
+    pub reserve: Option<Amount>,
 }
 
 impl From<PutAccountsRequest> for UpdateAccount {
@@ -67,6 +70,7 @@ impl From<PutAccountsRequest> for UpdateAccount {
         Self {
             name: req.name,
             erc20_approved: None,
+            reserve: req.reserve,
         }
     }
 }
@@ -78,20 +82,95 @@ pub struct GetUsersAccountsParams {
     pub offset: i64,
 }
 
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct GetAccountsBalancesParams {
+    /// Read from the primary db, so that the balance reflects transactions just created
+    #[serde(default)]
+    pub strict: bool,
+    /// Balance as of the moment instead of the current one
+    pub as_of: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct GetSystemReconcileParams {
+    pub currency: Currency,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct GetSystemFeesParams {
+    pub currency: Currency,
+    pub from: NaiveDateTime,
+    pub to: NaiveDateTime,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct GetSystemStrangeTransactionsParams {
+    pub offset: i64,
+    pub limit: i64,
+    pub currency: Option<Currency>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct GetSystemStuckTransactionsParams {
+    pub older_than_minutes: u32,
+    pub limit: i64,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PostSystemDepositsRequest {
+    pub account_id: AccountId,
+    pub currency: Currency,
+    pub value: Amount,
+    pub blockchain_tx_id: BlockchainTransactionId,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PutSystemResolverRequest {
+    pub enabled: bool,
+}
+
 #[derive(Debug, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct PostTransactionsRequest {
+    /// Generated by the server if omitted, supply it to make retries idempotent
+    #[serde(default = "TransactionId::generate")]
     pub id: TransactionId,
     pub user_id: UserId,
     pub from: AccountId,
     pub to: Recepient,
     pub to_type: RecepientType,
     pub to_currency: Currency,
+    #[serde(default, deserialize_with = "amount_as_string::deserialize")]
     pub value: Amount,
-    pub value_currency: Currency,
-    pub fee: Amount,
+    pub value_currency: ValueCurrency,
+    /// Estimated with the configured default fee tier if omitted
+    #[serde(default, deserialize_with = "amount_as_string::option::deserialize")]
+    pub fee: Option<Amount>,
     pub exchange_id: Option<ExchangeId>,
     pub exchange_rate: Option<f64>,
+    pub hold_until: Option<NaiveDateTime>,
+    pub gas_limit_override: Option<u64>,
+    #[serde(default)]
+    pub drain: bool,
+    pub fee_tier: Option<FeeTier>,
+    pub user_note: Option<String>,
+    pub category: Option<String>,
+    pub expected_recipient: Option<UserId>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PostTransactionsTransferAndWithdrawRequest {
+    #[serde(flatten)]
+    pub transaction: PostTransactionsRequest,
+    pub funding_account_id: AccountId,
 }
 
 impl From<PostTransactionsRequest> for CreateTransactionInput {
@@ -108,8 +187,21 @@ impl From<PostTransactionsRequest> for CreateTransactionInput {
             fee,
             exchange_id,
             exchange_rate,
+            hold_until,
+            gas_limit_override,
+            drain,
+            fee_tier,
+            user_note,
+            category,
+            expected_recipient,
         } = req;
 
+        // fiat value is converted to `to_currency` at creation time
+        let (value, value_currency, fiat_value) = match value_currency {
+            ValueCurrency::Crypto(currency) => (value, currency, None),
+            ValueCurrency::Fiat(currency) => (Amount::default(), to_currency, Some(FiatValue::new(currency, value))),
+        };
+
         Self {
             id,
             user_id,
@@ -119,9 +211,46 @@ impl From<PostTransactionsRequest> for CreateTransactionInput {
             to_currency,
             value,
             value_currency,
-            fee,
+            fee: fee.unwrap_or_default(),
             exchange_id,
             exchange_rate,
+            fiat_value,
+            fiat_rate: None,
+            hold_until,
+            gas_limit_override,
+            drain,
+            fee_tier,
+            fee_omitted: fee.is_none(),
+            // control characters are stripped, so that the note is safe to display
+            user_note: user_note.map(|note| note.chars().filter(|c| !c.is_control()).collect()),
+            category,
+            expected_recipient,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PostTransactionsBatchRequest {
+    #[serde(default = "TransactionId::generate")]
+    pub id: TransactionId,
+    pub user_id: UserId,
+    pub from: AccountId,
+    pub currency: Currency,
+    pub outputs: Vec<BlockchainTxOutput>,
+    #[serde(deserialize_with = "amount_as_string::deserialize")]
+    pub fee: Amount,
+}
+
+impl From<PostTransactionsBatchRequest> for CreateBatchTransactionInput {
+    fn from(req: PostTransactionsBatchRequest) -> Self {
+        Self {
+            id: req.id,
+            user_id: req.user_id,
+            from: req.from,
+            currency: req.currency,
+            outputs: req.outputs,
+            fee: req.fee,
         }
     }
 }
@@ -143,6 +272,54 @@ impl From<PutTransactionsRequest> for TransactionStatus {
 pub struct GetUsersTransactionsParams {
     pub limit: i64,
     pub offset: i64,
+    pub category: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct GetUsersExchangesParams {
+    pub from: NaiveDateTime,
+    pub to: NaiveDateTime,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct GetAccountTransactionsParams {
+    pub limit: i64,
+    pub offset: i64,
+    pub kind: Option<TransactionKind>,
+    pub category: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct GetSystemTransactionsParams {
+    pub group_kind: TransactionGroupKind,
+    pub offset: i64,
+    pub limit: i64,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct GetSystemTransactionsByDestinationParams {
+    pub address: BlockchainAddress,
+    pub currency: Currency,
+    pub offset: i64,
+    pub limit: i64,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct GetSystemCoinSelectionParams {
+    pub currency: Currency,
+    #[serde(deserialize_with = "amount_as_string::deserialize")]
+    pub value: Amount,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct GetAccountMaxWithdrawableParams {
+    pub to_currency: Currency,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -160,3 +337,75 @@ impl From<PostFeesRequest> for GetFees {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    fn transfer_request(id: Option<&str>) -> PostTransactionsRequest {
+        let mut body = json!({
+            "userId": 1,
+            "from": "a4b1cb0c-c4b0-4b4b-9f8a-1b1e9e4f2a01",
+            "to": "a4b1cb0c-c4b0-4b4b-9f8a-1b1e9e4f2a02",
+            "toType": "account",
+            "toCurrency": "btc",
+            "value": "100",
+            "valueCurrency": "btc",
+            "fee": "0",
+        });
+        if let Some(id) = id {
+            body["id"] = json!(id);
+        }
+        serde_json::from_value(body).unwrap()
+    }
+
+    #[test]
+    fn test_post_transactions_request_generates_id() {
+        let first = transfer_request(None);
+        let second = transfer_request(None);
+        assert_ne!(first.id, second.id);
+        let first_id = first.id;
+        let input: CreateTransactionInput = first.into();
+        assert_eq!(input.id, first_id);
+
+        let id = "5b1e4b8c-7a0a-4d8e-9c3c-0f6f2a9d1e11";
+        let supplied = transfer_request(Some(id));
+        assert_eq!(supplied.id.to_string(), id);
+    }
+
+    #[test]
+    fn test_post_transactions_request_strips_user_note() {
+        let mut request = transfer_request(None);
+        request.user_note = Some("rent\u{0}\n payment\u{7f}".to_string());
+        let input: CreateTransactionInput = request.into();
+        assert_eq!(input.user_note, Some("rent payment".to_string()));
+    }
+
+    #[test]
+    fn test_post_transactions_request_omitted_fee() {
+        let input: CreateTransactionInput = transfer_request(None).into();
+        assert_eq!(input.fee, Amount::new(0));
+        assert!(!input.fee_omitted);
+
+        let mut body = json!({
+            "userId": 1,
+            "from": "a4b1cb0c-c4b0-4b4b-9f8a-1b1e9e4f2a01",
+            "to": "btc_address",
+            "toType": "address",
+            "toCurrency": "btc",
+            "value": "100",
+            "valueCurrency": "btc",
+        });
+        let request: PostTransactionsRequest = serde_json::from_value(body.clone()).unwrap();
+        assert_eq!(request.fee, None);
+        let input: CreateTransactionInput = request.into();
+        assert_eq!(input.fee, Amount::new(0));
+        assert!(input.fee_omitted);
+
+        body["fee"] = json!("250");
+        let input: CreateTransactionInput = serde_json::from_value::<PostTransactionsRequest>(body).unwrap().into();
+        assert_eq!(input.fee, Amount::new(250));
+        assert!(!input.fee_omitted);
+    }
+}
@@ -1,7 +1,35 @@
 use chrono::NaiveDateTime;
+use serde_json;
 
+use super::error::ErrorKind;
 use models::*;
 
+/// Body of every error response. `errors` holds all failed validations of 422 responses
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiErrorResponse {
+    pub code: String,
+    pub message: String,
+    pub request_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub errors: Option<serde_json::Value>,
+}
+
+impl ApiErrorResponse {
+    pub fn new(kind: &ErrorKind, request_id: String) -> Self {
+        let errors = match kind {
+            ErrorKind::UnprocessableEntity(errors) => serde_json::from_str(errors).ok(),
+            _ => None,
+        };
+        Self {
+            code: kind.code(),
+            message: kind.message(),
+            request_id,
+            errors,
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct UsersResponse {
@@ -33,6 +61,7 @@ pub struct AccountsResponse {
     pub address: BlockchainAddress,
     pub name: Option<String>,
     pub erc20_approved: bool,
+    pub reserve: Amount,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
 }
@@ -48,6 +77,24 @@ impl From<Account> for AccountsResponse {
             created_at: account.created_at,
             updated_at: account.updated_at,
             erc20_approved: account.erc20_approved,
+            reserve: account.reserve,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct AccountWithBalanceResponse {
+    #[serde(flatten)]
+    pub account: AccountsResponse,
+    pub balance: Amount,
+}
+
+impl From<AccountWithBalance> for AccountWithBalanceResponse {
+    fn from(account_with_balance: AccountWithBalance) -> Self {
+        Self {
+            account: account_with_balance.account.into(),
+            balance: account_with_balance.balance,
         }
     }
 }
@@ -68,6 +115,26 @@ impl From<AccountWithBalance> for BalanceResponse {
     }
 }
 
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct MaxWithdrawableResponse {
+    pub account_id: AccountId,
+    pub currency: Currency,
+    pub value: Amount,
+}
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ResolverStatusResponse {
+    pub enabled: bool,
+}
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct IsInternalAddressResponse {
+    pub is_internal: bool,
+}
+
 #[derive(Debug, Serialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct BalancesResponse {
@@ -89,13 +156,26 @@ pub struct TransactionsResponse {
     pub id: TransactionId,
     pub from: Vec<TransactionAddressInfo>,
     pub to: TransactionAddressInfo,
+    #[serde(with = "amount_as_string")]
     pub from_value: Amount,
     pub from_currency: Currency,
+    #[serde(with = "amount_as_string")]
     pub to_value: Amount,
     pub to_currency: Currency,
+    #[serde(with = "amount_as_string")]
     pub fee: Amount,
+    pub fee_price: Option<f64>,
+    #[serde(with = "amount_as_string::option")]
+    pub gross_fee: Option<Amount>,
+    #[serde(with = "amount_as_string::option")]
+    pub network_fee: Option<Amount>,
+    pub fee_variance: Option<f64>,
     pub status: TransactionStatus,
     pub blockchain_tx_ids: Vec<BlockchainTransactionId>,
+    pub confirmations: Option<u64>,
+    pub failure_reason: Option<String>,
+    pub user_note: Option<String>,
+    pub category: Option<String>,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
 }
@@ -111,14 +191,173 @@ impl From<TransactionOut> for TransactionsResponse {
             to_value: transaction.to_value,
             to_currency: transaction.to_currency,
             fee: transaction.fee,
+            fee_price: transaction.fee_price,
+            gross_fee: transaction.gross_fee,
+            network_fee: transaction.network_fee,
+            fee_variance: transaction.fee_variance,
             status: transaction.status,
             blockchain_tx_ids: transaction.blockchain_tx_ids,
+            confirmations: transaction.confirmations,
+            failure_reason: transaction.failure_reason,
+            user_note: transaction.user_note,
+            category: transaction.category,
+            created_at: transaction.created_at,
+            updated_at: transaction.updated_at,
+        }
+    }
+}
+
+/// Shape of transactions returned before exchanges were introduced, with a single currency.
+/// Values are the ones written off the `from` account.
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct LegacyTransactionsResponse {
+    pub id: TransactionId,
+    pub from: Vec<TransactionAddressInfo>,
+    pub to: TransactionAddressInfo,
+    pub currency: Currency,
+    #[serde(with = "amount_as_string")]
+    pub value: Amount,
+    #[serde(with = "amount_as_string")]
+    pub fee: Amount,
+    pub status: TransactionStatus,
+    pub blockchain_tx_id: Option<BlockchainTransactionId>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+impl From<TransactionOut> for LegacyTransactionsResponse {
+    fn from(transaction: TransactionOut) -> Self {
+        Self {
+            id: transaction.id,
+            from: transaction.from,
+            to: transaction.to,
+            currency: transaction.from_currency,
+            value: transaction.from_value,
+            fee: transaction.fee,
+            status: transaction.status,
+            blockchain_tx_id: transaction.blockchain_tx_ids.into_iter().nth(0),
+            created_at: transaction.created_at,
+            updated_at: transaction.updated_at,
+        }
+    }
+}
+
+/// Version of api response shapes requested by client in `Accept-Version` header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApiVersion {
+    /// Flat single currency transactions
+    V1,
+    /// Transactions with `from` and `to` currencies, default
+    V2,
+}
+
+impl Default for ApiVersion {
+    fn default() -> Self {
+        ApiVersion::V2
+    }
+}
+
+impl ApiVersion {
+    pub fn from_header(header: &str) -> Option<Self> {
+        match header.trim() {
+            "1" => Some(ApiVersion::V1),
+            "2" => Some(ApiVersion::V2),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(untagged)]
+pub enum VersionedTransactionsResponse {
+    V1(LegacyTransactionsResponse),
+    V2(TransactionsResponse),
+}
+
+impl VersionedTransactionsResponse {
+    pub fn new(transaction: TransactionOut, version: ApiVersion) -> Self {
+        match version {
+            ApiVersion::V1 => VersionedTransactionsResponse::V1(transaction.into()),
+            ApiVersion::V2 => VersionedTransactionsResponse::V2(transaction.into()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct TransactionLegResponse {
+    pub id: TransactionId,
+    pub gid: TransactionId,
+    pub user_id: UserId,
+    pub dr_account_id: AccountId,
+    pub cr_account_id: AccountId,
+    pub currency: Currency,
+    pub value: Amount,
+    pub status: TransactionStatus,
+    pub blockchain_tx_id: Option<BlockchainTransactionId>,
+    pub kind: TransactionKind,
+    pub group_kind: TransactionGroupKind,
+    pub related_tx: Option<TransactionId>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+impl From<Transaction> for TransactionLegResponse {
+    fn from(transaction: Transaction) -> Self {
+        Self {
+            id: transaction.id,
+            gid: transaction.gid,
+            user_id: transaction.user_id,
+            dr_account_id: transaction.dr_account_id,
+            cr_account_id: transaction.cr_account_id,
+            currency: transaction.currency,
+            value: transaction.value,
+            status: transaction.status,
+            blockchain_tx_id: transaction.blockchain_tx_id,
+            kind: transaction.kind,
+            group_kind: transaction.group_kind,
+            related_tx: transaction.related_tx,
             created_at: transaction.created_at,
             updated_at: transaction.updated_at,
         }
     }
 }
 
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SignedBlockchainTransactionResponse {
+    pub hash: BlockchainTransactionId,
+    pub raw_tx: Option<BlockchainTransactionRaw>,
+}
+
+impl From<SignedBlockchainTransaction> for SignedBlockchainTransactionResponse {
+    fn from(transaction: SignedBlockchainTransaction) -> Self {
+        Self {
+            hash: transaction.hash,
+            raw_tx: transaction.raw_tx,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct TransactionStatusChangeResponse {
+    pub transaction_id: TransactionId,
+    pub status: TransactionStatus,
+    pub created_at: NaiveDateTime,
+}
+
+impl From<StatusChange> for TransactionStatusChangeResponse {
+    fn from(change: StatusChange) -> Self {
+        Self {
+            transaction_id: change.transaction_id,
+            status: change.status,
+            created_at: change.created_at,
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct FeesResponse {
@@ -134,3 +373,192 @@ impl From<Fees> for FeesResponse {
         }
     }
 }
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct StrangeTransactionResponse {
+    pub hash: BlockchainTransactionId,
+    pub from: serde_json::Value,
+    pub to: serde_json::Value,
+    pub block_number: i64,
+    pub currency: Currency,
+    pub fee: Amount,
+    pub confirmations: i32,
+    pub erc20_operation_kind: Option<Erc20OperationKind>,
+    /// Why the transaction was flagged
+    pub reason: String,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+impl From<StrangeBlockchainTransactionDB> for StrangeTransactionResponse {
+    fn from(transaction: StrangeBlockchainTransactionDB) -> Self {
+        Self {
+            hash: transaction.hash,
+            from: transaction.from_,
+            to: transaction.to_,
+            block_number: transaction.block_number,
+            currency: transaction.currency,
+            fee: transaction.fee,
+            confirmations: transaction.confirmations,
+            erc20_operation_kind: transaction.erc20_operation_kind,
+            reason: transaction.commentary,
+            created_at: transaction.created_at,
+            updated_at: transaction.updated_at,
+        }
+    }
+}
+
+/// First line of the csv export of transactions
+pub const TRANSACTIONS_CSV_HEADER: &str = "id,created_at,kind,from,to,value,currency,fee,status,blockchain_tx_id\n";
+
+/// Line of the csv export of transactions. Values are in satoshis / wei and are the ones written off the `from`
+/// account like in `LegacyTransactionsResponse`. Several addresses or blockchain txs are separated with spaces
+pub fn transaction_csv_row(transaction: &TransactionOut) -> String {
+    let from: Vec<String> = transaction.from.iter().map(|from| from.blockchain_address.to_string()).collect();
+    let blockchain_tx_ids: Vec<String> = transaction.blockchain_tx_ids.iter().map(ToString::to_string).collect();
+    let fields = [
+        transaction.id.to_string(),
+        transaction.created_at.format("%Y-%m-%dT%H:%M:%S").to_string(),
+        serialized_name(&transaction.kind),
+        from.join(" "),
+        transaction.to.blockchain_address.to_string(),
+        transaction.from_value.raw().to_string(),
+        transaction.from_currency.to_string(),
+        transaction.fee.raw().to_string(),
+        serialized_name(&transaction.status),
+        blockchain_tx_ids.join(" "),
+    ];
+    let fields: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+    format!("{}\n", fields.join(","))
+}
+
+// e.g. `internal_multi` for enums serialized in snake case
+fn serialized_name<T: ::serde::Serialize>(value: &T) -> String {
+    serde_json::to_value(value)
+        .ok()
+        .and_then(|value| value.as_str().map(String::from))
+        .unwrap_or_default()
+}
+
+// quoted only if needed, as in RFC 4180
+fn csv_field(field: &str) -> String {
+    if field.contains(|c: char| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    fn create_exchange_transaction() -> TransactionOut {
+        let now = ::chrono::Utc::now().naive_utc();
+        TransactionOut {
+            id: TransactionId::generate(),
+            kind: TransactionGroupKind::InternalMulti,
+            user_id: UserId::generate(),
+            from: vec![TransactionAddressInfo {
+                account_id: Some(AccountId::generate()),
+                blockchain_address: BlockchainAddress::new("from_address".to_string()),
+            }],
+            to: TransactionAddressInfo {
+                account_id: Some(AccountId::generate()),
+                blockchain_address: BlockchainAddress::new("to_address".to_string()),
+            },
+            from_value: Amount::new(100),
+            from_currency: Currency::Btc,
+            to_value: Amount::new(3000),
+            to_currency: Currency::Eth,
+            fee: Amount::new(1),
+            fee_price: None,
+            gross_fee: None,
+            network_fee: None,
+            fee_variance: None,
+            status: TransactionStatus::Done,
+            blockchain_tx_ids: vec![],
+            confirmations: None,
+            failure_reason: None,
+            user_note: None,
+            category: None,
+            created_at: now,
+            updated_at: now,
+        }
+    }
+
+    #[test]
+    fn test_api_error_response_codes() {
+        let not_enough_balance = r#"{"value":[{"code":"not_enough_balance","message":"account balance is not enough","params":{}}]}"#;
+        let cases = vec![
+            (ErrorKind::BadRequest, 400, "bad_request", "Bad request"),
+            (ErrorKind::Unauthorized, 401, "unauthorized", "Unauthorized"),
+            (ErrorKind::NotFound, 404, "not_found", "Not found"),
+            (
+                ErrorKind::UnprocessableEntity(not_enough_balance.to_string()),
+                422,
+                "not_enough_balance",
+                "account balance is not enough",
+            ),
+            (
+                ErrorKind::UnprocessableEntity(r#"{"rate":[{"code":"rate_expired","message":null,"params":{}}]}"#.to_string()),
+                422,
+                "rate_expired",
+                "Unprocessable entity",
+            ),
+            (
+                ErrorKind::UnprocessableEntity("not a json".to_string()),
+                422,
+                "unprocessable_entity",
+                "Unprocessable entity",
+            ),
+            (ErrorKind::TooManyRequests, 429, "too_many_requests", "Too many requests"),
+            (ErrorKind::Internal, 500, "internal_error", "Internal server error"),
+            (ErrorKind::ServiceUnavailable, 503, "service_unavailable", "Service unavailable"),
+        ];
+        for (kind, status, code, message) in cases {
+            assert_eq!(kind.status(), status, "{:?}", kind);
+            let response = serde_json::to_value(ApiErrorResponse::new(&kind, "request_id".to_string())).unwrap();
+            assert_eq!(response["code"], json!(code), "{:?}", kind);
+            assert_eq!(response["message"], json!(message), "{:?}", kind);
+            assert_eq!(response["requestId"], json!("request_id"), "{:?}", kind);
+        }
+
+        // all failed validations are kept
+        let response = serde_json::to_value(ApiErrorResponse::new(
+            &ErrorKind::UnprocessableEntity(not_enough_balance.to_string()),
+            "request_id".to_string(),
+        ))
+        .unwrap();
+        assert_eq!(response["errors"]["value"][0]["code"], json!("not_enough_balance"));
+        let response = serde_json::to_value(ApiErrorResponse::new(&ErrorKind::NotFound, "request_id".to_string())).unwrap();
+        assert!(response.get("errors").is_none());
+    }
+
+    #[test]
+    fn test_api_version_from_header() {
+        assert_eq!(ApiVersion::from_header("1"), Some(ApiVersion::V1));
+        assert_eq!(ApiVersion::from_header(" 2 "), Some(ApiVersion::V2));
+        assert_eq!(ApiVersion::from_header("3"), None);
+    }
+
+    #[test]
+    fn test_transaction_response_versions() {
+        let transaction = create_exchange_transaction();
+
+        let v1 = serde_json::to_value(VersionedTransactionsResponse::new(transaction.clone(), ApiVersion::V1)).unwrap();
+        assert_eq!(v1["currency"], json!("btc"));
+        assert_eq!(v1["value"], json!("100"));
+        assert_eq!(v1["fee"], json!("1"));
+        assert_eq!(v1["blockchainTxId"], json!(null));
+        assert!(v1.get("toValue").is_none());
+
+        let v2 = serde_json::to_value(VersionedTransactionsResponse::new(transaction, ApiVersion::V2)).unwrap();
+        assert_eq!(v2["fromCurrency"], json!("btc"));
+        assert_eq!(v2["toCurrency"], json!("eth"));
+        assert_eq!(v2["toValue"], json!("3000"));
+        assert!(v2.get("value").is_none());
+    }
+}
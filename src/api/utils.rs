@@ -37,3 +37,29 @@ where
             }),
     )
 }
+
+/// Rejects negative pagination params and clamps `limit` to `max_limit`
+pub fn clamp_pagination(offset: i64, limit: i64, max_limit: i64) -> Result<(i64, i64), Error> {
+    if offset < 0 || limit < 0 {
+        return Err(ectx!(err ErrorContext::RequestQueryParams, ErrorKind::BadRequest => offset, limit));
+    }
+    Ok((offset, limit.min(max_limit)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clamp_pagination() {
+        assert_eq!(clamp_pagination(0, 20, 100).unwrap(), (0, 20));
+        assert_eq!(clamp_pagination(40, 100, 100).unwrap(), (40, 100));
+        assert_eq!(clamp_pagination(0, 1_000_000, 100).unwrap(), (0, 100));
+        for (offset, limit) in vec![(-1, 20), (0, -1), (-10, -10)] {
+            match clamp_pagination(offset, limit, 100).unwrap_err().kind() {
+                ErrorKind::BadRequest => (),
+                kind => panic!("unexpected error kind: {:?}", kind),
+            }
+        }
+    }
+}
@@ -1,9 +1,12 @@
 mod error;
 mod responses;
 
+use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 
 use failure::Fail;
+use futures::future;
 use futures::prelude::*;
 use hyper::Method;
 use hyper::{Body, Request};
@@ -13,9 +16,9 @@ use serde_json;
 
 pub use self::error::*;
 use self::responses::*;
-use super::HttpClient;
+use super::{with_timeout, HttpClient};
 use config::Config;
-use utils::read_body;
+use utils::{read_body, Semaphore};
 
 pub trait BlockchainClient: Send + Sync + 'static {
     fn post_ethereum_transaction(
@@ -26,15 +29,35 @@ pub trait BlockchainClient: Send + Sync + 'static {
         &self,
         transaction: BlockchainTransactionRaw,
     ) -> Box<Future<Item = BlockchainTransactionId, Error = Error> + Send>;
-    fn get_bitcoin_utxos(&self, address: BlockchainAddress) -> Box<Future<Item = Vec<BitcoinUtxos>, Error = Error> + Send>;
+    /// Utxos of the address with at least `min_confirmations`
+    fn get_bitcoin_utxos(
+        &self,
+        address: BlockchainAddress,
+        min_confirmations: u64,
+    ) -> Box<Future<Item = Vec<BitcoinUtxos>, Error = Error> + Send>;
     fn get_ethereum_nonce(&self, address: BlockchainAddress) -> Box<Future<Item = u64, Error = Error> + Send>;
     fn get_balance(&self, address: BlockchainAddress, currency: Currency) -> Box<Future<Item = Amount, Error = Error> + Send>;
+    /// Gas that the node expects the transfer to use, only for ethereum and storiqa
+    fn estimate_gas(
+        &self,
+        from: BlockchainAddress,
+        to: BlockchainAddress,
+        value: Amount,
+        currency: Currency,
+    ) -> Box<Future<Item = Amount, Error = Error> + Send>;
+    /// Transaction as seen by blockchain gateway, `None` if it is neither mined nor in mempool
+    fn get_transaction(
+        &self,
+        hash: BlockchainTransactionId,
+        currency: Currency,
+    ) -> Box<Future<Item = Option<BlockchainTransaction>, Error = Error> + Send>;
 }
 
 #[derive(Clone)]
 pub struct BlockchainClientImpl {
     cli: Arc<HttpClient>,
     blockchain_url: String,
+    request_timeout: Duration,
 }
 
 impl BlockchainClientImpl {
@@ -42,6 +65,7 @@ impl BlockchainClientImpl {
         Self {
             cli: Arc::new(cli),
             blockchain_url: config.client.blockchain_url.clone(),
+            request_timeout: Duration::from_millis(config.client.request_timeout_ms),
         }
     }
 
@@ -55,6 +79,7 @@ impl BlockchainClientImpl {
         let query2 = query.clone();
         let query3 = query.clone();
         let cli = self.cli.clone();
+        let request_timeout = self.request_timeout;
         let mut builder = Request::builder();
         let url = format!("{}{}", self.blockchain_url, query);
         builder
@@ -63,7 +88,7 @@ impl BlockchainClientImpl {
             .body(Body::from(body))
             .map_err(ectx!(ErrorSource::Hyper, ErrorKind::MalformedInput => query3))
             .into_future()
-            .and_then(move |req| cli.request(req).map_err(ectx!(ErrorKind::Internal => query1)))
+            .and_then(move |req| with_timeout(cli.request(req), request_timeout).map_err(ectx!(ErrorKind::Internal => query1)))
             .and_then(move |resp| read_body(resp.into_body()).map_err(ectx!(ErrorSource::Hyper, ErrorKind::Internal => query2)))
             .and_then(|bytes| {
                 let bytes_clone = bytes.clone();
@@ -78,7 +103,7 @@ impl BlockchainClientImpl {
         let query2 = query.clone();
         let cli = self.cli.clone();
         let url = format!("{}{}", self.blockchain_url, query);
-        cli.get(url)
+        with_timeout(cli.get(url), self.request_timeout)
             .map_err(ectx!(ErrorKind::Internal => query1))
             .and_then(move |resp| read_body(resp.into_body()).map_err(ectx!(ErrorSource::Hyper, ErrorKind::Internal => query2)))
             .and_then(|bytes| {
@@ -123,22 +148,174 @@ impl BlockchainClient for BlockchainClientImpl {
                 .map(|resp| resp.tx_hash),
         )
     }
-    fn get_bitcoin_utxos(&self, address: BlockchainAddress) -> Box<Future<Item = Vec<BitcoinUtxos>, Error = Error> + Send> {
-        let url = format!("/bitcoin/{}/utxos", address);
+    fn get_bitcoin_utxos(
+        &self,
+        address: BlockchainAddress,
+        min_confirmations: u64,
+    ) -> Box<Future<Item = Vec<BitcoinUtxos>, Error = Error> + Send> {
+        let url = format!("/bitcoin/{}/utxos?minConfirmations={}", address, min_confirmations);
         Box::new(self.exec_query_get::<Vec<BitcoinUtxos>>(&url))
     }
     fn get_ethereum_nonce(&self, address: BlockchainAddress) -> Box<Future<Item = u64, Error = Error> + Send> {
         let url = format!("/ethereum/{}/nonce", address);
         Box::new(self.exec_query_get::<GetEtheriumNonceResponse>(&url).map(|resp| resp.nonce))
     }
+    fn estimate_gas(
+        &self,
+        from: BlockchainAddress,
+        to: BlockchainAddress,
+        value: Amount,
+        currency: Currency,
+    ) -> Box<Future<Item = Amount, Error = Error> + Send> {
+        let url = match currency {
+            Currency::Eth => "/ethereum/estimate_gas",
+            Currency::Stq => "/storiqa/estimate_gas",
+            Currency::Btc => {
+                return Box::new(futures::future::err(
+                    ectx!(err ErrorKind::MalformedInput, ErrorKind::MalformedInput => currency),
+                ))
+            }
+        };
+        let client = self.clone();
+        let request = EstimateGasRequest { from, to, value };
+        Box::new(
+            serde_json::to_string(&request)
+                .map_err(ectx!(ErrorSource::Json, ErrorKind::Internal => request))
+                .into_future()
+                .and_then(move |body| client.exec_query_post::<EstimateGasResponse>(url, body))
+                .map(|resp| resp.gas),
+        )
+    }
+    fn get_transaction(
+        &self,
+        hash: BlockchainTransactionId,
+        currency: Currency,
+    ) -> Box<Future<Item = Option<BlockchainTransaction>, Error = Error> + Send> {
+        let url = match currency {
+            Currency::Btc => format!("/bitcoin/transactions/{}", hash),
+            Currency::Eth => format!("/ethereum/transactions/{}", hash),
+            Currency::Stq => format!("/storiqa/transactions/{}", hash),
+        };
+        Box::new(self.exec_query_get::<Option<BlockchainTransaction>>(&url))
+    }
+}
+
+/// Blockchain client that keeps at most `max_in_flight` requests to the node at the same time,
+/// the rest are queued until one of the running requests completes
+#[derive(Clone)]
+pub struct ConcurrencyLimitedBlockchainClient {
+    inner: Arc<dyn BlockchainClient>,
+    semaphore: Semaphore,
+}
+
+impl ConcurrencyLimitedBlockchainClient {
+    pub fn new(inner: Arc<dyn BlockchainClient>, max_in_flight: usize) -> Self {
+        Self {
+            inner,
+            semaphore: Semaphore::new(max_in_flight),
+        }
+    }
+}
+
+impl BlockchainClient for ConcurrencyLimitedBlockchainClient {
+    fn post_ethereum_transaction(
+        &self,
+        transaction: BlockchainTransactionRaw,
+    ) -> Box<Future<Item = BlockchainTransactionId, Error = Error> + Send> {
+        let inner = self.inner.clone();
+        Box::new(
+            self.semaphore
+                .limit(future::lazy(move || inner.post_ethereum_transaction(transaction))),
+        )
+    }
+    fn post_bitcoin_transaction(
+        &self,
+        transaction: BlockchainTransactionRaw,
+    ) -> Box<Future<Item = BlockchainTransactionId, Error = Error> + Send> {
+        let inner = self.inner.clone();
+        Box::new(
+            self.semaphore
+                .limit(future::lazy(move || inner.post_bitcoin_transaction(transaction))),
+        )
+    }
+    fn get_bitcoin_utxos(
+        &self,
+        address: BlockchainAddress,
+        min_confirmations: u64,
+    ) -> Box<Future<Item = Vec<BitcoinUtxos>, Error = Error> + Send> {
+        let inner = self.inner.clone();
+        Box::new(
+            self.semaphore
+                .limit(future::lazy(move || inner.get_bitcoin_utxos(address, min_confirmations))),
+        )
+    }
+    fn get_ethereum_nonce(&self, address: BlockchainAddress) -> Box<Future<Item = u64, Error = Error> + Send> {
+        let inner = self.inner.clone();
+        Box::new(self.semaphore.limit(future::lazy(move || inner.get_ethereum_nonce(address))))
+    }
+    fn get_balance(&self, address: BlockchainAddress, currency: Currency) -> Box<Future<Item = Amount, Error = Error> + Send> {
+        let inner = self.inner.clone();
+        Box::new(self.semaphore.limit(future::lazy(move || inner.get_balance(address, currency))))
+    }
+    fn estimate_gas(
+        &self,
+        from: BlockchainAddress,
+        to: BlockchainAddress,
+        value: Amount,
+        currency: Currency,
+    ) -> Box<Future<Item = Amount, Error = Error> + Send> {
+        let inner = self.inner.clone();
+        Box::new(
+            self.semaphore
+                .limit(future::lazy(move || inner.estimate_gas(from, to, value, currency))),
+        )
+    }
+    fn get_transaction(
+        &self,
+        hash: BlockchainTransactionId,
+        currency: Currency,
+    ) -> Box<Future<Item = Option<BlockchainTransaction>, Error = Error> + Send> {
+        let inner = self.inner.clone();
+        Box::new(self.semaphore.limit(future::lazy(move || inner.get_transaction(hash, currency))))
+    }
 }
 
 #[derive(Default)]
-pub struct BlockchainClientMock;
+pub struct BlockchainClientMock {
+    balances: HashMap<(BlockchainAddress, Currency), Amount>,
+    transactions: Vec<BlockchainTransaction>,
+    gas_estimate: Option<Amount>,
+    utxos: Option<Vec<BitcoinUtxos>>,
+}
+
+impl BlockchainClientMock {
+    pub fn with_balance(mut self, address: BlockchainAddress, currency: Currency, value: Amount) -> Self {
+        self.balances.insert((address, currency), value);
+        self
+    }
+
+    pub fn with_transaction(mut self, transaction: BlockchainTransaction) -> Self {
+        self.transactions.push(transaction);
+        self
+    }
+
+    /// Without utxos a single confirmed one is returned
+    pub fn with_utxos(mut self, utxos: Vec<BitcoinUtxos>) -> Self {
+        self.utxos = Some(utxos);
+        self
+    }
+
+    /// Without an estimate the node is treated as unavailable
+    pub fn with_gas_estimate(mut self, gas: Amount) -> Self {
+        self.gas_estimate = Some(gas);
+        self
+    }
+}
 
 impl BlockchainClient for BlockchainClientMock {
-    fn get_balance(&self, _address: BlockchainAddress, _currency: Currency) -> Box<Future<Item = Amount, Error = Error> + Send> {
-        unimplemented!()
+    fn get_balance(&self, address: BlockchainAddress, currency: Currency) -> Box<Future<Item = Amount, Error = Error> + Send> {
+        let value = self.balances.get(&(address, currency)).cloned().unwrap_or(Amount::new(0));
+        Box::new(Ok(value).into_future())
     }
     fn post_ethereum_transaction(
         &self,
@@ -152,10 +329,132 @@ impl BlockchainClient for BlockchainClientMock {
     ) -> Box<Future<Item = BlockchainTransactionId, Error = Error> + Send> {
         Box::new(Ok(BlockchainTransactionId::default()).into_future())
     }
-    fn get_bitcoin_utxos(&self, _address: BlockchainAddress) -> Box<Future<Item = Vec<BitcoinUtxos>, Error = Error> + Send> {
-        Box::new(Ok(vec![BitcoinUtxos::default()]).into_future())
+    fn get_bitcoin_utxos(
+        &self,
+        _address: BlockchainAddress,
+        _min_confirmations: u64,
+    ) -> Box<Future<Item = Vec<BitcoinUtxos>, Error = Error> + Send> {
+        let utxos = self
+            .utxos
+            .clone()
+            .unwrap_or_else(|| vec![BitcoinUtxos::new(BlockchainTransactionId::default(), 0, Amount::default(), 6)]);
+        Box::new(Ok(utxos).into_future())
     }
     fn get_ethereum_nonce(&self, _address: BlockchainAddress) -> Box<Future<Item = u64, Error = Error> + Send> {
         Box::new(Ok(0).into_future())
     }
+    fn estimate_gas(
+        &self,
+        _from: BlockchainAddress,
+        _to: BlockchainAddress,
+        _value: Amount,
+        currency: Currency,
+    ) -> Box<Future<Item = Amount, Error = Error> + Send> {
+        let gas: Result<Amount, Error> = self
+            .gas_estimate
+            .ok_or(ectx!(err ErrorKind::Internal, ErrorKind::Internal => currency));
+        Box::new(gas.into_future())
+    }
+    fn get_transaction(
+        &self,
+        hash: BlockchainTransactionId,
+        currency: Currency,
+    ) -> Box<Future<Item = Option<BlockchainTransaction>, Error = Error> + Send> {
+        // a transaction of another asset with the same hash is reported as is, like for a shared address
+        let transaction = self
+            .transactions
+            .iter()
+            .find(|transaction| transaction.hash == hash && transaction.currency == currency)
+            .or_else(|| self.transactions.iter().find(|transaction| transaction.hash == hash))
+            .cloned();
+        Box::new(Ok(transaction).into_future())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Instant;
+    use tokio::runtime::current_thread::Runtime;
+    use tokio::timer::Delay;
+
+    // answers balance requests after a short delay, tracking how many of them run at once
+    #[derive(Default)]
+    struct SlowBlockchainClientMock {
+        in_flight: Arc<AtomicUsize>,
+        max_in_flight: Arc<AtomicUsize>,
+    }
+
+    impl BlockchainClient for SlowBlockchainClientMock {
+        fn get_balance(&self, _address: BlockchainAddress, _currency: Currency) -> Box<Future<Item = Amount, Error = Error> + Send> {
+            let in_flight = self.in_flight.clone();
+            let current = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            if current > self.max_in_flight.load(Ordering::SeqCst) {
+                self.max_in_flight.store(current, Ordering::SeqCst);
+            }
+            Box::new(
+                Delay::new(Instant::now() + Duration::from_millis(5))
+                    .map_err(ectx!(ErrorKind::Internal))
+                    .map(move |_| {
+                        in_flight.fetch_sub(1, Ordering::SeqCst);
+                        Amount::new(0)
+                    }),
+            )
+        }
+        fn post_ethereum_transaction(
+            &self,
+            _transaction: BlockchainTransactionRaw,
+        ) -> Box<Future<Item = BlockchainTransactionId, Error = Error> + Send> {
+            unimplemented!()
+        }
+        fn post_bitcoin_transaction(
+            &self,
+            _transaction: BlockchainTransactionRaw,
+        ) -> Box<Future<Item = BlockchainTransactionId, Error = Error> + Send> {
+            unimplemented!()
+        }
+        fn get_bitcoin_utxos(
+            &self,
+            _address: BlockchainAddress,
+            _min_confirmations: u64,
+        ) -> Box<Future<Item = Vec<BitcoinUtxos>, Error = Error> + Send> {
+            unimplemented!()
+        }
+        fn get_ethereum_nonce(&self, _address: BlockchainAddress) -> Box<Future<Item = u64, Error = Error> + Send> {
+            unimplemented!()
+        }
+        fn estimate_gas(
+            &self,
+            _from: BlockchainAddress,
+            _to: BlockchainAddress,
+            _value: Amount,
+            _currency: Currency,
+        ) -> Box<Future<Item = Amount, Error = Error> + Send> {
+            unimplemented!()
+        }
+        fn get_transaction(
+            &self,
+            _hash: BlockchainTransactionId,
+            _currency: Currency,
+        ) -> Box<Future<Item = Option<BlockchainTransaction>, Error = Error> + Send> {
+            unimplemented!()
+        }
+    }
+
+    #[test]
+    fn test_blockchain_client_concurrency_limit() {
+        let mut runtime = Runtime::new().unwrap();
+        let mock = SlowBlockchainClientMock::default();
+        let max_in_flight = mock.max_in_flight.clone();
+        let client = ConcurrencyLimitedBlockchainClient::new(Arc::new(mock), 3);
+        let requests: Vec<_> = (0..10)
+            .map(|_| client.get_balance(BlockchainAddress::default(), Currency::Btc))
+            .collect();
+        // requests above the limit are queued, not failed
+        let balances = runtime.block_on(future::join_all(requests)).unwrap();
+        assert_eq!(balances.len(), 10);
+        assert_eq!(max_in_flight.load(Ordering::SeqCst), 3);
+    }
 }
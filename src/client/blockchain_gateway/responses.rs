@@ -23,3 +23,17 @@ pub struct TxHashResponse {
 pub struct CreateBlockchainTxRequest {
     pub raw: BlockchainTransactionRaw,
 }
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct EstimateGasRequest {
+    pub from: BlockchainAddress,
+    pub to: BlockchainAddress,
+    pub value: Amount,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct EstimateGasResponse {
+    pub gas: Amount,
+}
@@ -21,6 +21,10 @@ pub enum ErrorKind {
     Internal,
     #[fail(display = "exchange client error - bad request")]
     Validation(String),
+    #[fail(display = "exchange client error - exchange gateway is unavailable")]
+    Unavailable,
+    #[fail(display = "exchange client error - rate rejected")]
+    RateRejected,
 }
 
 #[allow(dead_code)]
@@ -40,7 +44,17 @@ impl From<HttpClientErrorKind> for ErrorKind {
     fn from(err: HttpClientErrorKind) -> Self {
         match err {
             HttpClientErrorKind::Validation(s) => ErrorKind::Validation(s),
-            _ => ErrorKind::Internal,
+            // 4xx - exchange gateway rejected the request, e.g. the rate is expired
+            HttpClientErrorKind::BadRequest | HttpClientErrorKind::NotFound | HttpClientErrorKind::UnprocessableEntity => {
+                ErrorKind::RateRejected
+            }
+            // 5xx and connection errors - might succeed if retried later
+            HttpClientErrorKind::Internal
+            | HttpClientErrorKind::InternalServer
+            | HttpClientErrorKind::BadGateway
+            | HttpClientErrorKind::GatewayTimeout
+            | HttpClientErrorKind::UnknownServerError => ErrorKind::Unavailable,
+            HttpClientErrorKind::Unauthorized => ErrorKind::Internal,
         }
     }
 }
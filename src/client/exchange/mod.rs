@@ -1,6 +1,7 @@
 mod error;
 
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use failure::Fail;
 use futures::prelude::*;
@@ -11,20 +12,24 @@ use serde::Deserialize;
 use serde_json;
 
 pub use self::error::*;
-use super::HttpClient;
+use super::{with_timeout, HttpClient};
 use config::Config;
 use utils::read_body;
 
 pub trait ExchangeClient: Send + Sync + 'static {
     fn exchange(&self, exchange: ExchangeInput, role: Role) -> Box<Future<Item = Exchange, Error = Error> + Send>;
     fn rate(&self, exchange: RateInput, role: Role) -> Box<Future<Item = Rate, Error = Error> + Send>;
+    fn fiat_rate(&self, exchange: FiatRateInput, role: Role) -> Box<Future<Item = FiatRate, Error = Error> + Send>;
     fn refresh_rate(&self, exchange: RateRefreshInput, role: Role) -> Box<Future<Item = RateRefresh, Error = Error> + Send>;
+    /// Voids the exchange on the gateway side, a no-op if it was never executed
+    fn cancel_exchange(&self, exchange_id: ExchangeId, role: Role) -> Box<Future<Item = Exchange, Error = Error> + Send>;
 }
 
 #[derive(Clone)]
 pub struct ExchangeClientImpl {
     cli: Arc<HttpClient>,
     exchange_gateway_url: String,
+    request_timeout: Duration,
     exchange_gateway_user_id: UserId,
     exchange_gateway_token: AuthenticationToken,
     exchange_gateway_system_user_id: UserId,
@@ -36,6 +41,7 @@ impl ExchangeClientImpl {
         Self {
             cli: Arc::new(cli),
             exchange_gateway_url: config.client.exchange_gateway_url.clone(),
+            request_timeout: Duration::from_millis(config.client.request_timeout_ms),
             exchange_gateway_user_id: config.auth.exchange_gateway_user_id.clone(),
             exchange_gateway_token: config.auth.exchange_gateway_token.clone(),
             exchange_gateway_system_user_id: config.system.exchange_gateway_system_user_id,
@@ -49,12 +55,14 @@ impl ExchangeClientImpl {
         body: String,
         method: Method,
         role: Role,
+        idempotency_key: Option<ExchangeId>,
     ) -> impl Future<Item = T, Error = Error> + Send {
         let query = query.to_string();
         let query1 = query.clone();
         let query2 = query.clone();
         let query3 = query.clone();
         let cli = self.cli.clone();
+        let request_timeout = self.request_timeout;
         let mut builder = Request::builder();
         let url = format!("{}{}", self.exchange_gateway_url, query);
         let token = match role {
@@ -63,11 +71,14 @@ impl ExchangeClientImpl {
         };
         builder.uri(url).method(method);
         builder.header("Authorization", format!("Bearer {}", token.raw()));
+        if let Some(idempotency_key) = idempotency_key {
+            builder.header("Idempotency-Key", idempotency_key.inner().to_string());
+        }
         builder
             .body(Body::from(body))
             .map_err(ectx!(ErrorSource::Hyper, ErrorKind::MalformedInput => query3))
             .into_future()
-            .and_then(move |req| cli.request(req).map_err(ectx!(convert => query1)))
+            .and_then(move |req| with_timeout(cli.request(req), request_timeout).map_err(ectx!(convert => query1)))
             .and_then(move |resp| read_body(resp.into_body()).map_err(ectx!(ErrorSource::Hyper, ErrorKind::Internal => query2)))
             .and_then(|bytes| {
                 let bytes_clone = bytes.clone();
@@ -80,13 +91,15 @@ impl ExchangeClientImpl {
 impl ExchangeClient for ExchangeClientImpl {
     fn exchange(&self, create_exchange: ExchangeInput, role: Role) -> Box<Future<Item = Exchange, Error = Error> + Send> {
         let client = self.clone();
+        let exchange_id = create_exchange.id;
         Box::new(
             serde_json::to_string(&create_exchange)
                 .map_err(ectx!(ErrorSource::Json, ErrorKind::Internal => create_exchange))
                 .into_future()
                 .and_then(move |body| {
                     let url = "/exchange";
-                    client.exec_query::<Exchange>(&url, body, Method::POST, role)
+                    // exchange id is the idempotency key, so a retry after a timeout doesn't exchange twice
+                    client.exec_query::<Exchange>(&url, body, Method::POST, role, Some(exchange_id))
                 }),
         )
     }
@@ -99,7 +112,20 @@ impl ExchangeClient for ExchangeClientImpl {
                 .into_future()
                 .and_then(move |body| {
                     let url = "/rate";
-                    client.exec_query::<Rate>(&url, body, Method::POST, role)
+                    client.exec_query::<Rate>(&url, body, Method::POST, role, None)
+                }),
+        )
+    }
+
+    fn fiat_rate(&self, create_rate: FiatRateInput, role: Role) -> Box<Future<Item = FiatRate, Error = Error> + Send> {
+        let client = self.clone();
+        Box::new(
+            serde_json::to_string(&create_rate)
+                .map_err(ectx!(ErrorSource::Json, ErrorKind::Internal => create_rate))
+                .into_future()
+                .and_then(move |body| {
+                    let url = "/rate";
+                    client.exec_query::<FiatRate>(&url, body, Method::POST, role, None)
                 }),
         )
     }
@@ -112,32 +138,105 @@ impl ExchangeClient for ExchangeClientImpl {
                 .into_future()
                 .and_then(move |body| {
                     let url = "/rate/refresh";
-                    client.exec_query::<RateRefresh>(&url, body, Method::POST, role)
+                    client.exec_query::<RateRefresh>(&url, body, Method::POST, role, None)
                 }),
         )
     }
+
+    fn cancel_exchange(&self, exchange_id: ExchangeId, role: Role) -> Box<Future<Item = Exchange, Error = Error> + Send> {
+        let url = format!("/exchange/{}/cancel", exchange_id.inner());
+        Box::new(self.exec_query::<Exchange>(&url, String::new(), Method::POST, role, None))
+    }
 }
 
 #[derive(Default)]
-pub struct ExchangeClientMock;
+pub struct ExchangeClientMock {
+    rate: f64,
+    exchange_error: Option<ErrorKind>,
+    rate_error: Option<ErrorKind>,
+    cancelled_exchanges: Arc<Mutex<Vec<ExchangeId>>>,
+    executed_exchanges: Arc<Mutex<Vec<ExchangeId>>>,
+}
+
+impl ExchangeClientMock {
+    pub fn with_rate(rate: f64) -> Self {
+        Self {
+            rate,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_exchange_error(rate: f64, exchange_error: ErrorKind) -> Self {
+        Self {
+            rate,
+            exchange_error: Some(exchange_error),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_rate_error(rate_error: ErrorKind) -> Self {
+        Self {
+            rate_error: Some(rate_error),
+            ..Default::default()
+        }
+    }
+
+    pub fn cancelled_exchanges(&self) -> Vec<ExchangeId> {
+        self.cancelled_exchanges.lock().unwrap().clone()
+    }
+
+    pub fn executed_exchanges(&self) -> Vec<ExchangeId> {
+        self.executed_exchanges.lock().unwrap().clone()
+    }
+}
 
 impl ExchangeClient for ExchangeClientMock {
-    fn exchange(&self, _exchange: ExchangeInput, _role: Role) -> Box<Future<Item = Exchange, Error = Error> + Send> {
-        Box::new(Ok(Exchange::default()).into_future())
+    fn exchange(&self, exchange: ExchangeInput, _role: Role) -> Box<Future<Item = Exchange, Error = Error> + Send> {
+        match self.exchange_error.clone() {
+            Some(kind) => Box::new(Err(kind.into()).into_future()),
+            None => {
+                // like the gateway, an exchange with a known id is not executed again
+                let mut executed_exchanges = self.executed_exchanges.lock().unwrap();
+                if !executed_exchanges.contains(&exchange.id) {
+                    executed_exchanges.push(exchange.id);
+                }
+                Box::new(Ok(Exchange::default()).into_future())
+            }
+        }
     }
 
-    fn rate(&self, _exchange: RateInput, _role: Role) -> Box<Future<Item = Rate, Error = Error> + Send> {
+    fn rate(&self, input: RateInput, _role: Role) -> Box<Future<Item = Rate, Error = Error> + Send> {
+        if let Some(kind) = self.rate_error.clone() {
+            return Box::new(Err(kind.into()).into_future());
+        }
         Box::new(
             Ok(Rate {
                 expiration: ::chrono::Utc::now().naive_utc(),
                 created_at: ::chrono::Utc::now().naive_utc(),
                 updated_at: ::chrono::Utc::now().naive_utc(),
-                amount_currency: Default::default(),
-                id: Default::default(),
-                from: Default::default(),
-                to: Default::default(),
-                amount: Default::default(),
-                rate: Default::default(),
+                amount_currency: input.amount_currency,
+                id: input.id,
+                from: input.from,
+                to: input.to,
+                amount: input.amount,
+                rate: self.rate,
+            })
+            .into_future(),
+        )
+    }
+
+    fn fiat_rate(&self, input: FiatRateInput, _role: Role) -> Box<Future<Item = FiatRate, Error = Error> + Send> {
+        Box::new(
+            Ok(FiatRate {
+                expiration: ::chrono::Utc::now().naive_utc(),
+                created_at: ::chrono::Utc::now().naive_utc(),
+                updated_at: ::chrono::Utc::now().naive_utc(),
+                amount_currency: input.amount_currency,
+                id: input.id,
+                from: input.from,
+                to: input.to,
+                amount: input.amount,
+                rate: self.rate,
             })
             .into_future(),
         )
@@ -162,4 +261,86 @@ impl ExchangeClient for ExchangeClientMock {
             .into_future(),
         )
     }
+
+    fn cancel_exchange(&self, exchange_id: ExchangeId, _role: Role) -> Box<Future<Item = Exchange, Error = Error> + Send> {
+        self.cancelled_exchanges.lock().unwrap().push(exchange_id);
+        Box::new(Ok(Exchange::default()).into_future())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use client::http_client::Error as HttpClientError;
+    use futures::future;
+    use hyper::Response;
+    use tokio::runtime::current_thread::Runtime;
+
+    #[derive(Clone, Default)]
+    struct HangingHttpClientMock;
+
+    impl HttpClient for HangingHttpClientMock {
+        fn request(&self, _req: Request<Body>) -> Box<Future<Item = Response<Body>, Error = HttpClientError> + Send> {
+            Box::new(future::empty())
+        }
+        fn get(&self, _uri: String) -> Box<Future<Item = Response<Body>, Error = HttpClientError> + Send> {
+            Box::new(future::empty())
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct RecordingHttpClientMock {
+        idempotency_keys: Arc<Mutex<Vec<Option<String>>>>,
+    }
+
+    impl HttpClient for RecordingHttpClientMock {
+        fn request(&self, req: Request<Body>) -> Box<Future<Item = Response<Body>, Error = HttpClientError> + Send> {
+            let key = req
+                .headers()
+                .get("Idempotency-Key")
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.to_string());
+            self.idempotency_keys.lock().unwrap().push(key);
+            Box::new(future::ok(Response::new(Body::from(
+                serde_json::to_string(&Exchange::default()).unwrap(),
+            ))))
+        }
+        fn get(&self, _uri: String) -> Box<Future<Item = Response<Body>, Error = HttpClientError> + Send> {
+            unimplemented!()
+        }
+    }
+
+    #[test]
+    fn test_exchange_idempotency_key() {
+        let mut runtime = Runtime::new().unwrap();
+        let http_client = RecordingHttpClientMock::default();
+        let client = ExchangeClientImpl::new(&Config::new().unwrap(), http_client.clone());
+        let input = ExchangeInput {
+            id: ExchangeId::generate(),
+            from: Currency::Btc,
+            to: Currency::Eth,
+            rate: 30.0,
+            actual_amount: Amount::new(100),
+            amount_currency: Currency::Btc,
+        };
+        // a retry of the same exchange is sent with the same key, so the gateway executes it once
+        runtime.block_on(client.exchange(input.clone(), Role::User)).unwrap();
+        runtime.block_on(client.exchange(input.clone(), Role::User)).unwrap();
+        let expected_key = Some(input.id.inner().to_string());
+        assert_eq!(
+            *http_client.idempotency_keys.lock().unwrap(),
+            vec![expected_key.clone(), expected_key]
+        );
+    }
+
+    #[test]
+    fn test_exchange_request_timeout() {
+        let mut runtime = Runtime::new().unwrap();
+        let mut config = Config::new().unwrap();
+        config.client.request_timeout_ms = 10;
+        let client = ExchangeClientImpl::new(&config, HangingHttpClientMock);
+        let input = RateInput::new(Currency::Btc, Currency::Eth, Amount::new(100), Currency::Btc);
+        let res = runtime.block_on(client.rate(input, Role::User));
+        assert_eq!(res.unwrap_err().kind(), ErrorKind::Unavailable);
+    }
 }
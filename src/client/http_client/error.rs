@@ -39,6 +39,8 @@ pub enum ErrorSource {
     Hyper,
     #[fail(display = "http client source - server returned response with error")]
     Server,
+    #[fail(display = "http client source - error inside of timer")]
+    Timeout,
 }
 
 derive_error_impls!();
@@ -1,5 +1,7 @@
 pub mod error;
 
+use std::time::Duration;
+
 use config::Config;
 use failure::Fail;
 use futures::future::{self, Either};
@@ -8,6 +10,7 @@ use hyper;
 use hyper::{client::HttpConnector, Body, Request, Response};
 use hyper_tls::HttpsConnector;
 use log::{self, Level};
+use tokio::timer::Timeout;
 
 pub use self::error::*;
 use utils::read_body;
@@ -17,6 +20,23 @@ pub trait HttpClient: Send + Sync + 'static {
     fn get(&self, uri: String) -> Box<Future<Item = Response<Body>, Error = Error> + Send>;
 }
 
+/// Fails with `ErrorKind::GatewayTimeout` if the response is not received within `timeout`,
+/// so that a hung upstream service doesn't block callers indefinitely
+pub fn with_timeout<F>(fut: F, timeout: Duration) -> impl Future<Item = F::Item, Error = Error> + Send
+where
+    F: Future<Error = Error> + Send,
+{
+    Timeout::new(fut, timeout).map_err(move |e| {
+        if e.is_elapsed() {
+            ectx!(err ErrorSource::Timeout, ErrorKind::GatewayTimeout => timeout)
+        } else if e.is_inner() {
+            e.into_inner().unwrap()
+        } else {
+            ectx!(err ErrorSource::Timeout, ErrorKind::Internal => timeout)
+        }
+    })
+}
+
 #[derive(Clone)]
 pub struct HttpClientImpl {
     cli: hyper::Client<HttpsConnector<HttpConnector>>,
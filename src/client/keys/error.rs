@@ -2,13 +2,15 @@ use failure::{Backtrace, Context, Fail};
 use std::fmt;
 use std::fmt::Display;
 
+use client::http_client::error::ErrorKind as HttpClientErrorKind;
+
 #[derive(Debug)]
 pub struct Error {
     inner: Context<ErrorKind>,
 }
 
 #[allow(dead_code)]
-#[derive(Copy, Clone, Eq, PartialEq, Debug, Fail)]
+#[derive(Clone, Eq, PartialEq, Debug, Fail)]
 pub enum ErrorKind {
     #[fail(display = "key client error - malformed input")]
     MalformedInput,
@@ -16,6 +18,12 @@ pub enum ErrorKind {
     Unauthorized,
     #[fail(display = "key client error - internal error")]
     Internal,
+    #[fail(display = "key client error - key of the address is not found")]
+    KeyNotFound,
+    #[fail(display = "key client error - signing rejected, errors: {}", _0)]
+    SigningRejected(String),
+    #[fail(display = "key client error - signer is unavailable")]
+    Unavailable,
 }
 
 #[allow(dead_code)]
@@ -30,3 +38,21 @@ pub enum ErrorSource {
 }
 
 derive_error_impls!();
+
+impl From<HttpClientErrorKind> for ErrorKind {
+    fn from(err: HttpClientErrorKind) -> Self {
+        match err {
+            HttpClientErrorKind::NotFound => ErrorKind::KeyNotFound,
+            // the transaction can't be signed as is, e.g. there's not enough gas for it
+            HttpClientErrorKind::Validation(s) => ErrorKind::SigningRejected(s),
+            // 5xx and connection errors - might succeed if retried later
+            HttpClientErrorKind::Internal
+            | HttpClientErrorKind::InternalServer
+            | HttpClientErrorKind::BadGateway
+            | HttpClientErrorKind::GatewayTimeout
+            | HttpClientErrorKind::UnknownServerError => ErrorKind::Unavailable,
+            HttpClientErrorKind::Unauthorized => ErrorKind::Unauthorized,
+            HttpClientErrorKind::BadRequest | HttpClientErrorKind::UnprocessableEntity => ErrorKind::Internal,
+        }
+    }
+}
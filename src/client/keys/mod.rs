@@ -1,7 +1,9 @@
 mod error;
+mod remote_signer;
 mod responses;
 
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use failure::Fail;
 use futures::prelude::*;
@@ -12,8 +14,9 @@ use serde::Deserialize;
 use serde_json;
 
 pub use self::error::*;
+pub use self::remote_signer::*;
 use self::responses::*;
-use super::HttpClient;
+use super::{with_timeout, HttpClient};
 use config::Config;
 use utils::read_body;
 
@@ -31,10 +34,20 @@ pub trait KeysClient: Send + Sync + 'static {
     fn approve(&self, approve_input: ApproveInput, role: Role) -> Box<Future<Item = BlockchainTransactionRaw, Error = Error> + Send>;
 }
 
+/// Keys service client, wrapped into `RemoteSignerKeysClient` if an external signer is configured
+pub fn create_keys_client<C: HttpClient + Clone>(config: &Config, cli: C) -> Arc<dyn KeysClient> {
+    let keys_client = Arc::new(KeysClientImpl::new(config, cli.clone()));
+    match config.remote_signer {
+        Some(ref remote_signer) => Arc::new(RemoteSignerKeysClient::new(keys_client, remote_signer, cli)),
+        None => keys_client,
+    }
+}
+
 #[derive(Clone)]
 pub struct KeysClientImpl {
     cli: Arc<HttpClient>,
     keys_url: String,
+    request_timeout: Duration,
     // Todo - hack to make things quicker in upsert_system_accounts
     keys_user_id: UserId,
     keys_token: AuthenticationToken,
@@ -49,6 +62,7 @@ impl KeysClientImpl {
         Self {
             cli: Arc::new(cli),
             keys_url: config.client.keys_url.clone(),
+            request_timeout: Duration::from_millis(config.client.request_timeout_ms),
             keys_user_id: config.auth.keys_user_id.clone(),
             keys_token: config.auth.keys_token.clone(),
             keys_system_user_id: config.system.keys_system_user_id,
@@ -70,6 +84,7 @@ impl KeysClientImpl {
         let query2 = query.clone();
         let query3 = query.clone();
         let cli = self.cli.clone();
+        let request_timeout = self.request_timeout;
         let mut builder = Request::builder();
         let url = format!("{}{}", self.keys_url, query);
         let token = match role {
@@ -82,7 +97,7 @@ impl KeysClientImpl {
             .body(Body::from(body))
             .map_err(ectx!(ErrorSource::Hyper, ErrorKind::MalformedInput => query3))
             .into_future()
-            .and_then(move |req| cli.request(req).map_err(ectx!(ErrorKind::Internal => query1)))
+            .and_then(move |req| with_timeout(cli.request(req), request_timeout).map_err(ectx!(convert => query1)))
             .and_then(move |resp| read_body(resp.into_body()).map_err(ectx!(ErrorSource::Hyper, ErrorKind::Internal => query2)))
             .and_then(|bytes| {
                 let bytes_clone = bytes.clone();
@@ -148,7 +163,22 @@ impl KeysClient for KeysClientImpl {
 }
 
 #[derive(Default)]
-pub struct KeysClientMock;
+pub struct KeysClientMock {
+    signed_transactions: Mutex<Vec<CreateBlockchainTx>>,
+    sign_error: Option<ErrorKind>,
+}
+
+impl KeysClientMock {
+    pub fn signed_transactions(&self) -> Vec<CreateBlockchainTx> {
+        self.signed_transactions.lock().unwrap().clone()
+    }
+
+    /// Signing fails with `kind` instead of returning a raw transaction
+    pub fn with_sign_error(mut self, kind: ErrorKind) -> Self {
+        self.sign_error = Some(kind);
+        self
+    }
+}
 
 impl KeysClient for KeysClientMock {
     fn create_account_address(
@@ -163,9 +193,13 @@ impl KeysClient for KeysClientMock {
     }
     fn sign_transaction(
         &self,
-        _create_blockchain_tx: CreateBlockchainTx,
+        create_blockchain_tx: CreateBlockchainTx,
         _role: Role,
     ) -> Box<Future<Item = BlockchainTransactionRaw, Error = Error> + Send> {
+        if let Some(ref kind) = self.sign_error {
+            return Box::new(Err(Error::from(kind.clone())).into_future());
+        }
+        self.signed_transactions.lock().unwrap().push(create_blockchain_tx);
         Box::new(Ok(BlockchainTransactionRaw::default()).into_future())
     }
 }
@@ -0,0 +1,205 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use failure::Fail;
+use futures::prelude::*;
+use hyper::Method;
+use hyper::{Body, Request};
+use models::*;
+use serde_json;
+
+use super::error::*;
+use super::responses::*;
+use super::KeysClient;
+use client::{with_timeout, HttpClient};
+use config::RemoteSigner;
+use utils::read_body;
+
+/// Keys client that signs transactions with an external signer (e.g. an HSM behind a webhook),
+/// everything else is delegated to the `inner` keys client
+#[derive(Clone)]
+pub struct RemoteSignerKeysClient {
+    inner: Arc<dyn KeysClient>,
+    cli: Arc<HttpClient>,
+    url: String,
+    token: AuthenticationToken,
+    request_timeout: Duration,
+}
+
+impl RemoteSignerKeysClient {
+    pub fn new<C: HttpClient>(inner: Arc<dyn KeysClient>, config: &RemoteSigner, cli: C) -> Self {
+        Self {
+            inner,
+            cli: Arc::new(cli),
+            url: config.url.clone(),
+            token: config.token.clone(),
+            request_timeout: Duration::from_millis(config.request_timeout_ms),
+        }
+    }
+}
+
+impl KeysClient for RemoteSignerKeysClient {
+    fn create_account_address(
+        &self,
+        create_account: CreateAccountAddress,
+        role: Role,
+    ) -> Box<Future<Item = BlockchainAddress, Error = Error> + Send> {
+        self.inner.create_account_address(create_account, role)
+    }
+    fn approve(&self, approve_input: ApproveInput, role: Role) -> Box<Future<Item = BlockchainTransactionRaw, Error = Error> + Send> {
+        self.inner.approve(approve_input, role)
+    }
+    fn sign_transaction(
+        &self,
+        create_blockchain_tx: CreateBlockchainTx,
+        _role: Role,
+    ) -> Box<Future<Item = BlockchainTransactionRaw, Error = Error> + Send> {
+        let cli = self.cli.clone();
+        let request_timeout = self.request_timeout;
+        let url = self.url.clone();
+        let url1 = url.clone();
+        let url2 = url.clone();
+        let token = self.token.clone();
+        Box::new(
+            serde_json::to_string(&create_blockchain_tx)
+                .map_err(ectx!(ErrorSource::Json, ErrorKind::Internal => create_blockchain_tx))
+                .into_future()
+                .and_then(move |body| {
+                    let mut builder = Request::builder();
+                    builder.uri(url.clone()).method(Method::POST);
+                    builder.header("Authorization", format!("Bearer {}", token.raw()));
+                    builder
+                        .body(Body::from(body))
+                        .map_err(ectx!(ErrorSource::Hyper, ErrorKind::MalformedInput => url))
+                })
+                .and_then(move |req| with_timeout(cli.request(req), request_timeout).map_err(ectx!(convert => url1)))
+                .and_then(move |resp| read_body(resp.into_body()).map_err(ectx!(ErrorSource::Hyper, ErrorKind::Internal => url2)))
+                .and_then(|bytes| {
+                    let bytes_clone = bytes.clone();
+                    String::from_utf8(bytes).map_err(ectx!(ErrorSource::Utf8, ErrorKind::Internal => bytes_clone))
+                })
+                .and_then(|string| {
+                    serde_json::from_str::<CreateBlockchainTxResponse>(&string)
+                        .map_err(ectx!(ErrorSource::Json, ErrorKind::Internal => string))
+                })
+                .map(|resp_data| resp_data.raw),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use client::http_client::{Error as HttpClientError, ErrorKind as HttpClientErrorKind};
+    use client::KeysClientMock;
+    use futures::future;
+    use hyper::Response;
+    use std::sync::Mutex;
+    use tokio::runtime::current_thread::Runtime;
+
+    #[derive(Clone, Default)]
+    struct SignerHttpClientMock {
+        requests: Arc<Mutex<Vec<(String, String, String)>>>,
+    }
+
+    impl HttpClient for SignerHttpClientMock {
+        fn request(&self, req: Request<Body>) -> Box<Future<Item = Response<Body>, Error = HttpClientError> + Send> {
+            let requests = self.requests.clone();
+            let (parts, body) = req.into_parts();
+            let auth = parts
+                .headers
+                .get("Authorization")
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default()
+                .to_string();
+            Box::new(body.concat2().map_err(|_| HttpClientErrorKind::Internal.into()).map(move |chunk| {
+                let body = String::from_utf8(chunk.to_vec()).unwrap();
+                requests.lock().unwrap().push((parts.uri.to_string(), auth, body));
+                Response::new(Body::from(r#"{"raw":"signed_by_hsm"}"#))
+            }))
+        }
+        fn get(&self, _uri: String) -> Box<Future<Item = Response<Body>, Error = HttpClientError> + Send> {
+            unimplemented!()
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct HangingHttpClientMock;
+
+    impl HttpClient for HangingHttpClientMock {
+        fn request(&self, _req: Request<Body>) -> Box<Future<Item = Response<Body>, Error = HttpClientError> + Send> {
+            Box::new(future::empty())
+        }
+        fn get(&self, _uri: String) -> Box<Future<Item = Response<Body>, Error = HttpClientError> + Send> {
+            Box::new(future::empty())
+        }
+    }
+
+    #[derive(Clone)]
+    struct FailingHttpClientMock(HttpClientErrorKind);
+
+    impl HttpClient for FailingHttpClientMock {
+        fn request(&self, _req: Request<Body>) -> Box<Future<Item = Response<Body>, Error = HttpClientError> + Send> {
+            Box::new(future::err(self.0.clone().into()))
+        }
+        fn get(&self, _uri: String) -> Box<Future<Item = Response<Body>, Error = HttpClientError> + Send> {
+            unimplemented!()
+        }
+    }
+
+    fn remote_signer_config() -> RemoteSigner {
+        RemoteSigner {
+            url: "http://hsm-signer:8000/sign".to_string(),
+            token: AuthenticationToken::new("hsm_token".to_string()),
+            request_timeout_ms: 10,
+        }
+    }
+
+    #[test]
+    fn test_remote_signer_sign_transaction() {
+        let mut runtime = Runtime::new().unwrap();
+        let keys_client = Arc::new(KeysClientMock::default());
+        let http_client = SignerHttpClientMock::default();
+        let client = RemoteSignerKeysClient::new(keys_client.clone(), &remote_signer_config(), http_client.clone());
+        let input = CreateBlockchainTx::default();
+        let raw = runtime.block_on(client.sign_transaction(input.clone(), Role::User)).unwrap();
+        assert_eq!(raw, BlockchainTransactionRaw::new("signed_by_hsm".to_string()));
+        // nothing is signed by the keys service
+        assert!(keys_client.signed_transactions().is_empty());
+        let requests = http_client.requests.lock().unwrap().clone();
+        assert_eq!(requests.len(), 1);
+        let (uri, auth, body) = requests[0].clone();
+        assert_eq!(uri, "http://hsm-signer:8000/sign");
+        assert_eq!(auth, "Bearer hsm_token");
+        assert_eq!(body, serde_json::to_string(&input).unwrap());
+    }
+
+    #[test]
+    fn test_remote_signer_timeout() {
+        let mut runtime = Runtime::new().unwrap();
+        let client = RemoteSignerKeysClient::new(Arc::new(KeysClientMock::default()), &remote_signer_config(), HangingHttpClientMock);
+        let res = runtime.block_on(client.sign_transaction(CreateBlockchainTx::default(), Role::User));
+        assert_eq!(res.unwrap_err().kind(), ErrorKind::Unavailable);
+    }
+
+    #[test]
+    fn test_remote_signer_errors() {
+        let mut runtime = Runtime::new().unwrap();
+        let cases = vec![
+            (HttpClientErrorKind::NotFound, ErrorKind::KeyNotFound),
+            (
+                HttpClientErrorKind::Validation("not_enough_gas".to_string()),
+                ErrorKind::SigningRejected("not_enough_gas".to_string()),
+            ),
+            (HttpClientErrorKind::BadGateway, ErrorKind::Unavailable),
+            (HttpClientErrorKind::InternalServer, ErrorKind::Unavailable),
+            (HttpClientErrorKind::BadRequest, ErrorKind::Internal),
+        ];
+        for (http_error, expected) in cases {
+            let http_client = FailingHttpClientMock(http_error);
+            let client = RemoteSignerKeysClient::new(Arc::new(KeysClientMock::default()), &remote_signer_config(), http_client);
+            let res = runtime.block_on(client.sign_transaction(CreateBlockchainTx::default(), Role::User));
+            assert_eq!(res.unwrap_err().kind(), expected);
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use std::collections::HashSet;
 use std::env;
 
 use config_crate::{Config as RawConfig, ConfigError, Environment, File};
@@ -14,12 +15,24 @@ pub struct Config {
     pub rabbit: Rabbit,
     pub auth: Auth,
     pub fee_price: FeePrice,
+    pub min_fee_price: MinFeePrice,
+    pub dust_threshold: DustThreshold,
     pub system: System,
     pub fees_options: FeesOptions,
+    pub fee_tiers: FeeTiers,
     pub sentry: Option<SentryConfig>,
     pub limits: Limits,
+    pub create_transaction_rate_limit: RateLimit,
+    pub daily_withdrawal_limit: DailyWithdrawalLimit,
+    pub max_transaction_value: MaxTransactionValue,
+    pub required_confirmations: RequiredConfirmations,
+    pub withdrawals_enabled: WithdrawalsEnabled,
+    pub amount_precision: AmountPrecision,
+    pub address_screening: AddressScreening,
+    pub transaction_categories: Option<TransactionCategories>,
     pub graylog: Option<GrayLogConfig>,
     pub filelog: Option<FileLogConfig>,
+    pub remote_signer: Option<RemoteSigner>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -28,12 +41,27 @@ pub struct Client {
     pub keys_url: String,
     pub blockchain_url: String,
     pub exchange_gateway_url: String,
+    pub request_timeout_ms: u64,
+    /// Max number of concurrent requests to blockchain gateway, the rest wait in a queue
+    pub blockchain_max_in_flight: usize,
+}
+
+/// External signer (e.g. an HSM) used for withdrawals instead of the keys service
+#[derive(Debug, Deserialize, Clone)]
+pub struct RemoteSigner {
+    pub url: String,
+    pub token: AuthenticationToken,
+    pub request_timeout_ms: u64,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Server {
     pub host: String,
     pub port: String,
+    /// Max `limit` accepted by list endpoints, larger values are clamped
+    pub max_list_limit: i64,
+    /// How long in-flight transactions are waited for on shutdown
+    pub shutdown_timeout_secs: u64,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -42,6 +70,81 @@ pub struct FeePrice {
     pub ethereum: f64,
 }
 
+/// Lowest fee price of withdrawals, satoshis per byte for btc and wei per gas for eth / stq
+#[derive(Debug, Deserialize, Clone)]
+pub struct MinFeePrice {
+    pub btc: f64,
+    pub eth: f64,
+    pub stq: f64,
+}
+
+impl MinFeePrice {
+    pub fn for_currency(&self, currency: Currency) -> f64 {
+        match currency {
+            Currency::Btc => self.btc,
+            Currency::Eth => self.eth,
+            Currency::Stq => self.stq,
+        }
+    }
+}
+
+/// Change below this (in satoshis / wei) is added to the network fee instead of creating an uneconomical change output
+#[derive(Debug, Deserialize, Clone)]
+pub struct DustThreshold {
+    pub btc: u64,
+    pub eth: u64,
+    pub stq: u64,
+}
+
+impl DustThreshold {
+    pub fn for_currency(&self, currency: Currency) -> Amount {
+        let value = match currency {
+            Currency::Btc => self.btc,
+            Currency::Eth => self.eth,
+            Currency::Stq => self.stq,
+        };
+        Amount::new(value as u128)
+    }
+}
+
+/// Fee options offered to users on top of the estimated fee price
+#[derive(Debug, Deserialize, Clone)]
+pub struct FeeTiers {
+    /// Tier used to estimate the fee of a withdrawal if the client omits it
+    pub default_tier: FeeTier,
+    pub slow: FeeTierOptions,
+    pub normal: FeeTierOptions,
+    pub fast: FeeTierOptions,
+}
+
+impl FeeTiers {
+    pub fn for_tier(&self, tier: FeeTier) -> &FeeTierOptions {
+        match tier {
+            FeeTier::Slow => &self.slow,
+            FeeTier::Normal => &self.normal,
+            FeeTier::Fast => &self.fast,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct FeeTierOptions {
+    /// Multiplier of the estimated fee price
+    pub multiplier: f64,
+    /// Rough time until the transaction is mined
+    pub btc_eta_secs: u64,
+    pub eth_eta_secs: u64,
+}
+
+impl FeeTierOptions {
+    pub fn eta_secs(&self, currency: Currency) -> u64 {
+        match currency {
+            Currency::Btc => self.btc_eta_secs,
+            Currency::Eth | Currency::Stq => self.eth_eta_secs,
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct FeesOptions {
     pub btc_fees_collect_url: String,
@@ -49,12 +152,18 @@ pub struct FeesOptions {
     pub btc_transaction_size: i32,
     pub eth_gas_limit: i32,
     pub stq_gas_limit: i32,
+    pub max_gas_limit: u64,
     pub fee_upside: f64,
+    pub node_gas_estimation: bool,
+    pub cached_rate_ttl_secs: u64,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Database {
     pub url: String,
+    pub serialization_retries: usize,
+    /// Replica for read-only endpoints, primary `url` is used if not set
+    pub read_replica_url: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -88,6 +197,10 @@ pub struct System {
     pub btc_liquidity_account_id: AccountId,
     pub eth_liquidity_account_id: AccountId,
     pub stq_liquidity_account_id: AccountId,
+    /// Liquidity accounts exchanges are spread across together with the main one of the currency
+    pub btc_extra_liquidity_account_ids: Vec<AccountId>,
+    pub eth_extra_liquidity_account_ids: Vec<AccountId>,
+    pub stq_extra_liquidity_account_ids: Vec<AccountId>,
     pub btc_fees_account_id: AccountId,
     pub eth_fees_account_id: AccountId,
     pub stq_fees_account_id: AccountId,
@@ -98,6 +211,48 @@ pub struct System {
     pub approve_gas_price: f64,
     pub approve_gas_limit: u64,
     pub approve_delay_secs: u64,
+    pub scheduled_transactions_interval_secs: u64,
+    pub pending_transactions_interval_secs: u64,
+    pub pending_transactions_limit: i64,
+    /// Pending withdrawal unknown to blockchain gateway for this long is considered dropped
+    pub dropped_transaction_timeout_secs: u64,
+    /// Max deviation of client supplied exchange rate from the live one
+    pub exchange_rate_max_deviation_percent: f64,
+    /// Max number of accounts a single withdrawal can be collected from
+    pub max_withdrawal_source_accounts: usize,
+    /// Min interval between withdrawals of a user, 0 to allow them back to back
+    pub withdrawal_cooldown_secs: u64,
+    /// Share of exchanged value kept by the system, in basis points of the value received
+    pub exchange_spread_bps: u32,
+    /// Currencies accepted from clients, the ones missing here are rejected even if supported in code
+    pub enabled_currencies: HashSet<Currency>,
+}
+
+impl System {
+    /// Main liquidity account of `currency` followed by the extra ones of its pool
+    pub fn liquidity_account_ids(&self, currency: Currency) -> Vec<AccountId> {
+        let (main, extra) = match currency {
+            Currency::Btc => (self.btc_liquidity_account_id, &self.btc_extra_liquidity_account_ids),
+            Currency::Eth => (self.eth_liquidity_account_id, &self.eth_extra_liquidity_account_ids),
+            Currency::Stq => (self.stq_liquidity_account_id, &self.stq_extra_liquidity_account_ids),
+        };
+        let mut res = vec![main];
+        for account_id in extra {
+            if !res.contains(account_id) {
+                res.push(*account_id);
+            }
+        }
+        res
+    }
+}
+
+/// Token bucket per user
+#[derive(Debug, Deserialize, Clone)]
+pub struct RateLimit {
+    /// Max number of requests at once
+    pub burst: u32,
+    /// Rate at which requests are allowed after the burst is used up
+    pub refill_per_sec: f64,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -108,6 +263,101 @@ pub struct Limits {
     pub btc_limit: f64,
 }
 
+#[derive(Debug, Deserialize, Clone)]
+pub struct DailyWithdrawalLimit {
+    pub stq: f64,
+    pub eth: f64,
+    pub btc: f64,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct RequiredConfirmations {
+    pub btc: usize,
+    pub eth: usize,
+    pub stq: usize,
+    /// Confirmations of our own utxos before they are spent in withdrawals
+    pub btc_utxo: u64,
+}
+
+impl RequiredConfirmations {
+    pub fn for_currency(&self, currency: Currency) -> usize {
+        match currency {
+            Currency::Btc => self.btc,
+            Currency::Eth => self.eth,
+            Currency::Stq => self.stq,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct WithdrawalsEnabled {
+    pub btc: bool,
+    pub eth: bool,
+    pub stq: bool,
+}
+
+impl WithdrawalsEnabled {
+    pub fn for_currency(&self, currency: Currency) -> bool {
+        match currency {
+            Currency::Btc => self.btc,
+            Currency::Eth => self.eth,
+            Currency::Stq => self.stq,
+        }
+    }
+}
+
+/// Ceiling of a single transaction value (in btc / eth / stq) regardless of balance and limits
+#[derive(Debug, Deserialize, Clone)]
+pub struct MaxTransactionValue {
+    pub btc: f64,
+    pub eth: f64,
+    pub stq: f64,
+}
+
+impl MaxTransactionValue {
+    pub fn for_currency(&self, currency: Currency) -> Amount {
+        let value = match currency {
+            Currency::Btc => self.btc,
+            Currency::Eth => self.eth,
+            Currency::Stq => self.stq,
+        };
+        Amount::from_super_unit(currency, value)
+    }
+}
+
+/// Max number of fractional digits (in btc / eth / stq) of values accepted from clients
+#[derive(Debug, Deserialize, Clone)]
+pub struct AmountPrecision {
+    pub btc: u32,
+    pub eth: u32,
+    pub stq: u32,
+}
+
+impl AmountPrecision {
+    pub fn for_currency(&self, currency: Currency) -> u32 {
+        match currency {
+            Currency::Btc => self.btc,
+            Currency::Eth => self.eth,
+            Currency::Stq => self.stq,
+        }
+    }
+}
+
+/// Screening of withdrawal destinations for compliance
+#[derive(Debug, Deserialize, Clone)]
+pub struct AddressScreening {
+    /// Withdrawals to these addresses are rejected
+    pub denylist: Vec<BlockchainAddress>,
+    /// If set, withdrawals are allowed only to these addresses
+    pub allowlist: Option<Vec<BlockchainAddress>>,
+}
+
+/// Reporting categories users can tag transactions with, any category is accepted if not set
+#[derive(Debug, Deserialize, Clone)]
+pub struct TransactionCategories {
+    pub allowed: Vec<String>,
+}
+
 impl Config {
     pub fn new() -> Result<Self, ConfigError> {
         let mut s = RawConfig::new();
@@ -37,6 +37,7 @@ extern crate chrono;
 extern crate simplelog;
 extern crate tokio;
 extern crate tokio_core;
+extern crate tokio_signal;
 extern crate uuid;
 
 #[macro_use]
@@ -64,8 +65,9 @@ use futures::future::{self, Either};
 use futures_cpupool::CpuPool;
 use tokio::prelude::*;
 use tokio::runtime::Runtime;
-use tokio::timer::{Delay, Timeout};
+use tokio::timer::{Delay, Interval, Timeout};
 use tokio_core::reactor::Core;
+use tokio_signal::unix::{Signal, SIGTERM};
 
 use self::client::HttpClientImpl;
 use self::models::*;
@@ -76,10 +78,10 @@ use self::repos::{
     PendingBlockchainTransactionsRepoImpl, SeenHashesRepoImpl, StrangeBlockchainTransactionsRepoImpl, TransactionsRepo,
     TransactionsRepoImpl, UsersRepo, UsersRepoImpl,
 };
-use client::{BlockchainClientImpl, KeysClient, KeysClientImpl};
+use client::{create_keys_client, BlockchainClient, BlockchainClientImpl, ConcurrencyLimitedBlockchainClient, KeysClient, KeysClientImpl};
 use config::{Config, System};
 use rabbit::{RabbitConnectionManager, TransactionConsumerImpl, TransactionPublisherImpl};
-use services::BlockchainFetcher;
+use services::{BlockchainFetcher, Error as ServiceError, ShutdownCoordinator, SystemClock, SystemService, SystemServiceImpl};
 use utils::log_error;
 
 pub const DELAY_BEFORE_NACK: u64 = 1000;
@@ -119,8 +121,11 @@ pub fn start_server() {
     let pending_blockchain_transactions_repo = Arc::new(PendingBlockchainTransactionsRepoImpl);
     let key_values_repo = Arc::new(KeyValuesRepoImpl);
     let client = HttpClientImpl::new(&config_clone);
-    let blockchain_client = Arc::new(BlockchainClientImpl::new(&config_clone, client.clone()));
-    let keys_client = Arc::new(KeysClientImpl::new(&config_clone, client.clone()));
+    let blockchain_client = Arc::new(ConcurrencyLimitedBlockchainClient::new(
+        Arc::new(BlockchainClientImpl::new(&config_clone, client.clone())),
+        config_clone.client.blockchain_max_in_flight,
+    ));
+    let keys_client = create_keys_client(&config_clone, client.clone());
 
     debug!("Started creating rabbit connection pool");
 
@@ -150,6 +155,44 @@ pub fn start_server() {
     let publisher = Arc::new(publisher);
     let publisher_clone = publisher.clone();
 
+    let system_service = SystemServiceImpl::new(
+        accounts_repo.clone(),
+        transactions_repo.clone(),
+        pending_blockchain_transactions_repo.clone(),
+        Arc::new(config_clone.clone()),
+        Arc::new(SystemClock),
+    );
+    let system_service_clone = system_service.clone();
+    rt.block_on(db_executor.execute(move || system_service_clone.validate_system_accounts()))
+        .map_err(|e| {
+            log_error(&e);
+        })
+        .expect("System accounts are missing or misconfigured");
+    let scheduler_db_executor = db_executor.clone();
+    let scheduler_interval = Duration::from_secs(config_clone.system.scheduled_transactions_interval_secs);
+    rt.spawn(
+        Interval::new(Instant::now() + scheduler_interval, scheduler_interval)
+            .map_err(|e| {
+                error!("Scheduled transactions timer error: {}", e);
+            })
+            .for_each(move |_| {
+                let system_service = system_service.clone();
+                scheduler_db_executor
+                    .execute_transaction_with_isolation(Isolation::Serializable, move || system_service.process_due_transactions())
+                    .then(|res| {
+                        match res {
+                            Ok(txs) => {
+                                if !txs.is_empty() {
+                                    info!("Executed scheduled transactions: {:?}", txs);
+                                }
+                            }
+                            Err(e) => log_error(&e),
+                        }
+                        Ok(())
+                    })
+            }),
+    );
+
     let fetcher = BlockchainFetcher::new(
         Arc::new(config_clone.clone()),
         transactions_repo,
@@ -163,7 +206,31 @@ pub fn start_server() {
         keys_client,
         db_executor,
         publisher_clone,
+        Arc::new(SystemClock),
     );
+    let resolver = fetcher.clone();
+    let resolver_interval = Duration::from_secs(config_clone.system.pending_transactions_interval_secs);
+    let resolver_limit = config_clone.system.pending_transactions_limit;
+    rt.spawn(
+        Interval::new(Instant::now() + resolver_interval, resolver_interval)
+            .map_err(|e| {
+                error!("Pending transactions timer error: {}", e);
+            })
+            .for_each(move |_| {
+                resolver.resolve_pending_transactions(resolver_limit).then(|res| {
+                    match res {
+                        Ok(txs) => {
+                            if !txs.is_empty() {
+                                info!("Resolved pending transactions: {:?}", txs);
+                            }
+                        }
+                        Err(e) => log_error(&e),
+                    }
+                    Ok(())
+                })
+            }),
+    );
+
     let consumer = TransactionConsumerImpl::new(rabbit_connection_manager);
     let consumer_and_chans = rt
         .block_on(consumer.subscribe())
@@ -210,9 +277,42 @@ pub fn start_server() {
         );
     }
 
-    rt.spawn(api::server(config, publisher));
+    let shutdown = ShutdownCoordinator::new();
+    let shutdown_clone = shutdown.clone();
+    let shutdown_timeout = Duration::from_secs(config.server.shutdown_timeout_secs);
+    rt.spawn(api::server(config, publisher, shutdown.clone()));
+
+    // a withdrawal killed between posting to blockchain and saving to db is left without a ledger record,
+    // so on deploy the ones being created are given time to complete
+    let _ = rt.block_on(shutdown_signal().and_then(move |_| {
+        info!("Shutting down, waiting for {} in-flight transactions", shutdown.in_flight());
+        Timeout::new(shutdown.shutdown(), shutdown_timeout).then(move |res| {
+            match res {
+                Ok(_) => info!("In-flight transactions are complete"),
+                Err(_) => error!(
+                    "Shutdown timed out with {} transactions still in flight",
+                    shutdown_clone.in_flight()
+                ),
+            }
+            Ok(())
+        })
+    }));
+    rt.shutdown_now().wait().expect("Tokio runtime shutdown failed");
+}
 
-    rt.shutdown_on_idle().wait().expect("Tokio runtime shutdown failed");
+// SIGTERM is sent on deploy, ctrl-c is handy when running locally
+fn shutdown_signal() -> impl Future<Item = (), Error = ()> {
+    let sigterm = Signal::new(SIGTERM)
+        .flatten_stream()
+        .into_future()
+        .map(|_| ())
+        .map_err(|(e, _)| error!("SIGTERM handler error: {}", e));
+    let ctrl_c = tokio_signal::ctrl_c()
+        .flatten_stream()
+        .into_future()
+        .map(|_| ())
+        .map_err(|(e, _)| error!("Ctrl-C handler error: {}", e));
+    sigterm.select(ctrl_c).map(|_| ()).map_err(|_| ())
 }
 
 fn get_config() -> Config {
@@ -284,6 +384,11 @@ pub fn repair_approval_pending_transaction(id: &str) {
                 "revers of approval transaction with id {}",
                 transaction.id
             ))),
+            hold_until: None,
+            fee_price: None,
+            gross_fee: None,
+            user_note: None,
+            category: None,
         };
         transactions_repo.create(payload).expect("Failed to create transaction");
         transactions_repo
@@ -384,6 +489,11 @@ pub fn repair_withdrawal_pending_transaction(id: &str) {
                     "revers of approval transaction with id {}",
                     transaction.id
                 ))),
+                hold_until: None,
+                fee_price: None,
+                gross_fee: None,
+                user_note: None,
+                category: None,
             };
             transactions_repo.create(payload).expect("Failed to create transaction");
             transactions_repo
@@ -418,6 +528,11 @@ pub fn repair_withdrawal_pending_transaction(id: &str) {
                 "revers of approval fee_transaction with id {}",
                 fee_transaction.id
             ))),
+            hold_until: None,
+            fee_price: None,
+            gross_fee: None,
+            user_note: None,
+            category: None,
         };
         transactions_repo.create(payload).expect("Failed to create transaction");
 
@@ -426,6 +541,131 @@ pub fn repair_withdrawal_pending_transaction(id: &str) {
     hyper::rt::run(fut.map(|_| ()).map_err(|_| ()));
 }
 
+pub fn list_orphaned_withdrawals(limit: i64) {
+    let config = get_config();
+    let db_pool = create_db_pool(&config);
+    let cpu_pool = CpuPool::new(1);
+    let system_service = create_repair_system_service(&config);
+    let db_executor = DbExecutorImpl::new(db_pool, cpu_pool);
+    let fut = db_executor.execute(move || -> Result<(), ServiceError> {
+        let withdrawals = system_service.find_orphaned_withdrawals(limit)?;
+        for withdrawal in withdrawals {
+            let hash = withdrawal.blockchain_tx_id.expect("Failed to get blockchain tx hash");
+            println!("{} {} {} {}", withdrawal.id, hash, withdrawal.currency, withdrawal.value.raw());
+        }
+        Ok(())
+    });
+    hyper::rt::run(fut.map_err(|e| log_error(&e)));
+}
+
+pub fn recover_orphaned_pending_transaction(hash: &str) {
+    let config = get_config();
+    let db_pool = create_db_pool(&config);
+    let cpu_pool = CpuPool::new(1);
+    let fees_accounts_ids = vec![
+        config.system.btc_fees_account_id,
+        config.system.eth_fees_account_id,
+        config.system.stq_fees_account_id,
+    ];
+    let transactions_repo = TransactionsRepoImpl::new(config.system.system_user_id, fees_accounts_ids);
+    let system_service = create_repair_system_service(&config);
+    let blockchain_client = BlockchainClientImpl::new(&config, HttpClientImpl::new(&config));
+    let db_executor = DbExecutorImpl::new(db_pool, cpu_pool);
+    let hash = BlockchainTransactionId::new(hash.to_string());
+    let hash_clone = hash.clone();
+    let mut core = Core::new().unwrap();
+    let transaction = core
+        .run(db_executor.execute(move || transactions_repo.get_by_blockchain_tx(hash_clone)))
+        .expect("Failed to get transaction")
+        .expect("Failed to find transaction");
+    // erc20 transfers are stored with log index suffix, i.e. `<hash>:0`, but gateway knows them by tx hash
+    let gateway_hash = BlockchainTransactionId::new(hash.inner().split(':').next().unwrap_or_default().to_string());
+    let mut blockchain_tx = core
+        .run(blockchain_client.get_transaction(gateway_hash, transaction.currency))
+        .expect("Failed to get blockchain transaction")
+        .expect("Blockchain transaction is neither mined nor in mempool");
+    blockchain_tx.hash = hash.clone();
+    let res = core.run(db_executor.execute_transaction_with_isolation(Isolation::Serializable, move || {
+        system_service.recover_orphaned_pending(hash, blockchain_tx)
+    }));
+    match res {
+        Ok(pending) => println!("{:?}", pending),
+        Err(e) => log_error(&e),
+    }
+}
+
+pub fn fail_dropped_withdrawal(hash: &str) {
+    let config = get_config();
+    let db_pool = create_db_pool(&config);
+    let cpu_pool = CpuPool::new(1);
+    let fees_accounts_ids = vec![
+        config.system.btc_fees_account_id,
+        config.system.eth_fees_account_id,
+        config.system.stq_fees_account_id,
+    ];
+    let transactions_repo = TransactionsRepoImpl::new(config.system.system_user_id, fees_accounts_ids);
+    let system_service = create_repair_system_service(&config);
+    let blockchain_client = BlockchainClientImpl::new(&config, HttpClientImpl::new(&config));
+    let db_executor = DbExecutorImpl::new(db_pool, cpu_pool);
+    let hash = BlockchainTransactionId::new(hash.to_string());
+    let hash_clone = hash.clone();
+    let mut core = Core::new().unwrap();
+    let transaction = core
+        .run(db_executor.execute(move || transactions_repo.get_by_blockchain_tx(hash_clone)))
+        .expect("Failed to get transaction")
+        .expect("Failed to find transaction");
+    // erc20 transfers are stored with log index suffix, i.e. `<hash>:0`, but gateway knows them by tx hash
+    let gateway_hash = BlockchainTransactionId::new(hash.inner().split(':').next().unwrap_or_default().to_string());
+    let blockchain_tx = core
+        .run(blockchain_client.get_transaction(gateway_hash, transaction.currency))
+        .expect("Failed to get blockchain transaction");
+    if blockchain_tx.is_some() {
+        panic!("Blockchain transaction is known to gateway, it will be resolved by pending transactions resolver");
+    }
+    let res = core
+        .run(db_executor.execute_transaction_with_isolation(Isolation::Serializable, move || system_service.fail_dropped_withdrawal(hash)));
+    match res {
+        Ok(transactions) => println!("{:?}", transactions),
+        Err(e) => log_error(&e),
+    }
+}
+
+pub fn recompute_balances(currency: &str, batch_size: i64) {
+    let config = get_config();
+    let db_pool = create_db_pool(&config);
+    let cpu_pool = CpuPool::new(1);
+    let system_service = create_repair_system_service(&config);
+    let db_executor = DbExecutorImpl::new(db_pool, cpu_pool);
+    let currency: Currency = serde_json::from_value(serde_json::Value::String(currency.to_string())).expect("Failed to parse currency");
+    // not in a db transaction, so every batch is read on its own and nothing is locked for the whole run
+    let fut = db_executor.execute(move || -> Result<(), ServiceError> {
+        for progress in system_service.recompute_all_balances(currency, batch_size).wait() {
+            let progress = progress?;
+            for balance in progress.balances {
+                println!("{} {}", balance.account.id, balance.balance.raw());
+            }
+            eprintln!("Recomputed {} {} accounts", progress.processed, progress.currency);
+        }
+        Ok(())
+    });
+    hyper::rt::run(fut.map_err(|e| log_error(&e)));
+}
+
+fn create_repair_system_service(config: &Config) -> SystemServiceImpl {
+    let fees_accounts_ids = vec![
+        config.system.btc_fees_account_id,
+        config.system.eth_fees_account_id,
+        config.system.stq_fees_account_id,
+    ];
+    SystemServiceImpl::new(
+        Arc::new(AccountsRepoImpl),
+        Arc::new(TransactionsRepoImpl::new(config.system.system_user_id, fees_accounts_ids)),
+        Arc::new(PendingBlockchainTransactionsRepoImpl),
+        Arc::new(config.clone()),
+        Arc::new(SystemClock),
+    )
+}
+
 pub fn upsert_system_accounts() {
     let config = get_config();
     let client = HttpClientImpl::new(&config);
@@ -443,6 +683,9 @@ pub fn upsert_system_accounts() {
         btc_liquidity_account_id,
         eth_liquidity_account_id,
         stq_liquidity_account_id,
+        btc_extra_liquidity_account_ids,
+        eth_extra_liquidity_account_ids,
+        stq_extra_liquidity_account_ids,
         btc_fees_account_id,
         eth_fees_account_id,
         stq_fees_account_id,
@@ -469,24 +712,49 @@ pub fn upsert_system_accounts() {
         })
         .and_then(move |user| {
             let keys_client = keys_client.clone();
-            let inputs = [
-                (btc_transfer_account_id, user.id, Currency::Btc, "btc_transfer_account"),
-                (eth_transfer_account_id, user.id, Currency::Eth, "eth_transfer_account"),
-                (stq_transfer_account_id, user.id, Currency::Stq, "stq_transfer_account"),
-                (btc_liquidity_account_id, user.id, Currency::Btc, "btc_liquidity_account"),
-                (eth_liquidity_account_id, user.id, Currency::Eth, "eth_liquidity_account"),
-                (stq_liquidity_account_id, user.id, Currency::Stq, "stq_liquidity_account"),
-                (btc_fees_account_id, user.id, Currency::Btc, "btc_fees_account"),
-                (eth_fees_account_id, user.id, Currency::Eth, "eth_fees_account"),
-                (stq_fees_account_id, user.id, Currency::Stq, "stq_fees_account"),
+            let mut inputs = vec![
+                (btc_transfer_account_id, user.id, Currency::Btc, "btc_transfer_account".to_string()),
+                (eth_transfer_account_id, user.id, Currency::Eth, "eth_transfer_account".to_string()),
+                (stq_transfer_account_id, user.id, Currency::Stq, "stq_transfer_account".to_string()),
+                (
+                    btc_liquidity_account_id,
+                    user.id,
+                    Currency::Btc,
+                    "btc_liquidity_account".to_string(),
+                ),
+                (
+                    eth_liquidity_account_id,
+                    user.id,
+                    Currency::Eth,
+                    "eth_liquidity_account".to_string(),
+                ),
+                (
+                    stq_liquidity_account_id,
+                    user.id,
+                    Currency::Stq,
+                    "stq_liquidity_account".to_string(),
+                ),
+                (btc_fees_account_id, user.id, Currency::Btc, "btc_fees_account".to_string()),
+                (eth_fees_account_id, user.id, Currency::Eth, "eth_fees_account".to_string()),
+                (stq_fees_account_id, user.id, Currency::Stq, "stq_fees_account".to_string()),
             ];
+            for (currency, account_ids) in vec![
+                (Currency::Btc, btc_extra_liquidity_account_ids),
+                (Currency::Eth, eth_extra_liquidity_account_ids),
+                (Currency::Stq, stq_extra_liquidity_account_ids),
+            ] {
+                for (i, account_id) in account_ids.into_iter().enumerate() {
+                    let name = format!("{}_liquidity_account_{}", currency, i + 1);
+                    inputs.push((account_id, user.id, currency, name));
+                }
+            }
             let fs: Vec<_> = inputs
                 .into_iter()
                 .map(move |(account_id, user_id, currency, name)| {
                     let keys_client = keys_client.clone();
                     let db_executor = db_executor.clone();
 
-                    upsert_system_account(*account_id, *user_id, *currency, name, keys_client, db_executor)
+                    upsert_system_account(account_id, user_id, currency, &name, keys_client, db_executor)
                 })
                 .collect();
             futures::future::join_all(fs)
@@ -22,6 +22,21 @@ fn main() {
     } else if let Some(matches) = matches.subcommand_matches("repair_withdrawal_pending_transaction") {
         let id = matches.value_of("id").unwrap();
         transactions_lib::repair_withdrawal_pending_transaction(&id);
+    } else if let Some(matches) = matches.subcommand_matches("list_orphaned_withdrawals") {
+        let limit = matches.value_of("limit").unwrap_or("100");
+        let limit = limit.parse().expect("Failed to parse limit");
+        transactions_lib::list_orphaned_withdrawals(limit);
+    } else if let Some(matches) = matches.subcommand_matches("recover_orphaned_pending_transaction") {
+        let hash = matches.value_of("hash").unwrap();
+        transactions_lib::recover_orphaned_pending_transaction(&hash);
+    } else if let Some(matches) = matches.subcommand_matches("fail_dropped_withdrawal") {
+        let hash = matches.value_of("hash").unwrap();
+        transactions_lib::fail_dropped_withdrawal(&hash);
+    } else if let Some(matches) = matches.subcommand_matches("recompute_balances") {
+        let currency = matches.value_of("currency").unwrap();
+        let batch_size = matches.value_of("batch_size").unwrap_or("1000");
+        let batch_size = batch_size.parse().expect("Failed to parse batch size");
+        transactions_lib::recompute_balances(&currency, batch_size);
     } else {
         let _ = app.print_help();
         println!("\n")
@@ -18,6 +18,8 @@ pub struct Account {
     pub updated_at: NaiveDateTime,
     pub erc20_approved: bool,
     pub daily_limit_type: DailyLimitType,
+    /// Part of the balance that is never collected by withdrawals from the account address
+    pub reserve: Amount,
 }
 
 impl Default for Account {
@@ -33,6 +35,7 @@ impl Default for Account {
             updated_at: ::chrono::Utc::now().naive_utc(),
             erc20_approved: false,
             daily_limit_type: DailyLimitType::DefaultLimit,
+            reserve: Amount::default(),
         }
     }
 }
@@ -100,6 +103,7 @@ pub struct UpdateAccount {
     #[validate(length(min = "1", max = "40", message = "Name must not be empty "))]
     pub name: Option<String>,
     pub erc20_approved: Option<bool>,
+    pub reserve: Option<Amount>,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -108,6 +112,15 @@ pub struct AccountWithBalance {
     pub balance: Amount,
 }
 
+/// Total balance of all user's accounts in one currency
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CurrencyBalance {
+    pub currency: Currency,
+    pub balance: Amount,
+    pub accounts_count: u64,
+}
+
 #[derive(Debug, Clone, Validate)]
 pub struct CreateAccount {
     pub id: AccountId,
@@ -138,7 +151,7 @@ impl From<(CreateAccount, BlockchainAddress)> for NewAccount {
             user_id: create.0.user_id,
             currency: create.0.currency,
             kind: AccountKind::Cr,
-            address: create.1,
+            address: create.1.normalized(create.0.currency),
             daily_limit_type: create.0.daily_limit_type,
         }
     }
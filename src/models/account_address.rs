@@ -41,6 +41,17 @@ impl BlockchainAddress {
     pub fn raw(&self) -> &str {
         &self.0
     }
+
+    /// Canonical form of address used for storing and lookups. Ethereum addresses are case insensitive
+    /// (mixed case is only an EIP-55 checksum), so they are lowercased. Bitcoin addresses are case
+    /// sensitive and are only trimmed.
+    pub fn normalized(&self, currency: Currency) -> Self {
+        let address = self.0.trim();
+        match currency {
+            Currency::Eth | Currency::Stq => BlockchainAddress(address.to_lowercase()),
+            Currency::Btc => BlockchainAddress(address.to_string()),
+        }
+    }
 }
 
 impl Default for BlockchainAddress {
@@ -78,3 +89,23 @@ impl From<CreateAccount> for CreateAccountAddress {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalized() {
+        let checksummed = BlockchainAddress::new(" 5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed ".to_string());
+        let lowercase = BlockchainAddress::new("5aaeb6053f3e94c9b9a09f33669435e7ef1beaed".to_string());
+        assert_eq!(checksummed.normalized(Currency::Eth), lowercase);
+        assert_eq!(checksummed.normalized(Currency::Stq), lowercase);
+        assert_eq!(lowercase.normalized(Currency::Eth), lowercase);
+
+        let btc = BlockchainAddress::new(" 1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2\n".to_string());
+        assert_eq!(
+            btc.normalized(Currency::Btc),
+            BlockchainAddress::new("1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2".to_string())
+        );
+    }
+}
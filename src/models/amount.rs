@@ -16,11 +16,31 @@ use super::Currency;
 /// Numeric type from postgres has bigger precision, so you need to impose contraint
 /// that your db contains only limited precision numbers, i.e. no floating point and limited by u128 values.
 ///
-/// As a monetary amount it only implements checked_add and checked_sub
+/// As a monetary amount it only implements checked arithmetic, either returning `Option`
+/// (`checked_*`) or `Result` with `AmountError` (`try_*`)
 #[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, FromSqlRow, AsExpression, Default, PartialOrd)]
 #[sql_type = "Numeric"]
 pub struct Amount(u128);
 
+/// Error of `Amount` arithmetic, holds raw operands
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Fail)]
+pub enum AmountError {
+    #[fail(display = "amount error - overflow: {} {} {}", left, op, right)]
+    Overflow { left: u128, op: char, right: u128 },
+    #[fail(display = "amount error - underflow: {} - {}", left, right)]
+    Underflow { left: u128, right: u128 },
+    #[fail(display = "amount error - division by zero: {} / 0", _0)]
+    DivByZero(u128),
+}
+
+/// Error of parsing a display string with `Amount::from_display_string`
+#[derive(Clone, Debug, PartialEq, Eq, Fail)]
+#[fail(display = "amount parse error - invalid {} amount: {:?}", currency, value)]
+pub struct AmountParseError {
+    pub value: String,
+    pub currency: Currency,
+}
+
 const WEI_IN_ETH: u32 = 18;
 const SATOSHIS_IN_BTC: u32 = 8;
 const MAX_WEI_PRECISION: u32 = 6;
@@ -47,6 +67,37 @@ impl Amount {
         self.0.checked_div(other.0).map(Amount)
     }
 
+    /// Make addition, return error on overflow
+    pub fn try_add(&self, other: Amount) -> Result<Self, AmountError> {
+        self.checked_add(other).ok_or(AmountError::Overflow {
+            left: self.0,
+            op: '+',
+            right: other.0,
+        })
+    }
+
+    /// Make subtraction, return error if result is negative
+    pub fn try_sub(&self, other: Amount) -> Result<Self, AmountError> {
+        self.checked_sub(other).ok_or(AmountError::Underflow {
+            left: self.0,
+            right: other.0,
+        })
+    }
+
+    /// Make multiplication, return error on overflow
+    pub fn try_mul(&self, other: Amount) -> Result<Self, AmountError> {
+        self.checked_mul(other).ok_or(AmountError::Overflow {
+            left: self.0,
+            op: '*',
+            right: other.0,
+        })
+    }
+
+    /// Make div, return error on division by zero
+    pub fn try_div(&self, other: Amount) -> Result<Self, AmountError> {
+        self.checked_div(other).ok_or(AmountError::DivByZero(self.0))
+    }
+
     pub fn new(v: u128) -> Self {
         Amount(v)
     }
@@ -98,6 +149,165 @@ impl Amount {
         let converted: f64 = (amount as f64) / divisor_f64;
         converted
     }
+
+    /// Renders in super units (btc, eth, stq) with all the base unit decimals, trailing zeros trimmed,
+    /// e.g. `1.5` for 150_000_000 satoshis
+    pub fn to_display_string(&self, currency: Currency) -> String {
+        let decimals = decimals(currency);
+        let divisor = 10u128.pow(decimals);
+        let (whole, fraction) = (self.0 / divisor, self.0 % divisor);
+        if fraction == 0 {
+            return whole.to_string();
+        }
+        let fraction = format!("{:0width$}", fraction, width = decimals as usize);
+        format!("{}.{}", whole, fraction.trim_end_matches('0'))
+    }
+
+    /// Parses a string rendered by `to_display_string`. Fractions finer than the base unit,
+    /// signs, exponents and values out of `u128` range are rejected
+    pub fn from_display_string(value: &str, currency: Currency) -> Result<Self, AmountParseError> {
+        let error = || AmountParseError {
+            value: value.to_string(),
+            currency,
+        };
+        let decimals = decimals(currency) as usize;
+        let (whole, fraction) = match value.find('.') {
+            Some(pos) => (&value[..pos], &value[pos + 1..]),
+            None => (value, ""),
+        };
+        let is_digits = |s: &str| s.chars().all(|c| c.is_ascii_digit());
+        if whole.is_empty() || !is_digits(whole) || !is_digits(fraction) || fraction.len() > decimals {
+            return Err(error());
+        }
+        if value.ends_with('.') {
+            return Err(error());
+        }
+        let fraction = format!("{:0<width$}", fraction, width = decimals);
+        let whole: u128 = whole.parse().map_err(|_| error())?;
+        let fraction: u128 = fraction.parse().map_err(|_| error())?;
+        whole
+            .checked_mul(10u128.pow(decimals as u32))
+            .and_then(|whole| whole.checked_add(fraction))
+            .map(Amount)
+            .ok_or_else(error)
+    }
+
+    pub fn from_super_unit(currency: Currency, value: f64) -> Self {
+        let multiplier_u128 = match currency {
+            Currency::Btc => SATOSHIS_IN_BTC - MAX_SATOSHIS_PRECISION,
+            Currency::Eth => WEI_IN_ETH - MAX_WEI_PRECISION,
+            Currency::Stq => WEI_IN_ETH - MAX_WEI_PRECISION,
+        };
+        let multiplier_f64 = match currency {
+            Currency::Btc => MAX_SATOSHIS_PRECISION,
+            Currency::Eth => MAX_WEI_PRECISION,
+            Currency::Stq => MAX_WEI_PRECISION,
+        };
+
+        let multiplier_u128 = 10u128.pow(multiplier_u128);
+        let multiplier_f64 = 10u128.pow(multiplier_f64) as f64;
+        let amount = (value * multiplier_f64).round() as u128;
+        Amount::new(amount * multiplier_u128)
+    }
+}
+
+fn decimals(currency: Currency) -> u32 {
+    match currency {
+        Currency::Btc => SATOSHIS_IN_BTC,
+        Currency::Eth => WEI_IN_ETH,
+        Currency::Stq => WEI_IN_ETH,
+    }
+}
+
+/// Json (de)serialization of `Amount` as a decimal string, use with `#[serde(with = "amount_as_string")]`.
+/// Most clients parse json numbers as f64, that loses precision above 2^53 (~0.009 STQ in wei).
+/// Both strings and numbers are accepted on input.
+pub mod amount_as_string {
+    use std::fmt;
+
+    use serde::de::{self, Deserialize, Deserializer, MapAccess, Visitor};
+    use serde::Serializer;
+    use serde_json;
+
+    use super::Amount;
+
+    pub fn serialize<S>(amount: &Amount, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&amount.0.to_string())
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Amount, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_any(AmountVisitor)
+    }
+
+    struct AmountVisitor;
+
+    impl<'de> Visitor<'de> for AmountVisitor {
+        type Value = Amount;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("non-negative integer as a string or number")
+        }
+
+        fn visit_str<E>(self, value: &str) -> Result<Amount, E>
+        where
+            E: de::Error,
+        {
+            value
+                .parse()
+                .map(Amount)
+                .map_err(|_| de::Error::invalid_value(de::Unexpected::Str(value), &self))
+        }
+
+        fn visit_u64<E>(self, value: u64) -> Result<Amount, E>
+        where
+            E: de::Error,
+        {
+            Ok(Amount(u128::from(value)))
+        }
+
+        // with `arbitrary_precision` serde_json passes numbers as a map
+        fn visit_map<M>(self, visitor: M) -> Result<Amount, M::Error>
+        where
+            M: MapAccess<'de>,
+        {
+            let number: serde_json::Number = Deserialize::deserialize(de::value::MapAccessDeserializer::new(visitor))?;
+            self.visit_str(&number.to_string())
+        }
+    }
+
+    /// Same for `Option<Amount>`, use with `#[serde(with = "amount_as_string::option")]`
+    pub mod option {
+        use serde::{Deserialize, Deserializer, Serializer};
+
+        use super::super::Amount;
+
+        #[derive(Deserialize)]
+        struct AmountString(#[serde(deserialize_with = "::models::amount_as_string::deserialize")] Amount);
+
+        pub fn serialize<S>(amount: &Option<Amount>, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+        {
+            match *amount {
+                Some(ref amount) => super::serialize(amount, serializer),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<Amount>, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            let amount: Option<AmountString> = Deserialize::deserialize(deserializer)?;
+            Ok(amount.map(|AmountString(amount)| amount))
+        }
+    }
 }
 
 impl<'a> From<&'a Amount> for PgNumeric {
@@ -309,6 +519,59 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_display_string() {
+        let cases = [
+            (0, Currency::Btc, "0"),
+            (100_000_000, Currency::Btc, "1"),
+            (150_000_000, Currency::Btc, "1.5"),
+            (1, Currency::Btc, "0.00000001"),
+            (2_100_000_000_000_000, Currency::Btc, "21000000"),
+            (1_000_000_000_000_000_000, Currency::Eth, "1"),
+            (100_000_000_000_000_000, Currency::Eth, "0.1"),
+            (1_000_000_000_000_000_001, Currency::Eth, "1.000000000000000001"),
+            (13_000_000_000_000_000_000_000, Currency::Stq, "13000"),
+            (12_345_670_000_000_000_000, Currency::Stq, "12.34567"),
+        ];
+        for (amount, currency, display) in cases.into_iter() {
+            let amount = Amount::new(*amount);
+            assert_eq!(amount.to_display_string(*currency), *display, "Case: {:?}", amount);
+            assert_eq!(Amount::from_display_string(display, *currency), Ok(amount), "Case: {}", display);
+        }
+        // not in the shortest form, but still valid
+        assert_eq!(Amount::from_display_string("1.50", Currency::Btc), Ok(Amount(150_000_000)));
+        assert_eq!(
+            Amount::from_display_string("007", Currency::Eth),
+            Ok(Amount(7_000_000_000_000_000_000))
+        );
+        assert_eq!(
+            Amount::new(u128::max_value()).to_display_string(Currency::Eth),
+            "340282366920938463463.374607431768211455"
+        );
+    }
+
+    #[test]
+    fn test_display_string_errors() {
+        let error_cases = [
+            ("", Currency::Btc),
+            (".5", Currency::Btc),
+            ("1.", Currency::Btc),
+            ("-1", Currency::Btc),
+            ("+1", Currency::Btc),
+            ("1e8", Currency::Btc),
+            ("1,5", Currency::Btc),
+            ("1.2.3", Currency::Btc),
+            // finer than a satoshi
+            ("0.000000001", Currency::Btc),
+            ("0.0000000000000000001", Currency::Eth),
+            // u128::max_value wei + 1
+            ("340282366920938463463.374607431768211456", Currency::Stq),
+        ];
+        for (case, currency) in error_cases.into_iter() {
+            assert!(Amount::from_display_string(case, *currency).is_err(), "Case: {}", case);
+        }
+    }
+
     #[test]
     fn test_pg_numeric_happy_conversions() {
         let cases = [
@@ -451,4 +714,68 @@ mod tests {
         assert_eq!(Amount(8).checked_sub(Amount(11)), None);
     }
 
+    #[test]
+    fn test_try_ops() {
+        let max = u128::max_value();
+        assert_eq!(Amount(max - 1).try_add(Amount(1)), Ok(Amount(max)));
+        assert_eq!(
+            Amount(max).try_add(Amount(1)),
+            Err(AmountError::Overflow {
+                left: max,
+                op: '+',
+                right: 1
+            })
+        );
+        assert_eq!(Amount(11).try_sub(Amount(11)), Ok(Amount(0)));
+        assert_eq!(Amount(10).try_sub(Amount(11)), Err(AmountError::Underflow { left: 10, right: 11 }));
+        assert_eq!(Amount(max / 2).try_mul(Amount(2)), Ok(Amount(max - 1)));
+        assert_eq!(
+            Amount(max / 2 + 1).try_mul(Amount(2)),
+            Err(AmountError::Overflow {
+                left: max / 2 + 1,
+                op: '*',
+                right: 2
+            })
+        );
+        assert_eq!(Amount(13).try_div(Amount(2)), Ok(Amount(6)));
+        assert_eq!(Amount(13).try_div(Amount(0)), Err(AmountError::DivByZero(13)));
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct AmountStringHolder {
+        #[serde(with = "amount_as_string")]
+        value: Amount,
+        #[serde(with = "amount_as_string::option")]
+        fee: Option<Amount>,
+    }
+
+    #[test]
+    fn test_serde_amount_as_string() {
+        // 2^53 + 1 is the first integer not representable in f64
+        let big = Amount((1u128 << 53) + 1);
+        let holder = AmountStringHolder {
+            value: big,
+            fee: Some(Amount(u128::max_value())),
+        };
+        let serialized = serde_json::to_string(&holder).unwrap();
+        assert_eq!(
+            serialized,
+            r#"{"value":"9007199254740993","fee":"340282366920938463463374607431768211455"}"#
+        );
+        assert_eq!(serde_json::from_str::<AmountStringHolder>(&serialized).unwrap(), holder);
+
+        // numbers are still accepted
+        let parsed: AmountStringHolder = serde_json::from_str(r#"{"value":9007199254740993,"fee":null}"#).unwrap();
+        assert_eq!(parsed, AmountStringHolder { value: big, fee: None });
+        let parsed: AmountStringHolder = serde_json::from_str(r#"{"value":"10","fee":340282366920938463463374607431768211455}"#).unwrap();
+        assert_eq!(parsed.fee, Some(Amount(u128::max_value())));
+
+        for case in &[
+            r#"{"value":"-1","fee":null}"#,
+            r#"{"value":"1.5","fee":null}"#,
+            r#"{"value":"abc","fee":null}"#,
+        ] {
+            assert!(serde_json::from_str::<AmountStringHolder>(case).is_err());
+        }
+    }
 }
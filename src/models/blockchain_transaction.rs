@@ -66,6 +66,10 @@ pub struct BlockchainTransaction {
     pub fee: Amount,
     pub confirmations: usize,
     pub erc20_operation_kind: Option<Erc20OperationKind>,
+    /// Ethereum transaction was mined, but failed (out of gas, reverted contract call),
+    /// so nothing is transferred, while the network fee is paid
+    #[serde(default)]
+    pub reverted: bool,
 }
 
 impl BlockchainTransaction {
@@ -91,6 +95,21 @@ impl BlockchainTransaction {
         Ok((from, to))
     }
 
+    /// Addresses in the form they are stored in accounts, so that lookups by them match
+    pub fn with_normalized_addresses(&self) -> BlockchainTransaction {
+        let currency = self.currency;
+        let from = self.from.iter().map(|address| address.normalized(currency)).collect();
+        let to = self
+            .to
+            .iter()
+            .map(|entry| BlockchainTransactionEntryTo {
+                address: entry.address.normalized(currency),
+                value: entry.value,
+            })
+            .collect();
+        BlockchainTransaction { from, to, ..self.clone() }
+    }
+
     pub fn normalized(&self) -> Option<BlockchainTransaction> {
         let from: HashSet<BlockchainAddress> = self.from.clone().into_iter().collect();
 
@@ -172,6 +191,9 @@ impl From<BlockchainTransactionDB> for BlockchainTransaction {
             fee: transaction.fee,
             confirmations: transaction.confirmations as usize,
             erc20_operation_kind: transaction.erc20_operation_kind,
+            // the flag is not stored: deposits are stored only if successful, but withdrawals are upserted
+            // even if reverted, so a stored withdrawal might have failed - check the status of its legs
+            reverted: false,
         }
     }
 }
@@ -264,6 +286,32 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_with_normalized_addresses() {
+        let tx = BlockchainTransaction {
+            from: vec![BlockchainAddress::new(" 0xAbC ".to_string())],
+            to: vec![BlockchainTransactionEntryTo {
+                address: BlockchainAddress::new("0xDeF".to_string()),
+                value: Amount::new(25),
+            }],
+            currency: Currency::Stq,
+            ..Default::default()
+        };
+        let normalized_tx = tx.with_normalized_addresses();
+        assert_eq!(normalized_tx.from, vec![BlockchainAddress::new("0xabc".to_string())]);
+        assert_eq!(normalized_tx.to[0].address, BlockchainAddress::new("0xdef".to_string()));
+        assert_eq!(normalized_tx.to[0].value, Amount::new(25));
+
+        // bitcoin addresses are case sensitive
+        let tx = BlockchainTransaction {
+            currency: Currency::Btc,
+            ..tx
+        };
+        let normalized_tx = tx.with_normalized_addresses();
+        assert_eq!(normalized_tx.from, vec![BlockchainAddress::new("0xAbC".to_string())]);
+        assert_eq!(normalized_tx.to[0].address, BlockchainAddress::new("0xDeF".to_string()));
+    }
+
     #[test]
     fn test_value() {
         let tx = BlockchainTransaction {
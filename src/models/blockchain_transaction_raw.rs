@@ -1,6 +1,8 @@
 use diesel::sql_types::Varchar;
 use uuid::Uuid;
 
+use models::*;
+
 #[derive(Debug, Serialize, Deserialize, FromSqlRow, AsExpression, Clone, PartialEq)]
 #[sql_type = "Varchar"]
 pub struct BlockchainTransactionRaw(String);
@@ -21,3 +23,11 @@ impl Default for BlockchainTransactionRaw {
         BlockchainTransactionRaw(Uuid::new_v4().to_string())
     }
 }
+
+/// Signed blockchain transaction of a withdrawal leg, `raw_tx` is known
+/// only until the transaction is seen in blockchain
+#[derive(Debug, Clone)]
+pub struct SignedBlockchainTransaction {
+    pub hash: BlockchainTransactionId,
+    pub raw_tx: Option<BlockchainTransactionRaw>,
+}
@@ -1,4 +1,4 @@
-use super::{Amount, Currency};
+use super::{amount_as_string, Amount, Currency, FiatCurrency, TransactionId};
 
 use chrono::NaiveDateTime;
 use std::fmt::{self, Debug, Display};
@@ -35,6 +35,11 @@ impl ExchangeId {
     pub fn generate() -> Self {
         ExchangeId(Uuid::new_v4())
     }
+
+    /// Exchange of the transaction group, retries of the group get the same id, so the gateway deduplicates them
+    pub fn for_transaction(gid: TransactionId) -> Self {
+        ExchangeId(*gid.inner())
+    }
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -92,6 +97,42 @@ pub struct Rate {
     pub updated_at: NaiveDateTime,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FiatRateInput {
+    pub id: ExchangeId,
+    pub from: FiatCurrency,
+    pub to: Currency,
+    pub amount: Amount,
+    pub amount_currency: FiatCurrency,
+}
+
+impl FiatRateInput {
+    pub fn new(from: FiatCurrency, to: Currency, amount: Amount) -> Self {
+        Self {
+            id: ExchangeId::generate(),
+            from,
+            to,
+            amount,
+            amount_currency: from,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FiatRate {
+    pub id: ExchangeId,
+    pub from: FiatCurrency,
+    pub to: Currency,
+    pub amount: Amount,
+    pub amount_currency: FiatCurrency,
+    pub rate: f64,
+    pub expiration: NaiveDateTime,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct RateRefreshInput {
@@ -104,3 +145,20 @@ pub struct RateRefresh {
     pub exchange: Rate,
     pub is_new_rate: bool,
 }
+
+/// Exchange executed by the user, as recorded in an internal multi currency transaction
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExecutedExchange {
+    pub transaction_id: TransactionId,
+    /// Missing for exchanges made before the executed rate was persisted
+    pub exchange_id: Option<ExchangeId>,
+    pub from_currency: Currency,
+    pub to_currency: Currency,
+    #[serde(with = "amount_as_string")]
+    pub from_value: Amount,
+    #[serde(with = "amount_as_string")]
+    pub to_value: Amount,
+    pub rate: Option<f64>,
+    pub created_at: NaiveDateTime,
+}
@@ -44,3 +44,12 @@ impl Default for GetFees {
         }
     }
 }
+
+/// Speed of a withdrawal the user is willing to pay for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FeeTier {
+    Slow,
+    Normal,
+    Fast,
+}
@@ -0,0 +1,77 @@
+use std::fmt::{self, Display};
+
+use super::{Amount, Currency};
+
+/// Fiat currencies are only quoting units - there are no accounts in fiat
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum FiatCurrency {
+    Usd,
+}
+
+impl Default for FiatCurrency {
+    fn default() -> Self {
+        FiatCurrency::Usd
+    }
+}
+
+impl Display for FiatCurrency {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FiatCurrency::Usd => f.write_str("usd"),
+        }
+    }
+}
+
+/// Currency of the `value` field in transaction request - either crypto or fiat
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq, Hash)]
+#[serde(untagged)]
+pub enum ValueCurrency {
+    Crypto(Currency),
+    Fiat(FiatCurrency),
+}
+
+/// Value quoted in fiat, `value` is in cents
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct FiatValue {
+    pub currency: FiatCurrency,
+    pub value: Amount,
+}
+
+impl FiatValue {
+    pub fn new(currency: FiatCurrency, value: Amount) -> Self {
+        Self { currency, value }
+    }
+
+    /// Converts fiat value to `to_currency` given `rate` - units of `to_currency` per one fiat unit
+    pub fn to_crypto(&self, to_currency: Currency, rate: f64) -> Amount {
+        let fiat = (self.value.raw() as f64) / 100.0;
+        Amount::from_super_unit(to_currency, fiat * rate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    #[test]
+    fn test_value_currency_deserialize() {
+        let crypto: ValueCurrency = serde_json::from_str("\"btc\"").unwrap();
+        assert_eq!(crypto, ValueCurrency::Crypto(Currency::Btc));
+        let fiat: ValueCurrency = serde_json::from_str("\"usd\"").unwrap();
+        assert_eq!(fiat, ValueCurrency::Fiat(FiatCurrency::Usd));
+        // fiat is only a quoting unit and is not a wallet currency
+        assert!(serde_json::from_str::<Currency>("\"usd\"").is_err());
+    }
+
+    #[test]
+    fn test_fiat_to_crypto() {
+        // $100 at 0.0002 btc per usd
+        let value = FiatValue::new(FiatCurrency::Usd, Amount::new(10_000));
+        assert_eq!(value.to_crypto(Currency::Btc, 0.0002), Amount::new(2_000_000));
+        // $100 at 0.01 eth per usd
+        assert_eq!(value.to_crypto(Currency::Eth, 0.01), Amount::new(1_000_000_000_000_000_000));
+    }
+}
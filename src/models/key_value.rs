@@ -1,6 +1,8 @@
 use chrono::NaiveDateTime;
 
 use schema::key_values;
+use serde_json;
+use uuid::Uuid;
 
 #[derive(Debug, Queryable, Clone)]
 pub struct KeyValue {
@@ -16,3 +18,23 @@ pub struct NewKeyValue {
     pub key: String,
     pub value: serde_json::Value,
 }
+
+/// Stored under `withdrawal_lock:<user_id>` while a withdrawal of the user is checked and sent,
+/// `token` is generated for the request holding it, so that only this request releases it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WithdrawalLock {
+    pub token: Uuid,
+    pub locked_at: NaiveDateTime,
+}
+
+impl WithdrawalLock {
+    pub fn to_value(&self) -> serde_json::Value {
+        json!({ "token": self.token, "locked_at": self.locked_at.timestamp() })
+    }
+
+    pub fn from_value(value: &serde_json::Value) -> Option<Self> {
+        let token = serde_json::from_value(value["token"].clone()).ok()?;
+        let locked_at = NaiveDateTime::from_timestamp_opt(value["locked_at"].as_i64()?, 0)?;
+        Some(WithdrawalLock { token, locked_at })
+    }
+}
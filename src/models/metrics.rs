@@ -1,3 +1,5 @@
+use chrono::NaiveDateTime;
+
 use models::*;
 use std::collections::HashMap;
 
@@ -37,3 +39,52 @@ pub struct NegativeBalance {
     pub currency: Currency,
     pub value: Amount,
 }
+
+/// Ledger balance of system accounts sharing blockchain address compared to its on-chain balance
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReconciledBalance {
+    pub address: BlockchainAddress,
+    pub account_ids: Vec<AccountId>,
+    pub ledger_value: Amount,
+    pub blockchain_value: Amount,
+}
+
+impl ReconciledBalance {
+    pub fn is_diverging(&self) -> bool {
+        self.ledger_value != self.blockchain_value
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReconciliationReport {
+    pub currency: Currency,
+    pub balances: Vec<ReconciledBalance>,
+    pub discrepancies: Vec<ReconciledBalance>,
+}
+
+/// Balances recomputed from ledger history in one batch of all accounts of the currency
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecomputeProgress {
+    pub currency: Currency,
+    /// Number of accounts recomputed so far, including this batch
+    pub processed: u64,
+    pub balances: Vec<AccountWithBalance>,
+}
+
+/// Fees credited to system fees accounts over a period
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FeesCollected {
+    pub currency: Currency,
+    pub from: NaiveDateTime,
+    pub to: NaiveDateTime,
+    /// Fees charged from users, incl. exchange spread
+    #[serde(with = "amount_as_string")]
+    pub service_fees: Amount,
+    /// Network fees of withdrawals paid from the fees account
+    #[serde(with = "amount_as_string")]
+    pub blockchain_fees: Amount,
+}
@@ -13,6 +13,7 @@ mod daily_limit_type;
 mod delivery;
 mod exchange;
 mod fees;
+mod fiat;
 mod key_value;
 mod metrics;
 mod oauth_token;
@@ -20,6 +21,7 @@ mod pending_blockchain_transaction;
 mod recepient;
 mod role;
 mod seen_hashes;
+mod status_change;
 mod strange_blockchain_transaction;
 mod transaction;
 mod transaction_id;
@@ -43,6 +45,7 @@ pub use self::daily_limit_type::*;
 pub use self::delivery::*;
 pub use self::exchange::*;
 pub use self::fees::*;
+pub use self::fiat::*;
 pub use self::key_value::*;
 pub use self::metrics::*;
 pub use self::oauth_token::*;
@@ -50,6 +53,7 @@ pub use self::pending_blockchain_transaction::*;
 pub use self::recepient::*;
 pub use self::role::*;
 pub use self::seen_hashes::*;
+pub use self::status_change::*;
 pub use self::strange_blockchain_transaction::*;
 pub use self::transaction::*;
 pub use self::transaction_id::*;
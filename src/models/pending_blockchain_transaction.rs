@@ -14,6 +14,8 @@ pub struct PendingBlockchainTransactionDB {
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
     pub erc20_operation_kind: Option<Erc20OperationKind>,
+    /// Signed transaction as it was posted to blockchain
+    pub raw_tx: Option<BlockchainTransactionRaw>,
 }
 
 impl From<PendingBlockchainTransactionDB> for BlockchainTransaction {
@@ -30,6 +32,7 @@ impl From<PendingBlockchainTransactionDB> for BlockchainTransaction {
             fee: transaction.fee,
             confirmations: 0 as usize,
             erc20_operation_kind: transaction.erc20_operation_kind,
+            reverted: false,
         }
     }
 }
@@ -44,6 +47,7 @@ impl From<(CreateBlockchainTx, BlockchainTransactionId)> for NewPendingBlockchai
             value: transaction.0.value,
             fee: Amount::new(0),
             erc20_operation_kind: None,
+            raw_tx: None,
         }
     }
 }
@@ -58,6 +62,7 @@ impl From<(ApproveInput, BlockchainTransactionId)> for NewPendingBlockchainTrans
             value: transaction.0.value,
             fee: Amount::new(0),
             erc20_operation_kind: Some(Erc20OperationKind::Approve),
+            raw_tx: None,
         }
     }
 }
@@ -72,6 +77,7 @@ pub struct NewPendingBlockchainTransactionDB {
     pub value: Amount,
     pub fee: Amount,
     pub erc20_operation_kind: Option<Erc20OperationKind>,
+    pub raw_tx: Option<BlockchainTransactionRaw>,
 }
 
 impl Default for NewPendingBlockchainTransactionDB {
@@ -84,6 +90,7 @@ impl Default for NewPendingBlockchainTransactionDB {
             value: Amount::default(),
             fee: Amount::default(),
             erc20_operation_kind: None,
+            raw_tx: None,
         }
     }
 }
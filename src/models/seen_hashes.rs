@@ -10,6 +10,8 @@ pub struct SeenHashes {
     pub currency: Currency,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
+    /// Index of the deposit output credited, 0 for transactions processed as a whole
+    pub output_index: i32,
 }
 
 impl From<BlockchainTransaction> for NewSeenHashes {
@@ -18,6 +20,7 @@ impl From<BlockchainTransaction> for NewSeenHashes {
             hash: transaction.hash,
             block_number: transaction.block_number as i64,
             currency: transaction.currency,
+            output_index: 0,
         }
     }
 }
@@ -28,6 +31,7 @@ pub struct NewSeenHashes {
     pub hash: BlockchainTransactionId,
     pub block_number: i64,
     pub currency: Currency,
+    pub output_index: i32,
 }
 
 impl Default for NewSeenHashes {
@@ -36,6 +40,7 @@ impl Default for NewSeenHashes {
             hash: BlockchainTransactionId::default(),
             block_number: 0,
             currency: Currency::Eth,
+            output_index: 0,
         }
     }
 }
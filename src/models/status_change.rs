@@ -0,0 +1,29 @@
+use chrono::NaiveDateTime;
+
+use models::*;
+use schema::transaction_status_history;
+
+/// Status a transaction leg got at `created_at`
+#[derive(Debug, Queryable, Clone)]
+pub struct StatusChange {
+    pub id: i64,
+    pub transaction_id: TransactionId,
+    pub status: TransactionStatus,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Insertable, Clone)]
+#[table_name = "transaction_status_history"]
+pub struct NewStatusChange {
+    pub transaction_id: TransactionId,
+    pub status: TransactionStatus,
+}
+
+impl<'a> From<&'a Transaction> for NewStatusChange {
+    fn from(transaction: &'a Transaction) -> Self {
+        Self {
+            transaction_id: transaction.id,
+            status: transaction.status,
+        }
+    }
+}
@@ -25,6 +25,18 @@ pub struct Transaction {
     pub group_kind: TransactionGroupKind,
     pub related_tx: Option<TransactionId>,
     pub meta: Value,
+    pub hold_until: Option<NaiveDateTime>,
+    /// Fee price (satoshi per byte, wei per gas) the withdrawal was sent with, stored on the fee and blockchain fee legs
+    pub fee_price: Option<f64>,
+    /// Estimated network fee of the withdrawal, stored on the fee leg. Blockchain fee legs store the estimate
+    /// for their blockchain tx at `fee_price`, while their `value` is the fee it actually paid
+    pub gross_fee: Option<Amount>,
+    /// Why the withdrawal is `Failed`, e.g. dropped from mempool or reverted
+    pub failure_reason: Option<String>,
+    /// Note of the user, the same on all legs of the group
+    pub user_note: Option<String>,
+    /// Reporting category, e.g. "salary", the same on all legs of the group
+    pub category: Option<String>,
 }
 
 #[derive(Debug, Queryable, Clone, QueryableByName)]
@@ -54,6 +66,12 @@ impl Default for Transaction {
             group_kind: TransactionGroupKind::Internal,
             related_tx: None,
             meta: json!({}),
+            hold_until: None,
+            fee_price: None,
+            gross_fee: None,
+            failure_reason: None,
+            user_note: None,
+            category: None,
         }
     }
 }
@@ -74,6 +92,11 @@ pub struct NewTransaction {
     pub group_kind: TransactionGroupKind,
     pub related_tx: Option<TransactionId>,
     pub meta: Option<Value>,
+    pub hold_until: Option<NaiveDateTime>,
+    pub fee_price: Option<f64>,
+    pub gross_fee: Option<Amount>,
+    pub user_note: Option<String>,
+    pub category: Option<String>,
 }
 
 impl Default for NewTransaction {
@@ -93,6 +116,11 @@ impl Default for NewTransaction {
             group_kind: TransactionGroupKind::Internal,
             related_tx: None,
             meta: None,
+            hold_until: None,
+            fee_price: None,
+            gross_fee: None,
+            user_note: None,
+            category: None,
         }
     }
 }
@@ -126,8 +154,23 @@ fn valid_exchange(input: &CreateTransactionInput) -> Result<(), ValidationError>
     }
 }
 
+fn valid_fiat_value(input: &CreateTransactionInput) -> Result<(), ValidationError> {
+    if input.fiat_value.is_some() && input.exchange_id.is_some() {
+        let mut error = ValidationError::new("not_supported");
+        error.message = Some("Fiat value can not be used together with exchange".into());
+        Err(error)
+    } else {
+        Ok(())
+    }
+}
+
+fn valid_input(input: &CreateTransactionInput) -> Result<(), ValidationError> {
+    valid_exchange(input)?;
+    valid_fiat_value(input)
+}
+
 #[derive(Debug, Clone, Validate)]
-#[validate(schema(function = "valid_exchange", skip_on_field_errors = "false"))]
+#[validate(schema(function = "valid_input", skip_on_field_errors = "false"))]
 pub struct CreateTransactionInput {
     pub id: TransactionId,
     pub user_id: UserId,
@@ -141,6 +184,42 @@ pub struct CreateTransactionInput {
     pub exchange_id: Option<ExchangeId>,
     #[validate(custom = "valid_rate")]
     pub exchange_rate: Option<f64>,
+    /// Value quoted in fiat, if present `value` is calculated using the rate at the moment of creation
+    pub fiat_value: Option<FiatValue>,
+    /// Rate applied to `fiat_value`
+    pub fiat_rate: Option<f64>,
+    /// If set in future, internal transfer is scheduled and executed at this time
+    pub hold_until: Option<NaiveDateTime>,
+    /// Gas limit used instead of the configured one for eth / stq withdrawals, e.g. to contract wallets
+    pub gas_limit_override: Option<u64>,
+    /// Withdraw the whole balance of `from` less the fee, `value` is ignored
+    pub drain: bool,
+    /// Withdrawal speed, if set the fee is derived from the tier and `fee` is ignored
+    pub fee_tier: Option<FeeTier>,
+    /// Fee was not supplied by the client, withdrawals are charged the fee of the configured default tier
+    pub fee_omitted: bool,
+    /// Note visible only to the user, never sent outside of our system
+    #[validate(length(max = "256", message = "Note must not be longer than 256 characters"))]
+    pub user_note: Option<String>,
+    /// Reporting category of the group, checked against the configured ones if there are any
+    #[validate(length(min = "1", max = "64", message = "Category must be 1 to 64 characters long"))]
+    pub category: Option<String>,
+    /// Owner the recipient account must belong to, e.g. the issuer of a paid invoice. Internal transfers only
+    pub expected_recipient: Option<UserId>,
+}
+
+impl CreateTransactionInput {
+    /// Meta recorded on transaction if it was quoted in fiat
+    pub fn fiat_meta(&self) -> Option<Value> {
+        match (self.fiat_value, self.fiat_rate) {
+            (Some(fiat_value), Some(fiat_rate)) => Some(json!({
+                "fiatValue": fiat_value.value,
+                "fiatCurrency": fiat_value.currency,
+                "fiatRate": fiat_rate,
+            })),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Validate, Clone, Serialize)]
@@ -150,10 +229,14 @@ pub struct CreateBlockchainTx {
     pub from: BlockchainAddress,
     pub to: BlockchainAddress,
     pub currency: Currency,
+    #[serde(with = "amount_as_string")]
     pub value: Amount,
     pub fee_price: f64,
     pub nonce: Option<u64>,
     pub utxos: Option<Vec<BitcoinUtxos>>,
+    pub gas_limit: Option<u64>,
+    /// Multiple outputs of a single bitcoin transaction, `to` and `value` hold the first output and the total then
+    pub outputs: Option<Vec<BlockchainTxOutput>>,
 }
 
 impl Default for CreateBlockchainTx {
@@ -167,6 +250,8 @@ impl Default for CreateBlockchainTx {
             fee_price: 0.0,
             nonce: Some(0),
             utxos: None,
+            gas_limit: None,
+            outputs: None,
         }
     }
 }
@@ -190,8 +275,34 @@ impl CreateBlockchainTx {
             fee_price,
             nonce,
             utxos,
+            gas_limit: None,
+            outputs: None,
         }
     }
+
+    pub fn new_bitcoin_batch(
+        from: BlockchainAddress,
+        outputs: Vec<BlockchainTxOutput>,
+        fee_price: f64,
+        utxos: Vec<BitcoinUtxos>,
+    ) -> Option<Self> {
+        let to = outputs.get(0)?.to.clone();
+        let value = outputs
+            .iter()
+            .try_fold(Amount::new(0), |acc, output| acc.checked_add(output.value))?;
+        Some(Self {
+            outputs: Some(outputs),
+            ..Self::new(from, to, Currency::Btc, value, fee_price, None, Some(utxos))
+        })
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct BlockchainTxOutput {
+    pub to: BlockchainAddress,
+    #[serde(with = "amount_as_string")]
+    pub value: Amount,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -200,6 +311,10 @@ pub struct BitcoinUtxos {
     tx_hash: BlockchainTransactionId,
     index: u64,
     value: Amount,
+    /// Only used to pick spendable utxos, keys service doesn't need it. Gateway might omit it,
+    /// then the utxo is already filtered by `minConfirmations` of the request
+    #[serde(default, skip_serializing)]
+    confirmations: Option<u64>,
 }
 
 impl Default for BitcoinUtxos {
@@ -208,43 +323,86 @@ impl Default for BitcoinUtxos {
             tx_hash: BlockchainTransactionId::default(),
             index: 0,
             value: Amount::default(),
+            confirmations: None,
         }
     }
 }
 
+impl BitcoinUtxos {
+    pub fn new(tx_hash: BlockchainTransactionId, index: u64, value: Amount, confirmations: u64) -> Self {
+        Self {
+            tx_hash,
+            index,
+            value,
+            confirmations: Some(confirmations),
+        }
+    }
+
+    pub fn value(&self) -> Amount {
+        self.value
+    }
+
+    pub fn confirmations(&self) -> Option<u64> {
+        self.confirmations
+    }
+}
+
+/// Withdrawal to several external addresses, sent as a single blockchain transaction
+#[derive(Debug, Clone)]
+pub struct CreateBatchTransactionInput {
+    pub id: TransactionId,
+    pub user_id: UserId,
+    pub from: AccountId,
+    pub currency: Currency,
+    pub outputs: Vec<BlockchainTxOutput>,
+    pub fee: Amount,
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct TransactionOut {
+    /// Gid of the transaction group, not an id of any particular leg. Used to look the group up
+    /// with `get_transaction`, legs are available with `get_transaction_legs`
     pub id: TransactionId,
+    /// Kind of the group, e.g. to tell deposits from withdrawals in exports
+    pub kind: TransactionGroupKind,
     pub user_id: UserId,
     pub from: Vec<TransactionAddressInfo>,
     pub to: TransactionAddressInfo,
+    #[serde(with = "amount_as_string")]
     pub from_value: Amount,
     pub from_currency: Currency,
+    #[serde(with = "amount_as_string")]
     pub to_value: Amount,
     pub to_currency: Currency,
+    #[serde(with = "amount_as_string")]
     pub fee: Amount,
+    pub fee_price: Option<f64>,
+    #[serde(with = "amount_as_string::option")]
+    pub gross_fee: Option<Amount>,
+    /// Network fee actually paid by the blockchain txs of a resolved withdrawal, in the currency of `gross_fee`
+    #[serde(with = "amount_as_string::option")]
+    pub network_fee: Option<Amount>,
+    /// Relative difference of `network_fee` from the network fee estimated at `fee_price`, e.g. 0.25 when the network took 25% more
+    pub fee_variance: Option<f64>,
     pub status: TransactionStatus,
     pub blockchain_tx_ids: Vec<BlockchainTransactionId>,
+    pub confirmations: Option<u64>,
+    pub failure_reason: Option<String>,
+    /// Not serialized, so that it's never published outside of our system
+    #[serde(skip_serializing)]
+    pub user_note: Option<String>,
+    pub category: Option<String>,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
 }
 
-// impl TransactionOut {
-//     pub fn new(transaction: &Transaction, from: Vec<TransactionAddressInfo>, to: TransactionAddressInfo) -> Self {
-//         Self {
-//             id: transaction.id,
-//             from,
-//             to,
-//             currency: transaction.currency,
-//             value: transaction.value,
-//             fee: transaction.fee,
-//             status: transaction.status,
-//             blockchain_tx_id: transaction.blockchain_tx_id.clone(),
-//             created_at: transaction.created_at.clone(),
-//             updated_at: transaction.updated_at.clone(),
-//         }
-//     }
-// }
+/// Position in a listing of transaction groups, newest first. Groups are ordered by creation time of their
+/// first leg and by gid, so that pages don't shift while new transactions are created
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TransactionsCursor {
+    pub created_at: NaiveDateTime,
+    pub gid: TransactionId,
+}
 
 #[derive(Debug, Serialize, Clone)]
 pub struct TransactionAddressInfo {
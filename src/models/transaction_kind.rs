@@ -4,8 +4,9 @@ use diesel::serialize::{self, IsNull, Output, ToSql};
 use diesel::sql_types::VarChar;
 use std::io::Write;
 
-#[derive(Debug, FromSqlRow, AsExpression, Clone, Copy, Eq, PartialEq, Hash)]
+#[derive(Debug, Serialize, Deserialize, FromSqlRow, AsExpression, Clone, Copy, Eq, PartialEq, Hash)]
 #[sql_type = "VarChar"]
+#[serde(rename_all = "snake_case")]
 pub enum TransactionGroupKind {
     Deposit,
     Internal,
@@ -52,8 +53,9 @@ impl ToSql<VarChar, Pg> for TransactionGroupKind {
     }
 }
 
-#[derive(Debug, FromSqlRow, AsExpression, Clone, Copy, Eq, PartialEq, Hash)]
+#[derive(Debug, Serialize, Deserialize, FromSqlRow, AsExpression, Clone, Copy, Eq, PartialEq, Hash)]
 #[sql_type = "VarChar"]
+#[serde(rename_all = "snake_case")]
 pub enum TransactionKind {
     Fee,
     BlockchainFee,
@@ -10,6 +10,21 @@ use std::io::Write;
 pub enum TransactionStatus {
     Pending,
     Done,
+    /// Internal transfer waiting for its `hold_until`, doesn't affect balances
+    Scheduled,
+    /// Scheduled transfer cancelled before it was executed, doesn't affect balances
+    Cancelled,
+    /// Withdrawal dropped from blockchain without being mined, doesn't affect balances
+    Failed,
+}
+
+impl TransactionStatus {
+    pub fn affects_balance(&self) -> bool {
+        match self {
+            TransactionStatus::Pending | TransactionStatus::Done => true,
+            TransactionStatus::Scheduled | TransactionStatus::Cancelled | TransactionStatus::Failed => false,
+        }
+    }
 }
 
 impl FromSql<VarChar, Pg> for TransactionStatus {
@@ -17,6 +32,9 @@ impl FromSql<VarChar, Pg> for TransactionStatus {
         match data {
             Some(b"pending") => Ok(TransactionStatus::Pending),
             Some(b"done") => Ok(TransactionStatus::Done),
+            Some(b"scheduled") => Ok(TransactionStatus::Scheduled),
+            Some(b"cancelled") => Ok(TransactionStatus::Cancelled),
+            Some(b"failed") => Ok(TransactionStatus::Failed),
             Some(v) => Err(format!(
                 "Unrecognized enum variant: {:?}",
                 String::from_utf8(v.to_vec()).unwrap_or_else(|_| "Non - UTF8 value".to_string())
@@ -33,6 +51,9 @@ impl ToSql<VarChar, Pg> for TransactionStatus {
         match self {
             TransactionStatus::Pending => out.write_all(b"pending")?,
             TransactionStatus::Done => out.write_all(b"done")?,
+            TransactionStatus::Scheduled => out.write_all(b"scheduled")?,
+            TransactionStatus::Cancelled => out.write_all(b"cancelled")?,
+            TransactionStatus::Failed => out.write_all(b"failed")?,
         };
         Ok(IsNull::No)
     }
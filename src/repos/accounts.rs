@@ -20,7 +20,12 @@ pub trait AccountsRepo: Send + Sync + 'static {
     fn list_for_user(&self, user_id_arg: UserId, offset: i64, limit: i64) -> RepoResult<Vec<Account>>;
     fn get_by_address(&self, address_: BlockchainAddress, currency: Currency, kind_: AccountKind) -> RepoResult<Option<Account>>;
     fn filter_by_address(&self, address_: BlockchainAddress) -> RepoResult<Vec<Account>>;
+    /// Whether any of our accounts, in any currency, is bound to `address_`
+    fn address_exists(&self, address_: BlockchainAddress) -> RepoResult<bool>;
     fn get_by_addresses(&self, addresses: &[BlockchainAddress], currency_: Currency, kind_: AccountKind) -> RepoResult<Vec<Account>>;
+    /// Accounts of the currency with ids greater than `after`, ordered by id. Keyset pagination,
+    /// so listing all accounts doesn't get slower page by page
+    fn list_by_currency_after(&self, currency_: Currency, after: Option<AccountId>, limit: i64) -> RepoResult<Vec<Account>>;
 }
 
 #[derive(Clone, Default)]
@@ -132,6 +137,10 @@ impl<'a> AccountsRepo for AccountsRepoImpl {
         })
     }
 
+    fn address_exists(&self, address_: BlockchainAddress) -> RepoResult<bool> {
+        self.filter_by_address(address_).map(|accounts| !accounts.is_empty())
+    }
+
     fn get_by_addresses(&self, addresses: &[BlockchainAddress], currency_: Currency, kind_: AccountKind) -> RepoResult<Vec<Account>> {
         with_tls_connection(|conn| {
             accounts
@@ -145,6 +154,18 @@ impl<'a> AccountsRepo for AccountsRepoImpl {
                 })
         })
     }
+    fn list_by_currency_after(&self, currency_: Currency, after: Option<AccountId>, limit: i64) -> RepoResult<Vec<Account>> {
+        with_tls_connection(|conn| {
+            let mut query = accounts.filter(currency.eq(currency_)).into_boxed();
+            if let Some(after) = after {
+                query = query.filter(id.gt(after));
+            }
+            query.order(id).limit(limit).get_results(conn).map_err(move |e| {
+                let error_kind = ErrorKind::from(&e);
+                ectx!(err e, error_kind => currency_, after, limit)
+            })
+        })
+    }
 }
 
 #[cfg(test)]
@@ -259,6 +280,44 @@ pub mod tests {
             res
         }));
     }
+    #[test]
+    fn accounts_list_by_currency_after() {
+        let mut core = Core::new().unwrap();
+        let db_executor = create_executor();
+        let accounts_repo = AccountsRepoImpl::default();
+        let users_repo = UsersRepoImpl::default();
+        let new_user = NewUser::default();
+        let _ = core.run(db_executor.execute_test_transaction(move || {
+            let user = users_repo.create(new_user)?;
+            let mut created = vec![];
+            for currency_ in vec![Currency::Stq, Currency::Stq, Currency::Btc, Currency::Stq] {
+                let mut new_account = NewAccount::default();
+                new_account.user_id = user.id;
+                new_account.currency = currency_;
+                created.push(accounts_repo.create(new_account)?);
+            }
+
+            // every page starts right after the last account of the previous one
+            let mut listed = vec![];
+            let mut after = None;
+            loop {
+                let page = accounts_repo.list_by_currency_after(Currency::Stq, after, 2)?;
+                assert!(page.len() <= 2);
+                assert!(page.iter().all(|account| account.currency == Currency::Stq));
+                match page.last() {
+                    Some(last) => after = Some(last.id),
+                    None => break,
+                }
+                listed.extend(page.into_iter().map(|account| account.id));
+            }
+            for account in created {
+                let count = listed.iter().filter(|account_id| **account_id == account.id).count();
+                assert_eq!(count, if account.currency == Currency::Stq { 1 } else { 0 });
+            }
+            Ok(())
+        }));
+    }
+
     #[test]
     fn accounts_get_by_address() {
         let mut core = Core::new().unwrap();
@@ -276,6 +335,36 @@ pub mod tests {
             res
         }));
     }
+    #[test]
+    fn accounts_filter_by_address() {
+        let mut core = Core::new().unwrap();
+        let db_executor = create_executor();
+        let accounts_repo = AccountsRepoImpl::default();
+        let users_repo = UsersRepoImpl::default();
+        let new_user = NewUser::default();
+        let _ = core.run(db_executor.execute_test_transaction(move || {
+            let user = users_repo.create(new_user)?;
+            let address = BlockchainAddress::new("0x8a2bd1e9bb6ed1fd5ea3e6f3d2b6a4ed1b2c7d3e".to_string());
+            for currency_ in vec![Currency::Eth, Currency::Stq, Currency::Btc] {
+                let mut new_account = NewAccount::default();
+                new_account.user_id = user.id;
+                new_account.currency = currency_;
+                if currency_ != Currency::Btc {
+                    new_account.address = address.clone();
+                }
+                accounts_repo.create(new_account).unwrap();
+            }
+            let res = accounts_repo.filter_by_address(address.clone());
+            let mut currencies: Vec<Currency> = res.as_ref().unwrap().iter().map(|account| account.currency).collect();
+            currencies.sort_by_key(|currency_| currency_.to_string());
+            assert_eq!(currencies, vec![Currency::Eth, Currency::Stq]);
+            assert!(res.as_ref().unwrap().iter().all(|account| account.user_id == user.id));
+            assert_eq!(accounts_repo.address_exists(address.clone()).unwrap(), true);
+            let external = BlockchainAddress::new("0x0000000000000000000000000000000000000001".to_string());
+            assert_eq!(accounts_repo.address_exists(external).unwrap(), false);
+            res
+        }));
+    }
     // #[test]
     // fn accounts_get_min_enough_value() {
     //     let mut core = Core::new().unwrap();
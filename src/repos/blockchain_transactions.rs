@@ -1,4 +1,6 @@
 use diesel;
+use diesel::dsl::sql;
+use diesel::sql_types::{Bool, Jsonb};
 
 use super::error::*;
 use super::executor::with_tls_connection;
@@ -11,6 +13,10 @@ pub trait BlockchainTransactionsRepo: Send + Sync + 'static {
     fn create(&self, payload: NewBlockchainTransactionDB) -> RepoResult<BlockchainTransactionDB>;
     fn upsert(&self, payload: NewBlockchainTransactionDB) -> RepoResult<BlockchainTransactionDB>;
     fn get(&self, hash_: BlockchainTransactionId) -> RepoResult<Option<BlockchainTransactionDB>>;
+    /// Sets confirmations of an already stored transaction, returns `None` if it's not stored
+    fn update_confirmations(&self, hash_: BlockchainTransactionId, confirmations_: i32) -> RepoResult<Option<BlockchainTransactionDB>>;
+    /// Transactions having an output to the address
+    fn list_by_destination(&self, address: BlockchainAddress, currency_: Currency) -> RepoResult<Vec<BlockchainTransactionDB>>;
 }
 
 #[derive(Clone, Default)]
@@ -57,6 +63,35 @@ impl BlockchainTransactionsRepo for BlockchainTransactionsRepoImpl {
                 })
         })
     }
+
+    fn update_confirmations(&self, hash_: BlockchainTransactionId, confirmations_: i32) -> RepoResult<Option<BlockchainTransactionDB>> {
+        with_tls_connection(|conn| {
+            let filtered = blockchain_transactions.filter(hash.eq(hash_.clone()));
+            diesel::update(filtered)
+                .set(confirmations.eq(confirmations_))
+                .get_result(conn)
+                .optional()
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => hash_, confirmations_)
+                })
+        })
+    }
+
+    fn list_by_destination(&self, address: BlockchainAddress, currency_: Currency) -> RepoResult<Vec<BlockchainTransactionDB>> {
+        // `to_` is a json array of outputs, so the address is matched with jsonb containment
+        let output = json!([{ "address": address }]);
+        with_tls_connection(|conn| {
+            blockchain_transactions
+                .filter(sql::<Bool>("to_ @> ").bind::<Jsonb, _>(output))
+                .filter(currency.eq(currency_))
+                .get_results(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => address, currency_)
+                })
+        })
+    }
 }
 
 #[cfg(test)]
@@ -105,4 +140,42 @@ pub mod tests {
             res
         }));
     }
+
+    #[test]
+    fn blockchain_transactions_update_confirmations() {
+        let mut core = Core::new().unwrap();
+        let db_executor = create_executor();
+        let blockchain_transactions_repo = BlockchainTransactionsRepoImpl::default();
+        let _ = core.run(db_executor.execute_test_transaction(move || {
+            let trans = NewBlockchainTransactionDB::default();
+            let transaction = blockchain_transactions_repo.create(trans)?;
+            let res = blockchain_transactions_repo.update_confirmations(transaction.hash, 3);
+            assert_eq!(res.as_ref().unwrap().as_ref().map(|tx| tx.confirmations), Some(3));
+            let missing = blockchain_transactions_repo.update_confirmations(BlockchainTransactionId::default(), 3)?;
+            assert!(missing.is_none());
+            res
+        }));
+    }
+
+    #[test]
+    fn blockchain_transactions_list_by_destination() {
+        let mut core = Core::new().unwrap();
+        let db_executor = create_executor();
+        let blockchain_transactions_repo = BlockchainTransactionsRepoImpl::default();
+        let _ = core.run(db_executor.execute_test_transaction(move || {
+            let address = BlockchainAddress::default();
+            let mut trans = NewBlockchainTransactionDB::default();
+            trans.to_ = json!([
+                { "address": BlockchainAddress::default(), "value": Amount::new(10) },
+                { "address": address.clone(), "value": Amount::new(20) },
+            ]);
+            let transaction = blockchain_transactions_repo.create(trans)?;
+            blockchain_transactions_repo.create(NewBlockchainTransactionDB::default())?;
+            let res = blockchain_transactions_repo.list_by_destination(address.clone(), Currency::Eth);
+            let hashes: Vec<_> = res.as_ref().unwrap().iter().map(|tx| tx.hash.clone()).collect();
+            assert_eq!(hashes, vec![transaction.hash]);
+            assert!(blockchain_transactions_repo.list_by_destination(address, Currency::Btc)?.is_empty());
+            res
+        }));
+    }
 }
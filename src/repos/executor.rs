@@ -1,7 +1,9 @@
 use std::cell::RefCell;
+use std::thread;
+use std::time::Duration;
 
 use diesel::pg::PgConnection;
-use diesel::result::Error as DieselError;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
 use futures_cpupool::CpuPool;
 
 use super::error::*;
@@ -12,6 +14,10 @@ thread_local! {
     pub static DB_CONN: RefCell<Option<PgPooledConnection>> = RefCell::new(None)
 }
 
+/// Max delay before the first retry of a transaction aborted by serialization failure,
+/// doubled with every next attempt
+const SERIALIZATION_RETRY_BACKOFF_MS: u64 = 10;
+
 /// Transaction isolation level
 #[allow(dead_code)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -51,6 +57,16 @@ pub trait DbExecutor: Clone + Send + Sync + 'static {
         F: FnOnce() -> Result<T, E> + Send + 'static,
         E: From<Error> + Fail;
 
+    /// Execute mutations and queries inside one serializable transaction, retrying it up to `retries` times
+    /// if postgres aborts it with serialization failure (SQLSTATE 40001).
+    /// Since `f` may be called several times, it must not have any side effects outside of db,
+    /// i.e. it's fine for internal transfers, but never for anything that talks to blockchain.
+    fn execute_serializable_with_retries<F, T, E>(&self, retries: usize, f: F) -> Box<Future<Item = T, Error = E> + Send + 'static>
+    where
+        T: Send + 'static,
+        F: Fn() -> Result<T, E> + Send + 'static,
+        E: From<Error> + Fail;
+
     /// Execute mutations that will be rolled back. This is useful for tests, when you
     /// don't want to pollute your database
     #[cfg(test)]
@@ -99,41 +115,20 @@ impl DbExecutor for DbExecutorImpl {
         E: From<Error> + Fail,
     {
         let db_pool = self.db_pool.clone();
-        Box::new(self.db_thread_pool.spawn_fn(move || {
-            DB_CONN.with(move |tls_conn_cell| -> Result<T, E> {
-                put_connection_into_tls(&db_pool, tls_conn_cell)?;
-                let mut err: Option<E> = None;
-                let res = {
-                    let err_ref = &mut err;
-                    with_tls_connection(move |conn| {
-                        let builder = match isolation {
-                            Isolation::ReadCommitted => conn.build_transaction().read_committed(),
-                            Isolation::RepeatableRead => conn.build_transaction().repeatable_read(),
-                            Isolation::Serializable => conn.build_transaction().serializable(),
-                        };
-                        builder
-                            .run(|| {
-                                f().map_err(|e| {
-                                    *err_ref = Some(e);
-                                    DieselError::RollbackTransaction
-                                })
-                            })
-                            .map_err(|e: DieselError| match e {
-                                DieselError::AlreadyInTransaction => ectx!(err ErrorSource::Diesel, ErrorKind::AlreadyInTransaction),
-                                _ => ectx!(err ErrorSource::Diesel, ErrorKind::Internal),
-                            })
-                    })
-                };
-                res.map_err(|e| {
-                    if e.kind() == ErrorKind::AlreadyInTransaction {
-                        rollback_transaction(tls_conn_cell);
-                    }
-                    remove_connection_from_tls_if_broken(tls_conn_cell);
-                    let e: E = err.unwrap_or_else(|| e.into());
-                    e
-                })
-            })
-        }))
+        Box::new(self.db_thread_pool.spawn_fn(move || run_transaction(&db_pool, isolation, f)))
+    }
+
+    fn execute_serializable_with_retries<F, T, E>(&self, retries: usize, f: F) -> Box<Future<Item = T, Error = E> + Send + 'static>
+    where
+        T: Send + 'static,
+        F: Fn() -> Result<T, E> + Send + 'static,
+        E: From<Error> + Fail,
+    {
+        let db_pool = self.db_pool.clone();
+        Box::new(
+            self.db_thread_pool
+                .spawn_fn(move || retry_on_serialization_failure(retries, || run_transaction(&db_pool, Isolation::Serializable, &f))),
+        )
     }
 
     #[cfg(test)]
@@ -170,6 +165,82 @@ where
     })
 }
 
+/// Run `f` inside one db transaction using connection from thread local storage
+fn run_transaction<F, T, E>(db_pool: &PgPool, isolation: Isolation, f: F) -> Result<T, E>
+where
+    F: FnOnce() -> Result<T, E>,
+    E: From<Error> + Fail,
+{
+    DB_CONN.with(move |tls_conn_cell| -> Result<T, E> {
+        put_connection_into_tls(db_pool, tls_conn_cell)?;
+        let mut err: Option<E> = None;
+        let res = {
+            let err_ref = &mut err;
+            with_tls_connection(move |conn| {
+                let builder = match isolation {
+                    Isolation::ReadCommitted => conn.build_transaction().read_committed(),
+                    Isolation::RepeatableRead => conn.build_transaction().repeatable_read(),
+                    Isolation::Serializable => conn.build_transaction().serializable(),
+                };
+                builder
+                    .run(|| {
+                        f().map_err(|e| {
+                            *err_ref = Some(e);
+                            DieselError::RollbackTransaction
+                        })
+                    })
+                    .map_err(|e: DieselError| match e {
+                        DieselError::AlreadyInTransaction => ectx!(err ErrorSource::Diesel, ErrorKind::AlreadyInTransaction),
+                        // keep diesel error, e.g. serialization failure can be raised on commit
+                        e => ectx!(err e, ErrorSource::Diesel, ErrorKind::Internal),
+                    })
+            })
+        };
+        res.map_err(|e| {
+            if e.kind() == ErrorKind::AlreadyInTransaction {
+                rollback_transaction(tls_conn_cell);
+            }
+            remove_connection_from_tls_if_broken(tls_conn_cell);
+            let e: E = err.unwrap_or_else(|| e.into());
+            e
+        })
+    })
+}
+
+/// Calls `f` until it succeeds, fails with anything but serialization failure
+/// or runs out of `retries`. Sleeps for a random, exponentially growing time between attempts,
+/// so that concurrent transactions don't collide again.
+pub fn retry_on_serialization_failure<F, T, E>(retries: usize, mut f: F) -> Result<T, E>
+where
+    F: FnMut() -> Result<T, E>,
+    E: Fail,
+{
+    let mut attempt = 0;
+    loop {
+        let res = f();
+        let should_retry = match res {
+            Err(ref e) => attempt < retries && is_serialization_failure(e),
+            Ok(_) => false,
+        };
+        if !should_retry {
+            return res;
+        }
+        let max_delay = SERIALIZATION_RETRY_BACKOFF_MS << attempt;
+        let delay = thread_rng().gen_range(max_delay / 2, max_delay + 1);
+        thread::sleep(Duration::from_millis(delay));
+        attempt += 1;
+    }
+}
+
+/// Checks if there is postgres serialization failure somewhere in the chain of causes
+pub fn is_serialization_failure<E: Fail>(e: &E) -> bool {
+    let e: &Fail = e;
+    e.iter_chain().any(|cause| match cause.downcast_ref::<DieselError>() {
+        Some(DieselError::DatabaseError(DatabaseErrorKind::SerializationFailure, _)) => true,
+        _ => false,
+    })
+}
+
 /// Checkout connection from db_pool and put it into thead local storage
 /// if there is no connection already in thread local storage
 fn put_connection_into_tls(db_pool: &PgPool, tls_conn_cell: &RefCell<Option<PgPooledConnection>>) -> Result<(), Error> {
@@ -209,3 +280,55 @@ fn rollback_transaction(tls_conn_cell: &RefCell<Option<PgPooledConnection>>) {
         }
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+
+    fn serialization_failure() -> Error {
+        let e = DieselError::DatabaseError(
+            DatabaseErrorKind::SerializationFailure,
+            Box::new("could not serialize access due to read/write dependencies among transactions".to_string()),
+        );
+        ectx!(err e, ErrorSource::Diesel, ErrorKind::Internal)
+    }
+
+    #[test]
+    fn test_retry_on_serialization_failure() {
+        let attempts = Cell::new(0);
+        let res: Result<u32, Error> = retry_on_serialization_failure(3, || {
+            attempts.set(attempts.get() + 1);
+            if attempts.get() == 1 {
+                Err(serialization_failure())
+            } else {
+                Ok(42)
+            }
+        });
+        assert_eq!(res.unwrap(), 42);
+        assert_eq!(attempts.get(), 2);
+    }
+
+    #[test]
+    fn test_retry_on_serialization_failure_gives_up() {
+        let attempts = Cell::new(0);
+        let res: Result<u32, Error> = retry_on_serialization_failure(3, || {
+            attempts.set(attempts.get() + 1);
+            Err(serialization_failure())
+        });
+        assert!(is_serialization_failure(&res.unwrap_err()));
+        assert_eq!(attempts.get(), 4);
+    }
+
+    #[test]
+    fn test_no_retry_on_other_errors() {
+        let attempts = Cell::new(0);
+        let res: Result<u32, Error> = retry_on_serialization_failure(3, || {
+            attempts.set(attempts.get() + 1);
+            Err(ectx!(err DieselError::NotFound, ErrorSource::Diesel, ErrorKind::Internal))
+        });
+        assert!(!is_serialization_failure(&res.unwrap_err()));
+        assert_eq!(attempts.get(), 1);
+    }
+}
@@ -10,8 +10,20 @@ use schema::key_values::dsl::*;
 pub trait KeyValuesRepo: Send + Sync + 'static {
     fn get_nonce(&self, address: BlockchainAddress) -> RepoResult<Option<KeyValue>>;
     fn set_nonce(&self, address: BlockchainAddress, nonce: u64) -> RepoResult<u64>;
+    /// Last rate of the pair received from exchange, `updated_at` is the time it was received
+    fn get_rate(&self, from: Currency, to: Currency) -> RepoResult<Option<KeyValue>>;
+    fn set_rate(&self, from: Currency, to: Currency, rate: f64) -> RepoResult<f64>;
+    /// Whether pending transactions resolver runs, it does unless paused with `set_resolver_enabled`
+    fn get_resolver_enabled(&self) -> RepoResult<bool>;
+    fn set_resolver_enabled(&self, enabled: bool) -> RepoResult<bool>;
+    /// Withdrawal of the user that is being checked and sent, if any
+    fn get_withdrawal_lock(&self, user_id: UserId) -> RepoResult<Option<WithdrawalLock>>;
+    fn set_withdrawal_lock(&self, user_id: UserId, lock: WithdrawalLock) -> RepoResult<WithdrawalLock>;
+    fn delete_withdrawal_lock(&self, user_id: UserId) -> RepoResult<()>;
 }
 
+const RESOLVER_ENABLED_KEY: &str = "resolver_enabled";
+
 #[derive(Clone, Default)]
 pub struct KeyValuesRepoImpl;
 
@@ -44,4 +56,108 @@ impl KeyValuesRepo for KeyValuesRepoImpl {
                 })
         })
     }
+    fn get_rate(&self, from: Currency, to: Currency) -> RepoResult<Option<KeyValue>> {
+        with_tls_connection(|conn| {
+            let key_ = format!("rate:{}:{}", from, to);
+            key_values.filter(key.eq(key_)).first(conn).optional().map_err(move |e| {
+                let error_kind = ErrorKind::from(&e);
+                ectx!(err e, error_kind => from, to)
+            })
+        })
+    }
+    fn set_rate(&self, from: Currency, to: Currency, rate: f64) -> RepoResult<f64> {
+        with_tls_connection(|conn| {
+            let key_ = format!("rate:{}:{}", from, to);
+            diesel::insert_into(key_values)
+                .values(&NewKeyValue {
+                    key: key_,
+                    value: json!(rate),
+                })
+                .on_conflict(key)
+                .do_update()
+                .set(value.eq(json!(rate)))
+                .get_result::<KeyValue>(conn)
+                .map(|kv| kv.value.as_f64().unwrap())
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => from, to, rate)
+                })
+        })
+    }
+    fn get_resolver_enabled(&self) -> RepoResult<bool> {
+        with_tls_connection(|conn| {
+            key_values
+                .filter(key.eq(RESOLVER_ENABLED_KEY))
+                .first::<KeyValue>(conn)
+                .optional()
+                .map(|kv| kv.and_then(|kv| kv.value.as_bool()).unwrap_or(true))
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind)
+                })
+        })
+    }
+    fn set_resolver_enabled(&self, enabled: bool) -> RepoResult<bool> {
+        with_tls_connection(|conn| {
+            diesel::insert_into(key_values)
+                .values(&NewKeyValue {
+                    key: RESOLVER_ENABLED_KEY.to_string(),
+                    value: json!(enabled),
+                })
+                .on_conflict(key)
+                .do_update()
+                .set(value.eq(json!(enabled)))
+                .get_result::<KeyValue>(conn)
+                .map(|kv| kv.value.as_bool().unwrap())
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => enabled)
+                })
+        })
+    }
+    fn get_withdrawal_lock(&self, user_id: UserId) -> RepoResult<Option<WithdrawalLock>> {
+        with_tls_connection(|conn| {
+            let key_ = format!("withdrawal_lock:{}", user_id);
+            key_values
+                .filter(key.eq(key_))
+                .first::<KeyValue>(conn)
+                .optional()
+                .map(|kv| kv.and_then(|kv| WithdrawalLock::from_value(&kv.value)))
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => user_id)
+                })
+        })
+    }
+    fn set_withdrawal_lock(&self, user_id: UserId, lock: WithdrawalLock) -> RepoResult<WithdrawalLock> {
+        with_tls_connection(|conn| {
+            let key_ = format!("withdrawal_lock:{}", user_id);
+            diesel::insert_into(key_values)
+                .values(&NewKeyValue {
+                    key: key_,
+                    value: lock.to_value(),
+                })
+                .on_conflict(key)
+                .do_update()
+                .set(value.eq(lock.to_value()))
+                .get_result::<KeyValue>(conn)
+                .map(|_| lock)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => user_id, lock)
+                })
+        })
+    }
+    fn delete_withdrawal_lock(&self, user_id: UserId) -> RepoResult<()> {
+        with_tls_connection(|conn| {
+            let key_ = format!("withdrawal_lock:{}", user_id);
+            diesel::delete(key_values.filter(key.eq(key_)))
+                .execute(conn)
+                .map(|_| ())
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => user_id)
+                })
+        })
+    }
 }
@@ -2,14 +2,18 @@ use std::collections::HashMap;
 use std::collections::HashSet;
 use std::sync::{Arc, Mutex};
 
-use chrono::Duration;
+use chrono::{Duration, NaiveDateTime};
+use uuid::Uuid;
+use validator::{ValidationError, ValidationErrors};
 
 use super::accounts::*;
 use super::blockchain_transactions::*;
 use super::error::*;
-use super::executor::{DbExecutor, Isolation};
+use super::executor::{retry_on_serialization_failure, DbExecutor, Isolation};
 use super::key_values::*;
 use super::pending_blockchain_transactions::*;
+use super::seen_hashes::*;
+use super::strange_blockchain_transactions::*;
 use super::transactions::*;
 use super::types::RepoResult;
 use super::users::*;
@@ -103,6 +107,9 @@ impl AccountsRepo for AccountsRepoMock {
             .filter_map(|x| {
                 if x.id == account_id {
                     x.name = payload.name.clone();
+                    if let Some(reserve) = payload.reserve {
+                        x.reserve = reserve;
+                    }
                     Some(x)
                 } else {
                     None
@@ -116,9 +123,15 @@ impl AccountsRepo for AccountsRepoMock {
         let data = self.data.lock().unwrap();
         Ok(data.iter().filter(|x| x.id == account_id).nth(0).cloned().unwrap())
     }
-    fn list_for_user(&self, user_id_arg: UserId, _offset: i64, _limit: i64) -> RepoResult<Vec<Account>> {
+    fn list_for_user(&self, user_id_arg: UserId, offset: i64, limit: i64) -> RepoResult<Vec<Account>> {
         let data = self.data.lock().unwrap();
-        Ok(data.clone().into_iter().filter(|x| x.user_id == user_id_arg).collect())
+        Ok(data
+            .clone()
+            .into_iter()
+            .filter(|x| x.user_id == user_id_arg)
+            .skip(offset as usize)
+            .take(limit as usize)
+            .collect())
     }
     fn get_by_address(&self, address_: BlockchainAddress, currency_: Currency, kind_: AccountKind) -> RepoResult<Option<Account>> {
         let data = self.data.lock().unwrap();
@@ -136,6 +149,10 @@ impl AccountsRepo for AccountsRepoMock {
         Ok(u)
     }
 
+    fn address_exists(&self, address_: BlockchainAddress) -> RepoResult<bool> {
+        self.filter_by_address(address_).map(|accounts| !accounts.is_empty())
+    }
+
     fn get_by_addresses(&self, addresses: &[BlockchainAddress], currency_: Currency, kind_: AccountKind) -> RepoResult<Vec<Account>> {
         let addresses: HashSet<_> = addresses.iter().collect();
         let data = self.data.lock().unwrap();
@@ -146,19 +163,50 @@ impl AccountsRepo for AccountsRepoMock {
             .collect();
         Ok(u)
     }
+    fn list_by_currency_after(&self, currency_: Currency, after: Option<AccountId>, limit: i64) -> RepoResult<Vec<Account>> {
+        let data = self.data.lock().unwrap();
+        let mut res: Vec<Account> = data
+            .iter()
+            .filter(|x| x.currency == currency_)
+            .filter(|x| after.map(|after| x.id.inner() > after.inner()).unwrap_or(true))
+            .cloned()
+            .collect();
+        res.sort_by_key(|x| x.id.inner().clone());
+        res.truncate(limit as usize);
+        Ok(res)
+    }
 }
 
 #[derive(Clone, Default)]
 pub struct TransactionsRepoMock {
     data: Arc<Mutex<Vec<Transaction>>>,
+    status_history: Arc<Mutex<Vec<StatusChange>>>,
+}
+
+impl TransactionsRepoMock {
+    fn record_status_change(&self, transaction: &Transaction) {
+        let mut status_history = self.status_history.lock().unwrap();
+        let id = status_history.len() as i64 + 1;
+        status_history.push(StatusChange {
+            id,
+            transaction_id: transaction.id,
+            status: transaction.status,
+            created_at: ::chrono::Utc::now().naive_utc(),
+        });
+    }
 }
 
 impl TransactionsRepo for TransactionsRepoMock {
     fn create(&self, payload: NewTransaction) -> Result<Transaction, Error> {
         let mut data = self.data.lock().unwrap();
+        if data.iter().any(|x| x.id == payload.id) {
+            let mut errors = ValidationErrors::new();
+            errors.add("database", ValidationError::new("not unique"));
+            return Err(ectx!(err ErrorKind::Constraints(errors.clone()), ErrorKind::Constraints(errors) => payload));
+        }
         let res = Transaction {
             id: payload.id,
-            gid: payload.id,
+            gid: payload.gid,
             user_id: payload.user_id,
             dr_account_id: payload.dr_account_id,
             cr_account_id: payload.cr_account_id,
@@ -168,12 +216,19 @@ impl TransactionsRepo for TransactionsRepoMock {
             blockchain_tx_id: payload.blockchain_tx_id,
             created_at: ::chrono::Utc::now().naive_utc(),
             updated_at: ::chrono::Utc::now().naive_utc(),
-            kind: TransactionKind::Internal,
-            group_kind: TransactionGroupKind::Internal,
-            related_tx: None,
+            kind: payload.kind,
+            group_kind: payload.group_kind,
+            related_tx: payload.related_tx,
+            meta: payload.meta.unwrap_or_else(|| json!({})),
+            hold_until: payload.hold_until,
+            fee_price: payload.fee_price,
+            gross_fee: payload.gross_fee,
+            user_note: payload.user_note,
+            category: payload.category,
             ..Default::default()
         };
         data.push(res.clone());
+        self.record_status_change(&res);
         Ok(res)
     }
     fn get(&self, transaction_id: TransactionId) -> RepoResult<Option<Transaction>> {
@@ -184,6 +239,10 @@ impl TransactionsRepo for TransactionsRepoMock {
         let data = self.data.lock().unwrap();
         Ok(data.iter().filter(|x| x.gid == gid).cloned().collect())
     }
+    fn get_by_related_tx(&self, transaction_id: TransactionId) -> RepoResult<Vec<Transaction>> {
+        let data = self.data.lock().unwrap();
+        Ok(data.iter().filter(|x| x.related_tx == Some(transaction_id)).cloned().collect())
+    }
     fn get_by_blockchain_tx(&self, blockchain_tx_id: BlockchainTransactionId) -> RepoResult<Option<Transaction>> {
         let data = self.data.lock().unwrap();
         Ok(data
@@ -201,42 +260,259 @@ impl TransactionsRepo for TransactionsRepoMock {
         unimplemented!()
     }
 
-    fn get_account_spending(&self, account_id: AccountId, _kind: AccountKind, _period: Duration) -> RepoResult<Amount> {
+    fn get_account_spending(
+        &self,
+        account_id: AccountId,
+        _kind: AccountKind,
+        _period: Duration,
+        _now: NaiveDateTime,
+    ) -> RepoResult<Amount> {
         let data = self.data.lock().unwrap();
         let amount = data
             .iter()
-            .filter(|x| account_id == x.dr_account_id)
+            .filter(|x| account_id == x.dr_account_id && x.status.affects_balance())
             .try_fold(Amount::new(0), |acc, elem| acc.checked_add(elem.value));
         Ok(amount.unwrap())
     }
 
-    fn list_groups_for_account_skip_approval(&self, _account_id: AccountId, _offset: i64, _limit: i64) -> RepoResult<Vec<Transaction>> {
-        unimplemented!()
+    fn sum_withdrawals_since(&self, user_id: UserId, currency: Currency, since: NaiveDateTime) -> RepoResult<Amount> {
+        let data = self.data.lock().unwrap();
+        let amount = data
+            .iter()
+            .filter(|x| x.user_id == user_id && x.currency == currency && x.kind == TransactionKind::Withdrawal && x.created_at >= since)
+            .filter(|x| x.status != TransactionStatus::Cancelled && x.status != TransactionStatus::Failed)
+            .try_fold(Amount::new(0), |acc, elem| acc.checked_add(elem.value));
+        Ok(amount.unwrap())
     }
 
-    fn list_groups_for_user_skip_approval(&self, _user_id: UserId, _offset: i64, _limit: i64) -> RepoResult<Vec<Transaction>> {
-        unimplemented!()
+    fn last_withdrawal_at(&self, user_id: UserId) -> RepoResult<Option<NaiveDateTime>> {
+        let data = self.data.lock().unwrap();
+        Ok(data
+            .iter()
+            .filter(|x| x.user_id == user_id && x.kind == TransactionKind::Withdrawal)
+            .filter(|x| x.status != TransactionStatus::Cancelled && x.status != TransactionStatus::Failed)
+            .map(|x| x.created_at)
+            .max())
+    }
+
+    fn sum_credits(&self, account_id: AccountId, kind: TransactionKind, from: NaiveDateTime, to: NaiveDateTime) -> RepoResult<Amount> {
+        let data = self.data.lock().unwrap();
+        let amount = data
+            .iter()
+            .filter(|x| x.cr_account_id == account_id && x.kind == kind && x.created_at >= from && x.created_at < to)
+            .filter(|x| x.status == TransactionStatus::Done)
+            .try_fold(Amount::new(0), |acc, elem| acc.checked_add(elem.value));
+        Ok(amount.unwrap())
+    }
+
+    fn sum_debits(&self, account_id: AccountId, kind: TransactionKind, from: NaiveDateTime, to: NaiveDateTime) -> RepoResult<Amount> {
+        let data = self.data.lock().unwrap();
+        let amount = data
+            .iter()
+            .filter(|x| x.dr_account_id == account_id && x.kind == kind && x.created_at >= from && x.created_at < to)
+            .filter(|x| x.status == TransactionStatus::Done)
+            .try_fold(Amount::new(0), |acc, elem| acc.checked_add(elem.value));
+        Ok(amount.unwrap())
+    }
+
+    fn list_groups_for_account_skip_approval(
+        &self,
+        account_id: AccountId,
+        kind: Option<TransactionKind>,
+        category: Option<String>,
+        _offset: i64,
+        _limit: i64,
+    ) -> RepoResult<Vec<Transaction>> {
+        let data = self.data.lock().unwrap();
+        let gids: HashSet<_> = data
+            .iter()
+            .filter(|x| x.group_kind != TransactionGroupKind::Approval)
+            .filter(|x| x.cr_account_id == account_id || x.dr_account_id == account_id)
+            .filter(|x| kind.map(|kind| x.kind == kind).unwrap_or(true))
+            .filter(|x| category.is_none() || x.category == category)
+            .map(|x| x.gid)
+            .collect();
+        Ok(data.iter().filter(|x| gids.contains(&x.gid)).cloned().collect())
+    }
+
+    fn list_groups_for_user_skip_approval(
+        &self,
+        user_id: UserId,
+        category: Option<String>,
+        _offset: i64,
+        _limit: i64,
+    ) -> RepoResult<Vec<Transaction>> {
+        let data = self.data.lock().unwrap();
+        let gids: HashSet<_> = data
+            .iter()
+            .filter(|x| x.group_kind != TransactionGroupKind::Approval)
+            .filter(|x| x.user_id == user_id)
+            .filter(|x| category.is_none() || x.category == category)
+            .map(|x| x.gid)
+            .collect();
+        Ok(data.iter().filter(|x| gids.contains(&x.gid)).cloned().collect())
+    }
+
+    fn list_groups_for_user_after(&self, user_id: UserId, after: Option<TransactionsCursor>, limit: i64) -> RepoResult<Vec<Transaction>> {
+        let data = self.data.lock().unwrap();
+        let mut groups: HashMap<TransactionId, NaiveDateTime> = HashMap::new();
+        for x in data
+            .iter()
+            .filter(|x| x.group_kind != TransactionGroupKind::Approval)
+            .filter(|x| x.user_id == user_id)
+        {
+            let created_at = groups.entry(x.gid).or_insert(x.created_at);
+            if x.created_at < *created_at {
+                *created_at = x.created_at;
+            }
+        }
+        // uuids are compared bytewise, the same as in postgres
+        let mut groups: Vec<(NaiveDateTime, Uuid)> = groups
+            .into_iter()
+            .map(|(gid, created_at)| (created_at, *gid.inner()))
+            .filter(|key| after.map(|cursor| *key < (cursor.created_at, *cursor.gid.inner())).unwrap_or(true))
+            .collect();
+        groups.sort();
+        let gids: HashSet<_> = groups
+            .into_iter()
+            .rev()
+            .take(limit as usize)
+            .map(|(_, gid)| TransactionId::new(gid))
+            .collect();
+        Ok(data.iter().filter(|x| gids.contains(&x.gid)).cloned().collect())
+    }
+
+    fn list_by_group_kind(&self, kind: TransactionGroupKind, _offset: i64, _limit: i64) -> RepoResult<Vec<Transaction>> {
+        let data = self.data.lock().unwrap();
+        let gids: HashSet<_> = data.iter().filter(|x| x.group_kind == kind).map(|x| x.gid).collect();
+        Ok(data.iter().filter(|x| gids.contains(&x.gid)).cloned().collect())
+    }
+
+    fn list_exchanges_for_user(&self, user_id: UserId, from: NaiveDateTime, to: NaiveDateTime) -> RepoResult<Vec<Transaction>> {
+        let data = self.data.lock().unwrap();
+        Ok(data
+            .iter()
+            .filter(|x| x.user_id == user_id && x.group_kind == TransactionGroupKind::InternalMulti)
+            .filter(|x| x.kind == TransactionKind::MultiFrom || x.kind == TransactionKind::MultiTo)
+            .filter(|x| x.created_at >= from && x.created_at < to)
+            .cloned()
+            .collect())
     }
 
     fn update_status(&self, blockchain_tx_id: BlockchainTransactionId, transaction_status: TransactionStatus) -> RepoResult<Transaction> {
+        let mut data = self.data.lock().unwrap();
+        let u: Vec<Transaction> = data
+            .iter_mut()
+            .filter_map(|x| {
+                if x.blockchain_tx_id == Some(blockchain_tx_id.clone()) {
+                    x.status = transaction_status;
+                    Some(x.clone())
+                } else {
+                    None
+                }
+            })
+            .collect();
+        for tx in &u {
+            self.record_status_change(tx);
+        }
+        Ok(u[0].clone())
+    }
+    fn update_status_failed(&self, blockchain_tx_id: BlockchainTransactionId, failure_reason: String) -> RepoResult<Transaction> {
+        let mut data = self.data.lock().unwrap();
+        let u: Vec<Transaction> = data
+            .iter_mut()
+            .filter_map(|x| {
+                if x.blockchain_tx_id == Some(blockchain_tx_id.clone()) {
+                    x.status = TransactionStatus::Failed;
+                    x.failure_reason = Some(failure_reason.clone());
+                    Some(x.clone())
+                } else {
+                    None
+                }
+            })
+            .collect();
+        for tx in &u {
+            self.record_status_change(tx);
+        }
+        Ok(u[0].clone())
+    }
+    fn update_status_by_id(&self, transaction_id: TransactionId, transaction_status: TransactionStatus) -> RepoResult<Transaction> {
         let mut data = self.data.lock().unwrap();
         let u = data
             .iter_mut()
             .filter_map(|x| {
-                if let Some(x_blockchain_tx_id) = x.blockchain_tx_id.clone() {
-                    if x_blockchain_tx_id == blockchain_tx_id {
-                        x.status = transaction_status;
-                        Some(x)
-                    } else {
-                        None
-                    }
+                if x.id == transaction_id {
+                    x.status = transaction_status;
+                    Some(x)
                 } else {
                     None
                 }
             })
             .nth(0)
-            .cloned();
-        Ok(u.unwrap())
+            .cloned()
+            .unwrap();
+        self.record_status_change(&u);
+        Ok(u)
+    }
+    fn get_status_history(&self, transaction_ids: &[TransactionId]) -> RepoResult<Vec<StatusChange>> {
+        let status_history = self.status_history.lock().unwrap();
+        Ok(status_history
+            .iter()
+            .filter(|change| transaction_ids.contains(&change.transaction_id))
+            .cloned()
+            .collect())
+    }
+    fn get_scheduled_due(&self, now: NaiveDateTime) -> RepoResult<Vec<Transaction>> {
+        let data = self.data.lock().unwrap();
+        Ok(data
+            .iter()
+            .filter(|x| x.status == TransactionStatus::Scheduled && x.hold_until.map(|hold_until| hold_until <= now).unwrap_or(false))
+            .cloned()
+            .collect())
+    }
+    fn get_pending_withdrawals(&self, limit: i64) -> RepoResult<Vec<Transaction>> {
+        let data = self.data.lock().unwrap();
+        Ok(data
+            .iter()
+            .filter(|x| x.status == TransactionStatus::Pending && x.kind == TransactionKind::Withdrawal && x.blockchain_tx_id.is_some())
+            .take(limit as usize)
+            .cloned()
+            .collect())
+    }
+    fn list_pending_older_than(&self, now: NaiveDateTime, duration: Duration, limit: i64) -> RepoResult<Vec<Transaction>> {
+        let date = now - duration;
+        let data = self.data.lock().unwrap();
+        Ok(data
+            .iter()
+            .filter(|x| x.status == TransactionStatus::Pending && x.created_at < date)
+            .take(limit as usize)
+            .cloned()
+            .collect())
+    }
+    fn list_orphaned_withdrawals(&self, _limit: i64) -> RepoResult<Vec<Transaction>> {
+        unimplemented!()
+    }
+    fn list_withdrawals_by_blockchain_txs(
+        &self,
+        blockchain_tx_ids: &[BlockchainTransactionId],
+        offset: i64,
+        limit: i64,
+    ) -> RepoResult<Vec<Transaction>> {
+        let data = self.data.lock().unwrap();
+        Ok(data
+            .iter()
+            .rev()
+            .filter(|x| {
+                x.kind == TransactionKind::Withdrawal
+                    && x.blockchain_tx_id
+                        .as_ref()
+                        .map(|hash| blockchain_tx_ids.contains(hash))
+                        .unwrap_or(false)
+            })
+            .skip(offset as usize)
+            .take(limit as usize)
+            .cloned()
+            .collect())
     }
     fn list_for_user(&self, user_id: UserId, _offset: i64, _limit: i64) -> RepoResult<Vec<Transaction>> {
         let data = self.data.lock().unwrap();
@@ -261,7 +537,7 @@ impl TransactionsRepo for TransactionsRepoMock {
             .iter()
             .fold(Some(Amount::default()), |acc: Option<Amount>, x: &Transaction| {
                 if let Some(acc) = acc {
-                    if x.cr_account_id == account_id {
+                    if x.cr_account_id == account_id && x.status.affects_balance() {
                         acc.checked_add(x.value)
                     } else {
                         Some(acc)
@@ -277,7 +553,7 @@ impl TransactionsRepo for TransactionsRepoMock {
             .iter()
             .fold(Some(Amount::default()), |acc: Option<Amount>, x: &Transaction| {
                 if let Some(acc) = acc {
-                    if x.dr_account_id == account_id {
+                    if x.dr_account_id == account_id && x.status.affects_balance() {
                         acc.checked_add(x.value)
                     } else {
                         Some(acc)
@@ -296,6 +572,22 @@ impl TransactionsRepo for TransactionsRepoMock {
                 .ok_or_else(|| ectx!(err ErrorContext::BalanceOverflow, ErrorKind::Internal => account_id)),
         }
     }
+    fn balance_at(&self, account_id: AccountId, kind: AccountKind, as_of: NaiveDateTime) -> RepoResult<Amount> {
+        let data = self.data.lock().unwrap();
+        let sum = |is_leg: &Fn(&Transaction) -> bool| {
+            data.iter()
+                .filter(|x| is_leg(x) && x.created_at <= as_of && x.status.affects_balance())
+                .try_fold(Amount::new(0), |acc, x| acc.checked_add(x.value))
+                .ok_or_else(|| ectx!(err ErrorContext::BalanceOverflow, ErrorKind::Internal => account_id))
+        };
+        let cr_sum = sum(&|x| x.cr_account_id == account_id)?;
+        let dr_sum = sum(&|x| x.dr_account_id == account_id)?;
+        let balance = match kind {
+            AccountKind::Cr => cr_sum.checked_sub(dr_sum),
+            AccountKind::Dr => dr_sum.checked_sub(cr_sum),
+        };
+        balance.ok_or_else(|| ectx!(err ErrorContext::BalanceOverflow, ErrorKind::Internal => account_id))
+    }
     fn list_for_account(&self, account_id: AccountId, _offset: i64, _limit: i64) -> RepoResult<Vec<Transaction>> {
         let data = self.data.lock().unwrap();
         Ok(data
@@ -328,8 +620,11 @@ impl TransactionsRepo for TransactionsRepoMock {
             .into_iter()
             .filter(|x| x.currency == currency_ && x.value > value_)
             .map(|t| {
+                // liquidity is held on dr accounts
                 let mut acc = Account::default();
-                acc.id = t.cr_account_id;
+                acc.id = t.dr_account_id;
+                acc.currency = currency_;
+                acc.kind = AccountKind::Dr;
                 AccountWithBalance {
                     account: acc,
                     balance: value_,
@@ -361,6 +656,7 @@ impl PendingBlockchainTransactionsRepo for PendingBlockchainTransactionsRepoMock
             created_at: ::chrono::Utc::now().naive_utc(),
             updated_at: ::chrono::Utc::now().naive_utc(),
             erc20_operation_kind: None,
+            raw_tx: payload.raw_tx,
         };
         data.push(res.clone());
         Ok(res)
@@ -370,8 +666,17 @@ impl PendingBlockchainTransactionsRepo for PendingBlockchainTransactionsRepoMock
         Ok(data.iter().filter(|x| x.hash == hash_).nth(0).cloned())
     }
     fn delete(&self, hash_: BlockchainTransactionId) -> RepoResult<Option<PendingBlockchainTransactionDB>> {
+        let mut data = self.data.lock().unwrap();
+        let idx = data.iter().position(|x| x.hash == hash_);
+        Ok(idx.map(|idx| data.remove(idx)))
+    }
+    fn list_by_destination(&self, address: BlockchainAddress, currency: Currency) -> RepoResult<Vec<PendingBlockchainTransactionDB>> {
         let data = self.data.lock().unwrap();
-        Ok(data.iter().filter(|x| x.hash == hash_).nth(0).cloned())
+        Ok(data
+            .iter()
+            .filter(|x| x.to_ == address && x.currency == currency)
+            .cloned()
+            .collect())
     }
 }
 
@@ -413,6 +718,7 @@ impl BlockchainTransactionsRepo for BlockchainTransactionsRepoMock {
             updated_at: ::chrono::Utc::now().naive_utc(),
             erc20_operation_kind: None,
         };
+        data.retain(|x| x.hash != res.hash);
         data.push(res.clone());
         Ok(res)
     }
@@ -421,6 +727,118 @@ impl BlockchainTransactionsRepo for BlockchainTransactionsRepoMock {
         let data = self.data.lock().unwrap();
         Ok(data.iter().filter(|x| x.hash == hash_).next().cloned())
     }
+
+    fn update_confirmations(&self, hash_: BlockchainTransactionId, confirmations: i32) -> RepoResult<Option<BlockchainTransactionDB>> {
+        let mut data = self.data.lock().unwrap();
+        Ok(data.iter_mut().find(|x| x.hash == hash_).map(|x| {
+            x.confirmations = confirmations;
+            x.clone()
+        }))
+    }
+
+    fn list_by_destination(&self, address: BlockchainAddress, currency: Currency) -> RepoResult<Vec<BlockchainTransactionDB>> {
+        let data = self.data.lock().unwrap();
+        Ok(data
+            .iter()
+            .filter(|x| {
+                let to: Vec<BlockchainTransactionEntryTo> = ::serde_json::from_value(x.to_.clone()).unwrap_or_default();
+                x.currency == currency && to.iter().any(|entry| entry.address == address)
+            })
+            .cloned()
+            .collect())
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct SeenHashesRepoMock {
+    data: Arc<Mutex<Vec<SeenHashes>>>,
+}
+
+impl SeenHashesRepo for SeenHashesRepoMock {
+    fn create(&self, payload: NewSeenHashes) -> RepoResult<SeenHashes> {
+        let mut data = self.data.lock().unwrap();
+        let res = SeenHashes {
+            hash: payload.hash,
+            block_number: payload.block_number,
+            currency: payload.currency,
+            created_at: ::chrono::Utc::now().naive_utc(),
+            updated_at: ::chrono::Utc::now().naive_utc(),
+            output_index: payload.output_index,
+        };
+        data.push(res.clone());
+        Ok(res)
+    }
+    fn upsert(&self, payload: NewSeenHashes) -> RepoResult<SeenHashes> {
+        let mut data = self.data.lock().unwrap();
+        data.retain(|x| !(x.hash == payload.hash && x.currency == payload.currency && x.output_index == payload.output_index));
+        let res = SeenHashes {
+            hash: payload.hash,
+            block_number: payload.block_number,
+            currency: payload.currency,
+            created_at: ::chrono::Utc::now().naive_utc(),
+            updated_at: ::chrono::Utc::now().naive_utc(),
+            output_index: payload.output_index,
+        };
+        data.push(res.clone());
+        Ok(res)
+    }
+    fn get(&self, hash_: BlockchainTransactionId, currency_: Currency) -> RepoResult<Option<SeenHashes>> {
+        let data = self.data.lock().unwrap();
+        Ok(data.iter().filter(|x| x.hash == hash_ && x.currency == currency_).nth(0).cloned())
+    }
+    fn get_output(&self, hash_: BlockchainTransactionId, currency_: Currency, output_index_: i32) -> RepoResult<Option<SeenHashes>> {
+        let data = self.data.lock().unwrap();
+        Ok(data
+            .iter()
+            .filter(|x| x.hash == hash_ && x.currency == currency_ && x.output_index == output_index_)
+            .nth(0)
+            .cloned())
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct StrangeBlockchainTransactionsRepoMock {
+    data: Arc<Mutex<Vec<StrangeBlockchainTransactionDB>>>,
+}
+
+impl StrangeBlockchainTransactionsRepo for StrangeBlockchainTransactionsRepoMock {
+    fn create(&self, payload: NewStrangeBlockchainTransactionDB) -> RepoResult<StrangeBlockchainTransactionDB> {
+        let mut data = self.data.lock().unwrap();
+        let res = StrangeBlockchainTransactionDB {
+            hash: payload.hash,
+            from_: payload.from_,
+            to_: payload.to_,
+            block_number: payload.block_number,
+            currency: payload.currency,
+            fee: payload.fee,
+            confirmations: payload.confirmations,
+            created_at: ::chrono::Utc::now().naive_utc(),
+            updated_at: ::chrono::Utc::now().naive_utc(),
+            commentary: payload.commentary,
+            erc20_operation_kind: payload.erc20_operation_kind,
+        };
+        data.push(res.clone());
+        Ok(res)
+    }
+    fn count(&self) -> RepoResult<u64> {
+        let data = self.data.lock().unwrap();
+        Ok(data.len() as u64)
+    }
+    fn get(&self, hash_: BlockchainTransactionId) -> RepoResult<Option<StrangeBlockchainTransactionDB>> {
+        let data = self.data.lock().unwrap();
+        Ok(data.iter().filter(|x| x.hash == hash_).nth(0).cloned())
+    }
+    fn list(&self, offset: i64, limit: i64, currency: Option<Currency>) -> RepoResult<Vec<StrangeBlockchainTransactionDB>> {
+        let data = self.data.lock().unwrap();
+        Ok(data
+            .iter()
+            .rev()
+            .filter(|x| currency.map(|currency| x.currency == currency).unwrap_or(true))
+            .skip(offset as usize)
+            .take(limit as usize)
+            .cloned()
+            .collect())
+    }
 }
 
 #[derive(Clone, Default)]
@@ -446,10 +864,84 @@ impl KeyValuesRepo for KeyValuesRepoMock {
         data.push(res.clone());
         Ok(nonce)
     }
+    fn get_rate(&self, from: Currency, to: Currency) -> RepoResult<Option<KeyValue>> {
+        let data = self.data.lock().unwrap();
+        let key = format!("rate:{}:{}", from, to);
+        Ok(data.iter().filter(|x| x.key == key).nth(0).cloned())
+    }
+    fn set_rate(&self, from: Currency, to: Currency, rate: f64) -> RepoResult<f64> {
+        let mut data = self.data.lock().unwrap();
+        let key = format!("rate:{}:{}", from, to);
+        data.retain(|x| x.key != key);
+        data.push(KeyValue {
+            key,
+            value: json!(rate),
+            created_at: ::chrono::Utc::now().naive_utc(),
+            updated_at: ::chrono::Utc::now().naive_utc(),
+        });
+        Ok(rate)
+    }
+    fn get_resolver_enabled(&self) -> RepoResult<bool> {
+        let data = self.data.lock().unwrap();
+        Ok(data
+            .iter()
+            .filter(|x| x.key == "resolver_enabled")
+            .nth(0)
+            .and_then(|x| x.value.as_bool())
+            .unwrap_or(true))
+    }
+    fn set_resolver_enabled(&self, enabled: bool) -> RepoResult<bool> {
+        let mut data = self.data.lock().unwrap();
+        let key = "resolver_enabled".to_string();
+        data.retain(|x| x.key != key);
+        data.push(KeyValue {
+            key,
+            value: json!(enabled),
+            created_at: ::chrono::Utc::now().naive_utc(),
+            updated_at: ::chrono::Utc::now().naive_utc(),
+        });
+        Ok(enabled)
+    }
+    fn get_withdrawal_lock(&self, user_id: UserId) -> RepoResult<Option<WithdrawalLock>> {
+        let data = self.data.lock().unwrap();
+        let key = format!("withdrawal_lock:{}", user_id);
+        Ok(data
+            .iter()
+            .filter(|x| x.key == key)
+            .nth(0)
+            .and_then(|x| WithdrawalLock::from_value(&x.value)))
+    }
+    fn set_withdrawal_lock(&self, user_id: UserId, lock: WithdrawalLock) -> RepoResult<WithdrawalLock> {
+        let mut data = self.data.lock().unwrap();
+        let key = format!("withdrawal_lock:{}", user_id);
+        data.retain(|x| x.key != key);
+        data.push(KeyValue {
+            key,
+            value: lock.to_value(),
+            created_at: ::chrono::Utc::now().naive_utc(),
+            updated_at: ::chrono::Utc::now().naive_utc(),
+        });
+        Ok(lock)
+    }
+    fn delete_withdrawal_lock(&self, user_id: UserId) -> RepoResult<()> {
+        let mut data = self.data.lock().unwrap();
+        let key = format!("withdrawal_lock:{}", user_id);
+        data.retain(|x| x.key != key);
+        Ok(())
+    }
 }
 
 #[derive(Clone, Default)]
-pub struct DbExecutorMock;
+pub struct DbExecutorMock {
+    executed: Arc<Mutex<usize>>,
+}
+
+impl DbExecutorMock {
+    /// Number of closures run by this executor and its clones
+    pub fn executed(&self) -> usize {
+        *self.executed.lock().unwrap()
+    }
+}
 
 impl DbExecutor for DbExecutorMock {
     fn execute<F, T, E>(&self, f: F) -> Box<Future<Item = T, Error = E> + Send + 'static>
@@ -458,6 +950,7 @@ impl DbExecutor for DbExecutorMock {
         F: FnOnce() -> Result<T, E> + Send + 'static,
         E: From<Error> + Send + 'static,
     {
+        *self.executed.lock().unwrap() += 1;
         Box::new(f().into_future())
     }
     fn execute_transaction_with_isolation<F, T, E>(&self, _isolation: Isolation, f: F) -> Box<Future<Item = T, Error = E> + Send + 'static>
@@ -466,8 +959,17 @@ impl DbExecutor for DbExecutorMock {
         F: FnOnce() -> Result<T, E> + Send + 'static,
         E: From<Error> + Send + 'static,
     {
+        *self.executed.lock().unwrap() += 1;
         Box::new(f().into_future())
     }
+    fn execute_serializable_with_retries<F, T, E>(&self, retries: usize, f: F) -> Box<Future<Item = T, Error = E> + Send + 'static>
+    where
+        T: Send + 'static,
+        F: Fn() -> Result<T, E> + Send + 'static,
+        E: From<Error> + Fail,
+    {
+        Box::new(retry_on_serialization_failure(retries, f).into_future())
+    }
     fn execute_test_transaction<F, T, E>(&self, f: F) -> Box<Future<Item = T, Error = E> + Send + 'static>
     where
         T: Send + 'static,
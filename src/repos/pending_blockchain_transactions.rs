@@ -13,6 +13,7 @@ pub trait PendingBlockchainTransactionsRepo: Send + Sync + 'static {
     fn get(&self, hash_: BlockchainTransactionId) -> RepoResult<Option<PendingBlockchainTransactionDB>>;
     fn count(&self) -> RepoResult<u64>;
     fn delete(&self, hash_: BlockchainTransactionId) -> RepoResult<Option<PendingBlockchainTransactionDB>>;
+    fn list_by_destination(&self, address: BlockchainAddress, currency_: Currency) -> RepoResult<Vec<PendingBlockchainTransactionDB>>;
 }
 
 #[derive(Clone, Default)]
@@ -65,6 +66,18 @@ impl PendingBlockchainTransactionsRepo for PendingBlockchainTransactionsRepoImpl
             })
         })
     }
+    fn list_by_destination(&self, address: BlockchainAddress, currency_: Currency) -> RepoResult<Vec<PendingBlockchainTransactionDB>> {
+        with_tls_connection(|conn| {
+            pending_blockchain_transactions
+                .filter(to_.eq(address.clone()))
+                .filter(currency.eq(currency_))
+                .get_results(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => address, currency_)
+                })
+        })
+    }
 }
 
 #[cfg(test)]
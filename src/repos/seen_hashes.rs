@@ -10,7 +10,9 @@ use schema::seen_hashes::dsl::*;
 pub trait SeenHashesRepo: Send + Sync + 'static {
     fn create(&self, payload: NewSeenHashes) -> RepoResult<SeenHashes>;
     fn upsert(&self, payload: NewSeenHashes) -> RepoResult<SeenHashes>;
+    /// Any record of the transaction, no matter which output
     fn get(&self, hash_: BlockchainTransactionId, currency_: Currency) -> RepoResult<Option<SeenHashes>>;
+    fn get_output(&self, hash_: BlockchainTransactionId, currency_: Currency, output_index_: i32) -> RepoResult<Option<SeenHashes>>;
 }
 
 #[derive(Clone, Default)]
@@ -32,7 +34,7 @@ impl SeenHashesRepo for SeenHashesRepoImpl {
         with_tls_connection(|conn| {
             diesel::insert_into(seen_hashes)
                 .values(payload.clone())
-                .on_conflict((hash, currency))
+                .on_conflict((hash, currency, output_index))
                 .do_update()
                 // this will guarantee that tx is returned, unlike do_nothing case
                 .set(hash.eq(payload.hash.clone()))
@@ -58,6 +60,21 @@ impl SeenHashesRepo for SeenHashesRepoImpl {
                 })
         })
     }
+
+    fn get_output(&self, hash_: BlockchainTransactionId, currency_: Currency, output_index_: i32) -> RepoResult<Option<SeenHashes>> {
+        with_tls_connection(|conn| {
+            seen_hashes
+                .filter(hash.eq(hash_.clone()))
+                .filter(currency.eq(currency_))
+                .filter(output_index.eq(output_index_))
+                .get_result(conn)
+                .optional()
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => hash_, currency_, output_index_)
+                })
+        })
+    }
 }
 
 #[cfg(test)]
@@ -106,4 +123,25 @@ pub mod tests {
             res
         }));
     }
+
+    #[test]
+    fn seen_hashes_outputs() {
+        let mut core = Core::new().unwrap();
+        let db_executor = create_executor();
+        let seen_hashes_repo = SeenHashesRepoImpl::default();
+        let _ = core.run(db_executor.execute_test_transaction(move || {
+            let first = NewSeenHashes::default();
+            let second = NewSeenHashes {
+                output_index: 1,
+                ..first.clone()
+            };
+            seen_hashes_repo.create(first.clone())?;
+            seen_hashes_repo.create(second)?;
+            assert!(seen_hashes_repo.create(first.clone()).is_err());
+            assert!(seen_hashes_repo.get_output(first.hash.clone(), first.currency, 1)?.is_some());
+            let res = seen_hashes_repo.get_output(first.hash, first.currency, 2);
+            assert!(res.as_ref().unwrap().is_none());
+            res
+        }));
+    }
 }
@@ -12,6 +12,8 @@ pub trait StrangeBlockchainTransactionsRepo: Send + Sync + 'static {
     fn create(&self, payload: NewStrangeBlockchainTransactionDB) -> RepoResult<StrangeBlockchainTransactionDB>;
     fn count(&self) -> RepoResult<u64>;
     fn get(&self, hash_: BlockchainTransactionId) -> RepoResult<Option<StrangeBlockchainTransactionDB>>;
+    /// Flagged transactions, newest first, optionally only of one currency
+    fn list(&self, offset: i64, limit: i64, currency_: Option<Currency>) -> RepoResult<Vec<StrangeBlockchainTransactionDB>>;
 }
 
 #[derive(Clone, Default)]
@@ -55,6 +57,23 @@ impl StrangeBlockchainTransactionsRepo for StrangeBlockchainTransactionsRepoImpl
                 })
         })
     }
+    fn list(&self, offset: i64, limit: i64, currency_: Option<Currency>) -> RepoResult<Vec<StrangeBlockchainTransactionDB>> {
+        with_tls_connection(|conn| {
+            let mut query = strange_blockchain_transactions.into_boxed();
+            if let Some(currency_) = currency_ {
+                query = query.filter(currency.eq(currency_));
+            }
+            query
+                .order(created_at.desc())
+                .offset(offset)
+                .limit(limit)
+                .get_results(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => offset, limit, currency_)
+                })
+        })
+    }
 }
 
 #[cfg(test)]
@@ -103,4 +122,43 @@ pub mod tests {
             res
         }));
     }
+
+    #[test]
+    fn strange_blockchain_transactions_list() {
+        let mut core = Core::new().unwrap();
+        let db_executor = create_executor();
+        let strange_blockchain_transactions_repo = StrangeBlockchainTransactionsRepoImpl::default();
+        let _ = core.run(db_executor.execute_test_transaction(move || {
+            let mut first = NewStrangeBlockchainTransactionDB::default();
+            first.hash = BlockchainTransactionId::new("strange_list_1".to_string());
+            first.currency = Currency::Btc;
+            strange_blockchain_transactions_repo.create(first)?;
+            let mut second = NewStrangeBlockchainTransactionDB::default();
+            second.hash = BlockchainTransactionId::new("strange_list_2".to_string());
+            second.currency = Currency::Eth;
+            second.commentary = "unknown destination".to_string();
+            strange_blockchain_transactions_repo.create(second)?;
+
+            let res = strange_blockchain_transactions_repo.list(0, 100, None)?;
+            assert!(res.len() >= 2);
+            let res = strange_blockchain_transactions_repo.list(0, 1, None)?;
+            assert_eq!(res.len(), 1);
+
+            let res = strange_blockchain_transactions_repo.list(0, 100, Some(Currency::Eth))?;
+            assert!(res.iter().all(|tx| tx.currency == Currency::Eth));
+            let flagged = res
+                .iter()
+                .find(|tx| tx.hash == BlockchainTransactionId::new("strange_list_2".to_string()))
+                .unwrap();
+            assert_eq!(flagged.commentary, "unknown destination");
+            let res = strange_blockchain_transactions_repo.list(0, 100, Some(Currency::Btc));
+            assert!(res.as_ref().unwrap().iter().all(|tx| tx.currency == Currency::Btc));
+            assert!(res
+                .as_ref()
+                .unwrap()
+                .iter()
+                .any(|tx| tx.hash == BlockchainTransactionId::new("strange_list_1".to_string())));
+            res
+        }));
+    }
 }
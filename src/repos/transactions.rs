@@ -1,11 +1,13 @@
 use std::collections::{HashMap, HashSet};
 
-use chrono::{Duration, Utc};
+use chrono::{Duration, NaiveDateTime};
 use diesel;
-use diesel::dsl::{any, sum};
+use diesel::dsl::{any, max, sum};
+use diesel::pg::PgConnection;
+use diesel::result::Error as DieselError;
 use diesel::sql_query;
 use diesel::sql_types::Uuid as SqlUuid;
-use diesel::sql_types::{BigInt, Numeric, Timestamp, VarChar};
+use diesel::sql_types::{BigInt, Nullable, Numeric, Timestamp, VarChar};
 
 use super::error::*;
 use super::executor::with_tls_connection;
@@ -13,6 +15,7 @@ use super::*;
 use models::*;
 use prelude::*;
 use schema::accounts::dsl as Accounts;
+use schema::transaction_status_history::dsl as StatusHistory;
 use schema::transactions::dsl::*;
 
 // 0.001 BTC
@@ -26,19 +29,71 @@ pub trait TransactionsRepo: Send + Sync + 'static {
     fn create(&self, payload: NewTransaction) -> RepoResult<Transaction>;
     fn get(&self, transaction_id: TransactionId) -> RepoResult<Option<Transaction>>;
     fn update_status(&self, blockchain_tx_id: BlockchainTransactionId, transaction_status: TransactionStatus) -> RepoResult<Transaction>;
+    /// Sets `Failed` status with the reason on all legs of the blockchain transaction
+    fn update_status_failed(&self, blockchain_tx_id: BlockchainTransactionId, failure_reason_: String) -> RepoResult<Transaction>;
     fn get_by_gid(&self, gid: TransactionId) -> RepoResult<Vec<Transaction>>;
+    /// Transactions linked to the given one with `related_tx`, e.g. reversals and blockchain fees
+    fn get_by_related_tx(&self, transaction_id: TransactionId) -> RepoResult<Vec<Transaction>>;
     fn get_by_blockchain_tx(&self, blockchain_tx_id: BlockchainTransactionId) -> RepoResult<Option<Transaction>>;
     fn update_blockchain_tx(&self, transaction_id: TransactionId, blockchain_tx_id: BlockchainTransactionId) -> RepoResult<Transaction>;
     fn get_account_balance(&self, account_id: AccountId, kind: AccountKind) -> RepoResult<Amount>;
-    fn get_account_spending(&self, account_id: AccountId, kind: AccountKind, period: Duration) -> RepoResult<Amount>;
+    /// Balance made up of legs created at or before `as_of`, legs are counted by their current status
+    fn balance_at(&self, account_id: AccountId, kind: AccountKind, as_of: NaiveDateTime) -> RepoResult<Amount>;
+    fn get_account_spending(&self, account_id: AccountId, kind: AccountKind, period: Duration, now: NaiveDateTime) -> RepoResult<Amount>;
+    fn sum_withdrawals_since(&self, user_id: UserId, currency: Currency, since: NaiveDateTime) -> RepoResult<Amount>;
+    /// Creation time of the latest `Withdrawal` leg of the user in any currency, failed and cancelled ones are skipped
+    fn last_withdrawal_at(&self, user_id: UserId) -> RepoResult<Option<NaiveDateTime>>;
+    /// Sum of done legs of `kind` crediting the account, created in `[from, to)`
+    fn sum_credits(&self, account_id: AccountId, kind: TransactionKind, from: NaiveDateTime, to: NaiveDateTime) -> RepoResult<Amount>;
+    /// Sum of done legs of `kind` debiting the account, created in `[from, to)`
+    fn sum_debits(&self, account_id: AccountId, kind: TransactionKind, from: NaiveDateTime, to: NaiveDateTime) -> RepoResult<Amount>;
     fn get_accounts_balance(&self, auth_user_id: UserId, accounts: &[Account]) -> RepoResult<Vec<AccountWithBalance>>;
     fn list_for_user(&self, user_id_arg: UserId, offset: i64, limit: i64) -> RepoResult<Vec<Transaction>>;
     fn list_for_account(&self, account_id: AccountId, offset: i64, limit: i64) -> RepoResult<Vec<Transaction>>;
-    fn list_groups_for_account_skip_approval(&self, account_id: AccountId, offset: i64, limit: i64) -> RepoResult<Vec<Transaction>>;
-    fn list_groups_for_user_skip_approval(&self, user_id: UserId, offset: i64, limit: i64) -> RepoResult<Vec<Transaction>>;
+    /// Lists transaction groups of the account. If `kind` is set, only groups having a leg
+    /// of this kind for the account are returned, if `category` is set - only groups of this category
+    fn list_groups_for_account_skip_approval(
+        &self,
+        account_id: AccountId,
+        kind: Option<TransactionKind>,
+        category: Option<String>,
+        offset: i64,
+        limit: i64,
+    ) -> RepoResult<Vec<Transaction>>;
+    fn list_groups_for_user_skip_approval(
+        &self,
+        user_id: UserId,
+        category: Option<String>,
+        offset: i64,
+        limit: i64,
+    ) -> RepoResult<Vec<Transaction>>;
+    /// Up to `limit` transaction groups of the user following `after`, newest first
+    fn list_groups_for_user_after(&self, user_id: UserId, after: Option<TransactionsCursor>, limit: i64) -> RepoResult<Vec<Transaction>>;
+    /// Transaction groups of `kind` of all users, newest first
+    fn list_by_group_kind(&self, kind: TransactionGroupKind, offset: i64, limit: i64) -> RepoResult<Vec<Transaction>>;
+    /// `MultiFrom` and `MultiTo` legs of user's internal exchanges created in `[from, to)`
+    fn list_exchanges_for_user(&self, user_id: UserId, from: NaiveDateTime, to: NaiveDateTime) -> RepoResult<Vec<Transaction>>;
     fn get_system_balances(&self) -> RepoResult<HashMap<AccountId, (Amount, Amount)>>;
     fn get_blockchain_balances(&self) -> RepoResult<HashMap<(BlockchainAddress, Currency), (Amount, Amount)>>;
     fn get_accounts_for_withdrawal(&self, value: Amount, currency: Currency, total_fee: Amount) -> RepoResult<Vec<AccountWithBalance>>;
+    fn get_scheduled_due(&self, now: NaiveDateTime) -> RepoResult<Vec<Transaction>>;
+    /// Pending withdrawal legs already posted to blockchain, oldest first
+    fn get_pending_withdrawals(&self, limit: i64) -> RepoResult<Vec<Transaction>>;
+    /// Transactions of any kind that are still pending after `duration`, oldest first
+    fn list_pending_older_than(&self, now: NaiveDateTime, duration: Duration, limit: i64) -> RepoResult<Vec<Transaction>>;
+    /// Pending withdrawal legs posted to blockchain that have neither pending nor blockchain transaction,
+    /// i.e. saving the pending transaction failed after broadcast. Oldest first
+    fn list_orphaned_withdrawals(&self, limit: i64) -> RepoResult<Vec<Transaction>>;
+    /// Withdrawal legs sent in any of the blockchain transactions, newest first
+    fn list_withdrawals_by_blockchain_txs(
+        &self,
+        blockchain_tx_ids: &[BlockchainTransactionId],
+        offset: i64,
+        limit: i64,
+    ) -> RepoResult<Vec<Transaction>>;
+    fn update_status_by_id(&self, transaction_id: TransactionId, transaction_status: TransactionStatus) -> RepoResult<Transaction>;
+    /// Statuses of the transactions in order they were set, starting with the one on creation
+    fn get_status_history(&self, transaction_ids: &[TransactionId]) -> RepoResult<Vec<StatusChange>>;
 }
 
 #[derive(Debug, Clone, Queryable, QueryableByName)]
@@ -49,6 +104,12 @@ struct GidQuery {
     created_at: chrono::NaiveDateTime,
 }
 
+#[derive(Debug, Clone, Queryable, QueryableByName)]
+struct IdQuery {
+    #[sql_type = "SqlUuid"]
+    id: TransactionId,
+}
+
 #[derive(Debug, Clone, Queryable, QueryableByName)]
 struct BalanceQuery {
     #[sql_type = "VarChar"]
@@ -85,24 +146,28 @@ impl TransactionsRepoImpl {
 impl TransactionsRepo for TransactionsRepoImpl {
     fn create(&self, payload: NewTransaction) -> RepoResult<Transaction> {
         with_tls_connection(|conn| {
-            diesel::insert_into(transactions)
-                .values(payload.clone())
-                .get_result::<Transaction>(conn)
-                .map_err(move |e| {
-                    let error_kind = ErrorKind::from(&e);
-                    ectx!(err e, error_kind => payload)
-                })
+            conn.transaction::<_, DieselError, _>(|| {
+                let transaction = diesel::insert_into(transactions)
+                    .values(payload.clone())
+                    .get_result::<Transaction>(conn)?;
+                record_status_changes(conn, &[transaction.clone()])?;
+                Ok(transaction)
+            })
+            .map_err(move |e| {
+                let error_kind = ErrorKind::from(&e);
+                ectx!(err e, error_kind => payload)
+            })
         })
     }
 
-    // SELECT cr_account_id as id, SUM(value) FROM transactions JOIN accounts ON transactions.cr_account_id = accounts.id WHERE accounts.user_id = '00000000-0000-4000-8000-010000000000' AND accounts.kind = 'cr' GROUP BY cr_account_id;
-    // SELECT dr_account_id as id, SUM(value) FROM transactions JOIN accounts ON transactions.dr_account_id = accounts.id WHERE accounts.user_id = '00000000-0000-4000-8000-010000000000' AND accounts.kind = 'cr' GROUP BY dr_account_id;
+    // SELECT cr_account_id as id, SUM(value) FROM transactions JOIN accounts ON transactions.cr_account_id = accounts.id WHERE accounts.user_id = '00000000-0000-4000-8000-010000000000' AND accounts.kind = 'cr' AND transactions.status NOT IN ('scheduled', 'cancelled', 'failed') GROUP BY cr_account_id;
+    // SELECT dr_account_id as id, SUM(value) FROM transactions JOIN accounts ON transactions.dr_account_id = accounts.id WHERE accounts.user_id = '00000000-0000-4000-8000-010000000000' AND accounts.kind = 'cr' AND transactions.status NOT IN ('scheduled', 'cancelled', 'failed') GROUP BY dr_account_id;
 
     fn get_system_balances(&self) -> RepoResult<HashMap<AccountId, (Amount, Amount)>> {
         with_tls_connection(|conn| {
             let dr_turnovers: Vec<SystemBalanceQuery> =
                 sql_query(
-                "SELECT dr_account_id as id, SUM(value) FROM transactions JOIN accounts ON transactions.dr_account_id = accounts.id WHERE accounts.user_id = $1 AND accounts.kind = 'cr' GROUP BY dr_account_id;")
+                "SELECT dr_account_id as id, SUM(value) FROM transactions JOIN accounts ON transactions.dr_account_id = accounts.id WHERE accounts.user_id = $1 AND accounts.kind = 'cr' AND transactions.status NOT IN ('scheduled', 'cancelled', 'failed') GROUP BY dr_account_id;")
                     .bind::<SqlUuid, _>(self.system_user_id)
                     .get_results(conn)
                     .map_err(move |e| {
@@ -115,7 +180,7 @@ impl TransactionsRepo for TransactionsRepoImpl {
                 .collect();
             let cr_turnovers: Vec<SystemBalanceQuery> =
                 sql_query(
-                "SELECT cr_account_id as id, SUM(value) FROM transactions JOIN accounts ON transactions.cr_account_id = accounts.id WHERE accounts.user_id = $1 AND accounts.kind = 'cr' GROUP BY cr_account_id;")
+                "SELECT cr_account_id as id, SUM(value) FROM transactions JOIN accounts ON transactions.cr_account_id = accounts.id WHERE accounts.user_id = $1 AND accounts.kind = 'cr' AND transactions.status NOT IN ('scheduled', 'cancelled', 'failed') GROUP BY cr_account_id;")
                     .bind::<SqlUuid, _>(self.system_user_id)
                     .get_results(conn)
                     .map_err(move |e| {
@@ -144,7 +209,7 @@ impl TransactionsRepo for TransactionsRepoImpl {
         with_tls_connection(|conn| {
             let dr_turnovers: Vec<BalanceQuery> =
                 sql_query(
-                "SELECT accounts.address, accounts.currency, sums.sum FROM (SELECT dr_account_id, SUM(value) FROM transactions GROUP BY dr_account_id) AS sums INNER JOIN accounts ON accounts.id = sums.dr_account_id WHERE accounts.kind = 'dr'")
+                "SELECT accounts.address, accounts.currency, sums.sum FROM (SELECT dr_account_id, SUM(value) FROM transactions WHERE status <> 'failed' GROUP BY dr_account_id) AS sums INNER JOIN accounts ON accounts.id = sums.dr_account_id WHERE accounts.kind = 'dr'")
                     .get_results(conn)
                     .map_err(move |e| {
                         let error_kind = ErrorKind::from(&e);
@@ -156,7 +221,7 @@ impl TransactionsRepo for TransactionsRepoImpl {
                 .collect();
             let cr_turnovers: Vec<BalanceQuery> =
                 sql_query(
-                "SELECT accounts.address, accounts.currency, sums.sum FROM (SELECT cr_account_id, SUM(value) FROM transactions GROUP BY cr_account_id) AS sums INNER JOIN accounts ON accounts.id = sums.cr_account_id WHERE accounts.kind = 'dr'")
+                "SELECT accounts.address, accounts.currency, sums.sum FROM (SELECT cr_account_id, SUM(value) FROM transactions WHERE status <> 'failed' GROUP BY cr_account_id) AS sums INNER JOIN accounts ON accounts.id = sums.cr_account_id WHERE accounts.kind = 'dr'")
                     .get_results(conn)
                     .map_err(move |e| {
                         let error_kind = ErrorKind::from(&e);
@@ -202,6 +267,19 @@ impl TransactionsRepo for TransactionsRepoImpl {
         })
     }
 
+    fn get_by_related_tx(&self, transaction_id: TransactionId) -> RepoResult<Vec<Transaction>> {
+        with_tls_connection(|conn| {
+            transactions
+                .filter(related_tx.eq(transaction_id))
+                .order(created_at)
+                .get_results(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => transaction_id)
+                })
+        })
+    }
+
     //Todo - add filtering by user
     fn get_by_blockchain_tx(&self, blockchain_tx_id_: BlockchainTransactionId) -> RepoResult<Option<Transaction>> {
         with_tls_connection(|conn| {
@@ -219,20 +297,163 @@ impl TransactionsRepo for TransactionsRepoImpl {
 
     fn update_status(&self, blockchain_tx_id_: BlockchainTransactionId, transaction_status: TransactionStatus) -> RepoResult<Transaction> {
         with_tls_connection(|conn| {
-            let f = transactions.filter(blockchain_tx_id.eq(blockchain_tx_id_.clone()));
-            diesel::update(f)
-                .set(status.eq(transaction_status))
-                .get_result(conn)
+            conn.transaction::<_, DieselError, _>(|| {
+                let f = transactions.filter(blockchain_tx_id.eq(blockchain_tx_id_.clone()));
+                let updated: Vec<Transaction> = diesel::update(f).set(status.eq(transaction_status)).get_results(conn)?;
+                record_status_changes(conn, &updated)?;
+                updated.into_iter().next().ok_or(DieselError::NotFound)
+            })
+            .map_err(move |e| {
+                let error_kind = ErrorKind::from(&e);
+                ectx!(err e, error_kind => blockchain_tx_id_, transaction_status)
+            })
+        })
+    }
+
+    fn update_status_failed(&self, blockchain_tx_id_: BlockchainTransactionId, failure_reason_: String) -> RepoResult<Transaction> {
+        with_tls_connection(|conn| {
+            conn.transaction::<_, DieselError, _>(|| {
+                let f = transactions.filter(blockchain_tx_id.eq(blockchain_tx_id_.clone()));
+                let updated: Vec<Transaction> = diesel::update(f)
+                    .set((status.eq(TransactionStatus::Failed), failure_reason.eq(failure_reason_.clone())))
+                    .get_results(conn)?;
+                record_status_changes(conn, &updated)?;
+                updated.into_iter().next().ok_or(DieselError::NotFound)
+            })
+            .map_err(move |e| {
+                let error_kind = ErrorKind::from(&e);
+                ectx!(err e, error_kind => blockchain_tx_id_, failure_reason_)
+            })
+        })
+    }
+
+    fn update_status_by_id(&self, transaction_id: TransactionId, transaction_status: TransactionStatus) -> RepoResult<Transaction> {
+        with_tls_connection(|conn| {
+            conn.transaction::<_, DieselError, _>(|| {
+                let f = transactions.filter(id.eq(transaction_id));
+                let transaction: Transaction = diesel::update(f).set(status.eq(transaction_status)).get_result(conn)?;
+                record_status_changes(conn, &[transaction.clone()])?;
+                Ok(transaction)
+            })
+            .map_err(move |e| {
+                let error_kind = ErrorKind::from(&e);
+                ectx!(err e, error_kind => transaction_id, transaction_status)
+            })
+        })
+    }
+
+    fn get_status_history(&self, transaction_ids: &[TransactionId]) -> RepoResult<Vec<StatusChange>> {
+        let transaction_ids = transaction_ids.to_vec();
+        with_tls_connection(|conn| {
+            StatusHistory::transaction_status_history
+                .filter(StatusHistory::transaction_id.eq(any(transaction_ids.clone())))
+                .order(StatusHistory::id)
+                .get_results(conn)
                 .map_err(move |e| {
                     let error_kind = ErrorKind::from(&e);
-                    ectx!(err e, error_kind => blockchain_tx_id_, transaction_status)
+                    ectx!(err e, error_kind => transaction_ids)
                 })
         })
     }
+
+    fn get_scheduled_due(&self, now: NaiveDateTime) -> RepoResult<Vec<Transaction>> {
+        with_tls_connection(|conn| {
+            transactions
+                .filter(status.eq(TransactionStatus::Scheduled))
+                .filter(hold_until.le(now))
+                .order(hold_until)
+                .get_results(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => now)
+                })
+        })
+    }
+
+    fn get_pending_withdrawals(&self, limit: i64) -> RepoResult<Vec<Transaction>> {
+        with_tls_connection(|conn| {
+            transactions
+                .filter(status.eq(TransactionStatus::Pending))
+                .filter(kind.eq(TransactionKind::Withdrawal))
+                .filter(blockchain_tx_id.is_not_null())
+                .order(created_at)
+                .limit(limit)
+                .get_results(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => limit)
+                })
+        })
+    }
+
+    fn list_pending_older_than(&self, now: NaiveDateTime, duration: Duration, limit: i64) -> RepoResult<Vec<Transaction>> {
+        with_tls_connection(|conn| {
+            let date = now - duration;
+            transactions
+                .filter(status.eq(TransactionStatus::Pending))
+                .filter(created_at.lt(date))
+                .order(created_at)
+                .limit(limit)
+                .get_results(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => now, duration, limit)
+                })
+        })
+    }
+
+    fn list_orphaned_withdrawals(&self, limit: i64) -> RepoResult<Vec<Transaction>> {
+        with_tls_connection(|conn| {
+            let ids: Vec<IdQuery> =
+                sql_query(
+                "SELECT id FROM transactions WHERE status = 'pending' AND kind = 'withdrawal' AND blockchain_tx_id IS NOT NULL AND NOT EXISTS (SELECT 1 FROM pending_blockchain_transactions WHERE pending_blockchain_transactions.hash = transactions.blockchain_tx_id) AND NOT EXISTS (SELECT 1 FROM blockchain_transactions WHERE blockchain_transactions.hash = transactions.blockchain_tx_id) ORDER BY created_at LIMIT $1")
+                    .bind::<BigInt, _>(limit)
+                    .get_results(conn)
+                    .map_err(move |e| {
+                        let error_kind = ErrorKind::from(&e);
+                        ectx!(try err e, error_kind => limit)
+                    })?;
+            let ids: Vec<_> = ids.into_iter().map(|query| query.id).collect();
+            transactions
+                .filter(id.eq(any(ids)))
+                .order(created_at)
+                .get_results(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => limit)
+                })
+        })
+    }
+
+    fn list_withdrawals_by_blockchain_txs(
+        &self,
+        blockchain_tx_ids: &[BlockchainTransactionId],
+        offset: i64,
+        limit: i64,
+    ) -> RepoResult<Vec<Transaction>> {
+        let blockchain_tx_ids = blockchain_tx_ids.to_vec();
+        with_tls_connection(|conn| {
+            transactions
+                .filter(kind.eq(TransactionKind::Withdrawal))
+                .filter(blockchain_tx_id.eq(any(blockchain_tx_ids.clone())))
+                .order(created_at.desc())
+                .offset(offset)
+                .limit(limit)
+                .get_results(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => blockchain_tx_ids, offset, limit)
+                })
+        })
+    }
+
     fn get_account_balance(&self, account_id: AccountId, kind_: AccountKind) -> RepoResult<Amount> {
         with_tls_connection(|conn| {
             let cr_sum: Option<Amount> = transactions
                 .filter(cr_account_id.eq(account_id))
+                .filter(status.ne(TransactionStatus::Scheduled))
+                .filter(status.ne(TransactionStatus::Cancelled))
+                .filter(status.ne(TransactionStatus::Failed))
                 .select(sum(value))
                 .get_result(conn)
                 .map_err(move |e| {
@@ -244,6 +465,9 @@ impl TransactionsRepo for TransactionsRepoImpl {
 
             let dr_sum: Option<Amount> = transactions
                 .filter(dr_account_id.eq(account_id))
+                .filter(status.ne(TransactionStatus::Scheduled))
+                .filter(status.ne(TransactionStatus::Cancelled))
+                .filter(status.ne(TransactionStatus::Failed))
                 .select(sum(value))
                 .get_result(conn)
                 .map_err(move |e| {
@@ -263,13 +487,56 @@ impl TransactionsRepo for TransactionsRepoImpl {
             }
         })
     }
-    fn get_account_spending(&self, account_id: AccountId, kind_: AccountKind, period: Duration) -> RepoResult<Amount> {
+    fn balance_at(&self, account_id: AccountId, kind_: AccountKind, as_of: NaiveDateTime) -> RepoResult<Amount> {
         with_tls_connection(|conn| {
-            let date = Utc::now().naive_utc() - period;
+            let cr_sum: Option<Amount> = transactions
+                .filter(cr_account_id.eq(account_id))
+                .filter(created_at.le(as_of))
+                .filter(status.ne(TransactionStatus::Scheduled))
+                .filter(status.ne(TransactionStatus::Cancelled))
+                .filter(status.ne(TransactionStatus::Failed))
+                .select(sum(value))
+                .get_result(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(try err e, error_kind => account_id, as_of)
+                })?;
+            let cr_sum = cr_sum.unwrap_or_default();
+
+            let dr_sum: Option<Amount> = transactions
+                .filter(dr_account_id.eq(account_id))
+                .filter(created_at.le(as_of))
+                .filter(status.ne(TransactionStatus::Scheduled))
+                .filter(status.ne(TransactionStatus::Cancelled))
+                .filter(status.ne(TransactionStatus::Failed))
+                .select(sum(value))
+                .get_result(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(try err e, error_kind => account_id, as_of)
+                })?;
+            let dr_sum = dr_sum.unwrap_or_default();
+
+            match kind_ {
+                AccountKind::Cr => cr_sum
+                    .checked_sub(dr_sum)
+                    .ok_or_else(|| ectx!(err ErrorContext::BalanceOverflow, ErrorKind::Internal => account_id, as_of)),
+                AccountKind::Dr => dr_sum
+                    .checked_sub(cr_sum)
+                    .ok_or_else(|| ectx!(err ErrorContext::BalanceOverflow, ErrorKind::Internal => account_id, as_of)),
+            }
+        })
+    }
+    fn get_account_spending(&self, account_id: AccountId, kind_: AccountKind, period: Duration, now: NaiveDateTime) -> RepoResult<Amount> {
+        with_tls_connection(|conn| {
+            let date = now - period;
             let txs: Vec<Transaction> = match kind_ {
                 AccountKind::Dr => transactions
                     .filter(cr_account_id.eq(account_id))
                     .filter(created_at.ge(date))
+                    .filter(status.ne(TransactionStatus::Scheduled))
+                    .filter(status.ne(TransactionStatus::Cancelled))
+                    .filter(status.ne(TransactionStatus::Failed))
                     .get_results(conn)
                     .map_err(move |e| {
                         let error_kind = ErrorKind::from(&e);
@@ -278,6 +545,9 @@ impl TransactionsRepo for TransactionsRepoImpl {
                 AccountKind::Cr => transactions
                     .filter(dr_account_id.eq(account_id))
                     .filter(created_at.ge(date))
+                    .filter(status.ne(TransactionStatus::Scheduled))
+                    .filter(status.ne(TransactionStatus::Cancelled))
+                    .filter(status.ne(TransactionStatus::Failed))
                     .get_results(conn)
                     .map_err(move |e| {
                         let error_kind = ErrorKind::from(&e);
@@ -290,6 +560,80 @@ impl TransactionsRepo for TransactionsRepoImpl {
         })
     }
 
+    fn sum_withdrawals_since(&self, user_id_: UserId, currency_: Currency, since: NaiveDateTime) -> RepoResult<Amount> {
+        with_tls_connection(|conn| {
+            let withdrawn: Option<Amount> = transactions
+                .filter(user_id.eq(user_id_))
+                .filter(currency.eq(currency_))
+                .filter(kind.eq(TransactionKind::Withdrawal))
+                .filter(created_at.ge(since))
+                .filter(status.ne(TransactionStatus::Cancelled))
+                .filter(status.ne(TransactionStatus::Failed))
+                .select(sum(value))
+                .get_result(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(try err e, error_kind => user_id_, currency_, since)
+                })?;
+            //sum will return null if there are no rows in select statement returned
+            Ok(withdrawn.unwrap_or_default())
+        })
+    }
+
+    fn last_withdrawal_at(&self, user_id_: UserId) -> RepoResult<Option<NaiveDateTime>> {
+        with_tls_connection(|conn| {
+            transactions
+                .filter(user_id.eq(user_id_))
+                .filter(kind.eq(TransactionKind::Withdrawal))
+                .filter(status.ne(TransactionStatus::Cancelled))
+                .filter(status.ne(TransactionStatus::Failed))
+                .select(max(created_at))
+                .get_result(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => user_id_)
+                })
+        })
+    }
+
+    fn sum_credits(&self, account_id: AccountId, kind_: TransactionKind, from: NaiveDateTime, to: NaiveDateTime) -> RepoResult<Amount> {
+        with_tls_connection(|conn| {
+            let credited: Option<Amount> = transactions
+                .filter(cr_account_id.eq(account_id))
+                .filter(kind.eq(kind_))
+                .filter(status.eq(TransactionStatus::Done))
+                .filter(created_at.ge(from))
+                .filter(created_at.lt(to))
+                .select(sum(value))
+                .get_result(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(try err e, error_kind => account_id, kind_, from, to)
+                })?;
+            //sum will return null if there are no rows in select statement returned
+            Ok(credited.unwrap_or_default())
+        })
+    }
+
+    fn sum_debits(&self, account_id: AccountId, kind_: TransactionKind, from: NaiveDateTime, to: NaiveDateTime) -> RepoResult<Amount> {
+        with_tls_connection(|conn| {
+            let debited: Option<Amount> = transactions
+                .filter(dr_account_id.eq(account_id))
+                .filter(kind.eq(kind_))
+                .filter(status.eq(TransactionStatus::Done))
+                .filter(created_at.ge(from))
+                .filter(created_at.lt(to))
+                .select(sum(value))
+                .get_result(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(try err e, error_kind => account_id, kind_, from, to)
+                })?;
+            //sum will return null if there are no rows in select statement returned
+            Ok(debited.unwrap_or_default())
+        })
+    }
+
     fn list_for_user(&self, user_id_arg: UserId, offset: i64, limit: i64) -> RepoResult<Vec<Transaction>> {
         with_tls_connection(|conn| {
             let query = transactions.filter(user_id.eq(user_id_arg)).order(id).offset(offset).limit(limit);
@@ -313,14 +657,23 @@ impl TransactionsRepo for TransactionsRepoImpl {
                 })
         })
     }
-    fn list_groups_for_account_skip_approval(&self, account_id: AccountId, offset: i64, limit: i64) -> RepoResult<Vec<Transaction>> {
+    fn list_groups_for_account_skip_approval(
+        &self,
+        account_id: AccountId,
+        kind_: Option<TransactionKind>,
+        category_: Option<String>,
+        offset: i64,
+        limit: i64,
+    ) -> RepoResult<Vec<Transaction>> {
         with_tls_connection(|conn| {
             let gids: Vec<GidQuery> =
                 sql_query(
-                "SELECT gid, min(created_at) AS created_at FROM transactions WHERE group_kind <> 'approval' AND (cr_account_id = $1 OR dr_account_id = $1) GROUP BY gid ORDER BY created_at DESC OFFSET $2 LIMIT $3")
+                "SELECT gid, min(created_at) AS created_at FROM transactions WHERE group_kind <> 'approval' AND (cr_account_id = $1 OR dr_account_id = $1) AND ($4::varchar IS NULL OR kind = $4) AND ($5::varchar IS NULL OR category = $5) GROUP BY gid ORDER BY created_at DESC OFFSET $2 LIMIT $3")
                     .bind::<SqlUuid, _>(account_id)
                     .bind::<BigInt, _>(offset)
                     .bind::<BigInt, _>(limit)
+                    .bind::<Nullable<VarChar>, _>(kind_)
+                    .bind::<Nullable<VarChar>, _>(category_)
                     .get_results(conn)
                     .map_err(move |e| {
                         let error_kind = ErrorKind::from(&e);
@@ -338,14 +691,21 @@ impl TransactionsRepo for TransactionsRepoImpl {
         })
     }
 
-    fn list_groups_for_user_skip_approval(&self, user_id_: UserId, offset: i64, limit: i64) -> RepoResult<Vec<Transaction>> {
+    fn list_groups_for_user_skip_approval(
+        &self,
+        user_id_: UserId,
+        category_: Option<String>,
+        offset: i64,
+        limit: i64,
+    ) -> RepoResult<Vec<Transaction>> {
         with_tls_connection(|conn| {
             let gids: Vec<GidQuery> =
                 sql_query(
-                "SELECT gid, min(created_at) AS created_at FROM transactions WHERE group_kind <> 'approval' AND user_id = $1 GROUP BY gid ORDER BY created_at DESC OFFSET $2 LIMIT $3")
+                "SELECT gid, min(created_at) AS created_at FROM transactions WHERE group_kind <> 'approval' AND user_id = $1 AND ($4::varchar IS NULL OR category = $4) GROUP BY gid ORDER BY created_at DESC OFFSET $2 LIMIT $3")
                     .bind::<SqlUuid, _>(user_id_)
                     .bind::<BigInt, _>(offset)
                     .bind::<BigInt, _>(limit)
+                    .bind::<Nullable<VarChar>, _>(category_)
                     .get_results(conn)
                     .map_err(move |e| {
                         let error_kind = ErrorKind::from(&e);
@@ -363,6 +723,73 @@ impl TransactionsRepo for TransactionsRepoImpl {
         })
     }
 
+    fn list_groups_for_user_after(&self, user_id_: UserId, after: Option<TransactionsCursor>, limit: i64) -> RepoResult<Vec<Transaction>> {
+        with_tls_connection(|conn| {
+            let gids: Vec<GidQuery> =
+                sql_query(
+                "SELECT gid, min(created_at) AS created_at FROM transactions WHERE group_kind <> 'approval' AND user_id = $1 GROUP BY gid HAVING $2::timestamp IS NULL OR (min(created_at), gid) < ($2, $3) ORDER BY created_at DESC, gid DESC LIMIT $4")
+                    .bind::<SqlUuid, _>(user_id_)
+                    .bind::<Nullable<Timestamp>, _>(after.map(|cursor| cursor.created_at))
+                    .bind::<Nullable<SqlUuid>, _>(after.map(|cursor| cursor.gid))
+                    .bind::<BigInt, _>(limit)
+                    .get_results(conn)
+                    .map_err(move |e| {
+                        let error_kind = ErrorKind::from(&e);
+                        ectx!(try err e, error_kind => user_id_, after, limit)
+                    })?;
+            let gids: Vec<_> = gids.into_iter().map(|tuple| tuple.gid).collect();
+            transactions
+                .filter(gid.eq(any(gids)))
+                .order(created_at.desc())
+                .get_results(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind)
+                })
+        })
+    }
+
+    fn list_by_group_kind(&self, kind_: TransactionGroupKind, offset: i64, limit: i64) -> RepoResult<Vec<Transaction>> {
+        with_tls_connection(|conn| {
+            let gids: Vec<GidQuery> =
+                sql_query("SELECT gid, min(created_at) AS created_at FROM transactions WHERE group_kind = $1 GROUP BY gid ORDER BY created_at DESC OFFSET $2 LIMIT $3")
+                    .bind::<VarChar, _>(kind_)
+                    .bind::<BigInt, _>(offset)
+                    .bind::<BigInt, _>(limit)
+                    .get_results(conn)
+                    .map_err(move |e| {
+                        let error_kind = ErrorKind::from(&e);
+                        ectx!(try err e, error_kind => kind_, offset, limit)
+                    })?;
+            let gids: Vec<_> = gids.into_iter().map(|tuple| tuple.gid).collect();
+            transactions
+                .filter(gid.eq(any(gids)))
+                .order(created_at.desc())
+                .get_results(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => kind_)
+                })
+        })
+    }
+
+    fn list_exchanges_for_user(&self, user_id_: UserId, from: NaiveDateTime, to: NaiveDateTime) -> RepoResult<Vec<Transaction>> {
+        with_tls_connection(|conn| {
+            transactions
+                .filter(user_id.eq(user_id_))
+                .filter(group_kind.eq(TransactionGroupKind::InternalMulti))
+                .filter(kind.eq(TransactionKind::MultiFrom).or(kind.eq(TransactionKind::MultiTo)))
+                .filter(created_at.ge(from))
+                .filter(created_at.lt(to))
+                .order(created_at)
+                .get_results(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => user_id_, from, to)
+                })
+        })
+    }
+
     fn update_blockchain_tx(
         &self,
         transaction_id_arg: TransactionId,
@@ -385,6 +812,9 @@ impl TransactionsRepo for TransactionsRepoImpl {
             let ids: Vec<_> = accounts.into_iter().map(|acc| acc.id).collect();
             let txs = transactions
                 .filter(dr_account_id.eq(any(ids.clone())).or(cr_account_id.eq(any(ids))))
+                .filter(status.ne(TransactionStatus::Scheduled))
+                .filter(status.ne(TransactionStatus::Cancelled))
+                .filter(status.ne(TransactionStatus::Failed))
                 .get_results::<Transaction>(conn)
                 .map_err(move |e| {
                     let error_kind = ErrorKind::from(&e);
@@ -458,7 +888,7 @@ impl TransactionsRepo for TransactionsRepoImpl {
             };
             // get all dr accounts
             let dr_sum_accounts: Vec<TransactionSum> = sql_query(
-                "SELECT SUM(value) as sum, dr_account_id as account_id FROM transactions WHERE currency = $1 GROUP BY dr_account_id",
+                "SELECT SUM(value) as sum, dr_account_id as account_id FROM transactions WHERE currency = $1 AND status <> 'failed' GROUP BY dr_account_id",
             )
             .bind::<VarChar, _>(currency_)
             .get_results(conn)
@@ -473,7 +903,7 @@ impl TransactionsRepo for TransactionsRepoImpl {
 
             // get all cr accounts
             let cr_sum_accounts: Vec<TransactionSum> = sql_query(
-                "SELECT SUM(value) as sum, cr_account_id as account_id FROM transactions WHERE currency = $1 GROUP BY cr_account_id",
+                "SELECT SUM(value) as sum, cr_account_id as account_id FROM transactions WHERE currency = $1 AND status <> 'failed' GROUP BY cr_account_id",
             )
             .bind::<VarChar, _>(currency_)
             .get_results(conn)
@@ -533,12 +963,33 @@ impl TransactionsRepo for TransactionsRepoImpl {
                     ectx!(try err e, error_kind)
                 })?;
 
+            // reserve is set by users on their cr accounts, that share the address with dr ones
+            let res_addresses: Vec<BlockchainAddress> = res_accounts.iter().map(|acc| acc.address.clone()).collect();
+            let reserved_accounts: Vec<Account> = Accounts::accounts
+                .filter(Accounts::address.eq_any(res_addresses))
+                .filter(Accounts::currency.eq(currency_))
+                .get_results(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(try err e, error_kind)
+                })?;
+            let mut reserves: HashMap<BlockchainAddress, Amount> = HashMap::new();
+            for acc in reserved_accounts {
+                let reserve = reserves.entry(acc.address).or_insert_with(Amount::default);
+                if acc.reserve > *reserve {
+                    *reserve = acc.reserve;
+                }
+            }
+
             let res_accounts: Vec<(Account, Amount)> = res_accounts
                 .into_iter()
                 .map(|acc| {
                     let balance = remaining_accounts.get(&acc.id).cloned().unwrap_or_default();
+                    let reserve = reserves.get(&acc.address).cloned().unwrap_or_default();
+                    let balance = balance.checked_sub(reserve).unwrap_or_default();
                     (acc, balance)
                 })
+                .filter(|(_, balance)| balance.raw() > minimum_balance)
                 .filter(|(acc, _)| currency_ != Currency::Stq || acc.erc20_approved)
                 .collect();
 
@@ -571,8 +1022,18 @@ impl TransactionsRepo for TransactionsRepoImpl {
     }
 }
 
+// written in the same db transaction as the status itself, so that history can't miss a change
+fn record_status_changes(conn: &PgConnection, txs: &[Transaction]) -> Result<(), DieselError> {
+    let changes: Vec<NewStatusChange> = txs.iter().map(From::from).collect();
+    diesel::insert_into(StatusHistory::transaction_status_history)
+        .values(&changes)
+        .execute(conn)
+        .map(|_| ())
+}
+
 #[cfg(test)]
 pub mod tests {
+    use chrono::Utc;
     use diesel::r2d2::ConnectionManager;
     use diesel::PgConnection;
     use futures_cpupool::CpuPool;
@@ -620,6 +1081,41 @@ pub mod tests {
         }));
     }
 
+    #[test]
+    fn transactions_fee_estimate() {
+        let mut core = Core::new().unwrap();
+        let db_executor = create_executor();
+        let users_repo = UsersRepoImpl::default();
+        let accounts_repo = AccountsRepoImpl::default();
+        let transactions_repo = TransactionsRepoImpl::default();
+        let new_user = NewUser::default();
+        let _ = core.run(db_executor.execute_test_transaction(move || {
+            let user = users_repo.create(new_user)?;
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user.id;
+            let acc1 = accounts_repo.create(new_account)?;
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user.id;
+            let acc2 = accounts_repo.create(new_account)?;
+
+            let mut trans = NewTransaction::default();
+            trans.cr_account_id = acc1.id;
+            trans.dr_account_id = acc2.id;
+            trans.user_id = user.id;
+            trans.value = Amount::new(123);
+            trans.kind = TransactionKind::Fee;
+            trans.fee_price = Some(12.5);
+            trans.gross_fee = Some(Amount::new(2_800));
+
+            let transaction = transactions_repo.create(trans)?;
+            let res = transactions_repo.get(transaction.id);
+            let stored = res.as_ref().unwrap().clone().unwrap();
+            assert_eq!(stored.fee_price, Some(12.5));
+            assert_eq!(stored.gross_fee, Some(Amount::new(2_800)));
+            res
+        }));
+    }
+
     #[test]
     fn transactions_read() {
         let mut core = Core::new().unwrap();
@@ -682,6 +1178,118 @@ pub mod tests {
         }));
     }
 
+    #[test]
+    fn transactions_update_status_failed() {
+        let mut core = Core::new().unwrap();
+        let db_executor = create_executor();
+        let users_repo = UsersRepoImpl::default();
+        let accounts_repo = AccountsRepoImpl::default();
+        let transactions_repo = TransactionsRepoImpl::default();
+        let new_user = NewUser::default();
+        let _ = core.run(db_executor.execute_test_transaction(move || {
+            let user = users_repo.create(new_user)?;
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user.id;
+            let acc1 = accounts_repo.create(new_account)?;
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user.id;
+            let acc2 = accounts_repo.create(new_account)?;
+
+            let mut trans = NewTransaction::default();
+            trans.cr_account_id = acc1.id;
+            trans.dr_account_id = acc2.id;
+            trans.user_id = user.id;
+            trans.value = Amount::new(123);
+            trans.blockchain_tx_id = Some(BlockchainTransactionId::default());
+
+            let transaction = transactions_repo.create(trans)?;
+            let res = transactions_repo.update_status_failed(transaction.blockchain_tx_id.unwrap(), "reverted".to_string());
+            let updated = res.as_ref().unwrap();
+            assert_eq!(updated.status, TransactionStatus::Failed);
+            assert_eq!(updated.failure_reason, Some("reverted".to_string()));
+            res
+        }));
+    }
+
+    #[test]
+    fn transactions_status_history() {
+        let mut core = Core::new().unwrap();
+        let db_executor = create_executor();
+        let users_repo = UsersRepoImpl::default();
+        let accounts_repo = AccountsRepoImpl::default();
+        let transactions_repo = TransactionsRepoImpl::default();
+        let new_user = NewUser::default();
+        let _ = core.run(db_executor.execute_test_transaction(move || {
+            let user = users_repo.create(new_user)?;
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user.id;
+            let acc1 = accounts_repo.create(new_account)?;
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user.id;
+            let acc2 = accounts_repo.create(new_account)?;
+
+            let mut trans = NewTransaction::default();
+            trans.cr_account_id = acc1.id;
+            trans.dr_account_id = acc2.id;
+            trans.user_id = user.id;
+            trans.value = Amount::new(123);
+            trans.blockchain_tx_id = Some(BlockchainTransactionId::default());
+
+            let transaction = transactions_repo.create(trans)?;
+            transactions_repo.update_status(transaction.blockchain_tx_id.clone().unwrap(), TransactionStatus::Done)?;
+            let res = transactions_repo.get_status_history(&[transaction.id]);
+            let statuses: Vec<_> = res.as_ref().unwrap().iter().map(|change| change.status).collect();
+            assert_eq!(statuses, vec![TransactionStatus::Pending, TransactionStatus::Done]);
+            res
+        }));
+    }
+
+    #[test]
+    fn transactions_get_by_related_tx() {
+        let mut core = Core::new().unwrap();
+        let db_executor = create_executor();
+        let users_repo = UsersRepoImpl::default();
+        let accounts_repo = AccountsRepoImpl::default();
+        let transactions_repo = TransactionsRepoImpl::default();
+        let new_user = NewUser::default();
+        let _ = core.run(db_executor.execute_test_transaction(move || {
+            let user = users_repo.create(new_user)?;
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user.id;
+            let acc1 = accounts_repo.create(new_account)?;
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user.id;
+            let acc2 = accounts_repo.create(new_account)?;
+
+            let mut trans = NewTransaction::default();
+            trans.cr_account_id = acc1.id;
+            trans.dr_account_id = acc2.id;
+            trans.user_id = user.id;
+            trans.value = Amount::new(123);
+            let original = transactions_repo.create(trans)?;
+
+            let mut trans = NewTransaction::default();
+            trans.cr_account_id = acc2.id;
+            trans.dr_account_id = acc1.id;
+            trans.user_id = user.id;
+            trans.value = Amount::new(123);
+            trans.kind = TransactionKind::Reversal;
+            trans.related_tx = Some(original.id);
+            let reversal = transactions_repo.create(trans)?;
+
+            let related = transactions_repo.get_by_related_tx(original.id)?;
+            assert_eq!(related.iter().map(|tx| tx.id).collect::<Vec<_>>(), vec![reversal.id]);
+            // the link is one way, reversal is found from the original and not vice versa
+            let res = transactions_repo.get_by_related_tx(reversal.id);
+            assert!(res.as_ref().unwrap().is_empty());
+            assert_eq!(
+                transactions_repo.get(related[0].related_tx.unwrap())?.map(|tx| tx.id),
+                Some(original.id)
+            );
+            res
+        }));
+    }
+
     #[test]
     fn transactions_list_for_user() {
         let mut core = Core::new().unwrap();
@@ -742,6 +1350,48 @@ pub mod tests {
         }));
     }
     #[test]
+    fn transactions_get_accounts_for_withdrawal_with_reserve() {
+        let mut core = Core::new().unwrap();
+        let db_executor = create_executor();
+        let users_repo = UsersRepoImpl::default();
+        let accounts_repo = AccountsRepoImpl::default();
+        let transactions_repo = TransactionsRepoImpl::default();
+        let new_user = NewUser::default();
+        let _ = core.run(db_executor.execute_test_transaction(move || {
+            let user = users_repo.create(new_user)?;
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user.id;
+            new_account.currency = Currency::Eth;
+            let dr_account = new_account.create_debit();
+            let cr_account = accounts_repo.create(new_account)?;
+            let dr_account = accounts_repo.create(dr_account)?;
+            // 0.3 eth out of 1 eth is kept on the address
+            let payload = UpdateAccount {
+                reserve: Some(Amount::new(300_000_000_000_000_000)),
+                ..Default::default()
+            };
+            let cr_account = accounts_repo.update(cr_account.id, payload)?;
+
+            let mut deposit = NewTransaction::default();
+            deposit.cr_account_id = cr_account.id;
+            deposit.dr_account_id = dr_account.id;
+            deposit.user_id = user.id;
+            deposit.currency = Currency::Eth;
+            deposit.value = Amount::new(1_000_000_000_000_000_000);
+            transactions_repo.create(deposit)?;
+
+            let balance = transactions_repo.get_account_balance(cr_account.id, AccountKind::Cr)?;
+            assert_eq!(balance, Amount::new(1_000_000_000_000_000_000));
+            let res = transactions_repo.get_accounts_for_withdrawal(Amount::new(700_000_000_000_000_001), Currency::Eth, Amount::new(0));
+            assert!(res.is_err());
+            let res = transactions_repo.get_accounts_for_withdrawal(Amount::new(700_000_000_000_000_000), Currency::Eth, Amount::new(0))?;
+            assert_eq!(res.len(), 1);
+            assert_eq!(res[0].account.id, dr_account.id);
+            assert_eq!(res[0].balance, Amount::new(700_000_000_000_000_000));
+            Ok(res)
+        }));
+    }
+    #[test]
     fn transactions_list_for_account() {
         let mut core = Core::new().unwrap();
         let db_executor = create_executor();
@@ -771,6 +1421,164 @@ pub mod tests {
         }));
     }
 
+    #[test]
+    fn transactions_list_pending_older_than() {
+        let mut core = Core::new().unwrap();
+        let db_executor = create_executor();
+        let users_repo = UsersRepoImpl::default();
+        let accounts_repo = AccountsRepoImpl::default();
+        let transactions_repo = TransactionsRepoImpl::default();
+        let new_user = NewUser::default();
+        let _ = core.run(db_executor.execute_test_transaction(move || {
+            let user = users_repo.create(new_user)?;
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user.id;
+            let acc1 = accounts_repo.create(new_account)?;
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user.id;
+            let acc2 = accounts_repo.create(new_account)?;
+
+            let mut created = vec![];
+            for (age, tx_status) in vec![
+                (Duration::hours(2), TransactionStatus::Pending),
+                (Duration::minutes(10), TransactionStatus::Pending),
+                (Duration::hours(1), TransactionStatus::Done),
+                (Duration::seconds(0), TransactionStatus::Pending),
+            ] {
+                let mut trans = NewTransaction::default();
+                trans.cr_account_id = acc1.id;
+                trans.dr_account_id = acc2.id;
+                trans.user_id = user.id;
+                trans.value = Amount::new(123);
+                trans.status = tx_status;
+                trans.blockchain_tx_id = Some(BlockchainTransactionId::default());
+                let transaction = transactions_repo.create(trans)?;
+                with_tls_connection(|conn| {
+                    diesel::update(transactions.filter(id.eq(transaction.id)))
+                        .set(created_at.eq(Utc::now().naive_utc() - age))
+                        .execute(conn)
+                        .map_err(move |e| {
+                            let error_kind = ErrorKind::from(&e);
+                            ectx!(err e, error_kind => age)
+                        })
+                })?;
+                created.push(transaction);
+            }
+
+            let now = Utc::now().naive_utc();
+            let res = transactions_repo.list_pending_older_than(now, Duration::minutes(30), 10)?;
+            assert_eq!(res.iter().map(|tx| tx.id).collect::<Vec<_>>(), vec![created[0].id]);
+            assert_eq!(res[0].blockchain_tx_id, created[0].blockchain_tx_id);
+            let res = transactions_repo.list_pending_older_than(now, Duration::minutes(5), 10)?;
+            assert_eq!(res.iter().map(|tx| tx.id).collect::<Vec<_>>(), vec![created[0].id, created[1].id]);
+            let res = transactions_repo.list_pending_older_than(now, Duration::minutes(5), 1)?;
+            assert_eq!(res.len(), 1);
+            Ok(res)
+        }));
+    }
+
+    #[test]
+    fn transactions_list_orphaned_withdrawals() {
+        let mut core = Core::new().unwrap();
+        let db_executor = create_executor();
+        let users_repo = UsersRepoImpl::default();
+        let accounts_repo = AccountsRepoImpl::default();
+        let transactions_repo = TransactionsRepoImpl::default();
+        let pending_blockchain_transactions_repo = PendingBlockchainTransactionsRepoImpl::default();
+        let blockchain_transactions_repo = BlockchainTransactionsRepoImpl::default();
+        let new_user = NewUser::default();
+        let _ = core.run(db_executor.execute_test_transaction(move || {
+            let user = users_repo.create(new_user)?;
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user.id;
+            let acc1 = accounts_repo.create(new_account)?;
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user.id;
+            let acc2 = accounts_repo.create(new_account)?;
+
+            let pending_hash = BlockchainTransactionId::new("pending".to_string());
+            let seen_hash = BlockchainTransactionId::new("seen".to_string());
+            let orphaned_hash = BlockchainTransactionId::new("orphaned".to_string());
+            pending_blockchain_transactions_repo.create(NewPendingBlockchainTransactionDB {
+                hash: pending_hash.clone(),
+                ..Default::default()
+            })?;
+            blockchain_transactions_repo.create(NewBlockchainTransactionDB {
+                hash: seen_hash.clone(),
+                ..Default::default()
+            })?;
+            let mut created = vec![];
+            for hash in vec![pending_hash, seen_hash, orphaned_hash] {
+                let mut trans = NewTransaction::default();
+                trans.cr_account_id = acc1.id;
+                trans.dr_account_id = acc2.id;
+                trans.user_id = user.id;
+                trans.value = Amount::new(123);
+                trans.status = TransactionStatus::Pending;
+                trans.kind = TransactionKind::Withdrawal;
+                trans.blockchain_tx_id = Some(hash);
+                created.push(transactions_repo.create(trans)?);
+            }
+
+            let res = transactions_repo.list_orphaned_withdrawals(10)?;
+            assert_eq!(res.iter().map(|tx| tx.id).collect::<Vec<_>>(), vec![created[2].id]);
+            Ok(res)
+        }));
+    }
+
+    #[test]
+    fn transactions_list_by_group_kind() {
+        let mut core = Core::new().unwrap();
+        let db_executor = create_executor();
+        let users_repo = UsersRepoImpl::default();
+        let accounts_repo = AccountsRepoImpl::default();
+        let transactions_repo = TransactionsRepoImpl::default();
+        let new_user = NewUser::default();
+        let _ = core.run(db_executor.execute_test_transaction(move || {
+            let user = users_repo.create(new_user)?;
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user.id;
+            let acc1 = accounts_repo.create(new_account)?;
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user.id;
+            let acc2 = accounts_repo.create(new_account)?;
+
+            let group_kinds = vec![
+                TransactionGroupKind::Deposit,
+                TransactionGroupKind::Internal,
+                TransactionGroupKind::InternalMulti,
+                TransactionGroupKind::Withdrawal,
+                TransactionGroupKind::WithdrawalMulti,
+                TransactionGroupKind::Approval,
+                TransactionGroupKind::Reversal,
+            ];
+            // every group has two legs, both are returned with the group
+            let mut gids = vec![];
+            for group_kind_ in group_kinds.clone() {
+                let gid_ = TransactionId::generate();
+                for leg_id in vec![gid_, gid_.next()] {
+                    let mut trans = NewTransaction::default();
+                    trans.id = leg_id;
+                    trans.gid = gid_;
+                    trans.cr_account_id = acc1.id;
+                    trans.dr_account_id = acc2.id;
+                    trans.user_id = user.id;
+                    trans.value = Amount::new(123);
+                    trans.group_kind = group_kind_;
+                    transactions_repo.create(trans)?;
+                }
+                gids.push(gid_);
+            }
+
+            for (group_kind_, gid_) in group_kinds.into_iter().zip(gids.into_iter()) {
+                let res = transactions_repo.list_by_group_kind(group_kind_, 0, 100)?;
+                assert!(res.iter().all(|tx| tx.group_kind == group_kind_));
+                assert_eq!(res.iter().filter(|tx| tx.gid == gid_).count(), 2);
+            }
+            Ok(())
+        }));
+    }
+
     #[test]
     fn transactions_update_blockchain_tx_id() {
         let mut core = Core::new().unwrap();
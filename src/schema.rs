@@ -10,6 +10,7 @@ table! {
         updated_at -> Timestamp,
         erc20_approved -> Bool,
         daily_limit_type -> Varchar,
+        reserve -> Numeric,
     }
 }
 
@@ -48,16 +49,18 @@ table! {
         created_at -> Timestamp,
         updated_at -> Timestamp,
         erc20_operation_kind -> Nullable<Varchar>,
+        raw_tx -> Nullable<Varchar>,
     }
 }
 
 table! {
-    seen_hashes (hash, currency) {
+    seen_hashes (hash, currency, output_index) {
         hash -> Varchar,
         block_number -> Int8,
         currency -> Varchar,
         created_at -> Timestamp,
         updated_at -> Timestamp,
+        output_index -> Int4,
     }
 }
 
@@ -77,6 +80,15 @@ table! {
     }
 }
 
+table! {
+    transaction_status_history (id) {
+        id -> Int8,
+        transaction_id -> Uuid,
+        status -> Varchar,
+        created_at -> Timestamp,
+    }
+}
+
 table! {
     transactions (id) {
         id -> Uuid,
@@ -94,6 +106,12 @@ table! {
         group_kind -> Varchar,
         related_tx -> Nullable<Uuid>,
         meta -> Jsonb,
+        hold_until -> Nullable<Timestamp>,
+        fee_price -> Nullable<Float8>,
+        gross_fee -> Nullable<Numeric>,
+        failure_reason -> Nullable<Varchar>,
+        user_note -> Nullable<Varchar>,
+        category -> Nullable<Varchar>,
     }
 }
 
@@ -108,6 +126,7 @@ table! {
 }
 
 joinable!(accounts -> users (user_id));
+joinable!(transaction_status_history -> transactions (transaction_id));
 joinable!(transactions -> users (user_id));
 
 allow_tables_to_appear_in_same_query!(
@@ -117,6 +136,7 @@ allow_tables_to_appear_in_same_query!(
     pending_blockchain_transactions,
     seen_hashes,
     strange_blockchain_transactions,
+    transaction_status_history,
     transactions,
     users,
 );
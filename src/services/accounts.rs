@@ -1,3 +1,4 @@
+use std::collections::HashSet;
 use std::sync::Arc;
 
 use futures::future::{self, Either};
@@ -18,15 +19,23 @@ pub struct AccountsServiceImpl<E: DbExecutor> {
     accounts_repo: Arc<dyn AccountsRepo>,
     db_executor: E,
     keys_client: Arc<dyn KeysClient>,
+    enabled_currencies: HashSet<Currency>,
 }
 
 impl<E: DbExecutor> AccountsServiceImpl<E> {
-    pub fn new(auth_service: Arc<AuthService>, accounts_repo: Arc<AccountsRepo>, db_executor: E, keys_client: Arc<dyn KeysClient>) -> Self {
+    pub fn new(
+        auth_service: Arc<AuthService>,
+        accounts_repo: Arc<AccountsRepo>,
+        db_executor: E,
+        keys_client: Arc<dyn KeysClient>,
+        enabled_currencies: HashSet<Currency>,
+    ) -> Self {
         Self {
             auth_service,
             accounts_repo,
             db_executor,
             keys_client,
+            enabled_currencies,
         }
     }
 }
@@ -48,6 +57,10 @@ pub trait AccountsService: Send + Sync + 'static {
         offset: i64,
         limit: i64,
     ) -> Box<Future<Item = Vec<Account>, Error = Error> + Send>;
+    /// Whether `address` is one of the deposit addresses managed by us, so that integrators
+    /// can tell internal transfers from external ones
+    fn is_internal_address(&self, token: AuthenticationToken, address: BlockchainAddress)
+        -> Box<Future<Item = bool, Error = Error> + Send>;
 }
 
 impl<E: DbExecutor> AccountsService for AccountsServiceImpl<E> {
@@ -55,11 +68,17 @@ impl<E: DbExecutor> AccountsService for AccountsServiceImpl<E> {
         let accounts_repo = self.accounts_repo.clone();
         let db_executor = self.db_executor.clone();
         let keys_client = self.keys_client.clone();
+        let currency_enabled = self.enabled_currencies.contains(&input.currency);
         Box::new(self.auth_service.authenticate(token).and_then(move |user| {
             if input.user_id != user.id {
                 Either::A(future::err(
                     ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => user.id),
                 ))
+            } else if !currency_enabled {
+                let currency = input.currency;
+                Either::A(future::err(
+                    ectx!(err ErrorContext::CurrencyDisabled, ErrorKind::MalformedInput => currency),
+                ))
             } else {
                 Either::B(
                     input
@@ -168,6 +187,17 @@ impl<E: DbExecutor> AccountsService for AccountsServiceImpl<E> {
             })
         }))
     }
+    fn is_internal_address(
+        &self,
+        token: AuthenticationToken,
+        address: BlockchainAddress,
+    ) -> Box<Future<Item = bool, Error = Error> + Send> {
+        let accounts_repo = self.accounts_repo.clone();
+        let db_executor = self.db_executor.clone();
+        Box::new(self.auth_service.authenticate(token).and_then(move |_| {
+            db_executor.execute(move || accounts_repo.address_exists(address.clone()).map_err(ectx!(convert => address)))
+        }))
+    }
 }
 
 #[cfg(test)]
@@ -179,11 +209,20 @@ mod tests {
     use tokio_core::reactor::Core;
 
     fn create_account_service(token: AuthenticationToken, user_id: UserId) -> AccountsServiceImpl<DbExecutorMock> {
+        let enabled_currencies = vec![Currency::Btc, Currency::Eth, Currency::Stq].into_iter().collect();
+        create_account_service_with_currencies(token, user_id, enabled_currencies)
+    }
+
+    fn create_account_service_with_currencies(
+        token: AuthenticationToken,
+        user_id: UserId,
+        enabled_currencies: HashSet<Currency>,
+    ) -> AccountsServiceImpl<DbExecutorMock> {
         let auth_service = Arc::new(AuthServiceMock::new(vec![(token, user_id)]));
         let accounts_repo = Arc::new(AccountsRepoMock::default());
         let keys_client = Arc::new(KeysClientMock::default());
         let db_executor = DbExecutorMock::default();
-        AccountsServiceImpl::new(auth_service, accounts_repo, db_executor, keys_client)
+        AccountsServiceImpl::new(auth_service, accounts_repo, db_executor, keys_client, enabled_currencies)
     }
 
     #[test]
@@ -200,6 +239,28 @@ mod tests {
         let account = core.run(service.create_account(token, new_account));
         assert!(account.is_ok());
     }
+
+    #[test]
+    fn test_account_create_disabled_currency() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let user_id = UserId::generate();
+        let enabled_currencies = vec![Currency::Btc, Currency::Eth].into_iter().collect();
+        let service = create_account_service_with_currencies(token.clone(), user_id, enabled_currencies);
+
+        let mut new_account = CreateAccount::default();
+        new_account.name = "test test test acc".to_string();
+        new_account.user_id = user_id;
+        new_account.currency = Currency::Stq;
+        let err = core.run(service.create_account(token.clone(), new_account.clone())).unwrap_err();
+        match err.kind() {
+            ErrorKind::MalformedInput => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+
+        new_account.currency = Currency::Eth;
+        assert!(core.run(service.create_account(token, new_account)).is_ok());
+    }
     #[test]
     fn test_account_get() {
         let mut core = Core::new().unwrap();
@@ -261,6 +322,24 @@ mod tests {
         let account = core.run(service.get_accounts_for_user(token, new_account.user_id, 0, 10));
         assert!(account.is_ok());
     }
+    #[test]
+    fn test_account_is_internal_address() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let user_id = UserId::generate();
+        let service = create_account_service(token.clone(), user_id);
+
+        let mut new_account = CreateAccount::default();
+        new_account.name = "test test test acc".to_string();
+        new_account.user_id = user_id;
+        let account = core.run(service.create_account(token.clone(), new_account)).unwrap();
+
+        let is_internal = core.run(service.is_internal_address(token.clone(), account.address)).unwrap();
+        assert!(is_internal);
+        let external = BlockchainAddress::new("external_address".to_string());
+        let is_internal = core.run(service.is_internal_address(token, external)).unwrap();
+        assert!(!is_internal);
+    }
     // #[test]
     // fn test_account_get_balance() {
     //     let mut core = Core::new().unwrap();
@@ -303,5 +382,4 @@ mod tests {
     //     let balance = balance.unwrap();
     //     assert_eq!(balance.len(), 2);
     // }
-
 }
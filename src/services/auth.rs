@@ -8,17 +8,24 @@ use repos::{DbExecutor, UsersRepo};
 
 pub trait AuthService: Send + Sync + 'static {
     fn authenticate(&self, token: AuthenticationToken) -> ServiceFuture<User>;
+    /// Same as `authenticate`, but also tells if the token belongs to the system user
+    fn authenticate_with_role(&self, token: AuthenticationToken) -> ServiceFuture<(User, Role)>;
 }
 
 #[derive(Clone)]
 pub struct AuthServiceImpl<E: DbExecutor> {
     users_repo: Arc<UsersRepo>,
     db_executor: E,
+    system_user_id: UserId,
 }
 
 impl<E: DbExecutor> AuthServiceImpl<E> {
-    pub fn new(users_repo: Arc<UsersRepo>, db_executor: E) -> Self {
-        AuthServiceImpl { users_repo, db_executor }
+    pub fn new(users_repo: Arc<UsersRepo>, db_executor: E, system_user_id: UserId) -> Self {
+        AuthServiceImpl {
+            users_repo,
+            db_executor,
+            system_user_id,
+        }
     }
 }
 
@@ -36,4 +43,12 @@ impl<E: DbExecutor> AuthService for AuthServiceImpl<E> {
                 })
         }))
     }
+
+    fn authenticate_with_role(&self, token: AuthenticationToken) -> ServiceFuture<(User, Role)> {
+        let system_user_id = self.system_user_id;
+        Box::new(self.authenticate(token).map(move |user| {
+            let role = if user.id == system_user_id { Role::System } else { Role::User };
+            (user, role)
+        }))
+    }
 }
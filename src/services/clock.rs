@@ -0,0 +1,15 @@
+use chrono::{NaiveDateTime, Utc};
+
+/// Source of current time for business logic, so that time-dependent code can be tested
+pub trait Clock: Send + Sync + 'static {
+    fn now(&self) -> NaiveDateTime;
+}
+
+#[derive(Clone, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> NaiveDateTime {
+        Utc::now().naive_utc()
+    }
+}
@@ -3,6 +3,7 @@ use std::fmt::Display;
 
 use failure::{Backtrace, Context, Fail};
 use serde_json;
+use validator::{ValidationError, ValidationErrors};
 
 use client::blockchain_gateway::ErrorKind as BlockchainClientErrorKind;
 use client::exchange::ErrorKind as ExchangeClientErrorKind;
@@ -28,6 +29,18 @@ pub enum ErrorKind {
     Internal,
     #[fail(display = "service error - not found")]
     NotFound,
+    #[fail(display = "service error - balance constraint violated, errors: {}", _0)]
+    Balance(String),
+    #[fail(display = "service error - exchange is temporarily unavailable")]
+    ExchangeUnavailable,
+    #[fail(display = "service error - exchange rate rejected, errors: {}", _0)]
+    RateRejected(String),
+    #[fail(display = "service error - transaction signer is temporarily unavailable")]
+    SignerUnavailable,
+    #[fail(display = "service error - too many requests")]
+    TooManyRequests,
+    #[fail(display = "service error - service is shutting down")]
+    ShuttingDown,
 }
 
 #[allow(dead_code)]
@@ -54,10 +67,24 @@ pub enum ErrorContext {
     NoTransaction,
     #[fail(display = "service error context - not enough funds")]
     NotEnoughFunds,
+    #[fail(display = "service error context - not enough funds on liquidity accounts")]
+    NotEnoughLiquidity,
+    #[fail(display = "service error context - not enough funds in confirmed utxos")]
+    NotEnoughConfirmedFunds,
+    #[fail(display = "service error context - not enough funds on source account, other accounts of the user have them")]
+    NotEnoughFundsInSource,
     #[fail(display = "service error context - invalid currency")]
     InvalidCurrency,
     #[fail(display = "service error context - exchange rate is required, but not found")]
     MissingExchangeRate,
+    #[fail(display = "service error context - exchange rate is not a positive finite number")]
+    InvalidRate,
+    #[fail(display = "service error context - value exceeds max transaction value")]
+    ExceedsMaxValue,
+    #[fail(display = "service error context - exchange is provided for a transfer between accounts of the same currency")]
+    UnexpectedExchange,
+    #[fail(display = "service error context - category is not one of the configured ones")]
+    UnknownCategory,
     #[fail(display = "service error context - invalid utf8 bytes")]
     UTF8,
     #[fail(display = "service error context - failed to parse string to json")]
@@ -82,6 +109,50 @@ pub enum ErrorContext {
     LimitExceeded,
     #[fail(display = "service error context - missing address in transaction")]
     MissingAddressInTx,
+    #[fail(display = "service error context - daily withdrawal limit exceeded")]
+    WithdrawalLimitExceeded,
+    #[fail(display = "service error context - transaction is not scheduled")]
+    NotScheduled,
+    #[fail(display = "service error context - withdrawals of the currency are disabled")]
+    WithdrawalsDisabled,
+    #[fail(display = "service error context - exchange rate is too far from the live rate")]
+    RateOutOfBand,
+    #[fail(display = "service error context - withdrawal requires too many source accounts")]
+    TooManySources,
+    #[fail(display = "service error context - value has more fractional digits than allowed")]
+    AmountPrecision,
+    #[fail(display = "service error context - system account is missing or misconfigured")]
+    SystemAccountMisconfigured,
+    #[fail(display = "service error context - deposit blockchain transaction is already processed")]
+    DepositAlreadyProcessed,
+    #[fail(display = "service error context - deposit blockchain transaction transfers another currency")]
+    DepositCurrencyMismatch,
+    #[fail(display = "service error context - currency is not enabled")]
+    CurrencyDisabled,
+    #[fail(display = "service error context - transaction with this id already exists")]
+    DuplicateTransactionId,
+    #[fail(display = "service error context - fee exceeds the withdrawn value")]
+    FeeExceedsValue,
+    #[fail(display = "service error context - withdrawals to this address are blocked")]
+    AddressBlocked,
+    #[fail(display = "service error context - rate limit exceeded")]
+    RateLimitExceeded,
+    #[fail(display = "service error context - recipient account belongs to another user")]
+    RecipientMismatch,
+    #[fail(display = "service error context - previous withdrawal of the user is too recent")]
+    WithdrawalCooldown,
+    #[fail(display = "service error context - another withdrawal of the user is in progress")]
+    WithdrawalInProgress,
+    #[fail(display = "service error context - no new transactions are accepted during shutdown")]
+    ShuttingDown,
+    #[fail(display = "service error context - withdrawal failed before anything was sent to blockchain")]
+    NothingSentToBlockchain,
+    #[fail(display = "service error context - withdrawal is sent to blockchain, but not recorded")]
+    WithdrawalNotRecorded,
+    #[fail(
+        display = "service error context - withdrawal blockchain transaction is unknown to gateway, it has to be failed by an operator"
+    )]
+    WithdrawalDropped,
 }
 
 derive_error_impls!();
@@ -111,6 +182,10 @@ impl From<KeysClientErrorKind> for ErrorKind {
             KeysClientErrorKind::Internal => ErrorKind::Internal,
             KeysClientErrorKind::Unauthorized => ErrorKind::Internal,
             KeysClientErrorKind::MalformedInput => ErrorKind::Internal,
+            // retrying won't help, the address has no key to sign with
+            KeysClientErrorKind::KeyNotFound => ErrorKind::Internal,
+            KeysClientErrorKind::SigningRejected(s) => ErrorKind::InvalidInput(s),
+            KeysClientErrorKind::Unavailable => ErrorKind::SignerUnavailable,
         }
     }
 }
@@ -141,7 +216,15 @@ impl From<ExchangeClientErrorKind> for ErrorKind {
             ExchangeClientErrorKind::Internal => ErrorKind::Internal,
             ExchangeClientErrorKind::Unauthorized => ErrorKind::Internal,
             ExchangeClientErrorKind::MalformedInput => ErrorKind::Internal,
-            ExchangeClientErrorKind::Validation(s) => ErrorKind::InvalidInput(s),
+            ExchangeClientErrorKind::Validation(s) => ErrorKind::RateRejected(s),
+            ExchangeClientErrorKind::Unavailable => ErrorKind::ExchangeUnavailable,
+            ExchangeClientErrorKind::RateRejected => {
+                let mut errors = ValidationErrors::new();
+                let mut error = ValidationError::new("rate_rejected");
+                error.message = Some("exchange rate is rejected or expired".into());
+                errors.add("exchange_rate", error);
+                ErrorKind::RateRejected(serde_json::to_string(&errors).unwrap_or_default())
+            }
         }
     }
 }
@@ -66,7 +66,7 @@ impl<E: DbExecutor> FeesService for FeesServiceImpl<E> {
         let currency = get_fees.currency;
         let fee_upside = self.fee_upside;
         let service = self.clone();
-        let address = get_fees.account_address.clone();
+        let address = get_fees.account_address.normalized(currency);
         Box::new(
             db_executor
                 .execute(move || {
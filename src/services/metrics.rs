@@ -1,49 +1,98 @@
 use std::collections::HashMap;
 use std::sync::Arc;
 
+use chrono::{Duration, NaiveDateTime};
+
 use client::BlockchainClient;
 use config::Config;
 use models::*;
 use prelude::*;
 use repos::{AccountsRepo, DbExecutor, Isolation, PendingBlockchainTransactionsRepo, StrangeBlockchainTransactionsRepo, TransactionsRepo};
 
+use super::auth::AuthService;
+use super::clock::Clock;
 use super::error::*;
+use super::system::SystemService;
 
 const BLOCKCHAIN_BALANCES_CONCURRENCY: usize = 20;
 
 pub trait MetricsService: Send + Sync + 'static {
     fn get_metrics(&self) -> Box<Future<Item = Metrics, Error = Error> + Send>;
+    /// Compares ledger balances of system accounts with their on-chain balances
+    fn get_reconciliation_report(
+        &self,
+        system_token: AuthenticationToken,
+        currency: Currency,
+    ) -> Box<Future<Item = ReconciliationReport, Error = Error> + Send>;
+    /// Blockchain transactions that were flagged as violating our invariants, newest first
+    fn list_strange_transactions(
+        &self,
+        system_token: AuthenticationToken,
+        offset: i64,
+        limit: i64,
+        currency: Option<Currency>,
+    ) -> Box<Future<Item = Vec<StrangeBlockchainTransactionDB>, Error = Error> + Send>;
+    /// Fees collected in `currency` over `[from, to)`, service and blockchain fees separately
+    fn get_fees_collected(
+        &self,
+        system_token: AuthenticationToken,
+        currency: Currency,
+        from: NaiveDateTime,
+        to: NaiveDateTime,
+    ) -> Box<Future<Item = FeesCollected, Error = Error> + Send>;
+    /// Accounts of any currency at the blockchain address, for support tooling
+    fn get_accounts_by_address(
+        &self,
+        system_token: AuthenticationToken,
+        address: BlockchainAddress,
+    ) -> Box<Future<Item = Vec<Account>, Error = Error> + Send>;
+    /// Transactions pending for longer than `older_than`, oldest first, for alerting on stuck withdrawals
+    fn list_stuck_transactions(
+        &self,
+        system_token: AuthenticationToken,
+        older_than: Duration,
+        limit: i64,
+    ) -> Box<Future<Item = Vec<Transaction>, Error = Error> + Send>;
 }
 
 #[derive(Clone)]
 pub struct MetricsServiceImpl<E: DbExecutor> {
     config: Arc<Config>,
+    auth_service: Arc<AuthService>,
     accounts_repo: Arc<AccountsRepo>,
     transactions_repo: Arc<TransactionsRepo>,
     pending_blockchain_transactions_repo: Arc<PendingBlockchainTransactionsRepo>,
     strange_blockchain_transactions_repo: Arc<StrangeBlockchainTransactionsRepo>,
     blockchain_client: Arc<BlockchainClient>,
+    system_service: Arc<SystemService>,
     db_executor: E,
+    clock: Arc<Clock>,
 }
 
 impl<E: DbExecutor> MetricsServiceImpl<E> {
     pub fn new(
         config: Arc<Config>,
+        auth_service: Arc<AuthService>,
         accounts_repo: Arc<AccountsRepo>,
         transactions_repo: Arc<TransactionsRepo>,
         pending_blockchain_transactions_repo: Arc<PendingBlockchainTransactionsRepo>,
         strange_blockchain_transactions_repo: Arc<StrangeBlockchainTransactionsRepo>,
         db_executor: E,
         blockchain_client: Arc<BlockchainClient>,
+        system_service: Arc<SystemService>,
+        clock: Arc<Clock>,
     ) -> Self {
         MetricsServiceImpl {
             config,
+            auth_service,
             accounts_repo,
             transactions_repo,
             pending_blockchain_transactions_repo,
             strange_blockchain_transactions_repo,
             blockchain_client,
+            system_service,
             db_executor,
+            clock,
         }
     }
 }
@@ -71,7 +120,7 @@ impl<E: DbExecutor> MetricsService for MetricsServiceImpl<E> {
                     let self_3 = self_2.clone();
                     let self_4 = self_2.clone();
                     self_2
-                        .fetch_blockchain_balances(&reduced_balances)
+                        .fetch_blockchain_balances(reduced_balances.keys().cloned().collect())
                         .map(move |blockchain_balances| {
                             self_3.update_blockchain_balances(&mut metrics, &reduced_balances, &blockchain_balances);
                             (metrics, blockchain_balances)
@@ -86,6 +135,95 @@ impl<E: DbExecutor> MetricsService for MetricsServiceImpl<E> {
                 }),
         )
     }
+
+    fn get_reconciliation_report(
+        &self,
+        system_token: AuthenticationToken,
+        currency: Currency,
+    ) -> Box<Future<Item = ReconciliationReport, Error = Error> + Send> {
+        let self_clone = self.clone();
+        let system_service = self.system_service.clone();
+        let system_service_2 = self.system_service.clone();
+        let db_executor = self.db_executor.clone();
+        let db_executor_2 = self.db_executor.clone();
+        Box::new(
+            self.authenticate_system(system_token)
+                .and_then(move |_| db_executor_2.execute(move || system_service.get_system_accounts(currency)))
+                .and_then(move |accounts| {
+                    let keys = accounts.into_iter().map(|account| (account.address, currency)).collect();
+                    self_clone.fetch_blockchain_balances(keys)
+                })
+                .and_then(move |blockchain_balances| {
+                    db_executor.execute_transaction_with_isolation(Isolation::RepeatableRead, move || {
+                        system_service_2.reconcile(currency, &blockchain_balances)
+                    })
+                }),
+        )
+    }
+
+    fn list_strange_transactions(
+        &self,
+        system_token: AuthenticationToken,
+        offset: i64,
+        limit: i64,
+        currency: Option<Currency>,
+    ) -> Box<Future<Item = Vec<StrangeBlockchainTransactionDB>, Error = Error> + Send> {
+        let strange_blockchain_transactions_repo = self.strange_blockchain_transactions_repo.clone();
+        let db_executor = self.db_executor.clone();
+        Box::new(self.authenticate_system(system_token).and_then(move |_| {
+            db_executor.execute(move || {
+                strange_blockchain_transactions_repo
+                    .list(offset, limit, currency)
+                    .map_err(ectx!(ErrorKind::Internal => offset, limit, currency))
+            })
+        }))
+    }
+
+    fn get_fees_collected(
+        &self,
+        system_token: AuthenticationToken,
+        currency: Currency,
+        from: NaiveDateTime,
+        to: NaiveDateTime,
+    ) -> Box<Future<Item = FeesCollected, Error = Error> + Send> {
+        let system_service = self.system_service.clone();
+        let db_executor = self.db_executor.clone();
+        Box::new(self.authenticate_system(system_token).and_then(move |_| {
+            db_executor
+                .execute_transaction_with_isolation(Isolation::RepeatableRead, move || system_service.fees_collected(currency, from, to))
+        }))
+    }
+
+    fn get_accounts_by_address(
+        &self,
+        system_token: AuthenticationToken,
+        address: BlockchainAddress,
+    ) -> Box<Future<Item = Vec<Account>, Error = Error> + Send> {
+        let system_service = self.system_service.clone();
+        let db_executor = self.db_executor.clone();
+        Box::new(
+            self.authenticate_system(system_token)
+                .and_then(move |_| db_executor.execute(move || system_service.find_accounts_by_address(address))),
+        )
+    }
+
+    fn list_stuck_transactions(
+        &self,
+        system_token: AuthenticationToken,
+        older_than: Duration,
+        limit: i64,
+    ) -> Box<Future<Item = Vec<Transaction>, Error = Error> + Send> {
+        let transactions_repo = self.transactions_repo.clone();
+        let db_executor = self.db_executor.clone();
+        let now = self.clock.now();
+        Box::new(self.authenticate_system(system_token).and_then(move |_| {
+            db_executor.execute(move || {
+                transactions_repo
+                    .list_pending_older_than(now, older_than, limit)
+                    .map_err(ectx!(ErrorKind::Internal => now, older_than, limit))
+            })
+        }))
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -95,6 +233,17 @@ enum SystemAccountKind {
 }
 
 impl<E: DbExecutor> MetricsServiceImpl<E> {
+    /// System reports expose data of every user, so they are available to the system user only
+    fn authenticate_system(&self, system_token: AuthenticationToken) -> impl Future<Item = (), Error = Error> + Send {
+        let system_user_id = self.config.system.system_user_id;
+        self.auth_service.authenticate(system_token).and_then(move |user| {
+            if user.id != system_user_id {
+                return Err(ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => user.id));
+            }
+            Ok(())
+        })
+    }
+
     fn update_counts(&self, metrics: &mut Metrics) -> Result<(), Error> {
         let counts = self.accounts_repo.count_by_user().map_err(ectx!(try ErrorKind::Internal))?;
         let total = counts.iter().map(|(_, v)| v).sum();
@@ -183,10 +332,9 @@ impl<E: DbExecutor> MetricsServiceImpl<E> {
 
     fn fetch_blockchain_balances(
         &self,
-        balances: &HashMap<(BlockchainAddress, Currency), Amount>,
+        keys: Vec<(BlockchainAddress, Currency)>,
     ) -> impl Future<Item = HashMap<(BlockchainAddress, Currency), Amount>, Error = Error> {
         let self_ = self.clone();
-        let keys: Vec<_> = balances.keys().cloned().collect();
         let stream = futures::stream::iter_ok(keys)
             .map(move |(address, currency)| {
                 let address_ = address.clone();
@@ -253,14 +401,27 @@ impl<E: DbExecutor> MetricsServiceImpl<E> {
         metrics: &mut Metrics,
         balances: &HashMap<AccountId, (Amount, Amount)>,
     ) -> Result<f64, Error> {
-        let account_id = match (kind, currency) {
-            (SystemAccountKind::Fee, Currency::Btc) => self.config.system.btc_fees_account_id,
-            (SystemAccountKind::Fee, Currency::Eth) => self.config.system.eth_fees_account_id,
-            (SystemAccountKind::Fee, Currency::Stq) => self.config.system.stq_fees_account_id,
-            (SystemAccountKind::Liquidity, Currency::Btc) => self.config.system.btc_liquidity_account_id,
-            (SystemAccountKind::Liquidity, Currency::Eth) => self.config.system.eth_liquidity_account_id,
-            (SystemAccountKind::Liquidity, Currency::Stq) => self.config.system.stq_liquidity_account_id,
+        let account_ids = match (kind, currency) {
+            (SystemAccountKind::Fee, Currency::Btc) => vec![self.config.system.btc_fees_account_id],
+            (SystemAccountKind::Fee, Currency::Eth) => vec![self.config.system.eth_fees_account_id],
+            (SystemAccountKind::Fee, Currency::Stq) => vec![self.config.system.stq_fees_account_id],
+            // liquidity of the currency is the whole pool
+            (SystemAccountKind::Liquidity, currency) => self.config.system.liquidity_account_ids(currency),
         };
+        let mut total = 0f64;
+        for account_id in account_ids {
+            total += self.extract_account_balance(account_id, currency, metrics, balances)?;
+        }
+        Ok(total)
+    }
+
+    fn extract_account_balance(
+        &self,
+        account_id: AccountId,
+        currency: Currency,
+        metrics: &mut Metrics,
+        balances: &HashMap<AccountId, (Amount, Amount)>,
+    ) -> Result<f64, Error> {
         let balance_pair = balances.get(&account_id).cloned().unwrap_or((Amount::new(0), Amount::new(0)));
         match balance_pair.0.checked_sub(balance_pair.1) {
             Some(balance) => Ok(balance.to_super_unit(currency)),
@@ -319,3 +480,132 @@ impl<E: DbExecutor> MetricsServiceImpl<E> {
         Ok(res)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use client::*;
+    use repos::*;
+    use services::*;
+    use tokio_core::reactor::Core;
+
+    fn create_metrics_service(
+        accounts_repo: Arc<AccountsRepoMock>,
+        transactions_repo: Arc<TransactionsRepoMock>,
+        blockchain_client: Arc<BlockchainClientMock>,
+    ) -> MetricsServiceImpl<DbExecutorMock> {
+        let config = Arc::new(Config::new().unwrap());
+        let auth_service = Arc::new(
+            AuthServiceMock::new(vec![(user_token(), UserId::generate())]).with_system_user(system_token(), config.system.system_user_id),
+        );
+        let pending_blockchain_transactions_repo = Arc::new(PendingBlockchainTransactionsRepoMock::default());
+        let system_service = Arc::new(SystemServiceImpl::new(
+            accounts_repo.clone(),
+            transactions_repo.clone(),
+            pending_blockchain_transactions_repo.clone(),
+            config.clone(),
+            Arc::new(MockClock::default()),
+        ));
+        MetricsServiceImpl::new(
+            config,
+            auth_service,
+            accounts_repo,
+            transactions_repo,
+            pending_blockchain_transactions_repo,
+            Arc::new(StrangeBlockchainTransactionsRepoMock::default()),
+            DbExecutorMock::default(),
+            blockchain_client,
+            system_service,
+            Arc::new(MockClock::default()),
+        )
+    }
+
+    fn system_token() -> AuthenticationToken {
+        AuthenticationToken::new("system_token".to_string())
+    }
+
+    fn user_token() -> AuthenticationToken {
+        AuthenticationToken::new("user_token".to_string())
+    }
+
+    #[test]
+    fn test_get_accounts_by_address_system_only() {
+        let mut core = Core::new().unwrap();
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let account = accounts_repo.create(NewAccount::default()).unwrap();
+        let service = create_metrics_service(
+            accounts_repo,
+            Arc::new(TransactionsRepoMock::default()),
+            Arc::new(BlockchainClientMock::default()),
+        );
+
+        let res = core.run(service.get_accounts_by_address(user_token(), account.address.clone()));
+        match res.unwrap_err().kind() {
+            ErrorKind::Unauthorized => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+        let accounts = core.run(service.get_accounts_by_address(system_token(), account.address)).unwrap();
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].id, account.id);
+    }
+
+    #[test]
+    fn test_get_reconciliation_report() {
+        let mut core = Core::new().unwrap();
+        let config = Config::new().unwrap();
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let mut system_accounts = vec![];
+        for id in vec![
+            config.system.btc_transfer_account_id,
+            config.system.btc_liquidity_account_id,
+            config.system.btc_fees_account_id,
+        ] {
+            let account = accounts_repo
+                .create(NewAccount {
+                    id,
+                    currency: Currency::Btc,
+                    user_id: config.system.system_user_id,
+                    ..Default::default()
+                })
+                .unwrap();
+            let mut deposit = NewTransaction::default();
+            deposit.cr_account_id = account.id;
+            deposit.currency = Currency::Btc;
+            deposit.value = Amount::new(100);
+            deposit.status = TransactionStatus::Done;
+            transactions_repo.create(deposit).unwrap();
+            system_accounts.push(account);
+        }
+        let liquidity_address = system_accounts[1].address.clone();
+        let fees_address = system_accounts[2].address.clone();
+        // transfer account is missing on-chain funds, fees account holds more than the ledger says
+        let blockchain_client = Arc::new(
+            BlockchainClientMock::default()
+                .with_balance(liquidity_address.clone(), Currency::Btc, Amount::new(100))
+                .with_balance(fees_address.clone(), Currency::Btc, Amount::new(150)),
+        );
+        let service = create_metrics_service(accounts_repo, transactions_repo, blockchain_client);
+
+        let res = core.run(service.get_reconciliation_report(user_token(), Currency::Btc));
+        match res.unwrap_err().kind() {
+            ErrorKind::Unauthorized => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+        let report = core.run(service.get_reconciliation_report(system_token(), Currency::Btc)).unwrap();
+        assert_eq!(report.currency, Currency::Btc);
+        assert_eq!(report.balances.len(), 3);
+        assert_eq!(report.discrepancies.len(), 2);
+        let transfer = report
+            .discrepancies
+            .iter()
+            .find(|balance| balance.address == system_accounts[0].address)
+            .unwrap();
+        assert_eq!(transfer.ledger_value, Amount::new(100));
+        assert_eq!(transfer.blockchain_value, Amount::new(0));
+        let fees = report.discrepancies.iter().find(|balance| balance.address == fees_address).unwrap();
+        assert_eq!(fees.ledger_value, Amount::new(100));
+        assert_eq!(fees.blockchain_value, Amount::new(150));
+        assert!(report.discrepancies.iter().all(|balance| balance.address != liquidity_address));
+    }
+}
@@ -1,7 +1,10 @@
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 
+use chrono::{Duration, NaiveDateTime, Utc};
+
 use super::auth::AuthService;
+use super::clock::Clock;
 use super::error::*;
 use super::system::SystemService;
 use super::ServiceFuture;
@@ -10,6 +13,7 @@ use prelude::*;
 
 pub struct AuthServiceMock {
     users: HashMap<AuthenticationToken, User>,
+    system_user_id: Option<UserId>,
 }
 
 impl AuthServiceMock {
@@ -21,7 +25,19 @@ impl AuthServiceMock {
             user.id = id;
             users.insert(token, user);
         }
-        AuthServiceMock { users }
+        AuthServiceMock {
+            users,
+            system_user_id: None,
+        }
+    }
+
+    pub fn with_system_user(mut self, token: AuthenticationToken, system_user_id: UserId) -> Self {
+        let mut user = User::default();
+        user.authentication_token = token.clone();
+        user.id = system_user_id;
+        self.users.insert(token, user);
+        self.system_user_id = Some(system_user_id);
+        self
     }
 }
 
@@ -35,6 +51,52 @@ impl AuthService for AuthServiceMock {
                 .into_future(),
         )
     }
+
+    fn authenticate_with_role(&self, token: AuthenticationToken) -> ServiceFuture<(User, Role)> {
+        let system_user_id = self.system_user_id;
+        Box::new(self.authenticate(token).map(move |user| {
+            let role = if Some(user.id) == system_user_id {
+                Role::System
+            } else {
+                Role::User
+            };
+            (user, role)
+        }))
+    }
+}
+
+#[derive(Clone)]
+pub struct MockClock {
+    now: Arc<Mutex<NaiveDateTime>>,
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        MockClock::new(Utc::now().naive_utc())
+    }
+}
+
+impl MockClock {
+    pub fn new(now: NaiveDateTime) -> Self {
+        Self {
+            now: Arc::new(Mutex::new(now)),
+        }
+    }
+
+    pub fn set(&self, now: NaiveDateTime) {
+        *self.now.lock().unwrap() = now;
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        let mut now = self.now.lock().unwrap();
+        *now = *now + duration;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> NaiveDateTime {
+        *self.now.lock().unwrap()
+    }
 }
 
 #[derive(Clone)]
@@ -95,6 +157,14 @@ impl SystemService for SystemServiceMock {
         Ok(acc.clone())
     }
 
+    fn get_system_liquidity_accounts(&self, currency: Currency) -> Result<Vec<Account>, Error> {
+        Ok(vec![self.get_system_liquidity_account(currency)?])
+    }
+
+    fn select_system_liquidity_account(&self, currency: Currency, _value: Amount, _pays_out: bool) -> Result<Account, Error> {
+        self.get_system_liquidity_account(currency)
+    }
+
     fn get_system_fees_account(&self, currency: Currency) -> Result<Account, Error> {
         let data = self.data.lock().unwrap();
         let acc_id = match currency {
@@ -116,4 +186,46 @@ impl SystemService for SystemServiceMock {
         let acc = data.get(acc_id).unwrap();
         Ok(acc.clone())
     }
+    fn process_due_transactions(&self) -> Result<Vec<Transaction>, Error> {
+        Ok(vec![])
+    }
+    fn get_system_accounts(&self, currency: Currency) -> Result<Vec<Account>, Error> {
+        Ok(vec![
+            self.get_system_transfer_account(currency)?,
+            self.get_system_liquidity_account(currency)?,
+            self.get_system_fees_account(currency)?,
+        ])
+    }
+    fn reconcile(
+        &self,
+        _currency: Currency,
+        _blockchain_balances: &HashMap<(BlockchainAddress, Currency), Amount>,
+    ) -> Result<ReconciliationReport, Error> {
+        unimplemented!()
+    }
+    fn recompute_account_balance(&self, _account_id: AccountId) -> Result<Amount, Error> {
+        unimplemented!()
+    }
+    fn recompute_all_balances(&self, _currency: Currency, _batch_size: i64) -> Box<Stream<Item = RecomputeProgress, Error = Error> + Send> {
+        unimplemented!()
+    }
+    fn fees_collected(&self, _currency: Currency, _from: NaiveDateTime, _to: NaiveDateTime) -> Result<FeesCollected, Error> {
+        unimplemented!()
+    }
+    fn find_accounts_by_address(&self, _address: BlockchainAddress) -> Result<Vec<Account>, Error> {
+        unimplemented!()
+    }
+    fn find_orphaned_withdrawals(&self, _limit: i64) -> Result<Vec<Transaction>, Error> {
+        unimplemented!()
+    }
+    fn recover_orphaned_pending(
+        &self,
+        _hash: BlockchainTransactionId,
+        _blockchain_tx: BlockchainTransaction,
+    ) -> Result<PendingBlockchainTransactionDB, Error> {
+        unimplemented!()
+    }
+    fn fail_dropped_withdrawal(&self, _hash: BlockchainTransactionId) -> Result<Vec<Transaction>, Error> {
+        unimplemented!()
+    }
 }
@@ -1,5 +1,6 @@
 mod accounts;
 mod auth;
+mod clock;
 mod error;
 mod exchange;
 mod fee;
@@ -7,12 +8,15 @@ mod metrics;
 #[cfg(test)]
 mod mocks;
 mod rabbit;
+mod rate_limit;
+mod shutdown;
 mod system;
 mod transactions;
 mod users;
 
 pub use self::accounts::*;
 pub use self::auth::*;
+pub use self::clock::*;
 pub use self::error::*;
 pub use self::exchange::*;
 pub use self::fee::*;
@@ -20,6 +24,9 @@ pub use self::metrics::*;
 #[cfg(test)]
 pub use self::mocks::*;
 pub use self::rabbit::*;
+pub use self::rate_limit::*;
+pub use self::shutdown::*;
+pub use self::system::*;
 pub use self::transactions::*;
 pub use self::users::*;
 
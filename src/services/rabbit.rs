@@ -4,8 +4,10 @@ use std::time::{Duration, Instant};
 use chrono::{Duration as ChronoDuration, Utc};
 use futures::future::{self, Either};
 
+use super::clock::Clock;
 use super::error::*;
 use super::system::{SystemService, SystemServiceImpl};
+use super::transactions::bitcoin_tx_size;
 use super::transactions::converter::{ConverterService, ConverterServiceImpl};
 use client::{BlockchainClient, KeysClient};
 use config::Config;
@@ -42,6 +44,7 @@ pub struct BlockchainFetcher<E: DbExecutor> {
     keys_client: Arc<KeysClient>,
     db_executor: E,
     publisher: Arc<dyn TransactionPublisher>,
+    clock: Arc<Clock>,
 }
 
 impl<E: DbExecutor> BlockchainFetcher<E> {
@@ -58,9 +61,17 @@ impl<E: DbExecutor> BlockchainFetcher<E> {
         keys_client: Arc<KeysClient>,
         db_executor: E,
         publisher: Arc<dyn TransactionPublisher>,
+        clock: Arc<Clock>,
     ) -> Self {
-        let system_service = Arc::new(SystemServiceImpl::new(accounts_repo.clone(), config.clone()));
+        let system_service = Arc::new(SystemServiceImpl::new(
+            accounts_repo.clone(),
+            transactions_repo.clone(),
+            pending_blockchain_transactions_repo.clone(),
+            config.clone(),
+            clock.clone(),
+        ));
         let converter_service = Arc::new(ConverterServiceImpl::new(
+            config.clone(),
             accounts_repo.clone(),
             pending_blockchain_transactions_repo.clone(),
             blockchain_transactions_repo.clone(),
@@ -81,6 +92,7 @@ impl<E: DbExecutor> BlockchainFetcher<E> {
             keys_client,
             db_executor,
             publisher,
+            clock,
         }
     }
 }
@@ -106,6 +118,10 @@ pub enum InvariantViolation {
     WithdrawalValue,
     #[fail(display = "blockchain transaction invariant violation - deposit arrived from internal address")]
     DepositAddressInternal,
+    #[fail(display = "blockchain transaction invariant violation - deposit currency doesn't match currency of the receiving address")]
+    DepositCurrencyMismatch,
+    #[fail(display = "blockchain transaction invariant violation - deposit transaction was reverted, nothing was transferred")]
+    DepositReverted,
 }
 
 impl<E: DbExecutor> BlockchainFetcher<E> {
@@ -143,6 +159,87 @@ impl<E: DbExecutor> BlockchainFetcher<E> {
             })
     }
 
+    /// Checks up to `limit` pending withdrawals in blockchain, so that they are resolved even if
+    /// gateway notifications about them were lost. Returns one leg per resolved blockchain transaction.
+    /// Does nothing while the resolver is paused by the system user
+    pub fn resolve_pending_transactions(&self, limit: i64) -> impl Future<Item = Vec<Transaction>, Error = Error> + Send {
+        let transactions_repo = self.transactions_repo.clone();
+        let key_values_repo = self.key_values_repo.clone();
+        let self_clone = self.clone();
+        self.db_executor
+            .execute(move || -> Result<Vec<Transaction>, Error> {
+                // e.g. during chain incidents gateway might not know transactions that are fine, so they mustn't be failed as dropped
+                if !key_values_repo.get_resolver_enabled().map_err(ectx!(try convert))? {
+                    info!("Pending transactions resolver is paused");
+                    return Ok(vec![]);
+                }
+                transactions_repo.get_pending_withdrawals(limit).map_err(ectx!(convert => limit))
+            })
+            .and_then(move |txs| {
+                // legs of a batch withdrawal share one blockchain transaction
+                let mut pending: Vec<Transaction> = vec![];
+                for tx in txs {
+                    if !pending.iter().any(|pending_tx| pending_tx.blockchain_tx_id == tx.blockchain_tx_id) {
+                        pending.push(tx);
+                    }
+                }
+                futures::stream::iter_ok::<_, Error>(pending)
+                    .and_then(move |tx| {
+                        // one failed lookup shouldn't block the rest
+                        self_clone.resolve_pending_transaction(tx).then(|res| match res {
+                            Ok(resolved) => Ok(resolved),
+                            Err(e) => {
+                                log_and_capture_error(e);
+                                Ok(vec![])
+                            }
+                        })
+                    })
+                    .concat2()
+            })
+    }
+
+    fn resolve_pending_transaction(&self, tx: Transaction) -> Box<Future<Item = Vec<Transaction>, Error = Error> + Send> {
+        let hash = match tx.blockchain_tx_id.clone() {
+            Some(hash) => hash,
+            None => return Box::new(future::ok(vec![])),
+        };
+        // erc20 transfers are stored with log index suffix, i.e. `<hash>:0`, but gateway knows them by tx hash
+        let gateway_hash = BlockchainTransactionId::new(hash.inner().split(':').next().unwrap_or_default().to_string());
+        let currency = tx.currency;
+        let dropped_timeout = ChronoDuration::seconds(self.config.system.dropped_transaction_timeout_secs as i64);
+        let is_overdue = self.clock.now() - tx.created_at >= dropped_timeout;
+        let self_clone = self.clone();
+        let db_executor_ = self.db_executor.clone();
+        let transactions_repo_ = self.transactions_repo.clone();
+        let hash_clone = hash.clone();
+        Box::new(
+            self.blockchain_client
+                .get_transaction(gateway_hash.clone(), currency)
+                .map_err(ectx!(convert => gateway_hash, currency))
+                .and_then(move |blockchain_tx| match blockchain_tx {
+                    Some(mut blockchain_tx) => {
+                        // processed the same way as a notification from gateway, i.e. waits for enough confirmations
+                        blockchain_tx.hash = hash;
+                        Either::A(self_clone.handle_transaction(&blockchain_tx).map(|_| ()))
+                    }
+                    // signed transaction unknown to gateway might still be rebroadcast and mined until its nonce or inputs
+                    // are spent by another one, so failing it here could release funds that are still sent.
+                    // It is reported instead, an operator fails it with `fail_dropped_withdrawal` command
+                    None if is_overdue => {
+                        log_and_capture_error(ectx!(err ErrorContext::WithdrawalDropped, ErrorKind::Internal => hash, currency));
+                        Either::B(future::ok(()))
+                    }
+                    None => Either::B(future::ok(())),
+                })
+                .and_then(move |_| {
+                    db_executor_.execute(move || -> Result<Vec<Transaction>, Error> {
+                        let tx = transactions_repo_.get_by_blockchain_tx(hash_clone)?;
+                        Ok(tx.into_iter().filter(|tx| tx.status != TransactionStatus::Pending).collect())
+                    })
+                }),
+        )
+    }
+
     fn handle_transaction(&self, blockchain_tx: &BlockchainTransaction) -> impl Future<Item = Vec<Transaction>, Error = Error> + Send {
         let db_executor = self.db_executor.clone();
         let self_clone = self.clone();
@@ -151,16 +248,18 @@ impl<E: DbExecutor> BlockchainFetcher<E> {
         let blockchain_transactions_repo = self.blockchain_transactions_repo.clone();
         let accounts_repo = self.accounts_repo.clone();
         let pending_blockchain_transactions_repo = self.pending_blockchain_transactions_repo.clone();
-        let seen_hashes_repo = self.seen_hashes_repo.clone();
         let system_service = self.system_service.clone();
-        let blockchain_tx = blockchain_tx.clone();
+        // accounts store normalized addresses, while gateway might report e.g. checksummed ethereum ones
+        let blockchain_tx = blockchain_tx.with_normalized_addresses();
         db_executor
             .execute_transaction_with_isolation(Isolation::Serializable, move || {
                 let normalized_tx = blockchain_tx
                     .normalized()
                     .ok_or(ectx!(try err ErrorContext::BalanceOverflow, ErrorKind::Internal => blockchain_tx))?;
+                // confirmations keep growing after the transaction is stored, so they are refreshed on every notification
+                blockchain_transactions_repo.update_confirmations(blockchain_tx.hash.clone(), blockchain_tx.confirmations as i32)?;
                 // already processed this transaction - skipping
-                if let Some(_) = seen_hashes_repo.get(normalized_tx.hash.clone(), normalized_tx.currency)? {
+                if self_clone.was_seen(&normalized_tx)? {
                     return Ok((vec![], vec![]));
                 }
 
@@ -188,11 +287,7 @@ impl<E: DbExecutor> BlockchainFetcher<E> {
                                 // We don't need the notion of approved credit account anymore, as all debit accounts get approved
                                 blockchain_transactions_repo.create(blockchain_tx.clone().into())?;
                                 pending_blockchain_transactions_repo.delete(blockchain_tx.hash.clone())?;
-                                seen_hashes_repo.create(NewSeenHashes {
-                                    hash: blockchain_tx.hash.clone(),
-                                    block_number: blockchain_tx.block_number as i64,
-                                    currency: blockchain_tx.currency,
-                                })?;
+                                self_clone.record_seen_hash(&blockchain_tx)?;
                             }
                         }
                         // don't need to collect fees, etc. - see the comment in that send_erc20_approval
@@ -206,7 +301,8 @@ impl<E: DbExecutor> BlockchainFetcher<E> {
                         .value()
                         .ok_or(ectx!(try err ErrorContext::BalanceOverflow, ErrorKind::Internal => tx.clone()))?;
                     if required_confirmations(normalized_tx.currency, total_tx_value) > normalized_tx.confirmations as u64 {
-                        // skipping tx, waiting for more confirms
+                        // skipping tx, waiting for more confirms, but keeping it to show confirmations of the withdrawal
+                        blockchain_transactions_repo.upsert(blockchain_tx.clone().into())?;
                         return Ok((vec![], vec![]));
                     }
                     if let Some(violation) = self_clone.verify_withdrawal_tx(&tx, &normalized_tx)? {
@@ -231,9 +327,23 @@ impl<E: DbExecutor> BlockchainFetcher<E> {
                             .ok_or(ectx!(try err ErrorContext::NoAccount, ErrorKind::Internal => blockchain_tx, fees_currency))?,
                     };
                     let fees_account_cr = system_service.get_system_fees_account(fees_currency)?;
-                    blockchain_transactions_repo.create(blockchain_tx.clone().into())?;
+                    // kept next to the fee actually paid, so that the variance is reported without querying the blockchain txs
+                    let fee_price = transactions_repo
+                        .get_by_gid(tx.gid)?
+                        .into_iter()
+                        .find(|leg| leg.kind == TransactionKind::Fee)
+                        .and_then(|leg| leg.fee_price);
+                    let estimated_fee = fee_price.map(|fee_price| self_clone.estimated_network_fee(&blockchain_tx, fee_price));
+                    // might be already stored while waiting for confirmations
+                    blockchain_transactions_repo.upsert(blockchain_tx.clone().into())?;
                     pending_blockchain_transactions_repo.delete(blockchain_tx.hash.clone())?;
-                    transactions_repo.update_status(blockchain_tx.hash.clone(), TransactionStatus::Done)?;
+                    if blockchain_tx.reverted {
+                        // nothing is transferred, so withdrawn value is back on balance, but the network fee is still paid
+                        warn!("Withdrawal blockchain transaction {} was reverted", blockchain_tx.hash);
+                        transactions_repo.update_status_failed(blockchain_tx.hash.clone(), REVERTED_FAILURE_REASON.to_string())?;
+                    } else {
+                        transactions_repo.update_status(blockchain_tx.hash.clone(), TransactionStatus::Done)?;
+                    }
                     let fee_tx = NewTransaction {
                         id: TransactionId::generate(),
                         gid: tx.gid,
@@ -248,24 +358,31 @@ impl<E: DbExecutor> BlockchainFetcher<E> {
                         group_kind: tx.group_kind,
                         related_tx: None,
                         meta: None,
+                        hold_until: None,
+                        fee_price,
+                        gross_fee: estimated_fee,
+                        user_note: None,
+                        category: None,
                     };
                     transactions_repo.create(fee_tx)?;
-                    seen_hashes_repo.create(NewSeenHashes {
-                        hash: blockchain_tx.hash.clone(),
-                        block_number: blockchain_tx.block_number as i64,
-                        currency: blockchain_tx.currency,
-                    })?;
+                    self_clone.record_seen_hash(&blockchain_tx)?;
                     return Ok((vec![], vec![]));
                 };
 
                 let to_addresses: Vec<_> = normalized_tx.to.iter().map(|entry| entry.address.clone()).collect();
                 let matched_dr_accounts = accounts_repo.get_by_addresses(&to_addresses, blockchain_tx.currency, AccountKind::Dr)?;
+                if let Some(violation) = self_clone.verify_deposit_currency(&normalized_tx, &matched_dr_accounts)? {
+                    // e.g. stq sent to an address that we only have an eth account for - crediting it would mint money
+                    self_clone.handle_violation(violation, &blockchain_tx)?;
+                    return Ok((vec![], vec![]));
+                }
                 if matched_dr_accounts.len() == 0 {
-                    seen_hashes_repo.create(NewSeenHashes {
-                        hash: blockchain_tx.hash.clone(),
-                        block_number: blockchain_tx.block_number as i64,
-                        currency: blockchain_tx.currency,
-                    })?;
+                    self_clone.record_seen_hash(&blockchain_tx)?;
+                    return Ok((vec![], vec![]));
+                }
+                if blockchain_tx.reverted {
+                    // failed eth / stq transaction still lists its recipients, but they received nothing
+                    self_clone.handle_violation(InvariantViolation::DepositReverted, &blockchain_tx)?;
                     return Ok((vec![], vec![]));
                 }
 
@@ -275,20 +392,23 @@ impl<E: DbExecutor> BlockchainFetcher<E> {
                 }
 
                 let mut transactions_out = vec![];
-                let mut need_approve = vec![];
+                let mut need_approve: Vec<Account> = vec![];
 
-                let mut idx = 0;
-                for to_dr_account in matched_dr_accounts {
+                // one blockchain tx can pay to several of our addresses (or to the same one several times),
+                // so every output is credited on its own and exactly once
+                for (output_index, to_entry) in blockchain_tx.to.iter().enumerate() {
+                    let to_dr_account = match matched_dr_accounts.iter().find(|account| account.address == to_entry.address) {
+                        Some(account) => account.clone(),
+                        None => continue,
+                    };
+                    if self_clone.was_output_seen(&blockchain_tx, output_index)? {
+                        continue;
+                    }
                     let Account {
                         address: to_dr_address,
                         currency: to_dr_currency,
                         ..
                     } = to_dr_account.clone();
-                    let to_entry = blockchain_tx
-                        .to
-                        .iter()
-                        .find(|entry| entry.address == to_dr_address.clone())
-                        .ok_or(ectx!(try err ErrorContext::MissingAddressInTx, ErrorKind::Internal => to_dr_address.clone()))?;
                     let to_cr_account = accounts_repo
                         .get_by_address(to_dr_address.clone(), to_dr_currency.clone(), AccountKind::Cr)?
                         .ok_or(
@@ -309,26 +429,27 @@ impl<E: DbExecutor> BlockchainFetcher<E> {
                         group_kind: TransactionGroupKind::Deposit,
                         related_tx: None,
                         meta: None,
+                        hold_until: None,
+                        fee_price: None,
+                        gross_fee: None,
+                        user_note: None,
+                        category: None,
                     };
                     let dr_transaction = transactions_repo.create(new_tx)?;
                     transactions_out.push(dr_transaction);
-                    // don't need to create these more than one time, or conflict will be o/w
-                    if idx == 0 {
-                        blockchain_transactions_repo.create(blockchain_tx.clone().into())?;
-                        seen_hashes_repo.create(NewSeenHashes {
-                            hash: blockchain_tx.hash.clone(),
-                            block_number: blockchain_tx.block_number as i64,
-                            currency: blockchain_tx.currency,
-                        })?;
-                    };
+                    self_clone.record_seen_output(&blockchain_tx, output_index)?;
                     // approve account if balance has passed threshold
-                    if (to_dr_account.currency == Currency::Stq) && !to_dr_account.erc20_approved {
+                    let approve_pending = need_approve.iter().any(|account| account.id == to_dr_account.id);
+                    if (to_dr_account.currency == Currency::Stq) && !to_dr_account.erc20_approved && !approve_pending {
                         let balance = transactions_repo.get_accounts_balance(to_dr_account.user_id, &[to_dr_account.clone()])?[0].balance;
                         if balance >= Amount::new(STQ_BALANCE_THRESHOLD) {
                             need_approve.push(to_dr_account)
                         }
                     }
-                    idx += 1;
+                }
+                // don't need to create it more than one time, or conflict will be o/w
+                if !transactions_out.is_empty() {
+                    blockchain_transactions_repo.create(blockchain_tx.clone().into())?;
                 }
                 Ok((transactions_out, need_approve))
             })
@@ -345,6 +466,45 @@ impl<E: DbExecutor> BlockchainFetcher<E> {
             })
     }
 
+    // Network fee of the blockchain tx at the fee price it was sent with, to compare with the fee it paid
+    fn estimated_network_fee(&self, blockchain_tx: &BlockchainTransaction, fee_price: f64) -> Amount {
+        let base = match blockchain_tx.currency {
+            Currency::Btc => bitcoin_tx_size(blockchain_tx.from.len(), blockchain_tx.to.len()),
+            Currency::Eth => self.config.fees_options.eth_gas_limit as u64,
+            Currency::Stq => self.config.fees_options.stq_gas_limit as u64,
+        };
+        Amount::new((fee_price * base as f64).ceil() as u128)
+    }
+
+    // Must be called inside the same db transaction that credits / debits accounts,
+    // so that the hash is recorded only if the transaction is processed
+    fn record_seen_hash(&self, blockchain_tx: &BlockchainTransaction) -> Result<(), Error> {
+        self.seen_hashes_repo.create(blockchain_tx.clone().into())?;
+        Ok(())
+    }
+
+    // Deposits are recorded per output instead of `record_seen_hash`, with the same db transaction requirement
+    fn record_seen_output(&self, blockchain_tx: &BlockchainTransaction, output_index: usize) -> Result<(), Error> {
+        let seen_output = NewSeenHashes {
+            output_index: output_index as i32,
+            ..blockchain_tx.clone().into()
+        };
+        self.seen_hashes_repo.create(seen_output)?;
+        Ok(())
+    }
+
+    fn was_output_seen(&self, blockchain_tx: &BlockchainTransaction, output_index: usize) -> Result<bool, Error> {
+        let seen = self
+            .seen_hashes_repo
+            .get_output(blockchain_tx.hash.clone(), blockchain_tx.currency, output_index as i32)?;
+        Ok(seen.is_some())
+    }
+
+    fn was_seen(&self, blockchain_tx: &BlockchainTransaction) -> Result<bool, Error> {
+        let seen = self.seen_hashes_repo.get(blockchain_tx.hash.clone(), blockchain_tx.currency)?;
+        Ok(seen.is_some())
+    }
+
     fn handle_violation(&self, violation: InvariantViolation, blockchain_tx: &BlockchainTransaction) -> Result<(), Error> {
         log_error(&ectx!(try err violation => blockchain_tx));
 
@@ -352,11 +512,7 @@ impl<E: DbExecutor> BlockchainFetcher<E> {
         let new_strange_tx = (blockchain_tx.clone(), message).into();
         self.strange_blockchain_transactions_repo.create(new_strange_tx)?;
 
-        self.seen_hashes_repo.create(NewSeenHashes {
-            hash: blockchain_tx.hash.clone(),
-            block_number: blockchain_tx.block_number as i64,
-            currency: blockchain_tx.currency,
-        })?;
+        self.record_seen_hash(blockchain_tx)?;
         Ok(())
     }
 
@@ -370,6 +526,27 @@ impl<E: DbExecutor> BlockchainFetcher<E> {
         Ok(None)
     }
 
+    // Outputs to addresses that we have accounts for only in other currencies
+    fn verify_deposit_currency(
+        &self,
+        blockchain_tx: &BlockchainTransaction,
+        matched_dr_accounts: &[Account],
+    ) -> Result<Option<InvariantViolation>, Error> {
+        for to_entry in blockchain_tx.to.iter() {
+            if matched_dr_accounts.iter().any(|account| account.address == to_entry.address) {
+                continue;
+            }
+            let other_accounts = self.accounts_repo.filter_by_address(to_entry.address.clone())?;
+            if other_accounts
+                .iter()
+                .any(|account| account.kind == AccountKind::Dr && account.currency != blockchain_tx.currency)
+            {
+                return Ok(Some(InvariantViolation::DepositCurrencyMismatch));
+            }
+        }
+        Ok(None)
+    }
+
     // Returns error if there's an error in connecting to db, etc. (in this case it makes sense to nack and retry after)
     // Returns Ok(None) if the transaction is ok
     // Returns Ok(Some(violation)) if some invariants are broken (in this case, transaction is permanently broken, so write it
@@ -381,8 +558,12 @@ impl<E: DbExecutor> BlockchainFetcher<E> {
     // |--------------------------|----------------------------------------------------------------|---|
     // | User's account (Cr type) | Our internal acc with blockchain money managed by us (Dr type) |   |
     fn verify_withdrawal_tx(&self, tx: &Transaction, blockchain_tx: &BlockchainTransaction) -> Result<Option<InvariantViolation>, Error> {
-        // Our withdrawal transactions are 1 to 1.
-        if (blockchain_tx.from.len() != 1) || (blockchain_tx.to.len() != 1) {
+        // Our withdrawal transactions are 1 to 1, except for btc batch withdrawals having several outputs.
+        let to_count_valid = match blockchain_tx.currency {
+            Currency::Btc => !blockchain_tx.to.is_empty(),
+            _ => blockchain_tx.to.len() == 1,
+        };
+        if (blockchain_tx.from.len() != 1) || !to_count_valid {
             return Ok(Some(InvariantViolation::WithdrawalAdressesCount));
         }
         if tx.status != TransactionStatus::Pending {
@@ -393,7 +574,6 @@ impl<E: DbExecutor> BlockchainFetcher<E> {
         }
 
         let from_address = blockchain_tx.from[0].clone();
-        let BlockchainTransactionEntryTo { address: to_address, .. } = blockchain_tx.to[0].clone();
         // Transaction should have valid account in our db
         if let Some(managed_address) = self.accounts_repo.get(tx.cr_account_id)? {
             // Blockchain tx from_address should be equal to that of manages account address
@@ -403,21 +583,23 @@ impl<E: DbExecutor> BlockchainFetcher<E> {
         } else {
             return Ok(Some(InvariantViolation::NotExistingAccount));
         };
-        // to_address should be external to our system, because in all other cases we should do
-        // everything internally
-        if let Some(_) = self
-            .accounts_repo
-            .get_by_address(to_address.clone(), blockchain_tx.currency, AccountKind::Dr)?
-        {
-            return Ok(Some(InvariantViolation::WithdrawalAdressesInternal));
-        }
-        // to_address should be external to our system, because in all other cases we should do
-        // everything internally
-        if let Some(_) = self
-            .accounts_repo
-            .get_by_address(to_address.clone(), blockchain_tx.currency, AccountKind::Cr)?
-        {
-            return Ok(Some(InvariantViolation::WithdrawalAdressesInternal));
+        for BlockchainTransactionEntryTo { address: to_address, .. } in blockchain_tx.to.iter().cloned() {
+            // to_address should be external to our system, because in all other cases we should do
+            // everything internally
+            if let Some(_) = self
+                .accounts_repo
+                .get_by_address(to_address.clone(), blockchain_tx.currency, AccountKind::Dr)?
+            {
+                return Ok(Some(InvariantViolation::WithdrawalAdressesInternal));
+            }
+            // to_address should be external to our system, because in all other cases we should do
+            // everything internally
+            if let Some(_) = self
+                .accounts_repo
+                .get_by_address(to_address.clone(), blockchain_tx.currency, AccountKind::Cr)?
+            {
+                return Ok(Some(InvariantViolation::WithdrawalAdressesInternal));
+            }
         }
         // values in blockchain and our tx must match
         // TODO - subject to fees
@@ -508,6 +690,8 @@ impl<E: DbExecutor> BlockchainFetcher<E> {
                                 fee_price: approve_gas_price,
                                 nonce: Some(eth_fees_account_nonce),
                                 utxos: None,
+                                gas_limit: None,
+                                outputs: None,
                             };
 
                             // TODO: sign_transaction will use transferFrom, meaning
@@ -539,6 +723,11 @@ impl<E: DbExecutor> BlockchainFetcher<E> {
                                             group_kind: TransactionGroupKind::Approval,
                                             related_tx: None,
                                             meta: None,
+                                            hold_until: None,
+                                            fee_price: None,
+                                            gross_fee: None,
+                                            user_note: None,
+                                            category: None,
                                         };
                                         let new_pending_eth = (eth_transfer_blockchain_tx_clone, eth_tx_id.clone()).into();
                                         // Note - we don't rollback here, because the tx is already in blockchain. so after that just silently
@@ -614,6 +803,7 @@ impl<E: DbExecutor> BlockchainFetcher<E> {
     }
 }
 
+const REVERTED_FAILURE_REASON: &str = "Transaction was reverted in blockchain";
 const USD_PER_ETH: f64 = 200.0;
 const USD_PER_BTC: f64 = 6500.0;
 const USD_PER_STQ: f64 = 0.0025;
@@ -640,7 +830,8 @@ fn to_usd_approx(currency: Currency, value: Amount) -> u64 {
     usd_value as u64
 }
 
-fn required_confirmations(currency: Currency, value: Amount) -> u64 {
+/// Confirmations after which a blockchain transaction of the value is treated as final
+pub fn required_confirmations(currency: Currency, value: Amount) -> u64 {
     let usd_value = to_usd_approx(currency, value);
     let thresholds = match currency {
         Currency::Btc => BTC_CONFIRM_THRESHOLDS,
@@ -665,6 +856,579 @@ fn parse_transaction(data: Vec<u8>) -> Result<BlockchainTransaction, Error> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use client::*;
+    use rabbit::TransactionPublisherMock;
+    use repos::*;
+    use services::system::DROPPED_FAILURE_REASON;
+    use services::MockClock;
+    use tokio_core::reactor::Core;
+
+    fn create_blockchain_fetcher(
+        accounts_repo: Arc<AccountsRepoMock>,
+        transactions_repo: Arc<TransactionsRepoMock>,
+    ) -> BlockchainFetcher<DbExecutorMock> {
+        create_blockchain_fetcher_with_mocks(
+            Config::new().unwrap(),
+            accounts_repo,
+            transactions_repo,
+            Arc::new(PendingBlockchainTransactionsRepoMock::default()),
+            BlockchainClientMock::default(),
+        )
+    }
+
+    fn create_blockchain_fetcher_with_mocks(
+        config: Config,
+        accounts_repo: Arc<AccountsRepoMock>,
+        transactions_repo: Arc<TransactionsRepoMock>,
+        pending_blockchain_transactions_repo: Arc<PendingBlockchainTransactionsRepoMock>,
+        blockchain_client: BlockchainClientMock,
+    ) -> BlockchainFetcher<DbExecutorMock> {
+        create_blockchain_fetcher_with_clock(
+            config,
+            accounts_repo,
+            transactions_repo,
+            pending_blockchain_transactions_repo,
+            blockchain_client,
+            Arc::new(MockClock::default()),
+        )
+    }
+
+    fn create_blockchain_fetcher_with_clock(
+        config: Config,
+        accounts_repo: Arc<AccountsRepoMock>,
+        transactions_repo: Arc<TransactionsRepoMock>,
+        pending_blockchain_transactions_repo: Arc<PendingBlockchainTransactionsRepoMock>,
+        blockchain_client: BlockchainClientMock,
+        clock: Arc<MockClock>,
+    ) -> BlockchainFetcher<DbExecutorMock> {
+        BlockchainFetcher::new(
+            Arc::new(config),
+            transactions_repo,
+            accounts_repo,
+            Arc::new(SeenHashesRepoMock::default()),
+            Arc::new(BlockchainTransactionsRepoMock::default()),
+            Arc::new(StrangeBlockchainTransactionsRepoMock::default()),
+            pending_blockchain_transactions_repo,
+            Arc::new(KeyValuesRepoMock::default()),
+            Arc::new(blockchain_client),
+            Arc::new(KeysClientMock::default()),
+            DbExecutorMock::default(),
+            Arc::new(TransactionPublisherMock::default()),
+            clock,
+        )
+    }
+
+    #[test]
+    fn test_deposit_processed_once() {
+        let mut core = Core::new().unwrap();
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let mut new_account = NewAccount::default();
+        new_account.currency = Currency::Btc;
+        let cr_account = accounts_repo.create(new_account.clone()).unwrap();
+        accounts_repo.create(new_account.create_debit()).unwrap();
+        let fetcher = create_blockchain_fetcher(accounts_repo, transactions_repo.clone());
+
+        let blockchain_tx = BlockchainTransaction {
+            hash: BlockchainTransactionId::new("deposit_hash".to_string()),
+            from: vec![BlockchainAddress::new("external_address".to_string())],
+            to: vec![BlockchainTransactionEntryTo {
+                address: cr_account.address.clone(),
+                value: Amount::new(1000),
+            }],
+            currency: Currency::Btc,
+            ..Default::default()
+        };
+
+        let credited = core.run(fetcher.handle_transaction(&blockchain_tx)).unwrap();
+        assert_eq!(credited.len(), 1);
+        // replayed notification for the same hash
+        let credited = core.run(fetcher.handle_transaction(&blockchain_tx)).unwrap();
+        assert_eq!(credited.len(), 0);
+        let balance = transactions_repo.get_account_balance(cr_account.id, AccountKind::Cr).unwrap();
+        assert_eq!(balance, Amount::new(1000));
+    }
+
+    #[test]
+    fn test_deposit_checksummed_address() {
+        let mut core = Core::new().unwrap();
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let mut new_account = NewAccount::default();
+        new_account.currency = Currency::Eth;
+        new_account.address = BlockchainAddress::new("0x52908400098527886e0f7030069857d2e4169ee7".to_string());
+        let cr_account = accounts_repo.create(new_account.clone()).unwrap();
+        accounts_repo.create(new_account.create_debit()).unwrap();
+        let fetcher = create_blockchain_fetcher(accounts_repo, transactions_repo.clone());
+
+        let blockchain_tx = BlockchainTransaction {
+            hash: BlockchainTransactionId::new("deposit_hash".to_string()),
+            from: vec![BlockchainAddress::new("external_address".to_string())],
+            to: vec![BlockchainTransactionEntryTo {
+                address: BlockchainAddress::new("0x52908400098527886E0F7030069857D2E4169EE7".to_string()),
+                value: Amount::new(1000),
+            }],
+            currency: Currency::Eth,
+            ..Default::default()
+        };
+
+        let credited = core.run(fetcher.handle_transaction(&blockchain_tx)).unwrap();
+        assert_eq!(credited.len(), 1);
+        let balance = transactions_repo.get_account_balance(cr_account.id, AccountKind::Cr).unwrap();
+        assert_eq!(balance, Amount::new(1000));
+    }
+
+    #[test]
+    fn test_deposit_confirmations_updated() {
+        let mut core = Core::new().unwrap();
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let mut new_account = NewAccount::default();
+        new_account.currency = Currency::Btc;
+        let cr_account = accounts_repo.create(new_account.clone()).unwrap();
+        accounts_repo.create(new_account.create_debit()).unwrap();
+        let fetcher = create_blockchain_fetcher(accounts_repo, transactions_repo);
+
+        let hash = BlockchainTransactionId::new("deposit_hash".to_string());
+        let blockchain_tx = BlockchainTransaction {
+            hash: hash.clone(),
+            from: vec![BlockchainAddress::new("external_address".to_string())],
+            to: vec![BlockchainTransactionEntryTo {
+                address: cr_account.address.clone(),
+                value: Amount::new(1000),
+            }],
+            currency: Currency::Btc,
+            confirmations: 1,
+            ..Default::default()
+        };
+
+        core.run(fetcher.handle_transaction(&blockchain_tx)).unwrap();
+        let stored = fetcher.blockchain_transactions_repo.get(hash.clone()).unwrap().unwrap();
+        assert_eq!(stored.confirmations, 1);
+        let blockchain_tx = BlockchainTransaction {
+            confirmations: 3,
+            ..blockchain_tx
+        };
+        core.run(fetcher.handle_transaction(&blockchain_tx)).unwrap();
+        let stored = fetcher.blockchain_transactions_repo.get(hash).unwrap().unwrap();
+        assert_eq!(stored.confirmations, 3);
+    }
+
+    #[test]
+    fn test_deposit_outputs_credited_once() {
+        let mut core = Core::new().unwrap();
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let mut cr_accounts = vec![];
+        for _ in 0..2 {
+            let mut new_account = NewAccount::default();
+            new_account.currency = Currency::Btc;
+            cr_accounts.push(accounts_repo.create(new_account.clone()).unwrap());
+            accounts_repo.create(new_account.create_debit()).unwrap();
+        }
+        let fetcher = create_blockchain_fetcher(accounts_repo, transactions_repo.clone());
+
+        // two outputs to different users and one more to the first user
+        let blockchain_tx = BlockchainTransaction {
+            hash: BlockchainTransactionId::new("deposit_hash".to_string()),
+            from: vec![BlockchainAddress::new("external_address".to_string())],
+            to: vec![
+                BlockchainTransactionEntryTo {
+                    address: cr_accounts[0].address.clone(),
+                    value: Amount::new(1000),
+                },
+                BlockchainTransactionEntryTo {
+                    address: cr_accounts[1].address.clone(),
+                    value: Amount::new(2000),
+                },
+                BlockchainTransactionEntryTo {
+                    address: cr_accounts[0].address.clone(),
+                    value: Amount::new(500),
+                },
+                BlockchainTransactionEntryTo {
+                    address: BlockchainAddress::new("change_address".to_string()),
+                    value: Amount::new(300),
+                },
+            ],
+            currency: Currency::Btc,
+            ..Default::default()
+        };
+
+        let credited = core.run(fetcher.handle_transaction(&blockchain_tx)).unwrap();
+        assert_eq!(credited.len(), 3);
+        // replayed notification doesn't credit anything again
+        let credited = core.run(fetcher.handle_transaction(&blockchain_tx)).unwrap();
+        assert_eq!(credited.len(), 0);
+        let balance = transactions_repo.get_account_balance(cr_accounts[0].id, AccountKind::Cr).unwrap();
+        assert_eq!(balance, Amount::new(1500));
+        let balance = transactions_repo.get_account_balance(cr_accounts[1].id, AccountKind::Cr).unwrap();
+        assert_eq!(balance, Amount::new(2000));
+        assert!(fetcher.was_output_seen(&blockchain_tx, 2).unwrap());
+        assert!(!fetcher.was_output_seen(&blockchain_tx, 3).unwrap());
+    }
+
+    #[test]
+    fn test_deposit_currency_mismatch() {
+        let mut core = Core::new().unwrap();
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let mut new_account = NewAccount::default();
+        new_account.currency = Currency::Eth;
+        let cr_account = accounts_repo.create(new_account.clone()).unwrap();
+        accounts_repo.create(new_account.create_debit()).unwrap();
+        let fetcher = create_blockchain_fetcher(accounts_repo, transactions_repo.clone());
+
+        let hash = BlockchainTransactionId::new("deposit_hash".to_string());
+        let blockchain_tx = BlockchainTransaction {
+            hash: hash.clone(),
+            from: vec![BlockchainAddress::new("external_address".to_string())],
+            to: vec![BlockchainTransactionEntryTo {
+                address: cr_account.address.clone(),
+                value: Amount::new(1000),
+            }],
+            currency: Currency::Stq,
+            ..Default::default()
+        };
+
+        let credited = core.run(fetcher.handle_transaction(&blockchain_tx)).unwrap();
+        assert_eq!(credited.len(), 0);
+        let balance = transactions_repo.get_account_balance(cr_account.id, AccountKind::Cr).unwrap();
+        assert_eq!(balance, Amount::new(0));
+        assert!(fetcher.strange_blockchain_transactions_repo.get(hash).unwrap().is_some());
+        assert!(fetcher.was_seen(&blockchain_tx).unwrap());
+    }
+
+    #[test]
+    fn test_deposit_reverted() {
+        let mut core = Core::new().unwrap();
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let mut new_account = NewAccount::default();
+        new_account.currency = Currency::Stq;
+        let cr_account = accounts_repo.create(new_account.clone()).unwrap();
+        accounts_repo.create(new_account.create_debit()).unwrap();
+        let fetcher = create_blockchain_fetcher(accounts_repo, transactions_repo.clone());
+
+        let hash = BlockchainTransactionId::new("deposit_hash".to_string());
+        let blockchain_tx = BlockchainTransaction {
+            hash: hash.clone(),
+            from: vec![BlockchainAddress::new("external_address".to_string())],
+            to: vec![BlockchainTransactionEntryTo {
+                address: cr_account.address.clone(),
+                value: Amount::new(1000),
+            }],
+            currency: Currency::Stq,
+            reverted: true,
+            ..Default::default()
+        };
+
+        let credited = core.run(fetcher.handle_transaction(&blockchain_tx)).unwrap();
+        assert_eq!(credited.len(), 0);
+        let balance = transactions_repo.get_account_balance(cr_account.id, AccountKind::Cr).unwrap();
+        assert_eq!(balance, Amount::new(0));
+        assert!(fetcher.strange_blockchain_transactions_repo.get(hash.clone()).unwrap().is_some());
+        assert!(fetcher.blockchain_transactions_repo.get(hash).unwrap().is_none());
+        assert!(fetcher.was_seen(&blockchain_tx).unwrap());
+    }
+
+    // user's account with 10_000 withdrawn from it, the withdrawal is pending in blockchain
+    fn create_pending_withdrawal(
+        config: &Config,
+        accounts_repo: &AccountsRepoMock,
+        transactions_repo: &TransactionsRepoMock,
+        pending_blockchain_transactions_repo: &PendingBlockchainTransactionsRepoMock,
+        hash: BlockchainTransactionId,
+        currency: Currency,
+    ) -> (Account, Account) {
+        let fees_account_id = match currency {
+            Currency::Btc => config.system.btc_fees_account_id,
+            Currency::Eth => config.system.eth_fees_account_id,
+            Currency::Stq => config.system.stq_fees_account_id,
+        };
+        accounts_repo
+            .create(NewAccount {
+                id: fees_account_id,
+                currency,
+                ..Default::default()
+            })
+            .unwrap();
+        let user_account = accounts_repo
+            .create(NewAccount {
+                currency,
+                ..Default::default()
+            })
+            .unwrap();
+        let withdrawal_account = accounts_repo
+            .create(NewAccount {
+                currency,
+                kind: AccountKind::Dr,
+                ..Default::default()
+            })
+            .unwrap();
+        transactions_repo
+            .create(NewTransaction {
+                cr_account_id: user_account.id,
+                currency,
+                value: Amount::new(30_000),
+                status: TransactionStatus::Done,
+                kind: TransactionKind::Deposit,
+                ..Default::default()
+            })
+            .unwrap();
+        transactions_repo
+            .create(NewTransaction {
+                user_id: user_account.user_id,
+                dr_account_id: user_account.id,
+                cr_account_id: withdrawal_account.id,
+                currency,
+                value: Amount::new(10_000),
+                status: TransactionStatus::Pending,
+                blockchain_tx_id: Some(hash.clone()),
+                kind: TransactionKind::Withdrawal,
+                group_kind: TransactionGroupKind::Withdrawal,
+                ..Default::default()
+            })
+            .unwrap();
+        pending_blockchain_transactions_repo
+            .create(NewPendingBlockchainTransactionDB {
+                hash,
+                from_: withdrawal_account.address.clone(),
+                to_: BlockchainAddress::new("external_address".to_string()),
+                currency,
+                value: Amount::new(10_000),
+                ..Default::default()
+            })
+            .unwrap();
+        (user_account, withdrawal_account)
+    }
+
+    #[test]
+    fn test_resolve_confirmed_withdrawal() {
+        let mut core = Core::new().unwrap();
+        let config = Config::new().unwrap();
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let pending_blockchain_transactions_repo = Arc::new(PendingBlockchainTransactionsRepoMock::default());
+        let hash = BlockchainTransactionId::new("withdrawal_hash".to_string());
+        let (user_account, withdrawal_account) = create_pending_withdrawal(
+            &config,
+            &accounts_repo,
+            &transactions_repo,
+            &pending_blockchain_transactions_repo,
+            hash.clone(),
+            Currency::Btc,
+        );
+        let gid = transactions_repo.get_by_blockchain_tx(hash.clone()).unwrap().unwrap().gid;
+        transactions_repo
+            .create(NewTransaction {
+                gid,
+                user_id: user_account.user_id,
+                dr_account_id: user_account.id,
+                currency: Currency::Btc,
+                value: Amount::new(600),
+                status: TransactionStatus::Done,
+                kind: TransactionKind::Fee,
+                group_kind: TransactionGroupKind::Withdrawal,
+                fee_price: Some(2.0),
+                gross_fee: Some(Amount::new(560)),
+                ..Default::default()
+            })
+            .unwrap();
+        let blockchain_client = BlockchainClientMock::default().with_transaction(BlockchainTransaction {
+            hash: hash.clone(),
+            from: vec![withdrawal_account.address.clone()],
+            to: vec![BlockchainTransactionEntryTo {
+                address: BlockchainAddress::new("external_address".to_string()),
+                value: Amount::new(10_000),
+            }],
+            currency: Currency::Btc,
+            fee: Amount::new(500),
+            confirmations: 6,
+            ..Default::default()
+        });
+        let fetcher = create_blockchain_fetcher_with_mocks(
+            config,
+            accounts_repo,
+            transactions_repo.clone(),
+            pending_blockchain_transactions_repo.clone(),
+            blockchain_client,
+        );
+
+        let resolved = core.run(fetcher.resolve_pending_transactions(10)).unwrap();
+        assert_eq!(resolved.len(), 1);
+        assert_eq!(resolved[0].status, TransactionStatus::Done);
+        assert!(pending_blockchain_transactions_repo.get(hash).unwrap().is_none());
+        let balance = transactions_repo.get_account_balance(user_account.id, AccountKind::Cr).unwrap();
+        assert_eq!(balance, Amount::new(19_400));
+        // the fee actually paid is recorded next to its estimate for one input and one output at the fee price
+        let blockchain_fee = transactions_repo
+            .get_by_gid(gid)
+            .unwrap()
+            .into_iter()
+            .find(|tx| tx.kind == TransactionKind::BlockchainFee)
+            .unwrap();
+        assert_eq!(blockchain_fee.value, Amount::new(500));
+        assert_eq!(blockchain_fee.fee_price, Some(2.0));
+        assert_eq!(blockchain_fee.gross_fee, Some(Amount::new(384)));
+        // nothing left to resolve
+        let resolved = core.run(fetcher.resolve_pending_transactions(10)).unwrap();
+        assert!(resolved.is_empty());
+    }
+
+    #[test]
+    fn test_resolve_reverted_eth_withdrawal() {
+        let mut core = Core::new().unwrap();
+        let config = Config::new().unwrap();
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let pending_blockchain_transactions_repo = Arc::new(PendingBlockchainTransactionsRepoMock::default());
+        let hash = BlockchainTransactionId::new("withdrawal_hash".to_string());
+        let (user_account, withdrawal_account) = create_pending_withdrawal(
+            &config,
+            &accounts_repo,
+            &transactions_repo,
+            &pending_blockchain_transactions_repo,
+            hash.clone(),
+            Currency::Eth,
+        );
+        let blockchain_client = BlockchainClientMock::default().with_transaction(BlockchainTransaction {
+            hash: hash.clone(),
+            from: vec![withdrawal_account.address.clone()],
+            to: vec![BlockchainTransactionEntryTo {
+                address: BlockchainAddress::new("external_address".to_string()),
+                value: Amount::new(10_000),
+            }],
+            currency: Currency::Eth,
+            fee: Amount::new(500),
+            confirmations: 6,
+            reverted: true,
+            ..Default::default()
+        });
+        let fetcher = create_blockchain_fetcher_with_mocks(
+            config,
+            accounts_repo,
+            transactions_repo.clone(),
+            pending_blockchain_transactions_repo.clone(),
+            blockchain_client,
+        );
+
+        let resolved = core.run(fetcher.resolve_pending_transactions(10)).unwrap();
+        assert_eq!(resolved.len(), 1);
+        assert_eq!(resolved[0].status, TransactionStatus::Failed);
+        assert_eq!(resolved[0].failure_reason, Some(REVERTED_FAILURE_REASON.to_string()));
+        assert!(pending_blockchain_transactions_repo.get(hash).unwrap().is_none());
+        // withdrawn value is back on user's balance
+        let balance = transactions_repo.get_account_balance(user_account.id, AccountKind::Cr).unwrap();
+        assert_eq!(balance, Amount::new(30_000));
+        // network fee is paid anyway
+        let blockchain_fee = transactions_repo
+            .get_by_gid(resolved[0].gid)
+            .unwrap()
+            .into_iter()
+            .find(|tx| tx.kind == TransactionKind::BlockchainFee)
+            .unwrap();
+        assert_eq!(blockchain_fee.value, Amount::new(500));
+    }
+
+    #[test]
+    fn test_resolve_dropped_withdrawal() {
+        let mut core = Core::new().unwrap();
+        let config = Config::new().unwrap();
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let pending_blockchain_transactions_repo = Arc::new(PendingBlockchainTransactionsRepoMock::default());
+        let hash = BlockchainTransactionId::new("withdrawal_hash".to_string());
+        let (user_account, _) = create_pending_withdrawal(
+            &config,
+            &accounts_repo,
+            &transactions_repo,
+            &pending_blockchain_transactions_repo,
+            hash.clone(),
+            Currency::Btc,
+        );
+        // gateway doesn't know the transaction
+        let clock = Arc::new(MockClock::default());
+        let fetcher = create_blockchain_fetcher_with_clock(
+            config.clone(),
+            accounts_repo,
+            transactions_repo.clone(),
+            pending_blockchain_transactions_repo.clone(),
+            BlockchainClientMock::default(),
+            clock.clone(),
+        );
+
+        // could be not yet propagated to gateway
+        let resolved = core.run(fetcher.resolve_pending_transactions(10)).unwrap();
+        assert!(resolved.is_empty());
+
+        // might still be mined, so it is only reported and the withdrawn value stays reserved
+        clock.advance(ChronoDuration::seconds(config.system.dropped_transaction_timeout_secs as i64));
+        let resolved = core.run(fetcher.resolve_pending_transactions(10)).unwrap();
+        assert!(resolved.is_empty());
+        let withdrawal = transactions_repo.get_by_blockchain_tx(hash.clone()).unwrap().unwrap();
+        assert_eq!(withdrawal.status, TransactionStatus::Pending);
+        assert!(pending_blockchain_transactions_repo.get(hash.clone()).unwrap().is_some());
+        let balance = transactions_repo.get_account_balance(user_account.id, AccountKind::Cr).unwrap();
+        assert_eq!(balance, Amount::new(20_000));
+
+        // operator checked that it can't be mined anymore
+        let failed = fetcher.system_service.fail_dropped_withdrawal(hash.clone()).unwrap();
+        assert!(failed.iter().all(|tx| tx.status == TransactionStatus::Failed));
+        assert_eq!(failed[0].failure_reason, Some(DROPPED_FAILURE_REASON.to_string()));
+        assert!(pending_blockchain_transactions_repo.get(hash).unwrap().is_none());
+        let balance = transactions_repo.get_account_balance(user_account.id, AccountKind::Cr).unwrap();
+        assert_eq!(balance, Amount::new(30_000));
+    }
+
+    #[test]
+    fn test_resolver_paused() {
+        let mut core = Core::new().unwrap();
+        let config = Config::new().unwrap();
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let pending_blockchain_transactions_repo = Arc::new(PendingBlockchainTransactionsRepoMock::default());
+        let hash = BlockchainTransactionId::new("withdrawal_hash".to_string());
+        let (_, withdrawal_account) = create_pending_withdrawal(
+            &config,
+            &accounts_repo,
+            &transactions_repo,
+            &pending_blockchain_transactions_repo,
+            hash.clone(),
+            Currency::Btc,
+        );
+        let blockchain_client = BlockchainClientMock::default().with_transaction(BlockchainTransaction {
+            hash: hash.clone(),
+            from: vec![withdrawal_account.address.clone()],
+            to: vec![BlockchainTransactionEntryTo {
+                address: BlockchainAddress::new("external_address".to_string()),
+                value: Amount::new(10_000),
+            }],
+            currency: Currency::Btc,
+            fee: Amount::new(500),
+            confirmations: 6,
+            ..Default::default()
+        });
+        let fetcher = create_blockchain_fetcher_with_mocks(
+            config,
+            accounts_repo,
+            transactions_repo.clone(),
+            pending_blockchain_transactions_repo.clone(),
+            blockchain_client,
+        );
+
+        fetcher.key_values_repo.set_resolver_enabled(false).unwrap();
+        let resolved = core.run(fetcher.resolve_pending_transactions(10)).unwrap();
+        assert!(resolved.is_empty());
+        let withdrawal = transactions_repo.get_by_blockchain_tx(hash.clone()).unwrap().unwrap();
+        assert_eq!(withdrawal.status, TransactionStatus::Pending);
+        assert!(pending_blockchain_transactions_repo.get(hash.clone()).unwrap().is_some());
+
+        fetcher.key_values_repo.set_resolver_enabled(true).unwrap();
+        let resolved = core.run(fetcher.resolve_pending_transactions(10)).unwrap();
+        assert_eq!(resolved.len(), 1);
+        assert_eq!(resolved[0].status, TransactionStatus::Done);
+    }
+
     #[test]
     fn test_required_confirmations() {
         let cases = [
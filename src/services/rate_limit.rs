@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use chrono::NaiveDateTime;
+
+use super::clock::Clock;
+use config::RateLimit;
+use models::*;
+
+/// Buckets are dropped once there are this many of them, full buckets are the same as missing ones
+const MAX_BUCKETS: usize = 10_000;
+
+/// Decides whether a user may do one more rate limited request. The in-memory implementation
+/// is local to the process, a shared store can be plugged in by implementing this trait
+pub trait RateLimiter: Send + Sync + 'static {
+    /// Takes a token from the bucket of the user, `false` if there are none left
+    fn try_acquire(&self, user_id: UserId) -> bool;
+}
+
+/// Lets every request through
+#[derive(Clone, Default)]
+pub struct NoRateLimit;
+
+impl RateLimiter for NoRateLimit {
+    fn try_acquire(&self, _user_id: UserId) -> bool {
+        true
+    }
+}
+
+/// Token bucket per user: up to `burst` requests at once, refilled at `refill_per_sec`
+pub struct InMemoryRateLimiter {
+    burst: f64,
+    refill_per_sec: f64,
+    clock: Arc<dyn Clock>,
+    buckets: Mutex<HashMap<UserId, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated_at: NaiveDateTime,
+}
+
+impl InMemoryRateLimiter {
+    pub fn new(limit: &RateLimit, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            burst: limit.burst as f64,
+            refill_per_sec: limit.refill_per_sec,
+            clock,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn refill(&self, bucket: &Bucket, now: NaiveDateTime) -> f64 {
+        let elapsed_secs = (now - bucket.updated_at).num_milliseconds().max(0) as f64 / 1000.0;
+        (bucket.tokens + elapsed_secs * self.refill_per_sec).min(self.burst)
+    }
+}
+
+impl RateLimiter for InMemoryRateLimiter {
+    fn try_acquire(&self, user_id: UserId) -> bool {
+        let now = self.clock.now();
+        let mut buckets = match self.buckets.lock() {
+            Ok(buckets) => buckets,
+            // limiter must never take the service down
+            Err(_) => return true,
+        };
+        if buckets.len() >= MAX_BUCKETS {
+            let burst = self.burst;
+            buckets.retain(|_, bucket| self.refill(bucket, now) < burst);
+        }
+        let burst = self.burst;
+        let bucket = buckets.entry(user_id).or_insert(Bucket {
+            tokens: burst,
+            updated_at: now,
+        });
+        let tokens = self.refill(bucket, now);
+        bucket.updated_at = now;
+        if tokens >= 1.0 {
+            bucket.tokens = tokens - 1.0;
+            true
+        } else {
+            bucket.tokens = tokens;
+            false
+        }
+    }
+}
@@ -0,0 +1,118 @@
+use std::sync::{Arc, Mutex};
+
+use futures::prelude::*;
+use futures::task::{self, Task};
+
+/// Keeps track of operations that must not be cut off by a deploy, e.g. withdrawals between
+/// posting to blockchain and saving to db. Once shutdown is started no new operations are let in,
+/// and `shutdown` resolves when the running ones are complete
+#[derive(Clone, Default)]
+pub struct ShutdownCoordinator {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    shutting_down: bool,
+    in_flight: usize,
+    waiting: Vec<Task>,
+}
+
+/// Held while an operation is running, dropping it marks the operation complete
+pub struct InFlightGuard {
+    state: Arc<Mutex<State>>,
+}
+
+impl ShutdownCoordinator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a new operation, `None` if shutdown has already started
+    pub fn try_enter(&self) -> Option<InFlightGuard> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if state.shutting_down {
+            return None;
+        }
+        state.in_flight += 1;
+        Some(InFlightGuard { state: self.state.clone() })
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).in_flight
+    }
+
+    /// Stops letting new operations in and resolves once the running ones are complete.
+    /// It never times out by itself, so it should be wrapped in a timeout
+    pub fn shutdown(&self) -> Drained {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).shutting_down = true;
+        Drained { state: self.state.clone() }
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.in_flight -= 1;
+        if state.in_flight == 0 {
+            for task in state.waiting.drain(..) {
+                task.notify();
+            }
+        }
+    }
+}
+
+/// Future returned by `ShutdownCoordinator::shutdown`
+pub struct Drained {
+    state: Arc<Mutex<State>>,
+}
+
+impl Future for Drained {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if state.in_flight == 0 {
+            return Ok(Async::Ready(()));
+        }
+        state.waiting.push(task::current());
+        Ok(Async::NotReady)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future;
+    use tokio_core::reactor::Core;
+
+    #[test]
+    fn test_shutdown_waits_for_in_flight() {
+        let mut core = Core::new().unwrap();
+        let coordinator = ShutdownCoordinator::new();
+        let first = coordinator.try_enter().unwrap();
+        let second = coordinator.try_enter().unwrap();
+        assert_eq!(coordinator.in_flight(), 2);
+
+        let mut drained = coordinator.shutdown();
+        assert!(coordinator.try_enter().is_none());
+        let not_ready = core.run(future::lazy(|| Ok::<_, ()>(drained.poll()))).unwrap();
+        assert_eq!(not_ready, Ok(Async::NotReady));
+
+        drop(first);
+        let not_ready = core.run(future::lazy(|| Ok::<_, ()>(drained.poll()))).unwrap();
+        assert_eq!(not_ready, Ok(Async::NotReady));
+        drop(second);
+        core.run(drained).unwrap();
+        assert_eq!(coordinator.in_flight(), 0);
+    }
+
+    #[test]
+    fn test_shutdown_without_in_flight() {
+        let mut core = Core::new().unwrap();
+        let coordinator = ShutdownCoordinator::new();
+        core.run(coordinator.shutdown()).unwrap();
+        assert!(coordinator.try_enter().is_none());
+    }
+}
@@ -1,27 +1,146 @@
+use std::collections::HashMap;
 use std::sync::Arc;
 
+use chrono::NaiveDateTime;
+use futures::{future, stream};
+
+use super::clock::Clock;
 use super::error::*;
 use config::Config;
 use models::*;
 use prelude::*;
-use repos::AccountsRepo;
+use repos::{AccountsRepo, PendingBlockchainTransactionsRepo, TransactionsRepo};
+use utils::log_and_capture_error;
 
 pub trait SystemService: Send + Sync + 'static {
     fn get_system_transfer_account(&self, currency: Currency) -> Result<Account, Error>;
     fn get_system_liquidity_account(&self, currency: Currency) -> Result<Account, Error>;
+    /// Main liquidity account of `currency` followed by the extra ones of its pool
+    fn get_system_liquidity_accounts(&self, currency: Currency) -> Result<Vec<Account>, Error>;
+    /// Liquidity account of the pool taking part in an exchange of `value`. It is picked at random, so that
+    /// concurrent exchanges are spread over the pool. The one paying out is picked among the ones holding `value`
+    fn select_system_liquidity_account(&self, currency: Currency, value: Amount, pays_out: bool) -> Result<Account, Error>;
     fn get_system_fees_account(&self, currency: Currency) -> Result<Account, Error>;
     fn get_system_fees_account_dr(&self, currency: Currency) -> Result<Account, Error>;
+    /// Applies scheduled transfers that are due by now, must be run inside serializable transaction.
+    /// The ones that can't be applied, e.g. for lack of funds, are cancelled
+    fn process_due_transactions(&self) -> Result<Vec<Transaction>, Error>;
+    /// Transfer, liquidity pool and fees accounts for `currency`
+    fn get_system_accounts(&self, currency: Currency) -> Result<Vec<Account>, Error>;
+    /// Sums the balance of the account strictly from its ledger legs. Balances are never stored,
+    /// so this is the authoritative value to compare with anything derived from it
+    fn recompute_account_balance(&self, account_id: AccountId) -> Result<Amount, Error>;
+    /// Recomputes balances of all accounts of `currency` from ledger history, `batch_size` accounts at a time,
+    /// yielding each batch. Accounts are paged by id and every batch is read with its own queries,
+    /// so nothing is locked for the whole run. Batches are read as the stream is polled,
+    /// so it must be polled where repos have a connection, e.g. inside `DbExecutor::execute`
+    fn recompute_all_balances(&self, currency: Currency, batch_size: i64) -> Box<Stream<Item = RecomputeProgress, Error = Error> + Send>;
+    /// Compares ledger balances of system accounts with on-chain balances of their addresses
+    fn reconcile(
+        &self,
+        currency: Currency,
+        blockchain_balances: &HashMap<(BlockchainAddress, Currency), Amount>,
+    ) -> Result<ReconciliationReport, Error>;
+    /// Service and blockchain fees credited to the fees accounts of `currency` in `[from, to)`
+    fn fees_collected(&self, currency: Currency, from: NaiveDateTime, to: NaiveDateTime) -> Result<FeesCollected, Error>;
+    /// All accounts at the blockchain address regardless of currency and kind,
+    /// e.g. eth and stq accounts of a user share the same address
+    fn find_accounts_by_address(&self, address: BlockchainAddress) -> Result<Vec<Account>, Error>;
+    /// Withdrawals posted to blockchain, but missing the pending transaction, so they can't be matched
+    /// with the blockchain transaction once it's mined
+    fn find_orphaned_withdrawals(&self, limit: i64) -> Result<Vec<Transaction>, Error>;
+    /// Restores the pending transaction of an orphaned withdrawal from its ledger leg and the transaction as seen on chain
+    fn recover_orphaned_pending(
+        &self,
+        hash: BlockchainTransactionId,
+        blockchain_tx: BlockchainTransaction,
+    ) -> Result<PendingBlockchainTransactionDB, Error>;
+    /// Fails the withdrawal legs of a blockchain transaction that gateway doesn't know, once an operator has checked
+    /// that it can't be mined anymore. The fee is refunded when no withdrawal leg of the group is left
+    fn fail_dropped_withdrawal(&self, hash: BlockchainTransactionId) -> Result<Vec<Transaction>, Error>;
 }
 
+pub const DROPPED_FAILURE_REASON: &str = "Transaction was dropped from blockchain";
+
 #[derive(Clone)]
 pub struct SystemServiceImpl {
     accounts_repo: Arc<AccountsRepo>,
+    transactions_repo: Arc<TransactionsRepo>,
+    pending_blockchain_transactions_repo: Arc<PendingBlockchainTransactionsRepo>,
     config: Arc<Config>,
+    clock: Arc<Clock>,
 }
 
 impl SystemServiceImpl {
-    pub fn new(accounts_repo: Arc<AccountsRepo>, config: Arc<Config>) -> Self {
-        Self { accounts_repo, config }
+    pub fn new(
+        accounts_repo: Arc<AccountsRepo>,
+        transactions_repo: Arc<TransactionsRepo>,
+        pending_blockchain_transactions_repo: Arc<PendingBlockchainTransactionsRepo>,
+        config: Arc<Config>,
+        clock: Arc<Clock>,
+    ) -> Self {
+        Self {
+            accounts_repo,
+            transactions_repo,
+            pending_blockchain_transactions_repo,
+            config,
+            clock,
+        }
+    }
+
+    /// Checks that every configured system account and its dr counterpart exist
+    /// with the expected kind and currency. Called at boot to fail fast on misconfiguration.
+    pub fn validate_system_accounts(&self) -> Result<(), Error> {
+        for currency in &[Currency::Btc, Currency::Eth, Currency::Stq] {
+            let currency = *currency;
+            let mut accounts = vec![self.get_system_transfer_account(currency)?];
+            accounts.extend(self.get_system_liquidity_accounts(currency)?);
+            accounts.push(self.get_system_fees_account(currency)?);
+            for account in accounts {
+                let account_id = account.id;
+                if account.kind != AccountKind::Cr || account.currency != currency {
+                    return Err(ectx!(err ErrorContext::SystemAccountMisconfigured, ErrorKind::Internal => account_id, currency));
+                }
+                let dr_account_id = account_id.derive_system_dr_id();
+                let dr_account = self
+                    .accounts_repo
+                    .get(dr_account_id)
+                    .map_err(ectx!(try ErrorKind::Internal => dr_account_id))?
+                    .ok_or(ectx!(try err ErrorContext::NoAccount, ErrorKind::Internal => dr_account_id, currency))?;
+                if dr_account.kind != AccountKind::Dr || dr_account.currency != currency {
+                    return Err(ectx!(err ErrorContext::SystemAccountMisconfigured, ErrorKind::Internal => dr_account_id, currency));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // scheduled transactions are not counted in balance, so we check it at the moment of execution
+    fn can_execute_due_transaction(&self, tx: &Transaction) -> Result<bool, Error> {
+        let dr_account_id = tx.dr_account_id;
+        let dr_account = match self
+            .accounts_repo
+            .get(dr_account_id)
+            .map_err(ectx!(try ErrorKind::Internal => dr_account_id))?
+        {
+            Some(dr_account) => dr_account,
+            None => {
+                warn!("Source account of scheduled transaction is missing: {:?}", tx);
+                return Ok(false);
+            }
+        };
+        let balance = self
+            .transactions_repo
+            .get_account_balance(dr_account.id, dr_account.kind)
+            .map_err(ectx!(try ErrorKind::Internal => dr_account_id))?;
+        if balance < tx.value {
+            warn!(
+                "Not enough balance to execute scheduled transaction: {:?}, balance: {:?}",
+                tx, balance
+            );
+            return Ok(false);
+        }
+        Ok(true)
     }
 }
 
@@ -36,7 +155,7 @@ impl SystemService for SystemServiceImpl {
             .accounts_repo
             .get(acc_id.clone())
             .map_err(ectx!(try ErrorKind::Internal => acc_id))?
-            .ok_or(ectx!(try err ErrorContext::NoAccount, ErrorKind::NotFound))?;
+            .ok_or(ectx!(try err ErrorContext::NoAccount, ErrorKind::Internal => acc_id, currency))?;
         Ok(acc)
     }
 
@@ -50,10 +169,58 @@ impl SystemService for SystemServiceImpl {
             .accounts_repo
             .get(acc_id.clone())
             .map_err(ectx!(try ErrorKind::Internal => acc_id))?
-            .ok_or(ectx!(try err ErrorContext::NoAccount, ErrorKind::NotFound))?;
+            .ok_or(ectx!(try err ErrorContext::NoAccount, ErrorKind::Internal => acc_id, currency))?;
         Ok(acc)
     }
 
+    fn get_system_liquidity_accounts(&self, currency: Currency) -> Result<Vec<Account>, Error> {
+        let mut res = vec![];
+        for acc_id in self.config.system.liquidity_account_ids(currency) {
+            let acc = self
+                .accounts_repo
+                .get(acc_id)
+                .map_err(ectx!(try ErrorKind::Internal => acc_id))?
+                .ok_or(ectx!(try err ErrorContext::NoAccount, ErrorKind::Internal => acc_id, currency))?;
+            res.push(acc);
+        }
+        Ok(res)
+    }
+
+    fn select_system_liquidity_account(&self, currency: Currency, value: Amount, pays_out: bool) -> Result<Account, Error> {
+        let mut candidates = vec![];
+        let mut richest: Option<(Account, Amount)> = None;
+        for account in self.get_system_liquidity_accounts(currency)? {
+            if !pays_out {
+                candidates.push(account);
+                continue;
+            }
+            let account_id = account.id;
+            let balance = self
+                .transactions_repo
+                .get_account_balance(account_id, account.kind)
+                .map_err(ectx!(try ErrorKind::Internal => account_id))?;
+            if balance >= value {
+                candidates.push(account.clone());
+            }
+            if richest
+                .as_ref()
+                .map(|(_, richest_balance)| balance > *richest_balance)
+                .unwrap_or(true)
+            {
+                richest = Some((account, balance));
+            }
+        }
+        // picking by balance would send concurrent exchanges to the same account,
+        // so that all of them but one fail on serialization
+        if let Some(account) = thread_rng().choose(&candidates) {
+            return Ok(account.clone());
+        }
+        // none of the pool holds the value, the richest one is reported short of funds when paying out
+        richest
+            .map(|(account, _)| account)
+            .ok_or(ectx!(err ErrorContext::NoAccount, ErrorKind::Internal => currency))
+    }
+
     fn get_system_fees_account(&self, currency: Currency) -> Result<Account, Error> {
         let acc_id = match currency {
             Currency::Btc => self.config.system.btc_fees_account_id,
@@ -64,7 +231,7 @@ impl SystemService for SystemServiceImpl {
             .accounts_repo
             .get(acc_id.clone())
             .map_err(ectx!(try ErrorKind::Internal => acc_id))?
-            .ok_or(ectx!(try err ErrorContext::NoAccount, ErrorKind::NotFound => currency))?;
+            .ok_or(ectx!(try err ErrorContext::NoAccount, ErrorKind::Internal => acc_id, currency))?;
         Ok(acc)
     }
 
@@ -79,7 +246,935 @@ impl SystemService for SystemServiceImpl {
             .accounts_repo
             .get(dr_acc_id.clone())
             .map_err(ectx!(try ErrorKind::Internal => dr_acc_id))?
-            .ok_or(ectx!(try err ErrorContext::NoAccount, ErrorKind::NotFound))?;
+            .ok_or(ectx!(try err ErrorContext::NoAccount, ErrorKind::Internal => dr_acc_id, currency))?;
         Ok(acc)
     }
+
+    fn process_due_transactions(&self) -> Result<Vec<Transaction>, Error> {
+        let now = self.clock.now();
+        let due_txs = self
+            .transactions_repo
+            .get_scheduled_due(now)
+            .map_err(ectx!(try ErrorKind::Internal => now))?;
+        let mut res = vec![];
+        // errors are handled per transaction, so that a broken one doesn't hold back the rest of the batch
+        for tx in due_txs {
+            let tx_id = tx.id;
+            let status = match self.can_execute_due_transaction(&tx) {
+                Ok(true) => TransactionStatus::Done,
+                // transaction that can't be executed is cancelled, otherwise it's picked up again on every run
+                Ok(false) => TransactionStatus::Cancelled,
+                Err(e) => {
+                    log_and_capture_error(e);
+                    continue;
+                }
+            };
+            match self.transactions_repo.update_status_by_id(tx_id, status) {
+                Ok(tx) => {
+                    if tx.status == TransactionStatus::Done {
+                        res.push(tx);
+                    }
+                }
+                Err(e) => log_and_capture_error(ectx!(try err e, ErrorKind::Internal => tx_id, status)),
+            }
+        }
+        Ok(res)
+    }
+
+    fn get_system_accounts(&self, currency: Currency) -> Result<Vec<Account>, Error> {
+        let mut res: Vec<Account> = vec![];
+        let mut accounts = vec![self.get_system_transfer_account(currency)?];
+        accounts.extend(self.get_system_liquidity_accounts(currency)?);
+        accounts.push(self.get_system_fees_account(currency)?);
+        for account in accounts {
+            if res.iter().all(|acc| acc.id != account.id) {
+                res.push(account);
+            }
+        }
+        Ok(res)
+    }
+
+    fn recompute_account_balance(&self, account_id: AccountId) -> Result<Amount, Error> {
+        let account = self
+            .accounts_repo
+            .get(account_id)
+            .map_err(ectx!(try ErrorKind::Internal => account_id))?
+            .ok_or(ectx!(try err ErrorContext::NoAccount, ErrorKind::NotFound => account_id))?;
+        self.transactions_repo
+            .get_account_balance(account.id, account.kind)
+            .map_err(ectx!(ErrorKind::Internal => account_id))
+    }
+
+    fn recompute_all_balances(&self, currency: Currency, batch_size: i64) -> Box<Stream<Item = RecomputeProgress, Error = Error> + Send> {
+        let accounts_repo = self.accounts_repo.clone();
+        let transactions_repo = self.transactions_repo.clone();
+        let system_user_id = self.config.system.system_user_id;
+        // state is the id of the last recomputed account and the number of accounts recomputed so far,
+        // it's `None` once the last batch is shorter than `batch_size`
+        Box::new(stream::unfold(
+            Some((None, 0u64)),
+            move |state: Option<(Option<AccountId>, u64)>| {
+                let (after, processed) = state?;
+                let accounts = match accounts_repo.list_by_currency_after(currency, after, batch_size) {
+                    Ok(accounts) => accounts,
+                    Err(e) => return Some(future::err(ectx!(err e, ErrorKind::Internal => currency, after, batch_size))),
+                };
+                if accounts.is_empty() {
+                    return None;
+                }
+                let balances = match transactions_repo.get_accounts_balance(system_user_id, &accounts) {
+                    Ok(balances) => balances,
+                    Err(e) => return Some(future::err(ectx!(err e, ErrorKind::Internal => currency, after))),
+                };
+                let processed = processed + balances.len() as u64;
+                let next_state = if (accounts.len() as i64) < batch_size {
+                    None
+                } else {
+                    accounts.last().map(|account| (Some(account.id), processed))
+                };
+                let progress = RecomputeProgress {
+                    currency,
+                    processed,
+                    balances,
+                };
+                Some(future::ok((progress, next_state)))
+            },
+        ))
+    }
+
+    fn reconcile(
+        &self,
+        currency: Currency,
+        blockchain_balances: &HashMap<(BlockchainAddress, Currency), Amount>,
+    ) -> Result<ReconciliationReport, Error> {
+        let mut balances: Vec<ReconciledBalance> = vec![];
+        for account in self.get_system_accounts(currency)? {
+            let account_id = account.id;
+            let ledger_value = self
+                .transactions_repo
+                .get_account_balance(account_id, account.kind)
+                .map_err(ectx!(try ErrorKind::Internal => account_id))?;
+            // several system accounts may share the same address, so they are compared together
+            if let Some(balance) = balances.iter_mut().find(|balance| balance.address == account.address) {
+                balance.account_ids.push(account_id);
+                balance.ledger_value = balance
+                    .ledger_value
+                    .checked_add(ledger_value)
+                    .ok_or(ectx!(try err ErrorContext::BalanceOverflow, ErrorKind::Internal => account_id))?;
+                continue;
+            }
+            let blockchain_value = blockchain_balances
+                .get(&(account.address.clone(), currency))
+                .cloned()
+                .unwrap_or(Amount::new(0));
+            balances.push(ReconciledBalance {
+                address: account.address,
+                account_ids: vec![account_id],
+                ledger_value,
+                blockchain_value,
+            });
+        }
+        let discrepancies = balances.iter().filter(|balance| balance.is_diverging()).cloned().collect();
+        Ok(ReconciliationReport {
+            currency,
+            balances,
+            discrepancies,
+        })
+    }
+
+    fn fees_collected(&self, currency: Currency, from: NaiveDateTime, to: NaiveDateTime) -> Result<FeesCollected, Error> {
+        // users pay fees to the fees account, and network fees are paid out of it - either to its dr counterpart
+        // or, for btc and eth, to the account the withdrawal was sent from, so they are summed by the fees account
+        let fees_account_id = self.get_system_fees_account(currency)?.id;
+        let service_fees = self
+            .transactions_repo
+            .sum_credits(fees_account_id, TransactionKind::Fee, from, to)
+            .map_err(ectx!(try ErrorKind::Internal => fees_account_id, from, to))?;
+        let blockchain_fees = self
+            .transactions_repo
+            .sum_debits(fees_account_id, TransactionKind::BlockchainFee, from, to)
+            .map_err(ectx!(try ErrorKind::Internal => fees_account_id, from, to))?;
+        Ok(FeesCollected {
+            currency,
+            from,
+            to,
+            service_fees,
+            blockchain_fees,
+        })
+    }
+
+    fn find_accounts_by_address(&self, address: BlockchainAddress) -> Result<Vec<Account>, Error> {
+        // addresses are stored normalized, which depends on currency, so both forms are looked up
+        let mut addresses = vec![address.normalized(Currency::Btc), address.normalized(Currency::Eth)];
+        addresses.dedup();
+        let mut accounts = vec![];
+        for address in addresses {
+            let found = self
+                .accounts_repo
+                .filter_by_address(address.clone())
+                .map_err(ectx!(try ErrorKind::Internal => address))?;
+            for account in found {
+                if !accounts.contains(&account) {
+                    accounts.push(account);
+                }
+            }
+        }
+        Ok(accounts)
+    }
+
+    fn find_orphaned_withdrawals(&self, limit: i64) -> Result<Vec<Transaction>, Error> {
+        self.transactions_repo
+            .list_orphaned_withdrawals(limit)
+            .map_err(ectx!(ErrorKind::Internal => limit))
+    }
+
+    fn recover_orphaned_pending(
+        &self,
+        hash: BlockchainTransactionId,
+        blockchain_tx: BlockchainTransaction,
+    ) -> Result<PendingBlockchainTransactionDB, Error> {
+        let hash_clone = hash.clone();
+        if let Some(pending) = self
+            .pending_blockchain_transactions_repo
+            .get(hash.clone())
+            .map_err(ectx!(try ErrorKind::Internal => hash_clone))?
+        {
+            return Ok(pending);
+        }
+        let hash_clone = hash.clone();
+        let tx = self
+            .transactions_repo
+            .get_by_blockchain_tx(hash.clone())
+            .map_err(ectx!(try ErrorKind::Internal => hash_clone))?
+            .ok_or(ectx!(try err ErrorContext::NoTransaction, ErrorKind::NotFound => hash))?;
+        if tx.kind != TransactionKind::Withdrawal || tx.status != TransactionStatus::Pending {
+            return Err(ectx!(err ErrorContext::InvalidTransactionStructure, ErrorKind::MalformedInput => tx));
+        }
+        let from_account_id = tx.cr_account_id;
+        let from_account = self
+            .accounts_repo
+            .get(from_account_id)
+            .map_err(ectx!(try ErrorKind::Internal => from_account_id))?
+            .ok_or(ectx!(try err ErrorContext::NoAccount, ErrorKind::Internal => from_account_id))?;
+        // stq withdrawals move the tokens of the approved user address with `transferFrom`
+        let erc20_operation_kind = match tx.currency {
+            Currency::Stq => Some(Erc20OperationKind::TransferFrom),
+            _ => None,
+        };
+        if blockchain_tx.erc20_operation_kind.is_some() && blockchain_tx.erc20_operation_kind != erc20_operation_kind {
+            return Err(ectx!(err ErrorContext::InvalidBlockchainTransactionStructure, ErrorKind::MalformedInput => tx, blockchain_tx));
+        }
+        // withdrawals record their destination, older legs don't. For them the output paying the withdrawn value
+        // is taken only if there's no other one - change outputs are dropped by normalizing
+        let recorded_to = tx.meta["to"]
+            .as_str()
+            .map(|to| BlockchainAddress::new(to.to_string()).normalized(tx.currency));
+        let outputs: Vec<_> = blockchain_tx
+            .with_normalized_addresses()
+            .normalized()
+            .filter(|normalized| normalized.currency == tx.currency && normalized.from == vec![from_account.address.clone()])
+            .map(|normalized| normalized.to)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|entry| entry.value == tx.value && recorded_to.as_ref().map(|to| *to == entry.address).unwrap_or(true))
+            .collect();
+        if outputs.len() != 1 {
+            return Err(ectx!(err ErrorContext::InvalidBlockchainTransactionStructure, ErrorKind::MalformedInput => tx, blockchain_tx));
+        }
+        let to = outputs[0].clone();
+        let new_pending = NewPendingBlockchainTransactionDB {
+            hash,
+            from_: from_account.address,
+            to_: to.address,
+            currency: tx.currency,
+            value: tx.value,
+            fee: blockchain_tx.fee,
+            erc20_operation_kind,
+            // signed transaction is only known at the moment it's posted
+            raw_tx: None,
+        };
+        self.pending_blockchain_transactions_repo
+            .create(new_pending.clone())
+            .map_err(ectx!(ErrorKind::Internal => new_pending))
+    }
+
+    fn fail_dropped_withdrawal(&self, hash: BlockchainTransactionId) -> Result<Vec<Transaction>, Error> {
+        let hash_clone = hash.clone();
+        let tx = self
+            .transactions_repo
+            .get_by_blockchain_tx(hash.clone())
+            .map_err(ectx!(try ErrorKind::Internal => hash_clone))?
+            .ok_or(ectx!(try err ErrorContext::NoTransaction, ErrorKind::NotFound => hash))?;
+        if tx.kind != TransactionKind::Withdrawal || tx.status != TransactionStatus::Pending {
+            return Err(ectx!(err ErrorContext::InvalidTransactionStructure, ErrorKind::MalformedInput => tx));
+        }
+        warn!("Withdrawal blockchain transaction {} was dropped", hash);
+        let hash_clone = hash.clone();
+        self.transactions_repo
+            .update_status_failed(hash.clone(), DROPPED_FAILURE_REASON.to_string())
+            .map_err(ectx!(try ErrorKind::Internal => hash_clone))?;
+        let hash_clone = hash.clone();
+        self.pending_blockchain_transactions_repo
+            .delete(hash)
+            .map_err(ectx!(try ErrorKind::Internal => hash_clone))?;
+        let gid = tx.gid;
+        let group = self
+            .transactions_repo
+            .get_by_gid(gid)
+            .map_err(ectx!(try ErrorKind::Internal => gid))?;
+        // split withdrawal pays one fee for all its blockchain transactions, so it is kept while any of them is sent
+        let nothing_sent = group
+            .iter()
+            .filter(|leg| leg.kind == TransactionKind::Withdrawal)
+            .all(|leg| leg.status == TransactionStatus::Failed);
+        if nothing_sent {
+            for fee_tx in group
+                .iter()
+                .filter(|leg| leg.kind == TransactionKind::Fee && leg.status != TransactionStatus::Failed)
+            {
+                let fee_tx_id = fee_tx.id;
+                self.transactions_repo
+                    .update_status_by_id(fee_tx_id, TransactionStatus::Failed)
+                    .map_err(ectx!(try ErrorKind::Internal => fee_tx_id))?;
+            }
+        }
+        self.transactions_repo.get_by_gid(gid).map_err(ectx!(ErrorKind::Internal => gid))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+    use repos::*;
+    use services::*;
+    use std::collections::HashSet;
+
+    fn create_system_service(
+        accounts_repo: Arc<AccountsRepoMock>,
+        transactions_repo: Arc<TransactionsRepoMock>,
+        clock: Arc<MockClock>,
+    ) -> SystemServiceImpl {
+        let config = Arc::new(Config::new().unwrap());
+        SystemServiceImpl::new(
+            accounts_repo,
+            transactions_repo,
+            Arc::new(PendingBlockchainTransactionsRepoMock::default()),
+            config,
+            clock,
+        )
+    }
+
+    #[test]
+    fn test_process_due_transactions() {
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let clock = Arc::new(MockClock::default());
+        let service = create_system_service(accounts_repo.clone(), transactions_repo.clone(), clock.clone());
+        let from = accounts_repo.create(NewAccount::default()).unwrap();
+        let to = accounts_repo.create(NewAccount::default()).unwrap();
+        let now = clock.now();
+
+        let mut deposit = NewTransaction::default();
+        deposit.cr_account_id = from.id;
+        deposit.value = Amount::new(100);
+        deposit.status = TransactionStatus::Done;
+        transactions_repo.create(deposit).unwrap();
+
+        let mut scheduled = NewTransaction::default();
+        scheduled.dr_account_id = from.id;
+        scheduled.cr_account_id = to.id;
+        scheduled.value = Amount::new(70);
+        scheduled.status = TransactionStatus::Scheduled;
+        scheduled.hold_until = Some(now + Duration::hours(1));
+        let scheduled = transactions_repo.create(scheduled).unwrap();
+
+        // scheduled transfer doesn't move funds until it is due
+        assert_eq!(
+            transactions_repo.get_account_balance(from.id, AccountKind::Cr).unwrap(),
+            Amount::new(100)
+        );
+        assert_eq!(
+            transactions_repo.get_account_balance(to.id, AccountKind::Cr).unwrap(),
+            Amount::new(0)
+        );
+        let res = service.process_due_transactions().unwrap();
+        assert!(res.is_empty());
+
+        clock.advance(Duration::hours(2));
+        let res = service.process_due_transactions().unwrap();
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].id, scheduled.id);
+        assert_eq!(res[0].status, TransactionStatus::Done);
+        assert_eq!(
+            transactions_repo.get_account_balance(from.id, AccountKind::Cr).unwrap(),
+            Amount::new(30)
+        );
+        assert_eq!(
+            transactions_repo.get_account_balance(to.id, AccountKind::Cr).unwrap(),
+            Amount::new(70)
+        );
+
+        // already executed transfer is not processed again
+        clock.advance(Duration::hours(1));
+        let res = service.process_due_transactions().unwrap();
+        assert!(res.is_empty());
+    }
+
+    #[test]
+    fn test_process_due_transactions_not_enough_balance() {
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let clock = Arc::new(MockClock::default());
+        let service = create_system_service(accounts_repo.clone(), transactions_repo.clone(), clock.clone());
+        let from = accounts_repo.create(NewAccount::default()).unwrap();
+        let to = accounts_repo.create(NewAccount::default()).unwrap();
+        let now = clock.now();
+
+        let mut scheduled = NewTransaction::default();
+        scheduled.dr_account_id = from.id;
+        scheduled.cr_account_id = to.id;
+        scheduled.value = Amount::new(70);
+        scheduled.status = TransactionStatus::Scheduled;
+        scheduled.hold_until = Some(now);
+        let scheduled = transactions_repo.create(scheduled).unwrap();
+
+        let mut orphaned = NewTransaction::default();
+        orphaned.value = Amount::new(10);
+        orphaned.status = TransactionStatus::Scheduled;
+        orphaned.hold_until = Some(now);
+        let orphaned = transactions_repo.create(orphaned).unwrap();
+
+        let res = service.process_due_transactions().unwrap();
+        assert!(res.is_empty());
+        assert_eq!(
+            transactions_repo.get_account_balance(to.id, AccountKind::Cr).unwrap(),
+            Amount::new(0)
+        );
+        // neither the underfunded transfer nor the one without source account is retried forever
+        let scheduled = transactions_repo.get(scheduled.id).unwrap().unwrap();
+        assert_eq!(scheduled.status, TransactionStatus::Cancelled);
+        let history = transactions_repo.get_status_history(&[scheduled.id]).unwrap();
+        assert_eq!(history.last().map(|change| change.status), Some(TransactionStatus::Cancelled));
+        let orphaned = transactions_repo.get(orphaned.id).unwrap().unwrap();
+        assert_eq!(orphaned.status, TransactionStatus::Cancelled);
+
+        // funded transfers of the batch are still executed
+        let mut deposit = NewTransaction::default();
+        deposit.cr_account_id = from.id;
+        deposit.value = Amount::new(100);
+        deposit.status = TransactionStatus::Done;
+        transactions_repo.create(deposit).unwrap();
+        let mut funded = NewTransaction::default();
+        funded.dr_account_id = from.id;
+        funded.cr_account_id = to.id;
+        funded.value = Amount::new(70);
+        funded.status = TransactionStatus::Scheduled;
+        funded.hold_until = Some(now);
+        let funded = transactions_repo.create(funded).unwrap();
+        let res = service.process_due_transactions().unwrap();
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].id, funded.id);
+    }
+
+    #[test]
+    fn test_validate_system_accounts_missing_liquidity() {
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let clock = Arc::new(MockClock::default());
+        let service = create_system_service(accounts_repo.clone(), transactions_repo.clone(), clock.clone());
+        let system = Config::new().unwrap().system;
+        let accounts = vec![
+            (system.btc_transfer_account_id, Currency::Btc),
+            (system.eth_transfer_account_id, Currency::Eth),
+            (system.stq_transfer_account_id, Currency::Stq),
+            (system.eth_liquidity_account_id, Currency::Eth),
+            (system.stq_liquidity_account_id, Currency::Stq),
+            (system.btc_fees_account_id, Currency::Btc),
+            (system.eth_fees_account_id, Currency::Eth),
+            (system.stq_fees_account_id, Currency::Stq),
+        ];
+        for (id, currency) in accounts {
+            let mut new_account = NewAccount::default();
+            new_account.id = id;
+            new_account.currency = currency;
+            new_account.kind = AccountKind::Cr;
+            accounts_repo.create(new_account).unwrap();
+            let mut new_dr_account = NewAccount::default();
+            new_dr_account.id = id.derive_system_dr_id();
+            new_dr_account.currency = currency;
+            new_dr_account.kind = AccountKind::Dr;
+            accounts_repo.create(new_dr_account).unwrap();
+        }
+
+        match service.validate_system_accounts().unwrap_err().kind() {
+            ErrorKind::Internal => (),
+            _ => panic!("expected internal error on missing liquidity account"),
+        }
+        match service.get_system_liquidity_account(Currency::Btc).unwrap_err().kind() {
+            ErrorKind::Internal => (),
+            _ => panic!("expected internal error on missing liquidity account"),
+        }
+        assert!(service.get_system_liquidity_account(Currency::Eth).is_ok());
+    }
+
+    #[test]
+    fn test_select_system_liquidity_account() {
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let mut config = Config::new().unwrap();
+        let main_id = config.system.btc_liquidity_account_id;
+        let extra_id = AccountId::generate();
+        config.system.btc_extra_liquidity_account_ids = vec![extra_id];
+        let service = SystemServiceImpl::new(
+            accounts_repo.clone(),
+            transactions_repo.clone(),
+            Arc::new(PendingBlockchainTransactionsRepoMock::default()),
+            Arc::new(config),
+            Arc::new(MockClock::default()),
+        );
+        for (id, value) in vec![(main_id, 100), (extra_id, 300)] {
+            let mut new_account = NewAccount::default();
+            new_account.id = id;
+            new_account.currency = Currency::Btc;
+            new_account.kind = AccountKind::Cr;
+            accounts_repo.create(new_account).unwrap();
+            let mut new_transaction = NewTransaction::default();
+            new_transaction.cr_account_id = id;
+            new_transaction.currency = Currency::Btc;
+            new_transaction.value = Amount::new(value);
+            transactions_repo.create(new_transaction).unwrap();
+        }
+
+        let pool: Vec<AccountId> = service
+            .get_system_liquidity_accounts(Currency::Btc)
+            .unwrap()
+            .into_iter()
+            .map(|account| account.id)
+            .collect();
+        assert_eq!(pool, vec![main_id, extra_id]);
+        // only the extra account holds the value
+        for _ in 0..20 {
+            let account = service
+                .select_system_liquidity_account(Currency::Btc, Amount::new(200), true)
+                .unwrap();
+            assert_eq!(account.id, extra_id);
+        }
+        // nobody holds the value, the richest one is picked to be reported short of funds
+        let account = service
+            .select_system_liquidity_account(Currency::Btc, Amount::new(500), true)
+            .unwrap();
+        assert_eq!(account.id, extra_id);
+        // otherwise the pick is spread over the pool
+        let mut paying: HashSet<AccountId> = HashSet::new();
+        let mut receiving: HashSet<AccountId> = HashSet::new();
+        for _ in 0..100 {
+            paying.insert(
+                service
+                    .select_system_liquidity_account(Currency::Btc, Amount::new(50), true)
+                    .unwrap()
+                    .id,
+            );
+            receiving.insert(
+                service
+                    .select_system_liquidity_account(Currency::Btc, Amount::new(500), false)
+                    .unwrap()
+                    .id,
+            );
+        }
+        assert_eq!(paying, vec![main_id, extra_id].into_iter().collect());
+        assert_eq!(receiving, vec![main_id, extra_id].into_iter().collect());
+    }
+
+    #[test]
+    fn test_recompute_account_balance() {
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let clock = Arc::new(MockClock::default());
+        let service = create_system_service(accounts_repo.clone(), transactions_repo.clone(), clock.clone());
+        let account = accounts_repo.create(NewAccount::default()).unwrap();
+        let other = accounts_repo.create(NewAccount::default()).unwrap();
+
+        for (dr_account_id, cr_account_id, value, status) in vec![
+            (other.id, account.id, 100, TransactionStatus::Done),
+            (other.id, account.id, 50, TransactionStatus::Pending),
+            (account.id, other.id, 30, TransactionStatus::Done),
+            (account.id, other.id, 20, TransactionStatus::Failed),
+            (account.id, other.id, 10, TransactionStatus::Scheduled),
+        ] {
+            let mut new_transaction = NewTransaction::default();
+            new_transaction.dr_account_id = dr_account_id;
+            new_transaction.cr_account_id = cr_account_id;
+            new_transaction.value = Amount::new(value);
+            new_transaction.status = status;
+            transactions_repo.create(new_transaction).unwrap();
+        }
+
+        assert_eq!(service.recompute_account_balance(account.id).unwrap(), Amount::new(120));
+        match service.recompute_account_balance(AccountId::generate()).unwrap_err().kind() {
+            ErrorKind::NotFound => (),
+            _ => panic!("expected not found error for unknown account"),
+        }
+    }
+
+    #[test]
+    fn test_recompute_all_balances() {
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let clock = Arc::new(MockClock::default());
+        let service = create_system_service(accounts_repo.clone(), transactions_repo.clone(), clock.clone());
+        let source = accounts_repo
+            .create(NewAccount {
+                currency: Currency::Eth,
+                ..Default::default()
+            })
+            .unwrap();
+        let mut expected = HashMap::new();
+        for value in 1..6 {
+            let account = accounts_repo
+                .create(NewAccount {
+                    currency: Currency::Btc,
+                    ..Default::default()
+                })
+                .unwrap();
+            let mut new_transaction = NewTransaction::default();
+            new_transaction.dr_account_id = source.id;
+            new_transaction.cr_account_id = account.id;
+            new_transaction.value = Amount::new(value);
+            transactions_repo.create(new_transaction).unwrap();
+            expected.insert(account.id, Amount::new(value));
+        }
+
+        let progress = service.recompute_all_balances(Currency::Btc, 2).collect().wait().unwrap();
+        assert_eq!(progress.iter().map(|batch| batch.processed).collect::<Vec<_>>(), vec![2, 4, 5]);
+        let balances: HashMap<AccountId, Amount> = progress
+            .into_iter()
+            .flat_map(|batch| batch.balances.into_iter())
+            .map(|balance| (balance.account.id, balance.balance))
+            .collect();
+        assert_eq!(balances, expected);
+
+        // batch size dividing the number of accounts ends with the last full batch
+        let progress = service.recompute_all_balances(Currency::Btc, 5).collect().wait().unwrap();
+        assert_eq!(progress.len(), 1);
+        assert_eq!(progress[0].processed, 5);
+    }
+
+    #[test]
+    fn test_fees_collected() {
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let clock = Arc::new(MockClock::default());
+        let service = create_system_service(accounts_repo.clone(), transactions_repo.clone(), clock.clone());
+        let fees_account_id = Config::new().unwrap().system.eth_fees_account_id;
+        let fees_account_dr_id = fees_account_id.derive_system_dr_id();
+        for (id, kind) in vec![(fees_account_id, AccountKind::Cr), (fees_account_dr_id, AccountKind::Dr)] {
+            let mut new_account = NewAccount::default();
+            new_account.id = id;
+            new_account.currency = Currency::Eth;
+            new_account.kind = kind;
+            accounts_repo.create(new_account).unwrap();
+        }
+        let user_account = accounts_repo.create(NewAccount::default()).unwrap();
+        let mut withdrawal_account = NewAccount::default();
+        withdrawal_account.currency = Currency::Eth;
+        withdrawal_account.kind = AccountKind::Dr;
+        let withdrawal_account = accounts_repo.create(withdrawal_account).unwrap();
+
+        for (dr_account_id, cr_account_id, kind, value, status) in vec![
+            (user_account.id, fees_account_id, TransactionKind::Fee, 100, TransactionStatus::Done),
+            (user_account.id, fees_account_id, TransactionKind::Fee, 50, TransactionStatus::Done),
+            (
+                fees_account_id,
+                fees_account_dr_id,
+                TransactionKind::BlockchainFee,
+                30,
+                TransactionStatus::Done,
+            ),
+            // eth withdrawal pays the network fee from the account it was sent from
+            (
+                fees_account_id,
+                withdrawal_account.id,
+                TransactionKind::BlockchainFee,
+                20,
+                TransactionStatus::Done,
+            ),
+            // not fees
+            (
+                user_account.id,
+                fees_account_id,
+                TransactionKind::Internal,
+                1_000,
+                TransactionStatus::Done,
+            ),
+            (fees_account_id, user_account.id, TransactionKind::Fee, 10, TransactionStatus::Done),
+            // not collected yet or never collected
+            (
+                user_account.id,
+                fees_account_id,
+                TransactionKind::Fee,
+                7,
+                TransactionStatus::Pending,
+            ),
+            (user_account.id, fees_account_id, TransactionKind::Fee, 5, TransactionStatus::Failed),
+        ] {
+            let mut new_transaction = NewTransaction::default();
+            new_transaction.dr_account_id = dr_account_id;
+            new_transaction.cr_account_id = cr_account_id;
+            new_transaction.kind = kind;
+            new_transaction.currency = Currency::Eth;
+            new_transaction.value = Amount::new(value);
+            new_transaction.status = status;
+            transactions_repo.create(new_transaction).unwrap();
+        }
+
+        let now = ::chrono::Utc::now().naive_utc();
+        let fees = service
+            .fees_collected(Currency::Eth, now - Duration::hours(1), now + Duration::hours(1))
+            .unwrap();
+        assert_eq!(fees.service_fees, Amount::new(150));
+        assert_eq!(fees.blockchain_fees, Amount::new(50));
+
+        // legs outside of the window are not counted
+        let fees = service
+            .fees_collected(Currency::Eth, now + Duration::hours(1), now + Duration::hours(2))
+            .unwrap();
+        assert_eq!(fees.service_fees, Amount::new(0));
+        assert_eq!(fees.blockchain_fees, Amount::new(0));
+    }
+
+    #[test]
+    fn test_recover_orphaned_pending() {
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let clock = Arc::new(MockClock::default());
+        let service = create_system_service(accounts_repo.clone(), transactions_repo.clone(), clock.clone());
+        let mut new_account = NewAccount::default();
+        new_account.address = BlockchainAddress::new("from_address".to_string());
+        let from = accounts_repo.create(new_account).unwrap();
+        let user_account = accounts_repo.create(NewAccount::default()).unwrap();
+        let hash = BlockchainTransactionId::new("orphaned".to_string());
+        let mut withdrawal = NewTransaction::default();
+        withdrawal.dr_account_id = user_account.id;
+        withdrawal.cr_account_id = from.id;
+        withdrawal.kind = TransactionKind::Withdrawal;
+        withdrawal.status = TransactionStatus::Pending;
+        withdrawal.currency = Currency::Btc;
+        withdrawal.value = Amount::new(100);
+        withdrawal.blockchain_tx_id = Some(hash.clone());
+        withdrawal.meta = Some(json!({ "to": "to_address" }));
+        transactions_repo.create(withdrawal).unwrap();
+        let to = BlockchainAddress::new("to_address".to_string());
+        let blockchain_tx = BlockchainTransaction {
+            hash: hash.clone(),
+            from: vec![from.address.clone()],
+            to: vec![
+                // another output of a batch, paying the same value
+                BlockchainTransactionEntryTo {
+                    address: BlockchainAddress::new("other_address".to_string()),
+                    value: Amount::new(100),
+                },
+                BlockchainTransactionEntryTo {
+                    address: to.clone(),
+                    value: Amount::new(100),
+                },
+                // change
+                BlockchainTransactionEntryTo {
+                    address: from.address.clone(),
+                    value: Amount::new(50),
+                },
+            ],
+            currency: Currency::Btc,
+            fee: Amount::new(10),
+            ..Default::default()
+        };
+
+        let pending = service.recover_orphaned_pending(hash.clone(), blockchain_tx.clone()).unwrap();
+        assert_eq!(pending.from_, from.address);
+        assert_eq!(pending.to_, to);
+        assert_eq!(pending.value, Amount::new(100));
+        assert_eq!(pending.fee, Amount::new(10));
+        assert_eq!(pending.erc20_operation_kind, None);
+        let stored = service.pending_blockchain_transactions_repo.get(hash.clone()).unwrap().unwrap();
+        assert_eq!(stored.to_, to);
+        // recovering again returns the restored transaction
+        let again = service.recover_orphaned_pending(hash, blockchain_tx).unwrap();
+        assert_eq!(again.created_at, pending.created_at);
+    }
+
+    #[test]
+    fn test_recover_orphaned_pending_stq() {
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let clock = Arc::new(MockClock::default());
+        let service = create_system_service(accounts_repo.clone(), transactions_repo.clone(), clock.clone());
+        let mut new_account = NewAccount::default();
+        new_account.currency = Currency::Stq;
+        new_account.address = BlockchainAddress::new("0xfrom".to_string());
+        let from = accounts_repo.create(new_account).unwrap();
+        let hash = BlockchainTransactionId::new("orphaned".to_string());
+        let mut withdrawal = NewTransaction::default();
+        withdrawal.cr_account_id = from.id;
+        withdrawal.kind = TransactionKind::Withdrawal;
+        withdrawal.status = TransactionStatus::Pending;
+        withdrawal.currency = Currency::Stq;
+        withdrawal.value = Amount::new(100);
+        withdrawal.blockchain_tx_id = Some(hash.clone());
+        // recorded as the user typed it
+        withdrawal.meta = Some(json!({ "to": "0xAbC" }));
+        transactions_repo.create(withdrawal).unwrap();
+        let blockchain_tx = BlockchainTransaction {
+            hash: hash.clone(),
+            from: vec![BlockchainAddress::new("0xFROM".to_string())],
+            to: vec![BlockchainTransactionEntryTo {
+                address: BlockchainAddress::new("0xABC".to_string()),
+                value: Amount::new(100),
+            }],
+            currency: Currency::Stq,
+            erc20_operation_kind: Some(Erc20OperationKind::TransferFrom),
+            ..Default::default()
+        };
+
+        let pending = service.recover_orphaned_pending(hash.clone(), blockchain_tx.clone()).unwrap();
+        assert_eq!(pending.to_, BlockchainAddress::new("0xabc".to_string()));
+        assert_eq!(pending.erc20_operation_kind, Some(Erc20OperationKind::TransferFrom));
+
+        // approval sent from the same address is not the withdrawal
+        service.pending_blockchain_transactions_repo.delete(hash.clone()).unwrap();
+        let approval = BlockchainTransaction {
+            erc20_operation_kind: Some(Erc20OperationKind::Approve),
+            ..blockchain_tx
+        };
+        match service.recover_orphaned_pending(hash, approval).unwrap_err().kind() {
+            ErrorKind::MalformedInput => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn test_recover_orphaned_pending_mismatch() {
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let clock = Arc::new(MockClock::default());
+        let service = create_system_service(accounts_repo.clone(), transactions_repo.clone(), clock.clone());
+        let mut new_account = NewAccount::default();
+        new_account.address = BlockchainAddress::new("from_address".to_string());
+        let from = accounts_repo.create(new_account).unwrap();
+        let hash = BlockchainTransactionId::new("orphaned".to_string());
+        let blockchain_tx = BlockchainTransaction {
+            hash: hash.clone(),
+            from: vec![from.address.clone()],
+            to: vec![BlockchainTransactionEntryTo {
+                address: BlockchainAddress::new("to_address".to_string()),
+                value: Amount::new(100),
+            }],
+            currency: Currency::Btc,
+            ..Default::default()
+        };
+
+        // no withdrawal posted with the hash
+        match service
+            .recover_orphaned_pending(hash.clone(), blockchain_tx.clone())
+            .unwrap_err()
+            .kind()
+        {
+            ErrorKind::NotFound => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+
+        let mut withdrawal = NewTransaction::default();
+        withdrawal.cr_account_id = from.id;
+        withdrawal.kind = TransactionKind::Withdrawal;
+        withdrawal.status = TransactionStatus::Pending;
+        withdrawal.currency = Currency::Btc;
+        withdrawal.value = Amount::new(70);
+        withdrawal.blockchain_tx_id = Some(hash.clone());
+        transactions_repo.create(withdrawal).unwrap();
+        // chain transaction doesn't pay the withdrawn value
+        match service
+            .recover_orphaned_pending(hash.clone(), blockchain_tx.clone())
+            .unwrap_err()
+            .kind()
+        {
+            ErrorKind::MalformedInput => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+
+        // withdrawal without recorded destination, and two outputs paying its value
+        let ambiguous = BlockchainTransaction {
+            to: vec![
+                BlockchainTransactionEntryTo {
+                    address: BlockchainAddress::new("to_address".to_string()),
+                    value: Amount::new(70),
+                },
+                BlockchainTransactionEntryTo {
+                    address: BlockchainAddress::new("other_address".to_string()),
+                    value: Amount::new(70),
+                },
+            ],
+            ..blockchain_tx.clone()
+        };
+        match service.recover_orphaned_pending(hash.clone(), ambiguous).unwrap_err().kind() {
+            ErrorKind::MalformedInput => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+        assert!(service.pending_blockchain_transactions_repo.get(hash).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_fail_dropped_withdrawal() {
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let clock = Arc::new(MockClock::default());
+        let service = create_system_service(accounts_repo.clone(), transactions_repo.clone(), clock.clone());
+        let user_account = accounts_repo.create(NewAccount::default()).unwrap();
+        let withdrawal_account = accounts_repo.create(NewAccount::default()).unwrap();
+        let fees_account = accounts_repo.create(NewAccount::default()).unwrap();
+        let mut deposit = NewTransaction::default();
+        deposit.cr_account_id = user_account.id;
+        deposit.value = Amount::new(1_000);
+        deposit.status = TransactionStatus::Done;
+        transactions_repo.create(deposit).unwrap();
+        // split withdrawal with two blockchain transactions and one fee
+        let gid = TransactionId::generate();
+        let hashes = vec![
+            BlockchainTransactionId::new("dropped_1".to_string()),
+            BlockchainTransactionId::new("dropped_2".to_string()),
+        ];
+        for hash in &hashes {
+            let mut withdrawal = NewTransaction::default();
+            withdrawal.gid = gid;
+            withdrawal.dr_account_id = user_account.id;
+            withdrawal.cr_account_id = withdrawal_account.id;
+            withdrawal.kind = TransactionKind::Withdrawal;
+            withdrawal.status = TransactionStatus::Pending;
+            withdrawal.value = Amount::new(300);
+            withdrawal.blockchain_tx_id = Some(hash.clone());
+            transactions_repo.create(withdrawal).unwrap();
+        }
+        let mut fee = NewTransaction::default();
+        fee.gid = gid;
+        fee.dr_account_id = user_account.id;
+        fee.cr_account_id = fees_account.id;
+        fee.kind = TransactionKind::Fee;
+        fee.status = TransactionStatus::Done;
+        fee.value = Amount::new(100);
+        transactions_repo.create(fee).unwrap();
+
+        // fee is kept while the other blockchain transaction is still sent
+        service.fail_dropped_withdrawal(hashes[0].clone()).unwrap();
+        let balance = transactions_repo.get_account_balance(user_account.id, AccountKind::Cr).unwrap();
+        assert_eq!(balance, Amount::new(600));
+
+        let group = service.fail_dropped_withdrawal(hashes[1].clone()).unwrap();
+        assert!(group.iter().all(|tx| tx.status == TransactionStatus::Failed));
+        let balance = transactions_repo.get_account_balance(user_account.id, AccountKind::Cr).unwrap();
+        assert_eq!(balance, Amount::new(1_000));
+
+        // already failed
+        match service.fail_dropped_withdrawal(hashes[1].clone()).unwrap_err().kind() {
+            ErrorKind::MalformedInput => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+    }
 }
@@ -3,7 +3,10 @@ use std::sync::Arc;
 use chrono::{Duration as ChronoDuration, Utc};
 use future::Either;
 use futures::IntoFuture;
+use serde_json;
+use validator::{ValidationError, ValidationErrors};
 
+use super::super::clock::Clock;
 use super::super::error::*;
 use super::super::system::SystemService;
 use client::{BlockchainClient, ExchangeClient, KeysClient};
@@ -11,15 +14,39 @@ use config::Config;
 use models::*;
 use prelude::*;
 use repos::{DbExecutor, KeyValuesRepo, PendingBlockchainTransactionsRepo};
-use utils::log_and_capture_error;
+use utils::{log_and_capture_error, log_warn};
+
+/// Approximate size in bytes added to bitcoin transaction by each additional output
+pub const BITCOIN_OUTPUT_SIZE: usize = 34;
+/// Approximate size in bytes of a signed p2pkh input, the largest one keys service produces
+const BITCOIN_INPUT_SIZE: usize = 148;
+/// Version, locktime and input / output counts
+const BITCOIN_TX_OVERHEAD_SIZE: usize = 10;
 
 pub struct FeeEstimate {
     pub gross_fee: Amount,
     pub fee_price: f64,
     pub currency: Currency,
+    /// Fee price computed from the input fee was below the configured minimum and was raised to it
+    pub floor_applied: bool,
+    /// Fee was converted with a cached rate, because exchange was unavailable
+    pub stale_rate: bool,
+    /// Slow, normal and fast options derived from `fee_price`
+    pub tiers: Vec<FeeTierEstimate>,
+    /// `gross_fee` converted into the withdrawal currency, e.g. stq equivalent of the eth fee of stq withdrawal.
+    /// Informational only, the fee is charged in `currency`. Set if asked for and the rate is available
+    pub fee_in_withdrawal_currency: Option<Amount>,
+}
+
+pub struct FeeTierEstimate {
+    pub tier: FeeTier,
+    pub gross_fee: Amount,
+    pub fee_price: f64,
+    pub eta_secs: u64,
 }
 
 pub trait BlockchainService: Send + Sync + 'static {
+    /// `fee_price` might be raised a bit, so that the fee absorbs change below the dust threshold
     fn create_bitcoin_tx(
         &self,
         from: BlockchainAddress,
@@ -27,6 +54,13 @@ pub trait BlockchainService: Send + Sync + 'static {
         value: Amount,
         fee_price: f64,
     ) -> Box<Future<Item = BlockchainTransactionId, Error = Error> + Send>;
+    /// Sends one bitcoin transaction with an output per recipient, so that the network fee is paid once
+    fn create_batch_bitcoin_tx(
+        &self,
+        from: BlockchainAddress,
+        outputs: Vec<BlockchainTxOutput>,
+        fee_price: f64,
+    ) -> Box<Future<Item = BlockchainTransactionId, Error = Error> + Send>;
     fn create_ethereum_tx(
         &self,
         from: BlockchainAddress,
@@ -34,13 +68,33 @@ pub trait BlockchainService: Send + Sync + 'static {
         value: Amount,
         fee_price: f64,
         currency: Currency,
+        gas_limit_override: Option<u64>,
     ) -> Box<Future<Item = BlockchainTransactionId, Error = Error> + Send>;
     fn estimate_withdrawal_fee(
         &self,
         input_gross_fee: Amount,
         input_fee_currency: Currency,
         withdrawal_currency: Currency,
+        gas_limit_override: Option<u64>,
+        with_fee_in_withdrawal_currency: bool,
     ) -> Box<Future<Item = FeeEstimate, Error = Error> + Send>;
+    /// Fee in `fee_currency` that pays for a withdrawal with the configured fee price,
+    /// i.e. the inverse of `estimate_withdrawal_fee` for default fee price
+    fn estimate_default_withdrawal_fee(
+        &self,
+        fee_currency: Currency,
+        withdrawal_currency: Currency,
+    ) -> Box<Future<Item = Amount, Error = Error> + Send>;
+    /// Gas limit for eth / stq withdrawal - user supplied override if any, otherwise the node estimate
+    /// if it's enabled in config. `None` means that static gas limits from config are used
+    fn estimate_gas_limit(
+        &self,
+        from: BlockchainAddress,
+        to: BlockchainAddress,
+        value: Amount,
+        currency: Currency,
+        gas_limit_override: Option<u64>,
+    ) -> Box<Future<Item = Option<u64>, Error = Error> + Send>;
 }
 
 #[derive(Clone)]
@@ -53,6 +107,7 @@ pub struct BlockchainServiceImpl<E: DbExecutor> {
     key_values_repo: Arc<KeyValuesRepo>,
     system_service: Arc<SystemService>,
     db_executor: E,
+    clock: Arc<Clock>,
 }
 
 impl<E: DbExecutor> BlockchainServiceImpl<E> {
@@ -65,6 +120,7 @@ impl<E: DbExecutor> BlockchainServiceImpl<E> {
         key_values_repo: Arc<KeyValuesRepo>,
         system_service: Arc<SystemService>,
         db_executor: E,
+        clock: Arc<Clock>,
     ) -> Self {
         Self {
             config,
@@ -75,6 +131,93 @@ impl<E: DbExecutor> BlockchainServiceImpl<E> {
             key_values_repo,
             system_service,
             db_executor,
+            clock,
+        }
+    }
+
+    fn sign_and_post_bitcoin_tx(
+        &self,
+        create_blockchain_input: CreateBlockchainTx,
+    ) -> impl Future<Item = BlockchainTransactionId, Error = Error> + Send {
+        let db_executor = self.db_executor.clone();
+        let blockchain_client = self.blockchain_client.clone();
+        let pending_blockchain_transactions_repo = self.pending_blockchain_transactions_repo.clone();
+        let create_blockchain_input_clone = create_blockchain_input.clone();
+        self.keys_client
+            .sign_transaction(create_blockchain_input.clone(), Role::User)
+            .map_err(ectx!(convert => create_blockchain_input_clone, Role::User))
+            .and_then(move |raw_tx| {
+                let raw_tx_clone = raw_tx.clone();
+                blockchain_client
+                    .post_bitcoin_transaction(raw_tx.clone())
+                    .map_err(ectx!(convert => raw_tx_clone))
+                    .map(move |blockchain_tx_id| (blockchain_tx_id, raw_tx))
+            })
+            .and_then(move |(blockchain_tx_id, raw_tx)| {
+                db_executor.execute(move || {
+                    let new_pending = NewPendingBlockchainTransactionDB {
+                        raw_tx: Some(raw_tx),
+                        ..(create_blockchain_input, blockchain_tx_id.clone()).into()
+                    };
+                    // Note - we don't rollback here, because the tx is already in blockchain. so after that just silently
+                    // fail if we couldn't write a pending tx. Not having pending tx in db doesn't do a lot of harm, it's restored
+                    // with `recover_orphaned_pending_transaction` command.
+                    match pending_blockchain_transactions_repo.create(new_pending) {
+                        Err(e) => log_and_capture_error(e),
+                        _ => (),
+                    };
+
+                    Ok(blockchain_tx_id)
+                })
+            })
+    }
+
+    // Rate used to convert fees. The last received rate of the pair is cached, and if exchange fails
+    // it's used while not older than `cached_rate_ttl_secs`. The flag tells if the cached rate was used
+    fn fee_rate(&self, input: RateInput) -> impl Future<Item = (f64, bool), Error = Error> + Send {
+        let db_executor = self.db_executor.clone();
+        let key_values_repo = self.key_values_repo.clone();
+        let ttl = ChronoDuration::seconds(self.config.fees_options.cached_rate_ttl_secs as i64);
+        let clock = self.clock.clone();
+        let (from, to) = (input.from, input.to);
+        self.exchange_client.rate(input.clone(), Role::System).then(move |res| match res {
+            Ok(Rate { rate, .. }) => Either::A(db_executor.execute(move || {
+                // not being able to cache the rate doesn't affect the estimate
+                if let Err(e) = key_values_repo.set_rate(from, to, rate) {
+                    log_and_capture_error(e);
+                }
+                Ok((rate, false))
+            })),
+            Err(e) => {
+                let e: Error = ectx!(err e, ErrorKind::Internal => input);
+                Either::B(db_executor.execute(move || {
+                    let cached = key_values_repo.get_rate(from, to).map_err(ectx!(try convert => from, to))?;
+                    match cached {
+                        Some(KeyValue { value, updated_at, .. }) => match value.as_f64() {
+                            Some(rate) if clock.now() - updated_at < ttl => {
+                                log_warn(&e);
+                                warn!("Using cached {} -> {} rate {} from {} for fee estimate", from, to, rate, updated_at);
+                                Ok((rate, true))
+                            }
+                            _ => Err(e),
+                        },
+                        None => Err(e),
+                    }
+                }))
+            }
+        })
+    }
+
+    // user supplied gas limit is clamped to the configured max
+    fn gas_limit_override(&self, currency: Currency, gas_limit_override: Option<u64>) -> Result<Option<u64>, Error> {
+        match (currency, gas_limit_override) {
+            (_, None) => Ok(None),
+            (Currency::Eth, Some(gas_limit)) | (Currency::Stq, Some(gas_limit)) => {
+                Ok(Some(gas_limit.min(self.config.fees_options.max_gas_limit)))
+            }
+            (_, Some(gas_limit)) => {
+                Err(ectx!(err ErrorContext::NotSupported, ErrorKind::InvalidInput(currency.to_string()) => currency, gas_limit))
+            }
         }
     }
 }
@@ -87,19 +230,28 @@ impl<E: DbExecutor> BlockchainService for BlockchainServiceImpl<E> {
         input_gross_fee: Amount,
         input_fee_currency: Currency,
         withdrawal_currency: Currency,
+        gas_limit_override: Option<u64>,
+        with_fee_in_withdrawal_currency: bool,
     ) -> Box<Future<Item = FeeEstimate, Error = Error> + Send> {
+        let gas_limit_override = match self.gas_limit_override(withdrawal_currency, gas_limit_override) {
+            Ok(gas_limit_override) => gas_limit_override,
+            Err(e) => return Box::new(futures::future::err(e)),
+        };
         let estimate_currency = match withdrawal_currency {
             Currency::Btc => Currency::Btc,
             Currency::Eth => Currency::Eth,
             Currency::Stq => Currency::Eth,
         };
         let base = match withdrawal_currency {
-            Currency::Btc => self.config.fees_options.btc_transaction_size,
-            Currency::Eth => self.config.fees_options.eth_gas_limit,
-            Currency::Stq => self.config.fees_options.stq_gas_limit,
+            Currency::Btc => self.config.fees_options.btc_transaction_size as u64,
+            Currency::Eth => gas_limit_override.unwrap_or(self.config.fees_options.eth_gas_limit as u64),
+            Currency::Stq => gas_limit_override.unwrap_or(self.config.fees_options.stq_gas_limit as u64),
         };
         let base = Amount::new(base as u128);
-        let exchange_client = self.exchange_client.clone();
+        let min_fee_price = self.config.min_fee_price.for_currency(withdrawal_currency);
+        let fee_tiers = self.config.fee_tiers.clone();
+        let self_clone = self.clone();
+        let self_clone_ = self.clone();
         Box::new(
             input_gross_fee
                 .checked_div(Amount::new(self.config.fees_options.fee_upside as u128))
@@ -107,7 +259,7 @@ impl<E: DbExecutor> BlockchainService for BlockchainServiceImpl<E> {
                 .into_future()
                 .and_then(move |total_blockchain_fee_native_currency| {
                     if input_fee_currency == estimate_currency {
-                        Either::A(futures::future::ok(total_blockchain_fee_native_currency))
+                        Either::A(futures::future::ok((total_blockchain_fee_native_currency, false)))
                     } else {
                         let input_rate = RateInput {
                             id: ExchangeId::generate(),
@@ -116,17 +268,15 @@ impl<E: DbExecutor> BlockchainService for BlockchainServiceImpl<E> {
                             amount: total_blockchain_fee_native_currency,
                             amount_currency: input_fee_currency,
                         };
-                        Either::B(
-                            exchange_client
-                                .rate(input_rate.clone(), Role::System)
-                                .map_err(ectx!(ErrorKind::Internal => input_rate))
-                                .map(move |Rate { rate, .. }| {
-                                    total_blockchain_fee_native_currency.convert(input_fee_currency, estimate_currency, rate)
-                                }),
-                        )
+                        Either::B(self_clone.fee_rate(input_rate).map(move |(rate, stale_rate)| {
+                            (
+                                total_blockchain_fee_native_currency.convert(input_fee_currency, estimate_currency, rate),
+                                stale_rate,
+                            )
+                        }))
                     }
                 })
-                .and_then(move |total_blockchain_fee_esitmate_currency| {
+                .and_then(move |(total_blockchain_fee_esitmate_currency, stale_rate)| {
                     total_blockchain_fee_esitmate_currency
                         .checked_div(base)
                         .ok_or(ectx!(err ErrorContext::BalanceOverflow, ErrorKind::Internal))
@@ -138,12 +288,126 @@ impl<E: DbExecutor> BlockchainService for BlockchainServiceImpl<E> {
                             } else {
                                 fee_price_int.raw() as f64
                             };
+                            // under-priced transactions are never mined, so the system pays the difference
+                            let (gross_fee, fee_price, floor_applied) = if fee_price < min_fee_price {
+                                (Amount::new((min_fee_price * base.raw() as f64).ceil() as u128), min_fee_price, true)
+                            } else {
+                                (total_blockchain_fee_esitmate_currency, fee_price, false)
+                            };
+                            let tiers = [FeeTier::Slow, FeeTier::Normal, FeeTier::Fast]
+                                .iter()
+                                .map(|tier| {
+                                    let options = fee_tiers.for_tier(*tier);
+                                    let tier_fee_price = (fee_price * options.multiplier).max(min_fee_price);
+                                    FeeTierEstimate {
+                                        tier: *tier,
+                                        gross_fee: Amount::new((tier_fee_price * base.raw() as f64).ceil() as u128),
+                                        fee_price: tier_fee_price,
+                                        eta_secs: options.eta_secs(withdrawal_currency),
+                                    }
+                                })
+                                .collect();
                             FeeEstimate {
-                                gross_fee: total_blockchain_fee_esitmate_currency,
+                                gross_fee,
                                 fee_price,
                                 currency: estimate_currency,
+                                floor_applied,
+                                stale_rate,
+                                tiers,
+                                fee_in_withdrawal_currency: None,
                             }
                         })
+                })
+                .and_then(move |estimate| {
+                    if !with_fee_in_withdrawal_currency {
+                        return Either::A(futures::future::ok(estimate));
+                    }
+                    if estimate.currency == withdrawal_currency {
+                        let fee_in_withdrawal_currency = Some(estimate.gross_fee);
+                        return Either::A(futures::future::ok(FeeEstimate {
+                            fee_in_withdrawal_currency,
+                            ..estimate
+                        }));
+                    }
+                    let input_rate = RateInput::new(estimate.currency, withdrawal_currency, estimate.gross_fee, estimate.currency);
+                    Either::B(self_clone_.fee_rate(input_rate).then(move |res| {
+                        // the fee is charged in its own currency, so the estimate doesn't depend on the conversion
+                        let fee_in_withdrawal_currency = match res {
+                            Ok((rate, _)) => Some(estimate.gross_fee.convert(estimate.currency, withdrawal_currency, rate)),
+                            Err(e) => {
+                                log_warn(&e);
+                                None
+                            }
+                        };
+                        Ok::<_, Error>(FeeEstimate {
+                            fee_in_withdrawal_currency,
+                            ..estimate
+                        })
+                    }))
+                }),
+        )
+    }
+
+    fn estimate_default_withdrawal_fee(
+        &self,
+        fee_currency: Currency,
+        withdrawal_currency: Currency,
+    ) -> Box<Future<Item = Amount, Error = Error> + Send> {
+        let (estimate_currency, base, fee_price) = match withdrawal_currency {
+            Currency::Btc => (
+                Currency::Btc,
+                self.config.fees_options.btc_transaction_size as f64,
+                self.config.fee_price.bitcoin,
+            ),
+            Currency::Eth => (
+                Currency::Eth,
+                self.config.fees_options.eth_gas_limit as f64,
+                self.config.fee_price.ethereum,
+            ),
+            Currency::Stq => (
+                Currency::Eth,
+                self.config.fees_options.stq_gas_limit as f64,
+                self.config.fee_price.ethereum,
+            ),
+        };
+        let gross_fee = Amount::new((fee_price * base * self.config.fees_options.fee_upside) as u128);
+        if fee_currency == estimate_currency {
+            return Box::new(futures::future::ok(gross_fee));
+        }
+        let input_rate = RateInput::new(fee_currency, estimate_currency, gross_fee, estimate_currency);
+        Box::new(
+            self.fee_rate(input_rate)
+                .map(move |(rate, _)| gross_fee.convert(estimate_currency, fee_currency, 1.0 / rate)),
+        )
+    }
+
+    fn estimate_gas_limit(
+        &self,
+        from: BlockchainAddress,
+        to: BlockchainAddress,
+        value: Amount,
+        currency: Currency,
+        gas_limit_override: Option<u64>,
+    ) -> Box<Future<Item = Option<u64>, Error = Error> + Send> {
+        let gas_limit_override = match self.gas_limit_override(currency, gas_limit_override) {
+            Ok(gas_limit_override) => gas_limit_override,
+            Err(e) => return Box::new(futures::future::err(e)),
+        };
+        let node_estimate_applicable = currency == Currency::Eth || currency == Currency::Stq;
+        if gas_limit_override.is_some() || !node_estimate_applicable || !self.config.fees_options.node_gas_estimation {
+            return Box::new(futures::future::ok(gas_limit_override));
+        }
+        let max_gas_limit = self.config.fees_options.max_gas_limit;
+        Box::new(
+            self.blockchain_client
+                .estimate_gas(from.clone(), to.clone(), value, currency)
+                .then(move |res| match res {
+                    Ok(gas) => Ok(Some(gas.raw().min(max_gas_limit as u128) as u64)),
+                    // node is just more accurate, static limits are still good enough to withdraw
+                    Err(e) => {
+                        log_and_capture_error(ectx!(try err e, ErrorKind::Internal => from, to, value, currency));
+                        Ok(None)
+                    }
                 }),
         )
     }
@@ -156,40 +420,55 @@ impl<E: DbExecutor> BlockchainService for BlockchainServiceImpl<E> {
         fee_price: f64,
     ) -> Box<Future<Item = BlockchainTransactionId, Error = Error> + Send> {
         let from_clone = from.clone();
-        let db_executor = self.db_executor.clone();
-        let blockchain_client = self.blockchain_client.clone();
-        let keys_client = self.keys_client.clone();
-        let pending_blockchain_transactions_repo = self.pending_blockchain_transactions_repo.clone();
+        let self_clone = self.clone();
+        let min_confirmations = self.config.required_confirmations.btc_utxo;
+        let dust_threshold = self.config.dust_threshold.for_currency(Currency::Btc);
         Box::new(
             self.blockchain_client
-                .get_bitcoin_utxos(from.clone())
+                .get_bitcoin_utxos(from.clone(), min_confirmations)
                 .map_err(ectx!(convert => from_clone))
-                .and_then(move |utxos| {
+                .and_then(move |utxos| confirmed_utxos(utxos, min_confirmations, value))
+                .and_then(move |utxos| select_utxos(utxos, value, fee_price, dust_threshold))
+                .and_then(move |(utxos, fee_price)| {
                     let create_blockchain_input = CreateBlockchainTx::new(from, to, Currency::Btc, value, fee_price, None, Some(utxos));
-                    let create_blockchain_input_clone = create_blockchain_input.clone();
-
-                    keys_client
-                        .sign_transaction(create_blockchain_input.clone(), Role::User)
-                        .map_err(ectx!(convert => create_blockchain_input_clone, Role::User))
-                        .and_then(move |raw_tx| {
-                            blockchain_client
-                                .post_bitcoin_transaction(raw_tx.clone())
-                                .map_err(ectx!(convert => raw_tx))
-                        })
-                        .and_then(move |blockchain_tx_id| {
-                            db_executor.execute(move || {
-                                let new_pending = (create_blockchain_input, blockchain_tx_id.clone()).into();
-                                // Note - we don't rollback here, because the tx is already in blockchain. so after that just silently
-                                // fail if we couldn't write a pending tx. Not having pending tx in db doesn't do a lot of harm, we could cure
-                                // it later.
-                                match pending_blockchain_transactions_repo.create(new_pending) {
-                                    Err(e) => log_and_capture_error(e),
-                                    _ => (),
-                                };
+                    self_clone.sign_and_post_bitcoin_tx(create_blockchain_input)
+                }),
+        )
+    }
 
-                                Ok(blockchain_tx_id)
-                            })
-                        })
+    fn create_batch_bitcoin_tx(
+        &self,
+        from: BlockchainAddress,
+        outputs: Vec<BlockchainTxOutput>,
+        fee_price: f64,
+    ) -> Box<Future<Item = BlockchainTransactionId, Error = Error> + Send> {
+        if outputs.is_empty() {
+            return Box::new(futures::future::err(
+                ectx!(err ErrorContext::InvalidTransaction, ErrorKind::InvalidInput("outputs".to_string()) => from),
+            ));
+        }
+        let from_clone = from.clone();
+        let self_clone = self.clone();
+        let min_confirmations = self.config.required_confirmations.btc_utxo;
+        let value = match outputs.iter().try_fold(Amount::new(0), |acc, output| acc.checked_add(output.value)) {
+            Some(value) => value,
+            None => {
+                return Box::new(futures::future::err(
+                    ectx!(err ErrorContext::BalanceOverflow, ErrorKind::Internal => outputs),
+                ))
+            }
+        };
+        Box::new(
+            self.blockchain_client
+                .get_bitcoin_utxos(from.clone(), min_confirmations)
+                .map_err(ectx!(convert => from_clone))
+                .and_then(move |utxos| confirmed_utxos(utxos, min_confirmations, value))
+                .and_then(move |utxos| {
+                    let outputs_clone = outputs.clone();
+                    CreateBlockchainTx::new_bitcoin_batch(from, outputs, fee_price, utxos)
+                        .ok_or(ectx!(err ErrorContext::BalanceOverflow, ErrorKind::Internal => outputs_clone))
+                        .into_future()
+                        .and_then(move |create_blockchain_input| self_clone.sign_and_post_bitcoin_tx(create_blockchain_input))
                 }),
         )
     }
@@ -201,7 +480,12 @@ impl<E: DbExecutor> BlockchainService for BlockchainServiceImpl<E> {
         value: Amount,
         fee_price: f64,
         currency: Currency,
+        gas_limit_override: Option<u64>,
     ) -> Box<Future<Item = BlockchainTransactionId, Error = Error> + Send> {
+        let gas_limit = match self.gas_limit_override(currency, gas_limit_override) {
+            Ok(gas_limit) => gas_limit,
+            Err(e) => return Box::new(futures::future::err(e)),
+        };
         let db_executor = self.db_executor.clone();
         let db_executor_clone = self.db_executor.clone();
         let blockchain_client = self.blockchain_client.clone();
@@ -282,7 +566,10 @@ impl<E: DbExecutor> BlockchainService for BlockchainServiceImpl<E> {
                 })
                 .and_then(move |nonce| {
                     // creating blockchain transactions array
-                    let create_blockchain_input = CreateBlockchainTx::new(from_clone, to, currency, value, fee_price, Some(nonce), None);
+                    let create_blockchain_input = CreateBlockchainTx {
+                        gas_limit,
+                        ..CreateBlockchainTx::new(from_clone, to, currency, value, fee_price, Some(nonce), None)
+                    };
 
                     let create_blockchain = create_blockchain_input.clone();
 
@@ -290,21 +577,26 @@ impl<E: DbExecutor> BlockchainService for BlockchainServiceImpl<E> {
                         .sign_transaction(create_blockchain_input.clone(), Role::User)
                         .map_err(ectx!(convert => create_blockchain_input))
                         .and_then(move |raw_tx| {
+                            let raw_tx_clone = raw_tx.clone();
                             blockchain_client_clone
                                 .post_ethereum_transaction(raw_tx.clone())
-                                .map_err(ectx!(convert => raw_tx))
+                                .map_err(ectx!(convert => raw_tx_clone))
+                                .map(move |tx_id| (tx_id, raw_tx))
                         })
-                        .and_then(move |tx_id| {
+                        .and_then(move |(tx_id, raw_tx)| {
                             db_executor_clone.execute(move || {
                                 let tx_id = match currency {
                                     Currency::Eth => tx_id,
                                     // Erc-20 token, we need event log number here, to make a tx_id unique
                                     _ => BlockchainTransactionId::new(format!("{}:0", tx_id)),
                                 };
-                                let new_pending = (create_blockchain, tx_id.clone()).into();
+                                let new_pending = NewPendingBlockchainTransactionDB {
+                                    raw_tx: Some(raw_tx),
+                                    ..(create_blockchain, tx_id.clone()).into()
+                                };
                                 // Note - we don't rollback here, because the tx is already in blockchain. so after that just silently
-                                // fail if we couldn't write a pending tx. Not having pending tx in db doesn't do a lot of harm, we could cure
-                                // it later.
+                                // fail if we couldn't write a pending tx. Not having pending tx in db doesn't do a lot of harm, it's restored
+                                // with `recover_orphaned_pending_transaction` command.
                                 match pending_blockchain_transactions_repo.create(new_pending) {
                                     Err(e) => log_and_capture_error(e),
                                     _ => (),
@@ -317,9 +609,92 @@ impl<E: DbExecutor> BlockchainService for BlockchainServiceImpl<E> {
     }
 }
 
+/// Drops utxos with less than `min_confirmations`, gateway might not filter them itself.
+/// Utxos without confirmations are trusted to be filtered by the gateway.
+/// Fails only if the dropped utxos were needed to cover `value`, the rest is up to keys service
+fn confirmed_utxos(utxos: Vec<BitcoinUtxos>, min_confirmations: u64, value: Amount) -> Result<Vec<BitcoinUtxos>, Error> {
+    let utxos_count = utxos.len();
+    let confirmed: Vec<BitcoinUtxos> = utxos
+        .into_iter()
+        .filter(|utxo| {
+            utxo.confirmations()
+                .map(|confirmations| confirmations >= min_confirmations)
+                .unwrap_or(true)
+        })
+        .collect();
+    if confirmed.len() < utxos_count {
+        let confirmed_value = confirmed
+            .iter()
+            .try_fold(Amount::new(0), |acc, utxo| acc.checked_add(utxo.value()))
+            .ok_or(ectx!(try err ErrorContext::BalanceOverflow, ErrorKind::Internal => value))?;
+        if confirmed_value < value {
+            let mut errors = ValidationErrors::new();
+            let mut error = ValidationError::new("not_enough_confirmed_funds");
+            error.message = Some("not enough confirmed funds, recent deposits are not spendable yet".into());
+            errors.add("value", error);
+            return Err(
+                ectx!(err ErrorContext::NotEnoughConfirmedFunds, ErrorKind::Balance(serde_json::to_string(&errors).unwrap_or_default()) => confirmed_value, value, min_confirmations),
+            );
+        }
+    }
+    Ok(confirmed)
+}
+
+/// Approximate size in bytes of a bitcoin transaction with `inputs` and `outputs`
+pub fn bitcoin_tx_size(inputs: usize, outputs: usize) -> u64 {
+    (BITCOIN_TX_OVERHEAD_SIZE + BITCOIN_INPUT_SIZE * inputs + BITCOIN_OUTPUT_SIZE * outputs) as u64
+}
+
+/// Picks the largest utxos until they cover `value` and the network fee of the transaction spending them
+/// to the recipient and a change output, so that change is known before the transaction is signed.
+/// Change below `dust_threshold` costs more to spend than it's worth, so the change output is dropped
+/// and the fee price is raised for the network fee to absorb it.
+/// If the utxos are not enough, they are passed on as is and keys service reports it
+fn select_utxos(
+    mut utxos: Vec<BitcoinUtxos>,
+    value: Amount,
+    fee_price: f64,
+    dust_threshold: Amount,
+) -> Result<(Vec<BitcoinUtxos>, f64), Error> {
+    utxos.sort_by(|a, b| b.value().raw().cmp(&a.value().raw()));
+    let mut selected = vec![];
+    let mut selected_value = Amount::new(0);
+    let mut change = None;
+    for utxo in utxos.iter() {
+        selected_value = selected_value
+            .checked_add(utxo.value())
+            .ok_or(ectx!(try err ErrorContext::BalanceOverflow, ErrorKind::Internal => value))?;
+        selected.push(utxo.clone());
+        // every input makes the transaction and its fee larger
+        let fee = Amount::new((fee_price * bitcoin_tx_size(selected.len(), 2) as f64).ceil() as u128);
+        let required = value
+            .checked_add(fee)
+            .ok_or(ectx!(try err ErrorContext::BalanceOverflow, ErrorKind::Internal => value, fee))?;
+        if let Some(rest) = selected_value.checked_sub(required) {
+            change = Some(rest);
+            break;
+        }
+    }
+    let change = match change {
+        Some(change) => change,
+        None => return Ok((utxos, fee_price)),
+    };
+    if change == Amount::new(0) || change >= dust_threshold {
+        return Ok((selected, fee_price));
+    }
+    // everything above the value goes to the network fee of the transaction without change output
+    let fee = selected_value
+        .checked_sub(value)
+        .ok_or(ectx!(try err ErrorContext::BalanceOverflow, ErrorKind::Internal => selected_value, value))?;
+    let fee_price = fee.raw() as f64 / bitcoin_tx_size(selected.len(), 1) as f64;
+    Ok((selected, fee_price))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use client::exchange::ErrorKind as ExchangeClientErrorKind;
+    use client::keys::ErrorKind as KeysClientErrorKind;
     use client::*;
     use config::Config;
     use repos::*;
@@ -327,12 +702,52 @@ mod tests {
     use tokio_core::reactor::Core;
 
     fn create_blockchain_service() -> BlockchainServiceImpl<DbExecutorMock> {
-        let config = Arc::new(Config::new().unwrap());
         let keys_client = Arc::new(KeysClientMock::default());
-        let blockchain_client = Arc::new(BlockchainClientMock::default());
-        let exchange_client = Arc::new(ExchangeClientMock::default());
         let pending_blockchain_transactions_repo = Arc::new(PendingBlockchainTransactionsRepoMock::default());
-        let key_values_repo = Arc::new(KeyValuesRepoMock::default());
+        create_blockchain_service_with_mocks(keys_client, pending_blockchain_transactions_repo)
+    }
+
+    fn create_blockchain_service_with_mocks(
+        keys_client: Arc<KeysClientMock>,
+        pending_blockchain_transactions_repo: Arc<PendingBlockchainTransactionsRepoMock>,
+    ) -> BlockchainServiceImpl<DbExecutorMock> {
+        create_blockchain_service_with_client(
+            Config::new().unwrap(),
+            keys_client,
+            pending_blockchain_transactions_repo,
+            BlockchainClientMock::default(),
+        )
+    }
+
+    fn create_blockchain_service_with_client(
+        config: Config,
+        keys_client: Arc<KeysClientMock>,
+        pending_blockchain_transactions_repo: Arc<PendingBlockchainTransactionsRepoMock>,
+        blockchain_client: BlockchainClientMock,
+    ) -> BlockchainServiceImpl<DbExecutorMock> {
+        create_blockchain_service_with_exchange_client(
+            config,
+            keys_client,
+            pending_blockchain_transactions_repo,
+            blockchain_client,
+            ExchangeClientMock::default(),
+            Arc::new(KeyValuesRepoMock::default()),
+            Arc::new(MockClock::default()),
+        )
+    }
+
+    fn create_blockchain_service_with_exchange_client(
+        config: Config,
+        keys_client: Arc<KeysClientMock>,
+        pending_blockchain_transactions_repo: Arc<PendingBlockchainTransactionsRepoMock>,
+        blockchain_client: BlockchainClientMock,
+        exchange_client: ExchangeClientMock,
+        key_values_repo: Arc<KeyValuesRepoMock>,
+        clock: Arc<MockClock>,
+    ) -> BlockchainServiceImpl<DbExecutorMock> {
+        let config = Arc::new(config);
+        let blockchain_client = Arc::new(blockchain_client);
+        let exchange_client = Arc::new(exchange_client);
         let transfer_accounts: [Account; 3] = [Account::default(), Account::default(), Account::default()];
         let liquidity_accounts: [Account; 3] = [Account::default(), Account::default(), Account::default()];
         let fees_accounts: [Account; 3] = [Account::default(), Account::default(), Account::default()];
@@ -353,6 +768,7 @@ mod tests {
             key_values_repo,
             system_service,
             db_executor,
+            clock,
         )
     }
 
@@ -385,6 +801,193 @@ mod tests {
         assert!(res.is_ok());
     }
 
+    #[test]
+    fn test_blockchain_create_btc_signing_errors() {
+        let mut core = Core::new().unwrap();
+        let mut sign = |kind: KeysClientErrorKind| {
+            let keys_client = Arc::new(KeysClientMock::default().with_sign_error(kind));
+            let service = create_blockchain_service_with_mocks(keys_client, Arc::new(PendingBlockchainTransactionsRepoMock::default()));
+            core.run(service.create_bitcoin_tx(
+                BlockchainAddress::default(),
+                BlockchainAddress::default(),
+                Amount::new(100500),
+                0f64,
+            ))
+            .unwrap_err()
+        };
+        // only an unavailable signer is worth retrying
+        match sign(KeysClientErrorKind::Unavailable).kind() {
+            ErrorKind::SignerUnavailable => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+        match sign(KeysClientErrorKind::KeyNotFound).kind() {
+            ErrorKind::Internal => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+        let errors = r#"{"gas":[{"code":"not_enough_gas"}]}"#.to_string();
+        match sign(KeysClientErrorKind::SigningRejected(errors.clone())).kind() {
+            ErrorKind::InvalidInput(rejected) => assert_eq!(rejected, errors),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn test_blockchain_create_btc_unconfirmed_utxos() {
+        let mut core = Core::new().unwrap();
+        let keys_client = Arc::new(KeysClientMock::default());
+        let mut config = Config::new().unwrap();
+        config.required_confirmations.btc_utxo = 1;
+        let blockchain_client = BlockchainClientMock::default().with_utxos(vec![
+            BitcoinUtxos::new(BlockchainTransactionId::default(), 0, Amount::new(1_000), 3),
+            BitcoinUtxos::new(BlockchainTransactionId::default(), 1, Amount::new(5_000), 0),
+        ]);
+        let service = create_blockchain_service_with_client(
+            config,
+            keys_client.clone(),
+            Arc::new(PendingBlockchainTransactionsRepoMock::default()),
+            blockchain_client,
+        );
+
+        // confirmed utxo is enough, unconfirmed one is not spent
+        let res = core.run(service.create_bitcoin_tx(BlockchainAddress::default(), BlockchainAddress::default(), Amount::new(800), 0f64));
+        assert!(res.is_ok());
+        let utxos = keys_client.signed_transactions()[0].utxos.clone().unwrap();
+        assert_eq!(utxos.len(), 1);
+        assert_eq!(utxos[0].value(), Amount::new(1_000));
+
+        // funds are there, but mostly unconfirmed
+        let res = core.run(service.create_bitcoin_tx(BlockchainAddress::default(), BlockchainAddress::default(), Amount::new(3_000), 0f64));
+        match res.unwrap_err().kind() {
+            ErrorKind::Balance(_) => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+        assert_eq!(keys_client.signed_transactions().len(), 1);
+    }
+
+    #[test]
+    fn test_confirmed_utxos_without_confirmations() {
+        let utxos = vec![
+            // gateway didn't report confirmations, so it has already filtered the utxo by min confirmations
+            BitcoinUtxos::default(),
+            BitcoinUtxos::new(BlockchainTransactionId::default(), 1, Amount::new(0), 0),
+        ];
+        let confirmed = confirmed_utxos(utxos, 1, Amount::new(0)).unwrap();
+        assert_eq!(confirmed.len(), 1);
+        assert_eq!(confirmed[0].confirmations(), None);
+    }
+
+    #[test]
+    fn test_blockchain_create_btc_dust_change() {
+        let mut core = Core::new().unwrap();
+        let keys_client = Arc::new(KeysClientMock::default());
+        let config = Config::new().unwrap();
+        let blockchain_client = BlockchainClientMock::default().with_utxos(vec![
+            BitcoinUtxos::new(BlockchainTransactionId::default(), 0, Amount::new(500), 6),
+            BitcoinUtxos::new(BlockchainTransactionId::default(), 1, Amount::new(20_000), 6),
+        ]);
+        let service = create_blockchain_service_with_client(
+            config.clone(),
+            keys_client.clone(),
+            Arc::new(PendingBlockchainTransactionsRepoMock::default()),
+            blockchain_client,
+        );
+        // single input with recipient and change outputs
+        let fee = 10 * bitcoin_tx_size(1, 2) as u128;
+
+        // change of 100 satoshis is below dust, so it goes to the fee
+        let value = Amount::new(20_000 - fee - 100);
+        core.run(service.create_bitcoin_tx(BlockchainAddress::default(), BlockchainAddress::default(), value, 10f64))
+            .unwrap();
+        let signed = keys_client.signed_transactions()[0].clone();
+        let utxos = signed.utxos.unwrap();
+        assert_eq!(utxos.len(), 1);
+        assert_eq!(utxos[0].value(), Amount::new(20_000));
+        // change output is dropped, so the fee is paid by a smaller transaction
+        assert_eq!((signed.fee_price * bitcoin_tx_size(1, 1) as f64).round() as u128, fee + 100);
+
+        // change above dust is left as is
+        let value = Amount::new(20_000 - fee - 1_000);
+        core.run(service.create_bitcoin_tx(BlockchainAddress::default(), BlockchainAddress::default(), value, 10f64))
+            .unwrap();
+        let signed = keys_client.signed_transactions()[1].clone();
+        assert_eq!(signed.utxos.unwrap().len(), 1);
+        assert_eq!(signed.fee_price, 10f64);
+    }
+
+    #[test]
+    fn test_select_utxos() {
+        let dust_threshold = Amount::new(546);
+        let utxos = vec![
+            BitcoinUtxos::new(BlockchainTransactionId::default(), 0, Amount::new(1_000), 6),
+            BitcoinUtxos::new(BlockchainTransactionId::default(), 1, Amount::new(3_000), 6),
+        ];
+        let values = |utxos: Vec<BitcoinUtxos>| utxos.iter().map(|utxo| utxo.value()).collect::<Vec<_>>();
+
+        // 1 satoshi per byte, one input with recipient and change outputs is 226 bytes
+        let one_input_fee = bitcoin_tx_size(1, 2) as u128;
+        assert_eq!(one_input_fee, 226);
+
+        // the largest utxo goes first, 274 of dust change are added to the fee of the transaction without change output
+        let (selected, fee_price) = select_utxos(utxos.clone(), Amount::new(2_500), 1.0, dust_threshold).unwrap();
+        assert_eq!(values(selected), vec![Amount::new(3_000)]);
+        assert_eq!((fee_price * bitcoin_tx_size(1, 1) as f64).round() as u128, 500);
+        // no change at all
+        let (selected, fee_price) = select_utxos(utxos.clone(), Amount::new(3_000 - one_input_fee), 1.0, dust_threshold).unwrap();
+        assert_eq!(values(selected), vec![Amount::new(3_000)]);
+        assert_eq!(fee_price, 1.0);
+        // the largest utxo covers the value, but not the fee, and the second input adds to the fee
+        let (selected, fee_price) = select_utxos(utxos.clone(), Amount::new(2_800), 1.0, dust_threshold).unwrap();
+        assert_eq!(values(selected), vec![Amount::new(3_000), Amount::new(1_000)]);
+        assert_eq!(fee_price, 1.0);
+        // both utxos are needed and change of 4_000 - 3_000 - 374 is above dust
+        let (selected, fee_price) = select_utxos(utxos.clone(), Amount::new(3_000), 1.0, dust_threshold).unwrap();
+        assert_eq!(values(selected), vec![Amount::new(3_000), Amount::new(1_000)]);
+        assert_eq!(fee_price, 1.0);
+        // not enough, keys service decides what to do
+        let (selected, fee_price) = select_utxos(utxos, Amount::new(4_000), 1.0, dust_threshold).unwrap();
+        assert_eq!(selected.len(), 2);
+        assert_eq!(fee_price, 1.0);
+    }
+
+    #[test]
+    fn test_blockchain_create_batch_btc() {
+        let mut core = Core::new().unwrap();
+        let keys_client = Arc::new(KeysClientMock::default());
+        let pending_blockchain_transactions_repo = Arc::new(PendingBlockchainTransactionsRepoMock::default());
+        let service = create_blockchain_service_with_mocks(keys_client.clone(), pending_blockchain_transactions_repo.clone());
+        let from = BlockchainAddress::default();
+        let outputs: Vec<_> = [1000, 2000, 3000]
+            .iter()
+            .map(|value| BlockchainTxOutput {
+                to: BlockchainAddress::default(),
+                value: Amount::new(*value),
+            })
+            .collect();
+
+        let tx_id = core
+            .run(service.create_batch_bitcoin_tx(from.clone(), outputs.clone(), 10f64))
+            .unwrap();
+        // signed and posted once
+        let signed = keys_client.signed_transactions();
+        assert_eq!(signed.len(), 1);
+        assert_eq!(signed[0].currency, Currency::Btc);
+        assert_eq!(signed[0].from, from);
+        assert_eq!(signed[0].to, outputs[0].to);
+        assert_eq!(signed[0].value, Amount::new(6000));
+        let signed_outputs = signed[0].outputs.clone().unwrap();
+        assert_eq!(signed_outputs.len(), 3);
+        for (signed_output, output) in signed_outputs.iter().zip(outputs.iter()) {
+            assert_eq!(signed_output.to, output.to);
+            assert_eq!(signed_output.value, output.value);
+        }
+        let pending = pending_blockchain_transactions_repo.get(tx_id).unwrap().unwrap();
+        assert_eq!(pending.value, Amount::new(6000));
+        assert!(pending.raw_tx.is_some());
+
+        let res = core.run(service.create_batch_bitcoin_tx(from, vec![], 10f64));
+        assert!(res.is_err());
+    }
+
     #[test]
     fn test_blockchain_create_eth_happy() {
         let mut core = Core::new().unwrap();
@@ -395,6 +998,7 @@ mod tests {
             Amount::new(0),
             0f64,
             Currency::Eth,
+            None,
         ));
         assert!(res.is_ok());
 
@@ -404,6 +1008,7 @@ mod tests {
             Amount::new(100500000000),
             0f64,
             Currency::Eth,
+            None,
         ));
         assert!(res.is_ok());
 
@@ -413,6 +1018,7 @@ mod tests {
             Amount::new(0),
             100500f64,
             Currency::Eth,
+            None,
         ));
         assert!(res.is_ok());
 
@@ -422,6 +1028,7 @@ mod tests {
             Amount::new(100500),
             100500f64,
             Currency::Eth,
+            None,
         ));
         assert!(res.is_ok());
     }
@@ -436,6 +1043,7 @@ mod tests {
             Amount::new(0),
             0f64,
             Currency::Stq,
+            None,
         ));
         assert!(res.is_ok());
 
@@ -445,6 +1053,7 @@ mod tests {
             Amount::new(100500000000),
             0f64,
             Currency::Stq,
+            None,
         ));
         assert!(res.is_ok());
 
@@ -454,6 +1063,7 @@ mod tests {
             Amount::new(0),
             100500f64,
             Currency::Stq,
+            None,
         ));
         assert!(res.is_ok());
 
@@ -463,6 +1073,7 @@ mod tests {
             Amount::new(100500),
             100500f64,
             Currency::Stq,
+            None,
         ));
         assert!(res.is_ok());
     }
@@ -471,46 +1082,316 @@ mod tests {
     fn test_blockchain_create_estimate_withdrawal_fee_happy() {
         let mut core = Core::new().unwrap();
         let service = create_blockchain_service();
-        let res = core.run(service.estimate_withdrawal_fee(Amount::new(0), Currency::Stq, Currency::Stq));
+        let res = core.run(service.estimate_withdrawal_fee(Amount::new(0), Currency::Stq, Currency::Stq, None, false));
         assert!(res.is_ok());
-        let res = core.run(service.estimate_withdrawal_fee(Amount::new(100500000), Currency::Stq, Currency::Stq));
+        let res = core.run(service.estimate_withdrawal_fee(Amount::new(100500000), Currency::Stq, Currency::Stq, None, false));
         assert!(res.is_ok());
-        let res = core.run(service.estimate_withdrawal_fee(Amount::new(0), Currency::Stq, Currency::Eth));
+        let res = core.run(service.estimate_withdrawal_fee(Amount::new(0), Currency::Stq, Currency::Eth, None, false));
         assert!(res.is_ok());
-        let res = core.run(service.estimate_withdrawal_fee(Amount::new(100500000), Currency::Stq, Currency::Eth));
+        let res = core.run(service.estimate_withdrawal_fee(Amount::new(100500000), Currency::Stq, Currency::Eth, None, false));
         assert!(res.is_ok());
-        let res = core.run(service.estimate_withdrawal_fee(Amount::new(0), Currency::Stq, Currency::Btc));
+        let res = core.run(service.estimate_withdrawal_fee(Amount::new(0), Currency::Stq, Currency::Btc, None, false));
         assert!(res.is_ok());
-        let res = core.run(service.estimate_withdrawal_fee(Amount::new(100500000), Currency::Stq, Currency::Btc));
+        let res = core.run(service.estimate_withdrawal_fee(Amount::new(100500000), Currency::Stq, Currency::Btc, None, false));
         assert!(res.is_ok());
 
-        let res = core.run(service.estimate_withdrawal_fee(Amount::new(0), Currency::Eth, Currency::Stq));
+        let res = core.run(service.estimate_withdrawal_fee(Amount::new(0), Currency::Eth, Currency::Stq, None, false));
         assert!(res.is_ok());
-        let res = core.run(service.estimate_withdrawal_fee(Amount::new(100500000), Currency::Eth, Currency::Stq));
+        let res = core.run(service.estimate_withdrawal_fee(Amount::new(100500000), Currency::Eth, Currency::Stq, None, false));
         assert!(res.is_ok());
-        let res = core.run(service.estimate_withdrawal_fee(Amount::new(0), Currency::Eth, Currency::Eth));
+        let res = core.run(service.estimate_withdrawal_fee(Amount::new(0), Currency::Eth, Currency::Eth, None, false));
         assert!(res.is_ok());
-        let res = core.run(service.estimate_withdrawal_fee(Amount::new(100500000), Currency::Eth, Currency::Eth));
+        let res = core.run(service.estimate_withdrawal_fee(Amount::new(100500000), Currency::Eth, Currency::Eth, None, false));
         assert!(res.is_ok());
-        let res = core.run(service.estimate_withdrawal_fee(Amount::new(0), Currency::Eth, Currency::Btc));
+        let res = core.run(service.estimate_withdrawal_fee(Amount::new(0), Currency::Eth, Currency::Btc, None, false));
         assert!(res.is_ok());
-        let res = core.run(service.estimate_withdrawal_fee(Amount::new(100500000), Currency::Eth, Currency::Btc));
+        let res = core.run(service.estimate_withdrawal_fee(Amount::new(100500000), Currency::Eth, Currency::Btc, None, false));
         assert!(res.is_ok());
 
-        let res = core.run(service.estimate_withdrawal_fee(Amount::new(0), Currency::Btc, Currency::Stq));
+        let res = core.run(service.estimate_withdrawal_fee(Amount::new(0), Currency::Btc, Currency::Stq, None, false));
         assert!(res.is_ok());
-        let res = core.run(service.estimate_withdrawal_fee(Amount::new(100500000), Currency::Btc, Currency::Stq));
+        let res = core.run(service.estimate_withdrawal_fee(Amount::new(100500000), Currency::Btc, Currency::Stq, None, false));
         assert!(res.is_ok());
-        let res = core.run(service.estimate_withdrawal_fee(Amount::new(0), Currency::Btc, Currency::Eth));
+        let res = core.run(service.estimate_withdrawal_fee(Amount::new(0), Currency::Btc, Currency::Eth, None, false));
         assert!(res.is_ok());
-        let res = core.run(service.estimate_withdrawal_fee(Amount::new(100500000), Currency::Btc, Currency::Eth));
+        let res = core.run(service.estimate_withdrawal_fee(Amount::new(100500000), Currency::Btc, Currency::Eth, None, false));
         assert!(res.is_ok());
-        let res = core.run(service.estimate_withdrawal_fee(Amount::new(0), Currency::Btc, Currency::Btc));
+        let res = core.run(service.estimate_withdrawal_fee(Amount::new(0), Currency::Btc, Currency::Btc, None, false));
         assert!(res.is_ok());
-        let res = core.run(service.estimate_withdrawal_fee(Amount::new(100500000), Currency::Btc, Currency::Btc));
+        let res = core.run(service.estimate_withdrawal_fee(Amount::new(100500000), Currency::Btc, Currency::Btc, None, false));
         assert!(res.is_ok());
     }
 
+    #[test]
+    fn test_blockchain_estimate_withdrawal_fee_gas_limit_override() {
+        let mut core = Core::new().unwrap();
+        let service = create_blockchain_service();
+        // fee_upside = 2, eth_gas_limit = 21000 in test config
+        let res = core
+            .run(service.estimate_withdrawal_fee(Amount::new(100800000), Currency::Eth, Currency::Eth, None, false))
+            .unwrap();
+        assert_eq!(res.fee_price, 2400.0);
+        let res = core
+            .run(service.estimate_withdrawal_fee(Amount::new(100800000), Currency::Eth, Currency::Eth, Some(42000), false))
+            .unwrap();
+        assert_eq!(res.fee_price, 1200.0);
+        assert_eq!(res.gross_fee, Amount::new(50400000));
+        // clamped to max_gas_limit = 500000
+        let res = core
+            .run(service.estimate_withdrawal_fee(Amount::new(100800000), Currency::Eth, Currency::Stq, Some(100_000_000), false))
+            .unwrap();
+        assert_eq!(res.fee_price, 100.8);
+        let res = core.run(service.estimate_withdrawal_fee(Amount::new(100800000), Currency::Btc, Currency::Btc, Some(42000), false));
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_blockchain_estimate_withdrawal_fee_floor() {
+        let mut core = Core::new().unwrap();
+        let mut config = Config::new().unwrap();
+        config.min_fee_price.eth = 2000.0;
+        config.min_fee_price.btc = 5.0;
+        let service = create_blockchain_service_with_client(
+            config,
+            Arc::new(KeysClientMock::default()),
+            Arc::new(PendingBlockchainTransactionsRepoMock::default()),
+            BlockchainClientMock::default(),
+        );
+        // fee_upside = 2, eth_gas_limit = 21000 in test config, so computed price is 1200
+        let res = core
+            .run(service.estimate_withdrawal_fee(Amount::new(50400000), Currency::Eth, Currency::Eth, None, false))
+            .unwrap();
+        assert_eq!(res.fee_price, 2000.0);
+        assert_eq!(res.gross_fee, Amount::new(42000000));
+        assert!(res.floor_applied);
+        // price above the floor is kept
+        let res = core
+            .run(service.estimate_withdrawal_fee(Amount::new(100800000), Currency::Eth, Currency::Eth, None, false))
+            .unwrap();
+        assert_eq!(res.fee_price, 2400.0);
+        assert_eq!(res.gross_fee, Amount::new(50400000));
+        assert!(!res.floor_applied);
+        // zero fee is raised as well, btc_transaction_size = 280 in test config
+        let res = core
+            .run(service.estimate_withdrawal_fee(Amount::new(0), Currency::Btc, Currency::Btc, None, false))
+            .unwrap();
+        assert_eq!(res.fee_price, 5.0);
+        assert_eq!(res.gross_fee, Amount::new(1400));
+        assert!(res.floor_applied);
+    }
+
+    #[test]
+    fn test_blockchain_estimate_withdrawal_fee_tiers() {
+        let mut core = Core::new().unwrap();
+        let service = create_blockchain_service();
+        // estimated price is 2400, tier multipliers are 0.75, 1 and 1.5 in test config
+        let res = core
+            .run(service.estimate_withdrawal_fee(Amount::new(100800000), Currency::Eth, Currency::Eth, None, false))
+            .unwrap();
+        let tiers: Vec<(FeeTier, f64, Amount, u64)> = res
+            .tiers
+            .iter()
+            .map(|tier| (tier.tier, tier.fee_price, tier.gross_fee, tier.eta_secs))
+            .collect();
+        assert_eq!(
+            tiers,
+            vec![
+                (FeeTier::Slow, 1800.0, Amount::new(37800000), 600),
+                (FeeTier::Normal, 2400.0, Amount::new(50400000), 180),
+                (FeeTier::Fast, 3600.0, Amount::new(75600000), 60),
+            ]
+        );
+        // slow tier is never priced below the floor
+        let res = core
+            .run(service.estimate_withdrawal_fee(Amount::new(0), Currency::Btc, Currency::Btc, None, false))
+            .unwrap();
+        let min_fee_price = Config::new().unwrap().min_fee_price.btc;
+        assert!(res.tiers.iter().all(|tier| tier.fee_price >= min_fee_price));
+    }
+
+    #[test]
+    fn test_blockchain_estimate_withdrawal_fee_cached_rate() {
+        let mut core = Core::new().unwrap();
+        let key_values_repo = Arc::new(KeyValuesRepoMock::default());
+        let clock = Arc::new(MockClock::default());
+        let create_service = |exchange_client: ExchangeClientMock| {
+            create_blockchain_service_with_exchange_client(
+                Config::new().unwrap(),
+                Arc::new(KeysClientMock::default()),
+                Arc::new(PendingBlockchainTransactionsRepoMock::default()),
+                BlockchainClientMock::default(),
+                exchange_client,
+                key_values_repo.clone(),
+                clock.clone(),
+            )
+        };
+        // no cached rate yet, so the outage fails the estimate
+        let service = create_service(ExchangeClientMock::with_rate_error(ExchangeClientErrorKind::Unavailable));
+        let res = core.run(service.estimate_withdrawal_fee(Amount::new(10_000_000), Currency::Btc, Currency::Eth, None, false));
+        assert!(res.is_err());
+
+        // successful estimate caches btc -> eth rate
+        let service = create_service(ExchangeClientMock::with_rate(20.0));
+        let live = core
+            .run(service.estimate_withdrawal_fee(Amount::new(10_000_000), Currency::Btc, Currency::Eth, None, false))
+            .unwrap();
+        assert!(!live.stale_rate);
+
+        let service = create_service(ExchangeClientMock::with_rate_error(ExchangeClientErrorKind::Unavailable));
+        let cached = core
+            .run(service.estimate_withdrawal_fee(Amount::new(10_000_000), Currency::Btc, Currency::Eth, None, false))
+            .unwrap();
+        assert!(cached.stale_rate);
+        assert_eq!(cached.gross_fee, live.gross_fee);
+        assert_eq!(cached.fee_price, live.fee_price);
+
+        // expired cached rate is not used
+        let ttl = Config::new().unwrap().fees_options.cached_rate_ttl_secs as i64;
+        clock.advance(ChronoDuration::seconds(ttl + 1));
+        let service = create_service(ExchangeClientMock::with_rate_error(ExchangeClientErrorKind::Unavailable));
+        let res = core.run(service.estimate_withdrawal_fee(Amount::new(10_000_000), Currency::Btc, Currency::Eth, None, false));
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_blockchain_estimate_withdrawal_fee_in_withdrawal_currency() {
+        let mut core = Core::new().unwrap();
+        let create_service = |exchange_client: ExchangeClientMock| {
+            create_blockchain_service_with_exchange_client(
+                Config::new().unwrap(),
+                Arc::new(KeysClientMock::default()),
+                Arc::new(PendingBlockchainTransactionsRepoMock::default()),
+                BlockchainClientMock::default(),
+                exchange_client,
+                Arc::new(KeyValuesRepoMock::default()),
+                Arc::new(MockClock::default()),
+            )
+        };
+        let service = create_service(ExchangeClientMock::with_rate(20.0));
+        // stq withdrawal fee is paid in eth, its stq equivalent is informational
+        let estimate = core
+            .run(service.estimate_withdrawal_fee(Amount::new(100800000), Currency::Eth, Currency::Stq, None, true))
+            .unwrap();
+        assert_eq!(estimate.currency, Currency::Eth);
+        let fee_in_stq = estimate.gross_fee.convert(Currency::Eth, Currency::Stq, 20.0);
+        assert_ne!(fee_in_stq, estimate.gross_fee);
+        assert_eq!(estimate.fee_in_withdrawal_currency, Some(fee_in_stq));
+        let not_asked = core
+            .run(service.estimate_withdrawal_fee(Amount::new(100800000), Currency::Eth, Currency::Stq, None, false))
+            .unwrap();
+        assert_eq!(not_asked.gross_fee, estimate.gross_fee);
+        assert_eq!(not_asked.fee_in_withdrawal_currency, None);
+        // fee is already in the withdrawal currency
+        let estimate = core
+            .run(service.estimate_withdrawal_fee(Amount::new(100800000), Currency::Eth, Currency::Eth, None, true))
+            .unwrap();
+        assert_eq!(estimate.fee_in_withdrawal_currency, Some(estimate.gross_fee));
+
+        // exchange outage doesn't fail the estimate itself
+        let service = create_service(ExchangeClientMock::with_rate_error(ExchangeClientErrorKind::Unavailable));
+        let estimate = core
+            .run(service.estimate_withdrawal_fee(Amount::new(100800000), Currency::Eth, Currency::Stq, None, true))
+            .unwrap();
+        assert_eq!(estimate.currency, Currency::Eth);
+        assert_eq!(estimate.fee_in_withdrawal_currency, None);
+    }
+
+    #[test]
+    fn test_blockchain_estimate_gas_limit_by_node() {
+        let mut core = Core::new().unwrap();
+        let mut config = Config::new().unwrap();
+        config.fees_options.node_gas_estimation = true;
+        let service = create_blockchain_service_with_client(
+            config,
+            Arc::new(KeysClientMock::default()),
+            Arc::new(PendingBlockchainTransactionsRepoMock::default()),
+            BlockchainClientMock::default().with_gas_estimate(Amount::new(42000)),
+        );
+        let gas_limit = core
+            .run(service.estimate_gas_limit(
+                BlockchainAddress::default(),
+                BlockchainAddress::default(),
+                Amount::new(100),
+                Currency::Eth,
+                None,
+            ))
+            .unwrap();
+        assert_eq!(gas_limit, Some(42000));
+        // fee_upside = 2 in test config, fee price is twice lower than with eth_gas_limit = 21000
+        let res = core
+            .run(service.estimate_withdrawal_fee(Amount::new(100800000), Currency::Eth, Currency::Eth, gas_limit, false))
+            .unwrap();
+        assert_eq!(res.fee_price, 1200.0);
+        // user supplied gas limit wins
+        let gas_limit = core
+            .run(service.estimate_gas_limit(
+                BlockchainAddress::default(),
+                BlockchainAddress::default(),
+                Amount::new(100),
+                Currency::Eth,
+                Some(30000),
+            ))
+            .unwrap();
+        assert_eq!(gas_limit, Some(30000));
+        let gas_limit = core
+            .run(service.estimate_gas_limit(
+                BlockchainAddress::default(),
+                BlockchainAddress::default(),
+                Amount::new(100),
+                Currency::Btc,
+                None,
+            ))
+            .unwrap();
+        assert_eq!(gas_limit, None);
+    }
+
+    #[test]
+    fn test_blockchain_estimate_gas_limit_fallback() {
+        let mut core = Core::new().unwrap();
+        let mut config = Config::new().unwrap();
+        config.fees_options.node_gas_estimation = true;
+        // node fails to estimate
+        let service = create_blockchain_service_with_client(
+            config.clone(),
+            Arc::new(KeysClientMock::default()),
+            Arc::new(PendingBlockchainTransactionsRepoMock::default()),
+            BlockchainClientMock::default(),
+        );
+        let gas_limit = core
+            .run(service.estimate_gas_limit(
+                BlockchainAddress::default(),
+                BlockchainAddress::default(),
+                Amount::new(100),
+                Currency::Stq,
+                None,
+            ))
+            .unwrap();
+        assert_eq!(gas_limit, None);
+        let res = core
+            .run(service.estimate_withdrawal_fee(Amount::new(100800000), Currency::Eth, Currency::Eth, gas_limit, false))
+            .unwrap();
+        assert_eq!(res.fee_price, 2400.0);
+
+        // node is not asked when estimation is disabled
+        config.fees_options.node_gas_estimation = false;
+        let service = create_blockchain_service_with_client(
+            config,
+            Arc::new(KeysClientMock::default()),
+            Arc::new(PendingBlockchainTransactionsRepoMock::default()),
+            BlockchainClientMock::default().with_gas_estimate(Amount::new(42000)),
+        );
+        let gas_limit = core
+            .run(service.estimate_gas_limit(
+                BlockchainAddress::default(),
+                BlockchainAddress::default(),
+                Amount::new(100),
+                Currency::Eth,
+                None,
+            ))
+            .unwrap();
+        assert_eq!(gas_limit, None);
+    }
+
     #[test]
     fn test_blockchain_create_eth_wrong_currency() {
         let mut core = Core::new().unwrap();
@@ -521,6 +1402,7 @@ mod tests {
             Amount::new(0),
             0f64,
             Currency::Btc,
+            None,
         ));
         assert!(res.is_err());
     }
@@ -1,11 +1,13 @@
+use std::collections::HashSet;
 use std::sync::Arc;
 
 use chrono::Duration;
 use serde_json;
 use validator::{Validate, ValidationError, ValidationErrors};
 
+use super::super::clock::Clock;
 use super::super::error::*;
-use config::Config;
+use config::{AddressScreening, AmountPrecision, Config, MaxTransactionValue};
 use models::*;
 use prelude::*;
 use repos::{AccountsRepo, TransactionsRepo};
@@ -30,13 +32,22 @@ pub struct ClassifierServiceImpl {
     eth_wei_limit: Amount,
     btc_satoshi_limit: Amount,
     limit_period: Duration,
+    amount_precision: AmountPrecision,
+    max_transaction_value: MaxTransactionValue,
+    enabled_currencies: HashSet<Currency>,
+    address_screening: AddressScreening,
+    allowed_categories: Option<Vec<String>>,
+    clock: Arc<Clock>,
 }
 
-const WEI_IN_ETH: u128 = 1_000_000_000_000_000_000;
-const SATOSHI_IN_BTC: u128 = 100_000_000;
+pub const WEI_IN_ETH: u128 = 1_000_000_000_000_000_000;
+pub const SATOSHI_IN_BTC: u128 = 100_000_000;
+const BTC_DECIMALS: u32 = 8;
+const ETH_DECIMALS: u32 = 18;
+const STQ_DECIMALS: u32 = 18;
 
 impl ClassifierServiceImpl {
-    pub fn new(config: &Config, accounts_repo: Arc<AccountsRepo>, transactions_repo: Arc<TransactionsRepo>) -> Self {
+    pub fn new(config: &Config, accounts_repo: Arc<AccountsRepo>, transactions_repo: Arc<TransactionsRepo>, clock: Arc<Clock>) -> Self {
         let stq_wei_limit = Amount::new((config.limits.stq_limit as u128) * WEI_IN_ETH);
         let eth_wei_limit = Amount::new(((config.limits.eth_limit * 1000.0) as u128) * WEI_IN_ETH / 1000);
         let btc_satoshi_limit = Amount::new(((config.limits.btc_limit * 1000.0) as u128) * SATOSHI_IN_BTC / 1000);
@@ -48,15 +59,61 @@ impl ClassifierServiceImpl {
             eth_wei_limit,
             btc_satoshi_limit,
             limit_period,
+            amount_precision: config.amount_precision.clone(),
+            max_transaction_value: config.max_transaction_value.clone(),
+            enabled_currencies: config.system.enabled_currencies.clone(),
+            address_screening: config.address_screening.clone(),
+            allowed_categories: config.transaction_categories.as_ref().map(|categories| categories.allowed.clone()),
+            clock,
         }
     }
 
+    fn check_currency_enabled(&self, currency: Currency) -> Result<(), Error> {
+        if !self.enabled_currencies.contains(&currency) {
+            return Err(ectx!(err ErrorContext::CurrencyDisabled, ErrorKind::MalformedInput => currency));
+        }
+        Ok(())
+    }
+
+    // lists may contain addresses in any case, so they are compared in canonical form
+    fn check_address_screening(&self, address: &BlockchainAddress, currency: Currency) -> Result<(), Error> {
+        let address = address.normalized(currency);
+        let listed = |list: &[BlockchainAddress]| list.iter().any(|listed| listed.normalized(currency) == address);
+        let not_allowed = match self.address_screening.allowlist {
+            Some(ref allowlist) => !listed(allowlist),
+            None => false,
+        };
+        if listed(&self.address_screening.denylist) || not_allowed {
+            return Err(ectx!(err ErrorContext::AddressBlocked, ErrorKind::MalformedInput => address, currency));
+        }
+        Ok(())
+    }
+
+    // values are in base units (satoshi, wei), so a value is over precise
+    // if it is not a multiple of the smallest allowed fraction
+    fn check_amount_precision(&self, input: &CreateTransactionInput) -> Result<(), Error> {
+        let currency = input.value_currency;
+        let decimals = match currency {
+            Currency::Btc => BTC_DECIMALS,
+            Currency::Eth => ETH_DECIMALS,
+            Currency::Stq => STQ_DECIMALS,
+        };
+        let precision = self.amount_precision.for_currency(currency);
+        let step = 10u128.pow(decimals.saturating_sub(precision));
+        if input.value.raw() % step != 0 {
+            let value = input.value;
+            return Err(ectx!(err ErrorContext::AmountPrecision, ErrorKind::MalformedInput => value, currency, precision));
+        }
+        Ok(())
+    }
+
     fn check_account_daily_limit(&self, input: &CreateTransactionInput, account: &Account) -> Result<(), Error> {
         let (acct_id, acct_kind, limit_period) = (account.id.clone(), account.kind.clone(), self.limit_period.clone());
+        let now = self.clock.now();
         let spending = self
             .transactions_repo
-            .get_account_spending(acct_id.clone(), acct_kind.clone(), limit_period.clone())
-            .map_err(ectx!(try ErrorKind::Internal => acct_id, acct_kind, limit_period))?;
+            .get_account_spending(acct_id.clone(), acct_kind.clone(), limit_period.clone(), now)
+            .map_err(ectx!(try ErrorKind::Internal => acct_id, acct_kind, limit_period, now))?;
         let from_currency = account.currency;
         let to_currency = input.to_currency;
         let from_value = match input.value_currency {
@@ -95,6 +152,34 @@ impl ClassifierServiceImpl {
         Ok(())
     }
 
+    // exchanges are checked in the currency of the value, whichever side of the exchange it is
+    fn check_max_value(&self, input: &CreateTransactionInput) -> Result<(), Error> {
+        let currency = input.value_currency;
+        let max_value = self.max_transaction_value.for_currency(currency);
+        if input.value > max_value {
+            let value = input.value;
+            return Err(ectx!(err ErrorContext::ExceedsMaxValue, ErrorKind::MalformedInput => value, currency, max_value));
+        }
+        Ok(())
+    }
+
+    // length of the category is checked in validation, here it's only matched against the configured ones
+    fn check_category(&self, input: &CreateTransactionInput) -> Result<(), Error> {
+        match (input.category.as_ref(), self.allowed_categories.as_ref()) {
+            (Some(category), Some(allowed)) if !allowed.contains(category) => {
+                let mut errors = ValidationErrors::new();
+                let mut error = ValidationError::new("not_allowed");
+                error.message = Some("Category is not one of the allowed ones".into());
+                error.add_param("category".into(), category);
+                errors.add("category", error);
+                Err(
+                    ectx!(err ErrorContext::UnknownCategory, ErrorKind::InvalidInput(serde_json::to_string(&errors).unwrap_or_default()) => input),
+                )
+            }
+            _ => Ok(()),
+        }
+    }
+
     fn get_from_account(&self, input: &CreateTransactionInput) -> Result<Account, Error> {
         self.accounts_repo
             .get(input.from)
@@ -121,7 +206,7 @@ impl ClassifierServiceImpl {
                 Ok(Some(to_account))
             }
             RecepientType::Address => {
-                let to_address = input.to.clone().to_account_address();
+                let to_address = to_address(input);
                 self.accounts_repo
                     .get_by_address(to_address.clone(), input.to_currency, AccountKind::Cr)
                     .map_err(ectx!(convert => to_address, input.to_currency))
@@ -129,6 +214,20 @@ impl ClassifierServiceImpl {
         }
     }
 
+    /// Checks that we don't own any accounts with this address, so that an external withdrawal never
+    /// goes to our own address, eg a user accidentially put ether address to receive stq tokens.
+    /// Internal addresses in the same currency are classified as internal transfers before we get here
+    fn ensure_address_currency_consistent(&self, address: BlockchainAddress, currency: Currency) -> Result<(), Error> {
+        let accounts = self.accounts_repo.filter_by_address(address.clone()).map_err({
+            let address = address.clone();
+            ectx!(try convert => address)
+        })?;
+        if !accounts.is_empty() {
+            return Err(ectx!(err ErrorContext::InvalidCurrency, ErrorKind::MalformedInput => address, currency));
+        }
+        Ok(())
+    }
+
     fn get_transaction_type(
         &self,
         input: &CreateTransactionInput,
@@ -149,20 +248,13 @@ impl ClassifierServiceImpl {
                         exchange_rate,
                     ))
                 } else {
+                    ensure_no_exchange(input)?;
                     Ok(TransactionType::Internal(from_account, to_account))
                 }
             }
             None => {
-                // check that we don't own any other accounts with this address
-                // eg a user accidentially put ether address to receive stq tokens
-                let to_address = input.to.clone().to_account_address();
-                let accounts = self.accounts_repo.filter_by_address(to_address.clone()).map_err({
-                    let to_address = to_address.clone();
-                    ectx!(try convert => to_address)
-                })?;
-                if accounts.len() != 0 {
-                    return Err(ectx!(err ErrorContext::InvalidCurrency, ErrorKind::MalformedInput => input.clone()));
-                }
+                let to_address = to_address(input);
+                self.ensure_address_currency_consistent(to_address.clone(), input.to_currency)?;
                 if from_account.currency != input.to_currency {
                     let (exchange_id, exchange_rate) = match (input.exchange_id, input.exchange_rate) {
                         (Some(exchange_id), Some(exchange_rate)) => (exchange_id, exchange_rate),
@@ -177,6 +269,7 @@ impl ClassifierServiceImpl {
                         exchange_rate,
                     ))
                 } else {
+                    ensure_no_exchange(input)?;
                     Ok(TransactionType::Withdrawal(from_account, to_address, input.to_currency))
                 }
             }
@@ -184,28 +277,154 @@ impl ClassifierServiceImpl {
     }
 }
 
+/// Exchange data for a same currency transfer is a client error rather than something to silently ignore,
+/// since the client likely picked the wrong account
+fn ensure_no_exchange(input: &CreateTransactionInput) -> Result<(), Error> {
+    if input.exchange_id.is_some() || input.exchange_rate.is_some() {
+        return Err(ectx!(err ErrorContext::UnexpectedExchange, ErrorKind::MalformedInput => input));
+    }
+    Ok(())
+}
+
+// values are converted with the rate and its inverse, so anything but a positive finite number is rejected
+fn check_exchange_rate(input: &CreateTransactionInput) -> Result<(), Error> {
+    match input.exchange_rate {
+        Some(rate) if !rate.is_finite() || rate <= 0.0 => Err(ectx!(err ErrorContext::InvalidRate, ErrorKind::MalformedInput => input)),
+        _ => Ok(()),
+    }
+}
+
+// addresses of accounts are stored normalized, so recipient address is normalized before lookups
+fn to_address(input: &CreateTransactionInput) -> BlockchainAddress {
+    input.to.to_account_address().normalized(input.to_currency)
+}
+
+// only internal transfers can be scheduled, withdrawals and exchanges are executed immediately
+fn check_hold_until(input: &CreateTransactionInput, tx_type: &TransactionType) -> Result<(), Error> {
+    match (input.hold_until, tx_type) {
+        (None, _) | (Some(_), TransactionType::Internal(_, _)) => Ok(()),
+        (Some(hold_until), _) => {
+            let mut errors = ValidationErrors::new();
+            let mut error = ValidationError::new("not_supported");
+            error.message = Some("Only internal transfers can be scheduled".into());
+            error.add_param("hold_until".into(), &hold_until.to_string());
+            errors.add("hold_until", error);
+            Err(ectx!(err ErrorContext::NotSupported, ErrorKind::InvalidInput(serde_json::to_string(&errors).unwrap_or_default()) => input))
+        }
+    }
+}
+
+// owner of an external address is unknown, so the expected recipient can only be checked on internal transfers
+fn check_expected_recipient(input: &CreateTransactionInput, tx_type: &TransactionType) -> Result<(), Error> {
+    let expected_recipient = match input.expected_recipient {
+        Some(expected_recipient) => expected_recipient,
+        None => return Ok(()),
+    };
+    let (code, message, context) = match tx_type {
+        TransactionType::Internal(_, to_account) | TransactionType::InternalExchange(_, to_account, _, _) => {
+            if to_account.user_id == expected_recipient {
+                return Ok(());
+            }
+            (
+                "recipient_mismatch",
+                "Recipient account belongs to another user",
+                ErrorContext::RecipientMismatch,
+            )
+        }
+        _ => (
+            "not_supported",
+            "Expected recipient can only be set for internal transfers",
+            ErrorContext::NotSupported,
+        ),
+    };
+    let mut errors = ValidationErrors::new();
+    let mut error = ValidationError::new(code);
+    error.message = Some(message.into());
+    error.add_param("expected_recipient".into(), &expected_recipient.to_string());
+    errors.add("to", error);
+    Err(ectx!(err context, ErrorKind::InvalidInput(serde_json::to_string(&errors).unwrap_or_default()) => input))
+}
+
+// gas limit can only be overridden for eth / stq withdrawals
+fn check_gas_limit_override(input: &CreateTransactionInput, tx_type: &TransactionType) -> Result<(), Error> {
+    match (input.gas_limit_override, tx_type) {
+        (None, _)
+        | (Some(_), TransactionType::Withdrawal(_, _, Currency::Eth))
+        | (Some(_), TransactionType::Withdrawal(_, _, Currency::Stq))
+        | (Some(_), TransactionType::WithdrawalExchange(_, _, Currency::Eth, _, _))
+        | (Some(_), TransactionType::WithdrawalExchange(_, _, Currency::Stq, _, _)) => Ok(()),
+        (Some(gas_limit_override), _) => {
+            let mut errors = ValidationErrors::new();
+            let mut error = ValidationError::new("not_supported");
+            error.message = Some("Gas limit can only be set for ETH and STQ withdrawals".into());
+            error.add_param("gas_limit_override".into(), &gas_limit_override.to_string());
+            errors.add("gas_limit_override", error);
+            Err(ectx!(err ErrorContext::NotSupported, ErrorKind::InvalidInput(serde_json::to_string(&errors).unwrap_or_default()) => input))
+        }
+    }
+}
+
+// fee tiers only make sense for withdrawals, internal transfers are not charged a network fee
+fn check_fee_tier(input: &CreateTransactionInput, tx_type: &TransactionType) -> Result<(), Error> {
+    match (input.fee_tier, tx_type) {
+        (None, _) | (Some(_), TransactionType::Withdrawal(..)) | (Some(_), TransactionType::WithdrawalExchange(..)) => Ok(()),
+        (Some(fee_tier), _) => {
+            let mut errors = ValidationErrors::new();
+            let mut error = ValidationError::new("not_supported");
+            error.message = Some("Fee tier can only be set for withdrawals".into());
+            error.add_param("fee_tier".into(), &fee_tier);
+            errors.add("fee_tier", error);
+            Err(ectx!(err ErrorContext::NotSupported, ErrorKind::InvalidInput(serde_json::to_string(&errors).unwrap_or_default()) => input))
+        }
+    }
+}
+
 impl ClassifierService for ClassifierServiceImpl {
     fn validate_and_classify_transaction(&self, input: &CreateTransactionInput) -> Result<TransactionType, Error> {
         input
             .validate()
             .map_err(|e| ectx!(try err e.clone(), ErrorKind::InvalidInput(serde_json::to_string(&e).unwrap_or_default()) => input))?;
+        // drained value is the exact balance, so it is never rejected for precision
+        if !input.drain {
+            self.check_amount_precision(input)?;
+        }
+        self.check_max_value(input)?;
+        self.check_category(input)?;
+        self.check_currency_enabled(input.value_currency)?;
+        self.check_currency_enabled(input.to_currency)?;
         let from_account = self.get_from_account(input)?;
+        self.check_currency_enabled(from_account.currency)?;
+        // rate is used to convert values starting with the daily limit, so it is checked before anything else
+        check_exchange_rate(input)?;
         self.check_account_daily_limit(input, &from_account)?;
         let to_account = self.get_to_account(input)?;
-        self.get_transaction_type(input, from_account, to_account)
+        let tx_type = self.get_transaction_type(input, from_account, to_account)?;
+        match tx_type {
+            TransactionType::Withdrawal(_, ref to_address, to_currency)
+            | TransactionType::WithdrawalExchange(_, ref to_address, to_currency, _, _) => {
+                self.check_address_screening(to_address, to_currency)?
+            }
+            _ => (),
+        }
+        check_hold_until(input, &tx_type)?;
+        check_expected_recipient(input, &tx_type)?;
+        check_gas_limit_override(input, &tx_type)?;
+        check_fee_tier(input, &tx_type)?;
+        Ok(tx_type)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use config::Config;
+    use config::{Config, TransactionCategories};
     use repos::*;
+    use services::MockClock;
 
     fn create_classifier_service(accounts_repo: Arc<dyn AccountsRepo>) -> ClassifierServiceImpl {
         let config = Config::new().unwrap();
         let transactions_repo = Arc::new(TransactionsRepoMock::default());
-        ClassifierServiceImpl::new(&config, accounts_repo, transactions_repo)
+        ClassifierServiceImpl::new(&config, accounts_repo, transactions_repo, Arc::new(MockClock::default()))
     }
 
     fn create_internal_transaction_input(
@@ -229,6 +448,16 @@ mod tests {
             fee: Amount::default(),
             exchange_id: None,
             exchange_rate: None,
+            fiat_value: None,
+            fiat_rate: None,
+            hold_until: None,
+            gas_limit_override: None,
+            drain: false,
+            fee_tier: None,
+            fee_omitted: false,
+            user_note: None,
+            category: None,
+            expected_recipient: None,
         }
     }
 
@@ -255,6 +484,16 @@ mod tests {
             fee: Amount::default(),
             exchange_id,
             exchange_rate,
+            fiat_value: None,
+            fiat_rate: None,
+            hold_until: None,
+            gas_limit_override: None,
+            drain: false,
+            fee_tier: None,
+            fee_omitted: false,
+            user_note: None,
+            category: None,
+            expected_recipient: None,
         }
     }
 
@@ -278,6 +517,16 @@ mod tests {
             fee: Amount::default(),
             exchange_id: None,
             exchange_rate: None,
+            fiat_value: None,
+            fiat_rate: None,
+            hold_until: None,
+            gas_limit_override: None,
+            drain: false,
+            fee_tier: None,
+            fee_omitted: false,
+            user_note: None,
+            category: None,
+            expected_recipient: None,
         }
     }
 
@@ -303,6 +552,16 @@ mod tests {
             fee: Amount::default(),
             exchange_id,
             exchange_rate,
+            fiat_value: None,
+            fiat_rate: None,
+            hold_until: None,
+            gas_limit_override: None,
+            drain: false,
+            fee_tier: None,
+            fee_omitted: false,
+            user_note: None,
+            category: None,
+            expected_recipient: None,
         }
     }
 
@@ -345,6 +604,284 @@ mod tests {
         assert_eq!(res, TransactionType::Internal(acc1, acc2));
     }
 
+    #[test]
+    fn test_classify_over_precise_amount() {
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let user_id = UserId::generate();
+        let mut config = Config::new().unwrap();
+        config.amount_precision.btc = 6;
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let service = ClassifierServiceImpl::new(&config, accounts_repo.clone(), transactions_repo, Arc::new(MockClock::default()));
+        let mut new_account = NewAccount::default();
+        new_account.user_id = user_id;
+        new_account.currency = Currency::Btc;
+        let acc1 = accounts_repo.create(new_account.clone()).unwrap();
+        let mut new_account = NewAccount::default();
+        new_account.user_id = user_id;
+        new_account.currency = Currency::Btc;
+        let acc2 = accounts_repo.create(new_account).unwrap();
+
+        // 0.01234567 btc has 8 fractional digits
+        let input = create_internal_transaction_input(
+            user_id,
+            acc1.id,
+            acc1.currency,
+            Recepient::new(acc2.id.to_string()),
+            RecepientType::Account,
+            acc2.currency,
+            Amount::new(1_234_567),
+        );
+        match service.validate_and_classify_transaction(&input).unwrap_err().kind() {
+            ErrorKind::MalformedInput => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+
+        // 0.012345 btc fits
+        let input = CreateTransactionInput {
+            value: Amount::new(1_234_500),
+            ..input
+        };
+        let res = service.validate_and_classify_transaction(&input).unwrap();
+        assert_eq!(res, TransactionType::Internal(acc1, acc2));
+    }
+
+    #[test]
+    fn test_classify_category() {
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let user_id = UserId::generate();
+        let mut new_account = NewAccount::default();
+        new_account.user_id = user_id;
+        new_account.currency = Currency::Btc;
+        let acc1 = accounts_repo.create(new_account).unwrap();
+        let mut new_account = NewAccount::default();
+        new_account.currency = Currency::Btc;
+        let acc2 = accounts_repo.create(new_account).unwrap();
+        let input = create_internal_transaction_input(
+            user_id,
+            acc1.id,
+            acc1.currency,
+            Recepient::new(acc2.id.to_string()),
+            RecepientType::Account,
+            acc2.currency,
+            Amount::new(1_000),
+        );
+
+        // free-form categories are limited only by length
+        let config = Config::new().unwrap();
+        let service = ClassifierServiceImpl::new(
+            &config,
+            accounts_repo.clone(),
+            Arc::new(TransactionsRepoMock::default()),
+            Arc::new(MockClock::default()),
+        );
+        let input = CreateTransactionInput {
+            category: Some("salary".to_string()),
+            ..input
+        };
+        assert!(service.validate_and_classify_transaction(&input).is_ok());
+        for category in vec!["".to_string(), "a".repeat(65)] {
+            let input = CreateTransactionInput {
+                category: Some(category),
+                ..input.clone()
+            };
+            match service.validate_and_classify_transaction(&input).unwrap_err().kind() {
+                ErrorKind::InvalidInput(_) => (),
+                kind => panic!("unexpected error kind: {:?}", kind),
+            }
+        }
+
+        let mut config = Config::new().unwrap();
+        config.transaction_categories = Some(TransactionCategories {
+            allowed: vec!["fees".to_string(), "refund".to_string()],
+        });
+        let service = ClassifierServiceImpl::new(
+            &config,
+            accounts_repo,
+            Arc::new(TransactionsRepoMock::default()),
+            Arc::new(MockClock::default()),
+        );
+        match service.validate_and_classify_transaction(&input).unwrap_err().kind() {
+            ErrorKind::InvalidInput(_) => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+        let input = CreateTransactionInput {
+            category: Some("refund".to_string()),
+            ..input
+        };
+        assert!(service.validate_and_classify_transaction(&input).is_ok());
+        let input = CreateTransactionInput { category: None, ..input };
+        assert!(service.validate_and_classify_transaction(&input).is_ok());
+    }
+
+    #[test]
+    fn test_classify_max_transaction_value() {
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let user_id = UserId::generate();
+        let mut config = Config::new().unwrap();
+        config.max_transaction_value.btc = 0.01;
+        config.max_transaction_value.eth = 0.5;
+        config.max_transaction_value.stq = 1000.0;
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let service = ClassifierServiceImpl::new(&config, accounts_repo.clone(), transactions_repo, Arc::new(MockClock::default()));
+        let cases = vec![
+            (Currency::Btc, Amount::new(1_000_000)),
+            (Currency::Eth, Amount::new(500_000_000_000_000_000)),
+            (Currency::Stq, Amount::new(1_000_000_000_000_000_000_000)),
+        ];
+        for (currency, max_value) in cases {
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user_id;
+            new_account.currency = currency;
+            let acc1 = accounts_repo.create(new_account.clone()).unwrap();
+            let acc2 = accounts_repo.create(new_account).unwrap();
+
+            let input = create_internal_transaction_input(
+                user_id,
+                acc1.id,
+                acc1.currency,
+                Recepient::new(acc2.id.to_string()),
+                RecepientType::Account,
+                acc2.currency,
+                max_value,
+            );
+            let res = service.validate_and_classify_transaction(&input).unwrap();
+            assert_eq!(res, TransactionType::Internal(acc1, acc2));
+
+            let input = CreateTransactionInput {
+                value: max_value.checked_add(Amount::new(1)).unwrap(),
+                ..input
+            };
+            match service.validate_and_classify_transaction(&input).unwrap_err().kind() {
+                ErrorKind::MalformedInput => (),
+                kind => panic!("unexpected error kind: {:?}", kind),
+            }
+        }
+
+        // exchange value is checked in its own currency, here 1001 stq bought for btc
+        let mut new_account = NewAccount::default();
+        new_account.user_id = user_id;
+        new_account.currency = Currency::Btc;
+        let btc = accounts_repo.create(new_account.clone()).unwrap();
+        new_account.currency = Currency::Stq;
+        let stq = accounts_repo.create(new_account).unwrap();
+        let input = create_internal_exchange_transaction_input(
+            user_id,
+            btc.id,
+            btc.currency,
+            Recepient::new(stq.id.to_string()),
+            RecepientType::Account,
+            stq.currency,
+            Amount::new(1_001_000_000_000_000_000_000),
+            Some(ExchangeId::generate()),
+            Some(0.00001),
+        );
+        let input = CreateTransactionInput {
+            value_currency: Currency::Stq,
+            ..input
+        };
+        match service.validate_and_classify_transaction(&input).unwrap_err().kind() {
+            ErrorKind::MalformedInput => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn test_classify_disabled_currency() {
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let user_id = UserId::generate();
+        let mut config = Config::new().unwrap();
+        config.system.enabled_currencies.remove(&Currency::Stq);
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let service = ClassifierServiceImpl::new(&config, accounts_repo.clone(), transactions_repo, Arc::new(MockClock::default()));
+        let mut new_account = NewAccount::default();
+        new_account.user_id = user_id;
+        new_account.currency = Currency::Stq;
+        let stq1 = accounts_repo.create(new_account.clone()).unwrap();
+        let mut new_account = NewAccount::default();
+        new_account.user_id = user_id;
+        new_account.currency = Currency::Stq;
+        let stq2 = accounts_repo.create(new_account).unwrap();
+        let mut new_account = NewAccount::default();
+        new_account.user_id = user_id;
+        new_account.currency = Currency::Btc;
+        let btc = accounts_repo.create(new_account).unwrap();
+
+        // transfers between accounts of disabled currency
+        let input = create_internal_transaction_input(
+            user_id,
+            stq1.id,
+            stq1.currency,
+            Recepient::new(stq2.id.to_string()),
+            RecepientType::Account,
+            stq2.currency,
+            Amount::new(0),
+        );
+        match service.validate_and_classify_transaction(&input).unwrap_err().kind() {
+            ErrorKind::MalformedInput => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+
+        // exchange into disabled currency
+        let input = create_withdraw_exchange_transaction_input(
+            user_id,
+            btc.id,
+            btc.currency,
+            BlockchainAddress::default(),
+            Currency::Stq,
+            Amount::new(0),
+            Some(ExchangeId::generate()),
+            Some(1f64),
+        );
+        match service.validate_and_classify_transaction(&input).unwrap_err().kind() {
+            ErrorKind::MalformedInput => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+
+        // enabled currencies are still accepted
+        let input = create_withdraw_transaction_input(
+            user_id,
+            btc.id,
+            btc.currency,
+            BlockchainAddress::default(),
+            Currency::Btc,
+            Amount::new(0),
+        );
+        assert!(service.validate_and_classify_transaction(&input).is_ok());
+    }
+
+    #[test]
+    fn test_classify_internal_eth_address_case() {
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let user_id = UserId::generate();
+        let service = create_classifier_service(accounts_repo.clone());
+        let mut new_account = NewAccount::default();
+        new_account.user_id = user_id;
+        new_account.currency = Currency::Eth;
+        let acc1 = accounts_repo.create(new_account.clone()).unwrap();
+        let create_account = CreateAccount {
+            user_id,
+            currency: Currency::Eth,
+            ..Default::default()
+        };
+        let checksummed = "5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed";
+        let new_account: NewAccount = (create_account, BlockchainAddress::new(checksummed.to_string())).into();
+        let acc2 = accounts_repo.create(new_account).unwrap();
+
+        for address in &[checksummed, "5aaeb6053f3e94c9b9a09f33669435e7ef1beaed"] {
+            let input = create_internal_transaction_input(
+                user_id,
+                acc1.id,
+                acc1.currency,
+                Recepient::new(address.to_string()),
+                RecepientType::Address,
+                Currency::Eth,
+                Amount::new(0),
+            );
+            let res = service.validate_and_classify_transaction(&input).unwrap();
+            assert_eq!(res, TransactionType::Internal(acc1.clone(), acc2.clone()));
+        }
+    }
+
     #[test]
     fn test_classify_internal_one_account() {
         let accounts_repo = Arc::new(AccountsRepoMock::default());
@@ -417,6 +954,51 @@ mod tests {
         assert!(res.is_err());
     }
 
+    #[test]
+    fn test_classify_daily_limit_skips_inactive_transactions() {
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let user_id = UserId::generate();
+        let service = ClassifierServiceImpl::new(
+            &Config::new().unwrap(),
+            accounts_repo.clone(),
+            transactions_repo.clone(),
+            Arc::new(MockClock::default()),
+        );
+        let mut new_account = NewAccount::default();
+        new_account.user_id = user_id;
+        new_account.currency = Currency::Eth;
+        let acc1 = accounts_repo.create(new_account).unwrap();
+        let mut new_account = NewAccount::default();
+        new_account.user_id = user_id;
+        new_account.currency = Currency::Eth;
+        let acc2 = accounts_repo.create(new_account).unwrap();
+        // 1 eth daily limit would be exceeded, if transactions that never moved funds were counted
+        for status in vec![
+            TransactionStatus::Scheduled,
+            TransactionStatus::Cancelled,
+            TransactionStatus::Failed,
+        ] {
+            let mut tx = NewTransaction::default();
+            tx.dr_account_id = acc1.id;
+            tx.currency = Currency::Eth;
+            tx.value = Amount::new(WEI_IN_ETH);
+            tx.status = status;
+            transactions_repo.create(tx).unwrap();
+        }
+
+        let input = create_internal_transaction_input(
+            user_id,
+            acc1.id,
+            acc1.currency,
+            Recepient::new(acc2.id.to_string()),
+            RecepientType::Account,
+            acc2.currency,
+            Amount::new(WEI_IN_ETH / 2),
+        );
+        assert!(service.validate_and_classify_transaction(&input).is_ok());
+    }
+
     #[test]
     fn test_classify_internal_wrong_currencies() {
         let accounts_repo = Arc::new(AccountsRepoMock::default());
@@ -589,6 +1171,37 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_classify_internal_exchange_with_fiat_value() {
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let user_id = UserId::generate();
+        let service = create_classifier_service(accounts_repo.clone());
+        let mut new_account = NewAccount::default();
+        new_account.user_id = user_id;
+        new_account.currency = Currency::Btc;
+        let acc1 = accounts_repo.create(new_account.clone()).unwrap();
+        let mut new_account = NewAccount::default();
+        new_account.user_id = user_id;
+        new_account.currency = Currency::Stq;
+        let acc2 = accounts_repo.create(new_account).unwrap();
+
+        let mut input = create_internal_exchange_transaction_input(
+            user_id,
+            acc1.id,
+            acc1.currency,
+            Recepient::new(acc2.id.to_string()),
+            RecepientType::Account,
+            acc2.currency,
+            Amount::new(0),
+            Some(ExchangeId::generate()),
+            Some(1f64),
+        );
+        input.fiat_value = Some(FiatValue::new(FiatCurrency::Usd, Amount::new(10_000)));
+
+        let res = service.validate_and_classify_transaction(&input);
+        assert!(res.is_err());
+    }
+
     #[test]
     fn test_classify_internal_exchange_wrong_exchange_data() {
         let accounts_repo = Arc::new(AccountsRepoMock::default());
@@ -786,6 +1399,54 @@ mod tests {
         assert_eq!(res, TransactionType::Withdrawal(acc1.clone(), address, acc1.currency));
     }
 
+    #[test]
+    fn test_classify_withdraw_address_screening() {
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let user_id = UserId::generate();
+        let blocked = BlockchainAddress::new("D8F1C3E2A5B4967E8F0A1B2C3D4E5F6A7B8C9D0E".to_string());
+        let mut config = Config::new().unwrap();
+        config.address_screening.denylist = vec![blocked.clone()];
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let service = ClassifierServiceImpl::new(
+            &config,
+            accounts_repo.clone(),
+            transactions_repo.clone(),
+            Arc::new(MockClock::default()),
+        );
+        let mut new_account = NewAccount::default();
+        new_account.user_id = user_id;
+        new_account.currency = Currency::Eth;
+        let acc1 = accounts_repo.create(new_account).unwrap();
+
+        // eth addresses are case insensitive, so the lowercased one is blocked too
+        for address in vec![blocked.clone(), blocked.normalized(Currency::Eth)] {
+            let input = create_withdraw_transaction_input(user_id, acc1.id, acc1.currency, address, acc1.currency, Amount::new(0));
+            let res = service.validate_and_classify_transaction(&input);
+            match res.unwrap_err().kind() {
+                ErrorKind::MalformedInput => (),
+                kind => panic!("unexpected error kind: {:?}", kind),
+            }
+        }
+        let address = BlockchainAddress::default();
+        let input = create_withdraw_transaction_input(user_id, acc1.id, acc1.currency, address.clone(), acc1.currency, Amount::new(0));
+        let res = service.validate_and_classify_transaction(&input).unwrap();
+        assert_eq!(res, TransactionType::Withdrawal(acc1.clone(), address.clone(), acc1.currency));
+
+        // with an allowlist, only the listed addresses are permitted
+        let allowed = BlockchainAddress::default();
+        config.address_screening.allowlist = Some(vec![allowed.clone()]);
+        let service = ClassifierServiceImpl::new(&config, accounts_repo.clone(), transactions_repo, Arc::new(MockClock::default()));
+        let input = create_withdraw_transaction_input(user_id, acc1.id, acc1.currency, address, acc1.currency, Amount::new(0));
+        let res = service.validate_and_classify_transaction(&input);
+        match res.unwrap_err().kind() {
+            ErrorKind::MalformedInput => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+        let input = create_withdraw_transaction_input(user_id, acc1.id, acc1.currency, allowed.clone(), acc1.currency, Amount::new(0));
+        let res = service.validate_and_classify_transaction(&input).unwrap();
+        assert_eq!(res, TransactionType::Withdrawal(acc1, allowed, Currency::Eth));
+    }
+
     #[test]
     fn test_classify_withdraw_exceed_limit() {
         let accounts_repo = Arc::new(AccountsRepoMock::default());
@@ -808,6 +1469,115 @@ mod tests {
         assert!(res.is_err());
     }
 
+    #[test]
+    fn test_classify_withdraw_scheduled() {
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let user_id = UserId::generate();
+        let service = create_classifier_service(accounts_repo.clone());
+        let mut new_account = NewAccount::default();
+        new_account.user_id = user_id;
+        let acc1 = accounts_repo.create(new_account.clone()).unwrap();
+
+        let mut input = create_withdraw_transaction_input(
+            user_id,
+            acc1.id,
+            acc1.currency,
+            BlockchainAddress::default(),
+            acc1.currency,
+            Amount::new(0),
+        );
+        input.hold_until = Some(::chrono::Utc::now().naive_utc() + Duration::hours(1));
+
+        let res = service.validate_and_classify_transaction(&input);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_classify_expected_recipient() {
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let user_id = UserId::generate();
+        let recipient_id = UserId::generate();
+        let service = create_classifier_service(accounts_repo.clone());
+        let mut new_account = NewAccount::default();
+        new_account.user_id = user_id;
+        let acc1 = accounts_repo.create(new_account).unwrap();
+        let mut new_account = NewAccount::default();
+        new_account.user_id = recipient_id;
+        let acc2 = accounts_repo.create(new_account).unwrap();
+
+        let mut input = create_internal_transaction_input(
+            user_id,
+            acc1.id,
+            acc1.currency,
+            Recepient::new(acc2.address.to_string()),
+            RecepientType::Address,
+            acc2.currency,
+            Amount::new(0),
+        );
+        input.expected_recipient = Some(recipient_id);
+        let res = service.validate_and_classify_transaction(&input).unwrap();
+        assert_eq!(res, TransactionType::Internal(acc1.clone(), acc2));
+
+        // account of another user, e.g. a typo in the address
+        input.expected_recipient = Some(UserId::generate());
+        match service.validate_and_classify_transaction(&input).unwrap_err().kind() {
+            ErrorKind::InvalidInput(errors) => assert!(errors.contains("recipient_mismatch")),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+
+        // owner of an external address can't be checked
+        let mut input = create_withdraw_transaction_input(
+            user_id,
+            acc1.id,
+            acc1.currency,
+            BlockchainAddress::default(),
+            acc1.currency,
+            Amount::new(0),
+        );
+        input.expected_recipient = Some(recipient_id);
+        match service.validate_and_classify_transaction(&input).unwrap_err().kind() {
+            ErrorKind::InvalidInput(errors) => assert!(errors.contains("not_supported")),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn test_classify_withdraw_gas_limit_override() {
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let user_id = UserId::generate();
+        let service = create_classifier_service(accounts_repo.clone());
+        let mut new_account = NewAccount::default();
+        new_account.user_id = user_id;
+        new_account.currency = Currency::Stq;
+        let stq_account = accounts_repo.create(new_account.clone()).unwrap();
+        new_account.id = AccountId::generate();
+        new_account.address = BlockchainAddress::default();
+        new_account.currency = Currency::Btc;
+        let btc_account = accounts_repo.create(new_account).unwrap();
+
+        let mut input = create_withdraw_transaction_input(
+            user_id,
+            stq_account.id,
+            stq_account.currency,
+            BlockchainAddress::default(),
+            stq_account.currency,
+            Amount::new(0),
+        );
+        input.gas_limit_override = Some(100_000);
+        assert!(service.validate_and_classify_transaction(&input).is_ok());
+
+        let mut input = create_withdraw_transaction_input(
+            user_id,
+            btc_account.id,
+            btc_account.currency,
+            BlockchainAddress::default(),
+            btc_account.currency,
+            Amount::new(0),
+        );
+        input.gas_limit_override = Some(100_000);
+        assert!(service.validate_and_classify_transaction(&input).is_err());
+    }
+
     #[test]
     fn test_classify_withdraw_wrong_currencies() {
         let accounts_repo = Arc::new(AccountsRepoMock::default());
@@ -829,6 +1599,64 @@ mod tests {
         assert!(res.is_err());
     }
 
+    #[test]
+    fn test_classify_withdraw_to_own_address_in_other_currency() {
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let user_id = UserId::generate();
+        let service = create_classifier_service(accounts_repo.clone());
+        let mut new_account = NewAccount::default();
+        new_account.user_id = user_id;
+        new_account.currency = Currency::Btc;
+        let acc1 = accounts_repo.create(new_account.clone()).unwrap();
+        let mut new_account = NewAccount::default();
+        new_account.user_id = user_id;
+        new_account.currency = Currency::Eth;
+        let eth_account = accounts_repo.create(new_account).unwrap();
+
+        let input = create_withdraw_transaction_input(
+            user_id,
+            acc1.id,
+            acc1.currency,
+            eth_account.address.clone(),
+            Currency::Btc,
+            Amount::new(0),
+        );
+        let res = service.validate_and_classify_transaction(&input);
+        assert!(res.is_err());
+
+        let input = create_withdraw_exchange_transaction_input(
+            user_id,
+            acc1.id,
+            acc1.currency,
+            eth_account.address.clone(),
+            Currency::Stq,
+            Amount::new(0),
+            Some(ExchangeId::generate()),
+            Some(1f64),
+        );
+        let res = service.validate_and_classify_transaction(&input);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_ensure_address_currency_consistent() {
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let service = create_classifier_service(accounts_repo.clone());
+        let mut new_account = NewAccount::default();
+        new_account.currency = Currency::Eth;
+        let eth_account = accounts_repo.create(new_account).unwrap();
+
+        assert!(service
+            .ensure_address_currency_consistent(eth_account.address.clone(), Currency::Eth)
+            .is_err());
+        assert!(service
+            .ensure_address_currency_consistent(eth_account.address.clone(), Currency::Btc)
+            .is_err());
+        assert!(service
+            .ensure_address_currency_consistent(BlockchainAddress::default(), Currency::Btc)
+            .is_ok());
+    }
+
     #[test]
     fn test_classify_withdraw_wrong_account_ids() {
         let accounts_repo = Arc::new(AccountsRepoMock::default());
@@ -933,4 +1761,98 @@ mod tests {
         let res = service.validate_and_classify_transaction(&input);
         assert!(res.is_err());
     }
+
+    #[test]
+    fn test_classify_exchange_invalid_rate() {
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let user_id = UserId::generate();
+        let service = create_classifier_service(accounts_repo.clone());
+        let mut new_account = NewAccount::default();
+        new_account.user_id = user_id;
+        new_account.currency = Currency::Btc;
+        let acc1 = accounts_repo.create(new_account.clone()).unwrap();
+        new_account.currency = Currency::Stq;
+        let acc2 = accounts_repo.create(new_account).unwrap();
+        for rate in vec![0f64, -1f64, ::std::f64::NAN, ::std::f64::INFINITY] {
+            let input = create_internal_exchange_transaction_input(
+                user_id,
+                acc1.id,
+                acc1.currency,
+                Recepient::new(acc2.id.to_string()),
+                RecepientType::Account,
+                acc2.currency,
+                Amount::new(0),
+                Some(ExchangeId::generate()),
+                Some(rate),
+            );
+            match service.validate_and_classify_transaction(&input).unwrap_err().kind() {
+                ErrorKind::MalformedInput => (),
+                kind => panic!("unexpected error kind for rate {}: {:?}", rate, kind),
+            }
+
+            let input = create_withdraw_exchange_transaction_input(
+                user_id,
+                acc1.id,
+                acc1.currency,
+                BlockchainAddress::default(),
+                Currency::Stq,
+                Amount::new(0),
+                Some(ExchangeId::generate()),
+                Some(rate),
+            );
+            match service.validate_and_classify_transaction(&input).unwrap_err().kind() {
+                ErrorKind::MalformedInput => (),
+                kind => panic!("unexpected error kind for rate {}: {:?}", rate, kind),
+            }
+        }
+    }
+
+    #[test]
+    fn test_classify_same_currency_with_exchange_data() {
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let user_id = UserId::generate();
+        let service = create_classifier_service(accounts_repo.clone());
+        let mut new_account = NewAccount::default();
+        new_account.user_id = user_id;
+        new_account.currency = Currency::Btc;
+        let acc1 = accounts_repo.create(new_account.clone()).unwrap();
+        let acc2 = accounts_repo.create(new_account).unwrap();
+        let exchange_data = vec![
+            (Some(ExchangeId::generate()), Some(1f64)),
+            (Some(ExchangeId::generate()), None),
+            (None, Some(1f64)),
+        ];
+        for (exchange_id, exchange_rate) in exchange_data {
+            let input = create_internal_exchange_transaction_input(
+                user_id,
+                acc1.id,
+                acc1.currency,
+                Recepient::new(acc2.id.to_string()),
+                RecepientType::Account,
+                acc2.currency,
+                Amount::new(0),
+                exchange_id,
+                exchange_rate,
+            );
+            match service.validate_and_classify_transaction(&input).unwrap_err().kind() {
+                ErrorKind::MalformedInput => (),
+                kind => panic!("unexpected error kind: {:?}", kind),
+            }
+
+            let input = create_withdraw_exchange_transaction_input(
+                user_id,
+                acc1.id,
+                acc1.currency,
+                BlockchainAddress::default(),
+                Currency::Btc,
+                Amount::new(0),
+                exchange_id,
+                exchange_rate,
+            );
+            match service.validate_and_classify_transaction(&input).unwrap_err().kind() {
+                ErrorKind::MalformedInput => (),
+                kind => panic!("unexpected error kind: {:?}", kind),
+            }
+        }
+    }
 }
@@ -2,16 +2,21 @@ use std::sync::Arc;
 
 use super::super::error::*;
 use super::super::system::*;
+use config::Config;
 use models::*;
 use prelude::*;
 use repos::{AccountsRepo, BlockchainTransactionsRepo, PendingBlockchainTransactionsRepo};
 
 pub trait ConverterService: Send + Sync + 'static {
     fn convert_transaction(&self, transactions: Vec<Transaction>) -> Result<TransactionOut, Error>;
+    /// Same as `convert_transaction`, but `None` for groups that are never shown to users (approvals),
+    /// so that listings skip them instead of failing the whole page
+    fn convert_listed_transaction(&self, transactions: Vec<Transaction>) -> Result<Option<TransactionOut>, Error>;
 }
 
 #[derive(Clone)]
 pub struct ConverterServiceImpl {
+    config: Arc<Config>,
     accounts_repo: Arc<AccountsRepo>,
     pending_blockchain_transactions_repo: Arc<PendingBlockchainTransactionsRepo>,
     blockchain_transactions_repo: Arc<BlockchainTransactionsRepo>,
@@ -20,12 +25,14 @@ pub struct ConverterServiceImpl {
 
 impl ConverterServiceImpl {
     pub fn new(
+        config: Arc<Config>,
         accounts_repo: Arc<AccountsRepo>,
         pending_blockchain_transactions_repo: Arc<PendingBlockchainTransactionsRepo>,
         blockchain_transactions_repo: Arc<BlockchainTransactionsRepo>,
         system_service: Arc<SystemService>,
     ) -> Self {
         Self {
+            config,
             accounts_repo,
             pending_blockchain_transactions_repo,
             blockchain_transactions_repo,
@@ -34,7 +41,7 @@ impl ConverterServiceImpl {
     }
 
     // 1) Deposit
-    //   Always 1 tx with status Done
+    //   Always 1 tx, reported as Pending until the blockchain tx has enough confirmations
     fn convert_deposit_transaction(&self, transactions: Vec<Transaction>) -> Result<TransactionOut, Error> {
         if transactions.len() != 1 {
             return Err(ectx!(err ErrorContext::InvalidTransactionStructure, ErrorKind::Internal => transactions));
@@ -59,6 +66,12 @@ impl ConverterServiceImpl {
         let blockchain_tx = Into::<BlockchainTransaction>::into(blockchain_tx.unwrap())
             .normalized()
             .ok_or(ectx!(try err ErrorContext::BalanceOverflow, ErrorKind::Internal))?;
+        let required_confirmations = self.config.required_confirmations.for_currency(tx.currency);
+        let status = if blockchain_tx.confirmations < required_confirmations {
+            TransactionStatus::Pending
+        } else {
+            tx.status
+        };
         let from: Vec<_> = blockchain_tx
             .from
             .into_iter()
@@ -82,6 +95,7 @@ impl ConverterServiceImpl {
         };
         Ok(TransactionOut {
             id: tx.gid,
+            kind: TransactionGroupKind::Deposit,
             user_id: to_account.user_id,
             from,
             to,
@@ -90,8 +104,16 @@ impl ConverterServiceImpl {
             to_value: tx.value,
             to_currency: tx.currency,
             fee: Amount::new(0),
-            status: tx.status,
+            fee_price: None,
+            gross_fee: None,
+            network_fee: None,
+            fee_variance: None,
+            status,
             blockchain_tx_ids: tx.blockchain_tx_id.iter().cloned().collect(),
+            confirmations: Some(blockchain_tx.confirmations as u64),
+            failure_reason: None,
+            user_note: None,
+            category: None,
             created_at: tx.created_at,
             updated_at: tx.updated_at,
         })
@@ -133,6 +155,7 @@ impl ConverterServiceImpl {
 
         Ok(TransactionOut {
             id: tx.gid,
+            kind: TransactionGroupKind::Internal,
             from,
             to,
             user_id: to_account.user_id,
@@ -141,8 +164,16 @@ impl ConverterServiceImpl {
             to_value: tx.value,
             to_currency: tx.currency,
             fee: Amount::new(0),
+            fee_price: None,
+            gross_fee: None,
+            network_fee: None,
+            fee_variance: None,
             status: tx.status,
             blockchain_tx_ids: tx.blockchain_tx_id.iter().cloned().collect(),
+            confirmations: None,
+            failure_reason: None,
+            user_note: None,
+            category: None,
             created_at: tx.created_at,
             updated_at: tx.updated_at,
         })
@@ -216,6 +247,7 @@ impl ConverterServiceImpl {
         let blockchain_tx_ids: Vec<_> = withdrawal_txs.into_iter().flat_map(|tx| tx.blockchain_tx_id.into_iter()).collect();
         Ok(TransactionOut {
             id: withdrawal_tx.gid,
+            kind: TransactionGroupKind::Reversal,
             user_id: withdrawal_account.user_id,
             from,
             to,
@@ -224,17 +256,28 @@ impl ConverterServiceImpl {
             to_value: value,
             to_currency: withdrawal_tx.currency,
             fee: fee_tx.value,
+            fee_price: None,
+            gross_fee: None,
+            network_fee: None,
+            fee_variance: None,
             status: TransactionStatus::Done,
             blockchain_tx_ids,
+            confirmations: None,
+            failure_reason: None,
+            user_note: None,
+            category: None,
             created_at,
             updated_at,
         })
     }
 
     // 4) InternalMulti:
-    //   two txs: MultiFrom - Done, MultiTo - Done
+    //   a) two txs: MultiFrom - Done, MultiTo - Done
+    //   b) three txs: MultiFrom - Done, MultiTo - Done, Fee - Done (exchange spread)
+    // MultiTo is the value received at the net rate, spread is not shown as a fee
+    // since it's in the `to` currency
     fn convert_internal_multi_transaction(&self, transactions: Vec<Transaction>) -> Result<TransactionOut, Error> {
-        if transactions.len() != 2 {
+        if transactions.len() != 2 && transactions.len() != 3 {
             return Err(ectx!(err ErrorContext::InvalidTransactionStructure, ErrorKind::Internal => transactions));
         }
         let from_tx = transactions
@@ -272,6 +315,7 @@ impl ConverterServiceImpl {
         };
         Ok(TransactionOut {
             id: from_tx.gid,
+            kind: TransactionGroupKind::InternalMulti,
             user_id: to_account.user_id,
             from,
             to,
@@ -280,16 +324,50 @@ impl ConverterServiceImpl {
             to_value: to_tx.value,
             to_currency: to_tx.currency,
             fee: Amount::new(0),
+            fee_price: None,
+            gross_fee: None,
+            network_fee: None,
+            fee_variance: None,
             status: TransactionStatus::Done,
             blockchain_tx_ids: vec![],
+            confirmations: None,
+            failure_reason: None,
+            user_note: None,
+            category: None,
             created_at: from_tx.created_at,
             updated_at: from_tx.updated_at,
         })
     }
 
+    // Blockchain tx can be in pending txs as well, if it's not yet seen by blockchain fetcher
+    fn get_blockchain_tx(&self, hash: BlockchainTransactionId) -> Result<Option<BlockchainTransaction>, Error> {
+        let hash_clone = hash.clone();
+        let blockchain_tx = self
+            .blockchain_transactions_repo
+            .get(hash_clone.clone())
+            .map_err(ectx!(try ErrorKind::Internal => hash_clone))?
+            .map(Into::<BlockchainTransaction>::into);
+        let blockchain_tx = match blockchain_tx {
+            Some(tx) => Some(tx),
+            None => self
+                .pending_blockchain_transactions_repo
+                .get(hash.clone())
+                .map_err(ectx!(try ErrorKind::Internal => hash))?
+                .map(Into::<BlockchainTransaction>::into),
+        };
+        match blockchain_tx {
+            Some(tx) => tx
+                .normalized()
+                .ok_or(ectx!(err ErrorContext::BalanceOverflow, ErrorKind::Internal))
+                .map(Some),
+            None => Ok(None),
+        }
+    }
+
     // 3) Withdrawal:
     //   a) two txs: Withdrawal - Pending, Fee - Done
     //   b) three txs: Withdrwal - Done, Fee - Done, BlockchainFee - Done
+    //   c) split withdrawal: several Withdrawal legs with their own blockchain txs and one Fee leg
 
     fn convert_external_transaction(&self, transactions: Vec<Transaction>) -> Result<TransactionOut, Error> {
         let fee_tx = transactions
@@ -311,22 +389,9 @@ impl ConverterServiceImpl {
             .accounts_repo
             .get(withdrawal_tx.dr_account_id)?
             .ok_or(ectx!(try err ErrorContext::InvalidTransactionStructure, ErrorKind::Internal => transactions))?;
-        // Here the problem is it can be in pending txs as well
-        let blockchain_tx_hash_clone = blockchain_tx_hash.clone();
-        let blockchain_tx: BlockchainTransaction = self
-            .blockchain_transactions_repo
-            .get(blockchain_tx_hash_clone.clone())
-            .map_err(ectx!(try ErrorKind::Internal => blockchain_tx_hash_clone))?
-            .map(Into::<BlockchainTransaction>::into)
-            .or(self
-                .pending_blockchain_transactions_repo
-                .get(blockchain_tx_hash.clone())
-                .map_err(ectx!(try ErrorKind::Internal => blockchain_tx_hash))?
-                .map(Into::<BlockchainTransaction>::into))
+        let blockchain_tx = self
+            .get_blockchain_tx(blockchain_tx_hash)?
             .ok_or(ectx!(try err ErrorContext::InvalidTransactionStructure, ErrorKind::Internal => transactions))?;
-        let blockchain_tx = blockchain_tx
-            .normalized()
-            .ok_or(ectx!(try err ErrorContext::BalanceOverflow, ErrorKind::Internal))?;
         let to_address = blockchain_tx
             .to
             .get(0)
@@ -347,7 +412,11 @@ impl ConverterServiceImpl {
             .cloned()
             .collect();
 
-        let status = if withdrawal_txs.iter().all(|tx| tx.status == TransactionStatus::Done) {
+        let failed_tx = withdrawal_txs.iter().find(|tx| tx.status == TransactionStatus::Failed);
+        let failure_reason = failed_tx.and_then(|tx| tx.failure_reason.clone());
+        let status = if failed_tx.is_some() {
+            TransactionStatus::Failed
+        } else if withdrawal_txs.iter().all(|tx| tx.status == TransactionStatus::Done) {
             TransactionStatus::Done
         } else {
             TransactionStatus::Pending
@@ -366,9 +435,43 @@ impl ConverterServiceImpl {
             .iter()
             .fold(Some(Amount::new(0)), |acc, elem| acc.and_then(|a| a.checked_add(elem.value)))
             .ok_or(ectx!(try err ErrorContext::BalanceOverflow, ErrorKind::Internal => transactions))?;
-        let blockchain_tx_ids: Vec<_> = withdrawal_txs.into_iter().flat_map(|tx| tx.blockchain_tx_id.into_iter()).collect();
+        let mut blockchain_tx_ids: Vec<_> = withdrawal_txs.into_iter().flat_map(|tx| tx.blockchain_tx_id.into_iter()).collect();
+        // legs of btc batch withdrawal share the same blockchain tx
+        blockchain_tx_ids.dedup();
+        // legs of split withdrawal are sent in separate blockchain txs, the group is
+        // as confirmed as the least confirmed of them
+        let mut confirmations = blockchain_tx.confirmations as u64;
+        for hash in blockchain_tx_ids.iter().skip(1) {
+            if let Some(tx) = self.get_blockchain_tx(hash.clone())? {
+                confirmations = confirmations.min(tx.confirmations as u64);
+            }
+        }
+        // BlockchainFee legs are written when the withdrawal is resolved, one per blockchain tx, with the fee it
+        // actually paid and the fee estimated from the price it was sent with. Dropped txs paid nothing
+        let blockchain_fee_txs: Vec<_> = transactions.iter().filter(|tx| tx.kind == TransactionKind::BlockchainFee).collect();
+        let network_fee = if status == TransactionStatus::Pending || blockchain_fee_txs.is_empty() {
+            None
+        } else {
+            Some(
+                blockchain_fee_txs
+                    .iter()
+                    .fold(Some(Amount::new(0)), |acc, elem| acc.and_then(|a| a.checked_add(elem.value)))
+                    .ok_or(ectx!(try err ErrorContext::BalanceOverflow, ErrorKind::Internal => transactions))?,
+            )
+        };
+        // legs resolved before the estimate was recorded have no `gross_fee`
+        let estimated_network_fee = blockchain_fee_txs.iter().fold(Some(Amount::new(0)), |acc, elem| {
+            acc.and_then(|a| elem.gross_fee.and_then(|fee| a.checked_add(fee)))
+        });
+        let fee_variance = match (network_fee, estimated_network_fee) {
+            (Some(network_fee), Some(estimated_network_fee)) if estimated_network_fee.raw() > 0 => {
+                Some(network_fee.raw() as f64 / estimated_network_fee.raw() as f64 - 1.0)
+            }
+            _ => None,
+        };
         Ok(TransactionOut {
             id: withdrawal_tx.gid,
+            kind: TransactionGroupKind::Withdrawal,
             user_id: withdrawal_account.user_id,
             from,
             to,
@@ -376,9 +479,19 @@ impl ConverterServiceImpl {
             from_currency: withdrawal_tx.currency,
             to_value: value,
             to_currency: withdrawal_tx.currency,
+            // the user is charged the Fee leg only. BlockchainFee legs are the network cost paid by the system
+            // out of that fee, adding them would count it twice - it is reported separately as `network_fee`
             fee: fee_tx.value,
+            fee_price: fee_tx.fee_price,
+            gross_fee: fee_tx.gross_fee,
+            network_fee,
+            fee_variance,
             status,
             blockchain_tx_ids,
+            confirmations: Some(confirmations),
+            failure_reason,
+            user_note: None,
+            category: None,
             created_at,
             updated_at,
         })
@@ -402,6 +515,7 @@ impl ConverterServiceImpl {
         let withdrawal_tx_out = self.convert_external_transaction(withdrawal_txs)?;
         Ok(TransactionOut {
             id: currency_tx_out.id,
+            kind: TransactionGroupKind::WithdrawalMulti,
             user_id: withdrawal_tx_out.user_id,
             from: currency_tx_out.from,
             to: withdrawal_tx_out.to,
@@ -410,8 +524,16 @@ impl ConverterServiceImpl {
             to_value: currency_tx_out.to_value,
             to_currency: currency_tx_out.to_currency,
             fee: withdrawal_tx_out.fee,
+            fee_price: withdrawal_tx_out.fee_price,
+            gross_fee: withdrawal_tx_out.gross_fee,
+            network_fee: withdrawal_tx_out.network_fee,
+            fee_variance: withdrawal_tx_out.fee_variance,
             status: withdrawal_tx_out.status,
             blockchain_tx_ids: withdrawal_tx_out.blockchain_tx_ids,
+            confirmations: withdrawal_tx_out.confirmations,
+            failure_reason: withdrawal_tx_out.failure_reason,
+            user_note: None,
+            category: None,
             created_at: withdrawal_tx_out.created_at,
             updated_at: withdrawal_tx_out.updated_at,
         })
@@ -432,13 +554,15 @@ impl ConverterService for ConverterServiceImpl {
     //   b) three txs: Withdrawal - Done, Fee - Done, BlockchainFee - Done
     //
     // 4) InternalMulti:
-    //   two txs: MultiFrom - Done, MultiTo - Done
+    //   a) two txs: MultiFrom - Done, MultiTo - Done
+    //   b) three txs: MultiFrom - Done, MultiTo - Done, Fee - Done
     //
     // 5) ExternalMulti:
     //   a) MultiFrom - Done, MultiTo - Done, Withdrawal - Pending, Fee - Done
     //   b) MultiFrom - Done, MultiTo - Done, Withdrawal - Done, Fee - Done, BlockchainFee - Done
 
-    // 6) Approval - we don't serve this as TransactionOut since it's internal to our system
+    // 6) Approval - we don't serve this as TransactionOut since it's internal to our system,
+    //    `convert_listed_transaction` skips it
     // 7) Reversal -
     //   a) Withdrawal - Done, Fee - Done
 
@@ -451,7 +575,9 @@ impl ConverterService for ConverterServiceImpl {
             }
         }
         let group_kind = transactions[0].group_kind;
-        match group_kind {
+        let user_note = transactions.iter().filter_map(|tx| tx.user_note.clone()).next();
+        let category = transactions.iter().filter_map(|tx| tx.category.clone()).next();
+        let tx_out = match group_kind {
             TransactionGroupKind::Deposit => self.convert_deposit_transaction(transactions),
             TransactionGroupKind::Internal => self.convert_internal_transaction(transactions),
             TransactionGroupKind::InternalMulti => self.convert_internal_multi_transaction(transactions),
@@ -461,7 +587,12 @@ impl ConverterService for ConverterServiceImpl {
             TransactionGroupKind::Approval => {
                 return Err(ectx!(err ErrorContext::InvalidTransactionStructure, ErrorKind::Internal => transactions));
             }
-        }
+        }?;
+        Ok(TransactionOut {
+            user_note,
+            category,
+            ..tx_out
+        })
         // // internal + withdrawal tx
         // if transactions.len() == 1 {
         //     let tx = transactions[0].clone();
@@ -522,4 +653,334 @@ impl ConverterService for ConverterServiceImpl {
         // }
         // panic!("Unsupported transactions sequence: {:#?}", transactions)
     }
+
+    fn convert_listed_transaction(&self, transactions: Vec<Transaction>) -> Result<Option<TransactionOut>, Error> {
+        match transactions.first().map(|tx| tx.group_kind) {
+            Some(TransactionGroupKind::Approval) => Ok(None),
+            _ => self.convert_transaction(transactions).map(Some),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use repos::*;
+    use services::*;
+
+    fn create_converter_service(
+        accounts_repo: Arc<AccountsRepoMock>,
+        blockchain_transactions_repo: Arc<BlockchainTransactionsRepoMock>,
+    ) -> ConverterServiceImpl {
+        let pending_blockchain_transactions_repo = Arc::new(PendingBlockchainTransactionsRepoMock::default());
+        let transfer_accounts: [Account; 3] = [Account::default(), Account::default(), Account::default()];
+        let liquidity_accounts: [Account; 3] = [Account::default(), Account::default(), Account::default()];
+        let fees_accounts: [Account; 3] = [Account::default(), Account::default(), Account::default()];
+        let fees_accounts_dr: [Account; 3] = [Account::default(), Account::default(), Account::default()];
+        let system_service = Arc::new(SystemServiceMock::new(
+            transfer_accounts,
+            liquidity_accounts,
+            fees_accounts,
+            fees_accounts_dr,
+        ));
+        let config = Arc::new(Config::new().unwrap());
+        ConverterServiceImpl::new(
+            config,
+            accounts_repo,
+            pending_blockchain_transactions_repo,
+            blockchain_transactions_repo,
+            system_service,
+        )
+    }
+
+    fn create_withdrawal_group(currency: Currency, blockchain_fee_currency: Currency) -> (ConverterServiceImpl, Vec<Transaction>) {
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let blockchain_transactions_repo = Arc::new(BlockchainTransactionsRepoMock::default());
+        let mut new_account = NewAccount::default();
+        new_account.currency = currency;
+        let from = accounts_repo.create(new_account).unwrap();
+        let hash = BlockchainTransactionId::new("withdrawal_hash".to_string());
+        let to_address = BlockchainAddress::new("to_address".to_string());
+        blockchain_transactions_repo
+            .create(
+                BlockchainTransaction {
+                    hash: hash.clone(),
+                    from: vec![from.address.clone()],
+                    to: vec![BlockchainTransactionEntryTo {
+                        address: to_address,
+                        value: Amount::new(1000),
+                    }],
+                    currency,
+                    fee: Amount::new(5),
+                    ..Default::default()
+                }
+                .into(),
+            )
+            .unwrap();
+        let gid = TransactionId::generate();
+        let withdrawal = Transaction {
+            gid,
+            dr_account_id: from.id,
+            currency,
+            value: Amount::new(1000),
+            status: TransactionStatus::Done,
+            blockchain_tx_id: Some(hash),
+            kind: TransactionKind::Withdrawal,
+            group_kind: TransactionGroupKind::Withdrawal,
+            ..Default::default()
+        };
+        let fee = Transaction {
+            gid,
+            currency,
+            value: Amount::new(10),
+            status: TransactionStatus::Done,
+            kind: TransactionKind::Fee,
+            group_kind: TransactionGroupKind::Withdrawal,
+            fee_price: Some(20.0),
+            gross_fee: Some(Amount::new(8)),
+            ..Default::default()
+        };
+        let blockchain_fee = Transaction {
+            gid,
+            currency: blockchain_fee_currency,
+            value: Amount::new(5),
+            status: TransactionStatus::Done,
+            kind: TransactionKind::BlockchainFee,
+            group_kind: TransactionGroupKind::Withdrawal,
+            fee_price: Some(20.0),
+            gross_fee: Some(Amount::new(4)),
+            ..Default::default()
+        };
+        let service = create_converter_service(accounts_repo, blockchain_transactions_repo);
+        (service, vec![withdrawal, fee, blockchain_fee])
+    }
+
+    #[test]
+    fn test_convert_withdrawal_with_blockchain_fee() {
+        let (service, txs) = create_withdrawal_group(Currency::Btc, Currency::Btc);
+        let gid = txs[0].gid;
+        let tx_out = service.convert_transaction(txs).unwrap();
+        assert_eq!(tx_out.id, gid);
+        assert_eq!(tx_out.status, TransactionStatus::Done);
+        assert_eq!(tx_out.from_value, Amount::new(1000));
+        // user pays only the Fee leg, BlockchainFee leg is the network cost borne by the system
+        assert_eq!(tx_out.fee, Amount::new(10));
+        assert_eq!(tx_out.network_fee, Some(Amount::new(5)));
+        assert_eq!(tx_out.fee_price, Some(20.0));
+        assert_eq!(tx_out.gross_fee, Some(Amount::new(8)));
+    }
+
+    #[test]
+    fn test_convert_withdrawal_network_fee() {
+        let (service, txs) = create_withdrawal_group(Currency::Stq, Currency::Eth);
+        let tx_out = service.convert_transaction(txs.clone()).unwrap();
+        // the user paid 8, the network fee at the price the tx was sent with is 4, and the network took 5
+        assert_eq!(tx_out.gross_fee, Some(Amount::new(8)));
+        assert_eq!(tx_out.network_fee, Some(Amount::new(5)));
+        assert_eq!(tx_out.fee_variance, Some(0.25));
+
+        // blockchain fee legs recorded without the estimate
+        let no_estimate: Vec<Transaction> = txs
+            .clone()
+            .into_iter()
+            .map(|tx| {
+                if tx.kind == TransactionKind::BlockchainFee {
+                    Transaction { gross_fee: None, ..tx }
+                } else {
+                    tx
+                }
+            })
+            .collect();
+        let tx_out = service.convert_transaction(no_estimate).unwrap();
+        assert_eq!(tx_out.network_fee, Some(Amount::new(5)));
+        assert_eq!(tx_out.fee_variance, None);
+
+        // fee of a stored blockchain tx is not final until the withdrawal is resolved
+        let pending: Vec<Transaction> = txs
+            .into_iter()
+            .filter(|tx| tx.kind != TransactionKind::BlockchainFee)
+            .map(|tx| Transaction {
+                status: TransactionStatus::Pending,
+                ..tx
+            })
+            .collect();
+        let tx_out = service.convert_transaction(pending).unwrap();
+        assert_eq!(tx_out.status, TransactionStatus::Pending);
+        assert_eq!(tx_out.gross_fee, Some(Amount::new(8)));
+        assert_eq!(tx_out.network_fee, None);
+        assert_eq!(tx_out.fee_variance, None);
+    }
+
+    #[test]
+    fn test_convert_listed_transactions_skips_approval() {
+        let (service, withdrawal) = create_withdrawal_group(Currency::Stq, Currency::Eth);
+        let withdrawal_gid = withdrawal[0].gid;
+        let approval_gid = TransactionId::generate();
+        let approval: Vec<Transaction> = vec![TransactionKind::ApprovalTransfer, TransactionKind::ApprovalCall]
+            .into_iter()
+            .map(|kind| Transaction {
+                gid: approval_gid,
+                currency: Currency::Eth,
+                value: Amount::new(100),
+                status: TransactionStatus::Done,
+                kind,
+                group_kind: TransactionGroupKind::Approval,
+                ..Default::default()
+            })
+            .collect();
+
+        let page: Result<Vec<Option<TransactionOut>>, Error> = vec![approval.clone(), withdrawal]
+            .into_iter()
+            .map(|tx_group| service.convert_listed_transaction(tx_group))
+            .collect();
+        let ids: Vec<TransactionId> = page.unwrap().into_iter().filter_map(|tx| tx).map(|tx| tx.id).collect();
+        assert_eq!(ids, vec![withdrawal_gid]);
+        // approval alone is still not a valid transaction to show
+        assert!(service.convert_transaction(approval).is_err());
+    }
+
+    #[test]
+    fn test_convert_withdrawal_without_blockchain_fee() {
+        let (service, mut txs) = create_withdrawal_group(Currency::Btc, Currency::Btc);
+        txs.pop();
+        let tx_out = service.convert_transaction(txs).unwrap();
+        assert_eq!(tx_out.fee, Amount::new(10));
+    }
+
+    #[test]
+    fn test_convert_failed_withdrawal() {
+        let (service, mut txs) = create_withdrawal_group(Currency::Eth, Currency::Eth);
+        txs[0].status = TransactionStatus::Failed;
+        txs[0].failure_reason = Some("reverted".to_string());
+        let tx_out = service.convert_transaction(txs).unwrap();
+        assert_eq!(tx_out.status, TransactionStatus::Failed);
+        assert_eq!(tx_out.failure_reason, Some("reverted".to_string()));
+    }
+
+    #[test]
+    fn test_convert_withdrawal_confirmations() {
+        let (service, txs) = create_withdrawal_group(Currency::Btc, Currency::Btc);
+        let hash = txs[0].blockchain_tx_id.clone().unwrap();
+        let tx_out = service.convert_transaction(txs.clone()).unwrap();
+        assert_eq!(tx_out.confirmations, Some(0));
+
+        for confirmations in &[1, 6] {
+            let blockchain_tx: BlockchainTransaction = service.blockchain_transactions_repo.get(hash.clone()).unwrap().unwrap().into();
+            service
+                .blockchain_transactions_repo
+                .upsert(
+                    BlockchainTransaction {
+                        confirmations: *confirmations,
+                        ..blockchain_tx
+                    }
+                    .into(),
+                )
+                .unwrap();
+            let tx_out = service.convert_transaction(txs.clone()).unwrap();
+            assert_eq!(tx_out.confirmations, Some(*confirmations as u64));
+        }
+    }
+
+    #[test]
+    fn test_convert_split_withdrawal() {
+        let (service, mut txs) = create_withdrawal_group(Currency::Eth, Currency::Eth);
+        let hash = txs[0].blockchain_tx_id.clone().unwrap();
+        let blockchain_tx: BlockchainTransaction = service.blockchain_transactions_repo.get(hash.clone()).unwrap().unwrap().into();
+        service
+            .blockchain_transactions_repo
+            .upsert(
+                BlockchainTransaction {
+                    confirmations: 6,
+                    ..blockchain_tx.clone()
+                }
+                .into(),
+            )
+            .unwrap();
+        let second_hash = BlockchainTransactionId::new("second_withdrawal_hash".to_string());
+        service
+            .blockchain_transactions_repo
+            .create(
+                BlockchainTransaction {
+                    hash: second_hash.clone(),
+                    to: vec![BlockchainTransactionEntryTo {
+                        address: BlockchainAddress::new("second_to_address".to_string()),
+                        value: Amount::new(500),
+                    }],
+                    confirmations: 2,
+                    ..blockchain_tx
+                }
+                .into(),
+            )
+            .unwrap();
+        let second_withdrawal = Transaction {
+            id: TransactionId::generate(),
+            value: Amount::new(500),
+            blockchain_tx_id: Some(second_hash.clone()),
+            ..txs[0].clone()
+        };
+        txs.push(second_withdrawal);
+
+        let tx_out = service.convert_transaction(txs).unwrap();
+        assert_eq!(tx_out.status, TransactionStatus::Done);
+        assert_eq!(tx_out.from_value, Amount::new(1500));
+        assert_eq!(tx_out.fee, Amount::new(10));
+        assert_eq!(tx_out.blockchain_tx_ids, vec![hash, second_hash]);
+        assert_eq!(tx_out.confirmations, Some(2));
+    }
+
+    #[test]
+    fn test_convert_stq_withdrawal_with_eth_blockchain_fee() {
+        let (service, txs) = create_withdrawal_group(Currency::Stq, Currency::Eth);
+        let tx_out = service.convert_transaction(txs).unwrap();
+        assert_eq!(tx_out.fee, Amount::new(10));
+    }
+
+    #[test]
+    fn test_convert_deposit_confirmations() {
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let blockchain_transactions_repo = Arc::new(BlockchainTransactionsRepoMock::default());
+        let mut new_account = NewAccount::default();
+        new_account.currency = Currency::Btc;
+        let to = accounts_repo.create(new_account).unwrap();
+        let hash = BlockchainTransactionId::new("deposit_hash".to_string());
+        let blockchain_tx = BlockchainTransaction {
+            hash: hash.clone(),
+            from: vec![BlockchainAddress::new("from_address".to_string())],
+            to: vec![BlockchainTransactionEntryTo {
+                address: to.address.clone(),
+                value: Amount::new(1000),
+            }],
+            currency: Currency::Btc,
+            confirmations: 1,
+            ..Default::default()
+        };
+        blockchain_transactions_repo.create(blockchain_tx.clone().into()).unwrap();
+        let deposit = Transaction {
+            cr_account_id: to.id,
+            currency: Currency::Btc,
+            value: Amount::new(1000),
+            status: TransactionStatus::Done,
+            blockchain_tx_id: Some(hash),
+            kind: TransactionKind::Deposit,
+            group_kind: TransactionGroupKind::Deposit,
+            ..Default::default()
+        };
+        let service = create_converter_service(accounts_repo, blockchain_transactions_repo.clone());
+
+        // 2 confirmations are required for btc in config
+        let tx_out = service.convert_transaction(vec![deposit.clone()]).unwrap();
+        assert_eq!(tx_out.status, TransactionStatus::Pending);
+
+        blockchain_transactions_repo
+            .upsert(
+                BlockchainTransaction {
+                    confirmations: 2,
+                    ..blockchain_tx
+                }
+                .into(),
+            )
+            .unwrap();
+        let tx_out = service.convert_transaction(vec![deposit]).unwrap();
+        assert_eq!(tx_out.status, TransactionStatus::Done);
+    }
 }
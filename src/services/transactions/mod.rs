@@ -5,16 +5,25 @@ pub mod converter;
 use std::collections::HashMap;
 use std::sync::Arc;
 
+use chrono::{Duration, NaiveDateTime};
+use failure::Context;
 use future::Either;
 use futures::future;
 use futures::prelude::*;
+use serde_json;
+use uuid::Uuid;
 use validator::{ValidationError, ValidationErrors};
 
-use self::blockchain::{BlockchainService, BlockchainServiceImpl, FeeEstimate};
-use self::classifier::{ClassifierService, ClassifierServiceImpl, TransactionType};
+pub use self::blockchain::bitcoin_tx_size;
+use self::blockchain::{BlockchainService, BlockchainServiceImpl, FeeEstimate, BITCOIN_OUTPUT_SIZE};
+use self::classifier::{ClassifierService, ClassifierServiceImpl, TransactionType, SATOSHI_IN_BTC, WEI_IN_ETH};
 use self::converter::{ConverterService, ConverterServiceImpl};
 use super::auth::AuthService;
+use super::clock::Clock;
 use super::error::*;
+use super::rabbit::required_confirmations;
+use super::rate_limit::{NoRateLimit, RateLimiter};
+use super::shutdown::{InFlightGuard, ShutdownCoordinator};
 use super::system::{SystemService, SystemServiceImpl};
 use client::BlockchainClient;
 use client::ExchangeClient;
@@ -24,7 +33,8 @@ use models::*;
 use prelude::*;
 use rabbit::TransactionPublisher;
 use repos::{
-    AccountsRepo, BlockchainTransactionsRepo, DbExecutor, Isolation, KeyValuesRepo, PendingBlockchainTransactionsRepo, TransactionsRepo,
+    AccountsRepo, BlockchainTransactionsRepo, DbExecutor, ErrorKind as ReposErrorKind, Isolation, KeyValuesRepo,
+    PendingBlockchainTransactionsRepo, SeenHashesRepo, StrangeBlockchainTransactionsRepo, TransactionsRepo,
 };
 use utils::{log_and_capture_error, log_error};
 
@@ -37,11 +47,22 @@ pub struct TransactionsServiceImpl<E: DbExecutor> {
     converter_service: Arc<ConverterService>,
     system_service: Arc<SystemService>,
     transactions_repo: Arc<dyn TransactionsRepo>,
+    pending_transactions_repo: Arc<dyn PendingBlockchainTransactionsRepo>,
     blockchain_transactions_repo: Arc<dyn BlockchainTransactionsRepo>,
+    seen_hashes_repo: Arc<dyn SeenHashesRepo>,
+    strange_blockchain_transactions_repo: Arc<dyn StrangeBlockchainTransactionsRepo>,
     accounts_repo: Arc<dyn AccountsRepo>,
+    key_values_repo: Arc<dyn KeyValuesRepo>,
     db_executor: E,
+    // read-only queries that tolerate replication lag
+    read_db_executor: E,
+    blockchain_client: Arc<dyn BlockchainClient>,
     exchange_client: Arc<dyn ExchangeClient>,
     publisher: Arc<dyn TransactionPublisher>,
+    clock: Arc<dyn Clock>,
+    // requests to create transactions per user
+    rate_limiter: Arc<dyn RateLimiter>,
+    shutdown: ShutdownCoordinator,
 }
 
 pub trait TransactionsService: Send + Sync + 'static {
@@ -50,30 +71,181 @@ pub trait TransactionsService: Send + Sync + 'static {
         token: AuthenticationToken,
         input: CreateTransactionInput,
     ) -> Box<Future<Item = TransactionOut, Error = Error> + Send>;
+    /// Withdrawal to several external addresses in one blockchain transaction, supported only for btc
+    fn create_batch_transaction(
+        &self,
+        token: AuthenticationToken,
+        input: CreateBatchTransactionInput,
+    ) -> Box<Future<Item = TransactionOut, Error = Error> + Send>;
+    /// Withdrawal to several external addresses, each sent in its own blockchain transaction.
+    /// All withdrawal legs share one gid and one fee leg
+    fn create_split_transaction(
+        &self,
+        token: AuthenticationToken,
+        input: CreateBatchTransactionInput,
+    ) -> Box<Future<Item = TransactionOut, Error = Error> + Send>;
+    /// Moves value and fee from `funding_account_id` to `input.from` and withdraws it from there,
+    /// the move is undone if the withdrawal is not posted to blockchain
+    fn create_transfer_and_withdrawal(
+        &self,
+        token: AuthenticationToken,
+        input: CreateTransactionInput,
+        funding_account_id: AccountId,
+    ) -> Box<Future<Item = TransactionOut, Error = Error> + Send>;
+    /// Whole transaction group, `transaction_id` is either its gid or an id of one of its legs
     fn get_transaction(
         &self,
         token: AuthenticationToken,
         transaction_id: TransactionId,
     ) -> Box<Future<Item = Option<TransactionOut>, Error = Error> + Send>;
+    fn get_transaction_legs(
+        &self,
+        token: AuthenticationToken,
+        gid: TransactionId,
+    ) -> Box<Future<Item = Vec<Transaction>, Error = Error> + Send>;
+    /// Status changes of all legs of the transaction, oldest first
+    fn get_transaction_status_history(
+        &self,
+        token: AuthenticationToken,
+        transaction_id: TransactionId,
+    ) -> Box<Future<Item = Vec<StatusChange>, Error = Error> + Send>;
+    /// Legs referencing the leg with `transaction_id` in `related_tx`
+    fn get_related_transactions(
+        &self,
+        token: AuthenticationToken,
+        transaction_id: TransactionId,
+    ) -> Box<Future<Item = Vec<Transaction>, Error = Error> + Send>;
+    /// Withdrawal legs sent to the external address, newest first. Only for the system user
+    fn find_transactions_to_address(
+        &self,
+        system_token: AuthenticationToken,
+        address: BlockchainAddress,
+        currency: Currency,
+        offset: i64,
+        limit: i64,
+    ) -> Box<Future<Item = Vec<Transaction>, Error = Error> + Send>;
+    /// Transaction groups of `group_kind` of all users, newest first. Only for the system user
+    fn get_transactions_by_group_kind(
+        &self,
+        system_token: AuthenticationToken,
+        group_kind: TransactionGroupKind,
+        offset: i64,
+        limit: i64,
+    ) -> Box<Future<Item = Vec<TransactionOut>, Error = Error> + Send>;
+    /// Signed blockchain transactions posted for the group of `transaction_id`. Only for the system user
+    fn get_raw_transactions(
+        &self,
+        system_token: AuthenticationToken,
+        transaction_id: TransactionId,
+    ) -> Box<Future<Item = Vec<SignedBlockchainTransaction>, Error = Error> + Send>;
+    /// Source accounts and values a withdrawal of `value` with the default fee would use,
+    /// nothing is signed or written. Only for the system user
+    fn preview_coin_selection(
+        &self,
+        system_token: AuthenticationToken,
+        currency: Currency,
+        value: Amount,
+    ) -> Box<Future<Item = Vec<AccountWithBalance>, Error = Error> + Send>;
+    /// Cancels transaction in `scheduled` status, balances are not touched since scheduled
+    /// transactions don't move funds until they are due
+    fn cancel_scheduled_transaction(
+        &self,
+        token: AuthenticationToken,
+        transaction_id: TransactionId,
+    ) -> Box<Future<Item = TransactionOut, Error = Error> + Send>;
+    /// Balance is read from the replica unless `strict` is set, then it's read from the primary,
+    /// e.g. right after a transaction is created
     fn get_account_balance(
         &self,
         token: AuthenticationToken,
         account_id: AccountId,
+        strict: bool,
     ) -> Box<Future<Item = AccountWithBalance, Error = Error> + Send>;
+    /// Balance of the account as of the moment, i.e. made up of transactions created until then
+    fn get_account_balance_at(
+        &self,
+        token: AuthenticationToken,
+        account_id: AccountId,
+        as_of: NaiveDateTime,
+    ) -> Box<Future<Item = AccountWithBalance, Error = Error> + Send>;
+    /// Recomputes account balance from ledger history, available only to the system user
+    fn recompute_account_balance(
+        &self,
+        system_token: AuthenticationToken,
+        account_id: AccountId,
+    ) -> Box<Future<Item = AccountWithBalance, Error = Error> + Send>;
+    /// Credits a deposit missed by blockchain notifications, available only to the system user.
+    /// The blockchain transaction must pay the value to the account address and must not be processed yet.
+    /// A transaction transferring another currency is rejected and recorded as a strange one
+    fn manual_deposit(
+        &self,
+        system_token: AuthenticationToken,
+        account_id: AccountId,
+        currency: Currency,
+        value: Amount,
+        blockchain_tx_id: BlockchainTransactionId,
+    ) -> Box<Future<Item = TransactionOut, Error = Error> + Send>;
+    /// Value that can be withdrawn from the account after the default fee is written off
+    fn max_withdrawable(
+        &self,
+        token: AuthenticationToken,
+        account_id: AccountId,
+        to_currency: Currency,
+    ) -> Box<Future<Item = Amount, Error = Error> + Send>;
+    /// Balances of all user's accounts summed up by currency
+    fn get_user_summary(
+        &self,
+        token: AuthenticationToken,
+        user_id: UserId,
+    ) -> Box<Future<Item = Vec<CurrencyBalance>, Error = Error> + Send>;
+    /// Page of user's accounts, balances of the page are computed in one go
+    fn list_accounts_with_balances(
+        &self,
+        token: AuthenticationToken,
+        user_id: UserId,
+        offset: i64,
+        limit: i64,
+    ) -> Box<Future<Item = Vec<AccountWithBalance>, Error = Error> + Send>;
+    /// Transaction groups of the user, only the ones of `category` if it is set
     fn get_transactions_for_user(
         &self,
         token: AuthenticationToken,
         user_id: UserId,
+        category: Option<String>,
         offset: i64,
         limit: i64,
     ) -> Box<Future<Item = Vec<TransactionOut>, Error = Error> + Send>;
+    /// Up to `limit` transaction groups of the user following `after`, newest first, and the position
+    /// of the next page if the page is full. Pages don't shift while new transactions are created, e.g. for exports
+    fn get_transactions_for_user_after(
+        &self,
+        token: AuthenticationToken,
+        user_id: UserId,
+        after: Option<TransactionsCursor>,
+        limit: i64,
+    ) -> Box<Future<Item = (Vec<TransactionOut>, Option<TransactionsCursor>), Error = Error> + Send>;
     fn get_account_transactions(
         &self,
         token: AuthenticationToken,
         account_id: AccountId,
+        kind: Option<TransactionKind>,
+        category: Option<String>,
         offset: i64,
         limit: i64,
     ) -> Box<Future<Item = Vec<TransactionOut>, Error = Error> + Send>;
+    /// Exchanges made by the user in `[from, to)` with their executed rates, oldest first
+    fn get_exchange_history(
+        &self,
+        token: AuthenticationToken,
+        user_id: UserId,
+        from: NaiveDateTime,
+        to: NaiveDateTime,
+    ) -> Box<Future<Item = Vec<ExecutedExchange>, Error = Error> + Send>;
+    /// Whether pending transactions resolver runs, available only to the system user
+    fn get_resolver_enabled(&self, system_token: AuthenticationToken) -> Box<Future<Item = bool, Error = Error> + Send>;
+    /// Pauses or resumes pending transactions resolver, available only to the system user.
+    /// The flag is persisted, so it survives restarts
+    fn set_resolver_enabled(&self, system_token: AuthenticationToken, enabled: bool) -> Box<Future<Item = bool, Error = Error> + Send>;
 }
 
 impl<E: DbExecutor> TransactionsServiceImpl<E> {
@@ -83,6 +255,8 @@ impl<E: DbExecutor> TransactionsServiceImpl<E> {
         transactions_repo: Arc<TransactionsRepo>,
         pending_transactions_repo: Arc<dyn PendingBlockchainTransactionsRepo>,
         blockchain_transactions_repo: Arc<dyn BlockchainTransactionsRepo>,
+        seen_hashes_repo: Arc<dyn SeenHashesRepo>,
+        strange_blockchain_transactions_repo: Arc<dyn StrangeBlockchainTransactionsRepo>,
         accounts_repo: Arc<dyn AccountsRepo>,
         key_values_repo: Arc<dyn KeyValuesRepo>,
         db_executor: E,
@@ -90,25 +264,35 @@ impl<E: DbExecutor> TransactionsServiceImpl<E> {
         blockchain_client: Arc<dyn BlockchainClient>,
         exchange_client: Arc<dyn ExchangeClient>,
         publisher: Arc<dyn TransactionPublisher>,
+        clock: Arc<dyn Clock>,
     ) -> Self {
         let config = Arc::new(config);
         let classifier_service = Arc::new(ClassifierServiceImpl::new(
             &config,
             accounts_repo.clone(),
             transactions_repo.clone(),
+            clock.clone(),
+        ));
+        let system_service = Arc::new(SystemServiceImpl::new(
+            accounts_repo.clone(),
+            transactions_repo.clone(),
+            pending_transactions_repo.clone(),
+            config.clone(),
+            clock.clone(),
         ));
-        let system_service = Arc::new(SystemServiceImpl::new(accounts_repo.clone(), config.clone()));
         let blockchain_service = Arc::new(BlockchainServiceImpl::new(
             config.clone(),
             keys_client,
-            blockchain_client,
+            blockchain_client.clone(),
             exchange_client.clone(),
             pending_transactions_repo.clone(),
             key_values_repo.clone(),
             system_service.clone(),
             db_executor.clone(),
+            clock.clone(),
         ));
         let converter_service = Arc::new(ConverterServiceImpl::new(
+            config.clone(),
             accounts_repo.clone(),
             pending_transactions_repo.clone(),
             blockchain_transactions_repo.clone(),
@@ -121,13 +305,54 @@ impl<E: DbExecutor> TransactionsServiceImpl<E> {
             classifier_service,
             system_service,
             transactions_repo,
+            pending_transactions_repo,
             blockchain_transactions_repo,
+            seen_hashes_repo,
+            strange_blockchain_transactions_repo,
             accounts_repo,
+            key_values_repo,
+            read_db_executor: db_executor.clone(),
             db_executor,
             converter_service,
+            blockchain_client,
             exchange_client,
             publisher,
+            clock,
+            rate_limiter: Arc::new(NoRateLimit),
+            shutdown: ShutdownCoordinator::new(),
+        }
+    }
+
+    /// Sends listings of transactions and balances to a separate executor, e.g. one using a read replica.
+    /// By default they go to the primary `db_executor`
+    pub fn with_read_db_executor(self, read_db_executor: E) -> Self {
+        Self { read_db_executor, ..self }
+    }
+
+    /// Limits the rate of transactions created by a user, nothing is limited by default.
+    /// The limiter should outlive the service, so that it sees all requests
+    pub fn with_rate_limiter(self, rate_limiter: Arc<dyn RateLimiter>) -> Self {
+        Self { rate_limiter, ..self }
+    }
+
+    /// Lets the transactions created by this service be drained on shutdown.
+    /// The coordinator should be shared by all requests, so that it sees every transaction
+    pub fn with_shutdown(self, shutdown: ShutdownCoordinator) -> Self {
+        Self { shutdown, ..self }
+    }
+
+    // the guard must be kept until the transaction is saved, new transactions are rejected once shutdown started
+    fn enter_in_flight(&self) -> Result<InFlightGuard, Error> {
+        self.shutdown
+            .try_enter()
+            .ok_or(ectx!(err ErrorContext::ShuttingDown, ErrorKind::ShuttingDown))
+    }
+
+    fn check_rate_limit(&self, user_id: UserId) -> Result<(), Error> {
+        if !self.rate_limiter.try_acquire(user_id) {
+            return Err(ectx!(err ErrorContext::RateLimitExceeded, ErrorKind::TooManyRequests => user_id));
         }
+        Ok(())
     }
 
     fn create_base_tx(&self, tx: NewTransaction, dr_account: Account, cr_account: Account) -> Result<Transaction, Error> {
@@ -146,130 +371,995 @@ impl<E: DbExecutor> TransactionsServiceImpl<E> {
             .map(|accounts| accounts[0].balance)
             .map_err(ectx!(try convert => tx_clone))?;
         if balance >= tx.value {
-            transactions_repo.create(tx.clone()).map_err(ectx!(convert => tx.clone()))
+            let tx_clone = tx.clone();
+            transactions_repo.create(tx).map_err(move |e| match e.kind() {
+                // id is the only unique column of transactions, and it is supplied by clients
+                ReposErrorKind::Constraints(_) => ectx!(err e, ErrorContext::DuplicateTransactionId, ErrorKind::MalformedInput => tx_clone),
+                _ => ectx!(convert err e => tx_clone),
+            })
         } else {
+            let shortfall = tx.value.checked_sub(balance).unwrap_or_default();
+            Err(ectx!(err ErrorContext::NotEnoughFunds, not_enough_funds_error(tx.currency, tx.value, balance) => tx, balance, shortfall))
+        }
+    }
+
+    // Ids are supplied by clients, a known one is rejected before anything is sent to blockchain,
+    // so that a retried withdrawal is never sent twice
+    fn check_new_transaction_id(&self, id: TransactionId) -> Result<(), Error> {
+        let existing = self.transactions_repo.get(id).map_err(ectx!(try convert => id))?;
+        if existing.is_some() {
+            return Err(ectx!(err ErrorContext::DuplicateTransactionId, ErrorKind::MalformedInput => id));
+        }
+        Ok(())
+    }
+
+    // Withdrawals with several outputs are checked against the user's balance before anything is sent to blockchain.
+    // `create_base_tx` checks every leg again when they are written, but by then the funds have already left
+    fn check_withdrawal_balance(&self, from_account: &Account, value: Amount) -> Result<(), Error> {
+        let (user_id, from) = (from_account.user_id, from_account.id);
+        let balance = self
+            .transactions_repo
+            .get_accounts_balance(user_id, &[from_account.clone()])
+            .map(|accounts| accounts[0].balance)
+            .map_err(ectx!(try convert => user_id, from))?;
+        if balance < value {
+            let shortfall = value.checked_sub(balance).unwrap_or_default();
+            return Err(
+                ectx!(err ErrorContext::NotEnoughFunds, not_enough_funds_error(from_account.currency, value, balance) => from, value, balance, shortfall),
+            );
+        }
+        Ok(())
+    }
+
+    // checks that the user has not withdrawn more than the configured amount of currency in the last 24h
+    fn check_daily_withdrawal_limit(&self, user_id: UserId, currency: Currency, value: Amount) -> Result<(), Error> {
+        let limits = &self.config.daily_withdrawal_limit;
+        let limit = match currency {
+            Currency::Btc => Amount::new(((limits.btc * 1000.0) as u128) * SATOSHI_IN_BTC / 1000),
+            Currency::Eth => Amount::new(((limits.eth * 1000.0) as u128) * WEI_IN_ETH / 1000),
+            Currency::Stq => Amount::new((limits.stq as u128) * WEI_IN_ETH),
+        };
+        let since = self.clock.now() - Duration::days(1);
+        let withdrawn = self
+            .transactions_repo
+            .sum_withdrawals_since(user_id, currency, since)
+            .map_err(ectx!(try convert => user_id, currency, since))?;
+        let total = withdrawn
+            .checked_add(value)
+            .ok_or(ectx!(try err ErrorContext::BalanceOverflow, ErrorKind::Internal => withdrawn, value))?;
+        if total > limit {
             let mut errors = ValidationErrors::new();
-            let mut error = ValidationError::new("not_enough_balance");
-            error.message = Some("account balance is not enough".into());
+            let mut error = ValidationError::new("exceeded_daily_withdrawal_limit");
+            error.message = Some("daily withdrawal limit for the user exceeded".into());
+            error.add_param("limit".into(), &limit.to_super_unit(currency).to_string());
+            error.add_param("currency".into(), &currency.to_string().to_uppercase());
             errors.add("value", error);
-            Err(ectx!(err ErrorContext::NotEnoughFunds, ErrorKind::InvalidInput(serde_json::to_string(&errors).unwrap_or_default()) => tx))
+            return Err(
+                ectx!(err ErrorContext::WithdrawalLimitExceeded, ErrorKind::Balance(serde_json::to_string(&errors).unwrap_or_default()) => user_id, withdrawn, value, limit),
+            );
         }
+        Ok(())
     }
 
-    fn create_internal_mono_currency_tx(
+    fn check_withdrawal_cooldown(&self, user_id: UserId) -> Result<(), Error> {
+        let cooldown_secs = self.config.system.withdrawal_cooldown_secs;
+        if cooldown_secs == 0 {
+            return Ok(());
+        }
+        let last_withdrawal_at = self
+            .transactions_repo
+            .last_withdrawal_at(user_id)
+            .map_err(ectx!(try convert => user_id))?;
+        if let Some(last_withdrawal_at) = last_withdrawal_at {
+            let allowed_at = last_withdrawal_at + Duration::seconds(cooldown_secs as i64);
+            if self.clock.now() < allowed_at {
+                return Err(
+                    ectx!(err ErrorContext::WithdrawalCooldown, ErrorKind::TooManyRequests => user_id, last_withdrawal_at, allowed_at),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    // Concurrent withdrawals of a user would all pass the limit checks before any of them is written,
+    // so they go one at a time. The lock is taken in the serializable transaction of the checks,
+    // the one left by a crashed instance is taken over once it is stale
+    fn acquire_withdrawal_lock(&self, user_id: UserId, token: Uuid) -> Result<(), Error> {
+        let now = self.clock.now();
+        let lock = self
+            .key_values_repo
+            .get_withdrawal_lock(user_id)
+            .map_err(ectx!(try convert => user_id))?;
+        if let Some(lock) = lock {
+            if now < lock.locked_at + Duration::seconds(WITHDRAWAL_LOCK_TIMEOUT_SECS) {
+                return Err(ectx!(err ErrorContext::WithdrawalInProgress, ErrorKind::TooManyRequests => user_id, lock));
+            }
+        }
+        let lock = WithdrawalLock { token, locked_at: now };
+        self.key_values_repo
+            .set_withdrawal_lock(user_id, lock)
+            .map_err(ectx!(try convert => user_id, lock))?;
+        Ok(())
+    }
+
+    // Passes the result of the withdrawal through, the lock is released only by the request holding it.
+    // A failed release is just logged, the lock goes stale then
+    fn release_withdrawal_lock<T: Send + 'static>(
         &self,
-        create_tx_input: CreateTransactionInput,
-        dr_account: Account,
-        cr_account: Account,
-    ) -> impl Future<Item = Transaction, Error = Error> + Send {
-        let tx = NewTransaction {
-            id: create_tx_input.id,
-            gid: create_tx_input.id,
-            user_id: create_tx_input.user_id,
-            dr_account_id: dr_account.id,
-            cr_account_id: cr_account.id,
-            currency: dr_account.currency,
-            value: create_tx_input.value,
-            status: TransactionStatus::Done,
-            blockchain_tx_id: None,
-            kind: TransactionKind::Internal,
-            group_kind: TransactionGroupKind::Internal,
-            related_tx: None,
-            meta: None,
-        };
-        let self_clone = self.clone();
+        user_id: UserId,
+        token: Uuid,
+        res: Result<T, Error>,
+    ) -> impl Future<Item = T, Error = Error> + Send {
+        let key_values_repo = self.key_values_repo.clone();
         self.db_executor
-            .execute_transaction_with_isolation(Isolation::Serializable, move || {
-                self_clone.create_base_tx(tx, dr_account, cr_account)
+            .execute_transaction_with_isolation(Isolation::Serializable, move || -> Result<(), Error> {
+                let lock = key_values_repo
+                    .get_withdrawal_lock(user_id)
+                    .map_err(ectx!(try convert => user_id))?;
+                if lock.map(|lock| lock.token) == Some(token) {
+                    key_values_repo
+                        .delete_withdrawal_lock(user_id)
+                        .map_err(ectx!(try convert => user_id))?;
+                }
+                Ok(())
+            })
+            .then(move |released| {
+                if let Err(e) = released {
+                    log_and_capture_error(e);
+                }
+                res
             })
     }
 
-    fn create_external_mono_currency_tx(
-        &self,
-        input: CreateTransactionInput,
-        from_account: Account,
-        to_blockchain_address: BlockchainAddress,
-        to_currency: Currency,
-        // these group params will be filled with defaults for external mono currency
-        // to reuse it in external withdrawal, we put overrides here
-        gid: Option<TransactionId>,
-        tx_kind: Option<TransactionKind>,
-        tx_group_kind: Option<TransactionGroupKind>,
-        fee_currency: Option<Currency>,
-        // by default the fee is written off from_account. However you can override this
-        // using this param
-        fee_payer_account_id: Option<AccountId>,
-    ) -> impl Future<Item = Vec<Transaction>, Error = Error> + Send {
-        if from_account.currency != to_currency {
+    // withdrawals can be switched off per currency in config, internal transfers and deposits are not affected
+    fn check_withdrawals_enabled(&self, currency: Currency) -> Result<(), Error> {
+        if !self.config.withdrawals_enabled.for_currency(currency) {
+            let mut errors = ValidationErrors::new();
+            let mut error = ValidationError::new("withdrawals_disabled");
+            error.message = Some("withdrawals of the currency are temporarily disabled".into());
+            error.add_param("currency".into(), &currency.to_string().to_uppercase());
+            errors.add("currency", error);
+            return Err(
+                ectx!(err ErrorContext::WithdrawalsDisabled, ErrorKind::Balance(serde_json::to_string(&errors).unwrap_or_default()) => currency),
+            );
+        }
+        Ok(())
+    }
+
+    // every source account is a separate blockchain transaction with its own fee,
+    // so users have to consolidate their funds before withdrawing from too many accounts
+    fn check_withdrawal_sources_count(&self, currency: Currency, sources_count: usize) -> Result<(), Error> {
+        let max_sources = self.config.system.max_withdrawal_source_accounts;
+        if sources_count > max_sources {
+            let mut errors = ValidationErrors::new();
+            let mut error = ValidationError::new("too_many_sources");
+            error.message = Some("withdrawal requires too many source accounts, consolidate funds first".into());
+            error.add_param("currency".into(), &currency.to_string().to_uppercase());
+            error.add_param("sources_count".into(), &sources_count);
+            error.add_param("max_sources".into(), &max_sources);
+            errors.add("value", error);
+            return Err(
+                ectx!(err ErrorContext::TooManySources, ErrorKind::Balance(serde_json::to_string(&errors).unwrap_or_default()) => currency, sources_count, max_sources),
+            );
+        }
+        Ok(())
+    }
+
+    // accounts the withdrawal is paid from, the same selection is shown by `preview_coin_selection`
+    fn select_withdrawal_sources(&self, value: Amount, currency: Currency, total_fee: Amount) -> Result<Vec<AccountWithBalance>, Error> {
+        let sources = self
+            .transactions_repo
+            .get_accounts_for_withdrawal(value, currency, total_fee)
+            .map_err(ectx!(try convert => value, currency, total_fee))?;
+        self.check_withdrawal_sources_count(currency, sources.len())?;
+        Ok(sources)
+    }
+
+    // rejects client supplied exchange rate deviating from the live rate more than configured
+    fn check_exchange_rate_band(&self, rate: f64, live_rate: f64) -> Result<(), Error> {
+        let max_deviation_percent = self.config.system.exchange_rate_max_deviation_percent;
+        let deviation_percent = ((rate - live_rate) / live_rate).abs() * 100.0;
+        // also rejects nan and infinite deviation, e.g. when live rate is zero
+        if !(deviation_percent <= max_deviation_percent) {
+            let mut errors = ValidationErrors::new();
+            let mut error = ValidationError::new("rate_out_of_band");
+            error.message = Some("exchange rate is too far from the current market rate".into());
+            error.add_param("rate".into(), &rate);
+            error.add_param("live_rate".into(), &live_rate);
+            error.add_param("max_deviation_percent".into(), &max_deviation_percent);
+            errors.add("exchange_rate", error);
+            return Err(
+                ectx!(err ErrorContext::RateOutOfBand, ErrorKind::RateRejected(serde_json::to_string(&errors).unwrap_or_default()) => rate, live_rate, max_deviation_percent),
+            );
+        }
+        Ok(())
+    }
+
+    fn quote_fiat_value(&self, input: CreateTransactionInput) -> impl Future<Item = CreateTransactionInput, Error = Error> + Send {
+        let fiat_value = match input.fiat_value {
+            Some(fiat_value) => fiat_value,
+            None => return Either::A(future::ok(input)),
+        };
+        let rate_input = FiatRateInput::new(fiat_value.currency, input.to_currency, fiat_value.value);
+        let rate_input_clone = rate_input.clone();
+        Either::B(
+            self.exchange_client
+                .fiat_rate(rate_input, Role::User)
+                .map_err(ectx!(convert => rate_input_clone))
+                .map(move |FiatRate { rate, .. }| CreateTransactionInput {
+                    value: fiat_value.to_crypto(input.to_currency, rate),
+                    value_currency: input.to_currency,
+                    fiat_rate: Some(rate),
+                    ..input
+                }),
+        )
+    }
+
+    // For input with `fee_tier` sets the fee to the default withdrawal fee scaled by the tier multiplier,
+    // so that the fee price derived from it in `estimate_withdrawal_fee` is the one of the tier.
+    // Withdrawals without a fee are charged the one of the configured default tier.
+    // Input with both `fee` and `fee_tier` is rejected, as it's not clear which one the client meant.
+    fn resolve_fee_tier(&self, input: CreateTransactionInput) -> impl Future<Item = CreateTransactionInput, Error = Error> + Send {
+        if input.fee_tier.is_some() && !input.fee_omitted {
+            let (fee, fee_tier) = (input.fee, input.fee_tier);
             return Either::A(future::err(
-                ectx!(err ErrorContext::InvalidCurrency, ErrorKind::Internal => from_account, to_blockchain_address, to_currency),
+                ectx!(err ErrorContext::InvalidValue, fee_with_fee_tier_error() => fee, fee_tier),
             ));
+        }
+        let fee_tier = match input.fee_tier {
+            Some(fee_tier) => fee_tier,
+            // internal transfers carry no network fee, so an omitted fee is only estimated for withdrawals
+            None if input.fee_omitted && input.to_type == RecepientType::Address => self.config.fee_tiers.default_tier,
+            None => return Either::A(future::ok(input)),
         };
+        let multiplier = self.config.fee_tiers.for_tier(fee_tier).multiplier;
+        let accounts_repo = self.accounts_repo.clone();
+        let blockchain_service = self.blockchain_service.clone();
+        let from = input.from;
+        let to_currency = input.to_currency;
+        Either::B(
+            self.db_executor
+                .execute(move || -> Result<Account, Error> {
+                    accounts_repo
+                        .get(from)
+                        .map_err(ectx!(try convert => from))?
+                        .ok_or(ectx!(err ErrorContext::NoAccount, ErrorKind::NotFound => from))
+                })
+                .and_then(move |account| blockchain_service.estimate_default_withdrawal_fee(account.currency, to_currency))
+                .map(move |fee| CreateTransactionInput {
+                    fee: Amount::new((fee.raw() as f64 * multiplier).ceil() as u128),
+                    ..input
+                }),
+        )
+    }
 
-        let gid = gid.unwrap_or(input.id);
-        let value = input.value;
-        let fee_currency = fee_currency.unwrap_or(from_account.currency);
+    // For `drain` input sets value to the whole balance of `from` less the fee, so that no dust is left.
+    // The fee is written off the same account, for stq it is converted from the eth network fee,
+    // so no separate eth balance is needed for gas.
+    fn resolve_drain_value(&self, input: CreateTransactionInput) -> impl Future<Item = CreateTransactionInput, Error = Error> + Send {
+        if !input.drain {
+            return Either::A(future::ok(input));
+        }
+        let accounts_repo = self.accounts_repo.clone();
+        let transactions_repo = self.transactions_repo.clone();
+        let blockchain_service = self.blockchain_service.clone();
+        let from = input.from;
+        let user_id = input.user_id;
+        let to_currency = input.to_currency;
+        Either::B(
+            self.db_executor
+                .execute(move || -> Result<AccountWithBalance, Error> {
+                    let account = accounts_repo
+                        .get(from)
+                        .map_err(ectx!(try convert => from))?
+                        .ok_or(ectx!(try err ErrorContext::NoAccount, ErrorKind::NotFound => from))?;
+                    if account.user_id != user_id {
+                        return Err(ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => user_id, from));
+                    }
+                    // draining with exchange is not supported
+                    if account.currency != to_currency {
+                        return Err(ectx!(err ErrorContext::NotSupported, ErrorKind::MalformedInput => from, to_currency));
+                    }
+                    transactions_repo
+                        .get_accounts_balance(user_id, &[account])
+                        .map(|mut accounts| accounts.remove(0))
+                        .map_err(ectx!(convert => from))
+                })
+                .and_then(move |AccountWithBalance { account, balance }| {
+                    let fee = if input.fee > Amount::new(0) {
+                        Either::A(future::ok(input.fee))
+                    } else {
+                        Either::B(blockchain_service.estimate_default_withdrawal_fee(account.currency, to_currency))
+                    };
+                    fee.and_then(move |fee| {
+                        let value = match balance.checked_sub(fee) {
+                            Some(value) if value > Amount::new(0) => value,
+                            // the fee would eat up the whole balance, user would get nothing
+                            _ if balance > Amount::new(0) => {
+                                return Err(ectx!(err ErrorContext::FeeExceedsValue, not_enough_funds_error(account.currency, fee, balance) => from, fee, balance))
+                            }
+                            _ => {
+                                return Err(ectx!(err ErrorContext::NotEnoughFunds, not_enough_funds_error(account.currency, fee, balance) => from, fee, balance))
+                            }
+                        };
+                        Ok(CreateTransactionInput {
+                            value,
+                            value_currency: account.currency,
+                            fee,
+                            fiat_value: None,
+                            ..input
+                        })
+                    })
+                }),
+        )
+    }
+
+    // eth and stq transactions have exactly one recipient
+    fn check_batch_currency(&self, currency: Currency) -> Result<(), Error> {
+        if currency != Currency::Btc {
+            let mut errors = ValidationErrors::new();
+            let mut error = ValidationError::new("not_supported");
+            error.message = Some("Multiple recipients are supported only for btc".into());
+            errors.add("currency", error);
+            return Err(
+                ectx!(err ErrorContext::NotSupported, ErrorKind::InvalidInput(serde_json::to_string(&errors).unwrap_or_default()) => currency),
+            );
+        }
+        Ok(())
+    }
+
+    // returns account to withdraw from and total value of all outputs
+    fn validate_batch_transaction(&self, input: &CreateBatchTransactionInput) -> Result<(Account, Amount), Error> {
+        let invalid_input = |field: &'static str, code: &'static str, message: &'static str| {
+            let mut errors = ValidationErrors::new();
+            let mut error = ValidationError::new(code);
+            error.message = Some(message.into());
+            errors.add(field, error);
+            ErrorKind::InvalidInput(serde_json::to_string(&errors).unwrap_or_default())
+        };
+        if input.outputs.is_empty() {
+            return Err(
+                ectx!(err ErrorContext::InvalidTransaction, invalid_input("outputs", "empty", "At least one recipient is required") => input.id),
+            );
+        }
+        self.check_withdrawals_enabled(input.currency)?;
+        let from = input.from;
+        let from_account = self
+            .accounts_repo
+            .get(from)
+            .map_err(ectx!(try convert => from))?
+            .ok_or(ectx!(try err ErrorContext::NoAccount, ErrorKind::NotFound => from))?;
+        if from_account.user_id != input.user_id {
+            return Err(ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => input.user_id, from));
+        }
+        if from_account.currency != input.currency {
+            return Err(
+                ectx!(err ErrorContext::InvalidCurrency, invalid_input("currency", "invalid_currency", "Account currency doesn't match") => from_account.currency, input.currency),
+            );
+        }
+        let mut total = Amount::new(0);
+        for output in &input.outputs {
+            let address = output.to.normalized(input.currency);
+            // transfers to our own addresses are done internally and never go to blockchain
+            let internal_accounts = self
+                .accounts_repo
+                .filter_by_address(address.clone())
+                .map_err(ectx!(try convert => address))?;
+            if !internal_accounts.is_empty() {
+                return Err(
+                    ectx!(err ErrorContext::InvalidTransaction, invalid_input("outputs", "internal_recipient", "Recipient address belongs to the platform") => output.to),
+                );
+            }
+            // an output paying nothing would still be sent to blockchain
+            if output.value == Amount::new(0) {
+                return Err(ectx!(err ErrorContext::InvalidValue, ErrorKind::MalformedInput => input.id, output.to));
+            }
+            // every output is validated as a withdrawal of its own, e.g. its recipient is screened
+            let output_input = batch_output_input(input, output);
+            match self.classifier_service.validate_and_classify_transaction(&output_input)? {
+                TransactionType::Withdrawal(..) => (),
+                _ => return Err(ectx!(err ErrorContext::NotSupported, ErrorKind::MalformedInput => output_input)),
+            }
+            total = total
+                .try_add(output.value)
+                .map_err(ectx!(try ErrorContext::BalanceOverflow, ErrorKind::Internal))?;
+        }
+        // otherwise the max value is bypassed by splitting it between several outputs
+        let max_value = self.config.max_transaction_value.for_currency(input.currency);
+        if total > max_value {
+            return Err(ectx!(err ErrorContext::ExceedsMaxValue, ErrorKind::MalformedInput => total, input.currency, max_value));
+        }
+        Ok((from_account, total))
+    }
+
+    fn create_batch_external_tx(
+        &self,
+        input: CreateBatchTransactionInput,
+        from_account: Account,
+        total: Amount,
+    ) -> impl Future<Item = Vec<Transaction>, Error = Error> + Send {
         let db_executor = self.db_executor.clone();
         let db_executor_ = self.db_executor.clone();
         let transactions_repo = self.transactions_repo.clone();
         let system_service = self.system_service.clone();
         let blockchain_service = self.blockchain_service.clone();
         let self_clone = self.clone();
-        let user_id_clone = input.user_id.clone();
+        let self_clone2 = self.clone();
+        let self_clone3 = self.clone();
+        let btc_transaction_size = self.config.fees_options.btc_transaction_size as f64;
+        // fees are estimated for a transaction with one output
+        let batch_size = btc_transaction_size + (BITCOIN_OUTPUT_SIZE * input.outputs.len().saturating_sub(1)) as f64;
+        let input_fee = input.fee;
         let from_account_clone = from_account.clone();
-        let input_fee = input.fee.clone();
-        Either::B(self
-            .blockchain_service
-            .estimate_withdrawal_fee(input.fee, fee_currency, to_currency)
-            .map_err({
-                let fee = input.fee.clone();
-                ectx!(ErrorKind::Internal => fee, fee_currency, to_currency)
-            })
-            .and_then(move |FeeEstimate {gross_fee: total_fee_est,fee_price: fee_price_est,..}|{
+        let (lock_user_id, lock_token) = (input.user_id, Uuid::new_v4());
+        self.blockchain_service
+            .estimate_withdrawal_fee(input_fee, Currency::Btc, Currency::Btc, None, false)
+            .map_err(ectx!(ErrorKind::Internal => input_fee))
+            .and_then(move |FeeEstimate { fee_price, .. }| {
+                // fee price is per byte and is kept as estimated, floor included, so that the transaction is
+                // mined as fast as a single withdrawal. Every extra output makes both fees bigger instead
+                let gross_fee = Amount::new((fee_price * batch_size).ceil() as u128);
+                let fee = Amount::new((input_fee.raw() as f64 * batch_size / btc_transaction_size).ceil() as u128);
                 db_executor.execute_transaction_with_isolation(Isolation::Serializable, move || {
-                    let withdrawal_accs_with_balance =
-                        transactions_repo
-                        .get_accounts_for_withdrawal(value, to_currency, total_fee_est)
-                        .map_err(ectx!(try convert => value, to_currency, total_fee_est))?;
-
-                    let mut total_value = Amount::new(0);
-                    //double check
-                    for AccountWithBalance {
-                        account: acc,
-                        balance: value,
-                    } in &withdrawal_accs_with_balance
-                    {
-                        let acc_id = acc.id;
-                        let balance =
-                            transactions_repo
-                            .get_account_balance(acc_id, AccountKind::Dr)
-                            .map_err(ectx!(try convert => acc_id, AccountKind::Dr))?;
-                        if balance < *value {
-                            let mut errors = ValidationErrors::new();
-                            let mut error = ValidationError::new("not_enough_balance");
-                            error.message = Some("account balance is not enough".into());
-                            errors.add("value", error);
-                            return Err(
-                                ectx!(err ErrorContext::NotEnoughFunds, ErrorKind::InvalidInput(serde_json::to_string(&errors).unwrap_or_default()) => balance, value),
-                            );
-                        }
-                        total_value = total_value
-                            .checked_add(*value)
-                            .ok_or(ectx!(try err ErrorContext::BalanceOverflow, ErrorKind::Internal => total_value, *value))?;
-                    }
-
-                    if total_value != input.value {
-                        return Err(ectx!(err ErrorContext::InvalidValue, ErrorKind::Internal => input.clone(), total_value));
+                    let required = total
+                        .try_add(fee)
+                        .map_err(ectx!(try ErrorContext::BalanceOverflow, ErrorKind::Internal => total, fee))?;
+                    self_clone2.acquire_withdrawal_lock(input.user_id, lock_token)?;
+                    self_clone2.check_withdrawal_cooldown(input.user_id)?;
+                    self_clone2.check_withdrawal_balance(&from_account_clone, required)?;
+                    self_clone2.check_daily_withdrawal_limit(input.user_id, Currency::Btc, total)?;
+                    let mut withdrawal_accs_with_balance = transactions_repo
+                        .get_accounts_for_withdrawal(total, Currency::Btc, gross_fee)
+                        .map_err(ectx!(try convert => total, gross_fee))?;
+                    // all outputs are spent from one address, so it must hold the whole value
+                    if withdrawal_accs_with_balance.len() != 1 {
+                        let mut errors = ValidationErrors::new();
+                        let mut error = ValidationError::new("not_enough_liquidity");
+                        error.message = Some("There is no single address to send all outputs from, use separate withdrawals".into());
+                        errors.add("value", error);
+                        return Err(
+                            ectx!(err ErrorContext::NotEnoughFunds, ErrorKind::InvalidInput(serde_json::to_string(&errors).unwrap_or_default()) => total),
+                        );
                     }
-
+                    let withdrawal_acc = withdrawal_accs_with_balance.remove(0).account;
+                    let fees_account = system_service
+                        .get_system_fees_account(Currency::Btc)
+                        .map_err(ectx!(try ErrorKind::Internal))?;
+                    Ok((input, withdrawal_acc, fees_account, fee, gross_fee, fee_price))
+                })
+            })
+            .and_then(move |(input, withdrawal_acc, fees_account, fee, gross_fee, fee_price)| {
+                let withdrawal_address = withdrawal_acc.address.clone();
+                blockchain_service
+                    .create_batch_bitcoin_tx(withdrawal_address.clone(), input.outputs.clone(), fee_price)
+                    .map_err(ectx!(ErrorKind::Internal => withdrawal_address, fee_price))
+                    .map(move |blockchain_tx_id| (input, withdrawal_acc, fees_account, fee, gross_fee, fee_price, blockchain_tx_id))
+            })
+            .and_then(move |(input, withdrawal_acc, fees_account, fee, gross_fee, fee_price, blockchain_tx_id)| {
+                let gid = input.id;
+                // the blockchain tx is already sent, so the ledger is written regardless of fee estimate mismatch
+                db_executor_.execute_transaction_with_isolation(Isolation::Serializable, move || {
+                    let mut result = vec![];
+                    let fee_tx = NewTransaction {
+                        id: input.id,
+                        gid: input.id,
+                        user_id: input.user_id,
+                        dr_account_id: from_account.id,
+                        cr_account_id: fees_account.id,
+                        currency: Currency::Btc,
+                        value: fee,
+                        status: TransactionStatus::Done,
+                        blockchain_tx_id: None,
+                        kind: TransactionKind::Fee,
+                        group_kind: TransactionGroupKind::Withdrawal,
+                        related_tx: None,
+                        meta: None,
+                        hold_until: None,
+                        fee_price: Some(fee_price),
+                        gross_fee: Some(gross_fee),
+                        user_note: None,
+                        category: None,
+                    };
+                    result.push(self_clone.create_base_tx(fee_tx, from_account.clone(), fees_account)?);
+                    // one leg per output, all of them confirmed by the same blockchain tx
+                    let mut current_tx_id = input.id;
+                    for output in input.outputs {
+                        current_tx_id = current_tx_id.next();
+                        let new_tx = NewTransaction {
+                            id: current_tx_id,
+                            gid: input.id,
+                            user_id: input.user_id,
+                            dr_account_id: from_account.id,
+                            cr_account_id: withdrawal_acc.id,
+                            currency: Currency::Btc,
+                            value: output.value,
+                            status: TransactionStatus::Pending,
+                            blockchain_tx_id: Some(blockchain_tx_id.clone()),
+                            kind: TransactionKind::Withdrawal,
+                            group_kind: TransactionGroupKind::Withdrawal,
+                            related_tx: None,
+                            meta: Some(json!({ "to": output.to })),
+                            hold_until: None,
+                            fee_price: None,
+                            gross_fee: None,
+                            user_note: None,
+                            category: None,
+                        };
+                        result.push(self_clone.create_base_tx(new_tx, from_account.clone(), withdrawal_acc.clone())?);
+                    }
+                    Ok(result)
+                })
+                .map_err(move |e| withdrawal_not_recorded_error(e, gid))
+            })
+            .then(move |res| self_clone3.release_withdrawal_lock(lock_user_id, lock_token, res))
+    }
+
+    fn create_split_external_tx(
+        &self,
+        input: CreateBatchTransactionInput,
+        from_account: Account,
+        total: Amount,
+    ) -> impl Future<Item = Vec<Transaction>, Error = Error> + Send {
+        let db_executor = self.db_executor.clone();
+        let db_executor_ = self.db_executor.clone();
+        let transactions_repo = self.transactions_repo.clone();
+        let system_service = self.system_service.clone();
+        let blockchain_service = self.blockchain_service.clone();
+        let self_clone = self.clone();
+        let self_clone2 = self.clone();
+        let self_clone3 = self.clone();
+        let currency = input.currency;
+        let input_fee = input.fee;
+        let from_account_clone = from_account.clone();
+        let (lock_user_id, lock_token) = (input.user_id, Uuid::new_v4());
+        // the fee pays for all blockchain transactions, at least one per output
+        let outputs_count = Amount::new(input.outputs.len() as u128);
+        input_fee
+            .checked_div(outputs_count)
+            .ok_or(ectx!(err ErrorContext::BalanceOverflow, ErrorKind::Internal => input_fee, outputs_count))
+            .into_future()
+            .and_then({
+                let blockchain_service = self.blockchain_service.clone();
+                move |output_fee| {
+                    blockchain_service
+                        .estimate_withdrawal_fee(output_fee, currency, currency, None, false)
+                        .map_err(ectx!(ErrorKind::Internal => output_fee, currency))
+                }
+            })
+            .and_then(move |FeeEstimate { gross_fee, fee_price, .. }| {
+                db_executor.execute_transaction_with_isolation(Isolation::Serializable, move || {
+                    let required = total
+                        .try_add(input_fee)
+                        .map_err(ectx!(try ErrorContext::BalanceOverflow, ErrorKind::Internal => total, input_fee))?;
+                    self_clone2.acquire_withdrawal_lock(input.user_id, lock_token)?;
+                    self_clone2.check_withdrawal_cooldown(input.user_id)?;
+                    self_clone2.check_withdrawal_balance(&from_account_clone, required)?;
+                    self_clone2.check_daily_withdrawal_limit(input.user_id, currency, total)?;
+                    let sources = transactions_repo
+                        .get_accounts_for_withdrawal(total, currency, gross_fee)
+                        .map_err(ectx!(try convert => total, currency, gross_fee))?;
+                    self_clone2.check_withdrawal_sources_count(currency, sources.len())?;
+                    for AccountWithBalance { account, balance: value } in &sources {
+                        let acc_id = account.id;
+                        let balance = transactions_repo
+                            .get_account_balance(acc_id, AccountKind::Dr)
+                            .map_err(ectx!(try convert => acc_id, AccountKind::Dr))?;
+                        if balance < *value {
+                            let shortfall = value.checked_sub(balance).unwrap_or_default();
+                            warn!("Liquidity account {} holds {} {}, but {} is selected for withdrawal", acc_id, balance, currency, value);
+                            return Err(
+                                ectx!(err ErrorContext::NotEnoughLiquidity, liquidity_unavailable_error(currency) => acc_id, value, balance, shortfall),
+                            );
+                        }
+                    }
+                    let parts = split_outputs(sources, &input.outputs)
+                        .ok_or(ectx!(try err ErrorContext::InvalidValue, ErrorKind::Internal => input.clone(), total))?;
+                    let fees_account = system_service
+                        .get_system_fees_account(currency)
+                        .map_err(ectx!(try ErrorKind::Internal => currency))?;
+                    Ok((input, fees_account, parts, gross_fee, fee_price))
+                })
+            })
+            .and_then(move |(input, fees_account, parts, gross_fee, fee_price)| {
+                let parts_count = parts.len();
+                let (gid, user_id, from) = (input.id, input.user_id, input.from);
+                let legs: Vec<(NewTransaction, Account)> = Vec::new();
+                futures::stream::iter_ok(parts)
+                    .fold((gid, legs), move |(current_tx_id, mut legs), (source, output)| {
+                        let source_address = source.address.clone();
+                        let to = output.to.clone();
+                        let value = output.value;
+                        let blockchain_tx = match currency {
+                            Currency::Btc => blockchain_service.create_bitcoin_tx(source_address.clone(), to.clone(), value, fee_price),
+                            Currency::Eth | Currency::Stq => {
+                                blockchain_service.create_ethereum_tx(source_address.clone(), to.clone(), value, fee_price, currency, None)
+                            }
+                        };
+                        blockchain_tx
+                            .map_err(ectx!(ErrorKind::Internal => source_address, to, value, fee_price))
+                            .then(move |res| match res {
+                                Ok(blockchain_tx_id) => {
+                                    let current_tx_id = current_tx_id.next();
+                                    let new_tx = NewTransaction {
+                                        id: current_tx_id,
+                                        gid,
+                                        user_id,
+                                        dr_account_id: from,
+                                        cr_account_id: source.id,
+                                        currency,
+                                        value,
+                                        status: TransactionStatus::Pending,
+                                        blockchain_tx_id: Some(blockchain_tx_id),
+                                        kind: TransactionKind::Withdrawal,
+                                        group_kind: TransactionGroupKind::Withdrawal,
+                                        related_tx: None,
+                                        meta: Some(json!({ "to": output.to })),
+                                        hold_until: None,
+                                        fee_price: None,
+                                        gross_fee: None,
+                                        user_note: None,
+                                        category: None,
+                                    };
+                                    legs.push((new_tx, source));
+                                    Ok((current_tx_id, legs))
+                                }
+                                Err(e) => Err((e, legs)),
+                            })
+                    })
+                    .then(move |res| {
+                        let (legs, error) = match res {
+                            Ok((_, legs)) => (legs, None),
+                            Err((e, legs)) => (legs, Some(e)),
+                        };
+                        match error {
+                            // nothing is sent to blockchain, so there is nothing to account for
+                            Some(e) if legs.is_empty() => Either::A(future::err(e)),
+                            // the sent parts are already on their way, so the ledger must reflect them
+                            error => {
+                                if let Some(e) = error {
+                                    log_and_capture_error(e);
+                                }
+                                Either::B(db_executor_.execute_transaction_with_isolation(Isolation::Serializable, move || {
+                                    let mut result = vec![];
+                                    let total_gross_fee = gross_fee
+                                        .checked_mul(Amount::new(parts_count as u128))
+                                        .ok_or(ectx!(try err ErrorContext::BalanceOverflow, ErrorKind::Internal => gross_fee, parts_count))?;
+                                    let fee_tx = NewTransaction {
+                                        id: input.id,
+                                        gid: input.id,
+                                        user_id: input.user_id,
+                                        dr_account_id: from_account.id,
+                                        cr_account_id: fees_account.id,
+                                        currency,
+                                        value: input.fee,
+                                        status: TransactionStatus::Done,
+                                        blockchain_tx_id: None,
+                                        kind: TransactionKind::Fee,
+                                        group_kind: TransactionGroupKind::Withdrawal,
+                                        related_tx: None,
+                                        meta: None,
+                                        hold_until: None,
+                                        fee_price: Some(fee_price),
+                                        gross_fee: Some(total_gross_fee),
+                                        user_note: None,
+                                        category: None,
+                                    };
+                                    result.push(self_clone.create_base_tx(fee_tx, from_account.clone(), fees_account.clone())?);
+                                    for (new_tx, source) in legs {
+                                        result.push(self_clone.create_base_tx(new_tx, from_account.clone(), source)?);
+                                    }
+                                    Ok(result)
+                                })
+                                .map_err(move |e| withdrawal_not_recorded_error(e, gid)))
+                            }
+                        }
+                    })
+            })
+            .then(move |res| self_clone3.release_withdrawal_lock(lock_user_id, lock_token, res))
+    }
+
+    fn create_internal_mono_currency_tx(
+        &self,
+        create_tx_input: CreateTransactionInput,
+        dr_account: Account,
+        cr_account: Account,
+    ) -> impl Future<Item = Transaction, Error = Error> + Send {
+        // transfers with hold_until in future are applied later by system service
+        let now = self.clock.now();
+        let hold_until = create_tx_input.hold_until.filter(|hold_until| *hold_until > now);
+        let status = match hold_until {
+            Some(_) => TransactionStatus::Scheduled,
+            None => TransactionStatus::Done,
+        };
+        let tx = NewTransaction {
+            id: create_tx_input.id,
+            gid: create_tx_input.id,
+            user_id: create_tx_input.user_id,
+            dr_account_id: dr_account.id,
+            cr_account_id: cr_account.id,
+            currency: dr_account.currency,
+            value: create_tx_input.value,
+            status,
+            blockchain_tx_id: None,
+            kind: TransactionKind::Internal,
+            group_kind: TransactionGroupKind::Internal,
+            related_tx: None,
+            meta: create_tx_input.fiat_meta(),
+            hold_until,
+            fee_price: None,
+            gross_fee: None,
+            user_note: create_tx_input.user_note.clone(),
+            category: create_tx_input.category.clone(),
+        };
+        let self_clone = self.clone();
+        // internal transfer touches only db, so it's safe to retry it on serialization failures
+        self.db_executor
+            .execute_serializable_with_retries(self.config.database.serialization_retries, move || {
+                self_clone.check_internal_source_balance(&tx, &dr_account)?;
+                self_clone.create_base_tx(tx.clone(), dr_account.clone(), cr_account.clone())
+            })
+    }
+
+    // Internal transfers are made from exactly the source account, unlike withdrawals that pool
+    // liquidity accounts. If the source is short, but another account of the user could pay,
+    // the client gets a distinct error instead of the generic one from `create_base_tx`
+    fn check_internal_source_balance(&self, tx: &NewTransaction, dr_account: &Account) -> Result<(), Error> {
+        let user_id = dr_account.user_id;
+        let balance = self
+            .transactions_repo
+            .get_accounts_balance(user_id, &[dr_account.clone()])
+            .map(|accounts| accounts[0].balance)
+            .map_err(ectx!(try convert => user_id))?;
+        if balance >= tx.value {
+            return Ok(());
+        }
+        let other_accounts: Vec<Account> = self
+            .accounts_repo
+            .list_for_user(user_id, 0, i64::max_value())
+            .map_err(ectx!(try convert => user_id))?
+            .into_iter()
+            .filter(|account| account.id != dr_account.id && account.currency == dr_account.currency)
+            .collect();
+        if other_accounts.is_empty() {
+            return Ok(());
+        }
+        let other_balances = self
+            .transactions_repo
+            .get_accounts_balance(user_id, &other_accounts)
+            .map_err(ectx!(try convert => user_id))?;
+        if other_balances.iter().any(|account| account.balance >= tx.value) {
+            let value = tx.value;
+            return Err(
+                ectx!(err ErrorContext::NotEnoughFundsInSource, not_enough_funds_in_source_error(tx.currency, value, balance) => dr_account.id, value, balance),
+            );
+        }
+        Ok(())
+    }
+
+    // Internal move from the funding account and the withdrawal share gid. The move is written in the same
+    // db transaction as the withdrawal is classified, so it is never written for an invalid withdrawal.
+    // Sending to blockchain is the commit point: the move is cancelled only if the withdrawal fails before it.
+    // Once anything is sent, the move stays as it is and the error is reported for manual reconciliation.
+    fn create_transfer_and_withdrawal_tx(
+        &self,
+        input: CreateTransactionInput,
+        funding_account_id: AccountId,
+    ) -> impl Future<Item = Vec<Transaction>, Error = Error> + Send {
+        let gid = input.id;
+        let accounts_repo = self.accounts_repo.clone();
+        let transactions_repo = self.transactions_repo.clone();
+        let classifier_service = self.classifier_service.clone();
+        let db_executor = self.db_executor.clone();
+        let self_clone = self.clone();
+        let self_clone2 = self.clone();
+        self.db_executor
+            .execute_transaction_with_isolation(Isolation::Serializable, move || {
+                self_clone.check_new_transaction_id(gid)?;
+                let funding_account = accounts_repo
+                    .get(funding_account_id)
+                    .map_err(ectx!(try convert => funding_account_id))?
+                    .ok_or(ectx!(try err ErrorContext::NoAccount, ErrorKind::NotFound => funding_account_id))?;
+                let from = input.from;
+                let withdrawal_account = accounts_repo
+                    .get(from)
+                    .map_err(ectx!(try convert => from))?
+                    .ok_or(ectx!(try err ErrorContext::NoAccount, ErrorKind::NotFound => from))?;
+                if funding_account.user_id != input.user_id || withdrawal_account.user_id != input.user_id {
+                    return Err(ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => input.user_id, funding_account_id, from));
+                }
+                if funding_account.id == withdrawal_account.id || funding_account.currency != withdrawal_account.currency {
+                    return Err(ectx!(err ErrorContext::InvalidCurrency, ErrorKind::MalformedInput => funding_account_id, from));
+                }
+                let withdrawal_input = CreateTransactionInput { id: gid.next(), ..input };
+                let (from_account, to_blockchain_address, currency) =
+                    match classifier_service.validate_and_classify_transaction(&withdrawal_input)? {
+                        TransactionType::Withdrawal(from_account, to_blockchain_address, currency) => {
+                            (from_account, to_blockchain_address, currency)
+                        }
+                        _ => return Err(ectx!(err ErrorContext::NotSupported, ErrorKind::MalformedInput => withdrawal_input)),
+                    };
+                let value = withdrawal_input
+                    .value
+                    .try_add(withdrawal_input.fee)
+                    .map_err(ectx!(try ErrorContext::BalanceOverflow, ErrorKind::Internal => withdrawal_input))?;
+                let move_tx = NewTransaction {
+                    id: gid,
+                    gid,
+                    user_id: withdrawal_input.user_id,
+                    dr_account_id: funding_account.id,
+                    cr_account_id: withdrawal_account.id,
+                    currency: withdrawal_account.currency,
+                    value,
+                    status: TransactionStatus::Done,
+                    blockchain_tx_id: None,
+                    kind: TransactionKind::Internal,
+                    group_kind: TransactionGroupKind::Withdrawal,
+                    related_tx: None,
+                    meta: None,
+                    hold_until: None,
+                    fee_price: None,
+                    gross_fee: None,
+                    user_note: withdrawal_input.user_note.clone(),
+                    category: withdrawal_input.category.clone(),
+                };
+                let move_tx = self_clone.create_base_tx(move_tx, funding_account, withdrawal_account)?;
+                Ok((move_tx, withdrawal_input, from_account, to_blockchain_address, currency))
+            })
+            .and_then(move |(move_tx, withdrawal_input, from_account, to_blockchain_address, currency)| {
+                self_clone2
+                    .create_external_mono_currency_tx(
+                        withdrawal_input,
+                        from_account,
+                        to_blockchain_address,
+                        currency,
+                        Some(gid),
+                        None,
+                        None,
+                        None,
+                        None,
+                    )
+                    .then(move |res| match res {
+                        Ok(txs) => {
+                            let mut res = vec![move_tx];
+                            res.extend(txs);
+                            Either::A(future::ok(res))
+                        }
+                        Err(e) => {
+                            if is_nothing_sent_to_blockchain(&e) {
+                                // nothing was sent to blockchain, so funds are returned to the funding account
+                                Either::B(db_executor.execute(move || -> Result<Vec<Transaction>, Error> {
+                                    let move_tx_id = move_tx.id;
+                                    transactions_repo
+                                        .update_status_by_id(move_tx_id, TransactionStatus::Cancelled)
+                                        .map_err(ectx!(try convert => move_tx_id))?;
+                                    Err(e)
+                                }))
+                            } else {
+                                // withdrawal is sent, but not recorded: cancelling the move here would spend the funds twice
+                                Either::A(future::err(e))
+                            }
+                        }
+                    })
+            })
+    }
+
+    fn create_external_mono_currency_tx(
+        &self,
+        input: CreateTransactionInput,
+        from_account: Account,
+        to_blockchain_address: BlockchainAddress,
+        to_currency: Currency,
+        // these group params will be filled with defaults for external mono currency
+        // to reuse it in external withdrawal, we put overrides here
+        gid: Option<TransactionId>,
+        tx_kind: Option<TransactionKind>,
+        tx_group_kind: Option<TransactionGroupKind>,
+        fee_currency: Option<Currency>,
+        // by default the fee is written off from_account. However you can override this
+        // using this param
+        fee_payer_account_id: Option<AccountId>,
+    ) -> impl Future<Item = Vec<Transaction>, Error = Error> + Send {
+        // errors before blockchain txs are sent are marked, so that callers know it's safe to revert
+        // what they did in preparation for the withdrawal
+        if from_account.currency != to_currency {
+            return Either::A(future::err(
+                ectx!(err ErrorContext::InvalidCurrency, ErrorContext::NothingSentToBlockchain, ErrorKind::Internal => from_account, to_blockchain_address, to_currency),
+            ));
+        };
+        if let Err(e) = self.check_withdrawals_enabled(to_currency) {
+            return Either::A(future::err(ectx!(convert err e, ErrorContext::NothingSentToBlockchain)));
+        }
+        if input.value == Amount::new(0) {
+            return Either::A(future::err(
+                ectx!(err ErrorContext::InvalidValue, ErrorContext::NothingSentToBlockchain, ErrorKind::MalformedInput => input.id, input.value),
+            ));
+        }
+
+        let gid = gid.unwrap_or(input.id);
+        let value = input.value;
+        // the destination is recorded on the legs as well, so that a lost pending record can be restored
+        let mut meta = input.fiat_meta().unwrap_or_else(|| json!({}));
+        meta["to"] = json!(to_blockchain_address);
+        // stq withdrawals are charged in stq as well: the fee is converted to eth fee price
+        // and the gas is paid by the eth fees account (see `create_ethereum_tx`),
+        // so users don't need any eth to withdraw stq
+        let fee_currency = fee_currency.unwrap_or(from_account.currency);
+        let db_executor = self.db_executor.clone();
+        let db_executor_ = self.db_executor.clone();
+        let transactions_repo = self.transactions_repo.clone();
+        let system_service = self.system_service.clone();
+        let blockchain_service = self.blockchain_service.clone();
+        let self_clone = self.clone();
+        let self_clone2 = self.clone();
+        let self_clone3 = self.clone();
+        let user_id_clone = input.user_id.clone();
+        let (lock_user_id, lock_token) = (input.user_id, Uuid::new_v4());
+        let from_account_clone = from_account.clone();
+        let input_fee = input.fee.clone();
+        let user_note = input.user_note.clone();
+        let category = input.category.clone();
+        let blockchain_service_ = self.blockchain_service.clone();
+        Either::B(self
+            .blockchain_service
+            .estimate_gas_limit(from_account.address.clone(), to_blockchain_address.clone(), value, to_currency, input.gas_limit_override)
+            .and_then(move |gas_limit| {
+                // the same gas limit is used in the blockchain tx, so that the fee price covers it
+                blockchain_service_
+                    .estimate_withdrawal_fee(input_fee, fee_currency, to_currency, gas_limit, false)
+                    .map(move |fee_estimate| (fee_estimate, gas_limit))
+            })
+            .map_err({
+                let fee = input.fee.clone();
+                ectx!(ErrorKind::Internal => fee, fee_currency, to_currency)
+            })
+            .and_then(move |(FeeEstimate {gross_fee: total_fee_est,fee_price: fee_price_est,..}, gas_limit_override)|{
+                db_executor.execute_transaction_with_isolation(Isolation::Serializable, move || {
+                    // checking limits in the same transaction to make concurrent withdrawals see each other
+                    self_clone2.acquire_withdrawal_lock(input.user_id, lock_token)?;
+                    self_clone2.check_withdrawal_cooldown(input.user_id)?;
+                    self_clone2.check_daily_withdrawal_limit(input.user_id, to_currency, value)?;
+
+                    let withdrawal_accs_with_balance = self_clone2.select_withdrawal_sources(value, to_currency, total_fee_est)?;
+
+                    let mut total_value = Amount::new(0);
+                    //double check
+                    for AccountWithBalance {
+                        account: acc,
+                        balance: value,
+                    } in &withdrawal_accs_with_balance
+                    {
+                        let acc_id = acc.id;
+                        let balance =
+                            transactions_repo
+                            .get_account_balance(acc_id, AccountKind::Dr)
+                            .map_err(ectx!(try convert => acc_id, AccountKind::Dr))?;
+                        if balance < *value {
+                            let shortfall = value.checked_sub(balance).unwrap_or_default();
+                            warn!("Liquidity account {} holds {} {}, but {} is selected for withdrawal", acc_id, balance, to_currency, value);
+                            return Err(
+                                ectx!(err ErrorContext::NotEnoughLiquidity, liquidity_unavailable_error(to_currency) => acc_id, value, balance, shortfall),
+                            );
+                        }
+                        total_value = total_value
+                            .try_add(*value)
+                            .map_err(ectx!(try ErrorContext::BalanceOverflow, ErrorKind::Internal))?;
+                    }
+
+                    if total_value != input.value {
+                        return Err(ectx!(err ErrorContext::InvalidValue, ErrorKind::Internal => input.clone(), total_value));
+                    }
+
                     system_service
                         .get_system_fees_account(to_currency)
                         .map_err(ectx!(ErrorKind::Internal => to_currency))
-                        .map(|fees_account| (fees_account, input.id, withdrawal_accs_with_balance, fee_price_est))
+                        .map(|fees_account| (fees_account, input.id, withdrawal_accs_with_balance, fee_price_est, total_fee_est, gas_limit_override))
                 })
             })
-            .and_then(move |(fees_account, current_tx_id, withdrawal_accs_with_balance, fee_price_est)|{
+            .map_err(ectx!(convert ErrorContext::NothingSentToBlockchain))
+            .and_then(move |(fees_account, current_tx_id, withdrawal_accs_with_balance, fee_price_est, total_fee_est, gas_limit_override)|{
                 let new_db_transactions: Vec<(NewTransaction, Account, Account)> = Vec::new();
+                let user_note_ = user_note.clone();
+                let category_ = category.clone();
                 futures::stream::iter_ok(withdrawal_accs_with_balance).fold((current_tx_id, new_db_transactions), move |(current_tx_id, mut acc_), AccountWithBalance {account: acc,balance: value}| {
                     let to = to_blockchain_address.clone();
                     let acc_address = acc.address.clone();
@@ -279,15 +1369,19 @@ impl<E: DbExecutor> TransactionsServiceImpl<E> {
                     let to_currency = to_currency.clone();
                     let tx_kind = tx_kind.clone();
                     let tx_group_kind = tx_group_kind.clone();
+                    let meta = meta.clone();
+                    let user_note = user_note.clone();
+                    let category = category.clone();
+                    // kinds are kept, so that e.g. an unavailable signer can be told apart from a missing key
                     match to_currency {
                         x if x == Currency::Eth || x == Currency::Stq =>
                             Either::A(blockchain_service
-                            .create_ethereum_tx(acc.address.clone(), to.clone(), value, fee_price_est, x)
-                            .map_err(ectx!(ErrorKind::Internal => acc_address, to, value, fee_price_est, x))),
+                            .create_ethereum_tx(acc.address.clone(), to.clone(), value, fee_price_est, x, gas_limit_override)
+                            .map_err(ectx!(convert => acc_address, to, value, fee_price_est, x))),
                         x if x == Currency::Btc =>
                             Either::B(blockchain_service
                             .create_bitcoin_tx(acc.address.clone(), to.clone(), value, fee_price_est)
-                            .map_err(ectx!(ErrorKind::Internal => acc_address, to, value, fee_price_est))),
+                            .map_err(ectx!(convert => acc_address, to, value, fee_price_est))),
                         _ => unreachable!()
                     }.then(move |res| {
                         match res {
@@ -306,7 +1400,12 @@ impl<E: DbExecutor> TransactionsServiceImpl<E> {
                                     kind: tx_kind.unwrap_or(TransactionKind::Withdrawal),
                                     group_kind: tx_group_kind.unwrap_or(TransactionGroupKind::Withdrawal),
                                     related_tx: None,
-                                    meta: None,
+                                    meta: Some(meta),
+                                    hold_until: None,
+                                    fee_price: None,
+                                    gross_fee: None,
+                                    user_note: user_note.clone(),
+                                    category: category.clone(),
                                 };
                                 acc_.push((new_tx, from_account.clone(), acc.clone()));
                                 Ok((current_tx_id, acc_))
@@ -336,15 +1435,22 @@ impl<E: DbExecutor> TransactionsServiceImpl<E> {
                                 group_kind: tx_group_kind.unwrap_or(TransactionGroupKind::Withdrawal),
                                 related_tx: None,
                                 meta: None,
+                                hold_until: None,
+                                fee_price: Some(fee_price_est),
+                                gross_fee: Some(total_fee_est),
+                                user_note: user_note_.clone(),
+                                category: category_.clone(),
                             };
                             // first - we are adding fee transaction
+                            // network fee is unknown until confirmation, so BlockchainFee leg is added by BlockchainFetcher
                             result.push(self_clone.create_base_tx(fee_tx, from_account_clone.clone(), fees_account.clone())?);
                             // adding all blockchain transactions
                             for (new_tx, dr, cr) in new_db_transactions {
                                 result.push(self_clone.create_base_tx(new_tx, dr, cr)?);
                             }
                             Ok(result)
-                        })),
+                        })
+                        .map_err(move |e| withdrawal_not_recorded_error(e, gid))),
                         Err((e, new_db_transactions)) => Either::B({
                             // if we have more then zero db_transactions - so we have at least one blockchain transaction sent.
                             if new_db_transactions.len() > 0 {
@@ -366,6 +1472,11 @@ impl<E: DbExecutor> TransactionsServiceImpl<E> {
                                         group_kind: tx_group_kind.unwrap_or(TransactionGroupKind::Withdrawal),
                                         related_tx: None,
                                         meta: None,
+                                        hold_until: None,
+                                        fee_price: Some(fee_price_est),
+                                        gross_fee: Some(total_fee_est),
+                                        user_note: user_note_.clone(),
+                                        category: category_.clone(),
                                     };
                                     // first - we are adding fee transaction
                                     result.push(self_clone.create_base_tx(fee_tx, from_account_clone.clone(), fees_account.clone())?);
@@ -374,14 +1485,16 @@ impl<E: DbExecutor> TransactionsServiceImpl<E> {
                                         result.push(self_clone.create_base_tx(new_tx, dr, cr)?);
                                     }
                                     Ok(result)
-                                }))
+                                })
+                                .map_err(move |e| withdrawal_not_recorded_error(e, gid)))
                             } else {
-                                Either::B(future::err(e))
+                                Either::B(future::err(ectx!(convert err e, ErrorContext::NothingSentToBlockchain)))
                             }
                         })
                     }
                 })
-            }))
+            })
+            .then(move |res| self_clone3.release_withdrawal_lock(lock_user_id, lock_token, res)))
     }
 
     fn create_internal_multi_currency_tx(
@@ -389,12 +1502,15 @@ impl<E: DbExecutor> TransactionsServiceImpl<E> {
         input: CreateTransactionInput,
         from_account: Account,
         to_account: Account,
-        exchange_id: ExchangeId,
         exchange_rate: f64,
     ) -> impl Future<Item = Vec<Transaction>, Error = Error> + Send {
         let db_executor = self.db_executor.clone();
+        let db_executor_ = self.db_executor.clone();
+        let transactions_repo = self.transactions_repo.clone();
         let system_service = self.system_service.clone();
         let self_clone = self.clone();
+        let gid = input.id;
+        let exchange_id = ExchangeId::for_transaction(gid);
         let exchange_input = ExchangeInput {
             id: exchange_id,
             from: from_account.currency,
@@ -404,75 +1520,176 @@ impl<E: DbExecutor> TransactionsServiceImpl<E> {
             amount_currency: input.value_currency,
         };
         let exchange_input_clone = exchange_input.clone();
-        self.exchange_client
-            .exchange(exchange_input, Role::User)
-            .map_err(ectx!(convert => exchange_input_clone))
-            .and_then(move |_| {
-                db_executor.execute_transaction_with_isolation(Isolation::Serializable, move || {
-                    let mut res: Vec<Transaction> = Vec::new();
-
-                    let (from_value, to_value) = if from_account.currency == input.value_currency {
-                        (
-                            input.value,
-                            input.value.convert(from_account.currency, to_account.currency, exchange_rate),
-                        )
-                    } else if to_account.currency == input.value_currency {
-                        (
-                            input.value.convert(to_account.currency, from_account.currency, 1.0 / exchange_rate),
-                            input.value,
-                        )
-                    } else {
-                        return Err(ectx!(err ErrorContext::InvalidCurrency, ErrorKind::Internal => input, from_account, to_account));
-                    };
+        let rate_input = RateInput::new(from_account.currency, to_account.currency, input.value, input.value_currency);
+        let rate_input_clone = rate_input.clone();
+        let exchange_client = self.exchange_client.clone();
+        let self_clone2 = self.clone();
+        let self_clone3 = self.clone();
+        let self_clone4 = self.clone();
+        let exchange_spread_bps = self.config.system.exchange_spread_bps;
+        // the classifier checks value currency as well, but nothing may be exchanged with the wrong one
+        let (from_value, to_value) = match exchange_values(&input, &from_account, &to_account, exchange_rate) {
+            Ok(values) => values,
+            Err(e) => return Either::A(future::err(e)),
+        };
+        // client supplied rate is checked against the live one before anything is exchanged
+        Either::B(
+            self.exchange_client
+                .rate(rate_input, Role::System)
+                .map_err(ectx!(convert => rate_input_clone))
+                .and_then(move |Rate { rate: live_rate, .. }| self_clone2.check_exchange_rate_band(exchange_rate, live_rate))
+                .and_then(move |_| {
+                    exchange_client
+                        .exchange(exchange_input, Role::User)
+                        .map_err(ectx!(convert => exchange_input_clone))
+                        .or_else(move |e| match e.kind() {
+                            // gateway might have executed the exchange before failing, so it is voided explicitly
+                            ErrorKind::ExchangeUnavailable | ErrorKind::Internal => {
+                                Either::A(self_clone3.cancel_exchange(exchange_id).then(move |_| Err(e)))
+                            }
+                            _ => Either::B(future::err(e)),
+                        })
+                })
+                .and_then(move |_| {
+                    let legs = db_executor.execute_transaction_with_isolation(Isolation::Serializable, move || {
+                        let mut res: Vec<Transaction> = Vec::new();
 
-                    let current_tx_id = input.id;
+                        // spread is kept from the exchanged value, so the user receives it at the net rate
+                        let spread = to_value
+                            .try_mul(Amount::new(exchange_spread_bps as u128))
+                            .and_then(|value| value.try_div(Amount::new(10_000)))
+                            .map_err(ectx!(try ErrorContext::BalanceOverflow, ErrorKind::Internal => to_value, exchange_spread_bps))?;
+                        let net_to_value = to_value
+                            .try_sub(spread)
+                            .map_err(ectx!(try ErrorContext::BalanceOverflow, ErrorKind::Internal => to_value, spread))?;
 
-                    // Moving money from `from` account to system liquidity account
-                    let from_acct_currency = from_account.currency.clone();
-                    let from_counterpart_acc = system_service
-                        .get_system_liquidity_account(from_acct_currency.clone())
-                        .map_err(ectx!(try ErrorKind::Internal => from_acct_currency))?;
-                    let from_tx = NewTransaction {
-                        id: current_tx_id,
-                        gid: input.id,
-                        user_id: input.user_id,
-                        dr_account_id: from_account.id,
-                        cr_account_id: from_counterpart_acc.id,
-                        currency: from_account.currency,
-                        value: from_value,
-                        status: TransactionStatus::Done,
-                        blockchain_tx_id: None,
-                        kind: TransactionKind::MultiFrom,
-                        group_kind: TransactionGroupKind::InternalMulti,
-                        related_tx: None,
-                        meta: None,
-                    };
-                    res.push(self_clone.create_base_tx(from_tx, from_account.clone(), from_counterpart_acc)?);
-
-                    // Moving money from system liquidity account to `to` account
-                    let current_tx_id = current_tx_id.next();
-                    let to_acct_currency = to_account.currency.clone();
-                    let to_counterpart_acc = system_service
-                        .get_system_liquidity_account(to_acct_currency.clone())
-                        .map_err(ectx!(try ErrorKind::Internal => to_acct_currency))?;
-                    let to_tx = NewTransaction {
-                        id: current_tx_id,
-                        gid: input.id,
-                        user_id: input.user_id,
-                        dr_account_id: to_counterpart_acc.id,
-                        cr_account_id: to_account.id,
-                        currency: to_account.currency,
-                        value: to_value,
-                        status: TransactionStatus::Done,
-                        blockchain_tx_id: None,
-                        kind: TransactionKind::MultiTo,
-                        group_kind: TransactionGroupKind::InternalMulti,
-                        related_tx: None,
-                        meta: None,
-                    };
-                    res.push(self_clone.create_base_tx(to_tx, to_counterpart_acc, to_account.clone())?);
-                    Ok(res)
-                })
+                        let current_tx_id = input.id;
+
+                        // Moving money from `from` account to system liquidity account
+                        let from_acct_currency = from_account.currency.clone();
+                        let from_counterpart_acc = system_service
+                            .select_system_liquidity_account(from_acct_currency.clone(), from_value, false)
+                            .map_err(ectx!(try ErrorKind::Internal => from_acct_currency, from_value))?;
+                        let from_tx = NewTransaction {
+                            id: current_tx_id,
+                            gid: input.id,
+                            user_id: input.user_id,
+                            dr_account_id: from_account.id,
+                            cr_account_id: from_counterpart_acc.id,
+                            currency: from_account.currency,
+                            value: from_value,
+                            status: TransactionStatus::Done,
+                            blockchain_tx_id: None,
+                            kind: TransactionKind::MultiFrom,
+                            group_kind: TransactionGroupKind::InternalMulti,
+                            related_tx: None,
+                            // executed rate is kept for reconciliation, see `get_exchange_history`
+                            meta: Some(json!({ "exchangeId": exchange_id, "exchangeRate": exchange_rate })),
+                            hold_until: None,
+                            fee_price: None,
+                            gross_fee: None,
+                            user_note: input.user_note.clone(),
+                            category: input.category.clone(),
+                        };
+                        res.push(self_clone.create_base_tx(from_tx, from_account.clone(), from_counterpart_acc)?);
+
+                        // Moving money from system liquidity account to `to` account
+                        let current_tx_id = current_tx_id.next();
+                        let to_acct_currency = to_account.currency.clone();
+                        let to_counterpart_acc = system_service
+                            .select_system_liquidity_account(to_acct_currency.clone(), net_to_value, true)
+                            .map_err(ectx!(try ErrorKind::Internal => to_acct_currency, net_to_value))?;
+                        let to_tx = NewTransaction {
+                            id: current_tx_id,
+                            gid: input.id,
+                            user_id: input.user_id,
+                            dr_account_id: to_counterpart_acc.id,
+                            cr_account_id: to_account.id,
+                            currency: to_account.currency,
+                            value: net_to_value,
+                            status: TransactionStatus::Done,
+                            blockchain_tx_id: None,
+                            kind: TransactionKind::MultiTo,
+                            group_kind: TransactionGroupKind::InternalMulti,
+                            related_tx: None,
+                            meta: None,
+                            hold_until: None,
+                            fee_price: None,
+                            gross_fee: None,
+                            user_note: input.user_note.clone(),
+                            category: input.category.clone(),
+                        };
+                        res.push(self_clone.create_base_tx(to_tx, to_counterpart_acc.clone(), to_account.clone())?);
+
+                        // Moving spread from system liquidity account to fees account
+                        if spread > Amount::new(0) {
+                            let current_tx_id = current_tx_id.next();
+                            let fees_account = system_service
+                                .get_system_fees_account(to_acct_currency)
+                                .map_err(ectx!(try ErrorKind::Internal => to_acct_currency))?;
+                            let spread_tx = NewTransaction {
+                                id: current_tx_id,
+                                gid: input.id,
+                                user_id: input.user_id,
+                                dr_account_id: to_counterpart_acc.id,
+                                cr_account_id: fees_account.id,
+                                currency: to_account.currency,
+                                value: spread,
+                                status: TransactionStatus::Done,
+                                blockchain_tx_id: None,
+                                kind: TransactionKind::Fee,
+                                group_kind: TransactionGroupKind::InternalMulti,
+                                related_tx: None,
+                                meta: None,
+                                hold_until: None,
+                                fee_price: None,
+                                gross_fee: None,
+                                user_note: input.user_note.clone(),
+                                category: input.category.clone(),
+                            };
+                            res.push(self_clone.create_base_tx(spread_tx, to_counterpart_acc, fees_account)?);
+                        }
+                        Ok(res)
+                    });
+                    // exchange is already executed at this point, but there are no legs to account for it
+                    legs.or_else(move |e| match e.kind() {
+                        // duplicate transaction id - if it is a retry of the recorded exchange, gateway returned
+                        // the original exchange deduplicated by exchange id, so it must stay
+                        ErrorKind::MalformedInput => Either::A(
+                            db_executor_
+                                .execute(move || transactions_repo.get(gid).map_err(ectx!(convert => gid)))
+                                .then(move |recorded| {
+                                    let recorded_exchange_id = match recorded {
+                                        Ok(recorded) => {
+                                            recorded.and_then(|tx| serde_json::from_value::<ExchangeId>(tx.meta["exchangeId"].clone()).ok())
+                                        }
+                                        // the exchange might be recorded, voiding it would leave its legs without funds
+                                        Err(lookup_error) => {
+                                            log_and_capture_error(lookup_error);
+                                            Some(exchange_id)
+                                        }
+                                    };
+                                    if recorded_exchange_id == Some(exchange_id) {
+                                        Either::A(future::err(e))
+                                    } else {
+                                        Either::B(self_clone4.cancel_exchange(exchange_id).then(move |_| Err(e)))
+                                    }
+                                }),
+                        ),
+                        _ => Either::B(self_clone4.cancel_exchange(exchange_id).then(move |_| Err(e))),
+                    })
+                }),
+        )
+    }
+
+    /// Voids the exchange on the gateway, failures are only logged as the original error is what the caller gets
+    fn cancel_exchange(&self, exchange_id: ExchangeId) -> impl Future<Item = (), Error = ()> + Send {
+        self.exchange_client
+            .cancel_exchange(exchange_id, Role::User)
+            .map(|_| ())
+            .map_err(move |e| {
+                let e: Error = ectx!(err e, ErrorKind::Internal => exchange_id);
+                log_and_capture_error(e);
             })
     }
 }
@@ -488,14 +1705,30 @@ impl<E: DbExecutor> TransactionsService for TransactionsServiceImpl<E> {
         let self_clone = self.clone();
         let self_clone2 = self.clone();
         let self_clone3 = self.clone();
-        let input_clone = input.clone();
+        let self_clone4 = self.clone();
+        let self_clone5 = self.clone();
+        let self_clone6 = self.clone();
+        let in_flight = match self.enter_in_flight() {
+            Ok(in_flight) => in_flight,
+            Err(e) => return Box::new(future::err(e)),
+        };
         Box::new(
             self.auth_service
                 .authenticate(token.clone())
                 .and_then(move |user| {
                     let input = CreateTransactionInput { user_id: user.id, ..input };
+                    self_clone6
+                        .check_rate_limit(user.id)
+                        .into_future()
+                        .and_then(move |_| self_clone6.resolve_fee_tier(input))
+                })
+                .and_then(move |input| self_clone5.resolve_drain_value(input))
+                .and_then(move |input| self_clone4.quote_fiat_value(input))
+                .and_then(move |input| {
+                    let input_clone = input.clone();
                     db_executor
                         .execute_transaction_with_isolation(Isolation::Serializable, move || {
+                            self_clone.check_new_transaction_id(input.id)?;
                             self_clone.classifier_service.validate_and_classify_transaction(&input)
                         })
                         .and_then(move |tx_type| {
@@ -519,9 +1752,10 @@ impl<E: DbExecutor> TransactionsService for TransactionsServiceImpl<E> {
                                         None,
                                     )) as BoxedFuture
                                 }
-                                TransactionType::InternalExchange(from, to, exchange_id, rate) => {
-                                    Box::new(self_clone3.create_internal_multi_currency_tx(input_clone, from, to, exchange_id, rate))
-                                        as BoxedFuture
+                                // exchange id of the client is replaced by the one derived from the transaction id,
+                                // so that retries of the transaction are deduplicated by the gateway
+                                TransactionType::InternalExchange(from, to, _exchange_id, rate) => {
+                                    Box::new(self_clone3.create_internal_multi_currency_tx(input_clone, from, to, rate)) as BoxedFuture
                                 }
                                 TransactionType::WithdrawalExchange(_from, _to_blockchain_address, _to_currency, _exchange_id, _rate) => {
                                     // This function is implemented but not tested. For now we disable it,
@@ -572,174 +1806,4718 @@ impl<E: DbExecutor> TransactionsService for TransactionsServiceImpl<E> {
                                 Either::B(future::ok(tx))
                             }
                         })
+                })
+                // the transaction is saved or failed at this point, so the process can be stopped
+                .then(move |res| {
+                    drop(in_flight);
+                    res
                 }),
         )
     }
 
-    fn get_transaction(
+    fn create_transfer_and_withdrawal(
         &self,
         token: AuthenticationToken,
-        transaction_id: TransactionId,
-    ) -> Box<Future<Item = Option<TransactionOut>, Error = Error> + Send> {
-        let transactions_repo = self.transactions_repo.clone();
+        input: CreateTransactionInput,
+        funding_account_id: AccountId,
+    ) -> Box<Future<Item = TransactionOut, Error = Error> + Send> {
+        let self_clone = self.clone();
+        let self_clone2 = self.clone();
+        let self_clone3 = self.clone();
+        let self_clone4 = self.clone();
+        let in_flight = match self.enter_in_flight() {
+            Ok(in_flight) => in_flight,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        Box::new(
+            self.auth_service
+                .authenticate(token)
+                .and_then(move |user| {
+                    let input = CreateTransactionInput { user_id: user.id, ..input };
+                    self_clone4.resolve_fee_tier(input)
+                })
+                .and_then(move |input| self_clone.quote_fiat_value(input))
+                .and_then(move |input| self_clone2.create_transfer_and_withdrawal_tx(input, funding_account_id))
+                .and_then(move |tx_group| {
+                    let db_executor = self_clone3.db_executor.clone();
+                    db_executor.execute_transaction_with_isolation(Isolation::RepeatableRead, move || {
+                        self_clone3.converter_service.convert_transaction(tx_group)
+                    })
+                })
+                .then(move |res| {
+                    drop(in_flight);
+                    res
+                }),
+        )
+    }
+
+    fn create_batch_transaction(
+        &self,
+        token: AuthenticationToken,
+        input: CreateBatchTransactionInput,
+    ) -> Box<Future<Item = TransactionOut, Error = Error> + Send> {
         let db_executor = self.db_executor.clone();
         let self_clone = self.clone();
-        Box::new(self.auth_service.authenticate(token).and_then(move |user| {
+        let self_clone2 = self.clone();
+        let self_clone3 = self.clone();
+        let in_flight = match self.enter_in_flight() {
+            Ok(in_flight) => in_flight,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        Box::new(
+            self.auth_service
+                .authenticate(token)
+                .and_then(move |user| {
+                    let input = CreateBatchTransactionInput { user_id: user.id, ..input };
+                    self_clone
+                        .check_rate_limit(user.id)
+                        .into_future()
+                        .and_then(move |_| {
+                            db_executor.execute(move || {
+                                self_clone.check_new_transaction_id(input.id)?;
+                                self_clone.check_batch_currency(input.currency)?;
+                                let (from_account, total) = self_clone.validate_batch_transaction(&input)?;
+                                Ok((input, from_account, total))
+                            })
+                        })
+                        .and_then(move |(input, from_account, total)| self_clone2.create_batch_external_tx(input, from_account, total))
+                })
+                .and_then(move |tx_group| {
+                    let db_executor = self_clone3.db_executor.clone();
+                    db_executor.execute_transaction_with_isolation(Isolation::RepeatableRead, move || {
+                        self_clone3.converter_service.convert_transaction(tx_group)
+                    })
+                })
+                .then(move |res| {
+                    drop(in_flight);
+                    res
+                }),
+        )
+    }
+
+    fn create_split_transaction(
+        &self,
+        token: AuthenticationToken,
+        input: CreateBatchTransactionInput,
+    ) -> Box<Future<Item = TransactionOut, Error = Error> + Send> {
+        let db_executor = self.db_executor.clone();
+        let self_clone = self.clone();
+        let self_clone2 = self.clone();
+        let self_clone3 = self.clone();
+        let in_flight = match self.enter_in_flight() {
+            Ok(in_flight) => in_flight,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        Box::new(
+            self.auth_service
+                .authenticate(token)
+                .and_then(move |user| {
+                    let input = CreateBatchTransactionInput { user_id: user.id, ..input };
+                    self_clone
+                        .check_rate_limit(user.id)
+                        .into_future()
+                        .and_then(move |_| {
+                            db_executor.execute(move || {
+                                self_clone.check_new_transaction_id(input.id)?;
+                                let (from_account, total) = self_clone.validate_batch_transaction(&input)?;
+                                Ok((input, from_account, total))
+                            })
+                        })
+                        .and_then(move |(input, from_account, total)| self_clone2.create_split_external_tx(input, from_account, total))
+                })
+                .and_then(move |tx_group| {
+                    let db_executor = self_clone3.db_executor.clone();
+                    db_executor.execute_transaction_with_isolation(Isolation::RepeatableRead, move || {
+                        self_clone3.converter_service.convert_transaction(tx_group)
+                    })
+                })
+                .then(move |res| {
+                    drop(in_flight);
+                    res
+                }),
+        )
+    }
+
+    fn get_transaction(
+        &self,
+        token: AuthenticationToken,
+        transaction_id: TransactionId,
+    ) -> Box<Future<Item = Option<TransactionOut>, Error = Error> + Send> {
+        let transactions_repo = self.transactions_repo.clone();
+        let db_executor = self.db_executor.clone();
+        let self_clone = self.clone();
+        Box::new(self.auth_service.authenticate_with_role(token).and_then(move |(user, role)| {
             db_executor.execute(move || {
                 let transaction = transactions_repo
                     .get(transaction_id)
                     .map_err(ectx!(try convert => transaction_id))?;
-                if let Some(ref transaction) = transaction {
-                    if transaction.user_id != user.id {
-                        return Err(ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => user.id));
-                    }
-                    let tx_group = transactions_repo
-                        .get_by_gid(transaction.gid)
-                        .map_err(ectx!(try convert => transaction_id))?;
-                    let tx_out = self_clone.converter_service.convert_transaction(tx_group)?;
-                    return Ok(Some(tx_out));
+                // either a leg id or a gid returned as `TransactionOut::id`
+                let gid = transaction.map(|transaction| transaction.gid).unwrap_or(transaction_id);
+                let tx_group = transactions_repo.get_by_gid(gid).map_err(ectx!(try convert => transaction_id))?;
+                let owner_id = match tx_group.first() {
+                    Some(transaction) => transaction.user_id,
+                    None => return Ok(None),
+                };
+                // operators with the system token can inspect transactions of any user
+                let is_system = match role {
+                    Role::System => true,
+                    Role::User => false,
+                };
+                if !is_system && owner_id != user.id {
+                    return Err(ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => user.id));
                 }
-                Ok(None)
+                let mut tx_out = self_clone.converter_service.convert_transaction(tx_group)?;
+                // the note is private to the user, operators don't see it
+                if owner_id != user.id {
+                    tx_out.user_note = None;
+                }
+                Ok(Some(tx_out))
             })
         }))
     }
-    fn get_account_balance(
+    fn get_transaction_legs(
         &self,
         token: AuthenticationToken,
-        account_id: AccountId,
-    ) -> Box<Future<Item = AccountWithBalance, Error = Error> + Send> {
+        gid: TransactionId,
+    ) -> Box<Future<Item = Vec<Transaction>, Error = Error> + Send> {
         let transactions_repo = self.transactions_repo.clone();
-        let accounts_repo = self.accounts_repo.clone();
         let db_executor = self.db_executor.clone();
         Box::new(self.auth_service.authenticate(token).and_then(move |user| {
-            db_executor.execute(move || -> Result<AccountWithBalance, Error> {
-                let account = accounts_repo.get(account_id).map_err(ectx!(try convert => account_id))?;
-                if let Some(account) = account {
-                    if account.user_id != user.id {
-                        return Err(ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => user.id));
-                    }
-                    transactions_repo
-                        .get_accounts_balance(user.id, &[account])
-                        .map(|accounts| accounts[0].clone())
-                        .map_err(ectx!(convert => account_id))
-                } else {
-                    return Err(ectx!(err ErrorContext::NoAccount, ErrorKind::NotFound => account_id));
+            db_executor.execute(move || {
+                let legs = transactions_repo.get_by_gid(gid).map_err(ectx!(try convert => gid))?;
+                if legs.is_empty() {
+                    return Err(ectx!(err ErrorContext::NoTransaction, ErrorKind::NotFound => gid));
                 }
+                if legs.iter().any(|leg| leg.user_id != user.id) {
+                    return Err(ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => user.id, gid));
+                }
+                Ok(legs)
             })
         }))
     }
-    fn get_transactions_for_user(
+    fn get_transaction_status_history(
         &self,
         token: AuthenticationToken,
-        user_id: UserId,
+        transaction_id: TransactionId,
+    ) -> Box<Future<Item = Vec<StatusChange>, Error = Error> + Send> {
+        let transactions_repo = self.transactions_repo.clone();
+        let db_executor = self.db_executor.clone();
+        Box::new(self.auth_service.authenticate(token).and_then(move |user| {
+            db_executor.execute(move || {
+                // transaction id in api is the id of the group
+                let legs = transactions_repo
+                    .get_by_gid(transaction_id)
+                    .map_err(ectx!(try convert => transaction_id))?;
+                if legs.is_empty() {
+                    return Err(ectx!(err ErrorContext::NoTransaction, ErrorKind::NotFound => transaction_id));
+                }
+                if legs.iter().any(|leg| leg.user_id != user.id) {
+                    return Err(ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => user.id, transaction_id));
+                }
+                let leg_ids: Vec<_> = legs.iter().map(|leg| leg.id).collect();
+                transactions_repo
+                    .get_status_history(&leg_ids)
+                    .map_err(ectx!(convert => transaction_id))
+            })
+        }))
+    }
+    fn get_related_transactions(
+        &self,
+        token: AuthenticationToken,
+        transaction_id: TransactionId,
+    ) -> Box<Future<Item = Vec<Transaction>, Error = Error> + Send> {
+        let transactions_repo = self.transactions_repo.clone();
+        let db_executor = self.db_executor.clone();
+        Box::new(self.auth_service.authenticate(token).and_then(move |user| {
+            db_executor.execute(move || {
+                let transaction = transactions_repo
+                    .get(transaction_id)
+                    .map_err(ectx!(try convert => transaction_id))?
+                    .ok_or(ectx!(try err ErrorContext::NoTransaction, ErrorKind::NotFound => transaction_id))?;
+                if transaction.user_id != user.id {
+                    return Err(ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => user.id, transaction_id));
+                }
+                let related = transactions_repo
+                    .get_by_related_tx(transaction_id)
+                    .map_err(ectx!(try convert => transaction_id))?;
+                Ok(related.into_iter().filter(|tx| tx.user_id == user.id).collect())
+            })
+        }))
+    }
+    fn find_transactions_to_address(
+        &self,
+        system_token: AuthenticationToken,
+        address: BlockchainAddress,
+        currency: Currency,
         offset: i64,
         limit: i64,
-    ) -> Box<Future<Item = Vec<TransactionOut>, Error = Error> + Send> {
+    ) -> Box<Future<Item = Vec<Transaction>, Error = Error> + Send> {
         let transactions_repo = self.transactions_repo.clone();
+        let pending_transactions_repo = self.pending_transactions_repo.clone();
+        let blockchain_transactions_repo = self.blockchain_transactions_repo.clone();
         let db_executor = self.db_executor.clone();
+        let system_user_id = self.config.system.system_user_id;
+        // withdrawal addresses are stored normalized
+        let address = address.normalized(currency);
+        Box::new(self.auth_service.authenticate(system_token).and_then(move |user| {
+            db_executor.execute(move || -> Result<Vec<Transaction>, Error> {
+                if user.id != system_user_id {
+                    return Err(ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => user.id));
+                }
+                // destination is not stored on transactions, so it is resolved through blockchain transactions,
+                // both not yet seen in blockchain and already seen
+                let mut hashes = Vec::new();
+                let pending = pending_transactions_repo
+                    .list_by_destination(address.clone(), currency)
+                    .map_err(ectx!(try convert => address, currency))?;
+                hashes.extend(pending.into_iter().map(|tx| tx.hash));
+                let seen = blockchain_transactions_repo
+                    .list_by_destination(address.clone(), currency)
+                    .map_err(ectx!(try convert => address, currency))?;
+                hashes.extend(seen.into_iter().map(|tx| tx.hash));
+                if hashes.is_empty() {
+                    return Ok(Vec::new());
+                }
+                transactions_repo
+                    .list_withdrawals_by_blockchain_txs(&hashes, offset, limit)
+                    .map_err(ectx!(convert => address, currency, offset, limit))
+            })
+        }))
+    }
+    fn get_transactions_by_group_kind(
+        &self,
+        system_token: AuthenticationToken,
+        group_kind: TransactionGroupKind,
+        offset: i64,
+        limit: i64,
+    ) -> Box<Future<Item = Vec<TransactionOut>, Error = Error> + Send> {
+        let transactions_repo = self.transactions_repo.clone();
+        let db_executor = self.read_db_executor.clone();
+        let system_user_id = self.config.system.system_user_id;
         let self_clone = self.clone();
-        Box::new(self.auth_service.authenticate(token).and_then(move |user| {
+        Box::new(self.auth_service.authenticate(system_token).and_then(move |user| {
             db_executor.execute(move || -> Result<Vec<TransactionOut>, Error> {
-                if user_id != user.id {
+                if user.id != system_user_id {
                     return Err(ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => user.id));
                 }
                 let txs = transactions_repo
-                    .list_groups_for_user_skip_approval(user_id, offset, limit)
-                    .map_err(ectx!(try convert => user_id, offset, limit))?;
-                let res: Result<Vec<TransactionOut>, Error> = group_transactions(&txs)
+                    .list_by_group_kind(group_kind, offset, limit)
+                    .map_err(ectx!(try convert => group_kind, offset, limit))?;
+                // approval groups are internal, so they are skipped by the converter
+                let res: Result<Vec<Option<TransactionOut>>, Error> = group_transactions(&txs)
+                    .into_iter()
+                    .map(|tx_group| self_clone.converter_service.convert_listed_transaction(tx_group))
+                    .collect();
+                let mut res: Vec<TransactionOut> = res?
                     .into_iter()
-                    .map(|tx_group| self_clone.converter_service.convert_transaction(tx_group))
+                    .filter_map(|tx| tx)
+                    .map(|tx| hide_foreign_user_note(tx, user.id))
                     .collect();
-                let mut res = res?;
                 res.sort_by_key(|tx| tx.created_at);
                 let res: Vec<_> = res.into_iter().rev().collect();
                 Ok(res)
             })
         }))
     }
-    fn get_account_transactions(
+    fn get_raw_transactions(
+        &self,
+        system_token: AuthenticationToken,
+        transaction_id: TransactionId,
+    ) -> Box<Future<Item = Vec<SignedBlockchainTransaction>, Error = Error> + Send> {
+        let transactions_repo = self.transactions_repo.clone();
+        let pending_transactions_repo = self.pending_transactions_repo.clone();
+        let db_executor = self.db_executor.clone();
+        let system_user_id = self.config.system.system_user_id;
+        Box::new(self.auth_service.authenticate(system_token).and_then(move |user| {
+            db_executor.execute(move || -> Result<Vec<SignedBlockchainTransaction>, Error> {
+                if user.id != system_user_id {
+                    return Err(ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => user.id));
+                }
+                let transaction = transactions_repo
+                    .get(transaction_id)
+                    .map_err(ectx!(try convert => transaction_id))?
+                    .ok_or(ectx!(try err ErrorContext::NoTransaction, ErrorKind::NotFound => transaction_id))?;
+                let tx_group = transactions_repo
+                    .get_by_gid(transaction.gid)
+                    .map_err(ectx!(try convert => transaction.gid))?;
+                let mut hashes: Vec<_> = tx_group
+                    .into_iter()
+                    .filter(|tx| tx.kind == TransactionKind::Withdrawal)
+                    .flat_map(|tx| tx.blockchain_tx_id.into_iter())
+                    .collect();
+                // legs of btc batch withdrawal share the same blockchain tx
+                hashes.dedup();
+                let mut result = Vec::new();
+                for hash in hashes {
+                    // pending tx is deleted once it's seen in blockchain, raw tx goes with it
+                    let raw_tx = pending_transactions_repo
+                        .get(hash.clone())
+                        .map_err(ectx!(try convert => hash))?
+                        .and_then(|pending| pending.raw_tx);
+                    result.push(SignedBlockchainTransaction { hash, raw_tx });
+                }
+                Ok(result)
+            })
+        }))
+    }
+    fn preview_coin_selection(
+        &self,
+        system_token: AuthenticationToken,
+        currency: Currency,
+        value: Amount,
+    ) -> Box<Future<Item = Vec<AccountWithBalance>, Error = Error> + Send> {
+        if value == Amount::new(0) {
+            return Box::new(future::err(
+                ectx!(err ErrorContext::InvalidValue, ErrorKind::MalformedInput => currency, value),
+            ));
+        }
+        let db_executor = self.db_executor.clone();
+        let blockchain_service = self.blockchain_service.clone();
+        let system_user_id = self.config.system.system_user_id;
+        let self_clone = self.clone();
+        Box::new(
+            self.auth_service
+                .authenticate(system_token)
+                .and_then(move |user| {
+                    if user.id != system_user_id {
+                        return Err(ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => user.id));
+                    }
+                    Ok(())
+                })
+                .and_then(move |_| blockchain_service.estimate_default_withdrawal_fee(currency, currency))
+                .and_then(move |total_fee| db_executor.execute(move || self_clone.select_withdrawal_sources(value, currency, total_fee))),
+        )
+    }
+    fn cancel_scheduled_transaction(
         &self,
         token: AuthenticationToken,
-        account_id: AccountId,
-        offset: i64,
-        limit: i64,
-    ) -> Box<Future<Item = Vec<TransactionOut>, Error = Error> + Send> {
+        transaction_id: TransactionId,
+    ) -> Box<Future<Item = TransactionOut, Error = Error> + Send> {
         let transactions_repo = self.transactions_repo.clone();
-        let accounts_repo = self.accounts_repo.clone();
         let db_executor = self.db_executor.clone();
         let self_clone = self.clone();
         Box::new(self.auth_service.authenticate(token).and_then(move |user| {
-            db_executor.execute(move || {
+            // serializable, so that concurrent `process_due_transactions` either sees
+            // the cancelled status or makes this transaction fail
+            db_executor.execute_transaction_with_isolation(Isolation::Serializable, move || {
+                let transaction = transactions_repo
+                    .get(transaction_id)
+                    .map_err(ectx!(try convert => transaction_id))?
+                    .ok_or(ectx!(try err ErrorContext::NoTransaction, ErrorKind::NotFound => transaction_id))?;
+                if transaction.user_id != user.id {
+                    return Err(ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => user.id, transaction_id));
+                }
+                if transaction.status != TransactionStatus::Scheduled {
+                    let mut errors = ValidationErrors::new();
+                    let mut error = ValidationError::new("not_scheduled");
+                    error.message = Some("Only scheduled transactions can be cancelled".into());
+                    error.add_param("status".into(), &transaction.status);
+                    errors.add("status", error);
+                    return Err(
+                        ectx!(err ErrorContext::NotScheduled, ErrorKind::InvalidInput(serde_json::to_string(&errors).unwrap_or_default()) => transaction_id),
+                    );
+                }
+                let transaction = transactions_repo
+                    .update_status_by_id(transaction_id, TransactionStatus::Cancelled)
+                    .map_err(ectx!(try convert => transaction_id))?;
+                self_clone.converter_service.convert_transaction(vec![transaction])
+            })
+        }))
+    }
+    fn get_account_balance(
+        &self,
+        token: AuthenticationToken,
+        account_id: AccountId,
+        strict: bool,
+    ) -> Box<Future<Item = AccountWithBalance, Error = Error> + Send> {
+        let transactions_repo = self.transactions_repo.clone();
+        let accounts_repo = self.accounts_repo.clone();
+        let db_executor = if strict {
+            self.db_executor.clone()
+        } else {
+            self.read_db_executor.clone()
+        };
+        Box::new(self.auth_service.authenticate(token).and_then(move |user| {
+            db_executor.execute(move || -> Result<AccountWithBalance, Error> {
                 let account = accounts_repo.get(account_id).map_err(ectx!(try convert => account_id))?;
-                if let Some(ref account) = account {
+                if let Some(account) = account {
                     if account.user_id != user.id {
                         return Err(ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => user.id));
                     }
+                    transactions_repo
+                        .get_accounts_balance(user.id, &[account])
+                        .map(|accounts| accounts[0].clone())
+                        .map_err(ectx!(convert => account_id))
                 } else {
                     return Err(ectx!(err ErrorContext::NoAccount, ErrorKind::NotFound => account_id));
                 }
-                let txs = transactions_repo
-                    .list_groups_for_account_skip_approval(account_id, offset, limit)
-                    .map_err(ectx!(try convert => account_id))?;
-                let res: Result<Vec<TransactionOut>, Error> = group_transactions(&txs)
-                    .into_iter()
-                    .map(|tx_group| self_clone.converter_service.convert_transaction(tx_group))
-                    .collect();
-                let mut res = res?;
-                res.sort_by_key(|tx| tx.created_at);
-                let res: Vec<_> = res.into_iter().rev().collect();
-                Ok(res)
             })
         }))
     }
-}
-
-// group transactions into subgroups of related txs. I.e. group tx itself + fee
-fn group_transactions(transactions: &[Transaction]) -> Vec<Vec<Transaction>> {
-    let mut res: HashMap<TransactionId, Vec<Transaction>> = HashMap::new();
-    for tx in transactions.into_iter() {
-        res.entry(tx.gid).and_modify(|txs| txs.push(tx.clone())).or_insert(vec![tx.clone()]);
+    fn get_account_balance_at(
+        &self,
+        token: AuthenticationToken,
+        account_id: AccountId,
+        as_of: NaiveDateTime,
+    ) -> Box<Future<Item = AccountWithBalance, Error = Error> + Send> {
+        let transactions_repo = self.transactions_repo.clone();
+        let accounts_repo = self.accounts_repo.clone();
+        let db_executor = self.read_db_executor.clone();
+        Box::new(self.auth_service.authenticate(token).and_then(move |user| {
+            db_executor.execute(move || -> Result<AccountWithBalance, Error> {
+                let account = accounts_repo
+                    .get(account_id)
+                    .map_err(ectx!(try convert => account_id))?
+                    .ok_or(ectx!(try err ErrorContext::NoAccount, ErrorKind::NotFound => account_id))?;
+                if account.user_id != user.id {
+                    return Err(ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => user.id));
+                }
+                let balance = transactions_repo
+                    .balance_at(account.id, account.kind, as_of)
+                    .map_err(ectx!(try convert => account_id, as_of))?;
+                Ok(AccountWithBalance { account, balance })
+            })
+        }))
     }
-    res.into_iter().map(|(_, txs)| txs).collect()
-}
-
-#[cfg(test)]
-#[allow(unused)]
-mod tests {
-    use super::*;
-    use client::*;
-    use config::Config;
-    use rabbit::*;
-    use repos::*;
-    use services::*;
-    use tokio_core::reactor::Core;
-
-    fn create_transaction_service(token: AuthenticationToken, user_id: UserId) -> TransactionsServiceImpl<DbExecutorMock> {
-        let config = Config::new().unwrap();
-        let auth_service = Arc::new(AuthServiceMock::new(vec![(token, user_id)]));
-        let accounts_repo = Arc::new(AccountsRepoMock::default());
-        let transactions_repo = Arc::new(TransactionsRepoMock::default());
-        let pending_transactions_repo = Arc::new(PendingBlockchainTransactionsRepoMock::default());
-        let blockchain_transactions_repo = Arc::new(BlockchainTransactionsRepoMock::default());
-        let key_values_repo = Arc::new(KeyValuesRepoMock::default());
-        let keys_client = Arc::new(KeysClientMock::default());
-        let blockchain_client = Arc::new(BlockchainClientMock::default());
-        let exchange_client = Arc::new(ExchangeClientMock::default());
-        let db_executor = DbExecutorMock::default();
-        let publisher = Arc::new(TransactionPublisherMock::default());
-        TransactionsServiceImpl::new(
-            config,
-            auth_service,
-            transactions_repo,
-            pending_transactions_repo,
-            blockchain_transactions_repo,
-            accounts_repo,
-            key_values_repo,
-            db_executor,
-            keys_client,
-            blockchain_client,
+    fn get_resolver_enabled(&self, system_token: AuthenticationToken) -> Box<Future<Item = bool, Error = Error> + Send> {
+        let key_values_repo = self.key_values_repo.clone();
+        let db_executor = self.db_executor.clone();
+        let system_user_id = self.config.system.system_user_id;
+        Box::new(self.auth_service.authenticate(system_token).and_then(move |user| {
+            db_executor.execute(move || -> Result<bool, Error> {
+                if user.id != system_user_id {
+                    return Err(ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => user.id));
+                }
+                key_values_repo.get_resolver_enabled().map_err(ectx!(convert))
+            })
+        }))
+    }
+    fn set_resolver_enabled(&self, system_token: AuthenticationToken, enabled: bool) -> Box<Future<Item = bool, Error = Error> + Send> {
+        let key_values_repo = self.key_values_repo.clone();
+        let db_executor = self.db_executor.clone();
+        let system_user_id = self.config.system.system_user_id;
+        Box::new(self.auth_service.authenticate(system_token).and_then(move |user| {
+            db_executor.execute(move || -> Result<bool, Error> {
+                if user.id != system_user_id {
+                    return Err(ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => user.id));
+                }
+                warn!(
+                    "Pending transactions resolver is {} by the system user",
+                    if enabled { "resumed" } else { "paused" }
+                );
+                key_values_repo.set_resolver_enabled(enabled).map_err(ectx!(convert => enabled))
+            })
+        }))
+    }
+    fn recompute_account_balance(
+        &self,
+        system_token: AuthenticationToken,
+        account_id: AccountId,
+    ) -> Box<Future<Item = AccountWithBalance, Error = Error> + Send> {
+        let accounts_repo = self.accounts_repo.clone();
+        let system_service = self.system_service.clone();
+        let db_executor = self.db_executor.clone();
+        let system_user_id = self.config.system.system_user_id;
+        Box::new(self.auth_service.authenticate(system_token).and_then(move |user| {
+            db_executor.execute(move || -> Result<AccountWithBalance, Error> {
+                if user.id != system_user_id {
+                    return Err(ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => user.id));
+                }
+                let account = accounts_repo
+                    .get(account_id)
+                    .map_err(ectx!(try convert => account_id))?
+                    .ok_or(ectx!(try err ErrorContext::NoAccount, ErrorKind::NotFound => account_id))?;
+                let balance = system_service.recompute_account_balance(account_id)?;
+                Ok(AccountWithBalance { account, balance })
+            })
+        }))
+    }
+    fn manual_deposit(
+        &self,
+        system_token: AuthenticationToken,
+        account_id: AccountId,
+        currency: Currency,
+        value: Amount,
+        blockchain_tx_id: BlockchainTransactionId,
+    ) -> Box<Future<Item = TransactionOut, Error = Error> + Send> {
+        let accounts_repo = self.accounts_repo.clone();
+        let transactions_repo = self.transactions_repo.clone();
+        let blockchain_transactions_repo = self.blockchain_transactions_repo.clone();
+        let seen_hashes_repo = self.seen_hashes_repo.clone();
+        let strange_blockchain_transactions_repo = self.strange_blockchain_transactions_repo.clone();
+        let blockchain_client = self.blockchain_client.clone();
+        let converter_service = self.converter_service.clone();
+        let db_executor = self.db_executor.clone();
+        let system_user_id = self.config.system.system_user_id;
+        Box::new(self.auth_service.authenticate(system_token).and_then(move |user| {
+            if user.id != system_user_id {
+                return Either::A(future::err(ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => user.id)));
+            }
+            let blockchain_tx_id_clone = blockchain_tx_id.clone();
+            // checking the chain first, so that a phantom deposit is never credited
+            Either::B(
+                blockchain_client
+                    .get_transaction(blockchain_tx_id.clone(), currency)
+                    .map_err(ectx!(convert => blockchain_tx_id_clone, currency))
+                    .and_then(move |blockchain_tx| {
+                        // the asset reported by blockchain must be the requested one, e.g. not an erc20 transfer
+                        // on a shared ethereum address; the mismatch is kept for audit outside of the deposit transaction
+                        if let Some(mismatched_tx) = blockchain_tx.clone().filter(|tx| tx.currency != currency) {
+                            return db_executor.execute(move || -> Result<TransactionOut, Error> {
+                                let hash = mismatched_tx.hash.clone();
+                                let strange_tx = strange_blockchain_transactions_repo
+                                    .get(hash.clone())
+                                    .map_err(ectx!(try convert => hash))?;
+                                if strange_tx.is_none() {
+                                    let message = format!(
+                                        "Manual deposit currency mismatch: {} is requested, {} is reported by blockchain",
+                                        currency, mismatched_tx.currency
+                                    );
+                                    let new_strange_tx = (mismatched_tx.clone(), message).into();
+                                    strange_blockchain_transactions_repo
+                                        .create(new_strange_tx)
+                                        .map_err(ectx!(try convert => hash))?;
+                                }
+                                let kind = invalid_blockchain_tx_error("currency_mismatch", "Blockchain transaction transfers another currency");
+                                Err(ectx!(err ErrorContext::DepositCurrencyMismatch, kind => hash, currency, mismatched_tx.currency))
+                            });
+                        }
+                        db_executor.execute_transaction_with_isolation(Isolation::Serializable, move || -> Result<TransactionOut, Error> {
+                            let blockchain_tx = blockchain_tx
+                                .ok_or_else(|| {
+                                    let kind = invalid_blockchain_tx_error("not_found", "Blockchain transaction is not found");
+                                    ectx!(try err ErrorContext::NoTransaction, kind => blockchain_tx_id, currency)
+                                })?
+                                .with_normalized_addresses();
+                            let account = accounts_repo
+                                .get(account_id)
+                                .map_err(ectx!(try convert => account_id))?
+                                .ok_or(ectx!(try err ErrorContext::NoAccount, ErrorKind::NotFound => account_id))?;
+                            if account.kind != AccountKind::Cr || account.currency != currency {
+                                return Err(ectx!(err ErrorContext::InvalidCurrency, ErrorKind::MalformedInput => account, currency));
+                            }
+                            // processed by blockchain notifications or already imported
+                            let seen = seen_hashes_repo
+                                .get(blockchain_tx_id.clone(), currency)
+                                .map_err(ectx!(try convert => blockchain_tx_id, currency))?;
+                            let existing = transactions_repo
+                                .get_by_blockchain_tx(blockchain_tx_id.clone())
+                                .map_err(ectx!(try convert => blockchain_tx_id))?;
+                            if seen.is_some() || existing.is_some() {
+                                let kind = invalid_blockchain_tx_error("already_processed", "Blockchain transaction is already processed");
+                                return Err(ectx!(err ErrorContext::DepositAlreadyProcessed, kind => blockchain_tx_id, currency));
+                            }
+                            // failed eth / stq transaction still lists its recipients, but they received nothing
+                            if blockchain_tx.reverted {
+                                let kind = invalid_blockchain_tx_error("reverted", "Blockchain transaction was reverted");
+                                return Err(ectx!(err ErrorContext::InvalidBlockchainTransactionStructure, kind => blockchain_tx_id, currency));
+                            }
+                            let normalized_tx = blockchain_tx
+                                .normalized()
+                                .ok_or(ectx!(try err ErrorContext::BalanceOverflow, ErrorKind::Internal => blockchain_tx))?;
+                            // the same threshold as for transactions coming from the gateway
+                            let total_tx_value = normalized_tx
+                                .value()
+                                .ok_or(ectx!(try err ErrorContext::BalanceOverflow, ErrorKind::Internal => normalized_tx))?;
+                            let required = required_confirmations(currency, total_tx_value);
+                            if (normalized_tx.confirmations as u64) < required {
+                                let kind = invalid_blockchain_tx_error("not_confirmed", "Blockchain transaction doesn't have enough confirmations yet");
+                                return Err(
+                                    ectx!(err ErrorContext::InvalidBlockchainTransactionStructure, kind => blockchain_tx_id, normalized_tx.confirmations, required),
+                                );
+                            }
+                            let paid = normalized_tx
+                                .to
+                                .iter()
+                                .find(|entry| entry.address == account.address)
+                                .map(|entry| entry.value)
+                                .unwrap_or_default();
+                            if paid < value {
+                                let kind = invalid_blockchain_tx_error("value_mismatch", "Blockchain transaction doesn't pay the value to the account");
+                                return Err(ectx!(err ErrorContext::InvalidBlockchainTransactionStructure, kind => blockchain_tx_id, account.address, value, paid));
+                            }
+                            let dr_account = accounts_repo
+                                .get_by_address(account.address.clone(), currency, AccountKind::Dr)
+                                .map_err(ectx!(try convert => account.address, currency))?
+                                .ok_or(ectx!(try err ErrorContext::NoAccount, ErrorKind::Internal => account.address, currency))?;
+                            let tx_id = TransactionId::generate();
+                            let new_tx = NewTransaction {
+                                id: tx_id,
+                                gid: tx_id,
+                                user_id: account.user_id,
+                                dr_account_id: dr_account.id,
+                                cr_account_id: account.id,
+                                currency,
+                                value,
+                                status: TransactionStatus::Done,
+                                blockchain_tx_id: Some(blockchain_tx_id.clone()),
+                                kind: TransactionKind::Deposit,
+                                group_kind: TransactionGroupKind::Deposit,
+                                related_tx: None,
+                                meta: None,
+                                hold_until: None,
+                                fee_price: None,
+                                gross_fee: None,
+                                user_note: None,
+                                category: None,
+                            };
+                            let transaction = transactions_repo.create(new_tx.clone()).map_err(ectx!(try convert => new_tx))?;
+                            blockchain_transactions_repo
+                                .upsert(blockchain_tx.clone().into())
+                                .map_err(ectx!(try convert => blockchain_tx_id))?;
+                            seen_hashes_repo
+                                .create(blockchain_tx.into())
+                                .map_err(ectx!(try convert => blockchain_tx_id))?;
+                            info!("Deposit {} is imported manually from blockchain transaction {}", tx_id, blockchain_tx_id);
+                            converter_service.convert_transaction(vec![transaction])
+                        })
+                    }),
+            )
+        }))
+    }
+
+    fn max_withdrawable(
+        &self,
+        token: AuthenticationToken,
+        account_id: AccountId,
+        to_currency: Currency,
+    ) -> Box<Future<Item = Amount, Error = Error> + Send> {
+        let transactions_repo = self.transactions_repo.clone();
+        let accounts_repo = self.accounts_repo.clone();
+        let db_executor = self.db_executor.clone();
+        let blockchain_service = self.blockchain_service.clone();
+        Box::new(
+            self.auth_service
+                .authenticate(token)
+                .and_then(move |user| {
+                    db_executor.execute(move || -> Result<AccountWithBalance, Error> {
+                        let account = accounts_repo
+                            .get(account_id)
+                            .map_err(ectx!(try convert => account_id))?
+                            .ok_or(ectx!(try err ErrorContext::NoAccount, ErrorKind::NotFound => account_id))?;
+                        if account.user_id != user.id {
+                            return Err(ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => user.id, account_id));
+                        }
+                        // withdrawals with exchange are not supported yet
+                        if account.currency != to_currency {
+                            return Err(ectx!(err ErrorContext::NotSupported, ErrorKind::MalformedInput => account_id, to_currency));
+                        }
+                        transactions_repo
+                            .get_accounts_balance(user.id, &[account])
+                            .map(|mut accounts| accounts.remove(0))
+                            .map_err(ectx!(convert => account_id))
+                    })
+                })
+                .and_then(move |AccountWithBalance { account, balance }| {
+                    // the fee is written off the withdrawn account, for stq it is converted from the eth network fee
+                    blockchain_service
+                        .estimate_default_withdrawal_fee(account.currency, to_currency)
+                        .map(move |fee| balance.checked_sub(fee).unwrap_or_default())
+                }),
+        )
+    }
+    fn get_user_summary(
+        &self,
+        token: AuthenticationToken,
+        user_id: UserId,
+    ) -> Box<Future<Item = Vec<CurrencyBalance>, Error = Error> + Send> {
+        let transactions_repo = self.transactions_repo.clone();
+        let accounts_repo = self.accounts_repo.clone();
+        let db_executor = self.db_executor.clone();
+        Box::new(self.auth_service.authenticate(token).and_then(move |user| {
+            db_executor.execute(move || -> Result<Vec<CurrencyBalance>, Error> {
+                if user_id != user.id {
+                    return Err(ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => user.id, user_id));
+                }
+                let accounts = accounts_repo
+                    .list_for_user(user_id, 0, i64::max_value())
+                    .map_err(ectx!(try convert => user_id))?;
+                let balances = transactions_repo
+                    .get_accounts_balance(user_id, &accounts)
+                    .map_err(ectx!(try convert => user_id))?;
+                let mut summary: Vec<CurrencyBalance> = Vec::new();
+                for AccountWithBalance { account, balance } in balances {
+                    let position = summary.iter().position(|total| total.currency == account.currency);
+                    match position {
+                        Some(i) => {
+                            let total = &mut summary[i];
+                            total.balance = total
+                                .balance
+                                .try_add(balance)
+                                .map_err(ectx!(try ErrorContext::BalanceOverflow, ErrorKind::Internal => user_id))?;
+                            total.accounts_count += 1;
+                        }
+                        None => summary.push(CurrencyBalance {
+                            currency: account.currency,
+                            balance,
+                            accounts_count: 1,
+                        }),
+                    }
+                }
+                Ok(summary)
+            })
+        }))
+    }
+    fn list_accounts_with_balances(
+        &self,
+        token: AuthenticationToken,
+        user_id: UserId,
+        offset: i64,
+        limit: i64,
+    ) -> Box<Future<Item = Vec<AccountWithBalance>, Error = Error> + Send> {
+        let transactions_repo = self.transactions_repo.clone();
+        let accounts_repo = self.accounts_repo.clone();
+        let db_executor = self.db_executor.clone();
+        Box::new(self.auth_service.authenticate(token).and_then(move |user| {
+            db_executor.execute(move || -> Result<Vec<AccountWithBalance>, Error> {
+                if user_id != user.id {
+                    return Err(ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => user.id, user_id));
+                }
+                let accounts = accounts_repo
+                    .list_for_user(user_id, offset, limit)
+                    .map_err(ectx!(try convert => user_id, offset, limit))?;
+                transactions_repo
+                    .get_accounts_balance(user_id, &accounts)
+                    .map_err(ectx!(convert => user_id))
+            })
+        }))
+    }
+    fn get_transactions_for_user(
+        &self,
+        token: AuthenticationToken,
+        user_id: UserId,
+        category: Option<String>,
+        offset: i64,
+        limit: i64,
+    ) -> Box<Future<Item = Vec<TransactionOut>, Error = Error> + Send> {
+        let transactions_repo = self.transactions_repo.clone();
+        let db_executor = self.read_db_executor.clone();
+        let self_clone = self.clone();
+        Box::new(self.auth_service.authenticate(token).and_then(move |user| {
+            db_executor.execute(move || -> Result<Vec<TransactionOut>, Error> {
+                if user_id != user.id {
+                    return Err(ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => user.id));
+                }
+                let txs = transactions_repo
+                    .list_groups_for_user_skip_approval(user_id, category.clone(), offset, limit)
+                    .map_err(ectx!(try convert => user_id, category, offset, limit))?;
+                let res: Result<Vec<Option<TransactionOut>>, Error> = group_transactions(&txs)
+                    .into_iter()
+                    .map(|tx_group| self_clone.converter_service.convert_listed_transaction(tx_group))
+                    .collect();
+                let mut res: Vec<TransactionOut> = res?
+                    .into_iter()
+                    .filter_map(|tx| tx)
+                    .map(|tx| hide_foreign_user_note(tx, user.id))
+                    .collect();
+                res.sort_by_key(|tx| tx.created_at);
+                let res: Vec<_> = res.into_iter().rev().collect();
+                Ok(res)
+            })
+        }))
+    }
+    fn get_transactions_for_user_after(
+        &self,
+        token: AuthenticationToken,
+        user_id: UserId,
+        after: Option<TransactionsCursor>,
+        limit: i64,
+    ) -> Box<Future<Item = (Vec<TransactionOut>, Option<TransactionsCursor>), Error = Error> + Send> {
+        let transactions_repo = self.transactions_repo.clone();
+        let db_executor = self.read_db_executor.clone();
+        let self_clone = self.clone();
+        Box::new(self.auth_service.authenticate(token).and_then(move |user| {
+            db_executor.execute(move || -> Result<(Vec<TransactionOut>, Option<TransactionsCursor>), Error> {
+                if user_id != user.id {
+                    return Err(ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => user.id));
+                }
+                let txs = transactions_repo
+                    .list_groups_for_user_after(user_id, after, limit)
+                    .map_err(ectx!(try convert => user_id, after, limit))?;
+                let groups = group_transactions(&txs);
+                // the next page starts after the oldest group of this one
+                let next = if groups.len() as i64 == limit {
+                    groups
+                        .iter()
+                        .filter_map(|tx_group| group_cursor(tx_group))
+                        .min_by_key(|cursor| (cursor.created_at, *cursor.gid.inner()))
+                } else {
+                    None
+                };
+                let res: Result<Vec<Option<TransactionOut>>, Error> = groups
+                    .into_iter()
+                    .map(|tx_group| self_clone.converter_service.convert_listed_transaction(tx_group))
+                    .collect();
+                let mut res: Vec<TransactionOut> = res?
+                    .into_iter()
+                    .filter_map(|tx| tx)
+                    .map(|tx| hide_foreign_user_note(tx, user.id))
+                    .collect();
+                res.sort_by_key(|tx| (tx.created_at, *tx.id.inner()));
+                let res: Vec<_> = res.into_iter().rev().collect();
+                Ok((res, next))
+            })
+        }))
+    }
+    fn get_account_transactions(
+        &self,
+        token: AuthenticationToken,
+        account_id: AccountId,
+        kind: Option<TransactionKind>,
+        category: Option<String>,
+        offset: i64,
+        limit: i64,
+    ) -> Box<Future<Item = Vec<TransactionOut>, Error = Error> + Send> {
+        let transactions_repo = self.transactions_repo.clone();
+        let accounts_repo = self.accounts_repo.clone();
+        let db_executor = self.read_db_executor.clone();
+        let self_clone = self.clone();
+        Box::new(self.auth_service.authenticate(token).and_then(move |user| {
+            db_executor.execute(move || {
+                let account = accounts_repo.get(account_id).map_err(ectx!(try convert => account_id))?;
+                if let Some(ref account) = account {
+                    if account.user_id != user.id {
+                        return Err(ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => user.id));
+                    }
+                } else {
+                    return Err(ectx!(err ErrorContext::NoAccount, ErrorKind::NotFound => account_id));
+                }
+                let txs = transactions_repo
+                    .list_groups_for_account_skip_approval(account_id, kind, category.clone(), offset, limit)
+                    .map_err(ectx!(try convert => account_id, kind, category))?;
+                let res: Result<Vec<Option<TransactionOut>>, Error> = group_transactions(&txs)
+                    .into_iter()
+                    .map(|tx_group| self_clone.converter_service.convert_listed_transaction(tx_group))
+                    .collect();
+                let mut res: Vec<TransactionOut> = res?
+                    .into_iter()
+                    .filter_map(|tx| tx)
+                    .map(|tx| hide_foreign_user_note(tx, user.id))
+                    .collect();
+                res.sort_by_key(|tx| tx.created_at);
+                let res: Vec<_> = res.into_iter().rev().collect();
+                Ok(res)
+            })
+        }))
+    }
+    fn get_exchange_history(
+        &self,
+        token: AuthenticationToken,
+        user_id: UserId,
+        from: NaiveDateTime,
+        to: NaiveDateTime,
+    ) -> Box<Future<Item = Vec<ExecutedExchange>, Error = Error> + Send> {
+        let transactions_repo = self.transactions_repo.clone();
+        let db_executor = self.read_db_executor.clone();
+        Box::new(self.auth_service.authenticate(token).and_then(move |user| {
+            db_executor.execute(move || -> Result<Vec<ExecutedExchange>, Error> {
+                if user_id != user.id {
+                    return Err(ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => user.id, user_id));
+                }
+                let txs = transactions_repo
+                    .list_exchanges_for_user(user_id, from, to)
+                    .map_err(ectx!(try convert => user_id, from, to))?;
+                let mut res: Vec<ExecutedExchange> = group_transactions(&txs)
+                    .iter()
+                    .filter_map(|tx_group| executed_exchange(tx_group))
+                    .collect();
+                res.sort_by_key(|exchange| exchange.created_at);
+                Ok(res)
+            })
+        }))
+    }
+}
+
+// the note is private to the user who created the group, e.g. the recipient of a transfer or an operator doesn't see it
+fn hide_foreign_user_note(mut tx_out: TransactionOut, user_id: UserId) -> TransactionOut {
+    if tx_out.user_id != user_id {
+        tx_out.user_note = None;
+    }
+    tx_out
+}
+
+// single withdrawal to the output, as if it was requested on its own
+fn batch_output_input(input: &CreateBatchTransactionInput, output: &BlockchainTxOutput) -> CreateTransactionInput {
+    CreateTransactionInput {
+        id: input.id,
+        user_id: input.user_id,
+        from: input.from,
+        to: Recepient::new(output.to.raw().to_string()),
+        to_type: RecepientType::Address,
+        to_currency: input.currency,
+        value: output.value,
+        value_currency: input.currency,
+        fee: input.fee,
+        exchange_id: None,
+        exchange_rate: None,
+        fiat_value: None,
+        fiat_rate: None,
+        hold_until: None,
+        gas_limit_override: None,
+        drain: false,
+        fee_tier: None,
+        fee_omitted: false,
+        user_note: None,
+        category: None,
+        expected_recipient: None,
+    }
+}
+
+// a withdrawal is checked and sent within seconds, a lock older than that was left by a crashed instance
+const WITHDRAWAL_LOCK_TIMEOUT_SECS: i64 = 600;
+
+// validation error telling the client how much is missing, amounts are in base units (satoshi, wei)
+fn not_enough_funds_error(currency: Currency, required: Amount, available: Amount) -> ErrorKind {
+    balance_error("not_enough_balance", "account balance is not enough", currency, required, available)
+}
+
+// same as `not_enough_funds_error`, but the funds are there on other accounts of the user,
+// which are never used for internal transfers
+fn not_enough_funds_in_source_error(currency: Currency, required: Amount, available: Amount) -> ErrorKind {
+    balance_error(
+        "not_enough_balance_in_source",
+        "source account balance is not enough, internal transfers are made only from the source account",
+        currency,
+        required,
+        available,
+    )
+}
+
+fn balance_error(code: &'static str, message: &'static str, currency: Currency, required: Amount, available: Amount) -> ErrorKind {
+    let shortfall = required.checked_sub(available).unwrap_or_default();
+    let mut errors = ValidationErrors::new();
+    let mut error = ValidationError::new(code);
+    error.message = Some(message.into());
+    error.add_param("required".into(), &required.raw().to_string());
+    error.add_param("available".into(), &available.raw().to_string());
+    error.add_param("shortfall".into(), &shortfall.raw().to_string());
+    error.add_param("currency".into(), &currency.to_string().to_uppercase());
+    errors.add("value", error);
+    ErrorKind::InvalidInput(serde_json::to_string(&errors).unwrap_or_default())
+}
+
+// the balance of our liquidity accounts is not the client's business, so it's only logged
+fn liquidity_unavailable_error(currency: Currency) -> ErrorKind {
+    let mut errors = ValidationErrors::new();
+    let mut error = ValidationError::new("liquidity_unavailable");
+    error.message = Some("withdrawals are temporarily unavailable, try again later".into());
+    error.add_param("currency".into(), &currency.to_string().to_uppercase());
+    errors.add("value", error);
+    ErrorKind::InvalidInput(serde_json::to_string(&errors).unwrap_or_default())
+}
+
+fn fee_with_fee_tier_error() -> ErrorKind {
+    let mut errors = ValidationErrors::new();
+    let mut error = ValidationError::new("fee_with_fee_tier");
+    error.message = Some("fee can't be set together with fee tier".into());
+    errors.add("fee_tier", error);
+    ErrorKind::InvalidInput(serde_json::to_string(&errors).unwrap_or_default())
+}
+
+// validation error about blockchain transaction the client referred to
+fn invalid_blockchain_tx_error(code: &'static str, message: &'static str) -> ErrorKind {
+    let mut errors = ValidationErrors::new();
+    let mut error = ValidationError::new(code);
+    error.message = Some(message.into());
+    errors.add("blockchain_tx_id", error);
+    ErrorKind::InvalidInput(serde_json::to_string(&errors).unwrap_or_default())
+}
+
+/// Checks if withdrawal failed before any of its blockchain transactions was sent
+fn is_nothing_sent_to_blockchain(e: &Error) -> bool {
+    let e: &Fail = e;
+    e.iter_chain().any(|cause| match cause.downcast_ref::<Context<ErrorContext>>() {
+        Some(context) => *context.get_context() == ErrorContext::NothingSentToBlockchain,
+        None => false,
+    })
+}
+
+/// Error of a withdrawal that is sent to blockchain, but not written to the ledger. The cause is reported
+/// for manual reconciliation, the client must not retry the withdrawal with a fresh id
+fn withdrawal_not_recorded_error(e: Error, gid: TransactionId) -> Error {
+    log_and_capture_error(e);
+    ectx!(err ErrorContext::WithdrawalNotRecorded, ErrorKind::Internal => gid)
+}
+
+// Pairs outputs with the source accounts paying them, every pair is sent as a separate blockchain
+// transaction. An output is split between sources if none of them holds it whole.
+// Returns `None` if the sources don't hold all of the outputs.
+fn split_outputs(sources: Vec<AccountWithBalance>, outputs: &[BlockchainTxOutput]) -> Option<Vec<(Account, BlockchainTxOutput)>> {
+    let mut sources = sources.into_iter().filter(|source| source.balance > Amount::new(0));
+    let mut current = sources.next();
+    let mut parts = vec![];
+    for output in outputs {
+        let mut remaining = output.value;
+        while remaining > Amount::new(0) {
+            let exhausted = {
+                let source = current.as_mut()?;
+                let value = if source.balance < remaining { source.balance } else { remaining };
+                parts.push((
+                    source.account.clone(),
+                    BlockchainTxOutput {
+                        to: output.to.clone(),
+                        value,
+                    },
+                ));
+                remaining = remaining.checked_sub(value)?;
+                source.balance = source.balance.checked_sub(value)?;
+                source.balance == Amount::new(0)
+            };
+            if exhausted {
+                current = sources.next();
+            }
+        }
+    }
+    Some(parts)
+}
+
+// group transactions into subgroups of related txs. I.e. group tx itself + fee
+fn group_transactions(transactions: &[Transaction]) -> Vec<Vec<Transaction>> {
+    let mut res: HashMap<TransactionId, Vec<Transaction>> = HashMap::new();
+    for tx in transactions.into_iter() {
+        res.entry(tx.gid).and_modify(|txs| txs.push(tx.clone())).or_insert(vec![tx.clone()]);
+    }
+    res.into_iter().map(|(_, txs)| txs).collect()
+}
+
+// position of the group in listings, see `TransactionsCursor`
+fn group_cursor(txs: &[Transaction]) -> Option<TransactionsCursor> {
+    let created_at = txs.iter().map(|tx| tx.created_at).min()?;
+    let gid = txs.first()?.gid;
+    Some(TransactionsCursor { created_at, gid })
+}
+
+// exchange made in a group of internal multi currency legs, rate is read from the meta of `MultiFrom` leg
+fn executed_exchange(txs: &[Transaction]) -> Option<ExecutedExchange> {
+    let from_tx = txs.iter().find(|tx| tx.kind == TransactionKind::MultiFrom)?;
+    let to_tx = txs.iter().find(|tx| tx.kind == TransactionKind::MultiTo)?;
+    Some(ExecutedExchange {
+        transaction_id: from_tx.gid,
+        exchange_id: serde_json::from_value(from_tx.meta["exchangeId"].clone()).ok(),
+        from_currency: from_tx.currency,
+        to_currency: to_tx.currency,
+        from_value: from_tx.value,
+        to_value: to_tx.value,
+        rate: from_tx.meta["exchangeRate"].as_f64(),
+        created_at: from_tx.created_at,
+    })
+}
+
+// values debited from `from` account and credited to `to` account, the input value is in either of their currencies
+fn exchange_values(
+    input: &CreateTransactionInput,
+    from_account: &Account,
+    to_account: &Account,
+    rate: f64,
+) -> Result<(Amount, Amount), Error> {
+    if from_account.currency == input.value_currency {
+        Ok((input.value, input.value.convert(from_account.currency, to_account.currency, rate)))
+    } else if to_account.currency == input.value_currency {
+        Ok((
+            input.value.convert(to_account.currency, from_account.currency, 1.0 / rate),
+            input.value,
+        ))
+    } else {
+        Err(ectx!(err ErrorContext::InvalidCurrency, ErrorKind::MalformedInput => input, from_account, to_account))
+    }
+}
+
+#[cfg(test)]
+#[allow(unused)]
+mod tests {
+    use super::*;
+    use client::exchange::ErrorKind as ExchangeClientErrorKind;
+    use client::*;
+    use config::{Config, RateLimit};
+    use rabbit::*;
+    use repos::*;
+    use services::*;
+    use tokio_core::reactor::Core;
+
+    fn create_transaction_service(token: AuthenticationToken, user_id: UserId) -> TransactionsServiceImpl<DbExecutorMock> {
+        let config = Config::new().unwrap();
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        create_transaction_service_with_repo(token, user_id, config, transactions_repo)
+    }
+
+    fn create_transaction_service_with_repo(
+        token: AuthenticationToken,
+        user_id: UserId,
+        config: Config,
+        transactions_repo: Arc<TransactionsRepoMock>,
+    ) -> TransactionsServiceImpl<DbExecutorMock> {
+        let exchange_client = Arc::new(ExchangeClientMock::default());
+        let clock = Arc::new(MockClock::default());
+        create_transaction_service_with_mocks(token, user_id, config, transactions_repo, exchange_client, clock)
+    }
+
+    fn create_transaction_service_with_mocks(
+        token: AuthenticationToken,
+        user_id: UserId,
+        config: Config,
+        transactions_repo: Arc<TransactionsRepoMock>,
+        exchange_client: Arc<ExchangeClientMock>,
+        clock: Arc<MockClock>,
+    ) -> TransactionsServiceImpl<DbExecutorMock> {
+        create_transaction_service_with_blockchain_client(
+            token,
+            user_id,
+            config,
+            transactions_repo,
             exchange_client,
-            publisher,
+            BlockchainClientMock::default(),
+            clock,
         )
     }
+
+    fn create_transaction_service_with_blockchain_client(
+        token: AuthenticationToken,
+        user_id: UserId,
+        config: Config,
+        transactions_repo: Arc<TransactionsRepoMock>,
+        exchange_client: Arc<ExchangeClientMock>,
+        blockchain_client: BlockchainClientMock,
+        clock: Arc<MockClock>,
+    ) -> TransactionsServiceImpl<DbExecutorMock> {
+        let auth_service = Arc::new(AuthServiceMock::new(vec![(token, user_id)]));
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let pending_transactions_repo = Arc::new(PendingBlockchainTransactionsRepoMock::default());
+        let blockchain_transactions_repo = Arc::new(BlockchainTransactionsRepoMock::default());
+        let seen_hashes_repo = Arc::new(SeenHashesRepoMock::default());
+        let strange_blockchain_transactions_repo = Arc::new(StrangeBlockchainTransactionsRepoMock::default());
+        let key_values_repo = Arc::new(KeyValuesRepoMock::default());
+        let keys_client = Arc::new(KeysClientMock::default());
+        let blockchain_client = Arc::new(blockchain_client);
+        let db_executor = DbExecutorMock::default();
+        let publisher = Arc::new(TransactionPublisherMock::default());
+        TransactionsServiceImpl::new(
+            config,
+            auth_service,
+            transactions_repo,
+            pending_transactions_repo,
+            blockchain_transactions_repo,
+            seen_hashes_repo,
+            strange_blockchain_transactions_repo,
+            accounts_repo,
+            key_values_repo,
+            db_executor,
+            keys_client,
+            blockchain_client,
+            exchange_client,
+            publisher,
+            clock,
+        )
+    }
+
+    fn create_withdrawal_limit_service(user_id: UserId) -> TransactionsServiceImpl<DbExecutorMock> {
+        create_withdrawal_limit_service_with_clock(user_id, Arc::new(MockClock::default()))
+    }
+
+    // 0.05 BTC limit with 0.03 BTC already withdrawn today
+    fn create_withdrawal_limit_service_with_clock(user_id: UserId, clock: Arc<MockClock>) -> TransactionsServiceImpl<DbExecutorMock> {
+        let mut config = Config::new().unwrap();
+        config.daily_withdrawal_limit.btc = 0.05;
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let mut withdrawal = NewTransaction::default();
+        withdrawal.user_id = user_id;
+        withdrawal.currency = Currency::Btc;
+        withdrawal.value = Amount::new(3_000_000);
+        withdrawal.kind = TransactionKind::Withdrawal;
+        withdrawal.group_kind = TransactionGroupKind::Withdrawal;
+        transactions_repo.create(withdrawal).unwrap();
+        // internal transfers and other currencies are not counted
+        let mut internal = NewTransaction::default();
+        internal.user_id = user_id;
+        internal.currency = Currency::Btc;
+        internal.value = Amount::new(10_000_000);
+        transactions_repo.create(internal).unwrap();
+        let mut eth_withdrawal = NewTransaction::default();
+        eth_withdrawal.user_id = user_id;
+        eth_withdrawal.currency = Currency::Eth;
+        eth_withdrawal.value = Amount::new(10_000_000);
+        eth_withdrawal.kind = TransactionKind::Withdrawal;
+        transactions_repo.create(eth_withdrawal).unwrap();
+        let exchange_client = Arc::new(ExchangeClientMock::default());
+        create_transaction_service_with_mocks(
+            AuthenticationToken::default(),
+            user_id,
+            config,
+            transactions_repo,
+            exchange_client,
+            clock,
+        )
+    }
+
+    #[test]
+    fn test_daily_withdrawal_limit_under() {
+        let user_id = UserId::generate();
+        let service = create_withdrawal_limit_service(user_id);
+        let res = service.check_daily_withdrawal_limit(user_id, Currency::Btc, Amount::new(1_999_999));
+        assert!(res.is_ok());
+    }
+
+    #[test]
+    fn test_daily_withdrawal_limit_at() {
+        let user_id = UserId::generate();
+        let service = create_withdrawal_limit_service(user_id);
+        let res = service.check_daily_withdrawal_limit(user_id, Currency::Btc, Amount::new(2_000_000));
+        assert!(res.is_ok());
+    }
+
+    #[test]
+    fn test_daily_withdrawal_limit_over() {
+        let user_id = UserId::generate();
+        let service = create_withdrawal_limit_service(user_id);
+        let res = service.check_daily_withdrawal_limit(user_id, Currency::Btc, Amount::new(2_000_001));
+        match res.unwrap_err().kind() {
+            ErrorKind::Balance(_) => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+        // other users are not affected
+        let res = service.check_daily_withdrawal_limit(UserId::generate(), Currency::Btc, Amount::new(2_000_001));
+        assert!(res.is_ok());
+    }
+
+    #[test]
+    fn test_daily_withdrawal_limit_failed_not_counted() {
+        let user_id = UserId::generate();
+        let service = create_withdrawal_limit_service(user_id);
+        for status in vec![TransactionStatus::Failed, TransactionStatus::Cancelled] {
+            let mut withdrawal = NewTransaction::default();
+            withdrawal.user_id = user_id;
+            withdrawal.currency = Currency::Btc;
+            withdrawal.value = Amount::new(1_000_000);
+            withdrawal.status = status;
+            withdrawal.kind = TransactionKind::Withdrawal;
+            withdrawal.group_kind = TransactionGroupKind::Withdrawal;
+            service.transactions_repo.create(withdrawal).unwrap();
+        }
+        let res = service.check_daily_withdrawal_limit(user_id, Currency::Btc, Amount::new(2_000_000));
+        assert!(res.is_ok());
+    }
+
+    fn create_fiat_input(user_id: UserId, to_currency: Currency, fiat_value: Option<FiatValue>) -> CreateTransactionInput {
+        CreateTransactionInput {
+            id: TransactionId::generate(),
+            user_id,
+            from: AccountId::generate(),
+            to: Recepient::new(AccountId::generate().to_string()),
+            to_type: RecepientType::Account,
+            to_currency,
+            value: Amount::new(1_000),
+            value_currency: to_currency,
+            fee: Amount::default(),
+            exchange_id: None,
+            exchange_rate: None,
+            fiat_value,
+            fiat_rate: None,
+            hold_until: None,
+            gas_limit_override: None,
+            drain: false,
+            fee_tier: None,
+            fee_omitted: false,
+            user_note: None,
+            category: None,
+            expected_recipient: None,
+        }
+    }
+
+    #[test]
+    fn test_not_enough_funds_shortfall() {
+        let user_id = UserId::generate();
+        let service = create_transaction_service(AuthenticationToken::default(), user_id);
+        let user_account = service
+            .accounts_repo
+            .create(NewAccount {
+                user_id,
+                currency: Currency::Btc,
+                ..Default::default()
+            })
+            .unwrap();
+        let withdrawal_account = service
+            .accounts_repo
+            .create(NewAccount {
+                currency: Currency::Btc,
+                kind: AccountKind::Dr,
+                ..Default::default()
+            })
+            .unwrap();
+        // account is funded with 0.00015 btc out of 0.0002 btc required
+        service
+            .transactions_repo
+            .create(NewTransaction {
+                user_id,
+                cr_account_id: user_account.id,
+                currency: Currency::Btc,
+                value: Amount::new(15_000),
+                ..Default::default()
+            })
+            .unwrap();
+        let tx = NewTransaction {
+            user_id,
+            dr_account_id: user_account.id,
+            cr_account_id: withdrawal_account.id,
+            currency: Currency::Btc,
+            value: Amount::new(20_000),
+            kind: TransactionKind::Withdrawal,
+            group_kind: TransactionGroupKind::Withdrawal,
+            ..Default::default()
+        };
+
+        let err = service.create_base_tx(tx, user_account, withdrawal_account).unwrap_err();
+        let errors = match err.kind() {
+            ErrorKind::InvalidInput(errors) => errors,
+            kind => panic!("unexpected error kind: {:?}", kind),
+        };
+        let errors: serde_json::Value = serde_json::from_str(&errors).unwrap();
+        let error = &errors["value"][0];
+        assert_eq!(error["code"], "not_enough_balance");
+        assert_eq!(error["params"]["required"], "20000");
+        assert_eq!(error["params"]["available"], "15000");
+        assert_eq!(error["params"]["shortfall"], "5000");
+        assert_eq!(error["params"]["currency"], "BTC");
+    }
+
+    #[test]
+    fn test_internal_transfer_uses_only_source_account() {
+        let mut core = Core::new().unwrap();
+        let user_id = UserId::generate();
+        let config = Config::new().unwrap();
+        let service = create_transaction_service(AuthenticationToken::default(), user_id);
+        let mut fees_account = NewAccount::default();
+        fees_account.id = config.system.btc_fees_account_id;
+        fees_account.currency = Currency::Btc;
+        service.accounts_repo.create(fees_account).unwrap();
+        let mut accounts = vec![];
+        for _ in 0..3 {
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user_id;
+            new_account.currency = Currency::Btc;
+            accounts.push(service.accounts_repo.create(new_account).unwrap());
+        }
+        let (source, funded, to) = (accounts[0].clone(), accounts[1].clone(), accounts[2].clone());
+        for (account, value) in vec![(&source, 100_000), (&funded, 2_000_000)] {
+            let mut deposit = NewTransaction::default();
+            deposit.cr_account_id = account.id;
+            deposit.currency = Currency::Btc;
+            deposit.value = Amount::new(value);
+            service.transactions_repo.create(deposit).unwrap();
+        }
+        let error_code = |err: Error| match err.kind() {
+            ErrorKind::InvalidInput(errors) => {
+                let errors: serde_json::Value = serde_json::from_str(&errors).unwrap();
+                errors["value"][0]["code"].as_str().unwrap().to_string()
+            }
+            kind => panic!("unexpected error kind: {:?}", kind),
+        };
+
+        // internal transfer is never paid from another account, even if it could pay
+        let mut input = create_fiat_input(user_id, Currency::Btc, None);
+        input.value = Amount::new(1_000_000);
+        let err = core
+            .run(service.create_internal_mono_currency_tx(input.clone(), source.clone(), to.clone()))
+            .unwrap_err();
+        assert_eq!(error_code(err), "not_enough_balance_in_source");
+        let balance = |account: &Account| service.transactions_repo.get_account_balance(account.id, AccountKind::Cr).unwrap();
+        assert_eq!(balance(&funded), Amount::new(2_000_000));
+        assert_eq!(balance(&to), Amount::new(0));
+
+        // no account of the user could pay
+        input.id = TransactionId::generate();
+        input.value = Amount::new(5_000_000);
+        let err = core
+            .run(service.create_internal_mono_currency_tx(input.clone(), source.clone(), to.clone()))
+            .unwrap_err();
+        assert_eq!(error_code(err), "not_enough_balance");
+
+        // the source pays in full
+        input.id = TransactionId::generate();
+        input.value = Amount::new(100_000);
+        let tx = core
+            .run(service.create_internal_mono_currency_tx(input, source.clone(), to.clone()))
+            .unwrap();
+        assert_eq!(tx.dr_account_id, source.id);
+        assert_eq!(balance(&to), Amount::new(100_000));
+
+        // withdrawal is sent from pooled liquidity accounts rather than from an account of the user
+        let liquidity_account_id = AccountId::generate();
+        let mut liquidity = NewTransaction::default();
+        liquidity.dr_account_id = liquidity_account_id;
+        liquidity.currency = Currency::Btc;
+        liquidity.value = Amount::new(10_000_000);
+        service.transactions_repo.create(liquidity).unwrap();
+        let mut input = create_fiat_input(user_id, Currency::Btc, None);
+        input.value = Amount::new(1_000_000);
+        let txs = core
+            .run(service.create_external_mono_currency_tx(
+                input,
+                funded.clone(),
+                BlockchainAddress::new("btc_address".to_string()),
+                Currency::Btc,
+                None,
+                None,
+                None,
+                None,
+                None,
+            ))
+            .unwrap();
+        let withdrawal = txs.iter().find(|tx| tx.kind == TransactionKind::Withdrawal).unwrap();
+        assert_eq!(withdrawal.dr_account_id, funded.id);
+        assert_eq!(withdrawal.cr_account_id, liquidity_account_id);
+    }
+
+    #[test]
+    fn test_get_account_balance_at() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let user_id = UserId::generate();
+        let service = create_transaction_service(token.clone(), user_id);
+        let mut new_account = NewAccount::default();
+        new_account.user_id = user_id;
+        new_account.currency = Currency::Btc;
+        let account = service.accounts_repo.create(new_account).unwrap();
+        let mut deposit = NewTransaction::default();
+        deposit.cr_account_id = account.id;
+        deposit.currency = Currency::Btc;
+        deposit.value = Amount::new(1_000_000);
+        let deposit = service.transactions_repo.create(deposit).unwrap();
+        let mut withdrawal = NewTransaction::default();
+        withdrawal.dr_account_id = account.id;
+        withdrawal.currency = Currency::Btc;
+        withdrawal.value = Amount::new(300_000);
+        let withdrawal = service.transactions_repo.create(withdrawal).unwrap();
+        let just_before = |tx: &Transaction| tx.created_at - Duration::nanoseconds(1);
+
+        let cases = vec![
+            (just_before(&deposit), 0),
+            (deposit.created_at, 1_000_000),
+            (just_before(&withdrawal), 1_000_000),
+            (withdrawal.created_at, 700_000),
+            (withdrawal.created_at + Duration::days(1), 700_000),
+        ];
+        for (as_of, expected) in cases {
+            let res = core.run(service.get_account_balance_at(token.clone(), account.id, as_of)).unwrap();
+            assert_eq!(res.account.id, account.id);
+            assert_eq!(res.balance, Amount::new(expected), "as of {}", as_of);
+        }
+
+        // ownership is checked as for the current balance
+        let mut new_account = NewAccount::default();
+        new_account.currency = Currency::Btc;
+        let other_account = service.accounts_repo.create(new_account).unwrap();
+        match core
+            .run(service.get_account_balance_at(token, other_account.id, withdrawal.created_at))
+            .unwrap_err()
+            .kind()
+        {
+            ErrorKind::Unauthorized => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn test_quote_fiat_value() {
+        let mut core = Core::new().unwrap();
+        let user_id = UserId::generate();
+        let config = Config::new().unwrap();
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        // 0.0002 btc per usd
+        let exchange_client = Arc::new(ExchangeClientMock::with_rate(0.0002));
+        let clock = Arc::new(MockClock::default());
+        let service = create_transaction_service_with_mocks(
+            AuthenticationToken::default(),
+            user_id,
+            config,
+            transactions_repo,
+            exchange_client,
+            clock,
+        );
+        // $100
+        let fiat_value = FiatValue::new(FiatCurrency::Usd, Amount::new(10_000));
+        let input = create_fiat_input(user_id, Currency::Btc, Some(fiat_value));
+        let quoted = core.run(service.quote_fiat_value(input)).unwrap();
+        assert_eq!(quoted.value, Amount::new(2_000_000));
+        assert_eq!(quoted.value_currency, Currency::Btc);
+        assert_eq!(quoted.fiat_rate, Some(0.0002));
+        let meta = quoted.fiat_meta().unwrap();
+        assert_eq!(meta["fiatCurrency"], json!("usd"));
+        assert_eq!(meta["fiatValue"], json!(10_000));
+        assert_eq!(meta["fiatRate"], json!(0.0002));
+    }
+
+    #[test]
+    fn test_quote_fiat_value_without_fiat() {
+        let mut core = Core::new().unwrap();
+        let user_id = UserId::generate();
+        let service = create_transaction_service(AuthenticationToken::default(), user_id);
+        let input = create_fiat_input(user_id, Currency::Btc, None);
+        let quoted = core.run(service.quote_fiat_value(input)).unwrap();
+        assert_eq!(quoted.value, Amount::new(1_000));
+        assert_eq!(quoted.fiat_rate, None);
+        assert_eq!(quoted.fiat_meta(), None);
+    }
+
+    #[test]
+    fn test_daily_withdrawal_limit_next_day() {
+        let user_id = UserId::generate();
+        let clock = Arc::new(MockClock::default());
+        let service = create_withdrawal_limit_service_with_clock(user_id, clock.clone());
+        let res = service.check_daily_withdrawal_limit(user_id, Currency::Btc, Amount::new(5_000_000));
+        assert!(res.is_err());
+        // yesterday's withdrawal is out of the window
+        clock.advance(Duration::hours(25));
+        let res = service.check_daily_withdrawal_limit(user_id, Currency::Btc, Amount::new(5_000_000));
+        assert!(res.is_ok());
+    }
+
+    #[test]
+    fn test_withdrawal_cooldown() {
+        let mut core = Core::new().unwrap();
+        let user_id = UserId::generate();
+        let mut config = Config::new().unwrap();
+        config.system.withdrawal_cooldown_secs = 600;
+        let mut fees_account = NewAccount::default();
+        fees_account.id = config.system.btc_fees_account_id;
+        fees_account.currency = Currency::Btc;
+        let clock = Arc::new(MockClock::default());
+        let service = create_transaction_service_with_mocks(
+            AuthenticationToken::default(),
+            user_id,
+            config,
+            Arc::new(TransactionsRepoMock::default()),
+            Arc::new(ExchangeClientMock::default()),
+            clock.clone(),
+        );
+        service.accounts_repo.create(fees_account).unwrap();
+        let mut new_account = NewAccount::default();
+        new_account.user_id = user_id;
+        new_account.currency = Currency::Btc;
+        let account = service.accounts_repo.create(new_account).unwrap();
+        let mut deposit = NewTransaction::default();
+        deposit.cr_account_id = account.id;
+        deposit.currency = Currency::Btc;
+        deposit.value = Amount::new(3_000_000);
+        service.transactions_repo.create(deposit).unwrap();
+        let mut liquidity = NewTransaction::default();
+        liquidity.dr_account_id = AccountId::generate();
+        liquidity.currency = Currency::Btc;
+        liquidity.value = Amount::new(10_000_000);
+        service.transactions_repo.create(liquidity).unwrap();
+        let withdraw = |service: &TransactionsServiceImpl<DbExecutorMock>| {
+            let mut input = create_fiat_input(user_id, Currency::Btc, None);
+            input.value = Amount::new(1_000_000);
+            service.create_external_mono_currency_tx(
+                input,
+                account.clone(),
+                BlockchainAddress::new("btc_address".to_string()),
+                Currency::Btc,
+                None,
+                None,
+                None,
+                None,
+                None,
+            )
+        };
+
+        core.run(withdraw(&service)).unwrap();
+        // back to back withdrawal is rejected
+        clock.advance(Duration::seconds(599));
+        match core.run(withdraw(&service)).unwrap_err().kind() {
+            ErrorKind::TooManyRequests => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+        // other users are not affected
+        assert!(service.check_withdrawal_cooldown(UserId::generate()).is_ok());
+
+        // allowed once the cooldown is over
+        clock.advance(Duration::seconds(2));
+        core.run(withdraw(&service)).unwrap();
+    }
+
+    #[test]
+    fn test_withdrawal_lock() {
+        let mut core = Core::new().unwrap();
+        let user_id = UserId::generate();
+        let config = Config::new().unwrap();
+        let mut fees_account = NewAccount::default();
+        fees_account.id = config.system.btc_fees_account_id;
+        fees_account.currency = Currency::Btc;
+        let clock = Arc::new(MockClock::default());
+        let service = create_transaction_service_with_mocks(
+            AuthenticationToken::default(),
+            user_id,
+            config,
+            Arc::new(TransactionsRepoMock::default()),
+            Arc::new(ExchangeClientMock::default()),
+            clock.clone(),
+        );
+        service.accounts_repo.create(fees_account).unwrap();
+        let mut new_account = NewAccount::default();
+        new_account.user_id = user_id;
+        new_account.currency = Currency::Btc;
+        let account = service.accounts_repo.create(new_account).unwrap();
+        let mut deposit = NewTransaction::default();
+        deposit.cr_account_id = account.id;
+        deposit.currency = Currency::Btc;
+        deposit.value = Amount::new(3_000_000);
+        service.transactions_repo.create(deposit).unwrap();
+        let mut liquidity = NewTransaction::default();
+        liquidity.dr_account_id = AccountId::generate();
+        liquidity.currency = Currency::Btc;
+        liquidity.value = Amount::new(10_000_000);
+        service.transactions_repo.create(liquidity).unwrap();
+        let withdraw = |service: &TransactionsServiceImpl<DbExecutorMock>| {
+            let mut input = create_fiat_input(user_id, Currency::Btc, None);
+            input.value = Amount::new(1_000_000);
+            service.create_external_mono_currency_tx(
+                input,
+                account.clone(),
+                BlockchainAddress::new("btc_address".to_string()),
+                Currency::Btc,
+                None,
+                None,
+                None,
+                None,
+                None,
+            )
+        };
+        // another withdrawal of the user is being sent
+        let lock = WithdrawalLock {
+            token: Uuid::new_v4(),
+            locked_at: clock.now(),
+        };
+        service.key_values_repo.set_withdrawal_lock(user_id, lock).unwrap();
+
+        match core.run(withdraw(&service)).unwrap_err().kind() {
+            ErrorKind::TooManyRequests => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+        // only the request holding the lock releases it
+        assert_eq!(service.key_values_repo.get_withdrawal_lock(user_id).unwrap(), Some(lock));
+
+        // left by a crashed instance
+        clock.advance(Duration::seconds(WITHDRAWAL_LOCK_TIMEOUT_SECS));
+        core.run(withdraw(&service)).unwrap();
+        assert_eq!(service.key_values_repo.get_withdrawal_lock(user_id).unwrap(), None);
+    }
+
+    #[test]
+    fn test_withdrawal_cooldown_after_failed_withdrawal() {
+        let user_id = UserId::generate();
+        let mut config = Config::new().unwrap();
+        config.system.withdrawal_cooldown_secs = 600;
+        let service = create_transaction_service_with_mocks(
+            AuthenticationToken::default(),
+            user_id,
+            config,
+            Arc::new(TransactionsRepoMock::default()),
+            Arc::new(ExchangeClientMock::default()),
+            Arc::new(MockClock::default()),
+        );
+        let mut withdrawal = NewTransaction::default();
+        withdrawal.user_id = user_id;
+        withdrawal.currency = Currency::Btc;
+        withdrawal.value = Amount::new(1_000_000);
+        withdrawal.status = TransactionStatus::Failed;
+        withdrawal.kind = TransactionKind::Withdrawal;
+        withdrawal.group_kind = TransactionGroupKind::Withdrawal;
+        service.transactions_repo.create(withdrawal).unwrap();
+        // failed withdrawal doesn't hold back the retry
+        assert!(service.check_withdrawal_cooldown(user_id).is_ok());
+    }
+
+    #[test]
+    fn test_withdrawals_disabled() {
+        let mut core = Core::new().unwrap();
+        let user_id = UserId::generate();
+        let mut config = Config::new().unwrap();
+        config.withdrawals_enabled.btc = false;
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let from = Account {
+            user_id,
+            currency: Currency::Btc,
+            ..Default::default()
+        };
+        let to = Account {
+            currency: Currency::Btc,
+            ..Default::default()
+        };
+        let mut deposit = NewTransaction::default();
+        deposit.cr_account_id = from.id;
+        deposit.currency = Currency::Btc;
+        deposit.value = Amount::new(100);
+        transactions_repo.create(deposit).unwrap();
+        let service = create_transaction_service_with_repo(AuthenticationToken::default(), user_id, config, transactions_repo);
+
+        // btc withdrawal is rejected before anything is sent to blockchain
+        let mut input = create_fiat_input(user_id, Currency::Btc, None);
+        input.value = Amount::new(70);
+        let res = core.run(service.create_external_mono_currency_tx(
+            input.clone(),
+            from.clone(),
+            BlockchainAddress::new("btc_address".to_string()),
+            Currency::Btc,
+            None,
+            None,
+            None,
+            None,
+            None,
+        ));
+        match res.unwrap_err().kind() {
+            ErrorKind::Balance(_) => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+        // other currencies are still withdrawable
+        assert!(service.check_withdrawals_enabled(Currency::Eth).is_ok());
+        assert!(service.check_withdrawals_enabled(Currency::Stq).is_ok());
+        // internal btc transfers still work
+        let tx = core.run(service.create_internal_mono_currency_tx(input, from, to)).unwrap();
+        assert_eq!(tx.status, TransactionStatus::Done);
+    }
+
+    #[test]
+    fn test_preview_coin_selection() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let config = Config::new().unwrap();
+        let system_user_id = config.system.system_user_id;
+        let mut fees_account = NewAccount::default();
+        fees_account.id = config.system.btc_fees_account_id;
+        fees_account.currency = Currency::Btc;
+        let service =
+            create_transaction_service_with_repo(token.clone(), system_user_id, config, Arc::new(TransactionsRepoMock::default()));
+        service.accounts_repo.create(fees_account).unwrap();
+        let mut new_account = NewAccount::default();
+        new_account.currency = Currency::Btc;
+        let from = service.accounts_repo.create(new_account).unwrap();
+        for _ in 0..2 {
+            let mut deposit = NewTransaction::default();
+            deposit.cr_account_id = from.id;
+            deposit.currency = Currency::Btc;
+            deposit.value = Amount::new(600_000);
+            service.transactions_repo.create(deposit).unwrap();
+        }
+        let mut liquidity = NewTransaction::default();
+        liquidity.dr_account_id = AccountId::generate();
+        liquidity.currency = Currency::Btc;
+        liquidity.value = Amount::new(10_000_000);
+        service.transactions_repo.create(liquidity).unwrap();
+
+        let value = Amount::new(1_000_000);
+        let preview = core
+            .run(service.preview_coin_selection(token.clone(), Currency::Btc, value))
+            .unwrap();
+
+        let mut input = create_fiat_input(system_user_id, Currency::Btc, None);
+        input.value = value;
+        let txs = core
+            .run(service.create_external_mono_currency_tx(
+                input,
+                from,
+                BlockchainAddress::new("btc_address".to_string()),
+                Currency::Btc,
+                None,
+                None,
+                None,
+                None,
+                None,
+            ))
+            .unwrap();
+        let withdrawn: Vec<(AccountId, Amount)> = txs
+            .iter()
+            .filter(|tx| tx.kind == TransactionKind::Withdrawal)
+            .map(|tx| (tx.cr_account_id, tx.value))
+            .collect();
+        let previewed: Vec<(AccountId, Amount)> = preview.iter().map(|source| (source.account.id, source.balance)).collect();
+        assert_eq!(previewed, withdrawn);
+
+        // only for the system user
+        let service = create_transaction_service(token.clone(), UserId::generate());
+        match core
+            .run(service.preview_coin_selection(token, Currency::Btc, value))
+            .unwrap_err()
+            .kind()
+        {
+            ErrorKind::Unauthorized => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn test_stq_withdrawal_without_eth() {
+        let mut core = Core::new().unwrap();
+        let user_id = UserId::generate();
+        let config = Config::new().unwrap();
+        let service = create_transaction_service(AuthenticationToken::default(), user_id);
+        for (id, currency) in vec![
+            (config.system.stq_fees_account_id, Currency::Stq),
+            (config.system.eth_fees_account_id, Currency::Eth),
+        ] {
+            let mut fees_account = NewAccount::default();
+            fees_account.id = id;
+            fees_account.currency = currency;
+            service.accounts_repo.create(fees_account).unwrap();
+        }
+        // the user has only stq
+        let mut new_account = NewAccount::default();
+        new_account.user_id = user_id;
+        new_account.currency = Currency::Stq;
+        let from = service.accounts_repo.create(new_account).unwrap();
+        for _ in 0..2 {
+            let mut deposit = NewTransaction::default();
+            deposit.cr_account_id = from.id;
+            deposit.currency = Currency::Stq;
+            deposit.value = Amount::new(600_000);
+            service.transactions_repo.create(deposit).unwrap();
+        }
+        let mut liquidity = NewTransaction::default();
+        liquidity.dr_account_id = AccountId::generate();
+        liquidity.currency = Currency::Stq;
+        liquidity.value = Amount::new(10_000_000);
+        service.transactions_repo.create(liquidity).unwrap();
+
+        let mut input = create_fiat_input(user_id, Currency::Stq, None);
+        input.value = Amount::new(1_000_000);
+        input.fee = Amount::new(100);
+        let txs = core
+            .run(service.create_external_mono_currency_tx(
+                input,
+                from.clone(),
+                BlockchainAddress::new("stq_address".to_string()),
+                Currency::Stq,
+                None,
+                None,
+                None,
+                None,
+                None,
+            ))
+            .unwrap();
+        assert!(txs.iter().all(|tx| tx.currency == Currency::Stq));
+        let fee = txs.iter().find(|tx| tx.kind == TransactionKind::Fee).unwrap();
+        assert_eq!(fee.dr_account_id, from.id);
+        assert_eq!(fee.cr_account_id, config.system.stq_fees_account_id);
+        assert_eq!(fee.value, Amount::new(100));
+        assert_eq!(
+            service.transactions_repo.get_account_balance(from.id, AccountKind::Cr).unwrap(),
+            Amount::new(199_900)
+        );
+    }
+
+    #[test]
+    fn test_withdrawal_too_many_sources() {
+        let mut core = Core::new().unwrap();
+        let user_id = UserId::generate();
+        let mut config = Config::new().unwrap();
+        config.system.max_withdrawal_source_accounts = 3;
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let from = Account {
+            user_id,
+            currency: Currency::Btc,
+            ..Default::default()
+        };
+        // funds are spread over 5 accounts
+        for _ in 0..5 {
+            let mut deposit = NewTransaction::default();
+            deposit.cr_account_id = AccountId::generate();
+            deposit.currency = Currency::Btc;
+            deposit.value = Amount::new(100);
+            transactions_repo.create(deposit).unwrap();
+        }
+        let service = create_transaction_service_with_repo(AuthenticationToken::default(), user_id, config, transactions_repo);
+
+        let mut input = create_fiat_input(user_id, Currency::Btc, None);
+        input.value = Amount::new(70);
+        let res = core.run(service.create_external_mono_currency_tx(
+            input,
+            from,
+            BlockchainAddress::new("btc_address".to_string()),
+            Currency::Btc,
+            None,
+            None,
+            None,
+            None,
+            None,
+        ));
+        match res.unwrap_err().kind() {
+            ErrorKind::Balance(_) => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+        assert!(service.check_withdrawal_sources_count(Currency::Btc, 3).is_ok());
+        assert!(service.check_withdrawal_sources_count(Currency::Btc, 5).is_err());
+    }
+
+    #[test]
+    fn test_create_scheduled_internal_transaction() {
+        let mut core = Core::new().unwrap();
+        let user_id = UserId::generate();
+        let config = Config::new().unwrap();
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let clock = Arc::new(MockClock::default());
+        let from = Account {
+            user_id,
+            ..Default::default()
+        };
+        let to = Account::default();
+        let mut deposit = NewTransaction::default();
+        deposit.cr_account_id = from.id;
+        deposit.currency = from.currency;
+        deposit.value = Amount::new(100);
+        transactions_repo.create(deposit).unwrap();
+        let exchange_client = Arc::new(ExchangeClientMock::default());
+        let service = create_transaction_service_with_mocks(
+            AuthenticationToken::default(),
+            user_id,
+            config,
+            transactions_repo.clone(),
+            exchange_client,
+            clock.clone(),
+        );
+
+        let mut input = create_fiat_input(user_id, from.currency, None);
+        input.value = Amount::new(70);
+        input.hold_until = Some(clock.now() + Duration::hours(1));
+        let tx = core
+            .run(service.create_internal_mono_currency_tx(input.clone(), from.clone(), to.clone()))
+            .unwrap();
+        assert_eq!(tx.status, TransactionStatus::Scheduled);
+        assert_eq!(tx.hold_until, input.hold_until);
+        assert_eq!(
+            transactions_repo.get_account_balance(to.id, AccountKind::Cr).unwrap(),
+            Amount::new(0)
+        );
+
+        // hold_until in the past is executed immediately
+        clock.advance(Duration::hours(2));
+        input.id = TransactionId::generate();
+        let tx = core.run(service.create_internal_mono_currency_tx(input, from, to.clone())).unwrap();
+        assert_eq!(tx.status, TransactionStatus::Done);
+        assert_eq!(tx.hold_until, None);
+        assert_eq!(
+            transactions_repo.get_account_balance(to.id, AccountKind::Cr).unwrap(),
+            Amount::new(70)
+        );
+    }
+
+    #[test]
+    fn test_create_internal_transaction_duplicate_id() {
+        let mut core = Core::new().unwrap();
+        let user_id = UserId::generate();
+        let from = Account {
+            user_id,
+            ..Default::default()
+        };
+        let to = Account::default();
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let mut deposit = NewTransaction::default();
+        deposit.cr_account_id = from.id;
+        deposit.currency = from.currency;
+        deposit.value = Amount::new(100);
+        transactions_repo.create(deposit).unwrap();
+        let service = create_transaction_service_with_mocks(
+            AuthenticationToken::default(),
+            user_id,
+            Config::new().unwrap(),
+            transactions_repo.clone(),
+            Arc::new(ExchangeClientMock::default()),
+            Arc::new(MockClock::default()),
+        );
+
+        let mut input = create_fiat_input(user_id, from.currency, None);
+        input.value = Amount::new(10);
+        core.run(service.create_internal_mono_currency_tx(input.clone(), from.clone(), to.clone()))
+            .unwrap();
+        let err = core
+            .run(service.create_internal_mono_currency_tx(input, from, to.clone()))
+            .unwrap_err();
+        match err.kind() {
+            ErrorKind::MalformedInput => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+        // the first transfer is kept, the second one is not applied
+        assert_eq!(
+            transactions_repo.get_account_balance(to.id, AccountKind::Cr).unwrap(),
+            Amount::new(10)
+        );
+    }
+
+    #[test]
+    fn test_create_withdrawal_duplicate_id() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let user_id = UserId::generate();
+        let config = Config::new().unwrap();
+        let service = create_transaction_service(token.clone(), user_id);
+        let mut fees_account = NewAccount::default();
+        fees_account.id = config.system.btc_fees_account_id;
+        fees_account.currency = Currency::Btc;
+        service.accounts_repo.create(fees_account).unwrap();
+        let (funding, _) = create_transfer_and_withdrawal_accounts(&service, user_id);
+        let mut input = create_fiat_input(user_id, Currency::Btc, None);
+        input.from = funding.id;
+        input.to = Recepient::new("btc_address".to_string());
+        input.to_type = RecepientType::Address;
+        input.value = Amount::new(100_000);
+
+        // retry of a recorded transaction is rejected before it is sent to blockchain
+        let mut recorded = NewTransaction::default();
+        recorded.id = input.id;
+        service.transactions_repo.create(recorded).unwrap();
+        match core.run(service.create_transaction(token, input.clone())).unwrap_err().kind() {
+            ErrorKind::MalformedInput => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+        assert_eq!(service.pending_transactions_repo.count().unwrap(), 0);
+
+        // once sent, failing to record the withdrawal is not the client's fault
+        let input = CreateTransactionInput {
+            id: TransactionId::generate(),
+            ..input
+        };
+        let mut taken = NewTransaction::default();
+        taken.id = input.id.next();
+        service.transactions_repo.create(taken).unwrap();
+        let res = core.run(service.create_external_mono_currency_tx(
+            input,
+            funding,
+            BlockchainAddress::new("btc_address".to_string()),
+            Currency::Btc,
+            None,
+            None,
+            None,
+            None,
+            None,
+        ));
+        match res.unwrap_err().kind() {
+            ErrorKind::Internal => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+        assert_eq!(service.pending_transactions_repo.count().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_get_exchange_history() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let user_id = UserId::generate();
+        let service = create_transaction_service(token.clone(), user_id);
+        let exchange_id = ExchangeId::generate();
+        let gid = TransactionId::generate();
+        let legs = vec![
+            (
+                gid,
+                TransactionKind::MultiFrom,
+                Currency::Btc,
+                1_000,
+                Some(json!({ "exchangeId": exchange_id, "exchangeRate": 30.5 })),
+            ),
+            (gid.next(), TransactionKind::MultiTo, Currency::Eth, 30_500, None),
+        ];
+        for (id, kind, currency, value, meta) in legs {
+            let mut leg = NewTransaction::default();
+            leg.id = id;
+            leg.gid = gid;
+            leg.user_id = user_id;
+            leg.kind = kind;
+            leg.group_kind = TransactionGroupKind::InternalMulti;
+            leg.currency = currency;
+            leg.value = Amount::new(value);
+            leg.meta = meta;
+            service.transactions_repo.create(leg).unwrap();
+        }
+        // transfers of the user and exchanges of other users are not listed
+        let mut internal = NewTransaction::default();
+        internal.user_id = user_id;
+        service.transactions_repo.create(internal).unwrap();
+        let mut other_exchange = NewTransaction::default();
+        other_exchange.kind = TransactionKind::MultiFrom;
+        other_exchange.group_kind = TransactionGroupKind::InternalMulti;
+        service.transactions_repo.create(other_exchange).unwrap();
+
+        let now = ::chrono::Utc::now().naive_utc();
+        let exchanges = core
+            .run(service.get_exchange_history(token.clone(), user_id, now - Duration::hours(1), now + Duration::hours(1)))
+            .unwrap();
+        assert_eq!(exchanges.len(), 1);
+        let exchange = &exchanges[0];
+        assert_eq!(exchange.transaction_id, gid);
+        assert_eq!(exchange.exchange_id, Some(exchange_id));
+        assert_eq!((exchange.from_currency, exchange.to_currency), (Currency::Btc, Currency::Eth));
+        assert_eq!((exchange.from_value, exchange.to_value), (Amount::new(1_000), Amount::new(30_500)));
+        assert_eq!(exchange.rate, Some(30.5));
+
+        // window before the exchange
+        let exchanges = core
+            .run(service.get_exchange_history(token.clone(), user_id, now - Duration::days(2), now - Duration::days(1)))
+            .unwrap();
+        assert!(exchanges.is_empty());
+
+        let other_user_id = UserId::generate();
+        let err = core
+            .run(service.get_exchange_history(token, other_user_id, now - Duration::hours(1), now + Duration::hours(1)))
+            .unwrap_err();
+        match err.kind() {
+            ErrorKind::Unauthorized => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn test_get_user_summary() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let user_id = UserId::generate();
+        let service = create_transaction_service(token.clone(), user_id);
+        let accounts = vec![
+            (Currency::Btc, 100),
+            (Currency::Eth, 1_000),
+            (Currency::Btc, 50),
+            (Currency::Stq, 0),
+        ];
+        for (currency, value) in accounts {
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user_id;
+            new_account.currency = currency;
+            let account = service.accounts_repo.create(new_account).unwrap();
+            let mut deposit = NewTransaction::default();
+            deposit.cr_account_id = account.id;
+            deposit.currency = currency;
+            deposit.value = Amount::new(value);
+            service.transactions_repo.create(deposit).unwrap();
+        }
+        // accounts of other users are not counted
+        let mut new_account = NewAccount::default();
+        new_account.currency = Currency::Btc;
+        service.accounts_repo.create(new_account).unwrap();
+
+        let summary = core.run(service.get_user_summary(token.clone(), user_id)).unwrap();
+        let summary: Vec<_> = summary
+            .into_iter()
+            .map(|total| (total.currency, total.balance, total.accounts_count))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (Currency::Btc, Amount::new(150), 2),
+                (Currency::Eth, Amount::new(1_000), 1),
+                (Currency::Stq, Amount::new(0), 1),
+            ]
+        );
+
+        let res = core.run(service.get_user_summary(token, UserId::generate()));
+        match res.unwrap_err().kind() {
+            ErrorKind::Unauthorized => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn test_list_accounts_with_balances() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let user_id = UserId::generate();
+        let service = create_transaction_service(token.clone(), user_id);
+        for value in vec![100, 200, 300, 400, 500] {
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user_id;
+            let account = service.accounts_repo.create(new_account).unwrap();
+            let mut deposit = NewTransaction::default();
+            deposit.cr_account_id = account.id;
+            deposit.value = Amount::new(value);
+            service.transactions_repo.create(deposit).unwrap();
+        }
+        // accounts of other users are not listed
+        service.accounts_repo.create(NewAccount::default()).unwrap();
+
+        let pages = vec![(0, vec![100, 200]), (2, vec![300, 400]), (4, vec![500]), (6, vec![])];
+        for (offset, expected) in pages {
+            let page = core
+                .run(service.list_accounts_with_balances(token.clone(), user_id, offset, 2))
+                .unwrap();
+            let balances: Vec<Amount> = page.into_iter().map(|account_with_balance| account_with_balance.balance).collect();
+            let expected: Vec<Amount> = expected.into_iter().map(Amount::new).collect();
+            assert_eq!(balances, expected);
+        }
+
+        let res = core.run(service.list_accounts_with_balances(token, UserId::generate(), 0, 10));
+        match res.unwrap_err().kind() {
+            ErrorKind::Unauthorized => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn test_max_withdrawable() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let user_id = UserId::generate();
+        let config = Config::new().unwrap();
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        // 1 stq = 0.0001 eth
+        let exchange_client = Arc::new(ExchangeClientMock::with_rate(0.0001));
+        let clock = Arc::new(MockClock::default());
+        let service = create_transaction_service_with_mocks(token.clone(), user_id, config, transactions_repo, exchange_client, clock);
+        let mut accounts = Vec::new();
+        for (currency, value) in vec![(Currency::Btc, 1_000_000), (Currency::Stq, 100_000_000_000_000_000_000)] {
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user_id;
+            new_account.currency = currency;
+            let account = service.accounts_repo.create(new_account).unwrap();
+            let mut deposit = NewTransaction::default();
+            deposit.cr_account_id = account.id;
+            deposit.currency = currency;
+            deposit.value = Amount::new(value);
+            service.transactions_repo.create(deposit).unwrap();
+            accounts.push(account);
+        }
+
+        // btc fee = fee_price.bitcoin (4) * btc_transaction_size (280) * fee_upside (2)
+        let max = core
+            .run(service.max_withdrawable(token.clone(), accounts[0].id, Currency::Btc))
+            .unwrap();
+        assert_eq!(max, Amount::new(1_000_000 - 2_240));
+
+        // stq fee is eth network fee = fee_price.ethereum * stq_gas_limit * fee_upside converted to stq
+        let eth_fee = Amount::new(18_000_000_000 * 60_000 * 2);
+        let stq_fee = eth_fee.convert(Currency::Eth, Currency::Stq, 1.0 / 0.0001);
+        let max = core
+            .run(service.max_withdrawable(token.clone(), accounts[1].id, Currency::Stq))
+            .unwrap();
+        assert_eq!(max, Amount::new(100_000_000_000_000_000_000).checked_sub(stq_fee).unwrap());
+        assert!(max < Amount::new(100_000_000_000_000_000_000));
+
+        // withdrawals with exchange are not supported
+        let res = core.run(service.max_withdrawable(token, accounts[1].id, Currency::Eth));
+        match res.unwrap_err().kind() {
+            ErrorKind::MalformedInput => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn test_drain_btc_account() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let user_id = UserId::generate();
+        let config = Config::new().unwrap();
+        let btc_fees_account_id = config.system.btc_fees_account_id;
+        let service = create_transaction_service(token.clone(), user_id);
+        let mut new_account = NewAccount::default();
+        new_account.user_id = user_id;
+        new_account.currency = Currency::Btc;
+        let account = service.accounts_repo.create(new_account).unwrap();
+        let mut fees_account = NewAccount::default();
+        fees_account.id = btc_fees_account_id;
+        fees_account.currency = Currency::Btc;
+        service.accounts_repo.create(fees_account).unwrap();
+        for value in vec![600_000, 400_000] {
+            let mut deposit = NewTransaction::default();
+            deposit.cr_account_id = account.id;
+            deposit.currency = Currency::Btc;
+            deposit.value = Amount::new(value);
+            service.transactions_repo.create(deposit).unwrap();
+        }
+        // liquidity the withdrawal is sent from
+        let mut liquidity = NewTransaction::default();
+        liquidity.dr_account_id = AccountId::generate();
+        liquidity.currency = Currency::Btc;
+        liquidity.value = Amount::new(10_000_000);
+        service.transactions_repo.create(liquidity).unwrap();
+
+        let to = BlockchainAddress::new("btc_address".to_string());
+        let mut input = create_fiat_input(user_id, Currency::Btc, None);
+        input.from = account.id;
+        input.to = Recepient::new(to.to_string());
+        input.to_type = RecepientType::Address;
+        input.value = Amount::new(0);
+        input.drain = true;
+
+        // value is ignored, the default fee = fee_price.bitcoin (4) * btc_transaction_size (280) * fee_upside (2)
+        let input = core.run(service.resolve_drain_value(input)).unwrap();
+        assert_eq!(input.fee, Amount::new(2_240));
+        assert_eq!(input.value, Amount::new(1_000_000 - 2_240));
+
+        core.run(service.create_external_mono_currency_tx(input, account.clone(), to, Currency::Btc, None, None, None, None, None))
+            .unwrap();
+        assert_eq!(
+            service.transactions_repo.get_account_balance(account.id, AccountKind::Cr).unwrap(),
+            Amount::new(0)
+        );
+
+        // nothing left to drain
+        let mut input = create_fiat_input(user_id, Currency::Btc, None);
+        input.from = account.id;
+        input.drain = true;
+        match core.run(service.resolve_drain_value(input)).unwrap_err().kind() {
+            ErrorKind::InvalidInput(_) => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn test_resolve_fee_tier() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let user_id = UserId::generate();
+        let service = create_transaction_service(token.clone(), user_id);
+        let mut new_account = NewAccount::default();
+        new_account.user_id = user_id;
+        new_account.currency = Currency::Btc;
+        let account = service.accounts_repo.create(new_account).unwrap();
+
+        let mut input = create_fiat_input(user_id, Currency::Btc, None);
+        input.from = account.id;
+        input.to = Recepient::new("btc_address".to_string());
+        input.to_type = RecepientType::Address;
+        input.value = Amount::new(100_000);
+        input.fee_omitted = true;
+        let mut fee_prices = vec![];
+        for fee_tier in vec![FeeTier::Normal, FeeTier::Fast] {
+            input.fee_tier = Some(fee_tier);
+            let resolved = core.run(service.resolve_fee_tier(input.clone())).unwrap();
+            let estimate = core
+                .run(
+                    service
+                        .blockchain_service
+                        .estimate_withdrawal_fee(resolved.fee, Currency::Btc, Currency::Btc, None, false),
+                )
+                .unwrap();
+            fee_prices.push((resolved.fee, estimate.fee_price));
+        }
+        // the default fee = fee_price.bitcoin (4) * btc_transaction_size (280) * fee_upside (2), fast multiplier is 1.5
+        assert_eq!(fee_prices, vec![(Amount::new(2_240), 4.0), (Amount::new(3_360), 6.0)]);
+
+        // the fee is kept as is without a tier
+        input.fee_tier = None;
+        input.fee_omitted = false;
+        input.fee = Amount::new(1);
+        let resolved = core.run(service.resolve_fee_tier(input.clone())).unwrap();
+        assert_eq!(resolved.fee, Amount::new(1));
+
+        // either the fee or the tier, not both
+        input.fee_tier = Some(FeeTier::Fast);
+        match core.run(service.resolve_fee_tier(input)).unwrap_err().kind() {
+            ErrorKind::InvalidInput(errors) => assert!(errors.contains("fee_with_fee_tier")),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn test_create_withdrawal_with_omitted_fee() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let user_id = UserId::generate();
+        let config = Config::new().unwrap();
+        let service = create_transaction_service(token.clone(), user_id);
+        let mut fees_account = NewAccount::default();
+        fees_account.id = config.system.btc_fees_account_id;
+        fees_account.currency = Currency::Btc;
+        service.accounts_repo.create(fees_account).unwrap();
+        let (funding, _) = create_transfer_and_withdrawal_accounts(&service, user_id);
+
+        let mut input = create_transfer_and_withdrawal_input(user_id, &funding);
+        input.fee_omitted = true;
+        // the default tier is normal, so the fee = fee_price.bitcoin (4) * btc_transaction_size (280) * fee_upside (2)
+        let tx_out = core.run(service.create_transaction(token.clone(), input)).unwrap();
+        assert_eq!(tx_out.fee, Amount::new(2_240));
+        assert_eq!(
+            service.transactions_repo.get_account_balance(funding.id, AccountKind::Cr).unwrap(),
+            Amount::new(1_000_000 - 800_000 - 2_240)
+        );
+
+        // internal transfers are not charged a fee
+        let mut new_account = NewAccount::default();
+        new_account.currency = Currency::Btc;
+        let to = service.accounts_repo.create(new_account).unwrap();
+        let mut input = create_fiat_input(user_id, Currency::Btc, None);
+        input.from = funding.id;
+        input.to = Recepient::new(to.id.to_string());
+        input.fee_omitted = true;
+        let resolved = core.run(service.resolve_fee_tier(input)).unwrap();
+        assert_eq!(resolved.fee, Amount::new(0));
+    }
+
+    #[test]
+    fn test_get_transaction_by_gid_and_leg_id() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let user_id = UserId::generate();
+        let config = Config::new().unwrap();
+        let service = create_transaction_service(token.clone(), user_id);
+        let mut fees_account = NewAccount::default();
+        fees_account.id = config.system.btc_fees_account_id;
+        fees_account.currency = Currency::Btc;
+        service.accounts_repo.create(fees_account).unwrap();
+        let (funding, _) = create_transfer_and_withdrawal_accounts(&service, user_id);
+
+        let input = create_transfer_and_withdrawal_input(user_id, &funding);
+        let tx_out = core.run(service.create_transaction(token.clone(), input)).unwrap();
+        let found = core.run(service.get_transaction(token.clone(), tx_out.id)).unwrap().unwrap();
+        assert_eq!(found.id, tx_out.id);
+        assert_eq!(found.to_value, tx_out.to_value);
+        assert_eq!(found.fee, tx_out.fee);
+
+        // any leg resolves to the whole group
+        let legs = service.transactions_repo.get_by_gid(tx_out.id).unwrap();
+        assert!(legs.len() > 1);
+        for leg in legs {
+            let found = core.run(service.get_transaction(token.clone(), leg.id)).unwrap().unwrap();
+            assert_eq!(found.id, tx_out.id);
+        }
+
+        // gid that is not an id of any leg
+        let mut new_tx = NewTransaction::default();
+        new_tx.gid = TransactionId::generate();
+        new_tx.user_id = user_id;
+        new_tx.dr_account_id = funding.id;
+        new_tx.cr_account_id = funding.id;
+        new_tx.value = Amount::new(10);
+        let tx = service.transactions_repo.create(new_tx).unwrap();
+        let found = core.run(service.get_transaction(token.clone(), tx.gid)).unwrap().unwrap();
+        assert_eq!(found.id, tx.gid);
+
+        let unknown = core.run(service.get_transaction(token, TransactionId::generate())).unwrap();
+        assert!(unknown.is_none());
+    }
+
+    #[test]
+    fn test_shutdown_drains_in_flight_withdrawal() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let user_id = UserId::generate();
+        let config = Config::new().unwrap();
+        let shutdown = ShutdownCoordinator::new();
+        let service = create_transaction_service(token.clone(), user_id).with_shutdown(shutdown.clone());
+        let mut fees_account = NewAccount::default();
+        fees_account.id = config.system.btc_fees_account_id;
+        fees_account.currency = Currency::Btc;
+        service.accounts_repo.create(fees_account).unwrap();
+        let (funding, _) = create_transfer_and_withdrawal_accounts(&service, user_id);
+        let mut input = create_transfer_and_withdrawal_input(user_id, &funding);
+        input.fee_omitted = true;
+
+        // the withdrawal is started, but not yet polled when shutdown begins
+        let withdrawal = service.create_transaction(token.clone(), input.clone());
+        assert_eq!(shutdown.in_flight(), 1);
+        let drained = shutdown.shutdown();
+
+        input.id = TransactionId::generate();
+        match core.run(service.create_transaction(token, input)).unwrap_err().kind() {
+            ErrorKind::ShuttingDown => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+
+        let (tx_out, _) = core.run(withdrawal.join(drained.then(|_| Ok::<(), Error>(())))).unwrap();
+        assert_eq!(shutdown.in_flight(), 0);
+        let legs = service.transactions_repo.get_by_gid(tx_out.id).unwrap();
+        assert!(legs.iter().any(|leg| leg.kind == TransactionKind::Withdrawal));
+    }
+
+    #[test]
+    fn test_withdrawal_zero_value_and_fee_exceeding_value() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let user_id = UserId::generate();
+        let service = create_transaction_service(token.clone(), user_id);
+        let mut new_account = NewAccount::default();
+        new_account.user_id = user_id;
+        new_account.currency = Currency::Btc;
+        let account = service.accounts_repo.create(new_account).unwrap();
+        let mut deposit = NewTransaction::default();
+        deposit.cr_account_id = account.id;
+        deposit.currency = Currency::Btc;
+        deposit.value = Amount::new(2_000);
+        service.transactions_repo.create(deposit).unwrap();
+
+        let to = BlockchainAddress::new("btc_address".to_string());
+        let mut input = create_fiat_input(user_id, Currency::Btc, None);
+        input.from = account.id;
+        input.to = Recepient::new(to.to_string());
+        input.to_type = RecepientType::Address;
+        input.value = Amount::new(0);
+        match core
+            .run(service.create_external_mono_currency_tx(input.clone(), account.clone(), to, Currency::Btc, None, None, None, None, None))
+            .unwrap_err()
+            .kind()
+        {
+            ErrorKind::MalformedInput => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+
+        // the default fee (2_240) is more than the whole balance
+        input.drain = true;
+        match core.run(service.resolve_drain_value(input.clone())).unwrap_err().kind() {
+            ErrorKind::InvalidInput(_) => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+        // explicit fee equal to the balance leaves nothing to withdraw
+        input.fee = Amount::new(2_000);
+        match core.run(service.resolve_drain_value(input)).unwrap_err().kind() {
+            ErrorKind::InvalidInput(_) => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+    }
+
+    // btc accounts of the user: funding one with 0.01 btc and an empty one to withdraw from
+    fn create_transfer_and_withdrawal_accounts(service: &TransactionsServiceImpl<DbExecutorMock>, user_id: UserId) -> (Account, Account) {
+        let mut new_account = NewAccount::default();
+        new_account.user_id = user_id;
+        new_account.currency = Currency::Btc;
+        let funding = service.accounts_repo.create(new_account.clone()).unwrap();
+        new_account.id = AccountId::generate();
+        let from = service.accounts_repo.create(new_account).unwrap();
+        for value in vec![600_000, 400_000] {
+            let mut deposit = NewTransaction::default();
+            deposit.cr_account_id = funding.id;
+            deposit.currency = Currency::Btc;
+            deposit.value = Amount::new(value);
+            service.transactions_repo.create(deposit).unwrap();
+        }
+        // liquidity the withdrawal is sent from
+        let mut liquidity = NewTransaction::default();
+        liquidity.dr_account_id = AccountId::generate();
+        liquidity.currency = Currency::Btc;
+        liquidity.value = Amount::new(10_000_000);
+        service.transactions_repo.create(liquidity).unwrap();
+        (funding, from)
+    }
+
+    fn create_transfer_and_withdrawal_input(user_id: UserId, from: &Account) -> CreateTransactionInput {
+        let mut input = create_fiat_input(user_id, Currency::Btc, None);
+        input.from = from.id;
+        input.to = Recepient::new("btc_address".to_string());
+        input.to_type = RecepientType::Address;
+        input.value = Amount::new(800_000);
+        input
+    }
+
+    #[test]
+    fn test_create_transfer_and_withdrawal() {
+        let mut core = Core::new().unwrap();
+        let user_id = UserId::generate();
+        let config = Config::new().unwrap();
+        let service = create_transaction_service(AuthenticationToken::default(), user_id);
+        let mut fees_account = NewAccount::default();
+        fees_account.id = config.system.btc_fees_account_id;
+        fees_account.currency = Currency::Btc;
+        service.accounts_repo.create(fees_account).unwrap();
+        let (funding, from) = create_transfer_and_withdrawal_accounts(&service, user_id);
+        let input = create_transfer_and_withdrawal_input(user_id, &from);
+        let gid = input.id;
+
+        let txs = core.run(service.create_transfer_and_withdrawal_tx(input, funding.id)).unwrap();
+        assert!(txs.iter().all(|tx| tx.gid == gid));
+        assert_eq!(txs[0].kind, TransactionKind::Internal);
+        assert_eq!(txs[0].dr_account_id, funding.id);
+        assert_eq!(txs[0].cr_account_id, from.id);
+        assert!(txs.iter().any(|tx| tx.kind == TransactionKind::Withdrawal));
+        assert_eq!(
+            service.transactions_repo.get_account_balance(funding.id, AccountKind::Cr).unwrap(),
+            Amount::new(200_000)
+        );
+        assert_eq!(
+            service.transactions_repo.get_account_balance(from.id, AccountKind::Cr).unwrap(),
+            Amount::new(0)
+        );
+    }
+
+    #[test]
+    fn test_create_split_withdrawal() {
+        let mut core = Core::new().unwrap();
+        let user_id = UserId::generate();
+        let config = Config::new().unwrap();
+        let service = create_transaction_service(AuthenticationToken::default(), user_id);
+        let mut fees_account = NewAccount::default();
+        fees_account.id = config.system.btc_fees_account_id;
+        fees_account.currency = Currency::Btc;
+        service.accounts_repo.create(fees_account).unwrap();
+        let (from, _) = create_transfer_and_withdrawal_accounts(&service, user_id);
+        let output = |to: &str, value: u128| BlockchainTxOutput {
+            to: BlockchainAddress::new(to.to_string()),
+            value: Amount::new(value),
+        };
+        let input = CreateBatchTransactionInput {
+            id: TransactionId::generate(),
+            user_id,
+            from: from.id,
+            currency: Currency::Btc,
+            outputs: vec![output("first_address", 700_000), output("second_address", 200_000)],
+            fee: Amount::new(0),
+        };
+        let gid = input.id;
+
+        let txs = core
+            .run(service.create_split_external_tx(input, from.clone(), Amount::new(900_000)))
+            .unwrap();
+        assert!(txs.iter().all(|tx| tx.gid == gid));
+        assert_eq!(txs.iter().filter(|tx| tx.kind == TransactionKind::Fee).count(), 1);
+        let withdrawals: Vec<_> = txs.iter().filter(|tx| tx.kind == TransactionKind::Withdrawal).collect();
+        assert_eq!(withdrawals.len(), 2);
+        assert!(withdrawals.iter().all(|tx| tx.dr_account_id == from.id));
+        assert_eq!(withdrawals[0].value, Amount::new(700_000));
+        assert_eq!(withdrawals[0].meta, json!({ "to": "first_address" }));
+        assert_eq!(withdrawals[1].value, Amount::new(200_000));
+        assert_eq!(withdrawals[1].meta, json!({ "to": "second_address" }));
+        assert_eq!(
+            service.transactions_repo.get_account_balance(from.id, AccountKind::Cr).unwrap(),
+            Amount::new(100_000)
+        );
+    }
+
+    #[test]
+    fn test_create_split_withdrawal_cooldown() {
+        let mut core = Core::new().unwrap();
+        let user_id = UserId::generate();
+        let mut config = Config::new().unwrap();
+        config.system.withdrawal_cooldown_secs = 600;
+        let mut fees_account = NewAccount::default();
+        fees_account.id = config.system.btc_fees_account_id;
+        fees_account.currency = Currency::Btc;
+        let clock = Arc::new(MockClock::default());
+        let service = create_transaction_service_with_mocks(
+            AuthenticationToken::default(),
+            user_id,
+            config,
+            Arc::new(TransactionsRepoMock::default()),
+            Arc::new(ExchangeClientMock::default()),
+            clock.clone(),
+        );
+        service.accounts_repo.create(fees_account).unwrap();
+        let (from, _) = create_transfer_and_withdrawal_accounts(&service, user_id);
+        let input = || CreateBatchTransactionInput {
+            id: TransactionId::generate(),
+            user_id,
+            from: from.id,
+            currency: Currency::Btc,
+            outputs: vec![
+                BlockchainTxOutput {
+                    to: BlockchainAddress::new("first_address".to_string()),
+                    value: Amount::new(200_000),
+                },
+                BlockchainTxOutput {
+                    to: BlockchainAddress::new("second_address".to_string()),
+                    value: Amount::new(100_000),
+                },
+            ],
+            fee: Amount::new(0),
+        };
+
+        core.run(service.create_split_external_tx(input(), from.clone(), Amount::new(300_000)))
+            .unwrap();
+        // back to back withdrawal is rejected whatever its kind
+        clock.advance(Duration::seconds(599));
+        match core
+            .run(service.create_split_external_tx(input(), from.clone(), Amount::new(300_000)))
+            .unwrap_err()
+            .kind()
+        {
+            ErrorKind::TooManyRequests => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+        clock.advance(Duration::seconds(2));
+        core.run(service.create_split_external_tx(input(), from.clone(), Amount::new(300_000)))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_create_split_withdrawal_not_enough_funds() {
+        let mut core = Core::new().unwrap();
+        let user_id = UserId::generate();
+        let service = create_transaction_service(AuthenticationToken::default(), user_id);
+        let (_, empty) = create_transfer_and_withdrawal_accounts(&service, user_id);
+        let output = |to: &str, value: u128| BlockchainTxOutput {
+            to: BlockchainAddress::new(to.to_string()),
+            value: Amount::new(value),
+        };
+        let input = CreateBatchTransactionInput {
+            id: TransactionId::generate(),
+            user_id,
+            from: empty.id,
+            currency: Currency::Btc,
+            outputs: vec![output("first_address", 700_000), output("second_address", 200_000)],
+            fee: Amount::new(0),
+        };
+        match core
+            .run(service.create_split_external_tx(input, empty, Amount::new(900_000)))
+            .unwrap_err()
+            .kind()
+        {
+            ErrorKind::InvalidInput(_) => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+        // nothing is sent to blockchain
+        assert_eq!(service.pending_transactions_repo.count().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_split_outputs() {
+        let source = |value: u128| AccountWithBalance {
+            account: Account::default(),
+            balance: Amount::new(value),
+        };
+        let output = |value: u128| BlockchainTxOutput {
+            to: BlockchainAddress::default(),
+            value: Amount::new(value),
+        };
+        let sources = vec![source(50), source(100)];
+        let parts = split_outputs(sources.clone(), &[output(30), output(70), output(40)]).unwrap();
+        let values: Vec<_> = parts.iter().map(|(_, output)| output.value).collect();
+        assert_eq!(values, vec![Amount::new(30), Amount::new(20), Amount::new(50), Amount::new(40)]);
+        assert_eq!(parts[1].0.id, sources[0].account.id);
+        assert_eq!(parts[2].0.id, sources[1].account.id);
+        assert!(split_outputs(sources, &[output(200)]).is_none());
+    }
+
+    #[test]
+    fn test_create_transfer_and_withdrawal_rollback() {
+        let mut core = Core::new().unwrap();
+        let user_id = UserId::generate();
+        // no fees account, so the withdrawal fails after the move is written
+        let service = create_transaction_service(AuthenticationToken::default(), user_id);
+        let (funding, from) = create_transfer_and_withdrawal_accounts(&service, user_id);
+
+        // withdrawal is classified as internal transfer, nothing is moved
+        let mut input = create_transfer_and_withdrawal_input(user_id, &from);
+        input.to = Recepient::new(funding.id.to_string());
+        input.to_type = RecepientType::Account;
+        match core
+            .run(service.create_transfer_and_withdrawal_tx(input, funding.id))
+            .unwrap_err()
+            .kind()
+        {
+            ErrorKind::MalformedInput => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+        assert_eq!(service.transactions_repo.list_for_account(funding.id, 0, 10).unwrap().len(), 2);
+
+        // withdrawal fails before posting to blockchain, the move is cancelled
+        let input = create_transfer_and_withdrawal_input(user_id, &from);
+        let gid = input.id;
+        assert!(core.run(service.create_transfer_and_withdrawal_tx(input, funding.id)).is_err());
+        let move_tx = service.transactions_repo.get(gid).unwrap().unwrap();
+        assert_eq!(move_tx.status, TransactionStatus::Cancelled);
+        assert_eq!(
+            service.transactions_repo.get_account_balance(funding.id, AccountKind::Cr).unwrap(),
+            Amount::new(1_000_000)
+        );
+        assert_eq!(
+            service.transactions_repo.get_account_balance(from.id, AccountKind::Cr).unwrap(),
+            Amount::new(0)
+        );
+    }
+
+    #[test]
+    fn test_create_transfer_and_withdrawal_not_recorded() {
+        let mut core = Core::new().unwrap();
+        let user_id = UserId::generate();
+        let config = Config::new().unwrap();
+        let service = create_transaction_service(AuthenticationToken::default(), user_id);
+        let mut fees_account = NewAccount::default();
+        fees_account.id = config.system.btc_fees_account_id;
+        fees_account.currency = Currency::Btc;
+        service.accounts_repo.create(fees_account).unwrap();
+        let (funding, from) = create_transfer_and_withdrawal_accounts(&service, user_id);
+        let input = create_transfer_and_withdrawal_input(user_id, &from);
+        let gid = input.id;
+        // id of the fee leg is taken, so the withdrawal fails to be recorded after it is sent to blockchain
+        let mut taken = NewTransaction::default();
+        taken.id = gid.next();
+        service.transactions_repo.create(taken).unwrap();
+
+        assert!(core.run(service.create_transfer_and_withdrawal_tx(input, funding.id)).is_err());
+        // the move backs the sent withdrawal, so it is not cancelled
+        let move_tx = service.transactions_repo.get(gid).unwrap().unwrap();
+        assert_eq!(move_tx.status, TransactionStatus::Done);
+        assert_eq!(
+            service.transactions_repo.get_account_balance(funding.id, AccountKind::Cr).unwrap(),
+            Amount::new(200_000)
+        );
+    }
+
+    #[test]
+    fn test_find_transactions_to_address() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let config = Config::new().unwrap();
+        let system_user_id = config.system.system_user_id;
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let service = create_transaction_service_with_repo(token.clone(), system_user_id, config, transactions_repo);
+        let address = BlockchainAddress::new("btc_address".to_string());
+        let pending_hash = BlockchainTransactionId::new("pending_hash".to_string());
+        let seen_hash = BlockchainTransactionId::new("seen_hash".to_string());
+        let other_hash = BlockchainTransactionId::new("other_hash".to_string());
+        service
+            .pending_transactions_repo
+            .create(NewPendingBlockchainTransactionDB {
+                hash: pending_hash.clone(),
+                to_: address.clone(),
+                currency: Currency::Btc,
+                ..Default::default()
+            })
+            .unwrap();
+        service
+            .blockchain_transactions_repo
+            .create(NewBlockchainTransactionDB {
+                hash: seen_hash.clone(),
+                to_: json!([{ "address": address.clone(), "value": Amount::new(10) }]),
+                currency: Currency::Btc,
+                ..Default::default()
+            })
+            .unwrap();
+        service
+            .pending_transactions_repo
+            .create(NewPendingBlockchainTransactionDB {
+                hash: other_hash.clone(),
+                currency: Currency::Btc,
+                ..Default::default()
+            })
+            .unwrap();
+        let mut withdrawals = Vec::new();
+        for hash in vec![seen_hash, pending_hash, other_hash] {
+            let withdrawal = service
+                .transactions_repo
+                .create(NewTransaction {
+                    currency: Currency::Btc,
+                    kind: TransactionKind::Withdrawal,
+                    blockchain_tx_id: Some(hash),
+                    ..Default::default()
+                })
+                .unwrap();
+            withdrawals.push(withdrawal.id);
+        }
+
+        let found = core
+            .run(service.find_transactions_to_address(token, address.clone(), Currency::Btc, 0, 10))
+            .unwrap();
+        assert_eq!(
+            found.iter().map(|tx| tx.id).collect::<Vec<_>>(),
+            vec![withdrawals[1], withdrawals[0]]
+        );
+
+        // only the system user can search
+        let user_token = AuthenticationToken::default();
+        let user_service = create_transaction_service(user_token.clone(), UserId::generate());
+        match core
+            .run(user_service.find_transactions_to_address(user_token, address, Currency::Btc, 0, 10))
+            .unwrap_err()
+            .kind()
+        {
+            ErrorKind::Unauthorized => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn test_get_transactions_by_group_kind() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let config = Config::new().unwrap();
+        let system_user_id = config.system.system_user_id;
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let service = create_transaction_service_with_repo(token.clone(), system_user_id, config, transactions_repo.clone());
+        let account = service.accounts_repo.create(NewAccount::default()).unwrap();
+        let other_account = service.accounts_repo.create(NewAccount::default()).unwrap();
+
+        let deposit_hash = BlockchainTransactionId::new("deposit_hash".to_string());
+        service
+            .blockchain_transactions_repo
+            .create(NewBlockchainTransactionDB {
+                hash: deposit_hash.clone(),
+                ..Default::default()
+            })
+            .unwrap();
+        let mut deposit = NewTransaction::default();
+        deposit.cr_account_id = account.id;
+        deposit.kind = TransactionKind::Deposit;
+        deposit.group_kind = TransactionGroupKind::Deposit;
+        deposit.blockchain_tx_id = Some(deposit_hash);
+        let deposit = transactions_repo.create(deposit).unwrap();
+        let mut internal = NewTransaction::default();
+        internal.dr_account_id = account.id;
+        internal.cr_account_id = other_account.id;
+        let internal = transactions_repo.create(internal).unwrap();
+
+        for (group_kind, expected) in vec![
+            (TransactionGroupKind::Deposit, vec![deposit.gid]),
+            (TransactionGroupKind::Internal, vec![internal.gid]),
+            (TransactionGroupKind::InternalMulti, vec![]),
+            (TransactionGroupKind::Withdrawal, vec![]),
+            (TransactionGroupKind::WithdrawalMulti, vec![]),
+            (TransactionGroupKind::Approval, vec![]),
+            (TransactionGroupKind::Reversal, vec![]),
+        ] {
+            let txs = core
+                .run(service.get_transactions_by_group_kind(token.clone(), group_kind, 0, 10))
+                .unwrap();
+            assert_eq!(txs.iter().map(|tx| tx.id).collect::<Vec<_>>(), expected, "{:?}", group_kind);
+        }
+
+        // only the system user can list transactions of all users
+        let user_token = AuthenticationToken::default();
+        let user_service = create_transaction_service(user_token.clone(), UserId::generate());
+        match core
+            .run(user_service.get_transactions_by_group_kind(user_token, TransactionGroupKind::Deposit, 0, 10))
+            .unwrap_err()
+            .kind()
+        {
+            ErrorKind::Unauthorized => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn test_get_transaction_legs() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let user_id = UserId::generate();
+        let config = Config::new().unwrap();
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let gid = TransactionId::generate();
+        let kinds = [TransactionKind::Fee, TransactionKind::Withdrawal, TransactionKind::BlockchainFee];
+        for (i, kind) in kinds.iter().enumerate() {
+            let mut leg = NewTransaction::default();
+            leg.gid = gid;
+            leg.user_id = user_id;
+            leg.kind = *kind;
+            leg.group_kind = TransactionGroupKind::Withdrawal;
+            leg.value = Amount::new(i as u128 + 1);
+            transactions_repo.create(leg).unwrap();
+        }
+        transactions_repo.create(NewTransaction::default()).unwrap();
+        let service = create_transaction_service_with_repo(token.clone(), user_id, config, transactions_repo);
+
+        let legs = core.run(service.get_transaction_legs(token.clone(), gid)).unwrap();
+        assert_eq!(legs.len(), 3);
+        for kind in kinds.iter() {
+            assert!(legs.iter().any(|leg| leg.kind == *kind));
+        }
+
+        let res = core.run(service.get_transaction_legs(token, TransactionId::generate()));
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_get_transaction_legs_of_other_user() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let user_id = UserId::generate();
+        let config = Config::new().unwrap();
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let leg = transactions_repo.create(NewTransaction::default()).unwrap();
+        let service = create_transaction_service_with_repo(token.clone(), user_id, config, transactions_repo);
+
+        let res = core.run(service.get_transaction_legs(token, leg.gid));
+        match res.unwrap_err().kind() {
+            ErrorKind::Unauthorized => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn test_create_transaction_with_user_note() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let user_id = UserId::generate();
+        let recipient_token = AuthenticationToken::new("recipient_token".to_string());
+        let recipient_id = UserId::generate();
+        let system_token = AuthenticationToken::new("system_token".to_string());
+        let system_user_id = Config::new().unwrap().system.system_user_id;
+        let mut service = create_transaction_service(token.clone(), user_id);
+        service.auth_service = Arc::new(
+            AuthServiceMock::new(vec![(token.clone(), user_id), (recipient_token.clone(), recipient_id)])
+                .with_system_user(system_token.clone(), system_user_id),
+        );
+        let mut new_account = NewAccount::default();
+        new_account.user_id = user_id;
+        new_account.currency = Currency::Btc;
+        let from = service.accounts_repo.create(new_account).unwrap();
+        let mut new_account = NewAccount::default();
+        new_account.user_id = recipient_id;
+        new_account.currency = Currency::Btc;
+        let to = service.accounts_repo.create(new_account).unwrap();
+        let mut deposit = NewTransaction::default();
+        deposit.cr_account_id = from.id;
+        deposit.currency = Currency::Btc;
+        deposit.value = Amount::new(2_000_000);
+        service.transactions_repo.create(deposit).unwrap();
+
+        let mut input = create_fiat_input(user_id, Currency::Btc, None);
+        input.from = from.id;
+        input.to = Recepient::new(to.id.to_string());
+        input.value = Amount::new(1_000_000);
+        input.user_note = Some("rent payment".to_string());
+        let tx_out = core.run(service.create_transaction(token.clone(), input.clone())).unwrap();
+        assert_eq!(tx_out.user_note, Some("rent payment".to_string()));
+        let legs = service.transactions_repo.get_by_gid(tx_out.id).unwrap();
+        assert!(legs.iter().all(|leg| leg.user_note == Some("rent payment".to_string())));
+
+        let tx_out = core.run(service.get_transaction(token.clone(), tx_out.id)).unwrap().unwrap();
+        assert_eq!(tx_out.user_note, Some("rent payment".to_string()));
+        let tx_out = core.run(service.get_transaction(system_token, tx_out.id)).unwrap().unwrap();
+        assert_eq!(tx_out.user_note, None);
+
+        // listings show the note to its author only
+        let gid = tx_out.id;
+        let txs = core
+            .run(service.get_account_transactions(token.clone(), from.id, None, None, 0, 10))
+            .unwrap();
+        let listed = txs.into_iter().find(|tx| tx.id == gid).unwrap();
+        assert_eq!(listed.user_note, Some("rent payment".to_string()));
+        let txs = core
+            .run(service.get_transactions_for_user(token.clone(), user_id, None, 0, 10))
+            .unwrap();
+        let listed = txs.into_iter().find(|tx| tx.id == gid).unwrap();
+        assert_eq!(listed.user_note, Some("rent payment".to_string()));
+        let txs = core
+            .run(service.get_account_transactions(recipient_token, to.id, None, None, 0, 10))
+            .unwrap();
+        let listed = txs.into_iter().find(|tx| tx.id == gid).unwrap();
+        assert_eq!(listed.user_note, None);
+
+        input.id = TransactionId::generate();
+        input.user_note = Some("a".repeat(257));
+        match core.run(service.create_transaction(token, input)).unwrap_err().kind() {
+            ErrorKind::InvalidInput(_) => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn test_create_withdrawal_with_user_note_and_category() {
+        let mut core = Core::new().unwrap();
+        let user_id = UserId::generate();
+        let config = Config::new().unwrap();
+        let service = create_transaction_service(AuthenticationToken::default(), user_id);
+        let mut fees_account = NewAccount::default();
+        fees_account.id = config.system.btc_fees_account_id;
+        fees_account.currency = Currency::Btc;
+        service.accounts_repo.create(fees_account).unwrap();
+        let (funding, from) = create_transfer_and_withdrawal_accounts(&service, user_id);
+
+        // note and category are written with every leg, the move and the fee included
+        let mut input = create_transfer_and_withdrawal_input(user_id, &from);
+        input.user_note = Some("rent payment".to_string());
+        input.category = Some("rent".to_string());
+        let txs = core.run(service.create_transfer_and_withdrawal_tx(input, funding.id)).unwrap();
+        let legs = service.transactions_repo.get_by_gid(txs[0].gid).unwrap();
+        assert_eq!(legs.len(), txs.len());
+        assert!(legs.iter().all(|leg| leg.user_note == Some("rent payment".to_string())));
+        assert!(legs.iter().all(|leg| leg.category == Some("rent".to_string())));
+
+        // failed withdrawal leaves nothing behind
+        let mut input = create_transfer_and_withdrawal_input(user_id, &from);
+        input.value = Amount::new(2_000_000);
+        input.user_note = Some("rent payment".to_string());
+        let gid = input.id;
+        assert!(core.run(service.create_transfer_and_withdrawal_tx(input, funding.id)).is_err());
+        assert!(service.transactions_repo.get_by_gid(gid).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_create_transactions_with_category() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let user_id = UserId::generate();
+        let service = create_transaction_service(token.clone(), user_id);
+        let mut new_account = NewAccount::default();
+        new_account.user_id = user_id;
+        new_account.currency = Currency::Btc;
+        let from = service.accounts_repo.create(new_account).unwrap();
+        let mut new_account = NewAccount::default();
+        new_account.currency = Currency::Btc;
+        let to = service.accounts_repo.create(new_account).unwrap();
+        let mut deposit = NewTransaction::default();
+        deposit.cr_account_id = from.id;
+        deposit.currency = Currency::Btc;
+        deposit.value = Amount::new(2_000_000);
+        service.transactions_repo.create(deposit).unwrap();
+
+        let mut ids = vec![];
+        for category in vec![Some("salary"), Some("refund"), None] {
+            let mut input = create_fiat_input(user_id, Currency::Btc, None);
+            input.from = from.id;
+            input.to = Recepient::new(to.id.to_string());
+            input.value = Amount::new(100_000);
+            input.category = category.map(|category| category.to_string());
+            let tx_out = core.run(service.create_transaction(token.clone(), input)).unwrap();
+            assert_eq!(tx_out.category, category.map(|category| category.to_string()));
+            let legs = service.transactions_repo.get_by_gid(tx_out.id).unwrap();
+            assert!(legs.iter().all(|leg| leg.category == tx_out.category));
+            ids.push(tx_out.id);
+        }
+
+        let salary = Some("salary".to_string());
+        let txs = core
+            .run(service.get_transactions_for_user(token.clone(), user_id, salary.clone(), 0, 10))
+            .unwrap();
+        assert_eq!(txs.iter().map(|tx| tx.id).collect::<Vec<_>>(), vec![ids[0]]);
+        let txs = core
+            .run(service.get_account_transactions(token.clone(), from.id, None, salary, 0, 10))
+            .unwrap();
+        assert_eq!(txs.iter().map(|tx| tx.id).collect::<Vec<_>>(), vec![ids[0]]);
+        let txs = core
+            .run(service.get_account_transactions(token.clone(), from.id, None, Some("fees".to_string()), 0, 10))
+            .unwrap();
+        assert!(txs.is_empty());
+        // without the filter all groups are listed
+        let txs = core
+            .run(service.get_account_transactions(token, from.id, None, None, 0, 10))
+            .unwrap();
+        assert!(ids.iter().all(|id| txs.iter().any(|tx| tx.id == *id)));
+    }
+
+    #[test]
+    fn test_create_transaction_rate_limit() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let user_id = UserId::generate();
+        let config = Config::new().unwrap();
+        let clock = Arc::new(MockClock::default());
+        let limit = RateLimit {
+            burst: 2,
+            refill_per_sec: 1.0,
+        };
+        let service = create_transaction_service_with_mocks(
+            token.clone(),
+            user_id,
+            config,
+            Arc::new(TransactionsRepoMock::default()),
+            Arc::new(ExchangeClientMock::default()),
+            clock.clone(),
+        )
+        .with_rate_limiter(Arc::new(InMemoryRateLimiter::new(&limit, clock.clone())));
+        let mut new_account = NewAccount::default();
+        new_account.user_id = user_id;
+        new_account.currency = Currency::Btc;
+        let from = service.accounts_repo.create(new_account).unwrap();
+        let mut new_account = NewAccount::default();
+        new_account.currency = Currency::Btc;
+        let to = service.accounts_repo.create(new_account).unwrap();
+        let mut deposit = NewTransaction::default();
+        deposit.cr_account_id = from.id;
+        deposit.currency = Currency::Btc;
+        deposit.value = Amount::new(2_000_000);
+        service.transactions_repo.create(deposit).unwrap();
+
+        let new_input = || {
+            let mut input = create_fiat_input(user_id, Currency::Btc, None);
+            input.from = from.id;
+            input.to = Recepient::new(to.id.to_string());
+            input.value = Amount::new(100_000);
+            input
+        };
+        core.run(service.create_transaction(token.clone(), new_input())).unwrap();
+        core.run(service.create_transaction(token.clone(), new_input())).unwrap();
+        match core.run(service.create_transaction(token.clone(), new_input())).unwrap_err().kind() {
+            ErrorKind::TooManyRequests => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+
+        clock.advance(Duration::seconds(1));
+        core.run(service.create_transaction(token.clone(), new_input())).unwrap();
+        match core.run(service.create_transaction(token, new_input())).unwrap_err().kind() {
+            ErrorKind::TooManyRequests => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn test_create_batch_transaction_rate_limit() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let user_id = UserId::generate();
+        let clock = Arc::new(MockClock::default());
+        let limit = RateLimit {
+            burst: 1,
+            refill_per_sec: 1.0,
+        };
+        let service = create_transaction_service_with_mocks(
+            token.clone(),
+            user_id,
+            Config::new().unwrap(),
+            Arc::new(TransactionsRepoMock::default()),
+            Arc::new(ExchangeClientMock::default()),
+            clock.clone(),
+        )
+        .with_rate_limiter(Arc::new(InMemoryRateLimiter::new(&limit, clock.clone())));
+        // rejected by validation, but still counted
+        let input = || CreateBatchTransactionInput {
+            id: TransactionId::generate(),
+            user_id,
+            from: AccountId::generate(),
+            currency: Currency::Btc,
+            outputs: vec![],
+            fee: Amount::new(0),
+        };
+
+        match core
+            .run(service.create_batch_transaction(token.clone(), input()))
+            .unwrap_err()
+            .kind()
+        {
+            ErrorKind::InvalidInput(_) => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+        match core
+            .run(service.create_batch_transaction(token.clone(), input()))
+            .unwrap_err()
+            .kind()
+        {
+            ErrorKind::TooManyRequests => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+        match core
+            .run(service.create_split_transaction(token.clone(), input()))
+            .unwrap_err()
+            .kind()
+        {
+            ErrorKind::TooManyRequests => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+
+        clock.advance(Duration::seconds(1));
+        match core.run(service.create_split_transaction(token, input())).unwrap_err().kind() {
+            ErrorKind::InvalidInput(_) => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn test_get_transaction_of_other_user() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let user_id = UserId::generate();
+        let system_token = AuthenticationToken::new("system_token".to_string());
+        let system_user_id = Config::new().unwrap().system.system_user_id;
+        let mut service = create_transaction_service(token.clone(), user_id);
+        service.auth_service =
+            Arc::new(AuthServiceMock::new(vec![(token.clone(), user_id)]).with_system_user(system_token.clone(), system_user_id));
+        let from = service.accounts_repo.create(NewAccount::default()).unwrap();
+        let to = service.accounts_repo.create(NewAccount::default()).unwrap();
+        let mut new_tx = NewTransaction::default();
+        new_tx.dr_account_id = from.id;
+        new_tx.cr_account_id = to.id;
+        new_tx.value = Amount::new(10);
+        let tx = service.transactions_repo.create(new_tx).unwrap();
+
+        let tx_out = core.run(service.get_transaction(system_token, tx.id)).unwrap().unwrap();
+        assert_eq!(tx_out.id, tx.id);
+
+        let res = core.run(service.get_transaction(token, tx.id));
+        match res.unwrap_err().kind() {
+            ErrorKind::Unauthorized => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn test_get_raw_transactions() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let user_id = UserId::generate();
+        let system_token = AuthenticationToken::new("system_token".to_string());
+        let config = Config::new().unwrap();
+        let mut service = create_transaction_service(token.clone(), user_id);
+        service.auth_service = Arc::new(
+            AuthServiceMock::new(vec![(token.clone(), user_id)]).with_system_user(system_token.clone(), config.system.system_user_id),
+        );
+        let mut fees_account = NewAccount::default();
+        fees_account.id = config.system.btc_fees_account_id;
+        fees_account.currency = Currency::Btc;
+        service.accounts_repo.create(fees_account).unwrap();
+        let (from, _) = create_transfer_and_withdrawal_accounts(&service, user_id);
+        let input = CreateBatchTransactionInput {
+            id: TransactionId::generate(),
+            user_id,
+            from: from.id,
+            currency: Currency::Btc,
+            outputs: vec![BlockchainTxOutput {
+                to: BlockchainAddress::new("btc_address".to_string()),
+                value: Amount::new(700_000),
+            }],
+            fee: Amount::new(0),
+        };
+        let txs = core
+            .run(service.create_split_external_tx(input, from, Amount::new(700_000)))
+            .unwrap();
+        let withdrawal = txs.iter().find(|tx| tx.kind == TransactionKind::Withdrawal).unwrap();
+
+        let raw = core.run(service.get_raw_transactions(system_token, withdrawal.gid)).unwrap();
+        assert_eq!(raw.len(), 1);
+        assert_eq!(Some(raw[0].hash.clone()), withdrawal.blockchain_tx_id);
+        assert!(raw[0].raw_tx.is_some());
+
+        let res = core.run(service.get_raw_transactions(token, withdrawal.gid));
+        match res.unwrap_err().kind() {
+            ErrorKind::Unauthorized => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn test_reads_use_read_db_executor() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let user_id = UserId::generate();
+        let read_db_executor = DbExecutorMock::default();
+        let service = create_transaction_service(token.clone(), user_id).with_read_db_executor(read_db_executor.clone());
+        let mut new_account = NewAccount::default();
+        new_account.user_id = user_id;
+        let account = service.accounts_repo.create(new_account).unwrap();
+        let write_executed = service.db_executor.executed();
+
+        core.run(service.get_account_balance(token.clone(), account.id, false)).unwrap();
+        core.run(service.get_transactions_for_user(token.clone(), user_id, None, 0, 10))
+            .unwrap();
+        core.run(service.get_account_transactions(token.clone(), account.id, None, None, 0, 10))
+            .unwrap();
+        assert_eq!(read_db_executor.executed(), 3);
+        assert_eq!(service.db_executor.executed(), write_executed);
+
+        // strict balance is read from the primary
+        core.run(service.get_account_balance(token, account.id, true)).unwrap();
+        assert_eq!(read_db_executor.executed(), 3);
+        assert_eq!(service.db_executor.executed(), write_executed + 1);
+    }
+
+    #[test]
+    fn test_get_account_transactions_by_kind() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let user_id = UserId::generate();
+        let config = Config::new().unwrap();
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let service = create_transaction_service_with_repo(token.clone(), user_id, config, transactions_repo.clone());
+        let mut new_account = NewAccount::default();
+        new_account.user_id = user_id;
+        let account = service.accounts_repo.create(new_account).unwrap();
+        let other_account = service.accounts_repo.create(NewAccount::default()).unwrap();
+
+        let mut deposit = NewTransaction::default();
+        deposit.cr_account_id = account.id;
+        deposit.kind = TransactionKind::Deposit;
+        deposit.group_kind = TransactionGroupKind::Deposit;
+        transactions_repo.create(deposit).unwrap();
+        let mut internal = NewTransaction::default();
+        internal.dr_account_id = account.id;
+        internal.cr_account_id = other_account.id;
+        let internal = transactions_repo.create(internal).unwrap();
+
+        let txs = core
+            .run(service.get_account_transactions(token.clone(), account.id, Some(TransactionKind::Withdrawal), None, 0, 10))
+            .unwrap();
+        assert!(txs.is_empty());
+        let txs = core
+            .run(service.get_account_transactions(token, account.id, Some(TransactionKind::Internal), None, 0, 10))
+            .unwrap();
+        assert_eq!(txs.len(), 1);
+        assert_eq!(txs[0].id, internal.gid);
+    }
+
+    #[test]
+    fn test_create_internal_multi_currency_tx_exchange_errors() {
+        let mut core = Core::new().unwrap();
+        let user_id = UserId::generate();
+        let from = Account {
+            user_id,
+            currency: Currency::Btc,
+            ..Default::default()
+        };
+        let to = Account {
+            user_id,
+            currency: Currency::Eth,
+            ..Default::default()
+        };
+        let cases = vec![
+            (ExchangeClientErrorKind::Unavailable, true),
+            (ExchangeClientErrorKind::RateRejected, false),
+            (ExchangeClientErrorKind::Validation("rate expired".to_string()), false),
+        ];
+        for (exchange_error, retryable) in cases {
+            let exchange_client = Arc::new(ExchangeClientMock::with_exchange_error(1.0, exchange_error));
+            let service = create_transaction_service_with_mocks(
+                AuthenticationToken::default(),
+                user_id,
+                Config::new().unwrap(),
+                Arc::new(TransactionsRepoMock::default()),
+                exchange_client.clone(),
+                Arc::new(MockClock::default()),
+            );
+            let input = create_fiat_input(user_id, to.currency, None);
+            let exchange_id = ExchangeId::for_transaction(input.id);
+            let res = core.run(service.create_internal_multi_currency_tx(input, from.clone(), to.clone(), 1.0));
+            match (res.unwrap_err().kind(), retryable) {
+                (ErrorKind::ExchangeUnavailable, true) => (),
+                (ErrorKind::RateRejected(_), false) => (),
+                (kind, _) => panic!("unexpected error kind: {:?}", kind),
+            }
+            // only an exchange in unknown state is voided, a rejected one was never executed
+            let expected_cancelled = if retryable { vec![exchange_id] } else { vec![] };
+            assert_eq!(exchange_client.cancelled_exchanges(), expected_cancelled);
+        }
+    }
+
+    #[test]
+    fn test_create_internal_multi_currency_tx_cancels_exchange_on_db_error() {
+        let mut core = Core::new().unwrap();
+        let user_id = UserId::generate();
+        let from = Account {
+            user_id,
+            currency: Currency::Btc,
+            ..Default::default()
+        };
+        let to = Account {
+            user_id,
+            currency: Currency::Eth,
+            ..Default::default()
+        };
+        let exchange_client = Arc::new(ExchangeClientMock::with_rate(1.0));
+        let service = create_transaction_service_with_mocks(
+            AuthenticationToken::default(),
+            user_id,
+            Config::new().unwrap(),
+            Arc::new(TransactionsRepoMock::default()),
+            exchange_client.clone(),
+            Arc::new(MockClock::default()),
+        );
+        // there are no system liquidity accounts, so legs fail after the exchange is executed
+        let input = create_fiat_input(user_id, to.currency, None);
+        let exchange_id = ExchangeId::for_transaction(input.id);
+        let res = core.run(service.create_internal_multi_currency_tx(input, from, to, 1.0));
+        match res.unwrap_err().kind() {
+            ErrorKind::Internal => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+        assert_eq!(exchange_client.cancelled_exchanges(), vec![exchange_id]);
+    }
+
+    #[test]
+    fn test_create_internal_multi_currency_tx_unrelated_value_currency() {
+        let mut core = Core::new().unwrap();
+        let user_id = UserId::generate();
+        let from = Account {
+            user_id,
+            currency: Currency::Btc,
+            ..Default::default()
+        };
+        let to = Account {
+            user_id,
+            currency: Currency::Eth,
+            ..Default::default()
+        };
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let exchange_client = Arc::new(ExchangeClientMock::with_rate(1.0));
+        let service = create_transaction_service_with_mocks(
+            AuthenticationToken::default(),
+            user_id,
+            Config::new().unwrap(),
+            transactions_repo.clone(),
+            exchange_client.clone(),
+            Arc::new(MockClock::default()),
+        );
+        let input = create_fiat_input(user_id, Currency::Stq, None);
+        let gid = input.id;
+        let res = core.run(service.create_internal_multi_currency_tx(input, from, to, 1.0));
+        match res.unwrap_err().kind() {
+            ErrorKind::MalformedInput => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+        // rejected before the exchange, so there is nothing to void or account for
+        assert!(exchange_client.cancelled_exchanges().is_empty());
+        assert!(transactions_repo.get_by_gid(gid).unwrap().is_empty());
+    }
+
+    fn create_exchange_retry_service(
+        user_id: UserId,
+        transactions_repo: Arc<TransactionsRepoMock>,
+        exchange_client: Arc<ExchangeClientMock>,
+    ) -> TransactionsServiceImpl<DbExecutorMock> {
+        let config = Config::new().unwrap();
+        let btc_liquidity_id = config.system.btc_liquidity_account_id;
+        let service = create_transaction_service_with_mocks(
+            AuthenticationToken::default(),
+            user_id,
+            config,
+            transactions_repo,
+            exchange_client,
+            Arc::new(MockClock::default()),
+        );
+        service
+            .accounts_repo
+            .create(NewAccount {
+                id: btc_liquidity_id,
+                currency: Currency::Btc,
+                kind: AccountKind::Dr,
+                ..Default::default()
+            })
+            .unwrap();
+        service
+    }
+
+    #[test]
+    fn test_create_internal_multi_currency_tx_retry_keeps_exchange() {
+        let mut core = Core::new().unwrap();
+        let user_id = UserId::generate();
+        let from = Account {
+            user_id,
+            currency: Currency::Btc,
+            ..Default::default()
+        };
+        let to = Account {
+            user_id,
+            currency: Currency::Eth,
+            ..Default::default()
+        };
+        let mut input = create_fiat_input(user_id, to.currency, None);
+        input.value_currency = Currency::Btc;
+        // leg of the first attempt is already recorded with the same id and exchange
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let mut recorded = NewTransaction::default();
+        recorded.id = input.id;
+        recorded.cr_account_id = from.id;
+        recorded.currency = Currency::Btc;
+        recorded.value = Amount::new(1_000_000);
+        recorded.meta = Some(json!({ "exchangeId": ExchangeId::for_transaction(input.id), "exchangeRate": 1.0 }));
+        transactions_repo.create(recorded).unwrap();
+        let exchange_client = Arc::new(ExchangeClientMock::with_rate(1.0));
+        let service = create_exchange_retry_service(user_id, transactions_repo, exchange_client.clone());
+
+        let res = core.run(service.create_internal_multi_currency_tx(input, from, to, 1.0));
+        match res.unwrap_err().kind() {
+            ErrorKind::MalformedInput => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+        assert!(exchange_client.cancelled_exchanges().is_empty());
+    }
+
+    #[test]
+    fn test_create_internal_multi_currency_tx_reused_id_cancels_exchange() {
+        let mut core = Core::new().unwrap();
+        let user_id = UserId::generate();
+        let from = Account {
+            user_id,
+            currency: Currency::Btc,
+            ..Default::default()
+        };
+        let to = Account {
+            user_id,
+            currency: Currency::Eth,
+            ..Default::default()
+        };
+        let mut input = create_fiat_input(user_id, to.currency, None);
+        input.value_currency = Currency::Btc;
+        // id is taken by a transaction that is not an exchange
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let mut recorded = NewTransaction::default();
+        recorded.id = input.id;
+        recorded.cr_account_id = from.id;
+        recorded.currency = Currency::Btc;
+        recorded.value = Amount::new(1_000_000);
+        transactions_repo.create(recorded).unwrap();
+        let exchange_client = Arc::new(ExchangeClientMock::with_rate(1.0));
+        let service = create_exchange_retry_service(user_id, transactions_repo, exchange_client.clone());
+        let exchange_id = ExchangeId::for_transaction(input.id);
+
+        let res = core.run(service.create_internal_multi_currency_tx(input, from, to, 1.0));
+        match res.unwrap_err().kind() {
+            ErrorKind::MalformedInput => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+        assert_eq!(exchange_client.cancelled_exchanges(), vec![exchange_id]);
+    }
+
+    #[test]
+    fn test_create_internal_multi_currency_tx_same_gid_same_exchange() {
+        let mut core = Core::new().unwrap();
+        let user_id = UserId::generate();
+        let from = Account {
+            user_id,
+            currency: Currency::Btc,
+            ..Default::default()
+        };
+        let to = Account {
+            user_id,
+            currency: Currency::Eth,
+            ..Default::default()
+        };
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let mut deposit = NewTransaction::default();
+        deposit.cr_account_id = from.id;
+        deposit.currency = Currency::Btc;
+        deposit.value = Amount::new(1_000_000);
+        transactions_repo.create(deposit).unwrap();
+        let config = Config::new().unwrap();
+        let liquidity_accounts = vec![
+            (config.system.btc_liquidity_account_id, Currency::Btc),
+            (config.system.eth_liquidity_account_id, Currency::Eth),
+        ];
+        let exchange_client = Arc::new(ExchangeClientMock::with_rate(30.0));
+        let service = create_transaction_service_with_mocks(
+            AuthenticationToken::default(),
+            user_id,
+            config,
+            transactions_repo,
+            exchange_client.clone(),
+            Arc::new(MockClock::default()),
+        );
+        for (id, currency) in liquidity_accounts {
+            service
+                .accounts_repo
+                .create(NewAccount {
+                    id,
+                    currency,
+                    kind: AccountKind::Dr,
+                    ..Default::default()
+                })
+                .unwrap();
+            let mut liquidity = NewTransaction::default();
+            liquidity.dr_account_id = id;
+            liquidity.currency = currency;
+            liquidity.value = Amount::new(WEI_IN_ETH);
+            service.transactions_repo.create(liquidity).unwrap();
+        }
+        let mut input = create_fiat_input(user_id, to.currency, None);
+        input.value = Amount::new(1_000);
+        input.value_currency = Currency::Btc;
+        let exchange_id = ExchangeId::for_transaction(input.id);
+
+        let txs = core
+            .run(service.create_internal_multi_currency_tx(input.clone(), from.clone(), to.clone(), 30.0))
+            .unwrap();
+        assert_eq!(txs.len(), 2);
+        // retry of the same transaction is the same exchange for the gateway, and it is kept
+        let res = core.run(service.create_internal_multi_currency_tx(input, from, to.clone(), 30.0));
+        match res.unwrap_err().kind() {
+            ErrorKind::MalformedInput => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+        assert_eq!(exchange_client.executed_exchanges(), vec![exchange_id]);
+        assert!(exchange_client.cancelled_exchanges().is_empty());
+        let to_value = Amount::new(1_000).convert(Currency::Btc, Currency::Eth, 30.0);
+        let to_balance = service.transactions_repo.get_account_balance(to.id, AccountKind::Cr).unwrap();
+        assert_eq!(to_balance, to_value);
+    }
+
+    #[test]
+    fn test_create_internal_multi_currency_tx_rate_band() {
+        let mut core = Core::new().unwrap();
+        let user_id = UserId::generate();
+        let from = Account {
+            user_id,
+            currency: Currency::Btc,
+            ..Default::default()
+        };
+        let to = Account {
+            user_id,
+            currency: Currency::Eth,
+            ..Default::default()
+        };
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let mut deposit = NewTransaction::default();
+        deposit.cr_account_id = from.id;
+        deposit.currency = Currency::Btc;
+        deposit.value = Amount::new(1_000_000);
+        transactions_repo.create(deposit).unwrap();
+        let config = Config::new().unwrap();
+        let liquidity_accounts = vec![
+            (config.system.btc_liquidity_account_id, Currency::Btc),
+            (config.system.eth_liquidity_account_id, Currency::Eth),
+        ];
+        // 1 btc is 30 eth
+        let service = create_transaction_service_with_mocks(
+            AuthenticationToken::default(),
+            user_id,
+            config,
+            transactions_repo,
+            Arc::new(ExchangeClientMock::with_rate(30.0)),
+            Arc::new(MockClock::default()),
+        );
+        for (id, currency) in liquidity_accounts {
+            let liquidity_account = NewAccount {
+                id,
+                currency,
+                kind: AccountKind::Dr,
+                ..Default::default()
+            };
+            service.accounts_repo.create(liquidity_account).unwrap();
+            let mut liquidity = NewTransaction::default();
+            liquidity.dr_account_id = id;
+            liquidity.currency = currency;
+            liquidity.value = Amount::new(WEI_IN_ETH);
+            service.transactions_repo.create(liquidity).unwrap();
+        }
+        let mut input = create_fiat_input(user_id, to.currency, None);
+        input.value = Amount::new(1_000);
+        input.value_currency = Currency::Btc;
+
+        // 50% off
+        let res = core.run(service.create_internal_multi_currency_tx(input.clone(), from.clone(), to.clone(), 45.0));
+        match res.unwrap_err().kind() {
+            ErrorKind::RateRejected(errors) => assert!(errors.contains("rate_out_of_band")),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+        let res = core.run(service.create_internal_multi_currency_tx(input.clone(), from.clone(), to.clone(), 15.0));
+        assert!(res.is_err());
+
+        // within 5% band
+        let txs = core.run(service.create_internal_multi_currency_tx(input, from, to, 30.9)).unwrap();
+        assert_eq!(txs.len(), 2);
+    }
+
+    #[test]
+    fn test_create_internal_multi_currency_tx_spread() {
+        let mut core = Core::new().unwrap();
+        let user_id = UserId::generate();
+        let from = Account {
+            user_id,
+            currency: Currency::Btc,
+            ..Default::default()
+        };
+        let to = Account {
+            user_id,
+            currency: Currency::Eth,
+            ..Default::default()
+        };
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let mut deposit = NewTransaction::default();
+        deposit.cr_account_id = from.id;
+        deposit.currency = Currency::Btc;
+        deposit.value = Amount::new(1_000_000);
+        transactions_repo.create(deposit).unwrap();
+        let mut config = Config::new().unwrap();
+        config.system.exchange_spread_bps = 50;
+        let (btc_liquidity_id, eth_liquidity_id) = (config.system.btc_liquidity_account_id, config.system.eth_liquidity_account_id);
+        let eth_fees_id = config.system.eth_fees_account_id;
+        let service = create_transaction_service_with_mocks(
+            AuthenticationToken::default(),
+            user_id,
+            config,
+            transactions_repo,
+            Arc::new(ExchangeClientMock::with_rate(30.0)),
+            Arc::new(MockClock::default()),
+        );
+        for (id, currency) in vec![(btc_liquidity_id, Currency::Btc), (eth_liquidity_id, Currency::Eth)] {
+            service
+                .accounts_repo
+                .create(NewAccount {
+                    id,
+                    currency,
+                    kind: AccountKind::Dr,
+                    ..Default::default()
+                })
+                .unwrap();
+            let mut liquidity = NewTransaction::default();
+            liquidity.dr_account_id = id;
+            liquidity.currency = currency;
+            liquidity.value = Amount::new(WEI_IN_ETH);
+            service.transactions_repo.create(liquidity).unwrap();
+        }
+        service
+            .accounts_repo
+            .create(NewAccount {
+                id: eth_fees_id,
+                currency: Currency::Eth,
+                kind: AccountKind::Cr,
+                ..Default::default()
+            })
+            .unwrap();
+        let mut input = create_fiat_input(user_id, to.currency, None);
+        input.value = Amount::new(1_000);
+        input.value_currency = Currency::Btc;
+
+        let txs = core
+            .run(service.create_internal_multi_currency_tx(input, from, to.clone(), 30.0))
+            .unwrap();
+        assert_eq!(txs.len(), 3);
+        let gross_to_value = Amount::new(1_000).convert(Currency::Btc, Currency::Eth, 30.0);
+        let received = txs.iter().find(|tx| tx.kind == TransactionKind::MultiTo).unwrap();
+        let spread = txs.iter().find(|tx| tx.kind == TransactionKind::Fee).unwrap();
+        assert_eq!(spread.value, Amount::new(gross_to_value.raw() * 50 / 10_000));
+        assert_eq!(spread.dr_account_id, eth_liquidity_id);
+        assert_eq!(spread.cr_account_id, eth_fees_id);
+        // everything liquidity paid out is either received by the user or kept in fees account
+        assert_eq!(received.value.checked_add(spread.value), Some(gross_to_value));
+        let to_balance = service.transactions_repo.get_account_balance(to.id, AccountKind::Cr).unwrap();
+        let fees_balance = service.transactions_repo.get_account_balance(eth_fees_id, AccountKind::Cr).unwrap();
+        assert_eq!(to_balance.checked_add(fees_balance), Some(gross_to_value));
+    }
+
+    #[test]
+    fn test_create_internal_multi_currency_tx_liquidity_pool() {
+        let mut core = Core::new().unwrap();
+        let user_id = UserId::generate();
+        let from = Account {
+            user_id,
+            currency: Currency::Btc,
+            ..Default::default()
+        };
+        let to = Account {
+            user_id,
+            currency: Currency::Eth,
+            ..Default::default()
+        };
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let mut deposit = NewTransaction::default();
+        deposit.cr_account_id = from.id;
+        deposit.currency = Currency::Btc;
+        deposit.value = Amount::new(1_000_000);
+        transactions_repo.create(deposit).unwrap();
+        let mut config = Config::new().unwrap();
+        let (btc_liquidity_id, eth_liquidity_id) = (config.system.btc_liquidity_account_id, config.system.eth_liquidity_account_id);
+        let (btc_extra_liquidity_id, eth_extra_liquidity_id) = (AccountId::generate(), AccountId::generate());
+        config.system.btc_extra_liquidity_account_ids = vec![btc_extra_liquidity_id];
+        config.system.eth_extra_liquidity_account_ids = vec![eth_extra_liquidity_id];
+        let service = create_transaction_service_with_mocks(
+            AuthenticationToken::default(),
+            user_id,
+            config,
+            transactions_repo,
+            Arc::new(ExchangeClientMock::with_rate(30.0)),
+            Arc::new(MockClock::default()),
+        );
+        // the extra eth account can't pay out an exchange
+        for (id, currency, value) in vec![
+            (btc_liquidity_id, Currency::Btc, WEI_IN_ETH),
+            (btc_extra_liquidity_id, Currency::Btc, WEI_IN_ETH),
+            (eth_liquidity_id, Currency::Eth, WEI_IN_ETH),
+            (eth_extra_liquidity_id, Currency::Eth, 1_000),
+        ] {
+            service
+                .accounts_repo
+                .create(NewAccount {
+                    id,
+                    currency,
+                    kind: AccountKind::Dr,
+                    ..Default::default()
+                })
+                .unwrap();
+            let mut liquidity = NewTransaction::default();
+            liquidity.dr_account_id = id;
+            liquidity.currency = currency;
+            liquidity.value = Amount::new(value);
+            service.transactions_repo.create(liquidity).unwrap();
+        }
+        let exchange = |service: &TransactionsServiceImpl<DbExecutorMock>| {
+            let mut input = create_fiat_input(user_id, Currency::Eth, None);
+            input.value = Amount::new(1_000);
+            input.value_currency = Currency::Btc;
+            service.create_internal_multi_currency_tx(input, from.clone(), to.clone(), 30.0)
+        };
+
+        let (first, second) = core.run(exchange(&service).join(exchange(&service))).unwrap();
+        let txs: Vec<Transaction> = first.into_iter().chain(second.into_iter()).collect();
+        // only the accounts holding the value pay out, any account of the pool receives
+        for tx in txs.iter().filter(|tx| tx.kind == TransactionKind::MultiTo) {
+            assert_eq!(tx.dr_account_id, eth_liquidity_id);
+        }
+        for tx in txs.iter().filter(|tx| tx.kind == TransactionKind::MultiFrom) {
+            assert!(tx.cr_account_id == btc_liquidity_id || tx.cr_account_id == btc_extra_liquidity_id);
+        }
+        let to_value = Amount::new(1_000).convert(Currency::Btc, Currency::Eth, 30.0);
+        let to_balance = service.transactions_repo.get_account_balance(to.id, AccountKind::Cr).unwrap();
+        assert_eq!(to_balance, to_value.checked_add(to_value).unwrap());
+    }
+
+    fn create_scheduled_transaction(
+        transactions_repo: &TransactionsRepoMock,
+        user_id: UserId,
+        accounts_repo: &Arc<dyn AccountsRepo>,
+    ) -> (Transaction, Account) {
+        let from = accounts_repo
+            .create(NewAccount {
+                user_id,
+                ..Default::default()
+            })
+            .unwrap();
+        let to = accounts_repo.create(NewAccount::default()).unwrap();
+        let mut scheduled = NewTransaction::default();
+        scheduled.user_id = user_id;
+        scheduled.dr_account_id = from.id;
+        scheduled.cr_account_id = to.id;
+        scheduled.value = Amount::new(100);
+        scheduled.status = TransactionStatus::Scheduled;
+        scheduled.hold_until = Some(::chrono::Utc::now().naive_utc() + Duration::hours(1));
+        (transactions_repo.create(scheduled).unwrap(), to)
+    }
+
+    #[test]
+    fn test_cancel_scheduled_transaction_before_due() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let user_id = UserId::generate();
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let service = create_transaction_service_with_repo(token.clone(), user_id, Config::new().unwrap(), transactions_repo.clone());
+        let (scheduled, to) = create_scheduled_transaction(&transactions_repo, user_id, &service.accounts_repo);
+
+        let tx = core.run(service.cancel_scheduled_transaction(token, scheduled.id)).unwrap();
+        assert_eq!(tx.status, TransactionStatus::Cancelled);
+        assert_eq!(
+            transactions_repo.get(scheduled.id).unwrap().unwrap().status,
+            TransactionStatus::Cancelled
+        );
+        assert_eq!(
+            transactions_repo.get_account_balance(to.id, AccountKind::Cr).unwrap(),
+            Amount::new(0)
+        );
+        // cancelled transfer is not picked up when due
+        let due = transactions_repo
+            .get_scheduled_due(::chrono::Utc::now().naive_utc() + Duration::hours(2))
+            .unwrap();
+        assert!(due.is_empty());
+    }
+
+    #[test]
+    fn test_cancel_scheduled_transaction_after_due() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let user_id = UserId::generate();
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let service = create_transaction_service_with_repo(token.clone(), user_id, Config::new().unwrap(), transactions_repo.clone());
+        let (scheduled, to) = create_scheduled_transaction(&transactions_repo, user_id, &service.accounts_repo);
+        // promoted by `process_due_transactions`
+        transactions_repo
+            .update_status_by_id(scheduled.id, TransactionStatus::Done)
+            .unwrap();
+
+        let res = core.run(service.cancel_scheduled_transaction(token, scheduled.id));
+        match res.unwrap_err().kind() {
+            ErrorKind::InvalidInput(_) => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+        assert_eq!(
+            transactions_repo.get_account_balance(to.id, AccountKind::Cr).unwrap(),
+            Amount::new(100)
+        );
+    }
+
+    #[test]
+    fn test_cancel_scheduled_transaction_of_other_user() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let service =
+            create_transaction_service_with_repo(token.clone(), UserId::generate(), Config::new().unwrap(), transactions_repo.clone());
+        let (scheduled, _) = create_scheduled_transaction(&transactions_repo, UserId::generate(), &service.accounts_repo);
+
+        let res = core.run(service.cancel_scheduled_transaction(token, scheduled.id));
+        assert!(res.is_err());
+        assert_eq!(
+            transactions_repo.get(scheduled.id).unwrap().unwrap().status,
+            TransactionStatus::Scheduled
+        );
+    }
+
+    #[test]
+    fn test_create_batch_transaction_validation() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let user_id = UserId::generate();
+        let service = create_transaction_service(token.clone(), user_id);
+        let btc_account = service
+            .accounts_repo
+            .create(NewAccount {
+                user_id,
+                currency: Currency::Btc,
+                ..Default::default()
+            })
+            .unwrap();
+        let eth_account = service
+            .accounts_repo
+            .create(NewAccount {
+                user_id,
+                currency: Currency::Eth,
+                ..Default::default()
+            })
+            .unwrap();
+        let output = |to: BlockchainAddress| BlockchainTxOutput {
+            to,
+            value: Amount::new(1000),
+        };
+        let input = CreateBatchTransactionInput {
+            id: TransactionId::generate(),
+            user_id,
+            from: btc_account.id,
+            currency: Currency::Btc,
+            outputs: vec![
+                output(BlockchainAddress::default()),
+                output(BlockchainAddress::default()),
+                output(BlockchainAddress::default()),
+            ],
+            fee: Amount::new(100),
+        };
+
+        // eth and stq can't batch natively
+        let eth_input = CreateBatchTransactionInput {
+            from: eth_account.id,
+            currency: Currency::Eth,
+            ..input.clone()
+        };
+        match core
+            .run(service.create_batch_transaction(token.clone(), eth_input))
+            .unwrap_err()
+            .kind()
+        {
+            ErrorKind::InvalidInput(_) => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+        let empty_input = CreateBatchTransactionInput {
+            outputs: vec![],
+            ..input.clone()
+        };
+        match core
+            .run(service.create_batch_transaction(token.clone(), empty_input))
+            .unwrap_err()
+            .kind()
+        {
+            ErrorKind::InvalidInput(_) => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+        let internal_input = CreateBatchTransactionInput {
+            outputs: vec![output(BlockchainAddress::default()), output(eth_account.address.clone())],
+            ..input.clone()
+        };
+        match core
+            .run(service.create_batch_transaction(token.clone(), internal_input))
+            .unwrap_err()
+            .kind()
+        {
+            ErrorKind::InvalidInput(_) => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+
+        let (from_account, total) = service.validate_batch_transaction(&input).unwrap();
+        assert_eq!(from_account.id, btc_account.id);
+        assert_eq!(total, Amount::new(3000));
+    }
+
+    #[test]
+    fn test_create_batch_transaction_invalid_outputs() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let user_id = UserId::generate();
+        let create_service = |config: Config| {
+            let service = create_transaction_service_with_repo(token.clone(), user_id, config, Arc::new(TransactionsRepoMock::default()));
+            let account = service
+                .accounts_repo
+                .create(NewAccount {
+                    user_id,
+                    currency: Currency::Btc,
+                    ..Default::default()
+                })
+                .unwrap();
+            (service, account)
+        };
+        let input = |from: AccountId, values: &[u128]| CreateBatchTransactionInput {
+            id: TransactionId::generate(),
+            user_id,
+            from,
+            currency: Currency::Btc,
+            outputs: values
+                .iter()
+                .enumerate()
+                .map(|(i, value)| BlockchainTxOutput {
+                    to: BlockchainAddress::new(format!("address_{}", i)),
+                    value: Amount::new(*value),
+                })
+                .collect(),
+            fee: Amount::new(100),
+        };
+
+        // output paying nothing
+        let (service, account) = create_service(Config::new().unwrap());
+        match service
+            .validate_batch_transaction(&input(account.id, &[1000, 0]))
+            .unwrap_err()
+            .kind()
+        {
+            ErrorKind::MalformedInput => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+        // currency disabled in the system
+        let mut config = Config::new().unwrap();
+        config.system.enabled_currencies.remove(&Currency::Btc);
+        let (service, account) = create_service(config);
+        match service
+            .validate_batch_transaction(&input(account.id, &[1000, 1000]))
+            .unwrap_err()
+            .kind()
+        {
+            ErrorKind::MalformedInput => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+        // withdrawals of the currency switched off
+        let mut config = Config::new().unwrap();
+        config.withdrawals_enabled.btc = false;
+        let (service, account) = create_service(config);
+        match core
+            .run(service.create_batch_transaction(token.clone(), input(account.id, &[1000, 1000])))
+            .unwrap_err()
+            .kind()
+        {
+            ErrorKind::Balance(_) => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+        match core
+            .run(service.create_split_transaction(token.clone(), input(account.id, &[1000, 1000])))
+            .unwrap_err()
+            .kind()
+        {
+            ErrorKind::Balance(_) => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn test_create_batch_transaction_address_screening() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let user_id = UserId::generate();
+        let blocked = BlockchainAddress::new("blocked_address".to_string());
+        let mut config = Config::new().unwrap();
+        config.address_screening.denylist = vec![blocked.clone()];
+        let service = create_transaction_service_with_repo(token.clone(), user_id, config, Arc::new(TransactionsRepoMock::default()));
+        let btc_account = service
+            .accounts_repo
+            .create(NewAccount {
+                user_id,
+                currency: Currency::Btc,
+                ..Default::default()
+            })
+            .unwrap();
+        let output = |to: &str| BlockchainTxOutput {
+            to: BlockchainAddress::new(to.to_string()),
+            value: Amount::new(1000),
+        };
+        let input = CreateBatchTransactionInput {
+            id: TransactionId::generate(),
+            user_id,
+            from: btc_account.id,
+            currency: Currency::Btc,
+            outputs: vec![output("first_address"), output(blocked.raw())],
+            fee: Amount::new(100),
+        };
+
+        // any blocked recipient rejects the whole withdrawal, whether sent in one blockchain transaction or several
+        match core
+            .run(service.create_batch_transaction(token.clone(), input.clone()))
+            .unwrap_err()
+            .kind()
+        {
+            ErrorKind::MalformedInput => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+        match core.run(service.create_split_transaction(token, input)).unwrap_err().kind() {
+            ErrorKind::MalformedInput => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+        assert_eq!(service.pending_transactions_repo.count().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_create_batch_transaction_max_value() {
+        let user_id = UserId::generate();
+        let mut config = Config::new().unwrap();
+        // the account daily limit is way under the max value by default
+        config.limits.btc_limit = 1000.0;
+        let max_value = config.max_transaction_value.for_currency(Currency::Btc);
+        let service = create_transaction_service_with_repo(
+            AuthenticationToken::default(),
+            user_id,
+            config,
+            Arc::new(TransactionsRepoMock::default()),
+        );
+        let btc_account = service
+            .accounts_repo
+            .create(NewAccount {
+                user_id,
+                currency: Currency::Btc,
+                ..Default::default()
+            })
+            .unwrap();
+        let output = |to: &str, value: Amount| BlockchainTxOutput {
+            to: BlockchainAddress::new(to.to_string()),
+            value,
+        };
+        let half = Amount::new(max_value.raw() / 2);
+        let input = CreateBatchTransactionInput {
+            id: TransactionId::generate(),
+            user_id,
+            from: btc_account.id,
+            currency: Currency::Btc,
+            outputs: vec![output("first_address", half), output("second_address", half)],
+            fee: Amount::new(100),
+        };
+        assert!(service.validate_batch_transaction(&input).is_ok());
+
+        // one output over the max value
+        let over_max = max_value.checked_add(Amount::new(1)).unwrap();
+        let single_input = CreateBatchTransactionInput {
+            outputs: vec![output("first_address", over_max)],
+            ..input.clone()
+        };
+        match service.validate_batch_transaction(&single_input).unwrap_err().kind() {
+            ErrorKind::MalformedInput => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+        // each output is under the max value, but the total is over it
+        let split_input = CreateBatchTransactionInput {
+            outputs: vec![
+                output("first_address", half),
+                output("second_address", half),
+                output("third_address", half),
+            ],
+            ..input
+        };
+        match service.validate_batch_transaction(&split_input).unwrap_err().kind() {
+            ErrorKind::MalformedInput => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn test_create_batch_withdrawal_fee() {
+        let mut core = Core::new().unwrap();
+        let user_id = UserId::generate();
+        let create_service = |min_fee_price: f64| {
+            let mut config = Config::new().unwrap();
+            config.min_fee_price.btc = min_fee_price;
+            let mut fees_account = NewAccount::default();
+            fees_account.id = config.system.btc_fees_account_id;
+            fees_account.currency = Currency::Btc;
+            let service = create_transaction_service_with_repo(
+                AuthenticationToken::default(),
+                user_id,
+                config,
+                Arc::new(TransactionsRepoMock::default()),
+            );
+            service.accounts_repo.create(fees_account).unwrap();
+            let (funding, _) = create_transfer_and_withdrawal_accounts(&service, user_id);
+            (service, funding)
+        };
+        let output = |to: &str, value: u128| BlockchainTxOutput {
+            to: BlockchainAddress::new(to.to_string()),
+            value: Amount::new(value),
+        };
+        // 10 satoshis per byte of a single withdrawal, 280 bytes with fee upside of 2
+        let input = |from: AccountId| CreateBatchTransactionInput {
+            id: TransactionId::generate(),
+            user_id,
+            from,
+            currency: Currency::Btc,
+            outputs: vec![
+                output("first_address", 300_000),
+                output("second_address", 200_000),
+                output("third_address", 100_000),
+            ],
+            fee: Amount::new(5_600),
+        };
+
+        // two extra outputs add 68 bytes, the fee price stays the same and the fee grows with the size
+        let (service, funding) = create_service(0.0);
+        let txs = core
+            .run(service.create_batch_external_tx(input(funding.id), funding.clone(), Amount::new(600_000)))
+            .unwrap();
+        let fee = txs.iter().find(|tx| tx.kind == TransactionKind::Fee).unwrap();
+        assert_eq!(fee.value, Amount::new(6_960));
+        assert_eq!(fee.fee_price, Some(10.0));
+        assert_eq!(fee.gross_fee, Some(Amount::new(3_480)));
+        assert_eq!(
+            service.transactions_repo.get_account_balance(funding.id, AccountKind::Cr).unwrap(),
+            Amount::new(1_000_000 - 600_000 - 6_960)
+        );
+
+        // fee price floor is kept as well
+        let (service, funding) = create_service(20.0);
+        let txs = core
+            .run(service.create_batch_external_tx(input(funding.id), funding.clone(), Amount::new(600_000)))
+            .unwrap();
+        let fee = txs.iter().find(|tx| tx.kind == TransactionKind::Fee).unwrap();
+        assert_eq!(fee.fee_price, Some(20.0));
+        assert_eq!(fee.gross_fee, Some(Amount::new(6_960)));
+    }
+
+    #[test]
+    fn test_create_batch_withdrawal_not_enough_funds() {
+        let mut core = Core::new().unwrap();
+        let user_id = UserId::generate();
+        let service = create_transaction_service(AuthenticationToken::default(), user_id);
+        // 0.01 btc on the funding account, the other one is empty
+        let (funding, empty) = create_transfer_and_withdrawal_accounts(&service, user_id);
+        let output = |value: u128| BlockchainTxOutput {
+            to: BlockchainAddress::new("btc_address".to_string()),
+            value: Amount::new(value),
+        };
+        let input = CreateBatchTransactionInput {
+            id: TransactionId::generate(),
+            user_id,
+            from: empty.id,
+            currency: Currency::Btc,
+            outputs: vec![output(300_000), output(200_000)],
+            fee: Amount::new(0),
+        };
+        match core
+            .run(service.create_batch_external_tx(input.clone(), empty, Amount::new(500_000)))
+            .unwrap_err()
+            .kind()
+        {
+            ErrorKind::InvalidInput(_) => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+        // the fee is paid from the same account
+        let input = CreateBatchTransactionInput {
+            from: funding.id,
+            outputs: vec![output(600_000), output(400_000)],
+            fee: Amount::new(1),
+            ..input
+        };
+        assert!(core
+            .run(service.create_batch_external_tx(input, funding, Amount::new(1_000_000)))
+            .is_err());
+        // nothing is sent to blockchain
+        assert_eq!(service.pending_transactions_repo.count().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_get_transaction_status_history() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let user_id = UserId::generate();
+        let service = create_transaction_service(token.clone(), user_id);
+        let blockchain_tx_id = BlockchainTransactionId::new("withdrawal_hash".to_string());
+        let fee = service
+            .transactions_repo
+            .create(NewTransaction {
+                user_id,
+                status: TransactionStatus::Done,
+                kind: TransactionKind::Fee,
+                ..Default::default()
+            })
+            .unwrap();
+        let withdrawal = service
+            .transactions_repo
+            .create(NewTransaction {
+                gid: fee.gid,
+                user_id,
+                status: TransactionStatus::Pending,
+                blockchain_tx_id: Some(blockchain_tx_id.clone()),
+                kind: TransactionKind::Withdrawal,
+                ..Default::default()
+            })
+            .unwrap();
+        service
+            .transactions_repo
+            .update_status(blockchain_tx_id, TransactionStatus::Done)
+            .unwrap();
+
+        let history = core.run(service.get_transaction_status_history(token.clone(), fee.gid)).unwrap();
+        let changes: Vec<_> = history.iter().map(|change| (change.transaction_id, change.status)).collect();
+        assert_eq!(
+            changes,
+            vec![
+                (fee.id, TransactionStatus::Done),
+                (withdrawal.id, TransactionStatus::Pending),
+                (withdrawal.id, TransactionStatus::Done),
+            ]
+        );
+        // other users can't see it
+        let other_token = AuthenticationToken::default();
+        let other_service = create_transaction_service(other_token.clone(), UserId::generate());
+        other_service
+            .transactions_repo
+            .create(NewTransaction {
+                id: fee.id,
+                gid: fee.gid,
+                user_id,
+                ..Default::default()
+            })
+            .unwrap();
+        match core
+            .run(other_service.get_transaction_status_history(other_token, fee.gid))
+            .unwrap_err()
+            .kind()
+        {
+            ErrorKind::Unauthorized => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn test_get_related_transactions() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let user_id = UserId::generate();
+        let service = create_transaction_service(token.clone(), user_id);
+        let original = service
+            .transactions_repo
+            .create(NewTransaction {
+                user_id,
+                kind: TransactionKind::Withdrawal,
+                ..Default::default()
+            })
+            .unwrap();
+        let reversal = service
+            .transactions_repo
+            .create(NewTransaction {
+                user_id,
+                kind: TransactionKind::Reversal,
+                related_tx: Some(original.id),
+                ..Default::default()
+            })
+            .unwrap();
+
+        let related = core.run(service.get_related_transactions(token.clone(), original.id)).unwrap();
+        assert_eq!(related.iter().map(|tx| tx.id).collect::<Vec<_>>(), vec![reversal.id]);
+        let related = core.run(service.get_related_transactions(token, reversal.id)).unwrap();
+        assert!(related.is_empty());
+        // other users can't see it
+        let other_token = AuthenticationToken::default();
+        let other_service = create_transaction_service(other_token.clone(), UserId::generate());
+        other_service
+            .transactions_repo
+            .create(NewTransaction {
+                id: original.id,
+                user_id,
+                ..Default::default()
+            })
+            .unwrap();
+        match core
+            .run(other_service.get_related_transactions(other_token, original.id))
+            .unwrap_err()
+            .kind()
+        {
+            ErrorKind::Unauthorized => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+    }
+
+    fn create_manual_deposit_service(
+        token: AuthenticationToken,
+        hash: BlockchainTransactionId,
+    ) -> (TransactionsServiceImpl<DbExecutorMock>, Account) {
+        let config = Config::new().unwrap();
+        let system_user_id = config.system.system_user_id;
+        let mut new_account = NewAccount::default();
+        new_account.currency = Currency::Btc;
+        let blockchain_client = BlockchainClientMock::default().with_transaction(BlockchainTransaction {
+            hash,
+            from: vec![BlockchainAddress::new("external_address".to_string())],
+            to: vec![BlockchainTransactionEntryTo {
+                address: new_account.address.clone(),
+                value: Amount::new(1000),
+            }],
+            currency: Currency::Btc,
+            confirmations: 6,
+            ..Default::default()
+        });
+        let service = create_transaction_service_with_blockchain_client(
+            token,
+            system_user_id,
+            config,
+            Arc::new(TransactionsRepoMock::default()),
+            Arc::new(ExchangeClientMock::default()),
+            blockchain_client,
+            Arc::new(MockClock::default()),
+        );
+        let account = service.accounts_repo.create(new_account.clone()).unwrap();
+        service.accounts_repo.create(new_account.create_debit()).unwrap();
+        (service, account)
+    }
+
+    #[test]
+    fn test_manual_deposit() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let hash = BlockchainTransactionId::new("missed_deposit_hash".to_string());
+        let (service, account) = create_manual_deposit_service(token.clone(), hash.clone());
+
+        let tx_out = core
+            .run(service.manual_deposit(token.clone(), account.id, Currency::Btc, Amount::new(1000), hash.clone()))
+            .unwrap();
+        assert_eq!(tx_out.to.account_id, Some(account.id));
+        assert_eq!(tx_out.to_value, Amount::new(1000));
+        assert_eq!(tx_out.status, TransactionStatus::Done);
+        let balance = service.transactions_repo.get_account_balance(account.id, AccountKind::Cr).unwrap();
+        assert_eq!(balance, Amount::new(1000));
+
+        // not known to blockchain
+        let unknown_hash = BlockchainTransactionId::new("unknown_hash".to_string());
+        let res = core.run(service.manual_deposit(token, account.id, Currency::Btc, Amount::new(1000), unknown_hash));
+        match res.unwrap_err().kind() {
+            ErrorKind::InvalidInput(_) => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn test_manual_deposit_not_final() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let config = Config::new().unwrap();
+        let system_user_id = config.system.system_user_id;
+        let mut new_account = NewAccount::default();
+        new_account.currency = Currency::Eth;
+        let unconfirmed_hash = BlockchainTransactionId::new("unconfirmed_hash".to_string());
+        let reverted_hash = BlockchainTransactionId::new("reverted_hash".to_string());
+        // 1 eth needs 2 confirmations
+        let blockchain_client = BlockchainClientMock::default()
+            .with_transaction(BlockchainTransaction {
+                hash: unconfirmed_hash.clone(),
+                from: vec![BlockchainAddress::new("external_address".to_string())],
+                to: vec![BlockchainTransactionEntryTo {
+                    address: new_account.address.clone(),
+                    value: Amount::new(1_000_000_000_000_000_000),
+                }],
+                currency: Currency::Eth,
+                confirmations: 1,
+                ..Default::default()
+            })
+            .with_transaction(BlockchainTransaction {
+                hash: reverted_hash.clone(),
+                from: vec![BlockchainAddress::new("external_address".to_string())],
+                to: vec![BlockchainTransactionEntryTo {
+                    address: new_account.address.clone(),
+                    value: Amount::new(1000),
+                }],
+                currency: Currency::Eth,
+                confirmations: 6,
+                reverted: true,
+                ..Default::default()
+            });
+        let service = create_transaction_service_with_blockchain_client(
+            token.clone(),
+            system_user_id,
+            config,
+            Arc::new(TransactionsRepoMock::default()),
+            Arc::new(ExchangeClientMock::default()),
+            blockchain_client,
+            Arc::new(MockClock::default()),
+        );
+        let account = service.accounts_repo.create(new_account.clone()).unwrap();
+        service.accounts_repo.create(new_account.create_debit()).unwrap();
+
+        let res = core.run(service.manual_deposit(
+            token.clone(),
+            account.id,
+            Currency::Eth,
+            Amount::new(1_000_000_000_000_000_000),
+            unconfirmed_hash,
+        ));
+        match res.unwrap_err().kind() {
+            ErrorKind::InvalidInput(_) => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+        let res = core.run(service.manual_deposit(token, account.id, Currency::Eth, Amount::new(1000), reverted_hash));
+        match res.unwrap_err().kind() {
+            ErrorKind::InvalidInput(_) => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+        let balance = service.transactions_repo.get_account_balance(account.id, AccountKind::Cr).unwrap();
+        assert_eq!(balance, Amount::new(0));
+    }
+
+    #[test]
+    fn test_manual_deposit_duplicate_hash() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let hash = BlockchainTransactionId::new("missed_deposit_hash".to_string());
+        let (service, account) = create_manual_deposit_service(token.clone(), hash.clone());
+
+        core.run(service.manual_deposit(token.clone(), account.id, Currency::Btc, Amount::new(1000), hash.clone()))
+            .unwrap();
+        let res = core.run(service.manual_deposit(token, account.id, Currency::Btc, Amount::new(1000), hash));
+        match res.unwrap_err().kind() {
+            ErrorKind::InvalidInput(_) => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+        // credited only once
+        let balance = service.transactions_repo.get_account_balance(account.id, AccountKind::Cr).unwrap();
+        assert_eq!(balance, Amount::new(1000));
+    }
+
+    #[test]
+    fn test_manual_deposit_currency() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let hash = BlockchainTransactionId::new("stq_deposit_hash".to_string());
+        let config = Config::new().unwrap();
+        let system_user_id = config.system.system_user_id;
+        // eth and stq accounts sharing the address, blockchain reports an stq transfer
+        let mut new_eth_account = NewAccount::default();
+        new_eth_account.currency = Currency::Eth;
+        let mut new_stq_account = NewAccount::default();
+        new_stq_account.currency = Currency::Stq;
+        new_stq_account.address = new_eth_account.address.clone();
+        let blockchain_client = BlockchainClientMock::default().with_transaction(BlockchainTransaction {
+            hash: hash.clone(),
+            from: vec![BlockchainAddress::new("external_address".to_string())],
+            to: vec![BlockchainTransactionEntryTo {
+                address: new_stq_account.address.clone(),
+                value: Amount::new(1000),
+            }],
+            currency: Currency::Stq,
+            confirmations: 6,
+            ..Default::default()
+        });
+        let service = create_transaction_service_with_blockchain_client(
+            token.clone(),
+            system_user_id,
+            config,
+            Arc::new(TransactionsRepoMock::default()),
+            Arc::new(ExchangeClientMock::default()),
+            blockchain_client,
+            Arc::new(MockClock::default()),
+        );
+        let eth_account = service.accounts_repo.create(new_eth_account).unwrap();
+        let stq_account = service.accounts_repo.create(new_stq_account.clone()).unwrap();
+        service.accounts_repo.create(new_stq_account.create_debit()).unwrap();
+
+        // mismatching currency is not credited and is kept as a strange transaction
+        let res = core.run(service.manual_deposit(token.clone(), eth_account.id, Currency::Eth, Amount::new(1000), hash.clone()));
+        match res.unwrap_err().kind() {
+            ErrorKind::InvalidInput(_) => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+        let balance = service
+            .transactions_repo
+            .get_account_balance(eth_account.id, AccountKind::Cr)
+            .unwrap();
+        assert_eq!(balance, Amount::new(0));
+        let strange_tx = service.strange_blockchain_transactions_repo.get(hash.clone()).unwrap().unwrap();
+        assert_eq!(strange_tx.currency, Currency::Stq);
+
+        // matching currency is credited
+        let tx_out = core
+            .run(service.manual_deposit(token, stq_account.id, Currency::Stq, Amount::new(1000), hash))
+            .unwrap();
+        assert_eq!(tx_out.to.account_id, Some(stq_account.id));
+        assert_eq!(tx_out.to_currency, Currency::Stq);
+        let balance = service
+            .transactions_repo
+            .get_account_balance(stq_account.id, AccountKind::Cr)
+            .unwrap();
+        assert_eq!(balance, Amount::new(1000));
+    }
+
+    #[test]
+    fn test_resolver_enabled() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let system_user_id = Config::new().unwrap().system.system_user_id;
+        let service = create_transaction_service(token.clone(), system_user_id);
+
+        // runs unless paused
+        assert!(core.run(service.get_resolver_enabled(token.clone())).unwrap());
+        assert!(!core.run(service.set_resolver_enabled(token.clone(), false)).unwrap());
+        assert!(!core.run(service.get_resolver_enabled(token.clone())).unwrap());
+        assert!(!service.key_values_repo.get_resolver_enabled().unwrap());
+        assert!(core.run(service.set_resolver_enabled(token.clone(), true)).unwrap());
+        assert!(core.run(service.get_resolver_enabled(token)).unwrap());
+
+        // only the system user can toggle it
+        let user_token = AuthenticationToken::default();
+        let user_service = create_transaction_service(user_token.clone(), UserId::generate());
+        match core.run(user_service.set_resolver_enabled(user_token, false)).unwrap_err().kind() {
+            ErrorKind::Unauthorized => (),
+            kind => panic!("unexpected error kind: {:?}", kind),
+        }
+    }
 }
@@ -1,6 +1,10 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
 use failure::Fail;
-use futures::future;
+use futures::future::{self, Either};
 use futures::prelude::*;
+use futures::sync::oneshot;
 use hyper;
 use sentry::integrations::failure::capture_error;
 
@@ -57,3 +61,83 @@ pub fn read_body(body: hyper::Body) -> impl Future<Item = Vec<u8>, Error = hyper
         future::ok::<_, hyper::Error>(acc)
     })
 }
+
+/// Limits the number of futures running at the same time, futures above the limit
+/// wait in a queue for a permit instead of failing
+#[derive(Clone)]
+pub struct Semaphore {
+    state: Arc<Mutex<SemaphoreState>>,
+}
+
+struct SemaphoreState {
+    available: usize,
+    waiters: VecDeque<oneshot::Sender<SemaphorePermit>>,
+}
+
+/// Gives the slot back to the semaphore when dropped
+pub struct SemaphorePermit {
+    state: Option<Arc<Mutex<SemaphoreState>>>,
+}
+
+impl Semaphore {
+    pub fn new(permits: usize) -> Self {
+        Semaphore {
+            state: Arc::new(Mutex::new(SemaphoreState {
+                available: permits,
+                waiters: VecDeque::new(),
+            })),
+        }
+    }
+
+    pub fn acquire(&self) -> impl Future<Item = SemaphorePermit, Error = ()> + Send {
+        let mut state = self.state.lock().expect("semaphore lock is poisoned");
+        if state.available > 0 {
+            state.available -= 1;
+            return Either::A(future::ok(SemaphorePermit {
+                state: Some(self.state.clone()),
+            }));
+        }
+        let (tx, rx) = oneshot::channel();
+        state.waiters.push_back(tx);
+        Either::B(rx.map_err(|_| ()))
+    }
+
+    /// Runs `f` once a permit is available, holding the permit until `f` is resolved
+    pub fn limit<F>(&self, f: F) -> impl Future<Item = F::Item, Error = F::Error> + Send
+    where
+        F: Future + Send,
+    {
+        // waiters are dropped only together with the semaphore, so the permit is always there
+        self.acquire().then(move |permit| {
+            f.then(move |res| {
+                drop(permit);
+                res
+            })
+        })
+    }
+}
+
+impl Drop for SemaphorePermit {
+    fn drop(&mut self) {
+        let state_ref = match self.state.take() {
+            Some(state_ref) => state_ref,
+            None => return,
+        };
+        let mut state = match state_ref.lock() {
+            Ok(state) => state,
+            Err(_) => return,
+        };
+        // hand the slot over to the first waiter that is still interested in it
+        while let Some(waiter) = state.waiters.pop_front() {
+            let permit = SemaphorePermit {
+                state: Some(state_ref.clone()),
+            };
+            match waiter.send(permit) {
+                Ok(()) => return,
+                // the receiver is gone, the returned permit must not release the slot once more
+                Err(mut permit) => permit.state = None,
+            }
+        }
+        state.available += 1;
+    }
+}